@@ -0,0 +1,19 @@
+// Records the git commit being built (see `light::manifest::GIT_HASH`), unless
+// `LIGHT_GIT_HASH` is already set or this is not a git checkout.
+fn main() {
+    println!("cargo:rerun-if-env-changed=LIGHT_GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    if std::env::var_os("LIGHT_GIT_HASH").is_some() {
+        return;
+    }
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=LIGHT_GIT_HASH={}", hash.trim());
+        }
+    }
+}
@@ -0,0 +1,239 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Two rooms side by side, connected by a door. Only the first one has a
+//! window, so the second one is lit by what comes through the door.
+//!
+//! The scene is built programmatically, a grid of sensors is laid on the
+//! work plane of each room, and the illuminance under a CIE overcast sky is
+//! printed for each sensor.
+//!
+//! ```text
+//! cargo run --release --example two_rooms -- [n_ambient_samples] [max_depth]
+//! ```
+
+use geometry3d::{Point3D, Vector3D};
+use light::{
+    annual_irradiance, DCOptions, DCSession, Float, Material, SceneBuilder, SensorSpec, SkyBasis,
+};
+
+/// The size of each room, in meters
+const WIDTH: Float = 4.;
+const DEPTH: Float = 5.;
+const HEIGHT: Float = 3.;
+
+/// The horizontal illuminance of the overcast sky, in lux
+const SKY_ILLUMINANCE: Float = 10000.;
+
+/// An opening within a rectangle, as `(start, end)` along each of its sides
+type Opening = ((Float, Float), (Float, Float));
+
+/// Adds the rectangle with a corner at `origin` and sides `u` and `v`, leaving
+/// out an `opening` (which is split into up to four polygons)
+fn rectangle(
+    builder: &mut SceneBuilder,
+    modifier: &str,
+    name: &str,
+    origin: Point3D,
+    u: Vector3D,
+    v: Vector3D,
+    opening: Option<Opening>,
+) -> Result<(), String> {
+    let (lu, lv) = (u.length(), v.length());
+    let (du, dv) = (u * (1. / lu), v * (1. / lv));
+    let piece = |builder: &mut SceneBuilder, name: &str, s: (Float, Float), t: (Float, Float)| {
+        if s.1 - s.0 <= 0.0 || t.1 - t.0 <= 0.0 {
+            return Ok(());
+        }
+        let corner = |a: Float, b: Float| origin + du * a + dv * b;
+        builder.add_polygon(
+            modifier,
+            name,
+            &[
+                corner(s.0, t.0),
+                corner(s.1, t.0),
+                corner(s.1, t.1),
+                corner(s.0, t.1),
+            ],
+        )
+    };
+    match opening {
+        None => piece(builder, name, (0., lu), (0., lv)),
+        Some(((s0, s1), (t0, t1))) => {
+            piece(builder, &format!("{}_below", name), (0., lu), (0., t0))?;
+            piece(builder, &format!("{}_above", name), (0., lu), (t1, lv))?;
+            piece(builder, &format!("{}_left", name), (0., s0), (t0, t1))?;
+            piece(builder, &format!("{}_right", name), (s1, lu), (t0, t1))
+        }
+    }
+}
+
+/// Builds the two rooms. Room A goes from `x = 0` to `x = WIDTH`, and room B
+/// from there to `x = 2 WIDTH`. The window looks South (i.e., towards `-y`).
+fn two_rooms() -> Result<SceneBuilder, String> {
+    let mut builder = SceneBuilder::new();
+    builder.add_material("floor_mat", Material::plastic(0.2))?;
+    builder.add_material("wall_mat", Material::plastic(0.5))?;
+    builder.add_material("ceiling_mat", Material::plastic(0.7))?;
+    builder.add_material("glass_mat", Material::glass(0.8))?;
+
+    let x = Vector3D::new(1., 0., 0.);
+    let y = Vector3D::new(0., 1., 0.);
+    let z = Vector3D::new(0., 0., 1.);
+    for (room, x0) in [("a", 0.), ("b", WIDTH)] {
+        let origin = Point3D::new(x0, 0., 0.);
+        let top = Point3D::new(x0, 0., HEIGHT);
+        rectangle(
+            &mut builder,
+            "floor_mat",
+            &format!("{}_floor", room),
+            origin,
+            x * WIDTH,
+            y * DEPTH,
+            None,
+        )?;
+        rectangle(
+            &mut builder,
+            "ceiling_mat",
+            &format!("{}_ceiling", room),
+            top,
+            x * WIDTH,
+            y * DEPTH,
+            None,
+        )?;
+        let window = if room == "a" {
+            Some(((1., 3.), (0.9, 2.4)))
+        } else {
+            None
+        };
+        rectangle(
+            &mut builder,
+            "wall_mat",
+            &format!("{}_south", room),
+            origin,
+            x * WIDTH,
+            z * HEIGHT,
+            window,
+        )?;
+        rectangle(
+            &mut builder,
+            "wall_mat",
+            &format!("{}_north", room),
+            origin + y * DEPTH,
+            x * WIDTH,
+            z * HEIGHT,
+            None,
+        )?;
+    }
+    builder.add_polygon(
+        "glass_mat",
+        "a_window",
+        &[
+            Point3D::new(1., 0., 0.9),
+            Point3D::new(3., 0., 0.9),
+            Point3D::new(3., 0., 2.4),
+            Point3D::new(1., 0., 2.4),
+        ],
+    )?;
+    let wall = Point3D::new(0., 0., 0.);
+    rectangle(
+        &mut builder,
+        "wall_mat",
+        "a_west",
+        wall,
+        y * DEPTH,
+        z * HEIGHT,
+        None,
+    )?;
+    // The shared wall, with a door in the middle
+    let shared = Point3D::new(WIDTH, 0., 0.);
+    let door = Some(((2., 3.), (0., 2.1)));
+    rectangle(
+        &mut builder,
+        "wall_mat",
+        "shared",
+        shared,
+        y * DEPTH,
+        z * HEIGHT,
+        door,
+    )?;
+    let wall = Point3D::new(2. * WIDTH, 0., 0.);
+    rectangle(
+        &mut builder,
+        "wall_mat",
+        "b_east",
+        wall,
+        y * DEPTH,
+        z * HEIGHT,
+        None,
+    )?;
+    Ok(builder)
+}
+
+fn main() -> Result<(), String> {
+    let mut args = std::env::args().skip(1);
+    let mut number = |default: usize| -> Result<usize, String> {
+        match args.next() {
+            Some(a) => a
+                .parse()
+                .map_err(|_| format!("Expecting a number, found '{}'", a)),
+            None => Ok(default),
+        }
+    };
+    let n_ambient_samples = number(3000)?;
+    let max_depth = number(3)?;
+
+    let (scene, report) = two_rooms()?.build()?;
+    eprintln!("{}", report);
+
+    // Sensors on the work plane, half a meter away from the walls
+    let mut sensors = Vec::new();
+    for (room, x0) in [("a", 0.), ("b", WIDTH)] {
+        let grid =
+            SensorSpec::horizontal_grid((x0 + 0.5, 0.5), (x0 + WIDTH - 0.5, DEPTH - 0.5), 0.8, 1.)?;
+        sensors.extend(grid.into_iter().map(|s| s.with_zone(room)));
+    }
+
+    let session = DCSession::try_new(
+        1,
+        DCOptions {
+            n_ambient_samples,
+            max_depth,
+            ..DCOptions::default()
+        },
+    )?;
+    let dc = session.calc_sensor_dc(&sensors, &scene)?;
+    let sky = SkyBasis::new(1)?.cie_overcast(SKY_ILLUMINANCE, 0.2)?;
+    let illuminance = annual_irradiance(&dc.matrix, &sky)?;
+
+    println!("room,x,y,illuminance (lux),daylight factor (%)");
+    for (i, row) in dc.rows.iter().enumerate() {
+        let e = illuminance.get(i, 0)?;
+        println!(
+            "{},{:.2},{:.2},{:.1},{:.2}",
+            row.zone.as_deref().unwrap_or(""),
+            row.ray.origin.x,
+            row.ray.origin.y,
+            e,
+            100. * e / SKY_ILLUMINANCE
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The coefficients of a single sensor while they are being traced. Most
+//! sensors only see a few bins of the finer skies (e.g., a sensor in a
+//! corridor may see 50 of the 5185 bins of MF 6), and all the rows of a
+//! calculation are held in memory before being written into the matrix,
+//! so rows start sparse and only become dense once they are well filled.
+
+use crate::sparse::SparseMatrix;
+use crate::stats::{standard_error_from_moments, CulledRow, NoiseFloor};
+use crate::Float;
+use matrix::Matrix;
+
+/// A row becomes dense once more than this fraction of its bins is non-zero.
+/// Inserting into a sorted vector gets slower as it grows, and well before
+/// this point the row is no longer much lighter than a dense one.
+pub(crate) const DENSE_FILL: Float = 0.25;
+
+/// The `(bin, value, square)` of a bin
+type Entry = (usize, Float, Float);
+
+/// How the bins of a [`BinAccumulator`] are stored
+#[derive(Debug, Clone)]
+enum Bins {
+    /// The `(bin, value, square)` of the bins that were added to, sorted by bin
+    Sparse(Vec<Entry>),
+
+    /// The value and square of every bin
+    Dense {
+        values: Vec<Float>,
+        squares: Vec<Float>,
+    },
+}
+
+/// Accumulates the value (e.g., the coefficient) and the square (e.g., the
+/// mean of the squared contributions) of each bin of a row. Each bin adds up
+/// the same numbers in the same order whether the row is sparse or dense, so
+/// the results do not depend on the representation.
+#[derive(Debug, Clone)]
+pub(crate) struct BinAccumulator {
+    n_bins: usize,
+    bins: Bins,
+}
+
+impl BinAccumulator {
+    /// An empty (sparse) row of `n_bins` bins
+    pub(crate) fn new(n_bins: usize) -> Self {
+        Self {
+            n_bins,
+            bins: Bins::Sparse(Vec::new()),
+        }
+    }
+
+    /// Adds to the value and square of a bin
+    pub(crate) fn add(&mut self, bin: usize, value: Float, square: Float) {
+        debug_assert!(bin < self.n_bins);
+        match &mut self.bins {
+            Bins::Dense { values, squares } => {
+                values[bin] += value;
+                squares[bin] += square;
+            }
+            Bins::Sparse(entries) => {
+                match entries.binary_search_by_key(&bin, |e| e.0) {
+                    Ok(i) => {
+                        entries[i].1 += value;
+                        entries[i].2 += square;
+                    }
+                    // Starting from zero, so the sums are the same as when dense
+                    Err(i) => entries.insert(i, (bin, 0.0 + value, 0.0 + square)),
+                }
+                if entries.len() as Float > DENSE_FILL * self.n_bins as Float {
+                    self.make_dense();
+                }
+            }
+        }
+    }
+
+    /// Switches to the dense representation, if it was not already
+    fn make_dense(&mut self) {
+        if let Bins::Sparse(entries) = &self.bins {
+            let mut values = vec![0.0; self.n_bins];
+            let mut squares = vec![0.0; self.n_bins];
+            for (bin, v, sq) in entries {
+                values[*bin] = *v;
+                squares[*bin] = *sq;
+            }
+            self.bins = Bins::Dense { values, squares };
+        }
+    }
+
+    /// The `(bin, value, square)` of every bin that may be non-zero, sorted
+    /// by bin. All the bins of dense rows are included.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Entry> + '_ {
+        let (sparse, dense) = match &self.bins {
+            Bins::Sparse(entries) => (entries.as_slice(), None),
+            Bins::Dense { values, squares } => (&[] as &[Entry], Some(values.iter().zip(squares))),
+        };
+        let dense = dense.into_iter().flatten().enumerate();
+        sparse
+            .iter()
+            .copied()
+            .chain(dense.map(|(bin, (v, sq))| (bin, *v, *sq)))
+    }
+
+    /// The value of every bin
+    pub(crate) fn values(&self) -> Vec<Float> {
+        let mut ret = vec![0.0; self.n_bins];
+        for (bin, v, _) in self.iter() {
+            ret[bin] = v;
+        }
+        ret
+    }
+
+    /// Writes the values into a row of a matrix, which must start at zero
+    pub(crate) fn write_row(&self, matrix: &mut Matrix, row: usize) -> Result<(), String> {
+        for (bin, v, _) in self.iter() {
+            matrix.set(row, bin, v)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the values as a row of a sparse matrix, dropping those whose
+    /// absolute value is not larger than `threshold`
+    pub(crate) fn push_into(
+        &self,
+        sparse: &mut SparseMatrix,
+        threshold: Float,
+    ) -> Result<(), String> {
+        sparse.push_row(self.iter().map(|(bin, v, _)| (bin, v)), threshold)
+    }
+
+    /// Culls the values that are mostly noise, given that the squares are the
+    /// mean of the squared contributions of `n_samples` samples (see
+    /// [`NoiseFloor`]). The row becomes dense, as the policy of the floor may
+    /// need every bin.
+    pub(crate) fn cull(&mut self, floor: &NoiseFloor, n_samples: usize) -> CulledRow {
+        self.make_dense();
+        match &mut self.bins {
+            Bins::Dense { values, squares } => {
+                let errors: Vec<Float> = values
+                    .iter()
+                    .zip(squares.iter())
+                    .map(|(v, sq)| standard_error_from_moments(*v, *sq, n_samples))
+                    .collect();
+                floor.cull(values, &errors)
+            }
+            Bins::Sparse(_) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn test_sparse_until_filled() {
+        let n_bins = 100;
+        let mut row = BinAccumulator::new(n_bins);
+        let mut dense = vec![(0.0, 0.0); n_bins];
+        // A few bins, many times and out of order
+        for i in 0..1000 {
+            let bin = (i * 37) % 20;
+            let v = 0.1 + i as Float * 1e-3;
+            row.add(bin, v, v * v);
+            dense[bin].0 += v;
+            dense[bin].1 += v * v;
+        }
+        // Sparse rows only go through the bins that were added to
+        assert_eq!(row.iter().count(), 20);
+        assert!(row.iter().zip(row.iter().skip(1)).all(|(a, b)| a.0 < b.0));
+        for (bin, v, sq) in row.iter() {
+            assert_eq!((v, sq), dense[bin]);
+        }
+
+        // Past the fill threshold, it becomes dense and keeps the same numbers
+        for (bin, d) in dense.iter_mut().enumerate().take(30).skip(20) {
+            row.add(bin, 1., 1.);
+            *d = (1., 1.);
+        }
+        assert!(matches!(row.bins, Bins::Dense { .. }));
+        assert_eq!(row.iter().count(), n_bins);
+        let values: Vec<Float> = dense.iter().map(|d| d.0).collect();
+        assert_eq!(row.values(), values);
+    }
+
+    #[test]
+    fn test_outputs() {
+        let mut row = BinAccumulator::new(5);
+        row.add(3, 2., 4.);
+        row.add(1, 1e-9, 0.0);
+        let mut matrix = Matrix::new(0.0, 2, 5);
+        row.write_row(&mut matrix, 1).unwrap();
+        assert_eq!(matrix.get(1, 3).unwrap(), 2.);
+        assert_eq!(matrix.get(0, 3).unwrap(), 0.);
+
+        let mut sparse = SparseMatrix::new(5);
+        row.push_into(&mut sparse, 1e-6).unwrap();
+        assert_eq!(sparse.nnz(), 1);
+        assert_eq!(sparse.get(0, 3).unwrap(), 2.);
+
+        // A value equal to a single sample (i.e., its standard error) is culled
+        let mut row = BinAccumulator::new(5);
+        row.add(0, 1., 1.);
+        row.add(2, 0.01, 1.);
+        let culled = row.cull(&NoiseFloor::new(1.), 100);
+        assert_eq!(culled.n_culled, 1);
+        assert_eq!(row.values(), vec![1., 0., 0., 0., 0.]);
+    }
+}
@@ -0,0 +1,573 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Skies that are only fine where they need to be.
+//!
+//! In dense urban canyons, most of the daylight arrives through a narrow band
+//! of sky close to the horizon. A uniform Reinhart sky fine enough for that band
+//! is needlessly fine near the zenith, where little (or nothing) is seen. An
+//! [`AdaptiveRowBasis`] subdivides each of the seven rows of the Tregenza sky
+//! by its own multiplier instead: a row with multiplier `m` becomes `m` rows
+//! of `m` times as many patches, as in a Reinhart sky with MF `m`.
+//!
+//! The rows follow those of the Reinhart sky with the largest multiplier (see
+//! [`AdaptiveRowBasis::fine_mf`]), so that its patches are nested into theirs.
+//! Daylight Coefficients are thus traced with that sky and then gathered into
+//! the adaptive patches (see [`DCSession::calc_adaptive_dc`]), and sky vectors
+//! are converted to and from any Reinhart sky by sharing each patch between the
+//! patches it overlaps, in proportion to their solid angles.
+
+use crate::labeled_matrix::LabeledMatrix;
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::sky::{patch_overlaps_between, sky_direction, SkyBasis, SkyPatch, TREGENZA_ROW_PATCHES};
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use rendering::Scene;
+
+/// A Reinhart-like sky whose rows are subdivided by multipliers of their own
+/// (see the [module documentation](self)). Bins are ordered as in a
+/// [`SkyBasis`]: the ground first, and then the patches row by row from the
+/// horizon up, each row starting at North and going towards East.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AdaptiveRowBasis {
+    multipliers: [usize; 7],
+}
+
+impl AdaptiveRowBasis {
+    /// The index of the bin that represents the ground
+    pub const GROUND_BIN: usize = SkyBasis::GROUND_BIN;
+
+    /// A sky whose Tregenza rows—from the horizon up—are subdivided by
+    /// `multipliers` (e.g., `[4, 4, 2, 2, 1, 1, 1]`)
+    pub fn new(multipliers: &[usize]) -> Result<Self, String> {
+        let multipliers: [usize; 7] = multipliers.try_into().map_err(|_| {
+            format!(
+                "An adaptive sky needs one multiplier per Tregenza row (i.e., 7), but found {}",
+                multipliers.len()
+            )
+        })?;
+        if multipliers.contains(&0) {
+            return Err(format!(
+                "The multipliers of an adaptive sky must be at least 1, but found {:?}",
+                multipliers
+            ));
+        }
+        Ok(Self { multipliers })
+    }
+
+    /// The multiplier of each Tregenza row, from the horizon up
+    pub fn multipliers(&self) -> &[usize; 7] {
+        &self.multipliers
+    }
+
+    /// The subdivision of the Reinhart sky whose rows this one follows, which
+    /// is the largest multiplier
+    pub fn fine_mf(&self) -> usize {
+        self.multipliers.iter().copied().max().unwrap_or(1)
+    }
+
+    /// The Reinhart sky whose rows this one follows (see [`AdaptiveRowBasis::fine_mf`])
+    pub fn fine_basis(&self) -> SkyBasis {
+        SkyBasis::unchecked(self.fine_mf())
+    }
+
+    /// The rows of the sky, from the horizon up, as `(min_altitude,
+    /// max_altitude, n_patches)`. Altitudes are in radians, and the last row is
+    /// the zenith cap, which is that of the Reinhart sky of
+    /// [`AdaptiveRowBasis::fine_mf`].
+    pub fn rows(&self) -> Vec<(Float, Float, usize)> {
+        let fine = self.fine_mf() as Float;
+        let band = fine * (0.5 * PI) / (7. * fine + 0.5);
+        let mut ret = Vec::with_capacity(self.multipliers.iter().sum::<usize>() + 1);
+        for (k, m) in self.multipliers.iter().enumerate() {
+            let height = band / *m as Float;
+            let bottom = k as Float * band;
+            for i in 0..*m {
+                ret.push((
+                    bottom + i as Float * height,
+                    bottom + (i + 1) as Float * height,
+                    m * TREGENZA_ROW_PATCHES[k],
+                ));
+            }
+        }
+        ret.push((7. * band, 0.5 * PI, 1));
+        ret
+    }
+
+    /// The number of bins, including the ground
+    pub fn n_bins(&self) -> usize {
+        2 + self
+            .multipliers
+            .iter()
+            .zip(TREGENZA_ROW_PATCHES)
+            .map(|(m, n)| m * m * n)
+            .sum::<usize>()
+    }
+
+    /// The bin that a `direction` (of unit length) falls into: the ground
+    /// (i.e., [`AdaptiveRowBasis::GROUND_BIN`]) for those that point down
+    pub fn bin(&self, direction: Vector3D) -> usize {
+        if direction.z < 0.0 {
+            return Self::GROUND_BIN;
+        }
+        let altitude = direction.z.min(1.).asin();
+        let mut azimuth = direction.x.atan2(direction.y);
+        if azimuth < 0.0 {
+            azimuth += 2. * PI;
+        }
+        let mut first = 1;
+        for (_, max_alt, n) in self.rows() {
+            if altitude < max_alt || n == 1 {
+                let width = 2. * PI / n as Float;
+                return first + ((azimuth + 0.5 * width) / width).floor() as usize % n;
+            }
+            first += n;
+        }
+        unreachable!("the zenith cap takes every direction left")
+    }
+
+    /// The solid angle of each bin. The ground is a full hemisphere.
+    pub fn solid_angles(&self) -> Vec<Float> {
+        let mut ret = Vec::with_capacity(self.n_bins());
+        ret.push(2. * PI);
+        for (min_alt, max_alt, n) in self.rows() {
+            let omega = 2. * PI * (max_alt.sin() - min_alt.sin()) / n as Float;
+            ret.extend(std::iter::repeat_n(omega, n));
+        }
+        ret
+    }
+
+    /// The direction of the centre of each bin. The ground points down.
+    pub fn centroids(&self) -> Vec<Vector3D> {
+        let mut ret = Vec::with_capacity(self.n_bins());
+        ret.push(Vector3D::new(0., 0., -1.));
+        for (min_alt, max_alt, n) in self.rows() {
+            if n == 1 {
+                ret.push(Vector3D::new(0., 0., 1.));
+                continue;
+            }
+            let width = 2. * PI / n as Float;
+            let altitude = 0.5 * (min_alt + max_alt);
+            ret.extend((0..n).map(|j| sky_direction(altitude, j as Float * width)));
+        }
+        ret
+    }
+
+    /// The patches of the sky, in the order of their bins (i.e., without the
+    /// ground, so the first one is bin `1`)
+    pub fn patches(&self) -> Vec<SkyPatch> {
+        let omegas = self.solid_angles();
+        let centroids = self.centroids();
+        let mut ret = Vec::with_capacity(self.n_bins() - 1);
+        for (row, (min_alt, max_alt, n)) in self.rows().into_iter().enumerate() {
+            let width = 2. * PI / n as Float;
+            for j in 0..n {
+                let bin = ret.len() + 1;
+                let centre = j as Float * width;
+                ret.push(SkyPatch {
+                    bin,
+                    row,
+                    altitude: (min_alt, max_alt),
+                    azimuth: (centre - 0.5 * width, centre + 0.5 * width),
+                    solid_angle: omegas[bin],
+                    centroid: centroids[bin],
+                });
+            }
+        }
+        ret
+    }
+
+    /// Checks that a Daylight Coefficient matrix has one column per bin
+    pub fn check_dc(&self, dc: &Matrix) -> Result<(), String> {
+        let (_, ncols) = dc.size();
+        if ncols != self.n_bins() {
+            return Err(format!(
+                "Daylight Coefficient matrix has {} bins, but an adaptive sky with multipliers {:?} has {}",
+                ncols,
+                self.multipliers,
+                self.n_bins()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that a sky vector or matrix has one row per bin
+    pub fn check_sky(&self, sky: &Matrix) -> Result<(), String> {
+        let (nrows, _) = sky.size();
+        if nrows != self.n_bins() {
+            return Err(format!(
+                "Sky vector has {} elements, but an adaptive sky with multipliers {:?} has {} bins",
+                nrows,
+                self.multipliers,
+                self.n_bins()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Calculates, for each bin of `basis`, the fraction of its solid angle
+    /// that falls within each bin of this sky, as `(reinhart_bin,
+    /// adaptive_bin, fraction)` (see [`SkyBasis::overlaps`])
+    pub fn overlaps_from(&self, basis: &SkyBasis) -> Vec<(usize, usize, Float)> {
+        patch_overlaps_between(&basis.patches(), &self.patches())
+    }
+
+    /// Calculates, for each bin of this sky, the fraction of its solid angle
+    /// that falls within each bin of `basis`, as `(adaptive_bin,
+    /// reinhart_bin, fraction)`
+    pub fn overlaps_into(&self, basis: &SkyBasis) -> Vec<(usize, usize, Float)> {
+        patch_overlaps_between(&self.patches(), &basis.patches())
+    }
+
+    /// Converts a Daylight Coefficient matrix calculated with the Reinhart sky
+    /// `basis` into one for this sky, sharing each coefficient between the
+    /// patches it overlaps (as [`SkyBasis::downsample`] does). This is exact for
+    /// the patches nested into those of [`AdaptiveRowBasis::fine_basis`].
+    pub fn dc_from_reinhart(&self, dc: &Matrix, basis: &SkyBasis) -> Result<Matrix, String> {
+        basis.check_dc(dc)?;
+        resample(dc, false, self.overlaps_from(basis), self.n_bins())
+    }
+
+    /// Converts a Daylight Coefficient matrix of this sky into one for the
+    /// Reinhart sky `basis`, so that it can be multiplied by its sky vectors
+    pub fn dc_to_reinhart(&self, dc: &Matrix, basis: &SkyBasis) -> Result<Matrix, String> {
+        self.check_dc(dc)?;
+        resample(dc, false, self.overlaps_into(basis), basis.n_bins())
+    }
+
+    /// Converts sky vectors (one row per bin, and one column per timestep) of
+    /// the Reinhart sky `basis` into vectors of this sky. The radiance of each
+    /// patch is the average of those of the patches it overlaps, weighted by
+    /// the solid angle of the overlap.
+    pub fn sky_from_reinhart(&self, sky: &Matrix, basis: &SkyBasis) -> Result<Matrix, String> {
+        basis.check_sky(sky)?;
+        let overlaps = self
+            .overlaps_into(basis)
+            .into_iter()
+            .map(|(adaptive, reinhart, fraction)| (reinhart, adaptive, fraction))
+            .collect();
+        resample(sky, true, overlaps, self.n_bins())
+    }
+
+    /// Converts sky vectors of this sky into vectors of the Reinhart sky
+    /// `basis`, as [`AdaptiveRowBasis::sky_from_reinhart`] does the other way
+    pub fn sky_to_reinhart(&self, sky: &Matrix, basis: &SkyBasis) -> Result<Matrix, String> {
+        self.check_sky(sky)?;
+        let overlaps = self
+            .overlaps_from(basis)
+            .into_iter()
+            .map(|(reinhart, adaptive, fraction)| (adaptive, reinhart, fraction))
+            .collect();
+        resample(sky, true, overlaps, basis.n_bins())
+    }
+}
+
+/// Adds each bin of a matrix—which are its columns, or its rows if
+/// `bins_in_rows`—into `n_bins` others, as `(from, to, fraction)`
+fn resample(
+    m: &Matrix,
+    bins_in_rows: bool,
+    overlaps: Vec<(usize, usize, Float)>,
+    n_bins: usize,
+) -> Result<Matrix, String> {
+    let (nrows, ncols) = m.size();
+    let mut ret = if bins_in_rows {
+        Matrix::new(0.0, n_bins, ncols)
+    } else {
+        Matrix::new(0.0, nrows, n_bins)
+    };
+    let n_other = if bins_in_rows { ncols } else { nrows };
+    for (from, to, fraction) in overlaps {
+        for i in 0..n_other {
+            let (src, dst) = if bins_in_rows {
+                ((from, i), (to, i))
+            } else {
+                ((i, from), (i, to))
+            };
+            let v = m.get(src.0, src.1)?;
+            if v != 0.0 {
+                ret.set(dst.0, dst.1, ret.get(dst.0, dst.1)? + v * fraction)?;
+            }
+        }
+    }
+    Ok(ret)
+}
+
+impl DCSession {
+    /// Calculates the Daylight Coefficients of some sensors for an
+    /// [`AdaptiveRowBasis`]: they are traced as [`DCSession::calc_sensor_dc`]
+    /// does, and then gathered into its patches. The session must use the sky
+    /// the basis follows (see [`AdaptiveRowBasis::fine_mf`]).
+    pub fn calc_adaptive_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        basis: &AdaptiveRowBasis,
+    ) -> Result<LabeledMatrix, String> {
+        if self.mf() != basis.fine_mf() {
+            return Err(format!(
+                "An adaptive sky with multipliers {:?} follows the Reinhart sky with MF {}, but the session uses MF {}",
+                basis.multipliers(),
+                basis.fine_mf(),
+                self.mf()
+            ));
+        }
+        let mut ret = self.calc_sensor_dc(sensors, scene)?;
+        ret.matrix = basis.dc_from_reinhart(&ret.matrix, &self.basis()?)?;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, Material, SceneBuilder};
+    use geometry3d::{Point3D, Ray3D};
+    use validate::assert_close;
+
+    /// The integral of `f` over each bin (none over the ground), splitting
+    /// each patch into `n` by `n` cells of the same solid angle
+    fn integrate<F: Fn(Vector3D) -> Float>(patches: &[SkyPatch], n: usize, f: F) -> Vec<Float> {
+        let mut ret = vec![0.0];
+        for p in patches {
+            let (s0, s1) = (p.altitude.0.sin(), p.altitude.1.sin());
+            let mut sum = 0.0;
+            for i in 0..n {
+                let altitude = (s0 + (i as Float + 0.5) / n as Float * (s1 - s0)).asin();
+                for j in 0..n {
+                    let t = (j as Float + 0.5) / n as Float;
+                    sum += f(sky_direction(
+                        altitude,
+                        p.azimuth.0 + t * (p.azimuth.1 - p.azimuth.0),
+                    ));
+                }
+            }
+            ret.push(sum * p.solid_angle / (n * n) as Float);
+        }
+        ret
+    }
+
+    fn matrix(values: &[Float], nrows: usize) -> Matrix {
+        let ncols = values.len() / nrows;
+        let mut ret = Matrix::new(0.0, nrows, ncols);
+        for (i, v) in values.iter().enumerate() {
+            ret.set(i / ncols, i % ncols, *v).unwrap();
+        }
+        ret
+    }
+
+    fn dot(dc: &Matrix, sky: &Matrix) -> Float {
+        (0..sky.size().0)
+            .map(|i| dc.get(0, i).unwrap() * sky.get(i, 0).unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn test_rows() {
+        assert!(AdaptiveRowBasis::new(&[1, 1, 1]).is_err());
+        assert!(AdaptiveRowBasis::new(&[4, 4, 2, 0, 1, 1, 1]).is_err());
+
+        // The same multiplier everywhere is a Reinhart sky
+        for mf in [1, 3] {
+            let adaptive = AdaptiveRowBasis::new(&[mf; 7]).unwrap();
+            let reinhart = SkyBasis::new(mf).unwrap();
+            assert_eq!(adaptive.n_bins(), reinhart.n_bins());
+            for (a, b) in adaptive.solid_angles().iter().zip(reinhart.solid_angles()) {
+                assert_close!(*a, b, 1e-5);
+            }
+            for (bin, dir) in reinhart.centroids().iter().enumerate() {
+                assert_eq!(adaptive.bin(*dir), bin);
+            }
+        }
+
+        // Fine near the horizon only
+        let basis = AdaptiveRowBasis::new(&[4, 4, 2, 2, 1, 1, 1]).unwrap();
+        assert_eq!(basis.fine_mf(), 4);
+        assert_eq!(basis.n_bins(), 2 + 16 * 60 + 4 * 48 + 36);
+        assert_eq!(basis.rows().len(), 4 + 4 + 2 + 2 + 1 + 1 + 1 + 1);
+        let patches = basis.patches();
+        assert_eq!(patches.len(), basis.n_bins() - 1);
+        let total: Float = patches.iter().map(|p| p.solid_angle).sum();
+        assert_close!(total, 2. * PI, 1e-4);
+        for p in &patches {
+            assert_eq!(basis.bin(p.centroid), p.bin);
+        }
+        assert_eq!(basis.bin(Vector3D::new(0., 0., 1.)), basis.n_bins() - 1);
+        assert_eq!(
+            basis.bin(Vector3D::new(0.6, 0., -0.8)),
+            AdaptiveRowBasis::GROUND_BIN
+        );
+        assert!(basis.check_dc(&Matrix::new(0.0, 2, basis.n_bins())).is_ok());
+        assert!(basis.check_sky(&Matrix::new(0.0, 578, 1)).is_err());
+    }
+
+    #[test]
+    fn test_resampling() {
+        let basis = AdaptiveRowBasis::new(&[2, 2, 1, 1, 1, 1, 1]).unwrap();
+        let mf1 = SkyBasis::new(1).unwrap();
+        let mf2 = basis.fine_basis();
+
+        // Uniform skies stay uniform, either way
+        let uniform = Matrix::new(3.0, mf1.n_bins(), 2);
+        let sky = basis.sky_from_reinhart(&uniform, &mf1).unwrap();
+        let back = basis.sky_to_reinhart(&sky, &mf2).unwrap();
+        for bin in 0..mf2.n_bins() {
+            assert_close!(back.get(bin, 1).unwrap(), 3.0, 1e-4);
+        }
+
+        // Coefficients are conserved, and give the same result when converted
+        // as the sky they are multiplied with
+        let values: Vec<Float> = (0..basis.n_bins()).map(|i| (i % 7) as Float).collect();
+        let dc = matrix(&values, 1);
+        let total: Float = values.iter().sum();
+        for reinhart in [mf1, mf2, SkyBasis::new(3).unwrap()] {
+            let converted = basis.dc_to_reinhart(&dc, &reinhart).unwrap();
+            let sum: Float = (0..reinhart.n_bins())
+                .map(|i| converted.get(0, i).unwrap())
+                .sum();
+            assert_close!(sum, total, 1e-5 * total);
+            let sky: Vec<Float> = (0..reinhart.n_bins()).map(|i| (i % 5) as Float).collect();
+            let sky = matrix(&sky, reinhart.n_bins());
+            let adaptive_sky = basis.sky_from_reinhart(&sky, &reinhart).unwrap();
+            let expected = dot(&dc, &adaptive_sky);
+            assert_close!(dot(&converted, &sky), expected, 1e-5 * expected);
+        }
+        assert!(basis.dc_from_reinhart(&dc, &mf1).is_err());
+        assert!(basis.sky_to_reinhart(&uniform, &mf1).is_err());
+
+        // Traced with the sky the basis follows
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("floor_mat", Material::plastic(0.2))
+            .unwrap();
+        let vertices =
+            [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        builder
+            .add_polygon("floor_mat", "floor", &vertices)
+            .unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.5),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 1000,
+            ..DCOptions::default()
+        };
+        let dc = DCSession::new(2, options)
+            .calc_adaptive_dc(&sensors, &scene, &basis)
+            .unwrap();
+        basis.check_dc(&dc.matrix).unwrap();
+        let sum: Float = (0..basis.n_bins())
+            .map(|i| dc.matrix.get(0, i).unwrap())
+            .sum();
+        assert_close!(sum, PI, 0.05);
+        let err = DCSession::new(1, options)
+            .calc_adaptive_dc(&sensors, &scene, &basis)
+            .unwrap_err();
+        assert!(err.contains("MF 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_urban_canyon() {
+        // A window at street level facing South, under the balcony of the floor
+        // above and down a long street: it sees the sky between 2 and 20 degrees
+        // above the horizon, within 30 degrees of the axis of the street
+        let normal = Vector3D::new(0., -1., 0.);
+        let dc = |d: Vector3D| -> Float {
+            let altitude = d.z.asin().to_degrees();
+            let off_axis = d.x.atan2(-d.y).to_degrees().abs();
+            if (2.0..20.).contains(&altitude) && off_axis < 30. {
+                (d * normal).max(0.0)
+            } else {
+                0.0
+            }
+        };
+        // Low suns, on a sky that brightens towards the horizon
+        let suns: Vec<Vector3D> = [
+            (5., 150.),
+            (12., 170.),
+            (8., 200.),
+            (17., 185.),
+            (30., 180.),
+        ]
+        .iter()
+        .map(|(alt, az): &(Float, Float)| sky_direction(alt.to_radians(), az.to_radians()))
+        .collect();
+        let sky = |sun: Vector3D| {
+            move |d: Vector3D| -> Float {
+                let angle = (d * sun).min(1.).acos().to_degrees();
+                1. + 2. * (1. - d.z) + 500. * (-(angle / 4.).powi(2)).exp()
+            }
+        };
+        let reference: Vec<Float> = suns
+            .iter()
+            .map(|sun| {
+                let f = sky(*sun);
+                let cells = integrate(&SkyBasis::unchecked(12).patches(), 6, |d| dc(d) * f(d));
+                cells.iter().sum()
+            })
+            .collect();
+
+        // The relative error of the annual result, with the coefficients and
+        // the sky vectors of some Reinhart sky (converted to another one, if any)
+        let error = |basis: &SkyBasis, adaptive: Option<&AdaptiveRowBasis>| -> Float {
+            let patches = basis.patches();
+            let row = integrate(&patches, 8, dc);
+            let mut coefficients = matrix(&row, 1);
+            if let Some(adaptive) = adaptive {
+                coefficients = adaptive.dc_from_reinhart(&coefficients, basis).unwrap();
+            }
+            let (mut total, mut exact) = (0.0, 0.0);
+            for (sun, reference) in suns.iter().zip(&reference) {
+                let radiance: Vec<Float> = integrate(&patches, 8, sky(*sun))
+                    .iter()
+                    .zip(basis.solid_angles())
+                    .map(|(v, omega)| v / omega)
+                    .collect();
+                let mut radiance = matrix(&radiance, basis.n_bins());
+                if let Some(adaptive) = adaptive {
+                    radiance = adaptive.sky_from_reinhart(&radiance, basis).unwrap();
+                }
+                total += (dot(&coefficients, &radiance) - reference).abs();
+                exact += reference;
+            }
+            total / exact
+        };
+        let errors: Vec<Float> = (1..=4)
+            .map(|mf| error(&SkyBasis::unchecked(mf), None))
+            .collect();
+        assert!(errors.windows(2).all(|e| e[1] < e[0]), "{:?}", errors);
+
+        // As good as MF 4, with fewer bins than MF 3
+        let adaptive = AdaptiveRowBasis::new(&[4, 4, 2, 2, 1, 1, 1]).unwrap();
+        let fine = adaptive.fine_basis();
+        let e = error(&fine, Some(&adaptive));
+        assert!(e < 0.02, "{}", e);
+        assert_close!(e, errors[3], 1e-3);
+        assert!(e < 0.5 * errors[2], "{} vs {:?}", e, errors);
+        assert!(adaptive.n_bins() < SkyBasis::unchecked(3).n_bins());
+    }
+}
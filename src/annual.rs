@@ -0,0 +1,494 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::dtype::{from_wide, Wide};
+use crate::sky::SkyBasis;
+use crate::sparse::SparseMatrix;
+use crate::time::{AnnualSeries, TimeAxis};
+use crate::tracker::TrackerSpec;
+use crate::Float;
+use geometry3d::Vector3D;
+use matrix::Matrix;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Checks that a Daylight Coefficient matrix and a sky
+/// matrix can be multiplied
+fn check_sky_shape(dc: &Matrix, skies: &Matrix) -> Result<(), String> {
+    let (_, dc_cols) = dc.size();
+    let (sky_rows, _) = skies.size();
+    if dc_cols != sky_rows {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} bins but the sky matrix has {}",
+            dc_cols, sky_rows
+        ));
+    }
+    Ok(())
+}
+
+/// Something that can be multiplied by a sky matrix—with one column per
+/// timestep and one row per bin, as produced by `gendaymtx`—resulting in
+/// one row per sensor and one column per timestep.
+///
+/// Products are accumulated in [`Wide`] and rounded into [`Float`] once (see
+/// [`crate::dtype`]), so the results of `f32` and `f64` builds only differ
+/// by the precision of their inputs and outputs.
+pub trait ApplySky {
+    /// Applies the skies
+    fn apply_sky(&self, skies: &Matrix) -> Result<Matrix, String>;
+}
+
+impl ApplySky for Matrix {
+    fn apply_sky(&self, skies: &Matrix) -> Result<Matrix, String> {
+        apply_annual(self, skies)
+    }
+}
+
+impl ApplySky for SparseMatrix {
+    fn apply_sky(&self, skies: &Matrix) -> Result<Matrix, String> {
+        self.mul_dense(skies)
+    }
+}
+
+/// Applies a sky matrix to a (dense or sparse) Daylight Coefficient matrix.
+/// See [`ApplySky`].
+pub fn annual_irradiance<M: ApplySky>(dc: &M, skies: &Matrix) -> Result<Matrix, String> {
+    dc.apply_sky(skies)
+}
+
+/// The number of sensors processed together by [`apply_annual`]
+const ROW_BLOCK: usize = 32;
+
+/// The number of bins processed together by [`apply_annual`]
+const BIN_BLOCK: usize = 128;
+
+/// The number of timesteps processed together by [`apply_annual`]
+const STEP_BLOCK: usize = 1024;
+
+/// Sky matrices with fewer non-zero elements than this fraction are
+/// applied by [`apply_annual`] without going through their zeros
+pub const SPARSE_SKY_DENSITY: Float = 0.05;
+
+/// Copies a matrix into a row-major vector
+pub(crate) fn to_row_major(m: &Matrix) -> Result<Vec<Float>, String> {
+    let (nrows, ncols) = m.size();
+    let mut ret = Vec::with_capacity(nrows * ncols);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            ret.push(m.get(r, c)?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Multiplies a Daylight Coefficient matrix by an annual sky matrix (one row per bin
+/// and one column per timestep), which gives one row per sensor and one column
+/// per timestep.
+///
+/// This is a blocked matrix-matrix multiplication—parallel over blocks of sensors if
+/// the `parallel` feature is enabled—rather than one multiplication per sky vector.
+/// Skies with very few non-zero elements (e.g., the sun-only matrices, with up to
+/// [`GENDAYMTX_SUN_PATCHES`](crate::sky::GENDAYMTX_SUN_PATCHES) patches per
+/// timestep) take a dedicated path that only visits the non-zero patches of each
+/// timestep (see [`SPARSE_SKY_DENSITY`]).
+pub fn apply_annual(dc: &Matrix, skies: &Matrix) -> Result<Matrix, String> {
+    check_sky_shape(dc, skies)?;
+    let (n_sensors, n_bins) = dc.size();
+    let (_, n_steps) = skies.size();
+    let a = to_row_major(dc)?;
+    let b = to_row_major(skies)?;
+    let nnz = b.iter().filter(|v| **v != 0.0).count();
+    let mut out: Vec<Wide> = vec![0.0; n_sensors * n_steps];
+    if n_steps == 0 || n_bins == 0 {
+        return Ok(Matrix::new(0.0, n_sensors, n_steps));
+    }
+
+    let sparse = (nnz as Float) < SPARSE_SKY_DENSITY * b.len() as Float;
+    let patches: Vec<Vec<(usize, Float)>> = if sparse {
+        // The non-zero patches of each timestep
+        let mut patches = vec![Vec::new(); n_steps];
+        for (i, v) in b.iter().enumerate() {
+            if *v != 0.0 {
+                patches[i % n_steps].push((i / n_steps, *v));
+            }
+        }
+        patches
+    } else {
+        Vec::new()
+    };
+
+    let multiply_block = |(block, out): (usize, &mut [Wide])| {
+        let first_row = block * ROW_BLOCK;
+        let n_rows = out.len() / n_steps;
+        if sparse {
+            for r in 0..n_rows {
+                let dc_row = &a[(first_row + r) * n_bins..(first_row + r + 1) * n_bins];
+                let out_row = &mut out[r * n_steps..(r + 1) * n_steps];
+                for (v, step) in out_row.iter_mut().zip(patches.iter()) {
+                    *v = step
+                        .iter()
+                        .map(|(bin, s)| Wide::from(dc_row[*bin]) * Wide::from(*s))
+                        .sum();
+                }
+            }
+            return;
+        }
+        for step0 in (0..n_steps).step_by(STEP_BLOCK) {
+            let step1 = (step0 + STEP_BLOCK).min(n_steps);
+            for bin0 in (0..n_bins).step_by(BIN_BLOCK) {
+                let bin1 = (bin0 + BIN_BLOCK).min(n_bins);
+                for r in 0..n_rows {
+                    let dc_row = &a[(first_row + r) * n_bins..(first_row + r + 1) * n_bins];
+                    let out_row = &mut out[r * n_steps + step0..r * n_steps + step1];
+                    for bin in bin0..bin1 {
+                        let coef = Wide::from(dc_row[bin]);
+                        if coef == 0.0 {
+                            continue;
+                        }
+                        let sky_row = &b[bin * n_steps + step0..bin * n_steps + step1];
+                        for (v, s) in out_row.iter_mut().zip(sky_row.iter()) {
+                            *v += coef * Wide::from(*s);
+                        }
+                    }
+                }
+            }
+        }
+    };
+    #[cfg(feature = "parallel")]
+    out.par_chunks_mut(ROW_BLOCK * n_steps)
+        .enumerate()
+        .for_each(multiply_block);
+    #[cfg(not(feature = "parallel"))]
+    out.chunks_mut(ROW_BLOCK * n_steps)
+        .enumerate()
+        .for_each(multiply_block);
+
+    from_wide(n_sensors, n_steps, &out)
+}
+
+/// Like [`annual_irradiance`], for skies whose columns are the timesteps of
+/// an `axis`, which the result keeps
+pub fn annual_series<M: ApplySky>(
+    dc: &M,
+    skies: &Matrix,
+    axis: &TimeAxis,
+) -> Result<AnnualSeries, String> {
+    axis.check_columns(skies)?;
+    AnnualSeries::new(dc.apply_sky(skies)?, *axis)
+}
+
+/// Like [`annual_irradiance`], but also checks that the skies—and therefore the
+/// Daylight Coefficient matrix—follow a certain [`SkyBasis`], so that skies
+/// generated for another subdivision are reported as such.
+pub fn annual_irradiance_in_basis<M: ApplySky>(
+    dc: &M,
+    basis: &SkyBasis,
+    skies: &Matrix,
+) -> Result<Matrix, String> {
+    basis.check_sky(skies)?;
+    dc.apply_sky(skies)
+}
+
+/// Propagates the standard error of each coefficient (see
+/// [`DCStats::bin_standard_errors`](crate::stats::DCStats)) into the standard error of
+/// the results of applying a sky matrix. The errors of the coefficients are assumed
+/// to be independent, so the error of each result is `sqrt(sum((se * sky)^2))`.
+pub fn annual_standard_error(
+    bin_standard_errors: &Matrix,
+    skies: &Matrix,
+) -> Result<Matrix, String> {
+    check_sky_shape(bin_standard_errors, skies)?;
+    let (nrows, n_bins) = bin_standard_errors.size();
+    let (_, n_steps) = skies.size();
+    let mut ret = Matrix::new(0.0, nrows, n_steps);
+    for r in 0..nrows {
+        for step in 0..n_steps {
+            let mut v = 0.0;
+            for bin in 0..n_bins {
+                let x = bin_standard_errors.get(r, bin)? * skies.get(bin, step)?;
+                v += x * x;
+            }
+            ret.set(r, step, v.sqrt())?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Builds the band `values ± z * standard_errors` (e.g., `z = 1.96` for a 95%
+/// confidence interval), returning the lower and upper limits.
+pub fn uncertainty_band(
+    values: &Matrix,
+    standard_errors: &Matrix,
+    z: Float,
+) -> Result<(Matrix, Matrix), String> {
+    if values.size() != standard_errors.size() {
+        return Err(format!(
+            "Values are {:?} but their standard errors are {:?}",
+            values.size(),
+            standard_errors.size()
+        ));
+    }
+    let (nrows, ncols) = values.size();
+    let mut lower = Matrix::new(0.0, nrows, ncols);
+    let mut upper = Matrix::new(0.0, nrows, ncols);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            let v = values.get(r, c)?;
+            let e = z * standard_errors.get(r, c)?;
+            lower.set(r, c, v - e)?;
+            upper.set(r, c, v + e)?;
+        }
+    }
+    Ok((lower, upper))
+}
+
+/// Like [`annual_irradiance`], but for a set of points on a [`TrackerSpec`]. The rows
+/// of `dc` must contain the coefficients of each point in each of the
+/// rotations of the tracker, as produced by [`TrackerSpec::sensors`]. At each
+/// timestep, the row of the rotation chosen by the tracker for the
+/// corresponding element of `sun_directions` is used.
+///
+/// The result has one row per point and one column per timestep.
+pub fn tracker_annual_irradiance(
+    dc: &Matrix,
+    tracker: &TrackerSpec,
+    skies: &Matrix,
+    sun_directions: &[Option<Vector3D>],
+) -> Result<Matrix, String> {
+    tracker.validate()?;
+    check_sky_shape(dc, skies)?;
+    let (dc_rows, n_bins) = dc.size();
+    let (_, n_steps) = skies.size();
+    if sun_directions.len() != n_steps {
+        return Err(format!(
+            "The sky matrix has {} timesteps but {} sun directions were given",
+            n_steps,
+            sun_directions.len()
+        ));
+    }
+    let n_rotations = tracker.n_rotations();
+    if dc_rows % n_rotations != 0 {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} rows, which is not a multiple of the {} rotations of the tracker",
+            dc_rows, n_rotations
+        ));
+    }
+    let n_points = dc_rows / n_rotations;
+
+    let mut ret = Matrix::new(0.0, n_points, n_steps);
+    for (step, sun) in sun_directions.iter().enumerate() {
+        let rotation = tracker.select_rotation(*sun);
+        for point in 0..n_points {
+            let row = point * n_rotations + rotation;
+            let mut v = 0.0;
+            for bin in 0..n_bins {
+                v += dc.get(row, bin)? * skies.get(bin, step)?;
+            }
+            ret.set(point, step, v)?;
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::test_support::pseudo_random;
+    use crate::tracker::TrackingAlgorithm;
+    use validate::assert_close;
+
+    #[test]
+    fn test_annual_irradiance() {
+        let mut dc = Matrix::new(0.0, 2, 3);
+        dc.set(0, 1, 1.).unwrap();
+        dc.set(1, 2, 2.).unwrap();
+        let skies = Matrix::new(10.0, 3, 4);
+        let res = annual_irradiance(&dc, &skies).unwrap();
+        assert_eq!(res.size(), (2, 4));
+        assert_close!(res.get(1, 3).unwrap(), 20., 1e-9);
+
+        let wrong = Matrix::new(1., 4, 4);
+        assert!(annual_irradiance(&dc, &wrong).is_err());
+
+        // Sparse gives the same
+        let sparse = SparseMatrix::from_dense(&dc, 0.0).unwrap();
+        let res = annual_irradiance(&sparse, &skies).unwrap();
+        assert_close!(res.get(1, 3).unwrap(), 20., 1e-9);
+        assert_close!(res.get(0, 0).unwrap(), 10., 1e-9);
+        assert!(annual_irradiance(&sparse, &wrong).is_err());
+
+        // Skies of the wrong subdivision are refused
+        let basis = SkyBasis::new(1).unwrap();
+        let dc = Matrix::new(1.0, 2, basis.n_bins());
+        let skies = Matrix::new(1.0, basis.n_bins(), 3);
+        assert!(annual_irradiance_in_basis(&dc, &basis, &skies).is_ok());
+        let fine = SkyBasis::new(2).unwrap();
+        let err = annual_irradiance_in_basis(&dc, &fine, &skies).unwrap_err();
+        assert!(err.contains("MF 2"));
+    }
+
+    /// The naive multiplication, for comparison
+    fn naive(dc: &Matrix, skies: &Matrix) -> Matrix {
+        let (nrows, n_bins) = dc.size();
+        let (_, n_steps) = skies.size();
+        let mut ret = Matrix::new(0.0, nrows, n_steps);
+        for r in 0..nrows {
+            for step in 0..n_steps {
+                let mut v = 0.0;
+                for bin in 0..n_bins {
+                    v += dc.get(r, bin).unwrap() * skies.get(bin, step).unwrap();
+                }
+                ret.set(r, step, v).unwrap();
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn test_apply_annual() {
+        // Odd sizes, so that blocks are not full
+        let (n_sensors, n_bins, n_steps) = (ROW_BLOCK + 3, BIN_BLOCK + 17, STEP_BLOCK + 5);
+        let dc = pseudo_random(n_sensors, n_bins, 1);
+        let dense = pseudo_random(n_bins, n_steps, 2);
+        let mut sun = Matrix::new(0.0, n_bins, n_steps);
+        for step in 0..n_steps {
+            for k in 0..(step % 5) {
+                sun.set((step * 31 + k * 7) % n_bins, step, 100. + k as Float)
+                    .unwrap();
+            }
+        }
+        for skies in [dense, sun] {
+            let res = apply_annual(&dc, &skies).unwrap();
+            let expected = naive(&dc, &skies);
+            assert_eq!(res.size(), expected.size());
+            for r in 0..n_sensors {
+                for step in 0..n_steps {
+                    let e = expected.get(r, step).unwrap();
+                    assert_close!(res.get(r, step).unwrap(), e, 1e-6 * (1. + e.abs()));
+                }
+            }
+        }
+        assert!(apply_annual(&dc, &Matrix::new(0.0, n_bins + 1, 3)).is_err());
+        let empty = apply_annual(&dc, &Matrix::new(0.0, n_bins, 0)).unwrap();
+        assert_eq!(empty.size(), (n_sensors, 0));
+    }
+
+    #[test]
+    fn test_mixed_precision() {
+        use crate::dtype::{round_to, to_wide, Dtype};
+
+        // A Daylight Coefficient matrix read from an f32 artifact, and a sky
+        // with the precision of this build
+        let (n_sensors, n_bins, n_steps) = (3, 40, 7);
+        let dc = round_to(&pseudo_random(n_sensors, n_bins, 3), Dtype::F32).unwrap();
+        let skies = pseudo_random(n_bins, n_steps, 4);
+        let a = to_wide(&dc).unwrap();
+        let b = to_wide(&skies).unwrap();
+
+        let dense = dc.apply_sky(&skies).unwrap();
+        let sparse = SparseMatrix::from_dense(&dc, 0.0)
+            .unwrap()
+            .apply_sky(&skies)
+            .unwrap();
+        for r in 0..n_sensors {
+            for step in 0..n_steps {
+                // Promoted, accumulated and rounded once
+                let expected: Wide = (0..n_bins)
+                    .map(|bin| a[r * n_bins + bin] * b[bin * n_steps + step])
+                    .sum();
+                assert_close!(dense.get(r, step).unwrap(), expected as Float, 1e-12);
+                assert_eq!(sparse.get(r, step).unwrap(), expected as Float);
+            }
+        }
+    }
+
+    /// Run with `cargo test --release --features parallel -- --ignored bench_apply_annual --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_apply_annual() {
+        let n_bins = solar::ReinhartSky::n_bins(4);
+        let (n_sensors, n_steps) = (10_000, 8760);
+        let dc = pseudo_random(n_sensors, n_bins, 1);
+        let skies = pseudo_random(n_bins, n_steps, 2);
+        let start = std::time::Instant::now();
+        let res = apply_annual(&dc, &skies).unwrap();
+        println!(
+            "{} sensors x {} bins x {} timesteps: {:?}",
+            n_sensors,
+            n_bins,
+            n_steps,
+            start.elapsed()
+        );
+        assert_eq!(res.size(), (n_sensors, n_steps));
+    }
+
+    #[test]
+    fn test_uncertainty() {
+        let mut se = Matrix::new(0.0, 1, 2);
+        se.set(0, 0, 3.).unwrap();
+        se.set(0, 1, 4.).unwrap();
+        let skies = Matrix::new(1.0, 2, 2);
+        let res = annual_standard_error(&se, &skies).unwrap();
+        assert_close!(res.get(0, 1).unwrap(), 5., 1e-9);
+
+        let values = Matrix::new(10.0, 1, 2);
+        let (lower, upper) = uncertainty_band(&values, &res, 2.).unwrap();
+        assert_close!(lower.get(0, 0).unwrap(), 0., 1e-9);
+        assert_close!(upper.get(0, 0).unwrap(), 20., 1e-9);
+        assert!(uncertainty_band(&se, &Matrix::new(0., 2, 2), 1.).is_err());
+    }
+
+    #[test]
+    fn test_tracker_row_selection() {
+        let tracker = TrackerSpec {
+            axis_azimuth: 0.,
+            max_rotation: 60.,
+            rotations: vec![-30., 30.],
+            algorithm: TrackingAlgorithm::TrueTracking,
+        };
+
+        // One point, whose rows only see bins 1 (West rotation) and 2 (East rotation)
+        let mut dc = Matrix::new(0.0, 2, 3);
+        dc.set(0, 1, 1.).unwrap();
+        dc.set(1, 2, 1.).unwrap();
+
+        // A synthetic sky, where bin 1 is always at 100 and bin 2 at 200
+        let n_steps = 3;
+        let mut skies = Matrix::new(0.0, 3, n_steps);
+        for step in 0..n_steps {
+            skies.set(1, step, 100.).unwrap();
+            skies.set(2, step, 200.).unwrap();
+        }
+
+        let suns = vec![
+            Some(Vector3D::new(1., 0., 1.).get_normalized()), // morning -> East
+            Some(Vector3D::new(-1., 0., 1.).get_normalized()), // afternoon -> West
+            None, // night -> flat, ties go to the first rotation
+        ];
+        let res = tracker_annual_irradiance(&dc, &tracker, &skies, &suns).unwrap();
+        assert_eq!(res.size(), (1, n_steps));
+        assert_close!(res.get(0, 0).unwrap(), 200., 1e-9);
+        assert_close!(res.get(0, 1).unwrap(), 100., 1e-9);
+        assert_close!(res.get(0, 2).unwrap(), 100., 1e-9);
+
+        assert!(tracker_annual_irradiance(&dc, &tracker, &skies, &suns[..2]).is_err());
+    }
+}
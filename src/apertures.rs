@@ -0,0 +1,618 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Light-loss factors of apertures (e.g., dirt on the glass, or the fraction
+//! taken by the frame), applied to the Daylight Coefficients instead of being
+//! baked into the materials of the windows.
+//!
+//! Windows are grouped into [`ApertureGroup`]s, and the paths traced by
+//! [`DCSession::calc_aperture_dc`] remember which groups they went through. This
+//! splits the coefficients into components that can be scaled independently,
+//! so factors only affect what came through their windows, and can change
+//! from one month to another without tracing again.
+
+use crate::annual::apply_annual;
+use crate::scene_loading::SceneReport;
+use crate::sensitivity::{
+    diffuse_reflectance, glass_transmissivity, material_responses, LambertianTracer, Response,
+};
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::sky::SkyBasis;
+use crate::time::{AnnualSeries, TimeAxis};
+use crate::Float;
+use matrix::Matrix;
+use rendering::Scene;
+use std::collections::HashMap;
+
+/// The factors that scale the light coming through an aperture. All of
+/// them are between `0` and `1`, and `1` means no loss.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightLossFactors {
+    /// The fraction of the aperture that is glazed (i.e., not frame)
+    pub frame: Float,
+
+    /// The maintenance factor of the glazing in each month, starting in
+    /// January (e.g., to account for dirt accumulating in dry seasons)
+    pub dirt: [Float; 12],
+}
+
+impl std::default::Default for LightLossFactors {
+    fn default() -> Self {
+        Self {
+            frame: 1.,
+            dirt: [1.; 12],
+        }
+    }
+}
+
+impl LightLossFactors {
+    /// Creates factors with a maintenance factor for each month
+    pub fn new(frame: Float, dirt: [Float; 12]) -> Result<Self, String> {
+        let ret = Self { frame, dirt };
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    /// Creates factors with the same maintenance factor all year round
+    pub fn constant(frame: Float, dirt: Float) -> Result<Self, String> {
+        Self::new(frame, [dirt; 12])
+    }
+
+    /// Checks that every factor is between `0` and `1`
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.).contains(&self.frame) {
+            return Err(format!(
+                "Frame factors must be between 0 and 1... found {}",
+                self.frame
+            ));
+        }
+        if let Some(d) = self.dirt.iter().find(|d| !(0.0..=1.).contains(*d)) {
+            return Err(format!(
+                "Dirt depreciation factors must be between 0 and 1... found {}",
+                d
+            ));
+        }
+        Ok(())
+    }
+
+    /// The overall factor in a `month` (from `1` to `12`)
+    pub fn factor(&self, month: usize) -> Result<Float, String> {
+        if !(1..=12).contains(&month) {
+            return Err(format!(
+                "Months go from 1 to 12, but {} was requested",
+                month
+            ));
+        }
+        Ok(self.frame * self.dirt[month - 1])
+    }
+}
+
+/// A set of surfaces (e.g., the panes of the windows of one façade) that
+/// share their light-loss factors
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApertureGroup {
+    /// The name of the group
+    pub name: String,
+
+    /// The identifiers of the surfaces in the group, which must be `glass`
+    pub surfaces: Vec<String>,
+
+    /// The factors of the group
+    pub light_loss: LightLossFactors,
+}
+
+impl ApertureGroup {
+    /// Creates a new group
+    pub fn new(name: &str, surfaces: &[&str], light_loss: LightLossFactors) -> Self {
+        Self {
+            name: name.to_string(),
+            surfaces: surfaces.iter().map(|s| s.to_string()).collect(),
+            light_loss,
+        }
+    }
+}
+
+/// Daylight Coefficients split by the aperture groups their paths went
+/// through, as calculated by [`DCSession::calc_aperture_dc`]
+#[derive(Debug, Clone)]
+pub struct ApertureDC {
+    /// The discretisation of the sky of the coefficients
+    pub basis: SkyBasis,
+
+    /// The groups, in the order in which their factors are given to
+    /// [`ApertureDC::dc`]
+    pub groups: Vec<ApertureGroup>,
+
+    /// The number of times the paths went through each group, and the
+    /// coefficients (one row per sensor and one column per bin) of those paths
+    components: Vec<(Vec<u32>, Matrix)>,
+
+    /// The number of paths of each sensor that were skipped (see
+    /// [`ReflectanceTallies::skipped`](crate::ReflectanceTallies::skipped))
+    pub skipped: Vec<usize>,
+}
+
+impl ApertureDC {
+    /// Adds up the components, scaled by the factor of each group. `factor`
+    /// returns the factor of the `i`-th group.
+    fn scaled<F>(&self, factor: F) -> Result<Matrix, String>
+    where
+        F: Fn(usize) -> Result<Float, String>,
+    {
+        let factors = (0..self.groups.len())
+            .map(factor)
+            .collect::<Result<Vec<Float>, String>>()?;
+        let (nrows, _) = self.components[0].1.size();
+        let mut ret = Matrix::new(0.0, nrows, self.basis.n_bins());
+        for (crossings, component) in self.components.iter() {
+            let scale = crossings
+                .iter()
+                .zip(factors.iter())
+                .fold(1., |s, (n, f)| s * f.powi(*n as i32));
+            add_scaled(&mut ret, component, scale)?;
+        }
+        Ok(ret)
+    }
+
+    /// The coefficients with one factor per group (e.g., `1` for every group
+    /// gives the coefficients without any losses)
+    pub fn dc(&self, factors: &[Float]) -> Result<Matrix, String> {
+        if factors.len() != self.groups.len() {
+            return Err(format!(
+                "There are {} aperture groups, but {} factors were given",
+                self.groups.len(),
+                factors.len()
+            ));
+        }
+        self.scaled(|i| Ok(factors[i]))
+    }
+
+    /// The coefficients with the factors of each group in a `month` (from
+    /// `1` to `12`)
+    pub fn monthly_dc(&self, month: usize) -> Result<Matrix, String> {
+        self.scaled(|i| self.groups[i].light_loss.factor(month))
+    }
+
+    /// The coefficients of the paths that did not go through any group
+    pub fn untagged(&self) -> Result<Matrix, String> {
+        self.scaled(|_| Ok(0.0))
+    }
+
+    /// The coefficients of the paths that went through the group called
+    /// `name` (and maybe through others, without their factors)
+    pub fn through(&self, name: &str) -> Result<Matrix, String> {
+        let g = self
+            .groups
+            .iter()
+            .position(|g| g.name == name)
+            .ok_or_else(|| format!("There is no aperture group called '{}'", name))?;
+        let (nrows, _) = self.components[0].1.size();
+        let mut ret = Matrix::new(0.0, nrows, self.basis.n_bins());
+        for (crossings, component) in self.components.iter() {
+            if crossings[g] > 0 {
+                add_scaled(&mut ret, component, 1.)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Applies an annual sky matrix (one row per bin and one column per
+    /// timestep of the `axis`), using the factors of the month of each timestep.
+    /// The result has one row per sensor and one column per timestep.
+    pub fn annual(&self, skies: &Matrix, axis: &TimeAxis) -> Result<AnnualSeries, String> {
+        self.basis.check_sky(skies)?;
+        axis.check_columns(skies)?;
+        let (n_bins, n_steps) = skies.size();
+        let months: Vec<usize> = axis.timesteps().iter().map(|t| t.month()).collect();
+        let (nrows, _) = self.components[0].1.size();
+        let mut ret = Matrix::new(0.0, nrows, n_steps);
+        for month in 1..=12 {
+            let steps: Vec<usize> = (0..n_steps).filter(|i| months[*i] == month).collect();
+            if steps.is_empty() {
+                continue;
+            }
+            let mut month_skies = Matrix::new(0.0, n_bins, steps.len());
+            for (c, step) in steps.iter().enumerate() {
+                for bin in 0..n_bins {
+                    month_skies.set(bin, c, skies.get(bin, *step)?)?;
+                }
+            }
+            let values = apply_annual(&self.monthly_dc(month)?, &month_skies)?;
+            for (c, step) in steps.iter().enumerate() {
+                for r in 0..nrows {
+                    ret.set(r, *step, values.get(r, c)?)?;
+                }
+            }
+        }
+        AnnualSeries::new(ret, *axis)
+    }
+}
+
+/// The group (within `groups`) of each surface of the `report`, if any, checking
+/// that groups are not repeated, and that their surfaces exist, are made of
+/// glass and belong to a single group
+pub(crate) fn surface_groups(
+    report: &SceneReport,
+    groups: &[ApertureGroup],
+) -> Result<Vec<Option<usize>>, String> {
+    let mut ret: Vec<Option<usize>> = vec![None; report.surfaces.len()];
+    for (g, group) in groups.iter().enumerate() {
+        if groups[..g].iter().any(|other| other.name == group.name) {
+            return Err(format!("Aperture group '{}' is repeated", group.name));
+        }
+        for name in group.surfaces.iter() {
+            let s = report
+                .surfaces
+                .iter()
+                .position(|s| s.name == *name)
+                .ok_or_else(|| format!("There is no surface called '{}' in the scene", name))?;
+            let material = report
+                .materials
+                .iter()
+                .find(|m| m.name == report.surfaces[s].modifier);
+            if !matches!(material, Some(m) if m.kind == "glass") {
+                return Err(format!(
+                    "Surface '{}' of aperture group '{}' is not made of glass",
+                    name, group.name
+                ));
+            }
+            if let Some(other) = ret[s] {
+                return Err(format!(
+                    "Surface '{}' is in aperture groups '{}' and '{}'",
+                    name, groups[other].name, group.name
+                ));
+            }
+            ret[s] = Some(g);
+        }
+    }
+    Ok(ret)
+}
+
+/// Adds `b` times `scale` to `a`
+pub(crate) fn add_scaled(a: &mut Matrix, b: &Matrix, scale: Float) -> Result<(), String> {
+    let (nrows, ncols) = b.size();
+    for r in 0..nrows {
+        for c in 0..ncols {
+            a.set(r, c, a.get(r, c)? + b.get(r, c)? * scale)?;
+        }
+    }
+    Ok(())
+}
+
+impl DCSession {
+    /// Calculates Daylight Coefficients split by the aperture groups their paths
+    /// went through (see [`ApertureDC`]). The `report` must be the one returned
+    /// when loading the `scene`, as it says which surface each triangle belongs to.
+    ///
+    /// This follows the simplified model of
+    /// [`DCSession::calc_reflectance_tallies`], except that `glass` is not
+    /// absorbing: paths go straight through it or are reflected specularly, as
+    /// Radiance's `glass` does at each angle of incidence (or carrying its
+    /// normal transmittance whatever the angle, with
+    /// [`GlazingModel::Constant`](crate::GlazingModel::Constant)).
+    /// Glass that is not in any group is not tagged, but still transmits.
+    pub fn calc_aperture_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+        groups: &[ApertureGroup],
+    ) -> Result<ApertureDC, String> {
+        let tracer = LambertianTracer::new(self, scene, report)?;
+        for group in groups {
+            group.light_loss.validate()?;
+        }
+        let surface_groups = surface_groups(report, groups)?;
+        let reflectances: Vec<Float> = report
+            .materials
+            .iter()
+            .map(|m| diffuse_reflectance(&m.kind, m.rgb))
+            .collect();
+        let responses = material_responses(report, |i| {
+            let m = &report.materials[i];
+            let tau = glass_transmissivity(&m.kind, m.rgb);
+            if tau > 0.0 {
+                Response::Glass(tau)
+            } else if reflectances[i] > 0.0 {
+                Response::Reflect
+            } else {
+                Response::Absorb
+            }
+        });
+        // The paths through no group, plus the ones through each of them
+        self.check_budget(sensors.len() * (1 + groups.len()))?;
+
+        let basis = self.basis()?;
+        let n_bins = basis.n_bins();
+        let one_over_samples = 1. / self.options().n_ambient_samples as Float;
+        let trace = |(index, sensor): (usize, &SensorSpec)| {
+            let mut ret: HashMap<Vec<u32>, Vec<Float>> = HashMap::new();
+            let mut crossings = vec![0; groups.len()];
+            let skipped = tracer.trace(
+                index,
+                sensor,
+                &responses,
+                |throughput, reflections, transmissions, _, bin| {
+                    let v = reflections
+                        .iter()
+                        .fold(throughput, |t, m| t * reflectances[*m])
+                        * one_over_samples;
+                    crossings.iter_mut().for_each(|c| *c = 0);
+                    for g in transmissions.iter().filter_map(|s| surface_groups[*s]) {
+                        crossings[g] += 1;
+                    }
+                    if let Some(values) = ret.get_mut(&crossings) {
+                        values[bin] += v;
+                    } else {
+                        let mut values = vec![0.0; n_bins];
+                        values[bin] = v;
+                        ret.insert(crossings.clone(), values);
+                    }
+                },
+            )?;
+            Ok((ret, skipped))
+        };
+        let (rows, skipped): (Vec<_>, Vec<usize>) =
+            self.map_sensors(sensors, trace)?.into_iter().unzip();
+
+        // Paths through no group always make a component, even if empty
+        let mut keys: Vec<&Vec<u32>> = rows.iter().flat_map(|row| row.keys()).collect();
+        let untagged = vec![0; groups.len()];
+        keys.push(&untagged);
+        keys.sort();
+        keys.dedup();
+        let mut components = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut matrix = Matrix::new(0.0, sensors.len(), n_bins);
+            for (r, row) in rows.iter().enumerate() {
+                if let Some(values) = row.get(key) {
+                    for (bin, v) in values.iter().enumerate() {
+                        matrix.set(r, bin, *v)?;
+                    }
+                }
+            }
+            components.push((key.clone(), matrix));
+        }
+        Ok(ApertureDC {
+            basis,
+            groups: groups.to_vec(),
+            components,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, GlazingModel};
+    use crate::{Material, SceneBuilder};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    /// A sensor under a skylight, next to a wall that reflects
+    /// light from the sky without going through it
+    fn setup() -> (DCSession, Scene, SceneReport, Vec<SensorSpec>) {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("wall_mat", Material::plastic(0.6))
+            .unwrap();
+        builder
+            .add_material("glass_mat", Material::glass(0.8))
+            .unwrap();
+        let skylight =
+            [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)].map(|(x, y)| Point3D::new(x, y, 1.));
+        builder
+            .add_polygon("glass_mat", "skylight", &skylight)
+            .unwrap();
+        // Lower than the skylight, so no path goes through it twice
+        let wall =
+            [(-3., 0.), (3., 0.), (3., 0.9), (-3., 0.9)].map(|(y, z)| Point3D::new(1.5, y, z));
+        builder.add_polygon("wall_mat", "wall", &wall).unwrap();
+        let (mut scene, report) = builder.build().unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 3000,
+                max_depth: 1,
+                seed: 3,
+                ..DCOptions::default()
+            },
+        );
+        let sensors = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        (session, scene, report, sensors)
+    }
+
+    fn sum(m: &Matrix) -> Float {
+        let (nrows, ncols) = m.size();
+        (0..nrows)
+            .flat_map(|r| (0..ncols).map(move |c| (r, c)))
+            .map(|(r, c)| m.get(r, c).unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn test_light_loss_factors() {
+        assert!(LightLossFactors::constant(0.9, 0.95).is_ok());
+        assert!(LightLossFactors::constant(1.1, 0.95).is_err());
+        let mut dirt = [1.; 12];
+        dirt[6] = 0.5;
+        let factors = LightLossFactors::new(0.8, dirt).unwrap();
+        assert_close!(factors.factor(7).unwrap(), 0.4, 1e-6);
+        assert_close!(factors.factor(1).unwrap(), 0.8, 1e-6);
+        assert!(factors.factor(13).is_err());
+        dirt[0] = -0.1;
+        assert!(LightLossFactors::new(0.8, dirt).is_err());
+    }
+
+    #[test]
+    fn test_frame_factor() {
+        let (session, scene, report, sensors) = setup();
+        // Glass that reflects would send some of the paths reflected by the wall
+        // back down, which the model in which glass absorbs does not
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                glazing: GlazingModel::Constant,
+                ..*session.options()
+            },
+        );
+        let frame = LightLossFactors::constant(0.9, 1.).unwrap();
+        let groups = [ApertureGroup::new("skylight", &["skylight"], frame)];
+        let dc = session
+            .calc_aperture_dc(&sensors, &scene, &report, &groups)
+            .unwrap();
+        let untagged = dc.untagged().unwrap();
+        let through = dc.through("skylight").unwrap();
+        assert!(sum(&through) > 0.1 * sum(&untagged));
+
+        // Paths that did not go through the skylight (e.g., reflected by the wall)
+        // are those of the model in which glass absorbs
+        let tallies = session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+        assert_close!(sum(&untagged), sum(tallies.total()), 1e-4 * sum(&untagged));
+        assert!(sum(tallies.tally("wall_mat", 1).unwrap()) > 0.0);
+
+        // The frame only scales what came through the skylight
+        let scaled = dc.monthly_dc(3).unwrap();
+        assert_close!(
+            sum(&scaled),
+            sum(&untagged) + 0.9 * sum(&through),
+            1e-4 * sum(&scaled)
+        );
+        let (_, n_bins) = scaled.size();
+        for bin in 0..n_bins {
+            let expected = untagged.get(0, bin).unwrap() + 0.9 * through.get(0, bin).unwrap();
+            assert_close!(
+                scaled.get(0, bin).unwrap(),
+                expected,
+                1e-5 * (1. + expected)
+            );
+        }
+        assert_close!(
+            sum(&dc.dc(&[1.]).unwrap()),
+            sum(&untagged) + sum(&through),
+            1e-4 * sum(&scaled)
+        );
+        assert!(dc.dc(&[1., 1.]).is_err());
+        assert!(dc.through("door").is_err());
+    }
+
+    #[test]
+    fn test_monthly_dirt() {
+        let (session, scene, report, sensors) = setup();
+        let mut dirt = [1.; 12];
+        dirt[0] = 0.5;
+        let groups = [ApertureGroup::new(
+            "skylight",
+            &["skylight"],
+            LightLossFactors::new(0.9, dirt).unwrap(),
+        )];
+        let dc = session
+            .calc_aperture_dc(&sensors, &scene, &report, &groups)
+            .unwrap();
+        // The last hour of January and the first one of February
+        let n_bins = dc.basis.n_bins();
+        let axis = TimeAxis::new(1, 31 * 24 - 1, 2).unwrap();
+        let skies = Matrix::new(1.0, n_bins, 2);
+        let annual = dc.annual(&skies, &axis).unwrap().values;
+        assert_eq!(annual.size(), (1, 2));
+        let january = annual.get(0, 0).unwrap();
+        let february = annual.get(0, 1).unwrap();
+        let untagged = sum(&dc.untagged().unwrap());
+        let through = sum(&dc.through("skylight").unwrap());
+        assert_close!(january, untagged + 0.45 * through, 1e-4 * january);
+        assert_close!(february, untagged + 0.9 * through, 1e-4 * february);
+    }
+
+    #[test]
+    fn test_angular_glazing() {
+        // A sensor under a pane so large that it sees the sky through it
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("glass_mat", Material::glass(0.8))
+            .unwrap();
+        let pane = [(-100., -100.), (100., -100.), (100., 100.), (-100., 100.)]
+            .map(|(x, y)| Point3D::new(x, y, 1.));
+        builder.add_polygon("glass_mat", "pane", &pane).unwrap();
+        let (mut scene, report) = builder.build().unwrap();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let groups = [ApertureGroup::new(
+            "pane",
+            &["pane"],
+            LightLossFactors::default(),
+        )];
+        let through = |glazing| {
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    n_ambient_samples: 5000,
+                    max_depth: 1,
+                    seed: 5,
+                    glazing,
+                    ..DCOptions::default()
+                },
+            );
+            let dc = session
+                .calc_aperture_dc(&sensors, &scene, &report, &groups)
+                .unwrap();
+            sum(&dc.through("pane").unwrap())
+        };
+        let constant = through(GlazingModel::Constant);
+        // The transmittance of Radiance's glass, averaged over the
+        // hemisphere (weighted by the cosine), is about 90% of the normal one
+        let angular = through(GlazingModel::Angular);
+        assert_close!(angular / constant, 0.902, 0.03);
+    }
+
+    #[test]
+    fn test_bad_groups() {
+        let (session, scene, report, sensors) = setup();
+        let none = LightLossFactors::default();
+        for groups in [
+            vec![ApertureGroup::new("a", &["door"], none)],
+            vec![ApertureGroup::new("a", &["wall"], none)],
+            vec![
+                ApertureGroup::new("a", &["skylight"], none),
+                ApertureGroup::new("b", &["skylight"], none),
+            ],
+            vec![
+                ApertureGroup::new("a", &[], none),
+                ApertureGroup::new("a", &[], none),
+            ],
+        ] {
+            assert!(session
+                .calc_aperture_dc(&sensors, &scene, &report, &groups)
+                .is_err());
+        }
+    }
+}
@@ -0,0 +1,246 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Running a [`DCSession`] from asynchronous code (e.g., a web service) without
+//! blocking the threads of the runtime.
+//!
+//! The calculation itself is still synchronous: it runs on the blocking pool of
+//! `tokio`, in batches of sensors. Between batches, it publishes its progress on a
+//! `watch` channel and checks whether it has been cancelled.
+
+use crate::progress::{Clock, EtaEstimator, ProgressEstimate, SampleCounter, SystemClock};
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::Float;
+use matrix::Matrix;
+use rendering::Scene;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The number of sensors calculated between progress updates, by default
+pub const DEFAULT_ASYNC_BATCH: usize = 64;
+
+/// Stops a calculation started by [`DCSession::run_async`]. Clones share
+/// the same state, so any of them can cancel it.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that has not been cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the calculation to stop. It does so before its next batch of sensors.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    /// Checks whether the calculation was asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// How far a calculation started by [`DCSession::run_async`] has gone
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RunProgress {
+    /// The number of sensors whose coefficients are done
+    pub n_done: usize,
+
+    /// The number of sensors in the calculation
+    pub n_sensors: usize,
+
+    /// The time since the calculation started
+    pub elapsed: Duration,
+
+    /// The primary samples of the whole calculation that are done, and when
+    /// the rest are expected to be
+    pub samples: ProgressEstimate,
+}
+
+impl RunProgress {
+    /// The fraction of the sensors that are done, between 0 and 1
+    pub fn fraction(&self) -> Float {
+        if self.n_sensors == 0 {
+            1.
+        } else {
+            self.n_done as Float / self.n_sensors as Float
+        }
+    }
+
+    /// Whether all the sensors are done
+    pub fn is_finished(&self) -> bool {
+        self.n_done == self.n_sensors
+    }
+}
+
+/// The handles through which a calculation started by [`DCSession::run_async`]
+/// is followed and cancelled
+#[derive(Debug)]
+pub struct RunControl {
+    token: CancellationToken,
+    progress: watch::Sender<RunProgress>,
+    batch_size: usize,
+}
+
+impl Default for RunControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunControl {
+    /// Creates the handles of a new calculation, which publishes its progress
+    /// every [`DEFAULT_ASYNC_BATCH`] sensors
+    pub fn new() -> Self {
+        let (progress, _) = watch::channel(RunProgress::default());
+        Self {
+            token: CancellationToken::new(),
+            progress,
+            batch_size: DEFAULT_ASYNC_BATCH,
+        }
+    }
+
+    /// Sets how many sensors are calculated between progress updates (and
+    /// checks for cancellation). The results do not depend on it.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Makes the calculation stop when `token` is cancelled
+    pub fn with_token(mut self, token: CancellationToken) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// The token that cancels the calculation
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// A receiver of the progress of the calculation, which can be
+    /// subscribed to before or while it runs
+    pub fn subscribe(&self) -> watch::Receiver<RunProgress> {
+        self.progress.subscribe()
+    }
+}
+
+/// Cancels the calculation if the future running it is dropped before it
+/// finishes (e.g., because the client of an HTTP handler went away)
+struct CancelOnDrop(Option<CancellationToken>);
+
+impl CancelOnDrop {
+    /// Called once the calculation is over
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
+
+impl DCSession {
+    /// Calculates the Daylight Coefficient matrix of a set of sensors—as
+    /// [`DCSession::calc_sensor_dc`] does—on the blocking pool of `tokio`, so that
+    /// the returned future can be awaited from asynchronous code without blocking
+    /// its threads. Rays can be used as sensors through `into()`.
+    ///
+    /// The progress is published and the [`CancellationToken`] is checked every
+    /// batch of sensors (see [`RunControl`]). A cancelled calculation returns an
+    /// error, and so does one whose future is dropped before it finishes, as that
+    /// cancels it too.
+    ///
+    /// This needs to be awaited within a `tokio` runtime.
+    pub fn run_async(
+        &self,
+        sensors: Vec<SensorSpec>,
+        scene: Arc<Scene>,
+        control: RunControl,
+    ) -> impl Future<Output = Result<Matrix, String>> + Send + 'static {
+        let session = self.clone();
+        // Created out here, so that dropping the future before polling it cancels too
+        let mut guard = CancelOnDrop(Some(control.token()));
+        async move {
+            let ret = tokio::task::spawn_blocking(move || {
+                session.run_blocking(&sensors, &scene, &control)
+            })
+            .await
+            .map_err(|e| format!("The Daylight Coefficient calculation failed: {}", e))?;
+            guard.disarm();
+            ret
+        }
+    }
+
+    /// The synchronous part of [`DCSession::run_async`]
+    fn run_blocking(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        control: &RunControl,
+    ) -> Result<Matrix, String> {
+        if control.batch_size == 0 {
+            return Err(
+                "The batch size of an asynchronous calculation must be at least 1".to_string(),
+            );
+        }
+        self.check_budget(sensors.len())?;
+        // The workers tally their samples; a snapshot is taken after each batch
+        let counter = Arc::new(SampleCounter::new(
+            sensors.len() * self.options().n_ambient_samples,
+        ));
+        let session = self.clone().with_sample_counter(counter.clone());
+        let clock = SystemClock::start();
+        let mut eta = EtaEstimator::new(clock);
+        let n_bins = self.basis()?.n_bins();
+        let mut ret = Matrix::new(0.0, sensors.len(), n_bins);
+        for (i, batch) in sensors.chunks(control.batch_size).enumerate() {
+            if control.token.is_cancelled() {
+                return Err("The Daylight Coefficient calculation was cancelled".to_string());
+            }
+            let first_index = i * control.batch_size;
+            let dc = session.sensor_dc(batch, scene, first_index)?.matrix;
+            for r in 0..batch.len() {
+                for c in 0..n_bins {
+                    let v = dc.get(r, c)?;
+                    if v != 0.0 {
+                        ret.set(first_index + r, c, v)?;
+                    }
+                }
+            }
+            control.progress.send_replace(RunProgress {
+                n_done: first_index + batch.len(),
+                n_sensors: sensors.len(),
+                elapsed: clock.now(),
+                samples: eta.update_from(&counter),
+            });
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,247 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Batches: many independent items (e.g., grids of sensors, or the variants of
+//! a study) run one after the other, so that one that fails does not stop the
+//! rest.
+//!
+//! Each item has an identifier. Those that work go into
+//! [`BatchOutcome::succeeded`] and those that do not into
+//! [`BatchOutcome::failed`], with a [`BatchError`] that tells which item
+//! failed (and, if a sensor is to blame, which one) and why. It is up to the
+//! caller to decide whether a batch with failures is a failure (see
+//! [`BatchOutcome::into_result`]).
+
+use crate::labeled_matrix::LabeledMatrix;
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use rendering::Scene;
+use serde::{Deserialize, Serialize};
+
+/// Why an item of a batch failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchError {
+    /// The identifier of the item (e.g., the name of a grid, or of a variant)
+    pub id: String,
+
+    /// The index (within the item) of the sensor it failed because of, if any
+    pub sensor: Option<usize>,
+
+    /// What went wrong
+    pub message: String,
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "In '{}': {}", self.id, self.message)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl BatchError {
+    /// The error of an item that is not to blame on any sensor
+    pub fn new<S: Into<String>>(id: &str, message: S) -> Self {
+        Self {
+            id: id.to_string(),
+            sensor: None,
+            message: message.into(),
+        }
+    }
+
+    /// Blames the error on the sensor `index` of the item
+    pub fn at_sensor(mut self, index: usize) -> Self {
+        self.sensor = Some(index);
+        self
+    }
+}
+
+/// What happened to the items of a batch, in the order they were run
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchOutcome<T> {
+    /// The identifier and the result of each item that worked
+    pub succeeded: Vec<(String, T)>,
+
+    /// Why each of the others failed
+    pub failed: Vec<BatchError>,
+}
+
+impl<T> Default for BatchOutcome<T> {
+    fn default() -> Self {
+        Self {
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+impl<T> BatchOutcome<T> {
+    /// Records what happened to the item `id`
+    pub fn push(&mut self, id: &str, result: Result<T, BatchError>) {
+        match result {
+            Ok(v) => self.succeeded.push((id.to_string(), v)),
+            Err(e) => self.failed.push(e),
+        }
+    }
+
+    /// The number of items, whether they worked or not
+    pub fn len(&self) -> usize {
+        self.succeeded.len() + self.failed.len()
+    }
+
+    /// Whether there were no items at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether every item worked
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// The result of the item `id`, if it worked
+    pub fn get(&self, id: &str) -> Option<&T> {
+        self.succeeded
+            .iter()
+            .find(|(other, _)| other == id)
+            .map(|(_, v)| v)
+    }
+
+    /// Why the item `id` failed, if it did
+    pub fn error(&self, id: &str) -> Option<&BatchError> {
+        self.failed.iter().find(|e| e.id == id)
+    }
+
+    /// Describes every failure, one per line, after saying how many items failed
+    pub fn error_report(&self) -> String {
+        let mut ret = format!(
+            "{} of the {} items of the batch failed",
+            self.failed.len(),
+            self.len()
+        );
+        for e in &self.failed {
+            ret.push_str(&format!("\n{}", e));
+        }
+        ret
+    }
+
+    /// The results of every item, or the [`BatchOutcome::error_report`] if
+    /// any failed
+    pub fn into_result(self) -> Result<Vec<(String, T)>, String> {
+        if self.is_success() {
+            Ok(self.succeeded)
+        } else {
+            Err(self.error_report())
+        }
+    }
+}
+
+impl DCSession {
+    /// Calculates the Daylight Coefficients of several named grids of
+    /// sensors, one after the other, like [`DCSession::calc_sensor_dc`] does
+    /// for each. A grid that fails (e.g., because its matrix would exceed the
+    /// memory budget) is recorded in the outcome, and the rest carry on.
+    pub fn run_batches(
+        &self,
+        grids: &[(String, Vec<SensorSpec>)],
+        scene: &Scene,
+    ) -> BatchOutcome<LabeledMatrix> {
+        let mut ret = BatchOutcome::default();
+        for (id, sensors) in grids {
+            let result = self
+                .calc_sensor_dc(sensors, scene)
+                .map_err(|e| BatchError::new(id, e));
+            ret.push(id, result);
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, Float, Material, SceneBuilder};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+
+    #[test]
+    fn test_run_batches() {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("wall_mat", Material::plastic(0.5))
+            .unwrap();
+        let vertices =
+            [(0., -5.), (0., 5.), (10., 5.), (10., -5.)].map(|(x, y)| Point3D::new(x, y, -1.));
+        builder.add_polygon("wall_mat", "floor", &vertices).unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        // Room for the matrices of two sensors, but not of three
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 100,
+            ..DCOptions::default()
+        };
+        let bytes = DCSession::new(1, options).estimate(2).matrix_bytes;
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                memory_budget: Some(bytes),
+                ..options
+            },
+        );
+
+        let at = |x: Float| -> SensorSpec {
+            Ray3D {
+                origin: Point3D::new(x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        };
+        let grids = vec![
+            ("east".to_string(), vec![at(1.), at(2.)]),
+            ("large".to_string(), vec![at(1.), at(2.), at(3.)]),
+            ("west".to_string(), vec![at(-1.)]),
+        ];
+        let outcome = session.run_batches(&grids, &scene);
+        assert_eq!(outcome.len(), 3);
+        assert!(!outcome.is_success());
+
+        // The grids around the one that failed are calculated anyway
+        let ids: Vec<&str> = outcome
+            .succeeded
+            .iter()
+            .map(|(id, _)| id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["east", "west"]);
+        assert_eq!(outcome.get("east").unwrap().matrix.size().0, 2);
+        assert_eq!(outcome.get("west").unwrap().matrix.size().0, 1);
+        let error = outcome.error("large").unwrap();
+        assert_eq!(error.sensor, None);
+        assert!(
+            error
+                .to_string()
+                .starts_with("In 'large': The Daylight Coefficient matrix of 3 sensors"),
+            "{}",
+            error
+        );
+
+        let report = outcome.clone().into_result().unwrap_err();
+        assert!(report.starts_with("1 of the 3 items of the batch failed\nIn 'large'"));
+    }
+}
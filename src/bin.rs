@@ -19,26 +19,119 @@ SOFTWARE.
 */
 use solar::ReinhartSky;
 // use rendering::from_radiance::from
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use geometry3d::{Point3D, Ray3D, Vector3D};
-use rendering::{DCFactory, Scene};
+use light::matrix_io::{load_binary, load_mtx};
+use light::{compare_matrices, load_scene, ComparisonOptions, Float, Project};
+use matrix::Matrix;
+use rendering::DCFactory;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Inputs {
-    #[arg(short, long)]
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required_unless_present = "compare")]
+    input: Option<String>,
+
+    /// Compares two matrix files (binary, or text if they end in .mtx)
+    /// instead of calculating anything
+    #[arg(long, num_args = 2, value_names = ["A", "B"])]
+    compare: Option<Vec<String>>,
+
+    /// The relative RMS difference above which sensors are flagged
+    /// when comparing
+    #[arg(long, default_value_t = 0.05)]
+    tolerance: Float,
+
+    /// A sky vector file that weights the comparison, so that
+    /// differences are judged by illuminance
+    #[arg(long)]
+    sky: Option<String>,
     // #[arg(short, long)]
     // weather: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs a project file, writing its results and their manifest
+    Run {
+        /// The project file (JSON)
+        project: String,
+    },
+}
+
+/// Reads a matrix, in binary or text format depending on its extension
+fn load_matrix(path: &str) -> Result<Matrix, String> {
+    if path.ends_with(".mtx") {
+        load_mtx(path).map(|(m, _)| m)
+    } else {
+        load_binary(path)?.into_dense()
+    }
+}
+
+/// Compares the matrices of two files, printing the report
+fn compare_files(files: &[String], tolerance: Float, sky: Option<&str>) -> Result<(), String> {
+    let a = load_matrix(&files[0])?;
+    let b = load_matrix(&files[1])?;
+    let options = ComparisonOptions {
+        tolerance,
+        sky: sky.map(load_matrix).transpose()?,
+    };
+    println!("{}", compare_matrices(&a, &b, &options)?);
+    Ok(())
+}
+
+/// Runs a project file, printing what it wrote. Grids that fail do not stop
+/// the others, but make this fail once all of them were run.
+fn run_project(path: &str) -> Result<(), String> {
+    let outcome = Project::from_file(path)?.run_batch()?;
+    for (name, grid) in &outcome.succeeded {
+        for file in &grid.files {
+            println!("{}: {}", name, file.display());
+        }
+    }
+    if outcome.is_success() {
+        Ok(())
+    } else {
+        Err(outcome.error_report())
+    }
+}
+
 fn main() {
     let args = Inputs::parse();
 
-    let input_file = args.input;
+    if let Some(Command::Run { project }) = &args.command {
+        if let Err(e) = run_project(project) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(files) = &args.compare {
+        if let Err(e) = compare_files(files, args.tolerance, args.sky.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let input_file = args
+        .input
+        .expect("clap requires an input when not comparing");
 
     let mut scene = if input_file.ends_with(".rad") {
-        Scene::from_radiance(input_file)
+        match load_scene(&input_file) {
+            Ok((scene, report)) => {
+                eprintln!("{}", report);
+                scene
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
     } else if input_file.ends_with(".simple") || input_file.ends_with(".spl") {
         panic!("Reading SIMPLE models is still not suppoerted")
     } else {
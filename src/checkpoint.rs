@@ -0,0 +1,628 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Checkpoints of long calculations, so that they can be resumed after being
+//! interrupted.
+
+use crate::events::{EventKind, EventLog};
+use crate::labeled_matrix::{LabeledMatrix, RowMetadata};
+use crate::manifest::{verify_manifest, Fnv, RunManifest};
+use crate::matrix_io::{read_binary_with_manifest, write_dc_binary_with_manifest};
+use crate::scene_loading::SceneReport;
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::sky::SkyBasis;
+use crate::sky_matrix::GroundConvention;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::Scene;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"SLDCCKP\0";
+
+/// The version of the checkpoint format
+const VERSION: u8 = 1;
+
+/// The kinds of events, in the order in which they are stored
+const EVENT_KINDS: [EventKind; 12] = [
+    EventKind::NonApplicableSide,
+    EventKind::BelowHorizonEscape,
+    EventKind::EnclosedSensor,
+    EventKind::BadSample,
+    EventKind::ClampedValue,
+    EventKind::ExcludedObjectHit,
+    EventKind::NoiseCulled,
+    EventKind::FacingIntoSurface,
+    EventKind::RayBudgetExceeded,
+    EventKind::RayCapReached,
+    EventKind::OnSurface,
+    EventKind::RayBudgetUnenforced,
+];
+
+/// When a checkpointed calculation (see
+/// [`DCSession::calc_sensor_dc_checkpointed`]) saves its progress
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointPolicy {
+    /// The file where the checkpoint is written. It is replaced every time, and
+    /// removed once the calculation finishes.
+    pub path: PathBuf,
+
+    /// The number of sensors calculated at once. Progress is only saved,
+    /// and cancellation only checked, between batches.
+    pub batch_size: usize,
+
+    /// Save after this many sensors have been finished since the last checkpoint
+    pub every_sensors: usize,
+
+    /// Also save if this much time has passed since the last checkpoint
+    pub every: Option<Duration>,
+}
+
+impl CheckpointPolicy {
+    /// A policy that saves into `path` every 1024 sensors or 10 minutes,
+    /// in batches of 64 sensors
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            batch_size: 64,
+            every_sensors: 1024,
+            every: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+/// The progress of a calculation, as saved by
+/// [`DCSession::calc_sensor_dc_checkpointed`].
+///
+/// Sensors are only saved when they are finished, and the random numbers of each
+/// sensor depend on nothing but its index and the seed (both of which are
+/// recorded), so resuming gives the same results as an uninterrupted run.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// The manifest of the calculation, which records the inputs and options
+    pub manifest: RunManifest,
+
+    /// The coefficients of the sensors finished so far, which are the first ones
+    pub matrix: Matrix,
+
+    /// The number of samples sent from each finished sensor
+    pub n_samples: Vec<usize>,
+
+    /// The events of the finished sensors
+    pub events: EventLog,
+}
+
+impl Checkpoint {
+    /// The number of sensors that are finished
+    pub fn n_done(&self) -> usize {
+        self.n_samples.len()
+    }
+
+    /// Writes the checkpoint into `path`, through a temporary file so that an
+    /// interruption never leaves a half-written checkpoint behind
+    pub fn save<P: AsRef<Path>>(&self, path: P, basis: &SkyBasis) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut body: Vec<u8> = Vec::new();
+        write_dc_binary_with_manifest(&mut body, &self.matrix, basis, &self.manifest)?;
+        write_u64(&mut body, self.n_samples.len() as u64);
+        for n in &self.n_samples {
+            write_u64(&mut body, *n as u64);
+        }
+        let events = self.events.events();
+        write_u64(&mut body, events.len() as u64);
+        for e in events {
+            let kind = EVENT_KINDS.iter().position(|k| *k == e.kind).unwrap_or(0);
+            body.push(kind as u8);
+            match e.sensor {
+                Some(s) => {
+                    body.push(1);
+                    write_u64(&mut body, s as u64);
+                }
+                None => body.push(0),
+            }
+            match e.ray {
+                Some(r) => {
+                    body.push(1);
+                    write_ray(&mut body, &r);
+                }
+                None => body.push(0),
+            }
+            write_u64(&mut body, e.count as u64);
+            write_u64(&mut body, e.message.len() as u64);
+            body.extend_from_slice(e.message.as_bytes());
+        }
+
+        let mut hash = Fnv::new();
+        hash.write(&body);
+        let tmp = path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            file.write_all(&(body.len() as u64).to_le_bytes())?;
+            file.write_all(&body)?;
+            file.write_all(&hash.value().to_le_bytes())?;
+            file.sync_all()
+        };
+        write().map_err(|e| format!("Unable to write checkpoint '{}': {}", tmp.display(), e))?;
+        std::fs::rename(&tmp, path)
+            .map_err(|e| format!("Unable to write checkpoint '{}': {}", path.display(), e))
+    }
+
+    /// Reads a checkpoint written by [`Checkpoint::save`], failing if it
+    /// is incomplete or corrupted
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = std::fs::read(path)
+            .map_err(|e| format!("Unable to read checkpoint '{}': {}", path.display(), e))?;
+        let corrupted = || format!("Checkpoint '{}' is corrupted", path.display());
+        if content.len() < MAGIC.len() + 1 + 8 + 8 || &content[..MAGIC.len()] != MAGIC {
+            return Err(format!("'{}' is not a checkpoint", path.display()));
+        }
+        let version = content[MAGIC.len()];
+        if version != VERSION {
+            return Err(format!(
+                "Unsupported checkpoint version {} in '{}'",
+                version,
+                path.display()
+            ));
+        }
+        let start = MAGIC.len() + 1 + 8;
+        let len = u64::from_le_bytes(content[MAGIC.len() + 1..start].try_into().unwrap());
+        if content.len() as u64 != start as u64 + len + 8 {
+            return Err(corrupted());
+        }
+        let body = &content[start..content.len() - 8];
+        let mut hash = Fnv::new();
+        hash.write(body);
+        if hash.value().to_le_bytes() != content[content.len() - 8..] {
+            return Err(corrupted());
+        }
+
+        let mut r = body;
+        let (matrix, manifest) = read_binary_with_manifest(&mut r)?;
+        let matrix = matrix.into_dense()?;
+        let manifest = manifest.ok_or_else(corrupted)?;
+        let n_done = read_u64(&mut r)? as usize;
+        if n_done != matrix.size().0 {
+            return Err(corrupted());
+        }
+        let n_samples = (0..n_done)
+            .map(|_| read_u64(&mut r).map(|n| n as usize))
+            .collect::<Result<Vec<usize>, String>>()?;
+        let mut events = EventLog::new();
+        for _ in 0..read_u64(&mut r)? {
+            let kind = *EVENT_KINDS
+                .get(read_u8(&mut r)? as usize)
+                .ok_or_else(corrupted)?;
+            let sensor = match read_u8(&mut r)? {
+                0 => None,
+                _ => Some(read_u64(&mut r)? as usize),
+            };
+            let ray = match read_u8(&mut r)? {
+                0 => None,
+                _ => {
+                    let mut v = [0.0; 6];
+                    for x in v.iter_mut() {
+                        *x = f64::from_bits(read_u64(&mut r)?) as crate::Float;
+                    }
+                    Some(Ray3D {
+                        origin: Point3D::new(v[0], v[1], v[2]),
+                        direction: Vector3D::new(v[3], v[4], v[5]),
+                    })
+                }
+            };
+            let count = read_u64(&mut r)? as usize;
+            let len = read_u64(&mut r)? as usize;
+            if len > r.len() {
+                return Err(corrupted());
+            }
+            let message = String::from_utf8(r[..len].to_vec()).map_err(|_| corrupted())?;
+            r = &r[len..];
+            events.push(kind, sensor, ray, count, message);
+        }
+        if !r.is_empty() {
+            return Err(corrupted());
+        }
+        Ok(Self {
+            manifest,
+            matrix,
+            n_samples,
+            events,
+        })
+    }
+}
+
+fn write_u64(w: &mut Vec<u8>, v: u64) {
+    w.extend_from_slice(&v.to_le_bytes());
+}
+
+/// Writes the coordinates of a ray as `f64`, whatever the `Float`
+#[allow(clippy::unnecessary_cast)]
+fn write_ray(w: &mut Vec<u8>, r: &Ray3D) {
+    let (o, d) = (r.origin, r.direction);
+    for v in [o.x, o.y, o.z, d.x, d.y, d.z] {
+        w.extend_from_slice(&(v as f64).to_le_bytes());
+    }
+}
+
+fn read_u64(r: &mut &[u8]) -> Result<u64, String> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)
+        .map_err(|_| "Checkpoint ends unexpectedly".to_string())?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u8(r: &mut &[u8]) -> Result<u8, String> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)
+        .map_err(|_| "Checkpoint ends unexpectedly".to_string())?;
+    Ok(buf[0])
+}
+
+impl DCSession {
+    /// Like [`DCSession::calc_sensor_dc`], but saving its progress as prescribed by
+    /// the `policy`, so that it can be resumed through [`DCSession::resume`] if it is
+    /// interrupted. The `report` describes how the `scene` was loaded, and its files
+    /// are hashed into the [`RunManifest`] of the checkpoint.
+    ///
+    /// After each batch, `callback` receives the number of sensors finished so far.
+    /// Returning `false` cancels the calculation: a checkpoint is saved and
+    /// `None` is returned. Otherwise, the whole matrix is returned once it is
+    /// finished, and the checkpoint is removed.
+    pub fn calc_sensor_dc_checkpointed<F>(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+        policy: &CheckpointPolicy,
+        callback: F,
+    ) -> Result<Option<LabeledMatrix>, String>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let basis = self.basis()?;
+        let start = Checkpoint {
+            manifest: self.run_manifest(report, sensors, Duration::ZERO)?,
+            matrix: Matrix::new(0.0, 0, basis.n_bins()),
+            n_samples: Vec::new(),
+            events: EventLog::new(),
+        };
+        self.run_checkpointed(sensors, scene, policy, start, callback)
+    }
+
+    /// Continues a calculation from the checkpoint in `checkpoint` (see
+    /// [`DCSession::calc_sensor_dc_checkpointed`]). Progress keeps being saved
+    /// according to the `policy`, whose path may differ from the original one.
+    ///
+    /// Fails if the checkpoint is corrupted, or if the scene, the sensors, the
+    /// options of this session or the version of the code do not match
+    /// those recorded in it.
+    pub fn resume<P, F>(
+        &self,
+        checkpoint: P,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+        policy: &CheckpointPolicy,
+        callback: F,
+    ) -> Result<Option<LabeledMatrix>, String>
+    where
+        P: AsRef<Path>,
+        F: FnMut(usize) -> bool,
+    {
+        let checkpoint_path = checkpoint.as_ref();
+        let checkpoint = Checkpoint::load(checkpoint_path)?;
+        let m = &checkpoint.manifest;
+        let mut mismatches = verify_manifest(m, report, sensors)?;
+        if m.mf != self.mf() {
+            mismatches.push(format!(
+                "Calculated with MF {}, but this session uses MF {}",
+                m.mf,
+                self.mf()
+            ));
+        }
+        if m.options != *self.options() {
+            mismatches.push(format!(
+                "Calculated with options {:?}, but this session uses {:?}",
+                m.options,
+                self.options()
+            ));
+        }
+        if m.ray_filter != self.ray_filter().is_some()
+            || m.importance_hints != self.importance_hints().is_some()
+        {
+            mismatches.push(
+                "The ray filter or importance hints of this session are not those recorded"
+                    .to_string(),
+            );
+        }
+        if !mismatches.is_empty() {
+            return Err(format!(
+                "Checkpoint '{}' does not match this calculation: {}",
+                checkpoint_path.display(),
+                mismatches.join("; ")
+            ));
+        }
+        if checkpoint.n_done() > sensors.len()
+            || checkpoint.matrix.size().1 != self.basis()?.n_bins()
+        {
+            return Err(format!(
+                "Checkpoint '{}' is corrupted",
+                checkpoint_path.display()
+            ));
+        }
+        self.run_checkpointed(sensors, scene, policy, checkpoint, callback)
+    }
+
+    /// Calculates the sensors that are not yet in `checkpoint`
+    fn run_checkpointed<F>(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        policy: &CheckpointPolicy,
+        mut checkpoint: Checkpoint,
+        mut callback: F,
+    ) -> Result<Option<LabeledMatrix>, String>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        if policy.batch_size == 0 {
+            return Err(
+                "The batch size of a checkpointed calculation must be at least 1".to_string(),
+            );
+        }
+        self.check_budget(sensors.len())?;
+        let basis = self.basis()?;
+        let n_bins = basis.n_bins();
+        let started = Instant::now();
+        let previous_time = checkpoint.manifest.wall_time;
+
+        let mut rows: Vec<Vec<crate::Float>> = (0..checkpoint.n_done())
+            .map(|r| (0..n_bins).map(|c| checkpoint.matrix.get(r, c)).collect())
+            .collect::<Result<_, String>>()?;
+        let mut since_saved = 0;
+        let mut saved_at = Instant::now();
+        let save = |checkpoint: &mut Checkpoint, rows: &[Vec<crate::Float>]| {
+            let mut matrix = Matrix::new(0.0, rows.len(), n_bins);
+            for (r, row) in rows.iter().enumerate() {
+                for (c, v) in row.iter().enumerate() {
+                    matrix.set(r, c, *v)?;
+                }
+            }
+            checkpoint.matrix = matrix;
+            checkpoint.manifest.wall_time =
+                previous_time + started.elapsed().as_secs_f64() as crate::Float;
+            checkpoint.save(&policy.path, &basis)
+        };
+
+        while checkpoint.n_done() < sensors.len() {
+            let first = checkpoint.n_done();
+            let last = (first + policy.batch_size).min(sensors.len());
+            let batch = self.sensor_dc(&sensors[first..last], scene, first)?;
+            for (i, meta) in batch.rows.iter().enumerate() {
+                rows.push(
+                    (0..n_bins)
+                        .map(|c| batch.matrix.get(i, c))
+                        .collect::<Result<_, _>>()?,
+                );
+                checkpoint.n_samples.push(meta.n_samples);
+            }
+            checkpoint.events.merge(batch.events);
+            since_saved += last - first;
+
+            let keep_going = callback(checkpoint.n_done());
+            let due = since_saved >= policy.every_sensors
+                || policy
+                    .every
+                    .is_some_and(|every| saved_at.elapsed() >= every);
+            let finished = checkpoint.n_done() == sensors.len();
+            if !finished && (due || !keep_going) {
+                save(&mut checkpoint, &rows)?;
+                since_saved = 0;
+                saved_at = Instant::now();
+            }
+            if !keep_going && !finished {
+                return Ok(None);
+            }
+        }
+
+        if policy.path.exists() {
+            std::fs::remove_file(&policy.path).map_err(|e| {
+                format!(
+                    "Unable to remove checkpoint '{}': {}",
+                    policy.path.display(),
+                    e
+                )
+            })?;
+        }
+        let mut matrix = Matrix::new(0.0, sensors.len(), n_bins);
+        for (r, row) in rows.iter().enumerate() {
+            for (c, v) in row.iter().enumerate() {
+                matrix.set(r, c, *v)?;
+            }
+        }
+        let rows = sensors
+            .iter()
+            .zip(checkpoint.n_samples.iter())
+            .map(|(s, n)| RowMetadata {
+                ray: s.ray,
+                mask: s.mask.clone(),
+                zone: s.zone.clone(),
+                n_samples: *n,
+                id: s.sensor_id(),
+                angular_response: s.angular_response.clone(),
+            })
+            .collect();
+        Ok(Some(LabeledMatrix {
+            matrix,
+            rows,
+            events: checkpoint.events,
+            ground: GroundConvention::default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::scene_loading::load_scene;
+    use crate::session::DCOptions;
+
+    const SCENE: &str = "./tests/obstruction/courtyard.rad";
+
+    fn sensors() -> Vec<SensorSpec> {
+        (0..9)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(i as crate::Float - 4., 0.3 * i as crate::Float, 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect()
+    }
+
+    fn session(seed: u64) -> DCSession {
+        DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 300,
+                seed,
+                ..DCOptions::default()
+            },
+        )
+    }
+
+    fn policy(name: &str) -> CheckpointPolicy {
+        let path = std::env::temp_dir().join(format!(
+            "light_checkpoint_{}_{}.ckp",
+            name,
+            std::process::id()
+        ));
+        CheckpointPolicy {
+            batch_size: 2,
+            every_sensors: 4,
+            every: None,
+            ..CheckpointPolicy::new(path)
+        }
+    }
+
+    /// Runs until `n` sensors are finished, leaving a checkpoint behind
+    fn interrupted(policy: &CheckpointPolicy, n: usize) {
+        let (mut scene, report) = load_scene(SCENE).unwrap();
+        scene.build_accelerator();
+        let res = session(7)
+            .calc_sensor_dc_checkpointed(&sensors(), &scene, &report, policy, |done| done < n)
+            .unwrap();
+        assert!(res.is_none());
+        assert!(policy.path.exists());
+    }
+
+    #[test]
+    fn test_resume_matches_uninterrupted_run() {
+        let (mut scene, report) = load_scene(SCENE).unwrap();
+        scene.build_accelerator();
+        let sensors = sensors();
+        let expected = session(7).calc_sensor_dc(&sensors, &scene).unwrap();
+
+        let policy = policy("resume");
+        interrupted(&policy, 5);
+        let checkpoint = Checkpoint::load(&policy.path).unwrap();
+        // Cancelled after the batch that finished the sixth sensor
+        assert_eq!(checkpoint.n_done(), 6);
+        assert_eq!(checkpoint.n_samples, vec![300; 6]);
+
+        let mut calls = Vec::new();
+        let dc = session(7)
+            .resume(&policy.path, &sensors, &scene, &report, &policy, |done| {
+                calls.push(done);
+                true
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(calls, vec![8, 9]);
+        assert!(!policy.path.exists());
+        assert_eq!(dc.matrix.size(), expected.matrix.size());
+        let (nrows, ncols) = dc.matrix.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(
+                    dc.matrix.get(r, c).unwrap(),
+                    expected.matrix.get(r, c).unwrap()
+                );
+            }
+        }
+        assert_eq!(dc.rows.len(), 9);
+        assert_eq!(dc.events.len(), expected.events.len());
+    }
+
+    #[test]
+    fn test_mismatched_checkpoint() {
+        let (mut scene, report) = load_scene(SCENE).unwrap();
+        scene.build_accelerator();
+        let policy = policy("mismatch");
+        interrupted(&policy, 1);
+
+        // Other options
+        let err = session(8)
+            .resume(&policy.path, &sensors(), &scene, &report, &policy, |_| true)
+            .unwrap_err();
+        assert!(err.contains("does not match"), "{}", err);
+
+        // Other sensors
+        let mut moved = sensors();
+        moved[0].ray.origin.z += 0.1;
+        let err = session(7)
+            .resume(&policy.path, &moved, &scene, &report, &policy, |_| true)
+            .unwrap_err();
+        assert!(err.contains("sensors have changed"), "{}", err);
+        std::fs::remove_file(&policy.path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_checkpoint() {
+        let (mut scene, report) = load_scene(SCENE).unwrap();
+        scene.build_accelerator();
+        let policy = policy("corrupted");
+        interrupted(&policy, 1);
+        let mut content = std::fs::read(&policy.path).unwrap();
+        let middle = content.len() / 2;
+        content[middle] ^= 0xff;
+        std::fs::write(&policy.path, &content).unwrap();
+        let err = session(7)
+            .resume(&policy.path, &sensors(), &scene, &report, &policy, |_| true)
+            .unwrap_err();
+        assert!(err.contains("corrupted"), "{}", err);
+
+        // Truncated
+        std::fs::write(&policy.path, &content[..middle]).unwrap();
+        assert!(Checkpoint::load(&policy.path)
+            .unwrap_err()
+            .contains("corrupted"));
+
+        std::fs::write(&policy.path, b"not a checkpoint at all").unwrap();
+        assert!(Checkpoint::load(&policy.path).is_err());
+        std::fs::remove_file(&policy.path).unwrap();
+        assert!(Checkpoint::load(&policy.path).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Structured comparisons between two Daylight Coefficient matrices (e.g.,
+//! calculated with different sampling parameters).
+
+use crate::labeled_matrix::LabeledMatrix;
+use crate::Float;
+use matrix::Matrix;
+use std::fmt;
+
+/// How far apart (in any coordinate) two sensors can be and still
+/// be considered the same one
+const POSITION_TOLERANCE: Float = 1e-6;
+
+/// The options of [`compare`]
+#[derive(Debug, Clone, Default)]
+pub struct ComparisonOptions {
+    /// The relative RMS difference above which a sensor is flagged
+    /// (see [`ComparisonReport::exceeding`])
+    pub tolerance: Float,
+
+    /// A sky vector that weights each coefficient, so that differences are
+    /// judged by the illuminance (or irradiance) they produce. Without it,
+    /// raw coefficients are compared.
+    pub sky: Option<Matrix>,
+}
+
+/// How the coefficients of a sensor differ between two matrices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorComparison {
+    /// The RMS of the differences, relative to the RMS of the
+    /// coefficients of the first matrix
+    pub relative_rms: Float,
+
+    /// The largest absolute difference
+    pub max_difference: Float,
+
+    /// The bin where `max_difference` happens
+    pub max_bin: usize,
+
+    /// The sum of the coefficients of the second matrix over
+    /// that of the first one
+    pub energy_ratio: Float,
+}
+
+/// The result of [`compare`]. When comparing with a sky, all values
+/// are weighted by it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparisonReport {
+    /// The comparison of each sensor
+    pub sensors: Vec<SensorComparison>,
+
+    /// The sum of all the coefficients of the second matrix
+    /// over that of the first one
+    pub energy_ratio: Float,
+
+    /// The sensors whose relative RMS difference exceeds the tolerance
+    pub exceeding: Vec<usize>,
+
+    /// The tolerance the sensors were checked against
+    pub tolerance: Float,
+
+    /// Whether the coefficients were weighted by a sky
+    pub weighted: bool,
+}
+
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Compared {} sensors ({}): energy ratio {:.6}",
+            self.sensors.len(),
+            if self.weighted {
+                "weighted by a sky"
+            } else {
+                "raw coefficients"
+            },
+            self.energy_ratio
+        )?;
+        writeln!(
+            f,
+            "{:>8} {:>14} {:>14} {:>8} {:>12}",
+            "sensor", "relative_rms", "max_diff", "bin", "energy_ratio"
+        )?;
+        for (i, s) in self.sensors.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>8} {:>14.6e} {:>14.6e} {:>8} {:>12.6}",
+                i, s.relative_rms, s.max_difference, s.max_bin, s.energy_ratio
+            )?;
+        }
+        write!(
+            f,
+            "{} sensors exceed a relative RMS difference of {}",
+            self.exceeding.len(),
+            self.tolerance
+        )?;
+        if !self.exceeding.is_empty() {
+            let list: Vec<String> = self.exceeding.iter().map(|i| i.to_string()).collect();
+            write!(f, ": {}", list.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares two labeled matrices, which must describe the same sensors (i.e.,
+/// same positions, orientations, zones and masks). The number of samples can
+/// differ, as that is usually the point of the comparison.
+pub fn compare(
+    a: &LabeledMatrix,
+    b: &LabeledMatrix,
+    options: &ComparisonOptions,
+) -> Result<ComparisonReport, String> {
+    if a.rows.len() != b.rows.len() {
+        return Err(format!(
+            "Cannot compare matrices of {} and {} sensors",
+            a.rows.len(),
+            b.rows.len()
+        ));
+    }
+    for (i, (ra, rb)) in a.rows.iter().zip(b.rows.iter()).enumerate() {
+        let close = |x: Float, y: Float| (x - y).abs() <= POSITION_TOLERANCE;
+        let (oa, ob) = (ra.ray.origin, rb.ray.origin);
+        let (da, db) = (ra.ray.direction, rb.ray.direction);
+        let same_ray = close(oa.x, ob.x)
+            && close(oa.y, ob.y)
+            && close(oa.z, ob.z)
+            && close(da.x, db.x)
+            && close(da.y, db.y)
+            && close(da.z, db.z);
+        if !same_ray {
+            return Err(format!(
+                "Sensor {} is not the same in both matrices: {:?} and {:?}",
+                i, ra.ray, rb.ray
+            ));
+        }
+        if ra.zone != rb.zone || ra.mask.is_some() != rb.mask.is_some() {
+            return Err(format!(
+                "Sensor {} has different zones or masks in each matrix",
+                i
+            ));
+        }
+    }
+    compare_matrices(&a.matrix, &b.matrix, options)
+}
+
+/// Like [`compare`], for matrices without a description of their rows
+/// (e.g., read from files), which only need to be of the same size
+pub fn compare_matrices(
+    a: &Matrix,
+    b: &Matrix,
+    options: &ComparisonOptions,
+) -> Result<ComparisonReport, String> {
+    let (nrows, ncols) = a.size();
+    if b.size() != (nrows, ncols) {
+        let (brows, bcols) = b.size();
+        return Err(format!(
+            "Cannot compare a {}x{} matrix with a {}x{} one",
+            nrows, ncols, brows, bcols
+        ));
+    }
+    if !(options.tolerance.is_finite() && options.tolerance >= 0.0) {
+        return Err(format!(
+            "The tolerance of a comparison must be a non-negative number, but found {}",
+            options.tolerance
+        ));
+    }
+    let weights = match &options.sky {
+        Some(sky) => {
+            if sky.size() != (ncols, 1) {
+                let (srows, scols) = sky.size();
+                return Err(format!(
+                    "A sky vector for matrices of {} columns must be {}x1, but found {}x{}",
+                    ncols, ncols, srows, scols
+                ));
+            }
+            (0..ncols)
+                .map(|c| sky.get(c, 0))
+                .collect::<Result<Vec<Float>, String>>()?
+        }
+        None => vec![1.; ncols],
+    };
+
+    let mut sensors = Vec::with_capacity(nrows);
+    let mut exceeding = Vec::new();
+    let (mut total_a, mut total_b) = (0.0, 0.0);
+    for r in 0..nrows {
+        let (mut sum_a, mut sum_b) = (0.0, 0.0);
+        let (mut squares_a, mut squares_diff) = (0.0, 0.0);
+        let (mut max_difference, mut max_bin) = (0.0, 0);
+        for (c, w) in weights.iter().enumerate() {
+            let va = a.get(r, c)? * w;
+            let vb = b.get(r, c)? * w;
+            sum_a += va;
+            sum_b += vb;
+            squares_a += va * va;
+            squares_diff += (vb - va) * (vb - va);
+            if (vb - va).abs() > max_difference {
+                max_difference = (vb - va).abs();
+                max_bin = c;
+            }
+        }
+        let relative_rms = if squares_a > 0.0 {
+            (squares_diff / squares_a).sqrt()
+        } else if squares_diff > 0.0 {
+            Float::INFINITY
+        } else {
+            0.0
+        };
+        if relative_rms > options.tolerance {
+            exceeding.push(r);
+        }
+        total_a += sum_a;
+        total_b += sum_b;
+        sensors.push(SensorComparison {
+            relative_rms,
+            max_difference,
+            max_bin,
+            energy_ratio: ratio(sum_b, sum_a),
+        });
+    }
+    Ok(ComparisonReport {
+        sensors,
+        energy_ratio: ratio(total_b, total_a),
+        exceeding,
+        tolerance: options.tolerance,
+        weighted: options.sky.is_some(),
+    })
+}
+
+/// The ratio between two sums of coefficients, which is `1` if both are zero
+fn ratio(b: Float, a: Float) -> Float {
+    if a == 0.0 && b == 0.0 {
+        1.
+    } else {
+        b / a
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::events::EventLog;
+    use crate::labeled_matrix::RowMetadata;
+    use crate::rng::SensorRng;
+    use crate::sensor_id::SensorId;
+    use crate::sky_matrix::GroundConvention;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    fn labeled(matrix: Matrix) -> LabeledMatrix {
+        let (nrows, _) = matrix.size();
+        let rows = (0..nrows)
+            .map(|i| RowMetadata {
+                ray: Ray3D {
+                    origin: Point3D::new(i as Float, 0., 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                },
+                mask: None,
+                zone: None,
+                n_samples: 100,
+                id: SensorId::new(format!("sensor {}", i)).unwrap(),
+                angular_response: None,
+            })
+            .collect();
+        LabeledMatrix {
+            matrix,
+            rows,
+            events: EventLog::new(),
+            ground: GroundConvention::default(),
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let (nrows, ncols) = (3, 146);
+        let mut rng = SensorRng::new(0, 0);
+        let mut a = Matrix::new(0.0, nrows, ncols);
+        for r in 0..nrows {
+            for c in 0..ncols {
+                a.set(r, c, 0.5 + rng.gen()).unwrap();
+            }
+        }
+        // The first sensor is the same, the second one has up to 4% of noise,
+        // and the third one gets 20% more light
+        let mut b = a.clone();
+        for c in 0..ncols {
+            let v = a.get(1, c).unwrap();
+            b.set(1, c, v * (1. + 0.08 * (rng.gen() - 0.5))).unwrap();
+            b.set(2, c, 1.2 * a.get(2, c).unwrap()).unwrap();
+        }
+        b.set(0, 7, a.get(0, 7).unwrap() + 0.001).unwrap();
+        let options = ComparisonOptions {
+            tolerance: 0.1,
+            sky: None,
+        };
+        let report = compare(&labeled(a.clone()), &labeled(b.clone()), &options).unwrap();
+        assert_eq!(report.sensors.len(), 3);
+        assert_eq!(report.exceeding, vec![2]);
+        assert!(!report.weighted);
+
+        let s = report.sensors[0];
+        assert_close!(s.max_difference, 0.001, 1e-4);
+        assert_eq!(s.max_bin, 7);
+        assert!(s.relative_rms < 1e-3);
+
+        // Uniform noise of up to 4% has an RMS of 4/sqrt(3) %
+        let s = report.sensors[1];
+        assert!(s.relative_rms > 0.015 && s.relative_rms < 0.03);
+        assert_close!(s.energy_ratio, 1., 0.01);
+
+        let s = report.sensors[2];
+        assert_close!(s.relative_rms, 0.2, 1e-4);
+        assert_close!(s.energy_ratio, 1.2, 1e-4);
+        assert!(report.energy_ratio > 1.05 && report.energy_ratio < 1.1);
+        assert!(report.to_string().contains("1 sensors exceed"));
+
+        // A sky that ignores the bin that changed hides the difference
+        let mut sky = Matrix::new(1.0, ncols, 1);
+        sky.set(7, 0, 0.0).unwrap();
+        let weighted = ComparisonOptions {
+            tolerance: 0.1,
+            sky: Some(sky),
+        };
+        let report = compare(&labeled(a.clone()), &labeled(b.clone()), &weighted).unwrap();
+        assert!(report.weighted);
+        assert_eq!(report.sensors[0].max_difference, 0.0);
+        assert_eq!(report.sensors[0].relative_rms, 0.0);
+    }
+
+    #[test]
+    fn test_incompatible() {
+        let a = labeled(Matrix::new(1.0, 2, 146));
+        let options = ComparisonOptions::default();
+        assert!(compare(&a, &labeled(Matrix::new(1.0, 3, 146)), &options).is_err());
+        assert!(compare(&a, &labeled(Matrix::new(1.0, 2, 290)), &options).is_err());
+        let mut moved = a.clone();
+        moved.rows[1].ray.origin.z += 0.1;
+        assert!(compare(&a, &moved, &options).is_err());
+        let mut zoned = a.clone();
+        zoned.rows[0].zone = Some("office".to_string());
+        assert!(compare(&a, &zoned, &options).is_err());
+        let sky = Some(Matrix::new(1.0, 145, 1));
+        assert!(compare(&a, &a, &ComparisonOptions { sky, ..options }).is_err());
+        let report = compare(&a, &a, &ComparisonOptions::default()).unwrap();
+        assert!(report.exceeding.is_empty());
+        assert_eq!(report.energy_ratio, 1.);
+    }
+}
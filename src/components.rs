@@ -0,0 +1,390 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Annual irradiation split into its beam, sky-diffuse and ground-reflected
+//! components, as façade engineers report it.
+//!
+//! The components come from matrices that most studies already have: the total
+//! Daylight Coefficients, the direct-only ones (i.e., with a `max_depth` of `0`)
+//! and the ground coefficients of a [`TwoSidedDC`](crate::TwoSidedDC); and the
+//! skies split into the sun and the rest of the sky (e.g., `gendaymtx -d` and
+//! `gendaymtx -s`):
+//!
+//! * The **beam** component is the sun seen straight from each sensor (the direct
+//!   coefficients times the sun).
+//! * The **ground-reflected** component is whatever arrives from below the horizon
+//!   (the ground coefficients times the radiance of the ground).
+//! * The **sky-diffuse** component is the rest: the sky seen straight, and the sun
+//!   and the sky reflected by the scene.
+//!
+//! By construction, they add up to the total annual result.
+
+use crate::annual::apply_annual;
+use crate::sky::SkyBasis;
+use crate::two_sided::GroundModel;
+use crate::Float;
+use matrix::Matrix;
+
+/// How far apart the matrices given to [`annual_components`] can be—relative to
+/// the quantities compared—before they are considered inconsistent
+pub const COMPONENT_TOLERANCE: Float = 1e-3;
+
+/// The beam, sky-diffuse and ground-reflected components of an annual result,
+/// each with one row per sensor and one column per timestep
+#[derive(Debug, Clone)]
+pub struct AnnualComponents {
+    /// The sun seen straight from each sensor
+    pub beam: Matrix,
+    /// The sky seen straight, and the sun and the sky reflected by the scene
+    pub sky_diffuse: Matrix,
+    /// What arrives from below the horizon
+    pub ground_reflected: Matrix,
+}
+
+/// The fraction of the annual irradiation of a sensor that each component
+/// accounts for. Sensors that receive nothing have all fractions at `0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComponentFractions {
+    /// The fraction of the beam component
+    pub beam: Float,
+    /// The fraction of the sky-diffuse component
+    pub sky_diffuse: Float,
+    /// The fraction of the ground-reflected component
+    pub ground_reflected: Float,
+}
+
+/// The values of a matrix with its ground bin set to zero
+fn without_ground(m: &Matrix, transposed: bool) -> Result<Matrix, String> {
+    let mut ret = m.clone();
+    let (nrows, ncols) = m.size();
+    if transposed {
+        for c in 0..ncols {
+            ret.set(SkyBasis::GROUND_BIN, c, 0.0)?;
+        }
+    } else {
+        for r in 0..nrows {
+            ret.set(r, SkyBasis::GROUND_BIN, 0.0)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// The sum of each row of a matrix
+fn row_sums(m: &Matrix) -> Result<Vec<Float>, String> {
+    let (nrows, ncols) = m.size();
+    (0..nrows)
+        .map(|r| (0..ncols).map(|c| m.get(r, c)).sum())
+        .collect()
+}
+
+impl AnnualComponents {
+    /// The total annual result (i.e., the sum of the components)
+    pub fn total(&self) -> Matrix {
+        &(&self.beam + &self.sky_diffuse) + &self.ground_reflected
+    }
+
+    /// The fraction of the annual irradiation of each sensor that each
+    /// component accounts for
+    pub fn fractions(&self) -> Result<Vec<ComponentFractions>, String> {
+        let beam = row_sums(&self.beam)?;
+        let sky = row_sums(&self.sky_diffuse)?;
+        let ground = row_sums(&self.ground_reflected)?;
+        Ok(beam
+            .iter()
+            .zip(sky.iter())
+            .zip(ground.iter())
+            .map(|((b, s), g)| {
+                let total = b + s + g;
+                if total == 0.0 {
+                    return ComponentFractions {
+                        beam: 0.0,
+                        sky_diffuse: 0.0,
+                        ground_reflected: 0.0,
+                    };
+                }
+                ComponentFractions {
+                    beam: b / total,
+                    sky_diffuse: s / total,
+                    ground_reflected: g / total,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Splits an annual result into its beam, sky-diffuse and ground-reflected
+/// components (see the [module documentation](self)).
+///
+/// `dc_total` and `dc_direct` are Daylight Coefficient matrices following `basis`,
+/// and `dc_ground` the ground coefficients of the same sensors (see
+/// [`TwoSidedDC::ground`](crate::TwoSidedDC::ground)). The ground bin of
+/// `dc_total` must hold the sum of the latter, as
+/// [`TwoSidedDC::folded`](crate::TwoSidedDC::folded) does. `sun` and `sky` are
+/// sky matrices with the same timesteps, whose ground bins are ignored: the
+/// ground takes the radiance given by `ground`, lit by both.
+///
+/// Fails if the matrices do not match, or if the direct coefficients see more
+/// of the sky over the year than the total ones (beyond [`COMPONENT_TOLERANCE`]),
+/// which means they do not describe the same sensors. Timesteps are not checked
+/// one by one, so the sun-lit noise of separate runs can leave the sky-diffuse
+/// component of some slightly negative.
+pub fn annual_components(
+    dc_total: &Matrix,
+    dc_direct: &Matrix,
+    dc_ground: &Matrix,
+    basis: &SkyBasis,
+    sun: &Matrix,
+    sky: &Matrix,
+    ground: &GroundModel,
+) -> Result<AnnualComponents, String> {
+    basis.check_dc(dc_total)?;
+    basis.check_dc(dc_direct)?;
+    basis.check_sky(sun)?;
+    basis.check_sky(sky)?;
+    let (n_sensors, _) = dc_total.size();
+    for (what, m) in [("direct", dc_direct), ("ground", dc_ground)] {
+        if m.size().0 != n_sensors {
+            return Err(format!(
+                "There are {} sensors in the total Daylight Coefficients, but {} in the {} ones",
+                n_sensors,
+                m.size().0,
+                what
+            ));
+        }
+    }
+    if dc_ground.size().1 != basis.n_ground_bins() {
+        return Err(format!(
+            "The ground coefficients have {} bins, but a sky with MF {} has {}",
+            dc_ground.size().1,
+            basis.mf(),
+            basis.n_ground_bins()
+        ));
+    }
+    if sun.size() != sky.size() {
+        return Err(format!(
+            "The sun has {} timesteps, but the sky has {}",
+            sun.size().1,
+            sky.size().1
+        ));
+    }
+    for (r, sum) in row_sums(dc_ground)?.iter().enumerate() {
+        let bin = dc_total.get(r, SkyBasis::GROUND_BIN)?;
+        if (bin - sum).abs() > COMPONENT_TOLERANCE * bin.abs().max(sum.abs()) + Float::EPSILON {
+            return Err(format!(
+                "The ground bin of sensor {} is {} in the total Daylight Coefficients, but its ground coefficients add up to {}",
+                r, bin, sum
+            ));
+        }
+    }
+
+    let sun = without_ground(sun, true)?;
+    let skies = &sun + &without_ground(sky, true)?;
+    let beam = apply_annual(&without_ground(dc_direct, false)?, &sun)?;
+    let seen = apply_annual(&without_ground(dc_total, false)?, &skies)?;
+    let sky_diffuse = &seen - &beam;
+    let ground_reflected = apply_annual(dc_ground, &ground.ground_matrix(basis, &skies)?)?;
+
+    let totals = row_sums(&seen)?;
+    for (r, diffuse) in row_sums(&sky_diffuse)?.iter().enumerate() {
+        if *diffuse < -COMPONENT_TOLERANCE * totals[r].abs() {
+            return Err(format!(
+                "Sensor {} receives more over the year from the sun seen straight ({}) than from everything its total Daylight Coefficients see ({})",
+                r,
+                totals[r] - diffuse,
+                totals[r]
+            ));
+        }
+    }
+    Ok(AnnualComponents {
+        beam,
+        sky_diffuse,
+        ground_reflected,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::PI;
+    use geometry3d::Vector3D;
+    use validate::assert_close;
+
+    /// The coefficients of an unobstructed sensor facing `normal`: the
+    /// cosine-weighted solid angle of each bin it sees, as the total, direct
+    /// and ground coefficients
+    fn unobstructed(basis: &SkyBasis, normal: Vector3D) -> (Matrix, Matrix) {
+        let weight = |d: &Vector3D, omega: &Float| (*d * normal).max(0.0) * omega;
+        let ground: Vec<Float> = basis
+            .ground_centroids()
+            .iter()
+            .zip(basis.ground_solid_angles().iter())
+            .map(|(d, o)| weight(d, o))
+            .collect();
+        let mut direct = Matrix::new(0.0, 1, basis.n_bins());
+        for (bin, (d, o)) in basis
+            .centroids()
+            .iter()
+            .zip(basis.solid_angles().iter())
+            .enumerate()
+            .skip(1)
+        {
+            direct.set(0, bin, weight(d, o)).unwrap();
+        }
+        let dc_ground = Matrix::from_data(1, ground.len(), ground);
+        (direct, dc_ground)
+    }
+
+    /// The direct coefficients with the ground bin that the ground
+    /// coefficients add up to
+    fn folded(direct: &Matrix, dc_ground: &Matrix) -> Matrix {
+        let mut ret = direct.clone();
+        ret.set(0, SkyBasis::GROUND_BIN, row_sums(dc_ground).unwrap()[0])
+            .unwrap();
+        ret
+    }
+
+    #[test]
+    fn test_vertical_facade() {
+        let basis = SkyBasis::new(1).unwrap();
+        let normal = Vector3D::new(0., -1., 0.);
+        let (direct, dc_ground) = unobstructed(&basis, normal);
+        let total = folded(&direct, &dc_ground);
+
+        // Two timesteps: a sun in front of the façade over a uniform sky, and
+        // the sky alone
+        let centroids = basis.centroids();
+        let sun_bin = (1..basis.n_bins())
+            .filter(|b| centroids[*b].z > 0.5)
+            .max_by(|a, b| {
+                (centroids[*a] * normal)
+                    .partial_cmp(&(centroids[*b] * normal))
+                    .unwrap()
+            })
+            .unwrap();
+        let (sun_radiance, sky_radiance, albedo) = (1e6, 100., 0.2);
+        let mut sun = Matrix::new(0.0, basis.n_bins(), 2);
+        sun.set(sun_bin, 0, sun_radiance).unwrap();
+        let mut sky = Matrix::new(sky_radiance, basis.n_bins(), 2);
+        // ignored
+        sky.set(SkyBasis::GROUND_BIN, 0, 1e9).unwrap();
+        let ground = GroundModel::Uniform { albedo };
+
+        let components =
+            annual_components(&total, &direct, &dc_ground, &basis, &sun, &sky, &ground).unwrap();
+
+        // The sun is seen straight
+        let beam = direct.get(0, sun_bin).unwrap() * sun_radiance;
+        assert_close!(components.beam.get(0, 0).unwrap(), beam, 1e-6 * beam);
+        assert_eq!(components.beam.get(0, 1).unwrap(), 0.0);
+
+        // Half of a uniform sky, whose cosine-weighted solid angle is π / 2
+        for step in 0..2 {
+            let diffuse = components.sky_diffuse.get(0, step).unwrap();
+            assert_close!(
+                diffuse,
+                sky_radiance * PI / 2.,
+                0.03 * sky_radiance * PI / 2.
+            );
+        }
+
+        // and half of a uniform ground, lit by the sun and the sky
+        let horizontal = |step: usize| {
+            let sun_h = if step == 0 {
+                sun_radiance * centroids[sun_bin].z * basis.solid_angles()[sun_bin]
+            } else {
+                0.0
+            };
+            sun_h + sky_radiance * PI
+        };
+        for step in 0..2 {
+            let reflected = components.ground_reflected.get(0, step).unwrap();
+            let exp = albedo * horizontal(step) / 2.;
+            assert_close!(reflected, exp, 0.03 * exp);
+        }
+
+        // The components add up to the total
+        let mut skies = &sun + &sky;
+        for step in 0..2 {
+            let e_h = ground
+                .ground_matrix(&basis, &without_ground(&skies, true).unwrap())
+                .unwrap()
+                .get(0, step)
+                .unwrap();
+            skies.set(SkyBasis::GROUND_BIN, step, e_h).unwrap();
+        }
+        let expected = apply_annual(&total, &skies).unwrap();
+        let sum = components.total();
+        for step in 0..2 {
+            let exp = expected.get(0, step).unwrap();
+            assert_close!(sum.get(0, step).unwrap(), exp, 1e-5 * exp);
+        }
+        let fractions = components.fractions().unwrap();
+        let f = fractions[0];
+        assert_close!(f.beam + f.sky_diffuse + f.ground_reflected, 1., 1e-5);
+        // The sun dominates
+        assert!(f.beam > 0.5 && f.sky_diffuse > 0.0 && f.ground_reflected > 0.0);
+
+        // Light reflected by the scene is diffuse, and the beam stays
+        let mut reflecting = total.clone();
+        for bin in 1..basis.n_bins() {
+            let v = reflecting.get(0, bin).unwrap();
+            reflecting.set(0, bin, v + 0.01).unwrap();
+        }
+        let more = annual_components(
+            &reflecting,
+            &direct,
+            &dc_ground,
+            &basis,
+            &sun,
+            &sky,
+            &ground,
+        )
+        .unwrap();
+        assert_eq!(more.beam, components.beam);
+        assert_eq!(more.ground_reflected, components.ground_reflected);
+        assert!(more.sky_diffuse.get(0, 1).unwrap() > components.sky_diffuse.get(0, 1).unwrap());
+    }
+
+    #[test]
+    fn test_inconsistent() {
+        let basis = SkyBasis::new(1).unwrap();
+        let (direct, dc_ground) = unobstructed(&basis, Vector3D::new(1., 0., 0.));
+        let total = folded(&direct, &dc_ground);
+        let sun = Matrix::new(10.0, basis.n_bins(), 3);
+        let sky = Matrix::new(1.0, basis.n_bins(), 3);
+        let ground = GroundModel::Uniform { albedo: 0.2 };
+        let split = |total: &Matrix, direct: &Matrix, dc_ground: &Matrix, sky: &Matrix| {
+            annual_components(total, direct, dc_ground, &basis, &sun, sky, &ground)
+        };
+        assert!(split(&total, &direct, &dc_ground, &sky).is_ok());
+
+        // A ground bin that is not the sum of the ground coefficients
+        assert!(split(&direct, &direct, &dc_ground, &sky).is_err());
+        // Direct coefficients that see more than the total ones
+        let brighter = &direct + &direct;
+        assert!(split(&total, &brighter, &dc_ground, &sky).is_err());
+        // Shapes
+        assert!(split(&total, &direct, &direct, &sky).is_err());
+        let short = Matrix::new(1.0, basis.n_bins(), 2);
+        assert!(split(&total, &direct, &dc_ground, &short).is_err());
+        let two = Matrix::new(0.0, 2, basis.n_bins());
+        assert!(split(&total, &two, &dc_ground, &sky).is_err());
+    }
+}
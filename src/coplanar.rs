@@ -0,0 +1,553 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Detection of coplanar surfaces that overlap (e.g., a wall exported
+//! together with its paint layer), for which the surface hit by a ray
+//! depends on rounding errors.
+
+use crate::Float;
+use geometry3d::{Point3D, Vector3D};
+use std::collections::HashMap;
+
+/// Polygons whose bounding boxes cover more grid cells than this are not put
+/// in the grid, and are checked against every other polygon instead
+const MAX_CELLS: usize = 512;
+
+/// What to do with coplanar surfaces that overlap
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CoplanarPolicy {
+    /// Only report them
+    #[default]
+    Report,
+
+    /// Keep the surface that was loaded first, and remove the other one
+    KeepFirst,
+
+    /// Keep the surface whose material has the highest (average) reflectance, and
+    /// remove the other one. Ties keep the first one.
+    KeepHighestReflectance,
+
+    /// Keep both, moving the one that was loaded last along its normal by
+    /// this distance (times the number of surfaces loaded before that it overlaps)
+    Offset(Float),
+}
+
+/// How coplanar overlapping surfaces are found and treated when loading
+/// a scene (see [`load_scenes_with_coplanar`](crate::load_scenes_with_coplanar))
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoplanarOptions {
+    /// How far from the plane of a polygon the vertices of another one can be for
+    /// them to be considered coplanar, in the units of the scene
+    pub tolerance: Float,
+
+    /// What to do with the overlapping pairs
+    pub policy: CoplanarPolicy,
+}
+
+impl Default for CoplanarOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 1e-3,
+            policy: CoplanarPolicy::Report,
+        }
+    }
+}
+
+/// What was done about a [`CoplanarOverlap`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoplanarFix {
+    /// Nothing
+    None,
+
+    /// The surface with this name was removed
+    Removed(String),
+
+    /// The surface with this name was moved along its normal by this distance
+    Offset(String, Float),
+}
+
+/// Two coplanar polygons that overlap
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoplanarOverlap {
+    /// The name of the polygon that was loaded first
+    pub first: String,
+
+    /// The name of the polygon that was loaded last
+    pub second: String,
+
+    /// The area that both polygons share
+    pub area: Float,
+
+    /// What was done about it
+    pub fix: CoplanarFix,
+}
+
+/// A polygon with its plane and bounding box
+struct Planar<'a> {
+    vertices: &'a [Point3D],
+    normal: Vector3D,
+    min: [Float; 3],
+    max: [Float; 3],
+}
+
+impl<'a> Planar<'a> {
+    fn new(vertices: &'a [Point3D], tolerance: Float) -> Option<Self> {
+        if vertices.len() < 3 {
+            return None;
+        }
+        // Newell's method
+        let mut normal = Vector3D::new(0., 0., 0.);
+        for (i, a) in vertices.iter().enumerate() {
+            let b = vertices[(i + 1) % vertices.len()];
+            normal += Vector3D::new(
+                (a.y - b.y) * (a.z + b.z),
+                (a.z - b.z) * (a.x + b.x),
+                (a.x - b.x) * (a.y + b.y),
+            );
+        }
+        if normal.is_zero() {
+            return None;
+        }
+        let mut min = [Float::MAX; 3];
+        let mut max = [Float::MIN; 3];
+        for v in vertices {
+            for (i, c) in [v.x, v.y, v.z].iter().enumerate() {
+                min[i] = min[i].min(c - tolerance);
+                max[i] = max[i].max(c + tolerance);
+            }
+        }
+        Some(Self {
+            vertices,
+            normal: normal.get_normalized(),
+            min,
+            max,
+        })
+    }
+
+    /// Whether all the vertices of `other` are within `tolerance` of the plane
+    fn contains_plane_of(&self, other: &Planar, tolerance: Float) -> bool {
+        let origin = self.vertices[0];
+        other
+            .vertices
+            .iter()
+            .all(|v| ((*v - origin) * self.normal).abs() <= tolerance)
+    }
+
+    fn boxes_overlap(&self, other: &Planar) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+}
+
+/// The signed area of a polygon in 2D
+fn signed_area(polygon: &[(Float, Float)]) -> Float {
+    let n = polygon.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (polygon[i], polygon[(i + 1) % n]);
+            a.0 * b.1 - b.0 * a.1
+        })
+        .sum::<Float>()
+        * 0.5
+}
+
+/// Clips a polygon by a convex one with counterclockwise vertices
+/// (Sutherland-Hodgman), returning their intersection
+fn clip(subject: &[(Float, Float)], clipper: &[(Float, Float)]) -> Vec<(Float, Float)> {
+    let mut output = subject.to_vec();
+    for i in 0..clipper.len() {
+        if output.is_empty() {
+            break;
+        }
+        let (a, b) = (clipper[i], clipper[(i + 1) % clipper.len()]);
+        let side = |p: (Float, Float)| (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+        let input = std::mem::take(&mut output);
+        for j in 0..input.len() {
+            let (p, q) = (input[j], input[(j + 1) % input.len()]);
+            let (sp, sq) = (side(p), side(q));
+            if sp >= 0.0 {
+                output.push(p);
+            }
+            if (sp >= 0.0) != (sq >= 0.0) {
+                let t = sp / (sp - sq);
+                output.push((p.0 + t * (q.0 - p.0), p.1 + t * (q.1 - p.1)));
+            }
+        }
+    }
+    output
+}
+
+/// The triangles of a fan around the first vertex, counterclockwise
+fn fan(polygon: &[(Float, Float)]) -> Vec<[(Float, Float); 3]> {
+    (1..polygon.len() - 1)
+        .filter_map(|i| {
+            let t = [polygon[0], polygon[i], polygon[i + 1]];
+            let area = signed_area(&t);
+            if area > 0.0 {
+                Some(t)
+            } else if area < 0.0 {
+                Some([t[0], t[2], t[1]])
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The area shared by two coplanar polygons, measured in the plane of `a`. Polygons
+/// are split into triangles as a fan, as when building the scene.
+fn shared_area(a: &Planar, b: &Planar) -> Float {
+    let u = match a.normal.get_perpendicular() {
+        Ok(u) => u.get_normalized(),
+        Err(_) => return 0.0,
+    };
+    let v = a.normal.cross(u);
+    let origin = a.vertices[0];
+    let project = |p: &Point3D| {
+        let d = *p - origin;
+        (d * u, d * v)
+    };
+    let pa: Vec<(Float, Float)> = a.vertices.iter().map(project).collect();
+    let pb: Vec<(Float, Float)> = b.vertices.iter().map(project).collect();
+    let mut area = 0.0;
+    for ta in fan(&pa) {
+        for tb in fan(&pb) {
+            let piece = clip(&tb, &ta);
+            if piece.len() >= 3 {
+                area += signed_area(&piece).abs();
+            }
+        }
+    }
+    area
+}
+
+/// Finds the pairs of polygons that lie on the same plane (within `tolerance`) and
+/// overlap by more than `tolerance²`, as `(first, second, shared_area)` with
+/// `first < second`, sorted. Elements that are `None` (e.g., spheres) are ignored.
+///
+/// Candidate pairs come from a spatial hash of the bounding boxes, so the cost
+/// grows with the number of polygons rather than with the number of pairs.
+pub(crate) fn find_overlaps(
+    polygons: &[Option<Vec<Point3D>>],
+    tolerance: Float,
+) -> Vec<(usize, usize, Float)> {
+    let planars: Vec<Option<Planar>> = polygons
+        .iter()
+        .map(|p| p.as_ref().and_then(|v| Planar::new(v, tolerance)))
+        .collect();
+    let valid: Vec<(usize, &Planar)> = planars
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| p.as_ref().map(|p| (i, p)))
+        .collect();
+    if valid.len() < 2 {
+        return Vec::new();
+    }
+
+    // Cells as large as the typical polygon
+    let mut extents: Vec<Float> = valid
+        .iter()
+        .map(|(_, p)| (0..3).map(|i| p.max[i] - p.min[i]).fold(0.0, Float::max))
+        .collect();
+    extents.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let cell = extents[extents.len() / 2].max(tolerance);
+    let index = |x: Float| (x / cell).floor() as i64;
+
+    let mut grid: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+    let mut large = Vec::new();
+    for (k, (_, p)) in valid.iter().enumerate() {
+        let lo: Vec<i64> = p.min.iter().map(|v| index(*v)).collect();
+        let hi: Vec<i64> = p.max.iter().map(|v| index(*v)).collect();
+        let n_cells: i64 = (0..3).map(|i| hi[i] - lo[i] + 1).product();
+        if n_cells as usize > MAX_CELLS {
+            large.push(k);
+            continue;
+        }
+        for x in lo[0]..=hi[0] {
+            for y in lo[1]..=hi[1] {
+                for z in lo[2]..=hi[2] {
+                    grid.entry((x, y, z)).or_default().push(k);
+                }
+            }
+        }
+    }
+
+    let mut candidates: Vec<(usize, usize)> = Vec::new();
+    for members in grid.values() {
+        for (a, i) in members.iter().enumerate() {
+            for j in &members[a + 1..] {
+                candidates.push((*i.min(j), *i.max(j)));
+            }
+        }
+    }
+    for i in &large {
+        for j in 0..valid.len() {
+            if j != *i {
+                candidates.push((*i.min(&j), *i.max(&j)));
+            }
+        }
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let min_area = tolerance * tolerance;
+    // Candidates are sorted, and so are the valid polygons
+    candidates
+        .into_iter()
+        .filter_map(|(i, j)| {
+            let ((a_index, a), (b_index, b)) = (valid[i], valid[j]);
+            if !a.boxes_overlap(b)
+                || !a.contains_plane_of(b, tolerance)
+                || !b.contains_plane_of(a, tolerance)
+            {
+                return None;
+            }
+            let area = shared_area(a, b);
+            if area > min_area {
+                Some((a_index, b_index, area))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession};
+    use crate::{load_scene, load_scenes_with_coplanar, SensorSpec};
+    use geometry3d::Ray3D;
+    use matrix::Matrix;
+    use validate::assert_close;
+
+    fn square(x: Float, y: Float, size: Float, z: Float) -> Option<Vec<Point3D>> {
+        Some(vec![
+            Point3D::new(x, y, z),
+            Point3D::new(x + size, y, z),
+            Point3D::new(x + size, y + size, z),
+            Point3D::new(x, y + size, z),
+        ])
+    }
+
+    #[test]
+    fn test_find_overlaps() {
+        let mut tilted = square(10., 0., 1., 0.).unwrap();
+        tilted[2].z = 5e-4;
+        tilted[3].z = 5e-4;
+        let polygons = vec![
+            // A duplicate, with its vertices in another order and facing the other way
+            square(0., 0., 1., 0.),
+            square(0., 0., 1., 0.).map(|mut v| {
+                v.reverse();
+                v
+            }),
+            // Adjacent tiles only share an edge
+            square(1., 0., 1., 0.),
+            // Parallel, but too far away
+            square(0., 0., 1., 0.01),
+            // Non-polygons are skipped
+            None,
+            // A quarter of a square, slightly tilted
+            square(10.5, 0.5, 1., 0.),
+            Some(tilted),
+            // Inside another one, as a triangle
+            square(-5., -5., 2., 3.),
+            Some(vec![
+                Point3D::new(-4.5, -4.5, 3.),
+                Point3D::new(-3.5, -4.5, 3.),
+                Point3D::new(-4.5, -3.5, 3.),
+            ]),
+        ];
+        let found = find_overlaps(&polygons, 1e-3);
+        assert_eq!(found.len(), 3, "{:?}", found);
+        assert_eq!((found[0].0, found[0].1), (0, 1));
+        assert_close!(found[0].2, 1., 1e-4);
+        assert_eq!((found[1].0, found[1].1), (5, 6));
+        assert_close!(found[1].2, 0.25, 1e-3);
+        assert_eq!((found[2].0, found[2].1), (7, 8));
+        assert_close!(found[2].2, 0.5, 1e-4);
+
+        // A looser tolerance catches the parallel one
+        let found = find_overlaps(&polygons, 0.02);
+        assert!(found.iter().any(|(i, j, _)| (*i, *j) == (0, 3)));
+    }
+
+    #[test]
+    fn test_find_overlaps_large_grid() {
+        // A floor made of tiles, a rug covering some of them, and a ceiling much
+        // larger than the tiles (which is not put in the grid)
+        let mut polygons = Vec::new();
+        for i in 0..30 {
+            for j in 0..30 {
+                polygons.push(square(i as Float, j as Float, 1., 0.));
+            }
+        }
+        polygons.push(square(0., 0., 30., 3.));
+        polygons.push(square(0., 0., 30., 3.));
+        polygons.push(square(4.5, 4.5, 2., 0.));
+        let found = find_overlaps(&polygons, 1e-3);
+        let rug = polygons.len() - 1;
+        let ceiling = polygons.len() - 3;
+        assert_eq!(found.iter().filter(|o| o.1 == rug).count(), 9);
+        let area: Float = found.iter().filter(|o| o.1 == rug).map(|o| o.2).sum();
+        assert_close!(area, 4., 1e-3);
+        assert_eq!(
+            found
+                .iter()
+                .filter(|o| (o.0, o.1) == (ceiling, ceiling + 1))
+                .count(),
+            1
+        );
+        assert_eq!(found.len(), 10);
+    }
+
+    fn total(path: &str, options: Option<CoplanarOptions>, seed: u64) -> Matrix {
+        let (mut scene, report) = match options {
+            Some(options) => load_scenes_with_coplanar(&[path], &options).unwrap(),
+            None => load_scene(path).unwrap(),
+        };
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 500,
+                max_depth: 2,
+                seed,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = [
+            (Point3D::new(0., 0., 1.), Vector3D::new(0., 0., 1.)),
+            (Point3D::new(1., 0., 1.5), Vector3D::new(0., 0., 1.)),
+        ]
+        .iter()
+        .map(|(origin, direction)| {
+            Ray3D {
+                origin: *origin,
+                direction: *direction,
+            }
+            .into()
+        })
+        .collect();
+        session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap()
+            .total()
+            .clone()
+    }
+
+    fn assert_matrices_close(a: &Matrix, b: &Matrix, tol: Float) {
+        assert_eq!(a.size(), b.size());
+        let (nrows, ncols) = a.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                let (a, b) = (a.get(r, c).unwrap(), b.get(r, c).unwrap());
+                assert_close!(a, b, tol * (1e-3 + b.abs()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        let path = "./tests/coplanar/canopy_painted.rad";
+        let load = |policy| {
+            let options = CoplanarOptions {
+                policy,
+                ..CoplanarOptions::default()
+            };
+            load_scenes_with_coplanar(&[path], &options).unwrap().1
+        };
+
+        let report = load(CoplanarPolicy::Report);
+        assert_eq!(report.n_surfaces, 4);
+        assert_eq!(report.coplanar_overlaps.len(), 1);
+        let overlap = &report.coplanar_overlaps[0];
+        assert_eq!(
+            (overlap.first.as_str(), overlap.second.as_str()),
+            ("ceiling", "ceiling_paint")
+        );
+        assert_close!(overlap.area, 16., 1e-3);
+        assert_eq!(overlap.fix, CoplanarFix::None);
+        assert!(format!("{}", report).contains("are coplanar and overlap"));
+
+        let report = load(CoplanarPolicy::KeepFirst);
+        assert_eq!(report.n_surfaces, 3);
+        assert_eq!(
+            report.coplanar_overlaps[0].fix,
+            CoplanarFix::Removed("ceiling_paint".into())
+        );
+
+        let report = load(CoplanarPolicy::KeepHighestReflectance);
+        assert_eq!(report.n_surfaces, 3);
+        assert_eq!(
+            report.coplanar_overlaps[0].fix,
+            CoplanarFix::Removed("ceiling".into())
+        );
+
+        // The paint faces down, so it is moved below the ceiling
+        let report = load(CoplanarPolicy::Offset(0.01));
+        assert_eq!(report.n_surfaces, 4);
+        assert_eq!(
+            report.coplanar_overlaps[0].fix,
+            CoplanarFix::Offset("ceiling_paint".into(), 0.01)
+        );
+        let paint = report
+            .surfaces
+            .iter()
+            .find(|s| s.name == "ceiling_paint")
+            .unwrap();
+        assert_close!(paint.max.z, 1.9902, 1e-4);
+
+        // Offsetting by less than the tolerance would not fix anything
+        let options = CoplanarOptions {
+            policy: CoplanarPolicy::Offset(1e-4),
+            ..CoplanarOptions::default()
+        };
+        assert!(load_scenes_with_coplanar(&[path], &options).is_err());
+    }
+
+    #[test]
+    fn test_stable_after_fix() {
+        // Once fixed, the results are those of a scene with only the surface kept
+        let painted = "./tests/coplanar/canopy_painted.rad";
+        let with = |policy| {
+            Some(CoplanarOptions {
+                policy,
+                ..CoplanarOptions::default()
+            })
+        };
+        for seed in [1, 2, 3] {
+            let reference = total("./tests/sensitivity/canopy.rad", None, seed);
+            let fixed = total(painted, with(CoplanarPolicy::KeepFirst), seed);
+            assert_matrices_close(&fixed, &reference, 1e-6);
+
+            let reference = total("./tests/coplanar/canopy_paint_only.rad", None, seed);
+            let fixed = total(painted, with(CoplanarPolicy::KeepHighestReflectance), seed);
+            assert_matrices_close(&fixed, &reference, 1e-4);
+            // Offsetting moves the paint a bit, so bins change but rows do not
+            let fixed = total(painted, with(CoplanarPolicy::Offset(1e-2)), seed);
+            let (nrows, ncols) = reference.size();
+            for r in 0..nrows {
+                let row = |m: &Matrix| (0..ncols).map(|c| m.get(r, c).unwrap()).sum::<Float>();
+                assert_close!(row(&fixed), row(&reference), 2e-2 * row(&reference));
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Cumulative skies, which add up the sky vectors of a whole period so that
+//! the total irradiation received by a set of sensors can be calculated with
+//! a single multiplication by their Daylight Coefficients (as in Robinson and
+//! Stone's cumulative sky).
+
+use crate::report::OccupancySchedule;
+use crate::sky::{SkyBasis, SunMapping};
+use crate::time::DateRange;
+use crate::Float;
+use matrix::Matrix;
+use solar::{SkyUnits, Solar};
+use weather::Weather;
+
+/// The options of [`cumulative_sky`]
+#[derive(Debug, Clone)]
+pub struct CumulativeSkyOptions {
+    /// The number of timesteps in each hour. The weather and the position of
+    /// the sun are evaluated in the middle of each of them.
+    pub timesteps_per_hour: usize,
+    /// The units of the sky vectors
+    pub units: SkyUnits,
+    /// The reflectance of the ground
+    pub albedo: Float,
+    /// How the sun is assigned to the patches of the sky
+    pub sun_mapping: SunMapping,
+    /// If given, each timestep is weighted by its occupancy, so only the
+    /// occupied hours count. It must have the same timesteps per hour.
+    pub schedule: Option<OccupancySchedule>,
+}
+
+impl Default for CumulativeSkyOptions {
+    fn default() -> Self {
+        Self {
+            timesteps_per_hour: 1,
+            units: SkyUnits::Solar,
+            albedo: 0.2,
+            sun_mapping: SunMapping::default(),
+            schedule: None,
+        }
+    }
+}
+
+/// Adds up the Perez sky vectors of a Reinhart sky with subdivision `mf` over all
+/// the timesteps of a `period`, as built from the `weather` at the location of
+/// the `solar` data. Each timestep counts for its length, so with
+/// [`SkyUnits::Solar`] the result is the irradiation of each bin in kWh/m².sr
+/// (and in klx.h/sr with [`SkyUnits::Visible`]). The sun is included as the
+/// [`CumulativeSkyOptions::sun_mapping`] says.
+///
+/// Multiplying a Daylight Coefficient matrix by this vector (see [`to_sky_matrix`])
+/// gives the irradiation of each sensor during the period, as adding up the
+/// results of every timestep would.
+///
+/// Timesteps without any solar radiation are skipped, and it is an error if
+/// the weather lacks the direct normal or diffuse horizontal radiation.
+pub fn cumulative_sky<W: Weather + ?Sized>(
+    weather: &W,
+    solar: &Solar,
+    mf: usize,
+    period: &DateRange,
+    options: &CumulativeSkyOptions,
+) -> Result<Vec<Float>, String> {
+    let basis = SkyBasis::new(mf)?;
+    if !(0.0..=1.0).contains(&options.albedo) {
+        return Err(format!(
+            "The albedo must be between 0 and 1, but found {}",
+            options.albedo
+        ));
+    }
+    let timesteps = period.timesteps(options.timesteps_per_hour)?;
+    if let Some(schedule) = &options.schedule {
+        if schedule.timesteps_per_hour != options.timesteps_per_hour {
+            return Err(format!(
+                "The schedule has {} timesteps per hour, but the cumulative sky uses {}",
+                schedule.timesteps_per_hour, options.timesteps_per_hour
+            ));
+        }
+        if let Some(step) = timesteps
+            .iter()
+            .find(|s| s.index_in_year() >= schedule.weights.len())
+        {
+            return Err(format!(
+                "The schedule has {} timesteps, so it does not cover timestep {} of the period",
+                schedule.weights.len(),
+                step.index_in_year()
+            ));
+        }
+    }
+
+    // The length of a timestep in hours, and the conversion into kWh or klx.h
+    let scale = 1. / options.timesteps_per_hour as Float / 1000.;
+    let mut ret = vec![0.0; basis.n_bins()];
+    for step in timesteps {
+        let weight = match &options.schedule {
+            Some(schedule) => schedule.weights[step.index_in_year()],
+            None => 1.,
+        };
+        if weight <= 0.0 {
+            continue;
+        }
+        let date = step.date();
+        let weather_data = weather.get_weather_data(date);
+        let direct = weather_data.direct_normal_radiation.ok_or_else(|| {
+            format!(
+                "Missing direct normal radiation in the weather of {:?}",
+                date
+            )
+        })?;
+        let diffuse = weather_data.diffuse_horizontal_radiation.ok_or_else(|| {
+            format!(
+                "Missing diffuse horizontal radiation in the weather of {:?}",
+                date
+            )
+        })?;
+        if direct + diffuse < 1e-4 {
+            continue;
+        }
+        let vec = basis.gen_sky_vec(
+            solar,
+            date,
+            weather_data,
+            options.units,
+            options.albedo,
+            options.sun_mapping,
+        )?;
+        for (bin, v) in ret.iter_mut().enumerate() {
+            *v += vec.get(bin, 0)? * weight * scale;
+        }
+    }
+    Ok(ret)
+}
+
+/// Turns a sky vector (e.g., one produced by [`cumulative_sky`]) into a sky
+/// matrix with a single column, which can be applied to Daylight Coefficients
+pub fn to_sky_matrix(vec: &[Float]) -> Matrix {
+    let mut ret = Matrix::new(0.0, vec.len(), 1);
+    for (bin, v) in vec.iter().enumerate() {
+        // The matrix has exactly one row per element
+        ret.set(bin, 0, *v).unwrap();
+    }
+    ret
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::annual::annual_irradiance;
+    use calendar::Date;
+    use validate::assert_close;
+    use weather::CurrentWeather;
+
+    /// A clear day that repeats every day, with the sun up from 6 to 18
+    struct RepeatingDay;
+
+    impl Weather for RepeatingDay {
+        fn get_weather_data(&self, date: Date) -> CurrentWeather {
+            let daylight = (date.hour - 6.).max(0.) * (18. - date.hour).max(0.);
+            CurrentWeather {
+                direct_normal_radiation: Some(20. * daylight),
+                diffuse_horizontal_radiation: Some(3. * daylight),
+                ..CurrentWeather::default()
+            }
+        }
+    }
+
+    #[test]
+    fn test_schedule_filter() {
+        let solar = Solar::new(51.5, 0.0, 0.0);
+        let period = DateRange::new((6, 20), (6, 22)).unwrap();
+
+        // Nobody is ever there, so nothing counts
+        let options = CumulativeSkyOptions {
+            schedule: Some(OccupancySchedule::from_weights(vec![0.0; 8760], 1).unwrap()),
+            ..CumulativeSkyOptions::default()
+        };
+        let sky = cumulative_sky(&RepeatingDay, &solar, 1, &period, &options).unwrap();
+        assert_eq!(sky.len(), SkyBasis::new(1).unwrap().n_bins());
+        assert!(sky.iter().all(|v| *v == 0.0));
+
+        // Schedules must match the timesteps and cover the period
+        let options = CumulativeSkyOptions {
+            timesteps_per_hour: 2,
+            ..options
+        };
+        assert!(cumulative_sky(&RepeatingDay, &solar, 1, &period, &options).is_err());
+        let options = CumulativeSkyOptions {
+            timesteps_per_hour: 1,
+            schedule: Some(OccupancySchedule::from_weights(vec![1.0; 24 * 100], 1).unwrap()),
+            ..options
+        };
+        assert!(cumulative_sky(&RepeatingDay, &solar, 1, &period, &options).is_err());
+        assert!(cumulative_sky(&RepeatingDay, &solar, 0, &period, &options).is_err());
+    }
+
+    #[test]
+    fn test_cumulative_equals_sum() {
+        let solar = Solar::new(51.5, 0.0, 0.0);
+        let basis = SkyBasis::new(1).unwrap();
+        let period = DateRange::new((6, 20), (6, 22)).unwrap();
+        for sun_mapping in [SunMapping::Nearest, SunMapping::Shared(4)] {
+            let options = CumulativeSkyOptions {
+                timesteps_per_hour: 2,
+                sun_mapping,
+                ..CumulativeSkyOptions::default()
+            };
+            let cumulative = cumulative_sky(&RepeatingDay, &solar, 1, &period, &options).unwrap();
+
+            // One column per timestep
+            let steps = period.timesteps(2).unwrap();
+            let mut skies = Matrix::new(0.0, basis.n_bins(), steps.len());
+            for (col, step) in steps.iter().enumerate() {
+                let date = step.date();
+                let weather_data = RepeatingDay.get_weather_data(date);
+                if weather_data.direct_normal_radiation.unwrap() < 1e-4 {
+                    continue;
+                }
+                let vec = basis
+                    .gen_sky_vec(
+                        &solar,
+                        date,
+                        weather_data,
+                        SkyUnits::Solar,
+                        0.2,
+                        sun_mapping,
+                    )
+                    .unwrap();
+                for bin in 0..basis.n_bins() {
+                    skies.set(bin, col, vec.get(bin, 0).unwrap()).unwrap();
+                }
+            }
+
+            // Two sensors that see different parts of the sky
+            let mut dc = Matrix::new(0.0, 2, basis.n_bins());
+            for bin in 0..basis.n_bins() {
+                dc.set(0, bin, 0.01).unwrap();
+                dc.set(1, bin, 0.02 * (bin % 7) as Float).unwrap();
+            }
+            let each = annual_irradiance(&dc, &skies).unwrap();
+            let total = annual_irradiance(&dc, &to_sky_matrix(&cumulative)).unwrap();
+            for sensor in 0..2 {
+                let mut expected = 0.0;
+                for col in 0..steps.len() {
+                    expected += each.get(sensor, col).unwrap() * 0.5 / 1000.;
+                }
+                let found = total.get(sensor, 0).unwrap();
+                assert!(expected > 0.0);
+                assert_close!(found, expected, 1e-4 * expected);
+            }
+        }
+    }
+}
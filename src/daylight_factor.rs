@@ -0,0 +1,364 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The Daylight Factor: the illuminance at each sensor under a CIE standard
+//! overcast sky, as a percentage of the one on an unobstructed horizontal plane.
+//!
+//! Like the split-flux method, the result is the sum of a sky component and
+//! a reflected one. The sky component is integrated patch by patch over a
+//! fixed grid of directions, weighting the radiance of each patch by the cosine
+//! of its centre—just like [`SkyBasis::cie_overcast`] does when normalising the
+//! sky—so it is not noisy, and a sensor facing up that sees the whole sky gets
+//! exactly 100%. The reference illuminance is not traced either, so Monte Carlo
+//! noise only comes from the reflected component (i.e., when `max_depth` is
+//! larger than `0`).
+
+use crate::obstruction::escapes;
+use crate::scene_loading::SceneReport;
+use crate::sensor::SensorSpec;
+use crate::session::{DCOptions, DCSession};
+use crate::sky::SkyBasis;
+use crate::zones::ZoneGroups;
+use crate::Float;
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use rendering::Scene;
+
+/// The horizontal illuminance of the unobstructed overcast sky. It cancels
+/// out in the ratios, so any value works.
+const REFERENCE_ILLUMINANCE: Float = 10_000.;
+
+/// The options of [`daylight_factor`]
+#[derive(Debug, Clone, Copy)]
+pub struct DaylightFactorOptions {
+    /// The subdivision of the Reinhart sky
+    pub mf: usize,
+
+    /// The number of directions along the altitude and the azimuth of each
+    /// patch used for finding out how much of it a sensor sees (i.e., each
+    /// patch is checked with the square of this number of rays)
+    pub patch_resolution: usize,
+
+    /// The options for tracing the reflected component. With a `max_depth` of
+    /// `0`, only the sky component is calculated.
+    pub dc: DCOptions,
+
+    /// The reflectance of the ground, which is only seen through the
+    /// reflected component
+    pub albedo: Float,
+
+    /// The Daylight Factor (in percent) above which a sensor counts
+    /// towards [`ZoneDaylightFactor::area_above`]
+    pub threshold: Float,
+}
+
+impl std::default::Default for DaylightFactorOptions {
+    fn default() -> Self {
+        Self {
+            mf: 1,
+            patch_resolution: 4,
+            dc: DCOptions::default(),
+            albedo: 0.2,
+            threshold: 2.,
+        }
+    }
+}
+
+/// The Daylight Factor of the sensors of a zone
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneDaylightFactor {
+    /// The name of the zone
+    pub zone: String,
+
+    /// The number of sensors in the zone
+    pub n_sensors: usize,
+
+    /// The average Daylight Factor of the zone, in percent
+    pub mean: Float,
+
+    /// The fraction of the zone where the Daylight Factor reaches the
+    /// threshold of the [`DaylightFactorOptions`]
+    pub area_above: Float,
+}
+
+/// The results of [`daylight_factor`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaylightFactors {
+    /// The Daylight Factor of each sensor, in percent
+    pub values: Vec<Float>,
+
+    /// The part of `values` that comes straight from the sky
+    pub sky_component: Vec<Float>,
+
+    /// One element per zone, in order of first appearance. Sensors are weighted
+    /// by their [`TributaryArea`](crate::TributaryArea) if all sensors of the zone
+    /// have one, and equally otherwise.
+    pub zones: Vec<ZoneDaylightFactor>,
+}
+
+/// The coefficients of the patches of the sky seen directly by a sensor. Each of
+/// them is the solid angle of the patch, times the cosine of its centre, times
+/// the fraction of the patch that is visible.
+fn sky_component_row(
+    basis: &SkyBasis,
+    sensor: &SensorSpec,
+    scene: &Scene,
+    resolution: usize,
+) -> Result<Vec<Float>, String> {
+    let normal = sensor.ray.direction;
+    let mut aux = Vec::with_capacity(2);
+    let mut ret = vec![0.0; basis.n_bins()];
+    for patch in basis.patches() {
+        let cosine = patch.centroid * normal;
+        if cosine <= 0.0 {
+            continue;
+        }
+        let (min_sin, max_sin) = (patch.altitude.0.sin(), patch.altitude.1.sin());
+        let (min_az, max_az) = patch.azimuth;
+        let mut visible = 0;
+        for i in 0..resolution {
+            // Equal steps in the sine of the altitude cover equal solid angles
+            let sin_alt = min_sin + (i as Float + 0.5) / resolution as Float * (max_sin - min_sin);
+            let cos_alt = (1. - sin_alt * sin_alt).max(0.0).sqrt();
+            for j in 0..resolution {
+                let az = min_az + (j as Float + 0.5) / resolution as Float * (max_az - min_az);
+                let dir = Vector3D::new(az.sin() * cos_alt, az.cos() * cos_alt, sin_alt);
+                let in_view = match &sensor.mask {
+                    Some(mask) => mask.is_visible(normal, dir)?,
+                    None => dir * normal > 0.0,
+                };
+                if in_view && escapes(scene, sensor.ray.origin, dir, &mut aux) {
+                    visible += 1;
+                }
+            }
+        }
+        let fraction = visible as Float / (resolution * resolution) as Float;
+        ret[patch.bin] = patch.solid_angle * cosine * fraction;
+    }
+    Ok(ret)
+}
+
+/// The reflected component of the Daylight Coefficients: everything that bounces
+/// before reaching the sky, plus the ground seen directly
+fn reflected_component(
+    session: &DCSession,
+    sensors: &[SensorSpec],
+    scene: &Scene,
+    report: &SceneReport,
+) -> Result<Matrix, String> {
+    let components = session.calc_externally_reflected_dc(sensors, scene, report)?;
+    let sky = components.sky;
+    let mut ret = components.externally_reflected;
+    let ground = SkyBasis::GROUND_BIN;
+    for r in 0..sensors.len() {
+        ret.set(r, ground, ret.get(r, ground)? + sky.get(r, ground)?)?;
+    }
+    Ok(ret)
+}
+
+/// Summarises the Daylight Factor of each zone
+fn zone_daylight_factors(
+    sensors: &[SensorSpec],
+    values: &[Float],
+    threshold: Float,
+) -> Vec<ZoneDaylightFactor> {
+    let groups = ZoneGroups::from_sensors(sensors);
+    let mut ret = Vec::with_capacity(groups.len());
+    for (name, members) in groups.names.iter().zip(groups.members.iter()) {
+        let weights: Vec<Float> = if members.iter().all(|i| sensors[*i].area.is_some()) {
+            members
+                .iter()
+                .filter_map(|i| sensors[*i].area.map(|a| a.area()))
+                .collect()
+        } else {
+            vec![1.; members.len()]
+        };
+        let total: Float = weights.iter().sum();
+        let mut mean = 0.0;
+        let mut above = 0.0;
+        for (i, w) in members.iter().zip(weights.iter()) {
+            mean += w * values[*i];
+            if values[*i] >= threshold {
+                above += w;
+            }
+        }
+        ret.push(ZoneDaylightFactor {
+            zone: name.clone(),
+            n_sensors: members.len(),
+            mean: mean / total,
+            area_above: above / total,
+        });
+    }
+    ret
+}
+
+/// Calculates the Daylight Factor of some `sensors` (see the module documentation).
+///
+/// The reflected component is traced with the simplified model of
+/// [`DCSession::calc_externally_reflected_dc`], for which the `report` returned
+/// when loading the `scene` says what each surface is made of. It is not used
+/// when `max_depth` is `0`.
+pub fn daylight_factor(
+    sensors: &[SensorSpec],
+    scene: &Scene,
+    report: &SceneReport,
+    options: &DaylightFactorOptions,
+) -> Result<DaylightFactors, String> {
+    if options.patch_resolution == 0 {
+        return Err("The patches of the sky need at least one direction each".to_string());
+    }
+    if !(options.threshold.is_finite() && options.threshold >= 0.0) {
+        return Err(format!(
+            "The threshold of the Daylight Factor must be a non-negative percentage, but found {}",
+            options.threshold
+        ));
+    }
+    let session = DCSession::new(options.mf, options.dc);
+    let basis = session.basis()?;
+    let sky = basis.cie_overcast(REFERENCE_ILLUMINANCE, options.albedo)?;
+    let reflected = if options.dc.is_direct() {
+        None
+    } else {
+        Some(reflected_component(&session, sensors, scene, report)?)
+    };
+    let to_percent = 100. / REFERENCE_ILLUMINANCE;
+
+    let mut values = Vec::with_capacity(sensors.len());
+    let mut sky_component = Vec::with_capacity(sensors.len());
+    for (r, sensor) in sensors.iter().enumerate() {
+        let row = sky_component_row(&basis, sensor, scene, options.patch_resolution)?;
+        let mut direct = 0.0;
+        for (bin, c) in row.iter().enumerate() {
+            direct += c * sky.get(bin, 0)?;
+        }
+        let mut total = direct;
+        if let Some(reflected) = &reflected {
+            for bin in 0..basis.n_bins() {
+                total += reflected.get(r, bin)? * sky.get(bin, 0)?;
+            }
+        }
+        sky_component.push(direct * to_percent);
+        values.push(total * to_percent);
+    }
+    let zones = zone_daylight_factors(sensors, &values, options.threshold);
+    Ok(DaylightFactors {
+        values,
+        sky_component,
+        zones,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::test_support::{parallel_rectangle_view_factor, square_aperture};
+    use crate::TributaryArea;
+    use geometry3d::{Point3D, Ray3D};
+    use validate::assert_close;
+
+    fn direct_options() -> DaylightFactorOptions {
+        DaylightFactorOptions {
+            dc: DCOptions {
+                max_depth: 0,
+                ..DCOptions::default()
+            },
+            ..DaylightFactorOptions::default()
+        }
+    }
+
+    fn up(x: Float, y: Float, z: Float) -> SensorSpec {
+        SensorSpec::from(Ray3D {
+            origin: Point3D::new(x, y, z),
+            direction: Vector3D::new(0., 0., 1.),
+        })
+    }
+
+    #[test]
+    fn test_unobstructed() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let report = SceneReport::default();
+        for mf in [1, 2] {
+            let options = DaylightFactorOptions {
+                mf,
+                ..direct_options()
+            };
+            let df = daylight_factor(&[up(0., 0., 0.)], &scene, &report, &options).unwrap();
+            assert_close!(df.values[0], 100., 1e-3);
+            assert_close!(df.sky_component[0], 100., 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_small_aperture() {
+        // Under a skylight, the sky seen by the sensor is close to the zenith,
+        // where the overcast sky is 9/7 brighter than its average
+        let (a, height) = (1., 2.);
+        let reference = square_aperture(a, height).unwrap();
+        let report = SceneReport::default();
+        let view_factor = 4. * parallel_rectangle_view_factor(0.5 * a, 0.5 * a, height);
+        let split_flux = 100. * view_factor * 9. / 7.;
+        let options = DaylightFactorOptions {
+            mf: 2,
+            patch_resolution: 8,
+            ..direct_options()
+        };
+        let df = daylight_factor(&[up(0., 0., 0.)], &reference.scene, &report, &options).unwrap();
+        assert!(
+            (df.values[0] - split_flux).abs() < 0.1 * split_flux,
+            "{} vs {}",
+            df.values[0],
+            split_flux
+        );
+    }
+
+    #[test]
+    fn test_zones() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let report = SceneReport::default();
+        let area = |side: Float| TributaryArea {
+            u: Vector3D::new(side, 0., 0.),
+            v: Vector3D::new(0., side, 0.),
+        };
+        let mut sensors = vec![up(0., 0., 0.).with_zone("a"), up(1., 0., 0.)];
+        sensors[0].area = Some(area(1.));
+        let df = daylight_factor(&sensors, &scene, &report, &direct_options()).unwrap();
+        assert_eq!(df.values.len(), 2);
+        assert_eq!(df.zones.len(), 1);
+        assert_eq!(df.zones[0].zone, "a");
+        assert_eq!(df.zones[0].n_sensors, 1);
+        assert_close!(df.zones[0].mean, 100., 1e-3);
+        assert_eq!(df.zones[0].area_above, 1.);
+
+        // Nothing reaches an impossible threshold
+        let options = DaylightFactorOptions {
+            threshold: 1000.,
+            ..direct_options()
+        };
+        let df = daylight_factor(&sensors, &scene, &report, &options).unwrap();
+        assert_eq!(df.zones[0].area_above, 0.);
+        let options = DaylightFactorOptions {
+            patch_resolution: 0,
+            ..direct_options()
+        };
+        assert!(daylight_factor(&sensors, &scene, &report, &options).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Profiles of per-sensor results (e.g., Daylight Autonomy) against the depth
+//! of the sensors into a room, i.e., their distance from the façade.
+//!
+//! Sensors are put into bins of a fixed width by depth, and each bin gets the
+//! mean and a percentile of the values of its sensors, at the mean depth of
+//! those sensors. The depth at which a statistic drops below a threshold (e.g.,
+//! "how deep does DA stay above 50%?") is interpolated between bins.
+
+use crate::grid::SensorGrid;
+use crate::scene_loading::SceneReport;
+use crate::sensitivity::glass_transmissivity;
+use crate::Float;
+use geometry3d::{Point3D, Vector3D};
+use serde::{Deserialize, Serialize};
+
+/// How far (in metres) sensors can be in front of the façade, to account
+/// for rounding
+const TOLERANCE: Float = 1e-6;
+
+/// A vertical façade, seen from above as a line
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Facade {
+    /// A point on the façade (its elevation is ignored)
+    pub point: Point3D,
+
+    /// The horizontal direction in which the façade faces, away from the
+    /// room (its vertical component is ignored)
+    pub outward: Vector3D,
+}
+
+impl Facade {
+    /// A façade through `point`, facing `outward`
+    pub fn new(point: Point3D, outward: Vector3D) -> Result<Self, String> {
+        let length = (outward.x * outward.x + outward.y * outward.y).sqrt();
+        if !(length.is_finite() && length > TOLERANCE) {
+            return Err(format!(
+                "A façade needs a horizontal direction, but found ({}, {}, {})",
+                outward.x, outward.y, outward.z
+            ));
+        }
+        Ok(Self {
+            point,
+            outward: Vector3D::new(outward.x / length, outward.y / length, 0.),
+        })
+    }
+
+    /// The façade along the edge of the boundary of a `grid` that is nearest
+    /// to a window (i.e., to the centre of a surface made of glass in the
+    /// scene, at the elevation of the sensors)
+    pub fn infer(grid: &SensorGrid, report: &SceneReport) -> Result<Self, String> {
+        let windows: Vec<Point3D> = report
+            .surfaces
+            .iter()
+            .filter(|s| {
+                report
+                    .materials
+                    .iter()
+                    .find(|m| m.name == s.modifier)
+                    .is_some_and(|m| glass_transmissivity(&m.kind, m.rgb) > 0.0)
+            })
+            .filter(|s| !s.vertices.is_empty())
+            .map(|s| {
+                let n = s.vertices.len() as Float;
+                let (x, y, z) = s.vertices.iter().fold((0., 0., 0.), |(x, y, z), p| {
+                    (x + p.x / n, y + p.y / n, z + p.z / n)
+                });
+                Point3D::new(x, y, z)
+            })
+            .collect();
+        if windows.is_empty() {
+            return Err("The façade cannot be inferred from a scene with no windows".to_string());
+        }
+        let boundary = grid.boundary();
+        let z = grid.elevation();
+        let mut nearest: Option<(usize, Float)> = None;
+        for (k, a) in boundary.iter().enumerate() {
+            let b = boundary[(k + 1) % boundary.len()];
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let squared_length = dx * dx + dy * dy;
+            if squared_length <= TOLERANCE * TOLERANCE {
+                continue;
+            }
+            for w in windows.iter() {
+                let t = (((w.x - a.0) * dx + (w.y - a.1) * dy) / squared_length).clamp(0., 1.);
+                let (x, y) = (a.0 + t * dx, a.1 + t * dy);
+                let d = (w.x - x).powi(2) + (w.y - y).powi(2) + (w.z - z).powi(2);
+                if nearest.is_none_or(|(_, best)| d < best) {
+                    nearest = Some((k, d));
+                }
+            }
+        }
+        let (k, _) = nearest.ok_or_else(|| "The grid has no edges to be a façade".to_string())?;
+        let a = boundary[k];
+        let b = boundary[(k + 1) % boundary.len()];
+        let length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+        let (mut nx, mut ny) = ((b.1 - a.1) / length, (a.0 - b.0) / length);
+        // The normal that leaves the polygon
+        let step = 1e-3 * length;
+        let middle = (0.5 * (a.0 + b.0), 0.5 * (a.1 + b.1));
+        if grid.contains(middle.0 + step * nx, middle.1 + step * ny) {
+            (nx, ny) = (-nx, -ny);
+        }
+        Self::new(Point3D::new(a.0, a.1, z), Vector3D::new(nx, ny, 0.))
+    }
+
+    /// How far behind the façade (i.e., into the room) a point of the plane is
+    pub fn depth(&self, x: Float, y: Float) -> Float {
+        -((x - self.point.x) * self.outward.x + (y - self.point.y) * self.outward.y)
+    }
+}
+
+/// The statistics of the sensors in a bin of depths
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DepthBin {
+    /// The mean depth of the sensors in the bin
+    pub depth: Float,
+
+    /// The number of sensors in the bin
+    pub n_sensors: usize,
+
+    /// The mean of their values
+    pub mean: Float,
+
+    /// The percentile of their values (see [`DepthProfile::percentile`])
+    pub percentile: Float,
+}
+
+/// Which statistic of the bins a [`DepthProfile`] looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProfileStatistic {
+    /// The mean of the values in the bin
+    Mean,
+
+    /// The percentile of the values in the bin
+    Percentile,
+}
+
+/// A per-sensor result, binned by the depth of the sensors
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepthProfile {
+    /// The width of the bins, in metres
+    pub bin_width: Float,
+
+    /// The percentile of each bin (from `0` to `100`)
+    pub percentile: Float,
+
+    /// The bins that have sensors, from the façade inwards
+    pub bins: Vec<DepthBin>,
+}
+
+impl DepthProfile {
+    /// Bins the `values` of the sensors of a `grid` (one per sensor, in the
+    /// order of [`SensorGrid::sensors`]) by their depth behind a `facade`, in
+    /// bins of `bin_width` metres starting at the façade. Each bin gets the
+    /// mean and the `percentile` (from `0` to `100`, interpolated linearly
+    /// between the sorted values) of its values.
+    pub fn new(
+        grid: &SensorGrid,
+        facade: &Facade,
+        values: &[Float],
+        bin_width: Float,
+        percentile: Float,
+    ) -> Result<Self, String> {
+        if !(bin_width.is_finite() && bin_width > 0.0) {
+            return Err(format!(
+                "The bins of a depth profile must have a positive width, but found {}",
+                bin_width
+            ));
+        }
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(format!(
+                "Percentiles go from 0 to 100, but found {}",
+                percentile
+            ));
+        }
+        if values.len() != grid.len() {
+            return Err(format!(
+                "A grid of {} sensors cannot be profiled with {} values",
+                grid.len(),
+                values.len()
+            ));
+        }
+        // The depth and value of the sensors in each bin
+        let mut binned: Vec<Vec<(Float, Float)>> = Vec::new();
+        for (i, ((x, y), v)) in grid.positions().into_iter().zip(values).enumerate() {
+            let depth = facade.depth(x, y);
+            if depth < -TOLERANCE {
+                return Err(format!(
+                    "Sensor {} is {} m in front of the façade, which may be facing the wrong way",
+                    i, -depth
+                ));
+            }
+            let k = (depth.max(0.0) / bin_width) as usize;
+            if binned.len() <= k {
+                binned.resize(k + 1, Vec::new());
+            }
+            binned[k].push((depth, *v));
+        }
+        let bins = binned
+            .into_iter()
+            .filter(|bin| !bin.is_empty())
+            .map(|bin| {
+                let n = bin.len() as Float;
+                let mut sorted: Vec<Float> = bin.iter().map(|(_, v)| *v).collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let position = percentile / 100. * (sorted.len() - 1) as Float;
+                let low = position.floor() as usize;
+                let high = (low + 1).min(sorted.len() - 1);
+                let t = position - low as Float;
+                DepthBin {
+                    depth: bin.iter().map(|(d, _)| d).sum::<Float>() / n,
+                    n_sensors: bin.len(),
+                    mean: sorted.iter().sum::<Float>() / n,
+                    percentile: (1. - t) * sorted[low] + t * sorted[high],
+                }
+            })
+            .collect();
+        Ok(Self {
+            bin_width,
+            percentile,
+            bins,
+        })
+    }
+
+    /// The depth at which a `statistic` of the bins first drops below a
+    /// `threshold`, interpolated linearly between the last bin at or above it
+    /// and the next one. It is `None` if the statistic never drops below the
+    /// threshold, or if not even the bin nearest to the façade reaches it.
+    pub fn crossing_depth(&self, statistic: ProfileStatistic, threshold: Float) -> Option<Float> {
+        let value = |bin: &DepthBin| match statistic {
+            ProfileStatistic::Mean => bin.mean,
+            ProfileStatistic::Percentile => bin.percentile,
+        };
+        if value(self.bins.first()?) < threshold {
+            return None;
+        }
+        self.bins.windows(2).find_map(|pair| {
+            let (a, b) = (value(&pair[0]), value(&pair[1]));
+            if a >= threshold && b < threshold {
+                let t = (a - threshold) / (a - b);
+                Some(pair[0].depth + t * (pair[1].depth - pair[0].depth))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Writes the bins as CSV, one per line
+    pub fn to_csv(&self) -> String {
+        let mut ret = format!("depth,n_sensors,mean,p{}\n", self.percentile);
+        for bin in self.bins.iter() {
+            ret.push_str(&format!(
+                "{},{},{},{}\n",
+                bin.depth, bin.n_sensors, bin.mean, bin.percentile
+            ));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{Material, SceneBuilder};
+    use validate::assert_close;
+
+    /// A room 10 m deep (along X) and 4 m wide, with a window at X = 0 and
+    /// another one at X = 10 on the storey above
+    fn room() -> (SensorGrid, SceneReport) {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("glazing", Material::glass(0.6))
+            .unwrap();
+        let windows = [
+            (
+                "window",
+                [(0., 1., 1.), (0., 3., 1.), (0., 3., 2.), (0., 1., 2.)],
+            ),
+            (
+                "upstairs",
+                [(10., 1., 4.), (10., 3., 4.), (10., 3., 5.), (10., 1., 5.)],
+            ),
+        ];
+        for (name, vertices) in windows.iter() {
+            let vertices = vertices.map(|(x, y, z)| Point3D::new(x, y, z));
+            builder.add_polygon("glazing", name, &vertices).unwrap();
+        }
+        let (_, report) = builder.build().unwrap();
+        let boundary =
+            [(0., 0.), (10., 0.), (10., 4.), (0., 4.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        let grid = SensorGrid::new(&boundary, &[], 0.5, 0.8).unwrap();
+        (grid, report)
+    }
+
+    #[test]
+    fn test_infer_facade() {
+        let (grid, report) = room();
+        let facade = Facade::infer(&grid, &report).unwrap();
+        assert_close!(facade.outward.x, -1.);
+        assert_close!(facade.outward.y, 0.);
+        assert_close!(facade.depth(2.5, 1.), 2.5);
+
+        // No windows
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("wall", Material::plastic(0.5))
+            .unwrap();
+        let vertices = [(0., 0., 0.), (0., 4., 0.), (0., 4., 3.), (0., 0., 3.)]
+            .map(|(x, y, z)| Point3D::new(x, y, z));
+        builder.add_polygon("wall", "wall", &vertices).unwrap();
+        let (_, opaque) = builder.build().unwrap();
+        assert!(Facade::infer(&grid, &opaque).is_err());
+    }
+
+    #[test]
+    fn test_linear_decay() {
+        let (grid, report) = room();
+        let facade = Facade::infer(&grid, &report).unwrap();
+        let values: Vec<Float> = grid.positions().iter().map(|(x, _)| 1. - 0.1 * x).collect();
+        let profile = DepthProfile::new(&grid, &facade, &values, 1., 90.).unwrap();
+        assert_eq!(profile.bins.len(), 10);
+        for (k, bin) in profile.bins.iter().enumerate() {
+            assert_eq!(bin.n_sensors, 16);
+            assert_close!(bin.depth, k as Float + 0.5);
+            assert_close!(bin.mean, 1. - 0.1 * bin.depth);
+            assert!(bin.percentile >= bin.mean);
+        }
+        let depth = profile.crossing_depth(ProfileStatistic::Mean, 0.5).unwrap();
+        assert_close!(depth, 5., 1e-5);
+        let depth = profile
+            .crossing_depth(ProfileStatistic::Mean, 0.63)
+            .unwrap();
+        assert_close!(depth, 3.7, 1e-5);
+        // Values in a bin go from 1 - 0.1 * (k + 0.25) to 1 - 0.1 * (k + 0.75)
+        let depth = profile
+            .crossing_depth(ProfileStatistic::Percentile, 0.5)
+            .unwrap();
+        assert!(depth > 5.);
+
+        // Never above, or never below
+        assert!(profile.crossing_depth(ProfileStatistic::Mean, 2.).is_none());
+        assert!(profile
+            .crossing_depth(ProfileStatistic::Mean, -1.)
+            .is_none());
+        assert_eq!(profile.to_csv().lines().count(), 11);
+    }
+
+    #[test]
+    fn test_declared_facade() {
+        // The long side of the room, facing -Y
+        let (grid, _) = room();
+        let facade = Facade::new(Point3D::new(0., 0., 0.), Vector3D::new(0., -2., 0.)).unwrap();
+        let values: Vec<Float> = grid
+            .positions()
+            .iter()
+            .map(|(_, y)| 0.8 - 0.2 * y)
+            .collect();
+        let profile = DepthProfile::new(&grid, &facade, &values, 0.5, 50.).unwrap();
+        assert_eq!(profile.bins.len(), 8);
+        let depth = profile
+            .crossing_depth(ProfileStatistic::Percentile, 0.3)
+            .unwrap();
+        assert_close!(depth, 2.5, 1e-5);
+
+        // Facing the wrong way
+        let inward = Facade::new(Point3D::new(0., 0., 0.), Vector3D::new(0., 1., 0.)).unwrap();
+        let err = DepthProfile::new(&grid, &inward, &values, 0.5, 50.).unwrap_err();
+        assert!(err.contains("wrong way"), "{}", err);
+        assert!(Facade::new(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.)).is_err());
+        assert!(DepthProfile::new(&grid, &facade, &values, 0., 50.).is_err());
+        assert!(DepthProfile::new(&grid, &facade, &values, 1., 101.).is_err());
+        assert!(DepthProfile::new(&grid, &facade, &values[1..], 1., 50.).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Illuminance at specific design hours (e.g., 10:00 on the 21st of December)
+//! under defined skies, as required by guidance on schools and healthcare
+//! buildings, and its evaluation against per-hour thresholds.
+//!
+//! The Daylight Coefficients of the grid are traced once, and the sky vector
+//! of each hour is built internally. Every sky is normalised on the discretised
+//! sky, so an unobstructed sensor facing up receives exactly the horizontal
+//! illuminance of its [`SkyCondition`] (up to the noise of the trace).
+
+use crate::grid::SensorGrid;
+use crate::session::{DCOptions, DCSession};
+use crate::sky::SkyBasis;
+use crate::sun_hours::rotate_to_scene;
+use crate::time::{Timestamp, HOURS_PER_YEAR};
+use crate::Float;
+use calendar::Date;
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use rendering::Scene;
+use solar::{Solar, Time};
+
+/// The clock in which the date of a [`DesignHour`] is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DesignClock {
+    /// The standard time of the time zone, whose meridian is the one the
+    /// `Solar` of the location was built with
+    #[default]
+    Standard,
+
+    /// The standard time plus one hour
+    DaylightSaving,
+
+    /// The apparent solar time, in which the sun crosses the meridian at noon
+    Solar,
+}
+
+/// The sky under which a [`DesignHour`] is evaluated. Each of them produces
+/// its `horizontal_illuminance` (in lux) on an unobstructed horizontal plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkyCondition {
+    /// The CIE standard overcast sky, whose luminance at an altitude `α` is
+    /// proportional to `1 + 2 sin α`
+    CieOvercast {
+        /// The illuminance on an unobstructed horizontal plane
+        horizontal_illuminance: Float,
+    },
+
+    /// The CIE standard clear sky, without the sun itself. Its luminance
+    /// depends on the position of the sun, so the sun must be above the horizon.
+    CieClear {
+        /// The illuminance on an unobstructed horizontal plane
+        horizontal_illuminance: Float,
+    },
+
+    /// A sky of the same luminance in every direction
+    Uniform {
+        /// The illuminance on an unobstructed horizontal plane
+        horizontal_illuminance: Float,
+    },
+}
+
+impl SkyCondition {
+    /// The illuminance that the sky produces on an unobstructed horizontal plane
+    pub fn horizontal_illuminance(&self) -> Float {
+        match self {
+            Self::CieOvercast {
+                horizontal_illuminance,
+            }
+            | Self::CieClear {
+                horizontal_illuminance,
+            }
+            | Self::Uniform {
+                horizontal_illuminance,
+            } => *horizontal_illuminance,
+        }
+    }
+}
+
+/// An instant at which the illuminance of a grid is required
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DesignHour {
+    /// The month, day and hour
+    pub date: Date,
+
+    /// The clock in which the `date` is given
+    pub clock: DesignClock,
+
+    /// The sky at that instant
+    pub sky: SkyCondition,
+}
+
+impl DesignHour {
+    /// An instant given in the standard time of the location
+    pub fn new(month: u8, day: u8, hour: Float, sky: SkyCondition) -> Self {
+        Self {
+            date: Date { month, day, hour },
+            clock: DesignClock::Standard,
+            sky,
+        }
+    }
+
+    /// Sets the clock in which the date is given
+    pub fn with_clock(mut self, clock: DesignClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The time at which the position of the sun is calculated: daylight
+    /// saving dates are moved back an hour (into the previous day, if needed)
+    fn time(&self) -> Result<Time, String> {
+        let instant = Timestamp::from_date(self.date)?;
+        let date = match self.clock {
+            DesignClock::Standard | DesignClock::Solar => self.date,
+            DesignClock::DaylightSaving => Timestamp {
+                hour_of_year: (instant.hour_of_year - 1. + HOURS_PER_YEAR as Float)
+                    % HOURS_PER_YEAR as Float,
+            }
+            .date(),
+        };
+        Ok(match self.clock {
+            DesignClock::Solar => Time::Solar(date.day_of_year()),
+            _ => Time::Standard(date.day_of_year()),
+        })
+    }
+}
+
+/// The options of [`design_hours_illuminance`]
+#[derive(Debug, Clone, Copy)]
+pub struct DesignHourOptions {
+    /// The subdivision of the Reinhart sky
+    pub mf: usize,
+
+    /// The options of the Daylight Coefficients, traced once for all the hours
+    pub dc: DCOptions,
+
+    /// The reflectance of the ground, lit by the horizontal illuminance of each sky
+    pub albedo: Float,
+
+    /// The azimuth of the true North (in degrees), measured from the `+Y` axis
+    /// of the scene towards `+X` (see [`rotate_to_scene`])
+    pub north_offset: Float,
+}
+
+impl std::default::Default for DesignHourOptions {
+    fn default() -> Self {
+        Self {
+            mf: 1,
+            dc: DCOptions::default(),
+            albedo: 0.2,
+            north_offset: 0.,
+        }
+    }
+}
+
+/// A minimum illuminance, and the percentage of the area that needs to reach it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DesignThreshold {
+    /// The illuminance that a sensor needs to reach, in lux
+    pub illuminance: Float,
+
+    /// The percentage of the area of the grid that needs to reach it
+    pub required_area: Float,
+}
+
+/// How a grid fares against the [`DesignThreshold`] of a design hour
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HourCompliance {
+    /// The percentage of the area that reaches the threshold
+    pub area: Float,
+
+    /// Whether that area reaches the required one
+    pub passes: bool,
+}
+
+/// The results of [`design_hours_illuminance`]
+#[derive(Debug, Clone)]
+pub struct DesignHourIlluminance {
+    /// The hours, in the order of the columns of `values`
+    pub hours: Vec<DesignHour>,
+
+    /// The illuminance (in lux) of each sensor of the grid (rows, in the
+    /// order of [`SensorGrid::sensors`]) at each hour (columns)
+    pub values: Matrix,
+
+    /// The area represented by each sensor
+    pub areas: Vec<Float>,
+}
+
+impl DesignHourIlluminance {
+    /// Checks each hour against its threshold (one per hour, in the same order)
+    pub fn evaluate(&self, thresholds: &[DesignThreshold]) -> Result<Vec<HourCompliance>, String> {
+        if thresholds.len() != self.hours.len() {
+            return Err(format!(
+                "There are {} design hours, but {} thresholds",
+                self.hours.len(),
+                thresholds.len()
+            ));
+        }
+        let total: Float = self.areas.iter().sum();
+        let mut ret = Vec::with_capacity(thresholds.len());
+        for (col, threshold) in thresholds.iter().enumerate() {
+            if !(0.0..=100.).contains(&threshold.required_area) {
+                return Err(format!(
+                    "The required area must be a percentage between 0 and 100, but found {}",
+                    threshold.required_area
+                ));
+            }
+            let mut above = 0.0;
+            for (row, area) in self.areas.iter().enumerate() {
+                if self.values.get(row, col)? >= threshold.illuminance {
+                    above += area;
+                }
+            }
+            let area = if total > 0.0 {
+                100. * above / total
+            } else {
+                0.0
+            };
+            ret.push(HourCompliance {
+                area,
+                passes: area >= threshold.required_area,
+            });
+        }
+        Ok(ret)
+    }
+}
+
+/// The relative luminance of the CIE standard clear sky in the direction `dir`,
+/// with the sun in the direction `sun`
+fn cie_clear_luminance(dir: Vector3D, sun: Vector3D) -> Float {
+    let gamma = (dir * sun).clamp(-1., 1.).acos();
+    let cos_gamma = gamma.cos();
+    let indicatrix = 0.91 + 10. * (-3. * gamma).exp() + 0.45 * cos_gamma * cos_gamma;
+    let gradation = 1. - (-0.32 / dir.z.max(1e-3)).exp();
+    indicatrix * gradation
+}
+
+/// The sky vector of a `condition`, with the sun—in the axes of the scene—in
+/// the direction `sun` (if it is up)
+fn sky_vector(
+    basis: &SkyBasis,
+    condition: &SkyCondition,
+    sun: Option<Vector3D>,
+    albedo: Float,
+) -> Result<Matrix, String> {
+    let illuminance = condition.horizontal_illuminance();
+    if !illuminance.is_finite() || illuminance < 0.0 {
+        return Err(format!(
+            "The horizontal illuminance of a sky must be a non-negative number, but found {}",
+            illuminance
+        ));
+    }
+    match condition {
+        SkyCondition::CieOvercast { .. } => basis.cie_overcast(illuminance, albedo),
+        SkyCondition::Uniform { .. } => {
+            basis.scaled_sky_vec(&vec![1.; basis.n_bins()], illuminance, albedo)
+        }
+        SkyCondition::CieClear { .. } => {
+            let sun = match sun {
+                Some(sun) if sun.z > 0.0 => sun,
+                _ => return Err("A CIE clear sky needs the sun above the horizon".to_string()),
+            };
+            let relative: Vec<Float> = basis
+                .centroids()
+                .iter()
+                .map(|d| cie_clear_luminance(*d, sun))
+                .collect();
+            basis.scaled_sky_vec(&relative, illuminance, albedo)
+        }
+    }
+}
+
+/// Calculates the illuminance of the sensors of a `grid` at each of the design
+/// `hours` (see the module documentation), at the location of the `solar` data.
+///
+/// The position of the sun—which only the [`SkyCondition::CieClear`] sky depends
+/// on—is calculated in the clock of each hour, and rotated according to the
+/// `north_offset` of the options.
+pub fn design_hours_illuminance(
+    grid: &SensorGrid,
+    scene: &Scene,
+    hours: &[DesignHour],
+    solar: &Solar,
+    options: &DesignHourOptions,
+) -> Result<DesignHourIlluminance, String> {
+    let session = DCSession::try_new(options.mf, options.dc).map_err(|e| e.to_string())?;
+    let basis = session.basis()?;
+
+    // Build the skies first, so that bad hours fail before tracing
+    let mut skies = Matrix::new(0.0, basis.n_bins(), hours.len());
+    for (col, hour) in hours.iter().enumerate() {
+        let sun = solar
+            .sun_position(hour.time()?)
+            .map(|dir| rotate_to_scene(dir, options.north_offset));
+        let vec = sky_vector(&basis, &hour.sky, sun, options.albedo).map_err(|e| {
+            format!(
+                "Design hour {} ({}/{} at {}): {}",
+                col, hour.date.day, hour.date.month, hour.date.hour, e
+            )
+        })?;
+        for bin in 0..basis.n_bins() {
+            skies.set(bin, col, vec.get(bin, 0)?)?;
+        }
+    }
+
+    let sensors = grid.sensors();
+    let dc = session.calc_sensor_dc(&sensors, scene)?;
+    let values = if hours.is_empty() {
+        Matrix::new(0.0, sensors.len(), 0)
+    } else {
+        crate::annual::apply_annual(&dc.matrix, &skies)?
+    };
+    let areas = sensors
+        .iter()
+        .map(|s| s.area.map_or(1., |a| a.area()))
+        .collect();
+    Ok(DesignHourIlluminance {
+        hours: hours.to_vec(),
+        values,
+        areas,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use geometry3d::Point3D;
+    use validate::assert_close;
+
+    fn options() -> DesignHourOptions {
+        DesignHourOptions {
+            dc: DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 4000,
+                ..DCOptions::default()
+            },
+            ..DesignHourOptions::default()
+        }
+    }
+
+    fn grid() -> SensorGrid {
+        let square = [
+            Point3D::new(0., 0., 0.),
+            Point3D::new(1., 0., 0.),
+            Point3D::new(1., 1., 0.),
+            Point3D::new(0., 1., 0.),
+        ];
+        SensorGrid::new(&square, &[], 0.5, 0.8).unwrap()
+    }
+
+    #[test]
+    fn test_normalisation() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let solar = Solar::new(0.9, 0., 0.);
+        let hours = [
+            DesignHour::new(
+                12,
+                21,
+                10.,
+                SkyCondition::CieOvercast {
+                    horizontal_illuminance: 10000.,
+                },
+            ),
+            DesignHour::new(
+                3,
+                21,
+                12.,
+                SkyCondition::Uniform {
+                    horizontal_illuminance: 5000.,
+                },
+            )
+            .with_clock(DesignClock::DaylightSaving),
+        ];
+        let found = design_hours_illuminance(&grid(), &scene, &hours, &solar, &options()).unwrap();
+        assert_eq!(found.values.size(), (4, 2));
+        assert_eq!(found.hours, hours);
+        for row in 0..4 {
+            assert!((found.values.get(row, 0).unwrap() / 10000. - 1.).abs() < 0.03);
+            assert!((found.values.get(row, 1).unwrap() / 5000. - 1.).abs() < 0.01);
+        }
+
+        // The clear sky is normalised in the same way, wherever the sun is
+        let session = DCSession::new(1, options().dc);
+        let basis = session.basis().unwrap();
+        let dc = session.calc_sensor_dc(&grid().sensors(), &scene).unwrap();
+        for sun in [
+            Vector3D::new(0., -0.8, 0.6),
+            Vector3D::new(0.3, 0.1, 0.9).get_normalized(),
+        ] {
+            let condition = SkyCondition::CieClear {
+                horizontal_illuminance: 20000.,
+            };
+            let vec = sky_vector(&basis, &condition, Some(sun), 0.2).unwrap();
+            let e = crate::annual::apply_annual(&dc.matrix, &vec).unwrap();
+            assert!((e.get(0, 0).unwrap() / 20000. - 1.).abs() < 0.03);
+            // It is brighter around the sun than opposite to it
+            let reinhart = basis.reinhart();
+            let near = reinhart.dir_to_bin(sun);
+            let far = reinhart.dir_to_bin(Vector3D::new(-sun.x, -sun.y, sun.z));
+            assert!(vec.get(near, 0).unwrap() > 2. * vec.get(far, 0).unwrap());
+        }
+        let condition = SkyCondition::CieClear {
+            horizontal_illuminance: 20000.,
+        };
+        assert!(sky_vector(&basis, &condition, None, 0.2).is_err());
+        let negative = SkyCondition::Uniform {
+            horizontal_illuminance: -1.,
+        };
+        assert!(sky_vector(&basis, &negative, None, 0.2).is_err());
+    }
+
+    #[test]
+    fn test_clocks() {
+        let sky = SkyCondition::Uniform {
+            horizontal_illuminance: 1.,
+        };
+        let standard = |month, day, hour| Time::Standard(Date { month, day, hour }.day_of_year());
+        let same = |a: Time, b: Time| match (a, b) {
+            (Time::Standard(a), Time::Standard(b)) | (Time::Solar(a), Time::Solar(b)) => a == b,
+            _ => false,
+        };
+        let hour = DesignHour::new(6, 21, 13.5, sky);
+        assert!(same(hour.time().unwrap(), standard(6, 21, 13.5)));
+        let summer = hour.with_clock(DesignClock::DaylightSaving);
+        assert!(same(summer.time().unwrap(), standard(6, 21, 12.5)));
+        let midnight = DesignHour::new(3, 1, 0.5, sky).with_clock(DesignClock::DaylightSaving);
+        assert!(same(midnight.time().unwrap(), standard(2, 28, 23.5)));
+        let solar = hour.with_clock(DesignClock::Solar);
+        assert!(matches!(solar.time().unwrap(), Time::Solar(_)));
+        assert!(DesignHour::new(2, 29, 12., sky).time().is_err());
+    }
+
+    #[test]
+    fn test_compliance() {
+        let mut values = Matrix::new(0.0, 4, 2);
+        for (row, v) in [100., 200., 300., 400.].iter().enumerate() {
+            values.set(row, 0, *v).unwrap();
+            values.set(row, 1, 2. * v).unwrap();
+        }
+        let results = DesignHourIlluminance {
+            hours: vec![
+                DesignHour::new(
+                    12,
+                    21,
+                    10.,
+                    SkyCondition::CieOvercast {
+                        horizontal_illuminance: 10000.,
+                    }
+                );
+                2
+            ],
+            values,
+            areas: vec![1., 1., 1., 3.],
+        };
+        let thresholds = [
+            DesignThreshold {
+                illuminance: 250.,
+                required_area: 80.,
+            },
+            DesignThreshold {
+                illuminance: 250.,
+                required_area: 80.,
+            },
+        ];
+        let found = results.evaluate(&thresholds).unwrap();
+        assert_close!(found[0].area, 400. / 6., 1e-3);
+        assert!(!found[0].passes);
+        assert_close!(found[1].area, 500. / 6., 1e-3);
+        assert!(found[1].passes);
+        assert!(results.evaluate(&thresholds[..1]).is_err());
+        let bad = [DesignThreshold {
+            illuminance: 250.,
+            required_area: 120.,
+        }; 2];
+        assert!(results.evaluate(&bad).is_err());
+    }
+}
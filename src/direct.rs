@@ -0,0 +1,589 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::accumulator::BinAccumulator;
+use crate::environment::{EscapeRadiance, SkyRadiance};
+use crate::events::{EventKind, EventLog};
+use crate::horizon::HorizonProfile;
+use crate::importance::{ImportanceHints, ImportanceSampler};
+use crate::occupancy::OccupancyGrid;
+use crate::ray_caster::{SharedCaster, PACKET_SIZE};
+use crate::ray_filter::{RayAction, RayFilter};
+use crate::rng::{SampleStream, SensorRng};
+use crate::sampling::draw_checked;
+use crate::sensor::{DirectionSampler, SensorSpec, TributaryArea};
+use crate::sky::mirrored_ground_bin;
+use crate::stats::{SampleClamp, Welford};
+use crate::Float;
+use geometry3d::{Point3D, Ray3D};
+use rendering::{Ray, Scene};
+use solar::ReinhartSky;
+use std::ops::Range;
+
+/// The direct Daylight Coefficients of a sensor, calculated by [`direct_dc_row`]
+#[derive(Debug, Clone)]
+pub(crate) struct DirectRow {
+    /// The coefficient of each bin (as its value) and the mean of the squared
+    /// contribution of each sample to it (as its square)
+    pub bins: BinAccumulator,
+
+    /// The statistics of the contribution of each sample to
+    /// the sum of all the coefficients
+    pub totals: Welford,
+
+    /// The number of samples that escaped the scene
+    pub escaped: usize,
+
+    /// The coefficient of each ground bin, when the ground is kept
+    /// apart from the sky (see [`SkyBasis::ground_bin`](crate::SkyBasis::ground_bin)). Empty otherwise.
+    pub ground: BinAccumulator,
+
+    /// The energy removed by the [`SampleClamp`], if any
+    pub clamped: Float,
+
+    /// The most energy that the samples grazing the plane of the sensor could
+    /// have brought (see [`TraceHints::min_cosine`])
+    pub grazing: Float,
+
+    /// The number of samples skipped because their direction kept coming
+    /// out broken (see [`TraceHints::max_resamples`])
+    pub skipped: usize,
+}
+
+/// The optional features of the direct tracer, which are set
+/// through the [`DCSession`](crate::DCSession)
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TraceHints<'a> {
+    /// Decides which rays are traced (see [`RayFilter`])
+    pub filter: Option<&'a RayFilter>,
+
+    /// Objects that deserve more samples (see [`ImportanceHints`])
+    pub importance: Option<&'a ImportanceHints>,
+
+    /// The seed of the origins of the samples, if they are jittered within
+    /// the area of their sensor (see [`OriginJitter`])
+    pub jitter_seed: Option<u64>,
+
+    /// Limits the weight of each sample of the Daylight Coefficients
+    /// (see [`SampleClamp`])
+    pub clamp: Option<SampleClamp>,
+
+    /// The samples whose cosine with the normal of their sensor is below
+    /// this weigh nothing (see [`DirectionSampler::with_min_cosine`])
+    pub min_cosine: Float,
+
+    /// How many times samples with a broken direction are drawn again
+    /// before they are skipped (see [`draw_checked`])
+    pub max_resamples: usize,
+
+    /// Distant obstructions that rays escaping the scene can still hit
+    /// (see [`HorizonProfile`])
+    pub horizon: Option<&'a HorizonProfile>,
+
+    /// The radiance of the rays that escape the scene, if it is not
+    /// [`EscapeRadiance::Unit`]
+    pub escape: Option<&'a EscapeRadiance>,
+
+    /// Proves that some rays hit nothing, so they are not cast
+    /// (see [`OccupancyGrid`])
+    pub occupancy: Option<&'a OccupancyGrid>,
+
+    /// Casts the rays instead of the `Scene` (see [`RayCaster`](crate::RayCaster))
+    pub caster: Option<&'a SharedCaster>,
+}
+
+/// Mixed into the seed of the random numbers that place the origins of the
+/// samples, so that they are independent from those of the directions
+const JITTER_SEED: u64 = 0x0A11_A5EA;
+
+/// Mixed into the seed of the random numbers that draw broken samples again,
+/// which depend on nothing but the sensor
+const RESAMPLE_SEED: u64 = 0x0BAD_D1CE;
+
+/// Places the origin of each sample of a sensor at a random point of its
+/// [`TributaryArea`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OriginJitter {
+    area: TributaryArea,
+    key: u64,
+}
+
+impl OriginJitter {
+    /// The jitter of the sensor with a certain `index`, if it has an area and
+    /// origins are jittered (i.e., there is a `seed`)
+    pub(crate) fn new(sensor: &SensorSpec, index: usize, seed: Option<u64>) -> Option<Self> {
+        let (area, seed) = (sensor.area?, seed?);
+        Some(Self {
+            area,
+            key: SensorRng::new(seed ^ JITTER_SEED, index as u64).next_u64(),
+        })
+    }
+
+    /// The origin of the sample `j` of a sensor, which depends on
+    /// nothing else
+    pub(crate) fn origin(&self, sensor: &SensorSpec, j: usize) -> Point3D {
+        let mut rng = SensorRng::new(self.key, j as u64);
+        let (u1, u2) = (rng.gen(), rng.gen());
+        self.area.point(sensor.ray.origin, u1, u2)
+    }
+}
+
+/// The origin of the sample `j` of a sensor
+fn sample_origin(sensor: &SensorSpec, jitter: Option<&OriginJitter>, j: usize) -> Point3D {
+    match jitter {
+        Some(jitter) => jitter.origin(sensor, j),
+        None => sensor.ray.origin,
+    }
+}
+
+/// Records an [`EventKind::EnclosedSensor`] if none of the `n_samples` samples
+/// of a sensor escaped the scene
+pub(crate) fn report_enclosed(
+    events: &mut EventLog,
+    index: usize,
+    sensor: &SensorSpec,
+    escaped: usize,
+    n_samples: usize,
+) {
+    if n_samples > 0 && escaped == 0 {
+        events.push(
+            EventKind::EnclosedSensor,
+            Some(index),
+            Some(sensor.ray),
+            1,
+            format!("none of the {} samples escaped the scene", n_samples),
+        );
+    }
+}
+
+/// Records an [`EventKind::ExcludedObjectHit`] for the times that the rays of a
+/// sensor went through its host surface
+fn report_crossings(events: &mut EventLog, index: usize, sensor: &SensorSpec, crossings: usize) {
+    events.push(
+        EventKind::ExcludedObjectHit,
+        Some(index),
+        Some(sensor.ray),
+        crossings,
+        "rays hit the host surface of the sensor, and went on through it".to_string(),
+    );
+}
+
+/// Records an [`EventKind::BadSample`] for the samples of a sensor that were
+/// skipped because their direction kept coming out broken
+pub(crate) fn report_skipped(
+    events: &mut EventLog,
+    index: usize,
+    sensor: &SensorSpec,
+    skipped: usize,
+) {
+    events.push(
+        EventKind::BadSample,
+        Some(index),
+        Some(sensor.ray),
+        skipped,
+        "samples were skipped, as their direction was not a finite unit vector".to_string(),
+    );
+}
+
+/// Traces the rays that leave a `sensor`—given with the weight of their
+/// sample—returning whether each of them escapes the scene (going through its
+/// host surface, if any, and adding how many times they did to `crossings`). Rays without weight are not traced, and do not
+/// escape. The filter of the `hints`, if any, is asked before, and rays that
+/// their occupancy grid proves to hit nothing are not cast. The others are cast
+/// into the `scene` or—if there is one—by the [`RayCaster`](crate::RayCaster) of the `hints`, in
+/// a single packet if it supports them.
+fn escapes(
+    scene: &Scene,
+    sensor: &SensorSpec,
+    rays: &[(Ray3D, Float)],
+    hints: TraceHints,
+    node_aux: &mut Vec<usize>,
+    crossings: &mut usize,
+) -> [bool; PACKET_SIZE] {
+    let mut ret = [false; PACKET_SIZE];
+    let mut cast = Vec::with_capacity(rays.len());
+    for (i, (ray, weight)) in rays.iter().enumerate() {
+        if *weight <= 0.0 {
+            continue;
+        }
+        match hints.filter.map(|f| f.action(ray, 0)) {
+            None | Some(RayAction::Continue) => {
+                if hints.occupancy.is_some_and(|g| g.misses(ray)) {
+                    ret[i] = true;
+                } else {
+                    cast.push(i);
+                }
+            }
+            Some(RayAction::Kill) => {}
+            Some(RayAction::ForceSkyEscape) => ret[i] = true,
+        }
+    }
+    match hints.caster.map(|c| c.0.as_ref()) {
+        Some(caster) if caster.supports_packets() && !cast.is_empty() => {
+            // Lanes beyond the last ray repeat the first one
+            let mut packet = [rays[cast[0]].0; PACKET_SIZE];
+            for (lane, i) in cast.iter().enumerate() {
+                packet[lane] = rays[*i].0;
+            }
+            let hits = caster.cast_packet(&packet);
+            for (lane, i) in cast.iter().enumerate() {
+                ret[*i] = sensor
+                    .past_host(caster, &rays[*i].0, hits[lane], crossings)
+                    .is_none();
+            }
+        }
+        Some(caster) => {
+            for i in cast {
+                ret[i] = sensor.cast_with(caster, &rays[i].0, crossings).is_none();
+            }
+        }
+        None => {
+            for i in cast {
+                let mut ray = Ray {
+                    geometry: rays[i].0,
+                    ..Ray::default()
+                };
+                ret[i] = sensor
+                    .cast_ray_counting(scene, &mut ray, node_aux, crossings)
+                    .is_none();
+            }
+        }
+    }
+    ret
+}
+
+/// Draws the samples `js` of a sensor into `rays`, as the ray of each and its
+/// weight. Their origins are those of the samples `first` places later. Samples
+/// that come out broken are drawn again from `redraw`, up to `max_resamples`
+/// times, and then skipped (see [`draw_checked`]). Returns the sum of the weights
+/// that grazing samples would have had, and the number of samples skipped.
+#[allow(clippy::too_many_arguments)]
+fn next_rays(
+    sensor: &SensorSpec,
+    sampler: &ImportanceSampler,
+    jitter: Option<&OriginJitter>,
+    samples: &mut SampleStream,
+    js: Range<usize>,
+    first: usize,
+    (redraw, max_resamples): (&mut SensorRng, usize),
+    rays: &mut Vec<(Ray3D, Float)>,
+) -> (Float, usize) {
+    rays.clear();
+    let mut grazing = 0.0;
+    let mut skipped = 0;
+    for j in js {
+        let u = samples.next_2d();
+        let drawn = draw_checked(u, redraw, max_resamples, |u1, u2| {
+            let (direction, weight, clipped) = sampler.sample(j, u1, u2);
+            (weight.is_finite() && clipped.is_finite()).then_some((direction, (weight, clipped)))
+        });
+        let Some((direction, (weight, clipped))) = drawn else {
+            skipped += 1;
+            continue;
+        };
+        let origin = sample_origin(sensor, jitter, first + j);
+        rays.push((Ray3D { origin, direction }, weight));
+        grazing += clipped;
+    }
+    (grazing, skipped)
+}
+
+/// Calculates the direct Daylight Coefficients of a sensor, which become
+/// a row of the matrix. Rays that escape the scene contribute to the bin
+/// they exit through, and those that hit something contribute nothing. Rays
+/// that escape the scene but not the [`HorizonProfile`] of the `hints`, if any,
+/// contribute to the bins that light the terrain. The contribution of the
+/// rays that escape is weighted by the [`EscapeRadiance`] of the `hints`, if any.
+///
+/// If `two_sided`, rays that escape below the horizon contribute to the ground
+/// bin they exit through (see [`DirectRow::ground`]) instead of to the ground bin of
+/// the sky. Otherwise, these escapes are recorded in `events` under the sensor's `index`.
+/// Directions are sampled and rays are traced following `hints`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn direct_dc_row(
+    scene: &Scene,
+    sensor: &SensorSpec,
+    index: usize,
+    sky: &ReinhartSky,
+    n_bins: usize,
+    n_samples: usize,
+    samples: &mut SampleStream,
+    hints: TraceHints,
+    two_sided: bool,
+    events: &mut EventLog,
+) -> Result<DirectRow, String> {
+    let mut rows = direct_dc_rows(
+        scene,
+        sensor,
+        index,
+        &[(sky, n_bins)],
+        n_samples,
+        samples,
+        hints,
+        two_sided,
+        events,
+    )?;
+    Ok(rows.remove(0))
+}
+
+/// Like [`direct_dc_row`], but binning every sample into several skies at
+/// once—given as the sky and its number of bins—which returns one row per sky.
+/// The rays are only traced once, so every row sees the same samples.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn direct_dc_rows(
+    scene: &Scene,
+    sensor: &SensorSpec,
+    index: usize,
+    skies: &[(&ReinhartSky, usize)],
+    n_samples: usize,
+    samples: &mut SampleStream,
+    hints: TraceHints,
+    two_sided: bool,
+    events: &mut EventLog,
+) -> Result<Vec<DirectRow>, String> {
+    if hints.horizon.is_some() && hints.escape.is_some() {
+        return Err(
+            "Horizon profiles cannot be combined with escape radiances other than Unit".to_string(),
+        );
+    }
+    if let Some(grid) = hints.occupancy {
+        grid.check_scene(scene)?;
+    }
+    let base = DirectionSampler::new(sensor.ray.direction, sensor.mask.as_ref())?
+        .with_min_cosine(hints.min_cosine)
+        .with_response(sensor.angular_response.as_ref());
+    let sampler = ImportanceSampler::new(&base, sensor.ray.origin, hints.importance, n_samples);
+    let mut rows: Vec<DirectRow> = skies
+        .iter()
+        .map(|(_, n_bins)| DirectRow {
+            bins: BinAccumulator::new(*n_bins),
+            totals: Welford::new(),
+            escaped: 0,
+            ground: BinAccumulator::new(if two_sided { n_bins - 1 } else { 0 }),
+            clamped: 0.0,
+            grazing: 0.0,
+            skipped: 0,
+        })
+        .collect();
+    let jitter = OriginJitter::new(sensor, index, hints.jitter_seed);
+    if n_samples == 0 {
+        return Ok(rows);
+    }
+    // How much each bin of each sky contributes to the radiance of the terrain
+    let terrain_coefficients = match hints.horizon {
+        Some(horizon) => skies
+            .iter()
+            .map(|(_, n_bins)| horizon.terrain_coefficients_for(*n_bins))
+            .collect::<Result<Vec<_>, String>>()?,
+        None => Vec::new(),
+    };
+    let terrain_weight: Float = terrain_coefficients.first().map_or(0.0, |k| k.iter().sum());
+    let (mut terrain, mut terrain_squares) = (0.0, 0.0);
+    let mut totals = Welford::new();
+    let one_over_samples = 1. / n_samples as Float;
+    let mut node_aux = Vec::with_capacity(2);
+    let mut escaped = 0;
+    let mut below_horizon = 0;
+    let mut first_below = None;
+    let mut clamped = 0.0;
+    let mut grazing = 0.0;
+    let mut skipped = 0;
+    let mut crossings = 0;
+    let mut redraw = SensorRng::new(RESAMPLE_SEED, index as u64);
+    // Streams that go on from earlier samples move the origins along with them
+    let first = samples.position() as usize;
+    let mut rays = Vec::with_capacity(PACKET_SIZE);
+    for j in (0..n_samples).step_by(PACKET_SIZE) {
+        let js = j..(j + PACKET_SIZE).min(n_samples);
+        let (clipped, broken) = next_rays(
+            sensor,
+            &sampler,
+            jitter.as_ref(),
+            samples,
+            js,
+            first,
+            (&mut redraw, hints.max_resamples),
+            &mut rays,
+        );
+        grazing += clipped * one_over_samples;
+        skipped += broken;
+        let escaped_rays = escapes(scene, sensor, &rays, hints, &mut node_aux, &mut crossings);
+        for ((geometry, weight), escapes) in rays.iter().zip(escaped_rays) {
+            let (geometry, weight, direction) = (*geometry, *weight, geometry.direction);
+            let mut contribution = 0.0;
+            if escapes {
+                escaped += 1;
+                let weight = match &hints.clamp {
+                    Some(clamp) => {
+                        let w = clamp.clamp(weight, &totals);
+                        clamped += (weight - w) * one_over_samples;
+                        w
+                    }
+                    None => weight,
+                };
+                let weight = match hints.escape {
+                    Some(escape) => weight * escape.radiance(direction),
+                    None => weight,
+                };
+                contribution = weight;
+                if hints.horizon.is_some_and(|h| h.obstructs(direction)) {
+                    contribution = weight * terrain_weight;
+                    terrain += weight * one_over_samples;
+                    terrain_squares += weight * weight * one_over_samples;
+                } else if two_sided && direction.z < 0.0 {
+                    for (row, (sky, _)) in rows.iter_mut().zip(skies) {
+                        row.ground.add(
+                            mirrored_ground_bin(sky, direction),
+                            weight * one_over_samples,
+                            weight * weight * one_over_samples,
+                        );
+                    }
+                } else {
+                    if direction.z < 0.0 {
+                        below_horizon += 1;
+                        first_below.get_or_insert(geometry);
+                    }
+                    for (row, (sky, _)) in rows.iter_mut().zip(skies) {
+                        let bin = sky.dir_to_bin(direction);
+                        row.bins.add(
+                            bin,
+                            weight * one_over_samples,
+                            weight * weight * one_over_samples,
+                        );
+                    }
+                }
+            }
+            totals.push(contribution);
+        }
+        // The bins are divided by every sample, so skipped ones count as dark
+        (0..broken).for_each(|_| totals.push(0.0));
+    }
+    events.push(
+        EventKind::BelowHorizonEscape,
+        Some(index),
+        first_below,
+        below_horizon,
+        "samples escaped below the horizon, into the ground bin".to_string(),
+    );
+    report_skipped(events, index, sensor, skipped);
+    report_crossings(events, index, sensor, crossings);
+    for (row, k) in rows.iter_mut().zip(terrain_coefficients.iter()) {
+        if terrain > 0.0 {
+            for (bin, k) in k.iter().enumerate().filter(|(_, k)| **k > 0.0) {
+                row.bins.add(bin, terrain * k, terrain_squares * k * k);
+            }
+        }
+    }
+    for row in rows.iter_mut() {
+        row.totals = totals;
+        row.escaped = escaped;
+        row.clamped = clamped;
+        row.grazing = grazing;
+        row.skipped = skipped;
+    }
+    Ok(rows)
+}
+
+/// Calculates the direct irradiance received by a sensor from
+/// an environment, evaluating its radiance in the direction of every ray that
+/// escapes the scene. The statistics are those of the contribution of each sample.
+///
+/// Negative radiances are clamped to zero, and samples whose radiance is
+/// not finite are discarded. Both are recorded in `events`, as well as
+/// sensors that are enclosed. Directions are sampled and rays are traced following `hints`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn direct_environment_irradiance(
+    scene: &Scene,
+    sensor: &SensorSpec,
+    index: usize,
+    sky: &dyn SkyRadiance,
+    n_samples: usize,
+    samples: &mut SampleStream,
+    hints: TraceHints,
+    events: &mut EventLog,
+) -> Result<Welford, String> {
+    if let Some(grid) = hints.occupancy {
+        grid.check_scene(scene)?;
+    }
+    let base = DirectionSampler::new(sensor.ray.direction, sensor.mask.as_ref())?
+        .with_min_cosine(hints.min_cosine)
+        .with_response(sensor.angular_response.as_ref());
+    let sampler = ImportanceSampler::new(&base, sensor.ray.origin, hints.importance, n_samples);
+    let jitter = OriginJitter::new(sensor, index, hints.jitter_seed);
+    let mut ret = Welford::new();
+    let mut node_aux = Vec::with_capacity(2);
+    let mut escaped = 0;
+    let mut skipped = 0;
+    let mut crossings = 0;
+    let mut redraw = SensorRng::new(RESAMPLE_SEED, index as u64);
+    let mut rays = Vec::with_capacity(PACKET_SIZE);
+    for j in (0..n_samples).step_by(PACKET_SIZE) {
+        let js = j..(j + PACKET_SIZE).min(n_samples);
+        let redraw = (&mut redraw, hints.max_resamples);
+        let (_, broken) = next_rays(
+            sensor,
+            &sampler,
+            jitter.as_ref(),
+            samples,
+            js,
+            0,
+            redraw,
+            &mut rays,
+        );
+        skipped += broken;
+        (0..broken).for_each(|_| ret.push(0.0));
+        let escaped_rays = escapes(scene, sensor, &rays, hints, &mut node_aux, &mut crossings);
+        for ((geometry, weight), escapes) in rays.iter().zip(escaped_rays) {
+            let (geometry, weight) = (*geometry, *weight);
+            let mut contribution = 0.0;
+            if escapes {
+                escaped += 1;
+                let mut radiance = sky.radiance(geometry.direction);
+                if !radiance.is_finite() {
+                    events.push(
+                        EventKind::BadSample,
+                        Some(index),
+                        Some(geometry),
+                        1,
+                        "the environment returned a non-finite radiance".to_string(),
+                    );
+                    ret.push(0.0);
+                    continue;
+                }
+                if radiance < 0.0 {
+                    events.push(
+                        EventKind::ClampedValue,
+                        Some(index),
+                        Some(geometry),
+                        1,
+                        "a negative radiance was clamped to zero".to_string(),
+                    );
+                    radiance = 0.0;
+                }
+                contribution = weight * radiance;
+            }
+            ret.push(contribution);
+        }
+    }
+    report_enclosed(events, index, sensor, escaped, n_samples);
+    report_skipped(events, index, sensor, skipped);
+    report_crossings(events, index, sensor, crossings);
+    Ok(ret)
+}
@@ -0,0 +1,181 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The precision of the values of matrices. This crate computes either with
+//! `f32` or with `f64` (see the `float` feature and [`Float`]), but the
+//! artifacts it writes may be read by a build of the other kind. Binary
+//! matrix files (see [`crate::matrix_io`]) and [`RunManifest`](crate::RunManifest)s
+//! record the [`Dtype`] of their values and of the build that wrote them, so
+//! readers can convert them and say when precision was lost.
+//!
+//! Operations that combine matrices (e.g., [`ApplySky`](crate::ApplySky))
+//! accumulate in [`Wide`]—the wider of the types a matrix can be stored
+//! as—and round into [`Float`] once, using [`to_wide`] and [`from_wide`].
+
+use crate::Float;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// The type in which mixed-precision operations accumulate
+pub type Wide = f64;
+
+/// The type of the elements of a stored matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Dtype {
+    /// Single precision
+    F32,
+    /// Double precision
+    F64,
+}
+
+impl Dtype {
+    /// The type of [`Float`] this crate was compiled with
+    pub const NATIVE: Self = if std::mem::size_of::<Float>() == 4 {
+        Self::F32
+    } else {
+        Self::F64
+    };
+
+    /// The number of bytes of each element
+    pub fn bytes(self) -> u8 {
+        match self {
+            Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// The type whose elements have `bytes` bytes
+    pub fn from_bytes(bytes: u8) -> Result<Self, String> {
+        match bytes {
+            4 => Ok(Self::F32),
+            8 => Ok(Self::F64),
+            _ => Err(format!("Unsupported float size {} in matrix file", bytes)),
+        }
+    }
+
+    /// The name of the type, as in Rust
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::F64 => "f64",
+        }
+    }
+
+    /// The wider of two types, in which operations mixing them are done
+    pub fn wider(self, other: Self) -> Self {
+        self.max(other)
+    }
+
+    /// Rounds a value to what this type can represent
+    pub fn round(self, v: Wide) -> Wide {
+        match self {
+            Self::F32 => v as f32 as Wide,
+            Self::F64 => v,
+        }
+    }
+}
+
+impl std::fmt::Display for Dtype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Copies a matrix into a row-major vector of [`Wide`] values
+pub fn to_wide(m: &Matrix) -> Result<Vec<Wide>, String> {
+    let (nrows, ncols) = m.size();
+    let mut ret = Vec::with_capacity(nrows * ncols);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            ret.push(Wide::from(m.get(r, c)?));
+        }
+    }
+    Ok(ret)
+}
+
+/// Builds a matrix from row-major [`Wide`] values, rounding them into [`Float`]
+pub fn from_wide(nrows: usize, ncols: usize, values: &[Wide]) -> Result<Matrix, String> {
+    if values.len() != nrows * ncols {
+        return Err(format!(
+            "Cannot build a {}x{} matrix from {} values",
+            nrows,
+            ncols,
+            values.len()
+        ));
+    }
+    let mut ret = Matrix::new(0.0, nrows, ncols);
+    for (i, v) in values.iter().enumerate() {
+        if *v != 0.0 {
+            ret.set(i / ncols, i % ncols, *v as Float)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Rounds the elements of a matrix to what `dtype` can represent, as if it
+/// had been stored as such (e.g., for comparing the results of an `f64`
+/// build with those of an `f32` one)
+pub fn round_to(m: &Matrix, dtype: Dtype) -> Result<Matrix, String> {
+    let (nrows, ncols) = m.size();
+    let values: Vec<Wide> = to_wide(m)?.into_iter().map(|v| dtype.round(v)).collect();
+    from_wide(nrows, ncols, &values)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn test_dtype() {
+        assert_eq!(Dtype::NATIVE.bytes() as usize, std::mem::size_of::<Float>());
+        for d in [Dtype::F32, Dtype::F64] {
+            assert_eq!(Dtype::from_bytes(d.bytes()).unwrap(), d);
+        }
+        assert!(Dtype::from_bytes(2).is_err());
+        assert_eq!(Dtype::F32.wider(Dtype::F64), Dtype::F64);
+        assert_eq!(Dtype::F64.wider(Dtype::F32), Dtype::F64);
+        assert_eq!(Dtype::F32.to_string(), "f32");
+
+        let third: Wide = 1. / 3.;
+        assert_eq!(Dtype::F64.round(third), third);
+        assert_eq!(Dtype::F32.round(third), (1_f32 / 3.) as Wide);
+        assert!(Dtype::F64 > Dtype::F32);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut m = Matrix::new(0.0, 2, 3);
+        m.set(0, 1, 0.1).unwrap();
+        m.set(1, 2, -2.).unwrap();
+        let wide = to_wide(&m).unwrap();
+        assert_eq!(wide.len(), 6);
+        assert_eq!(wide[1], Wide::from(0.1 as Float));
+        assert_eq!(wide[5], -2.);
+        let back = from_wide(2, 3, &wide).unwrap();
+        assert_eq!(back.get(0, 1).unwrap(), 0.1);
+        assert_eq!(back.get(1, 2).unwrap(), -2.);
+        assert!(from_wide(3, 3, &wide).is_err());
+
+        let rounded = round_to(&m, Dtype::F32).unwrap();
+        assert_eq!(rounded.get(0, 1).unwrap(), 0.1_f32 as Float);
+        let kept = round_to(&m, Dtype::F64).unwrap();
+        assert_eq!(kept.get(0, 1).unwrap(), 0.1);
+    }
+}
@@ -0,0 +1,513 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Electrochromic windows, whose tint goes continuously from clear to fully
+//! tinted.
+//!
+//! Only the two extreme states are traced—e.g., with [`DCSession::calc_aperture_dc`]
+//! on a scene with clear glass and on another one with tinted glass—and every
+//! state in between blends the coefficients of the paths that went through the
+//! window. The tint of each timestep is either given or decided by a controller that
+//! looks at the irradiance on the façade, calculated from one of the sensors.
+//!
+//! [`DCSession::calc_aperture_dc`]: crate::DCSession::calc_aperture_dc
+
+use crate::annual::apply_annual;
+use crate::apertures::{add_scaled, ApertureDC};
+use crate::sky::SkyBasis;
+use crate::time::{AnnualSeries, TimeAxis};
+use crate::Float;
+use matrix::Matrix;
+
+/// How the tint of a window (from `0`, clear, to `1`, fully tinted) turns
+/// into the weight of the tinted state in the blend
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TintCurve {
+    /// The weight is the tint
+    #[default]
+    Linear,
+
+    /// The weight is interpolated linearly between `(tint, weight)` points,
+    /// sorted by tint, which must cover the whole range of tints (i.e., from
+    /// `0` to `1`). Weights must be between `0` and `1`.
+    Table(Vec<(Float, Float)>),
+}
+
+impl TintCurve {
+    /// Checks that a table goes from a tint of `0` to one of `1`, in order,
+    /// and that its weights are between `0` and `1`
+    pub fn validate(&self) -> Result<(), String> {
+        let points = match self {
+            Self::Linear => return Ok(()),
+            Self::Table(points) => points,
+        };
+        if points.len() < 2 {
+            return Err(format!(
+                "Tint curves need at least 2 points, but found {}",
+                points.len()
+            ));
+        }
+        if points[0].0 != 0.0 || points[points.len() - 1].0 != 1.0 {
+            return Err(format!(
+                "Tint curves must go from a tint of 0 to one of 1, but found {} to {}",
+                points[0].0,
+                points[points.len() - 1].0
+            ));
+        }
+        if let Some(w) = points.windows(2).find(|w| w[1].0 <= w[0].0) {
+            return Err(format!(
+                "The tints of a tint curve must increase, but {} comes after {}",
+                w[1].0, w[0].0
+            ));
+        }
+        if let Some((_, weight)) = points.iter().find(|(_, w)| !(0.0..=1.).contains(w)) {
+            return Err(format!(
+                "The weights of a tint curve must be between 0 and 1, but found {}",
+                weight
+            ));
+        }
+        Ok(())
+    }
+
+    /// The weight of the tinted state at a `tint` (from `0` to `1`)
+    pub fn weight(&self, tint: Float) -> Result<Float, String> {
+        if !(0.0..=1.).contains(&tint) {
+            return Err(format!("Tints must be between 0 and 1, but found {}", tint));
+        }
+        self.validate()?;
+        Ok(self.blend(tint))
+    }
+
+    /// The weight at a `tint`, which must be between `0` and `1`, of a valid curve
+    fn blend(&self, tint: Float) -> Float {
+        let points = match self {
+            Self::Linear => return tint,
+            Self::Table(points) => points,
+        };
+        let k = points
+            .windows(2)
+            .position(|w| tint <= w[1].0)
+            .unwrap_or(points.len() - 2);
+        let ((t0, w0), (t1, w1)) = (points[k], points[k + 1]);
+        w0 + (tint - t0) / (t1 - t0) * (w1 - w0)
+    }
+}
+
+/// How the tint of a window changes over time
+#[derive(Debug, Clone, PartialEq)]
+pub enum TintControl {
+    /// The tint of each timestep, from `0` (clear) to `1` (fully tinted)
+    Schedule(Vec<Float>),
+
+    /// The tint follows the irradiance on the façade, as seen by the sensor
+    /// of row `sensor` with the window clear: it is clear below `clear_below`,
+    /// fully tinted above `tinted_above`, and goes linearly in between. The
+    /// sensor should be outside (e.g., facing out of the façade), so that it
+    /// does not see through the window it controls.
+    FacadeIrradiance {
+        /// The row of the sensor on the façade
+        sensor: usize,
+        /// The irradiance up to which the window is clear
+        clear_below: Float,
+        /// The irradiance from which the window is fully tinted
+        tinted_above: Float,
+    },
+}
+
+/// The results of an [`ElectrochromicDC`] over a year, and the tint of the window
+/// at each timestep
+#[derive(Debug, Clone)]
+pub struct TintedSeries {
+    /// The results (one row per sensor and one column per timestep)
+    pub values: AnnualSeries,
+
+    /// The tint of the window at each timestep
+    pub tint: Vec<Float>,
+}
+
+impl TintedSeries {
+    /// Writes the series as CSV, like [`AnnualSeries::to_csv`], with the tint of
+    /// each timestep after the hour
+    pub fn to_csv(&self) -> Result<String, String> {
+        let (nrows, _) = self.values.values.size();
+        let mut ret = "month,day,hour,tint".to_string();
+        for r in 0..nrows {
+            ret.push_str(&format!(",sensor_{}", r));
+        }
+        ret.push('\n');
+        for (c, step) in self.values.axis.timesteps().iter().enumerate() {
+            let date = step.date();
+            ret.push_str(&format!(
+                "{},{},{},{}",
+                date.month, date.day, date.hour, self.tint[c]
+            ));
+            for r in 0..nrows {
+                ret.push_str(&format!(",{}", self.values.values.get(r, c)?));
+            }
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+}
+
+/// The Daylight Coefficients of a set of sensors near an electrochromic window,
+/// split into the paths that went through it—traced with the window clear and
+/// fully tinted—and the rest
+#[derive(Debug, Clone)]
+pub struct ElectrochromicDC {
+    /// The discretisation of the sky of the coefficients
+    pub basis: SkyBasis,
+
+    /// The coefficients of the paths that did not go through the window
+    rest: Matrix,
+
+    /// The coefficients of the paths through the clear window
+    clear: Matrix,
+
+    /// The coefficients of the paths through the fully tinted window
+    tinted: Matrix,
+}
+
+impl ElectrochromicDC {
+    /// Puts together the coefficients of the paths that did not go through
+    /// the window and those of the paths that did, with the window `clear` and
+    /// fully `tinted`. All of them have one row per sensor and one column per
+    /// bin of the `basis`.
+    pub fn new(
+        basis: SkyBasis,
+        rest: Matrix,
+        clear: Matrix,
+        tinted: Matrix,
+    ) -> Result<Self, String> {
+        let (nrows, _) = rest.size();
+        for (name, m) in [("rest", &rest), ("clear", &clear), ("tinted", &tinted)] {
+            let (r, c) = m.size();
+            if (r, c) != (nrows, basis.n_bins()) {
+                return Err(format!(
+                    "The electrochromic coefficients need {} rows and {} columns, but the {} ones have {} rows and {} columns",
+                    nrows,
+                    basis.n_bins(),
+                    name,
+                    r,
+                    c
+                ));
+            }
+        }
+        Ok(Self {
+            basis,
+            rest,
+            clear,
+            tinted,
+        })
+    }
+
+    /// Takes the paths through the aperture group called `group` from two
+    /// calculations of the same sensors, one with the window `clear` and one
+    /// with it `tinted`. The rest of the paths come from the `clear` one. No
+    /// light-loss factors are applied (i.e., they are all `1`).
+    pub fn from_aperture_dc(
+        clear: &ApertureDC,
+        tinted: &ApertureDC,
+        group: &str,
+    ) -> Result<Self, String> {
+        if clear.basis != tinted.basis {
+            return Err(
+                "The clear and tinted coefficients must follow the same sky basis".to_string(),
+            );
+        }
+        let ones = vec![1.; clear.groups.len()];
+        let through = clear.through(group)?;
+        let mut rest = clear.dc(&ones)?;
+        add_scaled(&mut rest, &through, -1.)?;
+        Self::new(clear.basis, rest, through, tinted.through(group)?)
+    }
+
+    /// The coefficients with a `weight` of the tinted state (see [`TintCurve`])
+    pub fn dc(&self, weight: Float) -> Result<Matrix, String> {
+        let mut ret = self.rest.clone();
+        add_scaled(&mut ret, &self.clear, 1. - weight)?;
+        add_scaled(&mut ret, &self.tinted, weight)?;
+        Ok(ret)
+    }
+
+    /// Applies an annual sky matrix (one row per bin and one column per
+    /// timestep of the `axis`), with the tint decided by the `control` at each
+    /// timestep turned into a blend by the `curve`
+    pub fn annual(
+        &self,
+        skies: &Matrix,
+        axis: &TimeAxis,
+        control: &TintControl,
+        curve: &TintCurve,
+    ) -> Result<TintedSeries, String> {
+        self.basis.check_sky(skies)?;
+        axis.check_columns(skies)?;
+        curve.validate()?;
+        let (nrows, n_steps) = (self.rest.size().0, skies.size().1);
+        let rest = apply_annual(&self.rest, skies)?;
+        let clear = apply_annual(&self.clear, skies)?;
+        let tinted = apply_annual(&self.tinted, skies)?;
+
+        let tint = match control {
+            TintControl::Schedule(tint) => {
+                if tint.len() != n_steps {
+                    return Err(format!(
+                        "The time axis has {} timesteps, but the tint schedule has {}",
+                        n_steps,
+                        tint.len()
+                    ));
+                }
+                tint.clone()
+            }
+            TintControl::FacadeIrradiance {
+                sensor,
+                clear_below,
+                tinted_above,
+            } => {
+                if *sensor >= nrows {
+                    return Err(format!(
+                        "There are {} sensors, so there is no façade sensor {}",
+                        nrows, sensor
+                    ));
+                }
+                if !(clear_below.is_finite() && tinted_above > clear_below) {
+                    return Err(format!(
+                        "The irradiance at which windows are fully tinted ({}) must be above the one up to which they are clear ({})",
+                        tinted_above, clear_below
+                    ));
+                }
+                (0..n_steps)
+                    .map(|c| {
+                        let e = rest.get(*sensor, c)? + clear.get(*sensor, c)?;
+                        Ok(((e - clear_below) / (tinted_above - clear_below)).clamp(0., 1.))
+                    })
+                    .collect::<Result<Vec<Float>, String>>()?
+            }
+        };
+
+        let mut values = Matrix::new(0.0, nrows, n_steps);
+        if let Some(t) = tint.iter().find(|t| !(0.0..=1.).contains(*t)) {
+            return Err(format!("Tints must be between 0 and 1, but found {}", t));
+        }
+        for (c, t) in tint.iter().enumerate() {
+            let w = curve.blend(*t);
+            for r in 0..nrows {
+                let v = rest.get(r, c)? + (1. - w) * clear.get(r, c)? + w * tinted.get(r, c)?;
+                values.set(r, c, v)?;
+            }
+        }
+        Ok(TintedSeries {
+            values: AnnualSeries::new(values, *axis)?,
+            tint,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession, GlazingModel};
+    use crate::{ApertureGroup, LightLossFactors, Material, SceneBuilder, SensorSpec};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    /// A sensor under a skylight of glass with a certain transmissivity, next
+    /// to a wall, and one above the roof that looks at the sky
+    fn aperture_dc(tau: Float) -> ApertureDC {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("wall_mat", Material::plastic(0.6))
+            .unwrap();
+        builder
+            .add_material("glass_mat", Material::glass(tau))
+            .unwrap();
+        let skylight =
+            [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)].map(|(x, y)| Point3D::new(x, y, 1.));
+        builder
+            .add_polygon("glass_mat", "skylight", &skylight)
+            .unwrap();
+        let wall =
+            [(-3., 0.), (3., 0.), (3., 0.9), (-3., 0.9)].map(|(y, z)| Point3D::new(1.5, y, z));
+        builder.add_polygon("wall_mat", "wall", &wall).unwrap();
+        let (mut scene, report) = builder.build().unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 2000,
+                max_depth: 1,
+                seed: 5,
+                glazing: GlazingModel::Constant,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = [0., 2.]
+            .iter()
+            .map(|z| {
+                Ray3D {
+                    origin: Point3D::new(0., 0., *z),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect();
+        let groups = [ApertureGroup::new(
+            "skylight",
+            &["skylight"],
+            LightLossFactors::default(),
+        )];
+        session
+            .calc_aperture_dc(&sensors, &scene, &report, &groups)
+            .unwrap()
+    }
+
+    /// Three hours of a sky that changes from one to the next
+    fn skies(basis: &SkyBasis) -> Matrix {
+        let mut skies = Matrix::new(0.0, basis.n_bins(), 3);
+        for bin in 0..basis.n_bins() {
+            for c in 0..3 {
+                skies.set(bin, c, (1 + c + bin % 5) as Float * 10.).unwrap();
+            }
+        }
+        skies
+    }
+
+    #[test]
+    fn test_tint_curve() {
+        assert_close!(TintCurve::Linear.weight(0.3).unwrap(), 0.3, 1e-6);
+        assert!(TintCurve::Linear.weight(1.2).is_err());
+        let curve = TintCurve::Table(vec![(0., 0.), (0.5, 0.8), (1., 1.)]);
+        assert_close!(curve.weight(0.).unwrap(), 0., 1e-6);
+        assert_close!(curve.weight(0.25).unwrap(), 0.4, 1e-6);
+        assert_close!(curve.weight(0.75).unwrap(), 0.9, 1e-6);
+        assert_close!(curve.weight(1.).unwrap(), 1., 1e-6);
+
+        assert!(TintCurve::Table(vec![(0., 0.)]).validate().is_err());
+        assert!(TintCurve::Table(vec![(0., 0.), (0.8, 1.)])
+            .validate()
+            .is_err());
+        assert!(
+            TintCurve::Table(vec![(0., 0.), (0.5, 0.5), (0.5, 0.6), (1., 1.)])
+                .validate()
+                .is_err()
+        );
+        assert!(TintCurve::Table(vec![(0., 0.), (1., 1.5)])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_blend_endpoints() {
+        let (clear, tinted) = (aperture_dc(0.8), aperture_dc(0.1));
+        let ec = ElectrochromicDC::from_aperture_dc(&clear, &tinted, "skylight").unwrap();
+        let skies = skies(&ec.basis);
+        let axis = TimeAxis::new(1, 4100, 3).unwrap();
+        let clear_values = clear.annual(&skies, &axis).unwrap().values;
+        let tinted_values = tinted.annual(&skies, &axis).unwrap().values;
+        // The window is darker when tinted
+        assert!(tinted_values.get(0, 0).unwrap() < clear_values.get(0, 0).unwrap());
+
+        let control = TintControl::Schedule(vec![0., 1., 0.5]);
+        let series = ec
+            .annual(&skies, &axis, &control, &TintCurve::Linear)
+            .unwrap();
+        assert_eq!(series.tint, vec![0., 1., 0.5]);
+        for r in 0..2 {
+            let found = |c| series.values.values.get(r, c).unwrap();
+            let clear = |c| clear_values.get(r, c).unwrap();
+            let tinted = |c| tinted_values.get(r, c).unwrap();
+            assert_close!(found(0), clear(0), 1e-4 * (1. + clear(0)));
+            assert_close!(found(1), tinted(1), 1e-4 * (1. + tinted(1)));
+            let halfway = 0.5 * (clear(2) + tinted(2));
+            assert_close!(found(2), halfway, 1e-4 * (1. + halfway));
+        }
+        let csv = series.to_csv().unwrap();
+        assert!(csv.starts_with("month,day,hour,tint,sensor_0,sensor_1\n"));
+        assert_eq!(csv.lines().count(), 4);
+
+        // Wrong schedules
+        let short = TintControl::Schedule(vec![0., 1.]);
+        assert!(ec
+            .annual(&skies, &axis, &short, &TintCurve::Linear)
+            .is_err());
+        let dark = TintControl::Schedule(vec![0., 1., 1.5]);
+        assert!(ec.annual(&skies, &axis, &dark, &TintCurve::Linear).is_err());
+    }
+
+    #[test]
+    fn test_facade_control() {
+        let ec =
+            ElectrochromicDC::from_aperture_dc(&aperture_dc(0.8), &aperture_dc(0.1), "skylight")
+                .unwrap();
+        let skies = skies(&ec.basis);
+        let axis = TimeAxis::new(1, 4100, 3).unwrap();
+        // The sensor above the roof does not see through the skylight
+        let facade = apply_annual(&ec.dc(0.).unwrap(), &skies).unwrap();
+        let facade: Vec<Float> = (0..3).map(|c| facade.get(1, c).unwrap()).collect();
+        let tinted = apply_annual(&ec.dc(1.).unwrap(), &skies).unwrap();
+        for (c, e) in facade.iter().enumerate() {
+            assert_close!(tinted.get(1, c).unwrap(), *e, 1e-4 * (1. + e));
+        }
+
+        // Clear in the first hour, tinted in the last one, and halfway between
+        let control = TintControl::FacadeIrradiance {
+            sensor: 1,
+            clear_below: facade[0] + 1e-3,
+            tinted_above: 2. * facade[1] - facade[0] - 1e-3,
+        };
+        let series = ec
+            .annual(&skies, &axis, &control, &TintCurve::Linear)
+            .unwrap();
+        assert_eq!(series.tint[0], 0.);
+        assert_close!(series.tint[1], 0.5, 1e-3);
+        assert_eq!(series.tint[2], 1.);
+        let at = |w: Float, c: usize| {
+            apply_annual(&ec.dc(w).unwrap(), &skies)
+                .unwrap()
+                .get(0, c)
+                .unwrap()
+        };
+        for c in 0..3 {
+            let expected = at(series.tint[c], c);
+            assert_close!(
+                series.values.values.get(0, c).unwrap(),
+                expected,
+                1e-4 * (1. + expected)
+            );
+        }
+
+        let inverted = TintControl::FacadeIrradiance {
+            sensor: 1,
+            clear_below: 100.,
+            tinted_above: 50.,
+        };
+        assert!(ec
+            .annual(&skies, &axis, &inverted, &TintCurve::Linear)
+            .is_err());
+        let missing = TintControl::FacadeIrradiance {
+            sensor: 2,
+            clear_below: 50.,
+            tinted_above: 100.,
+        };
+        assert!(ec
+            .annual(&skies, &axis, &missing, &TintCurve::Linear)
+            .is_err());
+    }
+}
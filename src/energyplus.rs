@@ -0,0 +1,293 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Annual results as the CSV files that EnergyPlus reads through `Schedule:File`,
+//! so that the illuminance of the reference points of a zone's
+//! `Daylighting:Controls` (or `Daylighting:DELight`) can come from this crate
+//! instead of the split-flux method.
+//!
+//! EnergyPlus schedules have one row per timestep, stamped at its *end*: the
+//! first row of the year is `01/01 01:00:00` (or `00:15:00`, with four
+//! timesteps per hour) and the last one of each day is `24:00:00`. This is the
+//! convention of EPW files too, so the value of the timestep that starts at
+//! midnight (see [`crate::time`]) goes in the row of 1:00, which is where the
+//! data of the EPW row for hour `1` belongs.
+
+use crate::annual::{annual_series, ApplySky};
+use crate::time::{month_day, AnnualSeries, TimeAxis, Timestep, HOURS_PER_YEAR};
+use matrix::Matrix;
+
+/// The number of rows before the values in the files written by
+/// [`EnergyPlusSchedules::to_csv`]: the zone, the reference points, the
+/// minutes per item and the column names. It is the `Rows to Skip at Top` of
+/// the `Schedule:File` objects.
+pub const SCHEDULE_HEADER_ROWS: usize = 4;
+
+/// The illuminance of the reference points of a zone, for a whole year, to be
+/// read by EnergyPlus
+#[derive(Debug, Clone)]
+pub struct EnergyPlusSchedules {
+    /// The name of the zone
+    pub zone: String,
+    /// The names of the reference points, one per row of the series
+    pub reference_points: Vec<String>,
+    /// The values, which are written as they are (EnergyPlus expects lux)
+    pub series: AnnualSeries,
+}
+
+/// Checks that a name can go in a CSV (and in an IDF) file as it is
+fn check_name(what: &str, name: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name.contains([',', ';', '!', '\n', '\r']) {
+        return Err(format!(
+            "The name of a {} must not be empty or contain commas, semicolons, exclamation marks or line breaks, but found '{}'",
+            what, name
+        ));
+    }
+    Ok(())
+}
+
+/// The `Date/Time` of a timestep, as EnergyPlus writes it: the month, day and
+/// time at which it ends, so that the last one of each day is at `24:00:00`
+fn energyplus_stamp(step: &Timestep) -> String {
+    let steps_per_day = 24 * step.timesteps_per_hour;
+    let index = step.index_in_year();
+    let (month, day) = month_day(index / steps_per_day);
+    let minutes = (index % steps_per_day + 1) * 60 / step.timesteps_per_hour;
+    format!(
+        " {:02}/{:02}  {:02}:{:02}:00",
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+impl EnergyPlusSchedules {
+    /// Names the rows of an annual `series`. EnergyPlus needs a whole year,
+    /// starting on the 1st of January, with a number of timesteps per hour
+    /// that divides an hour into whole minutes.
+    pub fn new(
+        zone: &str,
+        reference_points: &[&str],
+        series: AnnualSeries,
+    ) -> Result<Self, String> {
+        check_name("zone", zone)?;
+        for name in reference_points {
+            check_name("reference point", name)?;
+        }
+        let (nrows, _) = series.values.size();
+        if reference_points.len() != nrows {
+            return Err(format!(
+                "There are {} reference points, but the series has {} rows",
+                reference_points.len(),
+                nrows
+            ));
+        }
+        let axis = series.axis;
+        if 60 % axis.timesteps_per_hour != 0 {
+            return Err(format!(
+                "EnergyPlus schedules need a whole number of minutes per timestep, but there are {} timesteps per hour",
+                axis.timesteps_per_hour
+            ));
+        }
+        if axis != TimeAxis::annual(axis.timesteps_per_hour)? {
+            return Err(format!(
+                "EnergyPlus schedules need a whole year starting on the 1st of January, but the series has {} timesteps starting at {}",
+                axis.n_timesteps, axis.first
+            ));
+        }
+        Ok(Self {
+            zone: zone.to_string(),
+            reference_points: reference_points.iter().map(|s| s.to_string()).collect(),
+            series,
+        })
+    }
+
+    /// Like [`EnergyPlusSchedules::new`], applying skies—whose columns are the
+    /// timesteps of an `axis`—to the Daylight Coefficients of the reference points
+    pub fn from_dc<M: ApplySky>(
+        zone: &str,
+        reference_points: &[&str],
+        dc: &M,
+        skies: &Matrix,
+        axis: &TimeAxis,
+    ) -> Result<Self, String> {
+        Self::new(zone, reference_points, annual_series(dc, skies, axis)?)
+    }
+
+    /// The length of each timestep, in minutes
+    pub fn minutes_per_item(&self) -> usize {
+        60 / self.series.axis.timesteps_per_hour
+    }
+
+    /// Writes the schedules as CSV: [`SCHEDULE_HEADER_ROWS`] rows of metadata
+    /// and column names, followed by one row per timestep with its `Date/Time`
+    /// and the value of each reference point (in columns `2` onwards)
+    pub fn to_csv(&self) -> Result<String, String> {
+        let names = self.reference_points.join(",");
+        let mut ret = format!(
+            "Zone,{}\nReference Points,{}\nMinutes per Item,{}\nDate/Time,{}\n",
+            self.zone,
+            names,
+            self.minutes_per_item(),
+            names
+        );
+        for (c, step) in self.series.axis.timesteps().iter().enumerate() {
+            ret.push_str(&energyplus_stamp(step));
+            for r in 0..self.reference_points.len() {
+                ret.push_str(&format!(",{}", self.series.values.get(r, c)?));
+            }
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+
+    /// The `Schedule:File` objects that read each reference point from the
+    /// CSV written by [`EnergyPlusSchedules::to_csv`] to `file_name`. Each
+    /// schedule is named after the zone and the reference point.
+    pub fn schedule_file_objects(&self, file_name: &str) -> String {
+        self.reference_points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                format!(
+                    "Schedule:File,\n  {} {} Illuminance,  !- Name\n  ,  !- Schedule Type Limits Name\n  {},  !- File Name\n  {},  !- Column Number\n  {},  !- Rows to Skip at Top\n  {},  !- Number of Hours of Data\n  Comma,  !- Column Separator\n  No,  !- Interpolate to Timestep\n  {};  !- Minutes per Item\n",
+                    self.zone,
+                    point,
+                    file_name,
+                    i + 2,
+                    SCHEDULE_HEADER_ROWS,
+                    HOURS_PER_YEAR,
+                    self.minutes_per_item()
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::Float;
+
+    /// The global horizontal illuminance of every EPW row of a file, in the
+    /// timestep it belongs to
+    fn epw_illuminance(path: &str) -> AnnualSeries {
+        let content = std::fs::read_to_string(path).unwrap();
+        let mut values = Matrix::new(0.0, 1, HOURS_PER_YEAR);
+        for line in content.lines().skip(8) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let field = |i: usize| fields[i].trim().parse::<i32>().unwrap();
+            let step = Timestep::from_epw(field(0), field(1) as u8, field(2) as u8, field(3) as u8)
+                .unwrap()
+                .unwrap();
+            let lux: Float = fields[16].trim().parse().unwrap();
+            values.set(0, step.index, lux).unwrap();
+        }
+        AnnualSeries::new(values, TimeAxis::annual(1).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sunrise_alignment() {
+        let schedules = EnergyPlusSchedules::new(
+            "Office",
+            &["RP1"],
+            epw_illuminance("./tests/wellington.epw"),
+        )
+        .unwrap();
+        let csv = schedules.to_csv().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), SCHEDULE_HEADER_ROWS + HOURS_PER_YEAR);
+        assert_eq!(lines[SCHEDULE_HEADER_ROWS - 1], "Date/Time,RP1");
+        assert!(lines[SCHEDULE_HEADER_ROWS].starts_with(" 01/01  01:00:00,"));
+        assert!(lines.last().unwrap().starts_with(" 12/31  24:00:00,"));
+
+        // In Wellington, the sun rises at about 7:45 on the 21st of June, so
+        // the EPW row of hour 8 (from 7:00 to 8:00) is the first one with
+        // daylight, and so must be the schedule row stamped 08:00
+        let day: Vec<(&str, Float)> = lines[SCHEDULE_HEADER_ROWS..]
+            .iter()
+            .filter(|l| l.starts_with(" 06/21 "))
+            .map(|l| {
+                let (stamp, value) = l.split_once(',').unwrap();
+                (stamp, value.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(day.len(), 24);
+        let first = day.iter().find(|(_, v)| *v > 0.0).unwrap();
+        assert_eq!(first.0, " 06/21  08:00:00");
+        assert_eq!(day[6], (" 06/21  07:00:00", 0.0));
+
+        let content = std::fs::read_to_string("./tests/wellington.epw").unwrap();
+        let epw_row: Vec<&str> = content
+            .lines()
+            .skip(8)
+            .map(|l| l.split(',').collect::<Vec<&str>>())
+            .find(|fields| fields[1..4] == ["6", "21", "8"])
+            .unwrap();
+        assert_eq!(first.1, epw_row[16].trim().parse::<Float>().unwrap());
+    }
+
+    #[test]
+    fn test_subhourly() {
+        let axis = TimeAxis::annual(4).unwrap();
+        let mut values = Matrix::new(0.0, 2, axis.n_timesteps);
+        for c in 0..axis.n_timesteps {
+            values.set(0, c, c as Float).unwrap();
+            values.set(1, c, 1.).unwrap();
+        }
+        let dc = Matrix::from_data(2, 2, vec![1., 0., 0., 2.]);
+        let schedules =
+            EnergyPlusSchedules::from_dc("Office", &["RP1", "RP2"], &dc, &values, &axis).unwrap();
+        assert_eq!(schedules.minutes_per_item(), 15);
+        let csv = schedules.to_csv().unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[2], "Minutes per Item,15");
+        assert_eq!(lines[SCHEDULE_HEADER_ROWS], " 01/01  00:15:00,0,2");
+        assert_eq!(lines[SCHEDULE_HEADER_ROWS + 95], " 01/01  24:00:00,95,2");
+        assert_eq!(lines[SCHEDULE_HEADER_ROWS + 96], " 01/02  00:15:00,96,2");
+
+        let objects = schedules.schedule_file_objects("daylight.csv");
+        assert_eq!(objects.matches("Schedule:File,").count(), 2);
+        assert!(objects.contains("Office RP2 Illuminance,"));
+        assert!(objects.contains("  3,  !- Column Number"));
+        assert!(objects.contains("  4,  !- Rows to Skip at Top"));
+        assert!(objects.contains("  8760,  !- Number of Hours of Data"));
+        assert!(objects.contains("  15;  !- Minutes per Item"));
+    }
+
+    #[test]
+    fn test_refusals() {
+        let year = |tph: usize| {
+            let axis = TimeAxis::annual(tph).unwrap();
+            AnnualSeries::new(Matrix::new(0.0, 1, axis.n_timesteps), axis).unwrap()
+        };
+        assert!(EnergyPlusSchedules::new("Office", &["RP1"], year(1)).is_ok());
+        assert!(EnergyPlusSchedules::new("Office", &["RP1", "RP2"], year(1)).is_err());
+        assert!(EnergyPlusSchedules::new("Office, 1", &["RP1"], year(1)).is_err());
+        assert!(EnergyPlusSchedules::new("Office", &[""], year(1)).is_err());
+        assert!(EnergyPlusSchedules::new("Office", &["RP1"], year(7)).is_err());
+
+        let axis = TimeAxis::new(1, 24, HOURS_PER_YEAR - 24).unwrap();
+        let partial = AnnualSeries::new(Matrix::new(0.0, 1, axis.n_timesteps), axis).unwrap();
+        assert!(EnergyPlusSchedules::new("Office", &["RP1"], partial).is_err());
+    }
+}
@@ -0,0 +1,443 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::Float;
+use geometry3d::Vector3D;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The radiance of the environment that surrounds a scene, seen
+/// from any direction. Rays that escape the scene pick their value
+/// from here, instead of being binned into sky patches.
+pub trait SkyRadiance: Sync {
+    /// The radiance coming from `direction` (a unit vector pointing away from
+    /// the scene, in world coordinates)
+    fn radiance(&self, direction: Vector3D) -> Float;
+}
+
+/// An environment with the same radiance in all directions
+#[derive(Debug, Clone, Copy)]
+pub struct UniformSky(pub Float);
+
+impl SkyRadiance for UniformSky {
+    fn radiance(&self, _direction: Vector3D) -> Float {
+        self.0
+    }
+}
+
+/// Converts RGB radiance into a single value, with the same
+/// weights used by Radiance
+fn rgb_to_radiance(rgb: [Float; 3]) -> Float {
+    0.265 * rgb[0] + 0.670 * rgb[1] + 0.065 * rgb[2]
+}
+
+/// An image read from a Radiance `.hdr`/`.pic` file
+#[derive(Debug, Clone)]
+pub struct HdrImage {
+    /// The number of columns
+    pub width: usize,
+
+    /// The number of rows
+    pub height: usize,
+
+    /// The RGB values, row by row from the top of the image
+    pub pixels: Vec<[Float; 3]>,
+}
+
+impl HdrImage {
+    /// Reads an image from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Unable to open HDR file '{}': {}", path.display(), e))?;
+        Self::from_reader(file)
+    }
+
+    /// Reads an image in Radiance's RGBE format (flat or run-length encoded),
+    /// with the standard `-Y height +X width` orientation
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, String> {
+        let mut reader = BufReader::new(reader);
+        let mut exposure = 1.0;
+        let mut line = String::new();
+
+        // Header
+        let mut first = true;
+        loop {
+            line.clear();
+            let n = reader
+                .read_line(&mut line)
+                .map_err(|e| format!("Unable to read HDR header: {}", e))?;
+            if n == 0 {
+                return Err("Unexpected end of file in HDR header".to_string());
+            }
+            let l = line.trim();
+            if first {
+                if !l.starts_with("#?") {
+                    return Err("Not a Radiance HDR file".to_string());
+                }
+                first = false;
+                continue;
+            }
+            if l.is_empty() {
+                break;
+            }
+            if let Some(v) = l.strip_prefix("FORMAT=") {
+                if v != "32-bit_rle_rgbe" {
+                    return Err(format!("Unsupported HDR format '{}'", v));
+                }
+            } else if let Some(v) = l.strip_prefix("EXPOSURE=") {
+                let v: Float = v
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid exposure '{}' in HDR file", v))?;
+                exposure *= v;
+            }
+        }
+
+        // Resolution
+        line.clear();
+        reader
+            .read_line(&mut line)
+            .map_err(|e| format!("Unable to read HDR resolution: {}", e))?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 4 || parts[0] != "-Y" || parts[2] != "+X" {
+            return Err(format!(
+                "Unsupported HDR resolution line '{}'... only '-Y height +X width' is supported",
+                line.trim()
+            ));
+        }
+        let height: usize = parts[1]
+            .parse()
+            .map_err(|_| "Invalid HDR height".to_string())?;
+        let width: usize = parts[3]
+            .parse()
+            .map_err(|_| "Invalid HDR width".to_string())?;
+
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            read_scanline(&mut reader, &mut scanline)?;
+            for rgbe in scanline.iter() {
+                let mut rgb = rgbe_to_rgb(*rgbe);
+                rgb.iter_mut().for_each(|v| *v /= exposure);
+                pixels.push(rgb);
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// The RGB value of a pixel
+    pub fn pixel(&self, col: usize, row: usize) -> [Float; 3] {
+        self.pixels[row * self.width + col]
+    }
+
+    /// The radiance of the pixel at the normalized position
+    /// `(u, v)`, both in `[0, 1]` from the top-left corner
+    fn sample(&self, u: Float, v: Float) -> Float {
+        let col = ((u * self.width as Float) as usize).min(self.width - 1);
+        let row = ((v * self.height as Float) as usize).min(self.height - 1);
+        rgb_to_radiance(self.pixel(col, row))
+    }
+}
+
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> [Float; 3] {
+    if rgbe[3] == 0 {
+        return [0.; 3];
+    }
+    let f = (2.0 as Float).powi(rgbe[3] as i32 - (128 + 8));
+    [
+        (rgbe[0] as Float + 0.5) * f,
+        (rgbe[1] as Float + 0.5) * f,
+        (rgbe[2] as Float + 0.5) * f,
+    ]
+}
+
+fn read_bytes<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), String> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| format!("Unable to read HDR pixels: {}", e))
+}
+
+fn read_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> Result<(), String> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    read_bytes(reader, &mut first)?;
+    let is_rle = (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] < 128;
+    if !is_rle {
+        // Flat
+        scanline[0] = first;
+        for px in scanline.iter_mut().skip(1) {
+            read_bytes(reader, px)?;
+        }
+        return Ok(());
+    }
+    if ((first[2] as usize) << 8 | first[3] as usize) != width {
+        return Err("Scanline width mismatch in HDR file".to_string());
+    }
+    for channel in 0..4 {
+        let mut i = 0;
+        while i < width {
+            let mut count = [0u8; 1];
+            read_bytes(reader, &mut count)?;
+            let count = count[0] as usize;
+            if count > 128 {
+                let count = count - 128;
+                if i + count > width {
+                    return Err("Bad run in HDR file".to_string());
+                }
+                let mut value = [0u8; 1];
+                read_bytes(reader, &mut value)?;
+                for px in scanline[i..i + count].iter_mut() {
+                    px[channel] = value[0];
+                }
+                i += count;
+            } else {
+                if count == 0 || i + count > width {
+                    return Err("Bad run in HDR file".to_string());
+                }
+                let mut values = vec![0u8; count];
+                read_bytes(reader, &mut values)?;
+                for (px, v) in scanline[i..i + count].iter_mut().zip(values) {
+                    px[channel] = v;
+                }
+                i += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An [`HdrImage`] covering the whole sphere in equirectangular (i.e.,
+/// latitude-longitude) projection. The top row is the zenith and the bottom
+/// one the nadir; the first column is North (`+Y`), and azimuths grow
+/// towards East (`+X`).
+#[derive(Debug, Clone)]
+pub struct EquirectangularSky {
+    /// The image
+    pub image: HdrImage,
+}
+
+impl SkyRadiance for EquirectangularSky {
+    fn radiance(&self, direction: Vector3D) -> Float {
+        let d = direction.get_normalized();
+        let altitude = d.z.clamp(-1., 1.).asin();
+        let azimuth = d.x.atan2(d.y).rem_euclid(2. * crate::PI);
+        let u = azimuth / (2. * crate::PI);
+        let v = 0.5 - altitude / crate::PI;
+        self.image.sample(u, v)
+    }
+}
+
+/// An [`HdrImage`] of the sky as a 180° angular fisheye looking up, with
+/// North at the top (i.e., rendered by Radiance with `-vta -vh 180 -vv 180
+/// -vd 0 0 1 -vu 0 1 0`). As seen from below, East is on the left.
+#[derive(Debug, Clone)]
+pub struct AngularFisheyeSky {
+    /// The image
+    pub image: HdrImage,
+
+    /// The radiance of the directions below the horizon, which
+    /// are not in the image
+    pub ground: Float,
+}
+
+impl SkyRadiance for AngularFisheyeSky {
+    fn radiance(&self, direction: Vector3D) -> Float {
+        let d = direction.get_normalized();
+        if d.z <= 0.0 {
+            return self.ground;
+        }
+        // Distance from the centre, proportional to the zenith angle
+        let zenith = d.z.clamp(-1., 1.).acos();
+        let r = zenith / (0.5 * crate::PI);
+        let horizontal = (d.x * d.x + d.y * d.y).sqrt();
+        let (dx, dy) = if horizontal > 1e-9 {
+            (d.x / horizontal, d.y / horizontal)
+        } else {
+            (0., 0.)
+        };
+        let u = 0.5 * (1. - r * dx);
+        let v = 0.5 * (1. - r * dy);
+        self.image.sample(u, v)
+    }
+}
+
+/// The radiance that the direct tracer gives to the rays that escape the scene
+/// (see [`DCSession::with_escape_radiance`](crate::DCSession::with_escape_radiance)).
+///
+/// With the default, [`EscapeRadiance::Unit`], the coefficients correspond to
+/// a sky of unit radiance in every direction, and are meant to be multiplied by
+/// sky vectors afterwards. With any other, each sample is weighted by the
+/// radiance of its direction, so the sum of each row is directly the
+/// irradiance under that one sky.
+#[derive(Clone, Default)]
+pub enum EscapeRadiance {
+    /// A radiance of 1 in every direction
+    #[default]
+    Unit,
+
+    /// The relative radiance of the CIE overcast sky, `(1 + 2 sin(alt)) / 3`,
+    /// scaled so that the horizontal irradiance is that of the unit sky (i.e.,
+    /// `PI`). Below the horizon, the radiance is 1, as that of a white ground
+    /// lit by this sky.
+    CieOvercast,
+
+    /// A function of the direction (a unit vector pointing away from the scene)
+    Custom(Arc<EscapeFn>),
+}
+
+type EscapeFn = dyn Fn(Vector3D) -> Float + Send + Sync;
+
+impl EscapeRadiance {
+    /// Wraps a function of the direction
+    pub fn custom<F>(radiance: F) -> Self
+    where
+        F: Fn(Vector3D) -> Float + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(radiance))
+    }
+
+    /// Whether this is the default, [`EscapeRadiance::Unit`]
+    pub fn is_unit(&self) -> bool {
+        matches!(self, Self::Unit)
+    }
+}
+
+impl SkyRadiance for EscapeRadiance {
+    fn radiance(&self, direction: Vector3D) -> Float {
+        match self {
+            Self::Unit => 1.,
+            Self::CieOvercast => {
+                let sin_alt = direction.get_normalized().z;
+                if sin_alt < 0.0 {
+                    return 1.;
+                }
+                // The zenith radiance that gives a horizontal irradiance of PI
+                // is 9/7, as the CIE overcast sky gives 7 PI / 9 times it
+                3. * (1. + 2. * sin_alt) / 7.
+            }
+            Self::Custom(f) => f(direction),
+        }
+    }
+}
+
+impl fmt::Debug for EscapeRadiance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unit => write!(f, "Unit"),
+            Self::CieOvercast => write!(f, "CieOvercast"),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    /// An uncompressed image where the values of each pixel
+    /// are `rgb(col, row)`
+    fn flat_file(width: usize, height: usize, rgbe: impl Fn(usize, usize) -> [u8; 4]) -> Vec<u8> {
+        let mut buf = format!(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes();
+        for row in 0..height {
+            for col in 0..width {
+                buf.extend_from_slice(&rgbe(col, row));
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read_flat() {
+        let buf = flat_file(2, 3, |col, row| [128, 64 * col as u8, row as u8, 129]);
+        let img = HdrImage::from_reader(buf.as_slice()).unwrap();
+        assert_eq!((img.width, img.height), (2, 3));
+        let px = img.pixel(1, 2);
+        assert_close!(px[0], 128.5 / 128., 1e-6);
+        assert_close!(px[1], 64.5 / 128., 1e-6);
+        assert_close!(px[2], 2.5 / 128., 1e-6);
+
+        assert!(HdrImage::from_reader("P6\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_read_rle() {
+        let width = 8;
+        let mut buf = format!("#?RADIANCE\nEXPOSURE=2\n\n-Y 1 +X {}\n", width).into_bytes();
+        buf.extend_from_slice(&[2, 2, 0, width as u8]);
+        // R: two literal values and a run of 6
+        buf.extend_from_slice(&[2, 10, 20, 128 + 6, 30]);
+        // G, B and E: runs
+        buf.extend_from_slice(&[128 + 8, 0]);
+        buf.extend_from_slice(&[128 + 8, 0]);
+        buf.extend_from_slice(&[128 + 8, 136]);
+        let img = HdrImage::from_reader(buf.as_slice()).unwrap();
+        assert_close!(img.pixel(0, 0)[0], 10.5 / 2., 1e-6);
+        assert_close!(img.pixel(1, 0)[0], 20.5 / 2., 1e-6);
+        assert_close!(img.pixel(7, 0)[0], 30.5 / 2., 1e-6);
+    }
+
+    #[test]
+    fn test_mappings() {
+        // Top half bright, bottom half dark
+        let buf = flat_file(8, 4, |_, row| {
+            if row < 2 {
+                [128, 128, 128, 129]
+            } else {
+                [0; 4]
+            }
+        });
+        let image = HdrImage::from_reader(buf.as_slice()).unwrap();
+        let bright = rgb_to_radiance(image.pixel(0, 0));
+        let sky = EquirectangularSky {
+            image: image.clone(),
+        };
+        assert_close!(sky.radiance(Vector3D::new(0., 0., 1.)), bright, 1e-6);
+        assert_close!(sky.radiance(Vector3D::new(1., 0., 0.3)), bright, 1e-6);
+        assert_close!(sky.radiance(Vector3D::new(1., 0., -0.3)), 0., 1e-6);
+
+        // In a fisheye, the top-left quarter is North-East
+        let buf = flat_file(4, 4, |col, row| {
+            if col < 2 && row < 2 {
+                [128, 128, 128, 129]
+            } else {
+                [0; 4]
+            }
+        });
+        let fisheye = AngularFisheyeSky {
+            image: HdrImage::from_reader(buf.as_slice()).unwrap(),
+            ground: 0.1,
+        };
+        assert_close!(fisheye.radiance(Vector3D::new(1., 1., 1.)), bright, 1e-6);
+        assert_close!(fisheye.radiance(Vector3D::new(-1., 1., 1.)), 0., 1e-6);
+        assert_close!(fisheye.radiance(Vector3D::new(1., 1., -1.)), 0.1, 1e-6);
+    }
+}
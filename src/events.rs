@@ -0,0 +1,231 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use geometry3d::Ray3D;
+use std::collections::HashMap;
+
+/// The kinds of things that can go unexpectedly during a calculation
+/// without making it fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// A side of a surface was skipped because it does not receive sun
+    /// (e.g., it faces another space or the ground)
+    NonApplicableSide,
+
+    /// A sample escaped the scene below the horizon, so it was
+    /// accounted for in the ground bin
+    BelowHorizonEscape,
+
+    /// None of the samples of a sensor escaped the scene, which usually means
+    /// that it is inside a closed volume (or behind the surface it belongs to)
+    EnclosedSensor,
+
+    /// A sample produced a non-finite value, so it was discarded
+    BadSample,
+
+    /// A value out of its physical range (e.g., a negative radiance) was clamped
+    ClampedValue,
+
+    /// A sample hit an object that was meant to be excluded from the calculation
+    /// (i.e., the host surface of a sensor, see
+    /// [`SensorSpec::exclude_host_surface`](crate::SensorSpec::exclude_host_surface)),
+    /// and went on through it. The count is the number of such hits.
+    ExcludedObjectHit,
+
+    /// Coefficients below the noise floor were set to zero (see
+    /// [`NoiseFloor`](crate::NoiseFloor)). The count is the number of coefficients.
+    NoiseCulled,
+
+    /// A sensor has a surface right in front of it, so it is probably facing
+    /// into the surface it lies on (see
+    /// [`DCOptions::probe_distance`](crate::DCOptions::probe_distance))
+    FacingIntoSurface,
+
+    /// The calculation was expected to cast more rays than its
+    /// [`RayBudget`](crate::RayBudget) allows
+    RayBudgetExceeded,
+
+    /// The [`RayBudget`](crate::RayBudget) ran out, so a sensor was traced with
+    /// fewer samples than requested. The count is the number of samples it lost.
+    RayCapReached,
+
+    /// A sensor lay on a surface, so it was moved off it before the calculation
+    /// (see [`DCOptions::surface_offset`](crate::DCOptions::surface_offset))
+    OnSurface,
+
+    /// The calculation has a [`RayBudget`](crate::RayBudget), but its bounces are
+    /// traced by the `DCFactory`, whose rays can only be counted as if its paths did
+    /// not branch, so the budget may be exceeded
+    RayBudgetUnenforced,
+}
+
+/// Something that happened during a calculation. Repeated events of the same
+/// kind for the same sensor are merged into one, keeping the first `ray`.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// What happened
+    pub kind: EventKind,
+
+    /// The index of the sensor (or surface) involved, if any
+    pub sensor: Option<usize>,
+
+    /// The ray involved, if relevant
+    pub ray: Option<Ray3D>,
+
+    /// The number of times that this happened
+    pub count: usize,
+
+    /// A description of the event
+    pub message: String,
+}
+
+/// The [`Event`]s collected during a run.
+///
+/// When the `log` feature is enabled, each new event is also forwarded
+/// to `log::warn!`, so applications can route them to their own logging.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    events: Vec<Event>,
+
+    /// The position in `events` of the event of each kind and sensor
+    index: HashMap<(EventKind, Option<usize>), usize>,
+}
+
+impl EventLog {
+    /// Creates an empty log
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that something happened `count` times
+    pub fn push(
+        &mut self,
+        kind: EventKind,
+        sensor: Option<usize>,
+        ray: Option<Ray3D>,
+        count: usize,
+        message: String,
+    ) {
+        if count == 0 {
+            return;
+        }
+        if let Some(&i) = self.index.get(&(kind, sensor)) {
+            self.events[i].count += count;
+            return;
+        }
+        #[cfg(feature = "log")]
+        log::warn!("{:?} (sensor {:?}): {}", kind, sensor, message);
+        self.index.insert((kind, sensor), self.events.len());
+        self.events.push(Event {
+            kind,
+            sensor,
+            ray,
+            count,
+            message,
+        });
+    }
+
+    /// Adds the events of another log to this one
+    pub fn merge(&mut self, other: EventLog) {
+        for e in other.events {
+            self.push(e.kind, e.sensor, e.ray, e.count, e.message);
+        }
+    }
+
+    /// The recorded events, in order of first appearance
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// The events of a certain kind
+    pub fn of_kind(&self, kind: EventKind) -> impl Iterator<Item = &Event> {
+        self.events.iter().filter(move |e| e.kind == kind)
+    }
+
+    /// Whether an event of a certain kind was recorded for a sensor
+    pub fn has(&self, kind: EventKind, sensor: usize) -> bool {
+        self.index.contains_key(&(kind, Some(sensor)))
+    }
+
+    /// The number of distinct events
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Whether nothing was recorded
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use geometry3d::{Point3D, Vector3D};
+
+    #[test]
+    fn test_event_log() {
+        let ray = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., -1.),
+        };
+        let mut log = EventLog::new();
+        assert!(log.is_empty());
+        log.push(
+            EventKind::BadSample,
+            Some(1),
+            Some(ray),
+            2,
+            "NaN".to_string(),
+        );
+        log.push(EventKind::BadSample, Some(1), None, 3, "NaN".to_string());
+        log.push(EventKind::BadSample, Some(2), None, 1, "NaN".to_string());
+        log.push(
+            EventKind::ClampedValue,
+            Some(1),
+            None,
+            0,
+            "nothing".to_string(),
+        );
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.events()[0].count, 5);
+        assert!(log.events()[0].ray.is_some());
+        assert!(log.has(EventKind::BadSample, 2));
+        assert!(!log.has(EventKind::ClampedValue, 1));
+
+        let mut other = EventLog::new();
+        other.push(
+            EventKind::EnclosedSensor,
+            Some(0),
+            None,
+            1,
+            "closed".to_string(),
+        );
+        other.push(EventKind::BadSample, Some(2), None, 1, "NaN".to_string());
+        log.merge(other);
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            log.of_kind(EventKind::BadSample)
+                .map(|e| e.count)
+                .sum::<usize>(),
+            7
+        );
+    }
+}
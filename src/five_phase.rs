@@ -0,0 +1,493 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The five-phase method, in which the sun seen straight from each sensor is
+//! taken out of coarse coefficients and put back with fine ones:
+//!
+//! ```text
+//! E = C · S_total - C_direct · S_direct + C_sun · S_sun
+//! ```
+//!
+//! `C` are the coefficients of every path in a coarse basis (a Daylight
+//! Coefficient matrix, or the product `V·T·D` of the three-phase method) and
+//! `S_total` the whole sky and sun in that basis. `C_direct` are those of the
+//! paths that see the sky straight (i.e., with a `max_depth` of `0`) in the
+//! same basis, and `S_direct` the sun alone, exactly as it is within `S_total`.
+//! Their product is the sun seen straight through coarse patches, which is
+//! removed. `C_sun` are direct-only coefficients in a fine basis, and `S_sun` the
+//! sun alone in that basis, which puts it back sharply.
+//!
+//! The method only works if the subtracted term matches what it removes, so
+//! [`FivePhaseSkies`] builds all the skies from the same suns, with the same
+//! [`SunMapping`] and on the same [`TimeAxis`], and [`FivePhaseDC`] checks that
+//! the coefficients follow the bases of the skies. Neither the direct nor the
+//! sun term carries ground light: that is all in the total one.
+
+use crate::annual::apply_annual;
+use crate::labeled_matrix::LabeledMatrix;
+use crate::sky::{SkyBasis, SunMapping};
+use crate::sky_matrix::{GroundConvention, SkyMatrix};
+use crate::time::{AnnualSeries, TimeAxis};
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use solar::{PerezSky, SkyUnits, Solar, Time};
+use weather::Weather;
+
+/// The skies of the five-phase method, on the same timesteps (see the
+/// [module documentation](self))
+#[derive(Debug, Clone)]
+pub struct FivePhaseSkies {
+    /// The sky and the sun in the coarse basis
+    pub total: SkyMatrix,
+    /// The sun alone in the coarse basis, as it is within `total`
+    pub direct: SkyMatrix,
+    /// The sun alone in the fine basis
+    pub sun: SkyMatrix,
+    /// The timesteps of the columns of every sky
+    pub axis: TimeAxis,
+    /// How the sun was assigned to the patches of both bases
+    pub sun_mapping: SunMapping,
+}
+
+/// The vector of a sun coming from `direction` with a certain normal irradiance
+/// (or illuminance), spread over the patches of a `basis` as `mapping` says
+fn sun_vec(
+    basis: &SkyBasis,
+    direction: Vector3D,
+    normal: Float,
+    mapping: SunMapping,
+) -> Result<Matrix, String> {
+    let mut nearest = Matrix::new(0.0, basis.n_bins(), 1);
+    let bin = basis.bin(direction);
+    if bin != SkyBasis::GROUND_BIN {
+        nearest.set(bin, 0, normal / basis.solid_angles()[bin])?;
+    }
+    basis.map_sun(&nearest, direction, mapping)
+}
+
+/// Copies a sky vector into column `column` of a sky matrix (skipping the ground)
+fn set_column(skies: &mut Matrix, column: usize, vec: &Matrix) -> Result<(), String> {
+    let (n_bins, _) = vec.size();
+    for bin in 1..n_bins {
+        skies.set(bin, column, vec.get(bin, 0)?)?;
+    }
+    Ok(())
+}
+
+impl FivePhaseSkies {
+    /// Builds the skies from a coarse `sky` without the sun (e.g., from
+    /// `gendaymtx -s`) and the sun of each of its timesteps, given as its
+    /// direction and normal irradiance (in the units of the `sky`), or `None` when
+    /// it is down. The sun is spread into the patches of the coarse basis and of
+    /// the `fine` one with the same `mapping`, and it lights the ground of the
+    /// total sky if the `sky` includes it (see [`GroundConvention`]).
+    pub fn from_suns(
+        sky: &SkyMatrix,
+        fine: SkyBasis,
+        suns: &[Option<(Vector3D, Float)>],
+        axis: &TimeAxis,
+        mapping: SunMapping,
+    ) -> Result<Self, String> {
+        axis.check_columns(&sky.values)?;
+        if suns.len() != axis.n_timesteps {
+            return Err(format!(
+                "The time axis has {} timesteps, but {} suns were given",
+                axis.n_timesteps,
+                suns.len()
+            ));
+        }
+        let coarse = sky.basis;
+        let mut total = sky.values.clone();
+        let mut direct = Matrix::new(0.0, coarse.n_bins(), axis.n_timesteps);
+        let mut sun = Matrix::new(0.0, fine.n_bins(), axis.n_timesteps);
+        let omegas = coarse.solid_angles();
+        let centroids = coarse.centroids();
+        for (c, s) in suns.iter().enumerate() {
+            let Some((direction, normal)) = s else {
+                continue;
+            };
+            if !normal.is_finite() || *normal < 0.0 {
+                return Err(format!(
+                    "The normal irradiance of the sun must be a non-negative number, but found {} at timestep {}",
+                    normal, c
+                ));
+            }
+            let coarse_sun = sun_vec(&coarse, *direction, *normal, mapping)?;
+            set_column(&mut direct, c, &coarse_sun)?;
+            set_column(&mut sun, c, &sun_vec(&fine, *direction, *normal, mapping)?)?;
+            let mut horizontal = 0.0;
+            for bin in 1..coarse.n_bins() {
+                let v = coarse_sun.get(bin, 0)?;
+                total.set(bin, c, total.get(bin, c)? + v)?;
+                horizontal += v * omegas[bin] * centroids[bin].z;
+            }
+            if let GroundConvention::GroundIncluded { albedo } = sky.ground {
+                let ground = total.get(SkyBasis::GROUND_BIN, c)?;
+                total.set(SkyBasis::GROUND_BIN, c, ground + albedo * horizontal / PI)?;
+            }
+        }
+        Ok(Self {
+            total: SkyMatrix::new(coarse, total, sky.ground)?,
+            direct: SkyMatrix::new(coarse, direct, GroundConvention::GroundExcluded)?,
+            sun: SkyMatrix::new(fine, sun, GroundConvention::GroundExcluded)?,
+            axis: *axis,
+            sun_mapping: mapping,
+        })
+    }
+
+    /// Builds the Perez skies of the timesteps of an `axis` in a `coarse` and a
+    /// `fine` basis, from the `weather` at the location of the `solar` data (as
+    /// [`cumulative_sky`](crate::cumulative_sky) does). The ground of the total sky
+    /// has a certain `albedo`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_weather<W: Weather + ?Sized>(
+        weather: &W,
+        solar: &Solar,
+        coarse: SkyBasis,
+        fine: SkyBasis,
+        axis: &TimeAxis,
+        units: SkyUnits,
+        albedo: Float,
+        mapping: SunMapping,
+    ) -> Result<Self, String> {
+        let mut sky = Matrix::new(0.0, coarse.n_bins(), axis.n_timesteps);
+        let mut suns = Vec::with_capacity(axis.n_timesteps);
+        for (c, step) in axis.timesteps().iter().enumerate() {
+            let date = step.date();
+            let weather_data = weather.get_weather_data(date);
+            let direct = weather_data.direct_normal_radiation.ok_or_else(|| {
+                format!(
+                    "Missing direct normal radiation in the weather of {:?}",
+                    date
+                )
+            })?;
+            let diffuse = weather_data.diffuse_horizontal_radiation.ok_or_else(|| {
+                format!(
+                    "Missing diffuse horizontal radiation in the weather of {:?}",
+                    date
+                )
+            })?;
+            if direct + diffuse < 1e-4 {
+                suns.push(None);
+                continue;
+            }
+            let vec = PerezSky::gen_sky_vec(
+                coarse.mf(),
+                solar,
+                date,
+                weather_data,
+                units,
+                albedo,
+                true,
+                false,
+            )?;
+            for bin in 0..coarse.n_bins() {
+                sky.set(bin, c, vec.get(bin, 0)?)?;
+            }
+            // The sun is brought in nearest to its patch, in the units of the sky
+            suns.push(
+                match solar.sun_position(Time::Standard(date.day_of_year())) {
+                    Some(direction) => {
+                        let vec = PerezSky::gen_sky_vec(
+                            coarse.mf(),
+                            solar,
+                            date,
+                            weather_data,
+                            units,
+                            albedo,
+                            false,
+                            true,
+                        )?;
+                        Some((direction, coarse.integrate_sky_vec(&vec)?))
+                    }
+                    None => None,
+                },
+            );
+        }
+        let sky = SkyMatrix::from_gendaymtx(coarse, sky, albedo)?;
+        Self::from_suns(&sky, fine, &suns, axis, mapping)
+    }
+}
+
+/// The coefficients of the five-phase method (see the [module documentation](self))
+#[derive(Debug, Clone)]
+pub struct FivePhaseDC {
+    /// The coarse basis of `total` and `direct`
+    pub basis: SkyBasis,
+    /// The coefficients of every path, in the coarse basis
+    pub total: LabeledMatrix,
+    /// The coefficients of the paths that see the sky straight, in the coarse basis
+    pub direct: LabeledMatrix,
+    /// The fine basis of `sun`
+    pub sun_basis: SkyBasis,
+    /// The coefficients of the paths that see the sky straight, in the fine basis
+    pub sun: LabeledMatrix,
+}
+
+impl FivePhaseDC {
+    /// Gathers the coefficients of the three terms, checking that `total` and
+    /// `direct` follow the coarse `basis` and `sun` the fine `sun_basis`, and that
+    /// they all describe the same sensors
+    pub fn new(
+        basis: SkyBasis,
+        total: LabeledMatrix,
+        direct: LabeledMatrix,
+        sun_basis: SkyBasis,
+        sun: LabeledMatrix,
+    ) -> Result<Self, String> {
+        for (what, m, b) in [
+            ("total", &total, &basis),
+            ("direct", &direct, &basis),
+            ("sun", &sun, &sun_basis),
+        ] {
+            b.check_dc(&m.matrix)
+                .map_err(|e| format!("The {} coefficients do not fit: {}", what, e))?;
+        }
+        if sun_basis.mf() < basis.mf() {
+            return Err(format!(
+                "The sun coefficients must be at least as fine as the others, but they have MF {} and the others MF {}",
+                sun_basis.mf(),
+                basis.mf()
+            ));
+        }
+        let n_sensors = total.matrix.size().0;
+        for (what, m) in [("direct", &direct), ("sun", &sun)] {
+            if m.matrix.size().0 != n_sensors {
+                return Err(format!(
+                    "There are {} sensors in the total coefficients, but {} in the {} ones",
+                    n_sensors,
+                    m.matrix.size().0,
+                    what
+                ));
+            }
+        }
+        if direct.ground != sun.ground {
+            return Err(format!(
+                "The direct coefficients expect a ground {:?}, but the sun ones {:?}",
+                direct.ground, sun.ground
+            ));
+        }
+        Ok(Self {
+            basis,
+            total,
+            direct,
+            sun_basis,
+            sun,
+        })
+    }
+
+    /// The annual result of every sensor under the `skies`, with the sun seen
+    /// straight put back through the fine coefficients. Fails if any of the
+    /// skies does not follow the basis of its coefficients, which would subtract
+    /// a sun other than the one within the total sky.
+    pub fn apply(&self, skies: &FivePhaseSkies) -> Result<AnnualSeries, String> {
+        for (what, sky, basis) in [
+            ("total", &skies.total, &self.basis),
+            ("direct", &skies.direct, &self.basis),
+            ("sun", &skies.sun, &self.sun_basis),
+        ] {
+            if sky.basis.mf() != basis.mf() {
+                return Err(format!(
+                    "The {} sky has MF {}, but its coefficients have MF {}",
+                    what,
+                    sky.basis.mf(),
+                    basis.mf()
+                ));
+            }
+            skies.axis.check_columns(&sky.values)?;
+        }
+        let total = self.total.apply_sky(&skies.total)?;
+        let direct = apply_annual(
+            &self.direct.matrix,
+            &skies.direct.reconciled(GroundConvention::GroundExcluded)?,
+        )?;
+        let sun = apply_annual(
+            &self.sun.matrix,
+            &skies.sun.reconciled(GroundConvention::GroundExcluded)?,
+        )?;
+        AnnualSeries::new(&(&total - &direct) + &sun, skies.axis)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::events::EventLog;
+    use validate::assert_close;
+
+    fn column(m: &Matrix, c: usize) -> Matrix {
+        let (n_bins, _) = m.size();
+        let mut ret = Matrix::new(0.0, n_bins, 1);
+        for bin in 0..n_bins {
+            ret.set(bin, 0, m.get(bin, c).unwrap()).unwrap();
+        }
+        ret
+    }
+
+    fn labeled(n_sensors: usize, basis: &SkyBasis, ground: GroundConvention) -> LabeledMatrix {
+        LabeledMatrix {
+            matrix: Matrix::new(0.1, n_sensors, basis.n_bins()),
+            rows: Vec::new(),
+            events: EventLog::new(),
+            ground,
+        }
+    }
+
+    #[test]
+    fn test_from_suns() {
+        let coarse = SkyBasis::new(1).unwrap();
+        let fine = SkyBasis::new(2).unwrap();
+        let axis = TimeAxis::new(1, 4000, 3).unwrap();
+        let mut values = Matrix::new(10.0, coarse.n_bins(), 3);
+        for c in 0..3 {
+            values.set(SkyBasis::GROUND_BIN, c, 3.0).unwrap();
+        }
+        let albedo = 0.2;
+        let sky =
+            SkyMatrix::new(coarse, values, GroundConvention::GroundIncluded { albedo }).unwrap();
+        let direction = Vector3D::new(0.3, 0.4, 0.8).get_normalized();
+        let suns = [None, Some((direction, 600.0)), Some((direction, 0.0))];
+        let skies =
+            FivePhaseSkies::from_suns(&sky, fine, &suns, &axis, SunMapping::Shared(4)).unwrap();
+
+        // Without a sun, the total is the sky and the rest are dark
+        for c in [0, 2] {
+            assert_eq!(
+                coarse
+                    .integrate_sky_vec(&column(&skies.direct.values, c))
+                    .unwrap(),
+                0.0
+            );
+            assert_eq!(
+                fine.integrate_sky_vec(&column(&skies.sun.values, c))
+                    .unwrap(),
+                0.0
+            );
+            for bin in 0..coarse.n_bins() {
+                assert_eq!(
+                    skies.total.values.get(bin, c).unwrap(),
+                    sky.values.get(bin, c).unwrap()
+                );
+            }
+        }
+
+        // The sun carries the same energy in both bases, and the total sky holds
+        // exactly the direct one
+        let direct = column(&skies.direct.values, 1);
+        assert_close!(coarse.integrate_sky_vec(&direct).unwrap(), 600.0, 1e-2);
+        assert_close!(
+            fine.integrate_sky_vec(&column(&skies.sun.values, 1))
+                .unwrap(),
+            600.0,
+            1e-2
+        );
+        let omegas = coarse.solid_angles();
+        let centroids = coarse.centroids();
+        let mut horizontal = 0.0;
+        for bin in 1..coarse.n_bins() {
+            let v = direct.get(bin, 0).unwrap();
+            assert_close!(skies.total.values.get(bin, 1).unwrap() - 10.0, v, 1e-3);
+            horizontal += v * omegas[bin] * centroids[bin].z;
+        }
+        assert!(horizontal > 400.0);
+
+        // The sun lights the ground of the total sky, but not of the others
+        assert_close!(
+            skies.total.values.get(SkyBasis::GROUND_BIN, 1).unwrap(),
+            3.0 + albedo * horizontal / PI,
+            1e-3
+        );
+        assert_eq!(skies.direct.ground, GroundConvention::GroundExcluded);
+        assert_eq!(skies.sun.ground, GroundConvention::GroundExcluded);
+        assert_eq!(
+            skies.direct.values.get(SkyBasis::GROUND_BIN, 1).unwrap(),
+            0.0
+        );
+
+        // Suns that do not match the axis
+        assert!(
+            FivePhaseSkies::from_suns(&sky, fine, &suns[..2], &axis, SunMapping::Nearest)
+                .unwrap_err()
+                .contains("2 suns")
+        );
+        let bad = [None, Some((direction, Float::NAN)), None];
+        assert!(FivePhaseSkies::from_suns(&sky, fine, &bad, &axis, SunMapping::Nearest).is_err());
+    }
+
+    #[test]
+    fn test_misconfigured() {
+        let coarse = SkyBasis::new(1).unwrap();
+        let fine = SkyBasis::new(2).unwrap();
+        let excluded = GroundConvention::GroundExcluded;
+        let total = labeled(2, &coarse, GroundConvention::default());
+        let direct = labeled(2, &coarse, excluded);
+        let sun = labeled(2, &fine, excluded);
+
+        // Coefficients in a basis other than the one they are said to follow
+        let err =
+            FivePhaseDC::new(coarse, total.clone(), sun.clone(), fine, sun.clone()).unwrap_err();
+        assert!(err.contains("direct"), "{}", err);
+
+        // A sun coarser than the rest
+        let err =
+            FivePhaseDC::new(fine, sun.clone(), sun.clone(), coarse, direct.clone()).unwrap_err();
+        assert!(err.contains("at least as fine"), "{}", err);
+
+        // Different sensors
+        let err = FivePhaseDC::new(
+            coarse,
+            total.clone(),
+            direct.clone(),
+            fine,
+            labeled(3, &fine, excluded),
+        )
+        .unwrap_err();
+        assert!(err.contains("3 in the sun"), "{}", err);
+
+        // Direct and sun terms whose grounds do not cancel
+        let err = FivePhaseDC::new(
+            coarse,
+            total.clone(),
+            direct.clone(),
+            fine,
+            labeled(2, &fine, GroundConvention::default()),
+        )
+        .unwrap_err();
+        assert!(err.contains("ground"), "{}", err);
+
+        // Skies in the wrong bases, or with the wrong number of timesteps
+        let dc = FivePhaseDC::new(coarse, total, direct, fine, sun).unwrap();
+        let axis = TimeAxis::new(1, 4000, 2).unwrap();
+        let sky = SkyMatrix::new(coarse, Matrix::new(1.0, coarse.n_bins(), 2), excluded).unwrap();
+        let suns = [Some((Vector3D::new(0., 0., 1.), 100.0)), None];
+        let skies =
+            FivePhaseSkies::from_suns(&sky, coarse, &suns, &axis, SunMapping::Nearest).unwrap();
+        let err = dc.apply(&skies).unwrap_err();
+        assert!(err.contains("The sun sky has MF 1"), "{}", err);
+
+        let mut skies =
+            FivePhaseSkies::from_suns(&sky, fine, &suns, &axis, SunMapping::Nearest).unwrap();
+        let series = dc.apply(&skies).unwrap();
+        assert_eq!(series.values.size(), (2, 2));
+        skies.axis = TimeAxis::new(1, 4000, 3).unwrap();
+        assert!(dc.apply(&skies).is_err());
+    }
+}
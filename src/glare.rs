@@ -0,0 +1,532 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Detection of glare sources and Daylight Glare Probability (DGP), in the
+//! spirit of `evalglare`, from the luminance seen by a camera.
+//!
+//! A view Daylight Coefficient matrix has one row per pixel of a
+//! [`GlareCamera`] and one column per sky bin, so multiplying it by a sky
+//! vector gives the luminance image of that sky.
+
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use matrix::Matrix;
+
+/// A 180° angular fisheye camera, with square pixels numbered row by row
+/// from the top left corner of the image. The pixels outside the circle
+/// of the fisheye see nothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlareCamera {
+    /// The direction of the line of sight
+    pub view: Vector3D,
+
+    /// The up direction of the image, perpendicular to `view`
+    pub up: Vector3D,
+
+    /// The number of pixels of each side of the image
+    pub resolution: usize,
+}
+
+impl GlareCamera {
+    /// A camera looking along `view`, with `up` as the up direction (which only
+    /// needs to be not parallel to `view`), with `resolution × resolution` pixels
+    pub fn new(view: Vector3D, up: Vector3D, resolution: usize) -> Result<Self, String> {
+        if resolution == 0 {
+            return Err("A camera needs at least one pixel".to_string());
+        }
+        if view.is_zero() {
+            return Err("The view direction of a camera cannot be zero".to_string());
+        }
+        let view = view.get_normalized();
+        let up = up - view * (up * view);
+        if up.length() < 1e-6 {
+            return Err(
+                "The up direction of a camera cannot be parallel to its view direction".to_string(),
+            );
+        }
+        Ok(Self {
+            view,
+            up: up.get_normalized(),
+            resolution,
+        })
+    }
+
+    /// The number of pixels of the image
+    pub fn n_pixels(&self) -> usize {
+        self.resolution * self.resolution
+    }
+
+    /// The position of a pixel in the image plane, with `(0, 0)` at the centre,
+    /// `x` towards the right and `y` up, and the edges of the fisheye at a distance of `1`
+    fn image_position(&self, pixel: usize) -> (Float, Float) {
+        let n = self.resolution as Float;
+        let (row, col) = (pixel / self.resolution, pixel % self.resolution);
+        let x = 2. * (col as Float + 0.5) / n - 1.;
+        let y = 1. - 2. * (row as Float + 0.5) / n;
+        (x, y)
+    }
+
+    /// The direction seen by a pixel, or `None` if it is outside the fisheye
+    pub fn direction(&self, pixel: usize) -> Option<Vector3D> {
+        let (x, y) = self.image_position(pixel);
+        let r = (x * x + y * y).sqrt();
+        if r > 1. || pixel >= self.n_pixels() {
+            return None;
+        }
+        let theta = 0.5 * PI * r;
+        if r < 1e-9 {
+            return Some(self.view);
+        }
+        let right = self.view.cross(self.up);
+        let radial = (right * x + self.up * y) * (1. / r);
+        Some(self.view * theta.cos() + radial * theta.sin())
+    }
+
+    /// The solid angle of a pixel, which is zero outside the fisheye
+    pub fn solid_angle(&self, pixel: usize) -> Float {
+        let (x, y) = self.image_position(pixel);
+        let r = (x * x + y * y).sqrt();
+        if r > 1. || pixel >= self.n_pixels() {
+            return 0.0;
+        }
+        // Each pixel spans the same angle along the radius, and the
+        // circumference grows as sin(theta) rather than theta
+        let side = PI / self.resolution as Float;
+        let theta = 0.5 * PI * r;
+        if theta < 1e-9 {
+            side * side
+        } else {
+            side * side * theta.sin() / theta
+        }
+    }
+}
+
+/// The options of the glare evaluation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlareOptions {
+    /// Pixels brighter than this many times the average luminance
+    /// of the image are part of glare sources (`evalglare` uses 5)
+    pub source_factor: Float,
+
+    /// Applies the correction of Wienold (2009) for low vertical illuminances,
+    /// for which the DGP formula was not validated
+    pub low_light_correction: bool,
+}
+
+impl Default for GlareOptions {
+    fn default() -> Self {
+        Self {
+            source_factor: 5.,
+            low_light_correction: true,
+        }
+    }
+}
+
+/// A group of contiguous pixels that are bright enough to cause glare
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlareSource {
+    /// The pixels that make the source
+    pub pixels: Vec<usize>,
+
+    /// The solid angle of the source, in steradians
+    pub solid_angle: Float,
+
+    /// The average luminance of the source, weighted by solid angle
+    pub luminance: Float,
+
+    /// The average direction of the source, weighted by solid angle
+    pub direction: Vector3D,
+
+    /// The Guth position index of the source (see [`guth_position_index`])
+    pub position_index: Float,
+}
+
+/// The glare seen by a camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlareResult {
+    /// The illuminance at the camera, on the plane perpendicular to the line of sight
+    pub vertical_illuminance: Float,
+
+    /// The Daylight Glare Probability
+    pub dgp: Float,
+
+    /// The glare sources that were found
+    pub sources: Vec<GlareSource>,
+}
+
+/// The position index of Guth (as presented by Luckiesh and Guth, 1949), which tells
+/// how much less glaring a source becomes as it moves away from the line of sight.
+/// `sigma` is the angle between the line of sight and the direction of the source,
+/// and `tau` is the angle between the vertical and the plane that contains both
+/// (both in degrees). It is `1` along the line of sight.
+pub fn guth_position_index(sigma: Float, tau: Float) -> Float {
+    let linear = (35.2 - 0.31889 * tau - 1.22 * (-2. * tau / 9.).exp()) * 1e-3 * sigma;
+    let quadratic = (21. + 0.26667 * tau - 0.002963 * tau * tau) * 1e-5 * sigma * sigma;
+    (linear + quadratic).exp()
+}
+
+/// The Guth position index of a source in `direction`, seen by a camera
+pub fn position_index(camera: &GlareCamera, direction: Vector3D) -> Float {
+    let d = direction.get_normalized();
+    let sigma = (d * camera.view).clamp(-1., 1.).acos().to_degrees();
+    let right = camera.view.cross(camera.up);
+    let (x, y) = (d * right, d * camera.up);
+    let tau = if x.abs() < 1e-9 && y.abs() < 1e-9 {
+        0.0
+    } else {
+        x.abs().atan2(y).to_degrees()
+    };
+    guth_position_index(sigma, tau)
+}
+
+/// Groups the pixels that are `true` in `mask` into regions of pixels that share
+/// an edge (i.e., 4-connected), in order of their first pixel
+fn connected_regions(mask: &[bool], resolution: usize) -> Vec<Vec<usize>> {
+    let mut region = vec![usize::MAX; mask.len()];
+    let mut ret: Vec<Vec<usize>> = Vec::new();
+    for start in 0..mask.len() {
+        if !mask[start] || region[start] != usize::MAX {
+            continue;
+        }
+        let id = ret.len();
+        let mut pixels = Vec::new();
+        let mut stack = vec![start];
+        region[start] = id;
+        while let Some(p) = stack.pop() {
+            pixels.push(p);
+            let (row, col) = (p / resolution, p % resolution);
+            let mut neighbours = Vec::with_capacity(4);
+            if row > 0 {
+                neighbours.push(p - resolution);
+            }
+            if row + 1 < resolution {
+                neighbours.push(p + resolution);
+            }
+            if col > 0 {
+                neighbours.push(p - 1);
+            }
+            if col + 1 < resolution {
+                neighbours.push(p + 1);
+            }
+            for q in neighbours {
+                if mask[q] && region[q] == usize::MAX {
+                    region[q] = id;
+                    stack.push(q);
+                }
+            }
+        }
+        pixels.sort_unstable();
+        ret.push(pixels);
+    }
+    ret
+}
+
+/// Finds the glare sources of a luminance image seen by a `camera` (one value
+/// per pixel, in cd/m²) and calculates its Daylight Glare Probability (Wienold and
+/// Christoffersen, 2006):
+///
+/// `DGP = 5.87e-5 Ev + 9.18e-2 log10(1 + Σ Ls² ωs / (Ev^1.87 P²)) + 0.16`
+///
+/// where `Ev` is the vertical illuminance and each source has a luminance `Ls`,
+/// a solid angle `ωs` and a position index `P`.
+pub fn evaluate_image(
+    camera: &GlareCamera,
+    luminance: &[Float],
+    options: &GlareOptions,
+) -> Result<GlareResult, String> {
+    let n_pixels = camera.n_pixels();
+    if luminance.len() != n_pixels {
+        return Err(format!(
+            "The image has {} pixels, but the camera has {}",
+            luminance.len(),
+            n_pixels
+        ));
+    }
+    let mut vertical_illuminance = 0.0;
+    let mut total_omega = 0.0;
+    let mut total_luminance = 0.0;
+    for (pixel, l) in luminance.iter().enumerate() {
+        if let Some(dir) = camera.direction(pixel) {
+            let omega = camera.solid_angle(pixel);
+            vertical_illuminance += l * omega * (dir * camera.view).max(0.0);
+            total_omega += omega;
+            total_luminance += l * omega;
+        }
+    }
+    let threshold = options.source_factor * total_luminance / total_omega;
+    let mask: Vec<bool> = luminance
+        .iter()
+        .enumerate()
+        .map(|(pixel, l)| camera.solid_angle(pixel) > 0.0 && *l > threshold)
+        .collect();
+
+    let mut sources = Vec::new();
+    let mut sum = 0.0;
+    for pixels in connected_regions(&mask, camera.resolution) {
+        let mut solid_angle = 0.0;
+        let mut energy = 0.0;
+        let mut direction = Vector3D::new(0., 0., 0.);
+        for p in &pixels {
+            let omega = camera.solid_angle(*p);
+            solid_angle += omega;
+            energy += luminance[*p] * omega;
+            if let Some(d) = camera.direction(*p) {
+                direction += d * omega;
+            }
+        }
+        let direction = direction.get_normalized();
+        let luminance = energy / solid_angle;
+        let position_index = position_index(camera, direction);
+        sum += luminance * luminance * solid_angle / (position_index * position_index);
+        sources.push(GlareSource {
+            pixels,
+            solid_angle,
+            luminance,
+            direction,
+            position_index,
+        });
+    }
+
+    let ev = vertical_illuminance;
+    let mut dgp = 5.87e-5 * ev + 0.16;
+    if ev > 0.0 {
+        dgp += 9.18e-2 * (1. + sum / ev.powf(1.87)).log10();
+    }
+    if options.low_light_correction && ev < 1000. {
+        let e = (0.024 * ev - 4.).exp();
+        dgp *= e / (1. + e);
+    }
+    Ok(GlareResult {
+        vertical_illuminance,
+        dgp: dgp.min(1.),
+        sources,
+    })
+}
+
+/// Evaluates the glare of every timestep of an annual sky matrix (one row per
+/// bin and one column per timestep), given the view Daylight Coefficient matrix
+/// of a `camera` (one row per pixel and one column per bin).
+pub fn evaluate_glare(
+    camera: &GlareCamera,
+    view_dc: &Matrix,
+    skies: &Matrix,
+    options: &GlareOptions,
+) -> Result<Vec<GlareResult>, String> {
+    let (n_pixels, n_bins) = view_dc.size();
+    if n_pixels != camera.n_pixels() {
+        return Err(format!(
+            "The view Daylight Coefficient matrix has {} rows, but the camera has {} pixels",
+            n_pixels,
+            camera.n_pixels()
+        ));
+    }
+    let (sky_bins, n_steps) = skies.size();
+    if sky_bins != n_bins {
+        return Err(format!(
+            "The view Daylight Coefficient matrix has {} bins, but the skies have {}",
+            n_bins, sky_bins
+        ));
+    }
+    let images = crate::annual::apply_annual(view_dc, skies)?;
+    (0..n_steps)
+        .map(|t| {
+            let image = (0..n_pixels)
+                .map(|p| images.get(p, t))
+                .collect::<Result<Vec<Float>, String>>()?;
+            evaluate_image(camera, &image, options)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    fn camera(resolution: usize) -> GlareCamera {
+        GlareCamera::new(
+            Vector3D::new(0., 1., 0.),
+            Vector3D::new(0., 0., 1.),
+            resolution,
+        )
+        .unwrap()
+    }
+
+    /// A black image with squares of `side` pixels and a certain luminance,
+    /// whose top left corners are at `(col, row)`
+    fn squares(camera: &GlareCamera, side: usize, squares: &[(usize, usize, Float)]) -> Vec<Float> {
+        let mut ret = vec![0.0; camera.n_pixels()];
+        for (col, row, l) in squares {
+            for r in *row..row + side {
+                for c in *col..col + side {
+                    ret[r * camera.resolution + c] = *l;
+                }
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn test_camera() {
+        let camera = camera(101);
+        let total: Float = (0..camera.n_pixels()).map(|p| camera.solid_angle(p)).sum();
+        assert_close!(total, 2. * PI, 0.02 * 2. * PI);
+
+        // The centre looks forward, the top up and the right to the East
+        let centre = 50 * 101 + 50;
+        assert_eq!(camera.direction(centre), Some(Vector3D::new(0., 1., 0.)));
+        let top = camera.direction(50).unwrap();
+        assert!(top.z > 0.99);
+        let right = camera.direction(centre + 50).unwrap();
+        assert!(right.x > 0.99);
+
+        // Corners are out of the fisheye
+        assert!(camera.direction(0).is_none());
+        assert_eq!(camera.solid_angle(0), 0.0);
+
+        assert!(
+            GlareCamera::new(Vector3D::new(0., 1., 0.), Vector3D::new(0., 2., 0.), 10).is_err()
+        );
+        assert!(GlareCamera::new(Vector3D::new(0., 1., 0.), Vector3D::new(0., 0., 1.), 0).is_err());
+    }
+
+    #[test]
+    fn test_position_index() {
+        assert_close!(guth_position_index(0., 0.), 1., 1e-6);
+        // exp((35.2 - 1.22) 30e-3 + 21e-5 900)
+        assert_close!(guth_position_index(30., 0.), 3.348, 1e-3);
+        assert!(guth_position_index(40., 0.) > guth_position_index(20., 0.));
+
+        let camera = camera(10);
+        assert_close!(position_index(&camera, camera.view), 1., 1e-6);
+        let angle = (30. as Float).to_radians();
+        let above = Vector3D::new(0., angle.cos(), angle.sin());
+        assert_close!(
+            position_index(&camera, above),
+            guth_position_index(30., 0.),
+            1e-3
+        );
+        // Sources above the line of sight are less glaring than to the sides
+        let side = Vector3D::new(angle.sin(), angle.cos(), 0.);
+        assert_close!(
+            position_index(&camera, side),
+            guth_position_index(30., 90.),
+            1e-3
+        );
+        assert!(position_index(&camera, above) > position_index(&camera, side));
+    }
+
+    #[test]
+    fn test_single_square() {
+        // A 5 by 5 square of 1e5 cd/m2 right in the line of sight, on black
+        let camera = camera(101);
+        let ls = 1e5;
+        let image = squares(&camera, 5, &[(48, 48, ls)]);
+        let options = GlareOptions {
+            low_light_correction: false,
+            ..GlareOptions::default()
+        };
+        let result = evaluate_image(&camera, &image, &options).unwrap();
+        assert_eq!(result.sources.len(), 1);
+        let source = &result.sources[0];
+        assert_eq!(source.pixels.len(), 25);
+        assert_close!(source.luminance, ls, 1e-6 * ls);
+        assert_close!(source.position_index, 1., 1e-3);
+
+        // By hand: the source is small and centred, so each
+        // pixel spans (π / 101)² sr and Ev = Ls ωs
+        let side = PI / 101.;
+        let omega = 25. * side * side;
+        assert_close!(source.solid_angle, omega, 1e-3 * omega);
+        let ev = ls * omega;
+        assert_close!(result.vertical_illuminance, ev, 1e-2 * ev);
+        let dgp = 5.87e-5 * ev + 9.18e-2 * (1. + ls * ls * omega / ev.powf(1.87)).log10() + 0.16;
+        assert_close!(result.dgp, dgp, 1e-3);
+        assert_close!(result.dgp, 0.491, 1e-3);
+
+        // The correction for low light only matters below 1000 lux
+        let corrected = evaluate_image(&camera, &image, &GlareOptions::default()).unwrap();
+        assert_close!(corrected.dgp, result.dgp, 1e-3);
+        let dim = squares(&camera, 5, &[(48, 48, ls / 100.)]);
+        let uncorrected = evaluate_image(&camera, &dim, &options).unwrap();
+        let corrected = evaluate_image(&camera, &dim, &GlareOptions::default()).unwrap();
+        assert!(corrected.dgp < 0.5 * uncorrected.dgp);
+    }
+
+    #[test]
+    fn test_separate_sources() {
+        let camera = camera(51);
+        // Two squares, and another one that only touches the
+        // second one diagonally
+        let image = squares(&camera, 3, &[(18, 20, 5e4), (30, 20, 5e4), (33, 23, 5e4)]);
+        let result = evaluate_image(&camera, &image, &GlareOptions::default()).unwrap();
+        assert_eq!(result.sources.len(), 3);
+        // The sources to the left and to the right are equally glaring
+        let (left, right) = (&result.sources[0], &result.sources[1]);
+        assert!(left.direction.x < 0.0 && right.direction.x > 0.0);
+        assert_close!(
+            left.position_index,
+            right.position_index,
+            1e-2 * left.position_index
+        );
+        assert!(left.position_index > 1.);
+
+        assert!(evaluate_image(&camera, &image[1..], &GlareOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_glare() {
+        // Bin 0 lights up everything, and bin 1 only a square
+        let camera = camera(31);
+        let mut view_dc = Matrix::new(0.0, camera.n_pixels(), 2);
+        for p in 0..camera.n_pixels() {
+            view_dc.set(p, 0, 1.).unwrap();
+        }
+        for r in 14..17 {
+            for c in 14..17 {
+                view_dc.set(r * 31 + c, 1, 1.).unwrap();
+            }
+        }
+        let mut skies = Matrix::new(0.0, 2, 2);
+        skies.set(0, 0, 100.).unwrap();
+        skies.set(0, 1, 100.).unwrap();
+        skies.set(1, 1, 2e5).unwrap();
+
+        let options = GlareOptions::default();
+        let results = evaluate_glare(&camera, &view_dc, &skies, &options).unwrap();
+        assert_eq!(results.len(), 2);
+        // A uniform view has no glare sources
+        assert!(results[0].sources.is_empty());
+        assert_close!(results[0].vertical_illuminance, 100. * PI, 0.03 * 100. * PI);
+
+        let mut image = vec![100.; camera.n_pixels()];
+        for r in 14..17 {
+            for c in 14..17 {
+                image[r * 31 + c] += 2e5;
+            }
+        }
+        let expected = evaluate_image(&camera, &image, &options).unwrap();
+        assert_eq!(results[1].sources.len(), 1);
+        assert_close!(results[1].dgp, expected.dgp, 1e-6);
+
+        assert!(evaluate_glare(&camera, &view_dc, &Matrix::new(0.0, 3, 2), &options).is_err());
+        assert!(evaluate_glare(&camera, &Matrix::new(0.0, 10, 2), &skies, &options).is_err());
+    }
+}
@@ -0,0 +1,876 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Grids of sensors over horizontal polygons (e.g., the floor of a room), and
+//! meshes for plotting their results (e.g., as falsecolor images).
+//!
+//! Sensors are placed at the centre of each cell of a square grid aligned with
+//! the axes, as long as that centre is within the polygon. The mesh, on the other
+//! hand, covers the whole polygon: it is cut along the lines of the grid, so each
+//! triangle is within a cell, and each vertex gets a value interpolated between
+//! the sensors around it.
+//!
+//! Grids built from a surface of a scene (e.g., a roof) exclude that surface
+//! from the rays of their sensors, so sensors lying on it see no light coming
+//! from it—or from under it.
+//!
+//! [`auto_grids`] finds the floors of a scene by themselves (i.e., the surfaces
+//! that face up and have room above them), and places one grid over each.
+
+use crate::scene_loading::{ObjectId, SceneReport};
+use crate::sensor::{SensorSpec, TributaryArea};
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use rendering::{Ray, Scene};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// How far apart (in metres) two points can be and still be the same one
+const TOLERANCE: Float = 1e-6;
+
+/// A point of the plane of a grid
+type Point2D = (Float, Float);
+
+/// Checks whether a point is inside a set of rings, following the even-odd
+/// rule (so holes are just rings within other rings)
+fn inside(p: Point2D, rings: &[Vec<Point2D>]) -> bool {
+    let mut ret = false;
+    for ring in rings {
+        for (k, a) in ring.iter().enumerate() {
+            let b = ring[(k + 1) % ring.len()];
+            if (a.1 > p.1) != (b.1 > p.1) {
+                let x = a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0);
+                if p.0 < x {
+                    ret = !ret;
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// The area of a ring, whatever its orientation
+fn ring_area(ring: &[Point2D]) -> Float {
+    let mut twice = 0.0;
+    for (k, a) in ring.iter().enumerate() {
+        let b = ring[(k + 1) % ring.len()];
+        twice += a.0 * b.1 - b.0 * a.1;
+    }
+    0.5 * twice.abs()
+}
+
+/// Clips a convex polygon, keeping the part where `sign * (x - x0) >= 0`
+fn clip_x(polygon: &[Point2D], x0: Float, sign: Float) -> Vec<Point2D> {
+    let mut ret = Vec::with_capacity(polygon.len() + 1);
+    for (k, a) in polygon.iter().enumerate() {
+        let b = polygon[(k + 1) % polygon.len()];
+        let (da, db) = (sign * (a.0 - x0), sign * (b.0 - x0));
+        if da >= 0.0 {
+            ret.push(*a);
+        }
+        if (da > 0.0 && db < 0.0) || (da < 0.0 && db > 0.0) {
+            let t = da / (da - db);
+            ret.push((x0, a.1 + t * (b.1 - a.1)));
+        }
+    }
+    ret
+}
+
+/// A square grid of sensors facing up from a horizontal polygon
+#[derive(Debug, Clone)]
+pub struct SensorGrid {
+    /// The outer boundary and the holes, projected onto the plane
+    rings: Vec<Vec<Point2D>>,
+
+    /// The corner of the bounding box of the boundary where the grid starts
+    origin: Point2D,
+
+    /// The side of each cell
+    spacing: Float,
+
+    /// The number of cells along X and Y
+    size: (usize, usize),
+
+    /// The height of the sensors (not of the polygon)
+    z: Float,
+
+    /// The sensor at the centre of each cell, if any, row by row
+    cells: Vec<Option<usize>>,
+
+    /// The cell of each sensor
+    sensors: Vec<(usize, usize)>,
+
+    /// The surface of the scene that the grid covers, if any
+    host: Option<ObjectId>,
+
+    /// The zone that the sensors belong to, if any
+    zone: Option<String>,
+}
+
+impl SensorGrid {
+    /// Places sensors every `spacing` metres over a horizontal polygon with a
+    /// `boundary` and some `holes`, at a `height` above it. Polygons do not need
+    /// to be convex, but they cannot intersect themselves.
+    pub fn new(
+        boundary: &[Point3D],
+        holes: &[Vec<Point3D>],
+        spacing: Float,
+        height: Float,
+    ) -> Result<Self, String> {
+        if !(spacing.is_finite() && spacing > 0.0) {
+            return Err(format!(
+                "The spacing of a sensor grid must be a positive number, but found {}",
+                spacing
+            ));
+        }
+        if !height.is_finite() {
+            return Err(format!(
+                "The height of a sensor grid must be finite, but found {}",
+                height
+            ));
+        }
+        if boundary.len() < 3 {
+            return Err(format!(
+                "The boundary of a sensor grid needs at least 3 vertices, but found {}",
+                boundary.len()
+            ));
+        }
+        let elevation = boundary[0].z;
+        let mut rings = Vec::with_capacity(1 + holes.len());
+        for (i, ring) in std::iter::once(boundary)
+            .chain(holes.iter().map(|h| &h[..]))
+            .enumerate()
+        {
+            if ring.len() < 3 {
+                return Err(format!(
+                    "Hole {} of a sensor grid needs at least 3 vertices, but found {}",
+                    i - 1,
+                    ring.len()
+                ));
+            }
+            if let Some(p) = ring.iter().find(|p| (p.z - elevation).abs() > TOLERANCE) {
+                return Err(format!(
+                    "Sensor grids need horizontal polygons, but found a vertex at z = {} when the first one is at z = {}",
+                    p.z, elevation
+                ));
+            }
+            rings.push(ring.iter().map(|p| (p.x, p.y)).collect::<Vec<Point2D>>());
+        }
+        let (mut min, mut max) = ((Float::MAX, Float::MAX), (Float::MIN, Float::MIN));
+        for p in &rings[0] {
+            min = (min.0.min(p.0), min.1.min(p.1));
+            max = (max.0.max(p.0), max.1.max(p.1));
+        }
+        let cells_along = |length: Float| ((length / spacing - TOLERANCE).ceil() as usize).max(1);
+        let size = (cells_along(max.0 - min.0), cells_along(max.1 - min.1));
+
+        let mut ret = Self {
+            rings,
+            origin: min,
+            spacing,
+            size,
+            z: elevation + height,
+            cells: vec![None; size.0 * size.1],
+            sensors: Vec::new(),
+            host: None,
+            zone: None,
+        };
+        for j in 0..size.1 {
+            for i in 0..size.0 {
+                if inside(ret.centre(i, j), &ret.rings) {
+                    ret.cells[j * size.0 + i] = Some(ret.sensors.len());
+                    ret.sensors.push((i, j));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Places sensors every `spacing` metres over the horizontal polygon called
+    /// `name` in a scene, at a `height` above it. Their rays ignore the polygon
+    /// itself (see [`SensorSpec::with_host_surface`]).
+    pub fn from_surface(
+        report: &SceneReport,
+        name: &str,
+        spacing: Float,
+        height: Float,
+    ) -> Result<Self, String> {
+        let host = report.object_id(name)?;
+        let surface = report
+            .surfaces
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| format!("There is no surface called '{}' in the scene", name))?;
+        let mut ret = Self::new(&surface.vertices, &[], spacing, height)?;
+        ret.host = Some(host);
+        Ok(ret)
+    }
+
+    /// Tags the sensors of the grid as part of a zone (see [`SensorSpec::zone`])
+    pub fn with_zone<S: Into<String>>(mut self, zone: S) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// The zone that the sensors belong to, if any
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    /// The outer boundary of the polygon, projected onto the plane
+    pub(crate) fn boundary(&self) -> &[(Float, Float)] {
+        &self.rings[0]
+    }
+
+    /// Whether a point of the plane is within the polygon (and not in a hole)
+    pub(crate) fn contains(&self, x: Float, y: Float) -> bool {
+        inside((x, y), &self.rings)
+    }
+
+    /// The elevation of the sensors
+    pub(crate) fn elevation(&self) -> Float {
+        self.z
+    }
+
+    /// The position of each sensor on the plane, in the order of [`SensorGrid::sensors`]
+    pub(crate) fn positions(&self) -> Vec<(Float, Float)> {
+        self.sensors
+            .iter()
+            .map(|(i, j)| self.centre(*i, *j))
+            .collect()
+    }
+
+    /// The centre of a cell
+    fn centre(&self, i: usize, j: usize) -> Point2D {
+        (
+            self.origin.0 + (i as Float + 0.5) * self.spacing,
+            self.origin.1 + (j as Float + 0.5) * self.spacing,
+        )
+    }
+
+    /// The sensor at the centre of a cell, if the cell exists and has one
+    fn sensor_at(&self, i: isize, j: isize) -> Option<usize> {
+        if i < 0 || j < 0 || i as usize >= self.size.0 || j as usize >= self.size.1 {
+            return None;
+        }
+        self.cells[j as usize * self.size.0 + i as usize]
+    }
+
+    /// The number of sensors
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    /// Whether the polygon is too small for any sensor
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    /// The area of the polygon (i.e., of its boundary minus its holes)
+    pub fn area(&self) -> Float {
+        ring_area(&self.rings[0]) - self.rings[1..].iter().map(|r| ring_area(r)).sum::<Float>()
+    }
+
+    /// The sensors, row by row, each of them standing for the area of its cell.
+    /// Their ids are hashed from where they were placed, so they stay the same
+    /// when the sensors are filtered, reordered or transformed.
+    pub fn sensors(&self) -> Vec<SensorSpec> {
+        self.sensors
+            .iter()
+            .map(|(i, j)| {
+                let (x, y) = self.centre(*i, *j);
+                let mut sensor = SensorSpec::from(Ray3D {
+                    origin: Point3D::new(x, y, self.z),
+                    direction: Vector3D::new(0., 0., 1.),
+                });
+                sensor.area = Some(TributaryArea {
+                    u: Vector3D::new(self.spacing, 0., 0.),
+                    v: Vector3D::new(0., self.spacing, 0.),
+                });
+                sensor.exclude_host_surface = self.host.clone();
+                sensor.zone = self.zone.clone();
+                sensor.id = Some(sensor.sensor_id());
+                sensor
+            })
+            .collect()
+    }
+
+    /// The value at a point, interpolated bilinearly between the four sensors
+    /// around it. Close to the boundary, where some of them are missing, the
+    /// value of the nearest sensor is used instead.
+    fn interpolate(&self, p: Point2D, values: &[Float]) -> Float {
+        let u = (p.0 - self.origin.0) / self.spacing - 0.5;
+        let v = (p.1 - self.origin.1) / self.spacing - 0.5;
+        let (i, j) = (u.floor() as isize, v.floor() as isize);
+        let (fu, fv) = (u - i as Float, v - j as Float);
+        let corners = [
+            (
+                self.sensor_at(i, j),
+                (1. - fu) * (1. - fv),
+                fu * fu + fv * fv,
+            ),
+            (
+                self.sensor_at(i + 1, j),
+                fu * (1. - fv),
+                (1. - fu).powi(2) + fv * fv,
+            ),
+            (
+                self.sensor_at(i, j + 1),
+                (1. - fu) * fv,
+                fu * fu + (1. - fv).powi(2),
+            ),
+            (
+                self.sensor_at(i + 1, j + 1),
+                fu * fv,
+                (1. - fu).powi(2) + (1. - fv).powi(2),
+            ),
+        ];
+        if corners.iter().all(|(s, ..)| s.is_some()) {
+            return corners
+                .iter()
+                .filter_map(|(s, w, _)| s.map(|s| w * values[s]))
+                .sum();
+        }
+        let nearest = corners
+            .iter()
+            .filter_map(|(s, _, d)| s.map(|s| (s, *d)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(s, _)| s);
+        let nearest = nearest.unwrap_or_else(|| {
+            // Far from every sensor (e.g., in a narrow corner of the polygon)
+            let distance = |s: usize| {
+                let (x, y) = self.centre(self.sensors[s].0, self.sensors[s].1);
+                (x - p.0).powi(2) + (y - p.1).powi(2)
+            };
+            (0..self.sensors.len())
+                .min_by(|a, b| distance(*a).total_cmp(&distance(*b)))
+                .unwrap_or(0)
+        });
+        values[nearest]
+    }
+
+    /// Triangulates the polygon, cutting it along the lines of the grid, and
+    /// gives each vertex a value interpolated from the `values` of the sensors
+    /// (one per sensor, in the order of [`SensorGrid::sensors`]).
+    pub fn to_mesh(&self, values: &[Float]) -> Result<ValueMesh, String> {
+        if values.len() != self.len() {
+            return Err(format!(
+                "A grid of {} sensors cannot be plotted with {} values",
+                self.len(),
+                values.len()
+            ));
+        }
+        if self.is_empty() {
+            return Err("Cannot plot a grid without sensors".to_string());
+        }
+        let edges: Vec<(Point2D, Point2D)> = self
+            .rings
+            .iter()
+            .flat_map(|r| (0..r.len()).map(move |k| (r[k], r[(k + 1) % r.len()])))
+            .filter(|(a, b)| (a.1 - b.1).abs() > TOLERANCE)
+            .collect();
+        // Strips between vertices and grid lines have no vertex within them, so
+        // the polygon is a series of trapezoids across each of them
+        let mut ys: Vec<Float> = self.rings.iter().flatten().map(|p| p.1).collect();
+        ys.extend((0..=self.size.1).map(|j| self.origin.1 + j as Float * self.spacing));
+        ys.sort_by(|a, b| a.total_cmp(b));
+        ys.dedup_by(|a, b| (*a - *b).abs() < TOLERANCE);
+
+        let mut mesh = MeshBuilder::default();
+        for w in ys.windows(2) {
+            let (y0, y1) = (w[0], w[1]);
+            let mid = 0.5 * (y0 + y1);
+            let x_at =
+                |(a, b): &(Point2D, Point2D), y: Float| a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0);
+            let mut crossings: Vec<&(Point2D, Point2D)> = edges
+                .iter()
+                .filter(|(a, b)| a.1.min(b.1) < mid && a.1.max(b.1) > mid)
+                .collect();
+            crossings.sort_by(|a, b| x_at(a, mid).total_cmp(&x_at(b, mid)));
+            for pair in crossings.chunks_exact(2) {
+                let trapezoid = [
+                    (x_at(pair[0], y0), y0),
+                    (x_at(pair[1], y0), y0),
+                    (x_at(pair[1], y1), y1),
+                    (x_at(pair[0], y1), y1),
+                ];
+                let min_x = trapezoid.iter().map(|p| p.0).fold(Float::MAX, Float::min);
+                let max_x = trapezoid.iter().map(|p| p.0).fold(Float::MIN, Float::max);
+                let first = ((min_x - self.origin.0) / self.spacing).floor().max(0.) as usize;
+                for i in first..self.size.0 {
+                    let x0 = self.origin.0 + i as Float * self.spacing;
+                    if x0 >= max_x {
+                        break;
+                    }
+                    let piece = clip_x(&trapezoid, x0, 1.);
+                    let piece = clip_x(&piece, x0 + self.spacing, -1.);
+                    mesh.add_polygon(&piece, |p| self.interpolate(p, values));
+                }
+            }
+        }
+        Ok(ValueMesh {
+            vertices: mesh.vertices.iter().map(|p| [p.0, p.1, self.z]).collect(),
+            triangles: mesh.triangles,
+            values: mesh.values,
+        })
+    }
+}
+
+/// Which surfaces of a scene [`auto_grids`] takes as floors
+#[derive(Debug, Clone, PartialEq)]
+pub struct FloorSelection {
+    /// How far (in degrees) the normal of a floor can be from `+Z`. Floors that
+    /// are not exactly horizontal are flattened at their average height.
+    pub max_tilt: Float,
+
+    /// The modifiers (i.e., materials) that floors are made of. Surfaces made
+    /// of any material are taken if empty.
+    pub modifiers: Vec<String>,
+
+    /// How much free space (in metres) there must be above a floor, which
+    /// leaves out the tops of furniture and of shallow voids
+    pub min_headroom: Float,
+}
+
+impl std::default::Default for FloorSelection {
+    fn default() -> Self {
+        Self {
+            max_tilt: 1.,
+            modifiers: Vec::new(),
+            min_headroom: 2.,
+        }
+    }
+}
+
+/// Places a [`SensorGrid`] every `spacing` metres at an `offset` above each
+/// floor of a scene, as chosen by the `selection`. The `report` must be the one
+/// returned when loading the `scene`. Each grid is a zone named after its floor,
+/// in the order in which they were loaded.
+///
+/// Floors are the polygons facing up (so the ceilings under them, which face
+/// down, are not floors) with at least `min_headroom` of free space above them,
+/// which is checked by a ray going up from one of their sensors. Floors too
+/// small for any sensor are left out.
+pub fn auto_grids(
+    scene: &Scene,
+    report: &SceneReport,
+    selection: &FloorSelection,
+    spacing: Float,
+    offset: Float,
+) -> Result<Vec<SensorGrid>, String> {
+    if !(0.0..90.).contains(&selection.max_tilt) {
+        return Err(format!(
+            "The tilt of floors must be between 0 and 90 degrees, but found {}",
+            selection.max_tilt
+        ));
+    }
+    if !(selection.min_headroom.is_finite() && selection.min_headroom >= 0.0) {
+        return Err(format!(
+            "The headroom above floors must be a non-negative number, but found {}",
+            selection.min_headroom
+        ));
+    }
+    if let Some(m) = selection
+        .modifiers
+        .iter()
+        .find(|m| !report.materials.iter().any(|material| material.name == **m))
+    {
+        return Err(format!("There is no material called '{}' in the scene", m));
+    }
+    let min_cos = selection.max_tilt.to_radians().cos();
+    let mut aux = Vec::with_capacity(2);
+    let mut ret = Vec::new();
+    for surface in report.surfaces.iter() {
+        let normal = match surface.normal {
+            Some(n) if n.length() > 0.0 => n.get_normalized(),
+            _ => continue,
+        };
+        if normal.z < min_cos
+            || !(selection.modifiers.is_empty() || selection.modifiers.contains(&surface.modifier))
+        {
+            continue;
+        }
+        let n = surface.vertices.len() as Float;
+        let z = surface.vertices.iter().map(|p| p.z).sum::<Float>() / n;
+        let boundary: Vec<Point3D> = surface
+            .vertices
+            .iter()
+            .map(|p| Point3D::new(p.x, p.y, z))
+            .collect();
+        let grid = SensorGrid::new(&boundary, &[], spacing, offset)?.with_zone(&surface.name);
+        let (x, y) = match grid.sensors.first() {
+            Some((i, j)) => grid.centre(*i, *j),
+            None => continue,
+        };
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: Point3D::new(x, y, z + TOLERANCE.sqrt()),
+                direction: Vector3D::new(0., 0., 1.),
+            },
+            ..Ray::default()
+        };
+        if scene.cast_ray(&mut ray, &mut aux).is_some()
+            && ray.interaction.point.z - z < selection.min_headroom
+        {
+            continue;
+        }
+        ret.push(grid);
+    }
+    Ok(ret)
+}
+
+/// Collects the vertices and triangles of a [`ValueMesh`], sharing the
+/// vertices that are in the same place
+#[derive(Default)]
+struct MeshBuilder {
+    vertices: Vec<Point2D>,
+    values: Vec<Float>,
+    triangles: Vec<[usize; 3]>,
+    index: HashMap<(i64, i64), usize>,
+}
+
+impl MeshBuilder {
+    fn vertex<F: Fn(Point2D) -> Float>(&mut self, p: Point2D, value: &F) -> usize {
+        let key = (
+            (p.0 / TOLERANCE).round() as i64,
+            (p.1 / TOLERANCE).round() as i64,
+        );
+        if let Some(i) = self.index.get(&key) {
+            return *i;
+        }
+        self.vertices.push(p);
+        self.values.push(value(p));
+        self.index.insert(key, self.vertices.len() - 1);
+        self.vertices.len() - 1
+    }
+
+    /// Adds a convex polygon as a fan of triangles, skipping repeated vertices
+    fn add_polygon<F: Fn(Point2D) -> Float>(&mut self, polygon: &[Point2D], value: F) {
+        let mut indices: Vec<usize> = polygon.iter().map(|p| self.vertex(*p, &value)).collect();
+        indices.dedup();
+        while indices.len() > 1 && indices.first() == indices.last() {
+            indices.pop();
+        }
+        for k in 1..indices.len().saturating_sub(1) {
+            let (a, b, c) = (indices[0], indices[k], indices[k + 1]);
+            let (pa, pb, pc) = (self.vertices[a], self.vertices[b], self.vertices[c]);
+            let twice_area = (pb.0 - pa.0) * (pc.1 - pa.1) - (pc.0 - pa.0) * (pb.1 - pa.1);
+            if twice_area.abs() > TOLERANCE * TOLERANCE {
+                self.triangles.push([a, b, c]);
+            }
+        }
+    }
+}
+
+/// A triangulated mesh with a value at each vertex, made by [`SensorGrid::to_mesh`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueMesh {
+    /// The position of each vertex
+    pub vertices: Vec<[Float; 3]>,
+
+    /// The vertices of each triangle, counter-clockwise when seen from above
+    pub triangles: Vec<[usize; 3]>,
+
+    /// The value at each vertex
+    pub values: Vec<Float>,
+}
+
+impl ValueMesh {
+    /// The total area of the triangles
+    pub fn area(&self) -> Float {
+        self.triangles
+            .iter()
+            .map(|t| {
+                let [a, b, c] = t.map(|i| self.vertices[i]);
+                0.5 * ((b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1])).abs()
+            })
+            .sum()
+    }
+
+    /// Writes the geometry in Wavefront OBJ format. The values go in a sidecar
+    /// file (see [`ValueMesh::values_csv`]), as OBJ has no place for them.
+    pub fn to_obj(&self) -> String {
+        let mut ret = format!(
+            "# {} vertices and {} triangles; values are in a separate file\n",
+            self.vertices.len(),
+            self.triangles.len()
+        );
+        for v in &self.vertices {
+            ret.push_str(&format!("v {} {} {}\n", v[0], v[1], v[2]));
+        }
+        for t in &self.triangles {
+            // OBJ indices start at 1
+            ret.push_str(&format!("f {} {} {}\n", t[0] + 1, t[1] + 1, t[2] + 1));
+        }
+        ret
+    }
+
+    /// Writes the value of each vertex, in the order of the OBJ file
+    pub fn values_csv(&self) -> String {
+        let mut ret = "value\n".to_string();
+        for v in &self.values {
+            ret.push_str(&format!("{}\n", v));
+        }
+        ret
+    }
+
+    /// Writes the mesh (geometry and values) as JSON
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| format!("Could not serialise mesh: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, DCSession};
+    use validate::assert_close;
+
+    fn ring(points: &[(Float, Float)]) -> Vec<Point3D> {
+        points
+            .iter()
+            .map(|(x, y)| Point3D::new(*x, *y, 0.))
+            .collect()
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let boundary = ring(&[(0., 0.), (4., 0.), (4., 3.), (0., 3.)]);
+        let grid = SensorGrid::new(&boundary, &[], 1., 0.8).unwrap();
+        let sensors = grid.sensors();
+        assert_eq!(sensors.len(), 12);
+        assert_eq!(sensors[0].ray.origin, Point3D::new(0.5, 0.5, 0.8));
+        assert_eq!(sensors[5].ray.origin, Point3D::new(1.5, 1.5, 0.8));
+
+        // A linear field is reproduced exactly between sensors...
+        let f = |x: Float, y: Float| x + 2. * y;
+        let values: Vec<Float> = sensors
+            .iter()
+            .map(|s| f(s.ray.origin.x, s.ray.origin.y))
+            .collect();
+        let mesh = grid.to_mesh(&values).unwrap();
+        assert_eq!(mesh.vertices.len(), mesh.values.len());
+        for (v, value) in mesh.vertices.iter().zip(mesh.values.iter()) {
+            assert_eq!(v[2], 0.8);
+            if v[0] > 0.4 && v[0] < 3.6 && v[1] > 0.4 && v[1] < 2.6 {
+                assert_close!(*value, f(v[0], v[1]), 1e-4);
+            }
+        }
+        // ... at the corners of the cells, for instance
+        let at = |x: Float, y: Float| {
+            let k = mesh
+                .vertices
+                .iter()
+                .position(|v| (v[0] - x).abs() < 1e-6 && (v[1] - y).abs() < 1e-6)
+                .unwrap();
+            mesh.values[k]
+        };
+        assert_close!(at(2., 1.), f(2., 1.), 1e-4);
+        // ... and the boundary takes the nearest sensor
+        assert_close!(at(0., 0.), f(0.5, 0.5), 1e-4);
+        assert_close!(at(4., 1.), f(3.5, 0.5), 1e-4);
+
+        assert!(grid.to_mesh(&values[1..]).is_err());
+        assert_close!(mesh.area(), 12., 1e-4);
+        let obj = mesh.to_obj();
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("v ")).count(),
+            mesh.vertices.len()
+        );
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("f ")).count(),
+            mesh.triangles.len()
+        );
+        assert_eq!(mesh.values_csv().lines().count(), mesh.values.len() + 1);
+    }
+
+    #[test]
+    fn test_mesh_area() {
+        // An L-shaped room with a column, not aligned with the grid
+        let boundary = ring(&[
+            (0., 0.),
+            (5.3, 0.),
+            (5.3, 2.1),
+            (2.2, 2.1),
+            (2.2, 4.7),
+            (0., 4.7),
+        ]);
+        let column = ring(&[(0.7, 0.6), (1.3, 0.9), (1.0, 1.5)]);
+        let grid = SensorGrid::new(&boundary, &[column], 0.5, 0.8).unwrap();
+        // The column is its bounding box minus three right triangles
+        let column_area = 0.6 * 0.9 - 0.5 * 0.6 * 0.3 - 0.5 * 0.3 * 0.6 - 0.5 * 0.3 * 0.9;
+        let expected = 5.3 * 2.1 + 2.2 * (4.7 - 2.1) - column_area;
+        assert_close!(grid.area(), expected, 1e-4);
+        let values: Vec<Float> = (0..grid.len()).map(|i| i as Float).collect();
+        let mesh = grid.to_mesh(&values).unwrap();
+        assert!((mesh.area() - expected).abs() < 1e-4 * expected);
+        // No sensor is in the column or outside the room
+        for s in grid.sensors() {
+            let (x, y) = (s.ray.origin.x, s.ray.origin.y);
+            assert!(x < 5.3 && y < 4.7 && !(x > 2.2 && y > 2.1));
+        }
+        let min = values[0];
+        let max = values[values.len() - 1];
+        assert!(mesh.values.iter().all(|v| *v >= min && *v <= max));
+
+        assert!(SensorGrid::new(&boundary, &[], 0., 0.8).is_err());
+        assert!(SensorGrid::new(&boundary[..2], &[], 1., 0.8).is_err());
+        let mut tilted = boundary.clone();
+        tilted[1].z = 1.;
+        assert!(SensorGrid::new(&tilted, &[], 1., 0.8).is_err());
+    }
+
+    #[test]
+    fn test_from_surface() {
+        let (mut scene, report) = crate::load_scene("./tests/sensitivity/roof.rad").unwrap();
+        scene.build_accelerator();
+        let grid = SensorGrid::from_surface(&report, "roof", 1., 0.).unwrap();
+        assert_eq!(grid.len(), 16);
+        assert_close!(grid.area(), 16., 1e-4);
+        let sensors = grid.sensors();
+        let host = report.object_id("roof").unwrap();
+        assert_eq!(host.triangles.len(), 2);
+        for s in &sensors {
+            assert_eq!(s.ray.origin.z, 3.);
+            assert_eq!(s.exclude_host_surface.as_ref(), Some(&host));
+        }
+        assert!(SensorGrid::from_surface(&report, "attic", 1., 0.).is_err());
+        assert!(SensorGrid::from_surface(&report, "wall", 1., 0.).is_err());
+
+        // Rays going up never reach the roof, so the direct tracer finds the
+        // same coefficients with or without it
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 2000,
+                ..DCOptions::default()
+            },
+        );
+        let with_host = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+        let bare: Vec<SensorSpec> = sensors.iter().map(|s| SensorSpec::from(s.ray)).collect();
+        let without_host = session.calc_sensor_dc(&bare, &scene).unwrap().matrix;
+        let (nrows, ncols) = with_host.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(
+                    with_host.get(r, c).unwrap(),
+                    without_host.get(r, c).unwrap()
+                );
+            }
+        }
+
+        // Other tracers cannot skip the roof
+        let session = DCSession::new(1, DCOptions::default());
+        assert!(session.calc_sensor_dc(&sensors, &scene).is_err());
+    }
+
+    #[test]
+    fn test_auto_grids() {
+        // Two storeys, each with the ceiling of the one below under its
+        // floor, a roof, and a desk on the ground floor
+        let mut builder = crate::SceneBuilder::new();
+        for m in ["floor_mat", "roof_mat"] {
+            builder
+                .add_material(m, crate::Material::plastic(0.3))
+                .unwrap();
+        }
+        let rectangle = |(x0, y0): (Float, Float), (x1, y1): (Float, Float), z: Float| {
+            [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|(x, y)| Point3D::new(x, y, z))
+        };
+        let facing_down = |mut r: [Point3D; 4]| {
+            r.reverse();
+            r
+        };
+        let surfaces = [
+            (
+                "floor_mat",
+                "ground_floor",
+                rectangle((0., 0.), (6., 4.), 0.),
+            ),
+            ("floor_mat", "desk", rectangle((2., 1.), (3., 2.), 1.)),
+            (
+                "floor_mat",
+                "ground_ceiling",
+                facing_down(rectangle((0., 0.), (6., 4.), 2.8)),
+            ),
+            (
+                "floor_mat",
+                "first_floor",
+                rectangle((0., 0.), (5., 4.), 3.),
+            ),
+            (
+                "floor_mat",
+                "first_ceiling",
+                facing_down(rectangle((0., 0.), (5., 4.), 5.8)),
+            ),
+            ("roof_mat", "roof", rectangle((0., 0.), (5., 4.), 6.)),
+        ];
+        for (m, name, vertices) in surfaces.iter() {
+            builder.add_polygon(m, name, vertices).unwrap();
+        }
+        let (mut scene, report) = builder.build().unwrap();
+        scene.build_accelerator();
+
+        let selection = FloorSelection {
+            modifiers: vec!["floor_mat".to_string()],
+            ..FloorSelection::default()
+        };
+        let grids = auto_grids(&scene, &report, &selection, 0.5, 0.8).unwrap();
+        assert_eq!(grids.len(), 2);
+        let (ground, first) = (&grids[0], &grids[1]);
+        assert_eq!(ground.zone(), Some("ground_floor"));
+        assert_eq!(ground.len(), 96);
+        assert_close!(ground.area(), 24., 1e-4);
+        assert_eq!(first.zone(), Some("first_floor"));
+        assert_eq!(first.len(), 80);
+        assert_close!(first.area(), 20., 1e-4);
+        for (grid, z) in [(ground, 0.8), (first, 3.8)] {
+            for s in grid.sensors() {
+                assert_close!(s.ray.origin.z, z, 1e-5);
+                assert_eq!(s.zone.as_deref(), grid.zone());
+                assert!(s.exclude_host_surface.is_none());
+            }
+        }
+
+        // Any material takes the roof too, and less headroom the desk
+        let grids = auto_grids(&scene, &report, &FloorSelection::default(), 0.5, 0.8).unwrap();
+        let zones: Vec<&str> = grids.iter().filter_map(|g| g.zone()).collect();
+        assert_eq!(zones, vec!["ground_floor", "first_floor", "roof"]);
+        let low = FloorSelection {
+            min_headroom: 1.5,
+            ..selection.clone()
+        };
+        let grids = auto_grids(&scene, &report, &low, 0.5, 0.8).unwrap();
+        let zones: Vec<&str> = grids.iter().filter_map(|g| g.zone()).collect();
+        assert_eq!(zones, vec!["ground_floor", "desk", "first_floor"]);
+
+        let unknown = FloorSelection {
+            modifiers: vec!["carpet".to_string()],
+            ..FloorSelection::default()
+        };
+        assert!(auto_grids(&scene, &report, &unknown, 0.5, 0.8).is_err());
+        let steep = FloorSelection {
+            max_tilt: 90.,
+            ..FloorSelection::default()
+        };
+        assert!(auto_grids(&scene, &report, &steep, 0.5, 0.8).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Distant obstructions (e.g., terrain or skylines that are not modelled as
+//! geometry), described by the altitude of the horizon at each azimuth.
+//!
+//! A [`HorizonProfile`] can be applied in either of two ways, which agree on
+//! average:
+//! * At trace time, through [`DCSession::with_horizon`](crate::DCSession::with_horizon):
+//!   rays that escape the scene below the profile hit the terrain instead of the sky.
+//! * At sky-vector time, through [`HorizonProfile::attenuate_sky`]: the radiance of
+//!   each patch is attenuated by the fraction of it that lies below the profile,
+//!   so Daylight Coefficients calculated without the profile can be reused.
+//!
+//! The terrain is a Lambertian surface lit by the whole sky, so its radiance
+//! is `ρ E / π`, where `E` is the unobstructed horizontal irradiance of the sky.
+//! That is a linear combination of the patches of the sky, so the light of the
+//! terrain is added to the patches instead of having a column of its own.
+
+use crate::obstruction::SkylineProfile;
+use crate::session::MAX_MF;
+use crate::sky::SkyBasis;
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use solar::ReinhartSky;
+
+/// The altitude of distant obstructions as a function of the azimuth,
+/// together with the reflectance of whatever is obstructing the sky. Angles
+/// are in degrees, and azimuths are measured from North (`+Y`) towards East (`+X`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HorizonProfile {
+    skyline: SkylineProfile,
+    reflectance: Float,
+}
+
+impl HorizonProfile {
+    /// Creates a profile from a list of `(azimuth, altitude)` pairs, which is
+    /// interpolated linearly (and wraps around North). Azimuths can be given in
+    /// any order, but not repeated.
+    pub fn new(points: &[(Float, Float)], reflectance: Float) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("A horizon profile needs at least one point".to_string());
+        }
+        if !(0.0..=1.0).contains(&reflectance) {
+            return Err(format!(
+                "The reflectance of the terrain must be between 0 and 1, but found {}",
+                reflectance
+            ));
+        }
+        let mut points: Vec<(Float, Float)> = points.to_vec();
+        for (azimuth, altitude) in points.iter_mut() {
+            if !azimuth.is_finite() || !(0.0..=90.).contains(altitude) {
+                return Err(format!(
+                    "Invalid point of a horizon profile: azimuth {} and altitude {} (which must be between 0 and 90)",
+                    azimuth, altitude
+                ));
+            }
+            *azimuth = azimuth.rem_euclid(360.);
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some(w) = points.windows(2).find(|w| w[0].0 == w[1].0) {
+            return Err(format!(
+                "Azimuth {} appears more than once in a horizon profile",
+                w[0].0
+            ));
+        }
+        Ok(Self {
+            skyline: SkylineProfile {
+                azimuths: points.iter().map(|p| p.0).collect(),
+                altitudes: points.iter().map(|p| p.1).collect(),
+            },
+            reflectance,
+        })
+    }
+
+    /// A horizon at the same `altitude` in every direction
+    pub fn constant(altitude: Float, reflectance: Float) -> Result<Self, String> {
+        Self::new(&[(0., altitude)], reflectance)
+    }
+
+    /// The profile of the obstructions around a point, as seen from the scene
+    /// (see [`max_obstruction_altitude`](crate::max_obstruction_altitude))
+    pub fn from_skyline(skyline: &SkylineProfile, reflectance: Float) -> Result<Self, String> {
+        let points: Vec<(Float, Float)> = skyline
+            .azimuths
+            .iter()
+            .copied()
+            .zip(skyline.altitudes.iter().copied())
+            .collect();
+        Self::new(&points, reflectance)
+    }
+
+    /// The reflectance of the terrain
+    pub fn reflectance(&self) -> Float {
+        self.reflectance
+    }
+
+    /// The altitude of the horizon at any azimuth
+    pub fn altitude_at(&self, azimuth: Float) -> Float {
+        self.skyline.altitude_at(azimuth)
+    }
+
+    /// Whether a direction that leaves the scene above the
+    /// horizontal plane hits the terrain
+    pub fn obstructs(&self, direction: Vector3D) -> bool {
+        let d = direction.get_normalized();
+        if d.z < 0.0 {
+            return false;
+        }
+        let altitude = d.z.clamp(-1., 1.).asin().to_degrees();
+        let azimuth = d.x.atan2(d.y).to_degrees();
+        altitude < self.altitude_at(azimuth)
+    }
+
+    /// The fraction of each bin that lies below the profile, found by checking
+    /// a grid of `resolution` by `resolution` directions within each patch.
+    /// The ground bin is not obstructed.
+    pub fn obstructed_fractions(&self, basis: &SkyBasis, resolution: usize) -> Vec<Float> {
+        let mut ret = vec![0.0; basis.n_bins()];
+        let n = resolution.max(1);
+        for patch in basis.patches() {
+            let (min_sin, max_sin) = (patch.altitude.0.sin(), patch.altitude.1.sin());
+            let (min_az, max_az) = patch.azimuth;
+            let mut obstructed = 0;
+            for i in 0..n {
+                // Equal steps in the sine of the altitude cover equal solid angles
+                let sin_alt = min_sin + (i as Float + 0.5) / n as Float * (max_sin - min_sin);
+                let cos_alt = (1. - sin_alt * sin_alt).max(0.0).sqrt();
+                for j in 0..n {
+                    let az = min_az + (j as Float + 0.5) / n as Float * (max_az - min_az);
+                    let dir = Vector3D::new(az.sin() * cos_alt, az.cos() * cos_alt, sin_alt);
+                    if self.obstructs(dir) {
+                        obstructed += 1;
+                    }
+                }
+            }
+            ret[patch.bin] = obstructed as Float / (n * n) as Float;
+        }
+        ret
+    }
+
+    /// How much each bin of the sky contributes to the radiance of the terrain,
+    /// which is `ρ/π` times the unobstructed horizontal irradiance of the sky
+    pub(crate) fn terrain_coefficients(&self, basis: &SkyBasis) -> Vec<Float> {
+        let mut ret = vec![0.0; basis.n_bins()];
+        for patch in basis.patches() {
+            ret[patch.bin] = self.reflectance / PI * patch.solid_angle * patch.centroid.z;
+        }
+        ret
+    }
+
+    /// Like [`HorizonProfile::terrain_coefficients`], for a sky that is only
+    /// known by its number of bins
+    pub(crate) fn terrain_coefficients_for(&self, n_bins: usize) -> Result<Vec<Float>, String> {
+        let mf = (1..=MAX_MF)
+            .find(|mf| ReinhartSky::n_bins(*mf) == n_bins)
+            .ok_or_else(|| format!("No Reinhart sky has {} bins", n_bins))?;
+        Ok(self.terrain_coefficients(&SkyBasis::new(mf)?))
+    }
+
+    /// Applies the profile to the columns of a sky matrix (or to a sky vector):
+    /// the radiance of each patch is replaced, in the fraction of it that is
+    /// obstructed, by the radiance of the terrain. Fractions are calculated as
+    /// in [`HorizonProfile::obstructed_fractions`].
+    pub fn attenuate_sky(
+        &self,
+        basis: &SkyBasis,
+        sky: &Matrix,
+        resolution: usize,
+    ) -> Result<Matrix, String> {
+        let (nrows, ncols) = sky.size();
+        if nrows != basis.n_bins() {
+            return Err(format!(
+                "A sky with MF {} has {} bins, but the sky matrix has {} rows",
+                basis.mf(),
+                basis.n_bins(),
+                nrows
+            ));
+        }
+        let fractions = self.obstructed_fractions(basis, resolution);
+        let terrain = self.terrain_coefficients(basis);
+        let mut ret = sky.clone();
+        for c in 0..ncols {
+            let mut radiance = 0.0;
+            for (bin, k) in terrain.iter().enumerate() {
+                radiance += k * sky.get(bin, c)?;
+            }
+            for (bin, f) in fractions.iter().enumerate() {
+                if *f > 0.0 {
+                    let v = sky.get(bin, c)?;
+                    ret.set(bin, c, v * (1. - f) + radiance * f)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession};
+    use crate::{Material, SceneBuilder, SensorSpec};
+    use geometry3d::{Point3D, Ray3D};
+    use rendering::Scene;
+
+    #[test]
+    fn test_profile() {
+        let profile = HorizonProfile::new(&[(90., 20.), (-90., 10.)], 0.).unwrap();
+        assert_eq!(profile.altitude_at(90.), 20.);
+        assert_eq!(profile.altitude_at(270.), 10.);
+        assert_eq!(profile.altitude_at(0.), 15.);
+        assert_eq!(profile.altitude_at(180.), 15.);
+        assert!(profile.obstructs(Vector3D::new(1., 0., 0.3)));
+        assert!(!profile.obstructs(Vector3D::new(1., 0., 0.4)));
+        assert!(!profile.obstructs(Vector3D::new(1., 0., -0.3)));
+
+        assert!(HorizonProfile::new(&[], 0.).is_err());
+        assert!(HorizonProfile::new(&[(0., 10.), (360., 20.)], 0.).is_err());
+        assert!(HorizonProfile::constant(100., 0.).is_err());
+        assert!(HorizonProfile::constant(10., 1.5).is_err());
+
+        // Patches are either under or over a constant horizon at 12 degrees
+        let basis = SkyBasis::new(1).unwrap();
+        let flat = HorizonProfile::constant(12., 0.).unwrap();
+        let fractions = flat.obstructed_fractions(&basis, 4);
+        for patch in basis.patches() {
+            let expected = if patch.row == 0 { 1. } else { 0. };
+            assert_eq!(fractions[patch.bin], expected);
+        }
+    }
+
+    /// A ring of black walls around the origin, whose top is at
+    /// `altitude` degrees when seen from the origin
+    fn ring(altitude: Float) -> Scene {
+        let n = 72;
+        let r = 100.;
+        // The middle of each wall (not their corners) is at distance `r`
+        let corner_r = r / (PI / n as Float).cos();
+        let height = r * altitude.to_radians().tan();
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("black", Material::plastic(0.))
+            .unwrap();
+        for i in 0..n {
+            let corner = |j: usize, z: Float| {
+                let a = 2. * PI * j as Float / n as Float;
+                Point3D::new(corner_r * a.cos(), corner_r * a.sin(), z)
+            };
+            let wall = [
+                corner(i, -1.),
+                corner(i + 1, -1.),
+                corner(i + 1, height),
+                corner(i, height),
+            ];
+            builder
+                .add_polygon("black", &format!("wall_{}", i), &wall)
+                .unwrap();
+        }
+        builder.build().unwrap().0
+    }
+
+    /// The irradiance of an overcast sky on a few sensors
+    fn irradiance(session: &DCSession, scene: &Scene, sky: &Matrix) -> Vec<Float> {
+        let sensors: Vec<SensorSpec> = [
+            Vector3D::new(0., 0., 1.),
+            Vector3D::new(0., 1., 0.),
+            Vector3D::new(-1., 0., 0.),
+        ]
+        .iter()
+        .map(|d| {
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(0., 0., 0.),
+                direction: *d,
+            })
+        })
+        .collect();
+        let dc = session.calc_sensor_dc(&sensors, scene).unwrap().matrix;
+        let (nrows, ncols) = dc.size();
+        (0..nrows)
+            .map(|r| {
+                (0..ncols)
+                    .map(|c| dc.get(r, c).unwrap() * sky.get(c, 0).unwrap())
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_consistent_with_ring() {
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20_000,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(2, options);
+        let basis = session.basis().unwrap();
+        let sky = basis.cie_overcast(1000., 0.).unwrap();
+        let mut empty = Scene::new();
+        empty.build_accelerator();
+        let mut ring = ring(10.);
+        ring.build_accelerator();
+
+        // A black ring is the same as a black terrain, whichever way it is applied
+        let black = HorizonProfile::constant(10., 0.).unwrap();
+        let expected = irradiance(&session, &ring, &sky);
+        let traced = irradiance(&session.clone().with_horizon(black.clone()), &empty, &sky);
+        let attenuated = black.attenuate_sky(&basis, &sky, 8).unwrap();
+        let on_sky = irradiance(&session, &empty, &attenuated);
+        for i in 0..expected.len() {
+            assert!((traced[i] - expected[i]).abs() < 0.01 * expected[i]);
+            assert!((on_sky[i] - expected[i]).abs() < 0.03 * expected[i]);
+        }
+
+        // A bright terrain adds light, and both paths still agree
+        let bright = HorizonProfile::constant(10., 0.5).unwrap();
+        let traced = irradiance(&session.clone().with_horizon(bright.clone()), &empty, &sky);
+        let attenuated = bright.attenuate_sky(&basis, &sky, 8).unwrap();
+        let on_sky = irradiance(&session, &empty, &attenuated);
+        for i in 0..expected.len() {
+            assert!(traced[i] > expected[i]);
+            assert!(
+                (traced[i] - on_sky[i]).abs() < 0.03 * traced[i],
+                "{}: {} vs {}",
+                i,
+                traced[i],
+                on_sky[i]
+            );
+        }
+    }
+}
@@ -0,0 +1,305 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::annual::annual_irradiance;
+use crate::labeled_matrix::LabeledMatrix;
+use crate::Float;
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use solar::ReinhartSky;
+
+/// An Incidence Angle Modifier (IAM): the fraction of the irradiance
+/// that makes it through the cover of a PV module, as a function of the
+/// angle between the incoming light and the normal of the module.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IamModel {
+    /// The ASHRAE model, `IAM = 1 - b0 * (1/cos(θ) - 1)`, clamped to `[0, 1]`.
+    /// A typical value for glass is `b0 = 0.05`.
+    Ashrae {
+        /// The fitted coefficient
+        b0: Float,
+    },
+
+    /// A user-supplied curve of `(incidence angle in degrees, IAM)` pairs,
+    /// sorted by angle and interpolated linearly. Angles outside of the curve
+    /// take the value of the closest end. See [`IamModel::curve`].
+    Curve(Vec<(Float, Float)>),
+}
+
+impl IamModel {
+    /// Builds an [`IamModel::Curve`], checking that the angles are sorted
+    /// and within `[0, 90]` degrees
+    pub fn curve(points: Vec<(Float, Float)>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("An IAM curve needs at least one point".to_string());
+        }
+        for (angle, iam) in points.iter() {
+            if !(0.0..=90.).contains(angle) {
+                return Err(format!(
+                    "IAM curve angles must be between 0 and 90 degrees... found {}",
+                    angle
+                ));
+            }
+            if !iam.is_finite() || *iam < 0.0 {
+                return Err(format!("IAM values must be non-negative... found {}", iam));
+            }
+        }
+        if points.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return Err("IAM curve angles must be strictly increasing".to_string());
+        }
+        Ok(Self::Curve(points))
+    }
+
+    /// The IAM for light arriving at `angle` degrees from the normal. Light
+    /// arriving from behind the module (i.e., at 90 degrees or more) gets `0`.
+    pub fn factor(&self, angle: Float) -> Float {
+        if angle >= 90. {
+            return 0.0;
+        }
+        let angle = angle.max(0.0);
+        match self {
+            Self::Ashrae { b0 } => {
+                let cos = angle.to_radians().cos();
+                (1. - b0 * (1. / cos - 1.)).clamp(0.0, 1.0)
+            }
+            Self::Curve(points) => interpolate(points, angle),
+        }
+    }
+}
+
+/// Interpolates linearly within a curve of `(x, y)` pairs sorted by `x`. Beyond
+/// its ends, the curve takes the value of the closest one.
+pub(crate) fn interpolate(points: &[(Float, Float)], x: Float) -> Float {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    if x <= first.0 {
+        return first.1;
+    }
+    if x >= last.0 {
+        return last.1;
+    }
+    let i = points
+        .iter()
+        .position(|(a, _)| *a > x)
+        .unwrap_or(points.len() - 1);
+    let ((a0, v0), (a1, v1)) = (points[i - 1], points[i]);
+    v0 + (v1 - v0) * (x - a0) / (a1 - a0)
+}
+
+/// How the IAM is applied to the ground bin, whose light does not come from
+/// a single direction
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum GroundIam {
+    /// Use the effective incidence angle of ground-reflected light from
+    /// Brandemuehl and Beckman (1980), `90 - 0.5788β + 0.002693β²`, where `β`
+    /// is the tilt of the sensor in degrees
+    #[default]
+    EffectiveAngle,
+
+    /// Multiply the ground bin by a fixed factor
+    Fixed(Float),
+
+    /// Do not modify the ground bin
+    None,
+}
+
+/// The options for applying an [`IamModel`] to Daylight Coefficients
+#[derive(Debug, Clone, PartialEq)]
+pub struct IamOptions {
+    /// The IAM of the module
+    pub model: IamModel,
+
+    /// How the ground bin is treated
+    pub ground: GroundIam,
+}
+
+/// Calculates the IAM factor of each bin of a Reinhart sky with subdivision `mf`,
+/// for a sensor facing `normal`. The angle of each sky patch is measured from
+/// the direction of its centre, and the ground bin (i.e., the first one) is handled
+/// as described by `options.ground`.
+pub fn iam_factors(
+    normal: Vector3D,
+    mf: usize,
+    options: &IamOptions,
+) -> Result<Vec<Float>, String> {
+    if normal.is_zero() {
+        return Err(
+            "Cannot calculate incidence angles for a sensor with a zero normal".to_string(),
+        );
+    }
+    let normal = normal.get_normalized();
+    let sky = ReinhartSky::new(mf);
+    let n_bins = ReinhartSky::n_bins(mf);
+    let mut ret = Vec::with_capacity(n_bins);
+    ret.push(match options.ground {
+        GroundIam::EffectiveAngle => {
+            let tilt = normal.z.clamp(-1., 1.).acos().to_degrees();
+            let angle = 90. - 0.5788 * tilt + 0.002693 * tilt * tilt;
+            options.model.factor(angle)
+        }
+        GroundIam::Fixed(v) => v,
+        GroundIam::None => 1.,
+    });
+    for bin in 1..n_bins {
+        let cos = (sky.bin_dir(bin).get_normalized() * normal).clamp(-1., 1.);
+        ret.push(options.model.factor(cos.acos().to_degrees()));
+    }
+    Ok(ret)
+}
+
+/// Multiplies each coefficient of a Daylight Coefficient matrix—calculated for
+/// a sky with subdivision `mf`—by the IAM factor of its bin, using the normal
+/// of the sensor of each row (see [`iam_factors`]).
+pub fn apply_iam(dc: &LabeledMatrix, mf: usize, options: &IamOptions) -> Result<Matrix, String> {
+    let (nrows, ncols) = dc.matrix.size();
+    if dc.rows.len() != nrows {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} rows, but {} rows are described",
+            nrows,
+            dc.rows.len()
+        ));
+    }
+    if ncols != ReinhartSky::n_bins(mf) {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} bins, but a sky with MF {} has {}",
+            ncols,
+            mf,
+            ReinhartSky::n_bins(mf)
+        ));
+    }
+    let mut ret = dc.matrix.clone();
+    for (r, row) in dc.rows.iter().enumerate() {
+        let factors = iam_factors(row.ray.direction, mf, options)?;
+        for (c, f) in factors.iter().enumerate() {
+            ret.set(r, c, dc.matrix.get(r, c)? * f)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Like [`annual_irradiance`], but correcting each coefficient by an
+/// incidence-angle modifier first (see [`apply_iam`]). This is the irradiance
+/// that reaches the cells of a PV module rather than its surface.
+pub fn annual_irradiance_with_iam(
+    dc: &LabeledMatrix,
+    mf: usize,
+    skies: &Matrix,
+    options: &IamOptions,
+) -> Result<Matrix, String> {
+    annual_irradiance(&apply_iam(dc, mf, options)?, skies)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::events::EventLog;
+    use crate::labeled_matrix::RowMetadata;
+    use crate::sensor_id::SensorId;
+    use crate::sky_matrix::GroundConvention;
+    use geometry3d::{Point3D, Ray3D};
+    use validate::assert_close;
+
+    #[test]
+    fn test_iam_models() {
+        let ashrae = IamModel::Ashrae { b0: 0.05 };
+        assert_close!(ashrae.factor(0.), 1., 1e-9);
+        // 1 - 0.05 * (1/cos(60) - 1) = 1 - 0.05 * (2 - 1)
+        assert_close!(ashrae.factor(60.), 0.95, 1e-6);
+        assert_close!(ashrae.factor(89.9), 0., 1e-9);
+        assert_close!(ashrae.factor(120.), 0., 1e-9);
+
+        let curve = IamModel::curve(vec![(0., 1.), (60., 0.9), (90., 0.)]).unwrap();
+        assert_close!(curve.factor(30.), 0.95, 1e-6);
+        assert_close!(curve.factor(60.), 0.9, 1e-6);
+        assert_close!(curve.factor(75.), 0.45, 1e-6);
+        assert!(IamModel::curve(vec![(10., 1.), (5., 0.9)]).is_err());
+        assert!(IamModel::curve(vec![(100., 1.)]).is_err());
+        assert!(IamModel::curve(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_iam_at_60_degrees() {
+        let mf = 1;
+        let sky = ReinhartSky::new(mf);
+        let bin = 40;
+        let sun = sky.bin_dir(bin).get_normalized();
+
+        // A normal at 60 degrees from the centre of the patch
+        let perpendicular = sun.cross(Vector3D::new(0., 0., 1.)).get_normalized();
+        let normal = sun * (60. as Float).to_radians().cos()
+            + perpendicular * (60. as Float).to_radians().sin();
+
+        let options = IamOptions {
+            model: IamModel::Ashrae { b0: 0.05 },
+            ground: GroundIam::None,
+        };
+        let factors = iam_factors(normal, mf, &options).unwrap();
+        assert_close!(factors[bin], 0.95, 1e-4);
+        assert_close!(factors[0], 1., 1e-9);
+
+        // A DC with a single coefficient, under a sky that only has that patch
+        let n_bins = ReinhartSky::n_bins(mf);
+        let mut matrix = Matrix::new(0.0, 1, n_bins);
+        matrix.set(0, bin, 0.02).unwrap();
+        let dc = LabeledMatrix {
+            matrix,
+            rows: vec![RowMetadata {
+                ray: Ray3D {
+                    origin: Point3D::new(0., 0., 0.),
+                    direction: normal,
+                },
+                mask: None,
+                zone: None,
+                n_samples: 1,
+                id: SensorId::new("sensor").unwrap(),
+                angular_response: None,
+            }],
+            events: EventLog::new(),
+            ground: GroundConvention::default(),
+        };
+        let mut skies = Matrix::new(0.0, n_bins, 1);
+        skies.set(bin, 0, 5000.).unwrap();
+        let plain = annual_irradiance(&dc.matrix, &skies).unwrap();
+        let corrected = annual_irradiance_with_iam(&dc, mf, &skies, &options).unwrap();
+        assert_close!(plain.get(0, 0).unwrap(), 100., 1e-6);
+        assert_close!(corrected.get(0, 0).unwrap(), 95., 1e-2);
+
+        assert!(annual_irradiance_with_iam(&dc, 2, &skies, &options).is_err());
+    }
+
+    #[test]
+    fn test_ground_iam() {
+        let model = IamModel::Ashrae { b0: 0.05 };
+        let vertical = Vector3D::new(0., 1., 0.);
+        let effective = IamOptions {
+            model: model.clone(),
+            ground: GroundIam::EffectiveAngle,
+        };
+        // Vertical: 90 - 0.5788 * 90 + 0.002693 * 90^2 = 59.7213 degrees
+        let factors = iam_factors(vertical, 1, &effective).unwrap();
+        assert_close!(factors[0], model.factor(59.7213), 1e-6);
+
+        let fixed = IamOptions {
+            model,
+            ground: GroundIam::Fixed(0.8),
+        };
+        assert_close!(iam_factors(vertical, 1, &fixed).unwrap()[0], 0.8, 1e-9);
+        assert!(iam_factors(Vector3D::new(0., 0., 0.), 1, &fixed).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::sampling::HemisphereSampler;
+use crate::sensor::{DirectionSampler, LocalFrame};
+use crate::{Float, PI};
+use geometry3d::{Point3D, Vector3D};
+
+/// A sphere that contains an object, used for sending samples towards it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    /// The centre of the sphere
+    pub centre: Point3D,
+
+    /// The radius of the sphere
+    pub radius: Float,
+}
+
+impl BoundingSphere {
+    /// The smallest sphere containing the box with corners `min` and `max`
+    pub fn from_box(min: Point3D, max: Point3D) -> Self {
+        let centre = Point3D::new(
+            0.5 * (min.x + max.x),
+            0.5 * (min.y + max.y),
+            0.5 * (min.z + max.z),
+        );
+        Self {
+            centre,
+            radius: (max - centre).length(),
+        }
+    }
+}
+
+/// Objects that are small but important (e.g., thin light shelves, fins or
+/// mullions), which random samples might miss altogether.
+///
+/// The direct tracer sends a `fraction` of the samples of each sensor towards
+/// the objects—uniformly within the cone subtended by their [`BoundingSphere`]—and
+/// the rest over the sensor's hemisphere as usual. The two are combined through
+/// multiple importance sampling (i.e., the balance heuristic), so the results
+/// remain unbiased, while the coefficients of the bins around the objects become
+/// much less noisy. Sensors inside a sphere ignore that object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportanceHints {
+    /// The objects worth sampling
+    pub objects: Vec<BoundingSphere>,
+
+    /// The fraction of samples sent towards the objects, in `[0, 1)`
+    pub fraction: Float,
+}
+
+impl ImportanceHints {
+    /// Creates a new set of hints, checking that `fraction` is in `[0, 1)` so that
+    /// every direction can still be sampled
+    pub fn new(objects: Vec<BoundingSphere>, fraction: Float) -> Result<Self, String> {
+        if !(0.0..1.0).contains(&fraction) {
+            return Err(format!(
+                "The fraction of samples sent towards important objects must be in [0, 1), found {}",
+                fraction
+            ));
+        }
+        if let Some(s) = objects
+            .iter()
+            .find(|s| s.radius.is_nan() || s.radius <= 0.0)
+        {
+            return Err(format!(
+                "Important objects need a positive radius, found {}",
+                s.radius
+            ));
+        }
+        Ok(Self { objects, fraction })
+    }
+}
+
+/// The cone subtended by a [`BoundingSphere`], as seen from a sensor
+#[derive(Debug, Clone, Copy)]
+struct Cone {
+    frame: LocalFrame,
+    cos_max: Float,
+}
+
+impl Cone {
+    /// Returns `None` if `origin` is inside the sphere
+    fn new(origin: Point3D, sphere: &BoundingSphere) -> Option<Self> {
+        let axis = sphere.centre - origin;
+        let d2 = axis * axis;
+        let r2 = sphere.radius * sphere.radius;
+        if d2 <= r2 {
+            return None;
+        }
+        Some(Self {
+            frame: LocalFrame::new(axis).ok()?,
+            cos_max: (1. - r2 / d2).sqrt(),
+        })
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> Vector3D {
+        let cos = 1. - u1 * (1. - self.cos_max);
+        let sin = (1. - cos * cos).max(0.0).sqrt();
+        self.frame.direction(sin, cos, 2. * PI * u2)
+    }
+
+    fn pdf(&self, direction: Vector3D) -> Float {
+        if direction.get_normalized() * self.frame.normal < self.cos_max {
+            return 0.0;
+        }
+        1. / (2. * PI * (1. - self.cos_max))
+    }
+}
+
+/// Samples the directions seen from a sensor, sending part of the samples
+/// towards the objects of some [`ImportanceHints`].
+///
+/// Out of every `n_samples` samples, `n_cone` (spread evenly) choose one of the
+/// cones uniformly, and the rest use the sensor's own sampler. Each sample is then
+/// weighted by the balance heuristic, which amounts to dividing the integrand by the
+/// combined density `(n_base * p_base + n_cone * p_cone) / n_samples`.
+pub(crate) struct ImportanceSampler<'a> {
+    base: &'a DirectionSampler,
+    cones: Vec<Cone>,
+    n_samples: usize,
+    n_cone: usize,
+}
+
+impl<'a> ImportanceSampler<'a> {
+    pub fn new(
+        base: &'a DirectionSampler,
+        origin: Point3D,
+        hints: Option<&ImportanceHints>,
+        n_samples: usize,
+    ) -> Self {
+        let (cones, fraction) = match hints {
+            Some(h) => (
+                h.objects
+                    .iter()
+                    .filter_map(|s| Cone::new(origin, s))
+                    .collect::<Vec<Cone>>(),
+                h.fraction,
+            ),
+            None => (Vec::new(), 0.0),
+        };
+        let n_cone = if cones.is_empty() {
+            0
+        } else {
+            (fraction * n_samples as Float).round() as usize
+        };
+        Self {
+            base,
+            cones,
+            n_samples,
+            n_cone,
+        }
+    }
+
+    /// Whether the `j`-th sample (counting from `0`) is sent towards the objects
+    fn is_cone_sample(&self, j: usize) -> bool {
+        (j + 1) * self.n_cone / self.n_samples > j * self.n_cone / self.n_samples
+    }
+
+    /// Samples the direction of `j`-th sample out of `n_samples`, its weight and
+    /// the weight it would have had if it did not graze the plane of the sensor
+    /// (see [`DirectionSampler::with_min_cosine`]), which is `0` for the rest
+    pub fn sample(&self, j: usize, u1: Float, u2: Float) -> (Vector3D, Float, Float) {
+        if self.n_cone == 0 {
+            let (direction, weight) = self.base.sample(u1, u2);
+            return (direction, weight, self.base.clipped(direction));
+        }
+        let direction = if self.is_cone_sample(j) {
+            // The first number also chooses the cone
+            let n = self.cones.len() as Float;
+            let k = ((u1 * n) as usize).min(self.cones.len() - 1);
+            self.cones[k].sample(u1 * n - k as Float, u2)
+        } else {
+            self.base.sample_direction(u1, u2)
+        };
+        let f = self.base.integrand(direction);
+        if f <= 0.0 {
+            let cos = self.base.grazing(direction);
+            if cos > 0.0 {
+                return (direction, 0.0, cos / self.pdf(direction));
+            }
+            return (direction, 0.0, 0.0);
+        }
+        (direction, f / self.pdf(direction), 0.0)
+    }
+
+    /// The combined density of drawing `direction`
+    fn pdf(&self, direction: Vector3D) -> Float {
+        let p_cone: Float =
+            self.cones.iter().map(|c| c.pdf(direction)).sum::<Float>() / self.cones.len() as Float;
+        let n_base = (self.n_samples - self.n_cone) as Float;
+        (n_base * self.base.pdf(direction) + self.n_cone as Float * p_cone)
+            / self.n_samples as Float
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    #[test]
+    fn test_hints() {
+        let sphere = BoundingSphere::from_box(Point3D::new(0., 0., 0.), Point3D::new(2., 0., 0.));
+        assert_eq!(sphere.centre, Point3D::new(1., 0., 0.));
+        assert_close!(sphere.radius, 1., 1e-9);
+        assert!(ImportanceHints::new(vec![sphere], 0.3).is_ok());
+        assert!(ImportanceHints::new(vec![sphere], 1.).is_err());
+        assert!(ImportanceHints::new(vec![sphere], -0.1).is_err());
+        let flat = BoundingSphere {
+            centre: sphere.centre,
+            radius: 0.,
+        };
+        assert!(ImportanceHints::new(vec![flat], 0.3).is_err());
+    }
+
+    #[test]
+    fn test_allocation() {
+        let base = DirectionSampler::new(Vector3D::new(0., 0., 1.), None).unwrap();
+        let sphere = BoundingSphere {
+            centre: Point3D::new(0., 0., 2.),
+            radius: 0.5,
+        };
+        let hints = ImportanceHints::new(vec![sphere], 0.3).unwrap();
+        let sampler = ImportanceSampler::new(&base, Point3D::new(0., 0., 0.), Some(&hints), 100);
+        assert_eq!((0..100).filter(|j| sampler.is_cone_sample(*j)).count(), 30);
+
+        // Sensors inside the sphere ignore it
+        let inside = ImportanceSampler::new(&base, Point3D::new(0., 0., 2.), Some(&hints), 100);
+        assert_eq!(inside.n_cone, 0);
+        let (direction, weight) = base.sample(0.3, 0.6);
+        assert_eq!(inside.sample(0, 0.3, 0.6), (direction, weight, 0.0));
+    }
+
+    #[test]
+    fn test_unbiased() {
+        // The weights integrate the cosine over the hemisphere, whatever the hints
+        let base = DirectionSampler::new(Vector3D::new(0., 0., 1.), None).unwrap();
+        let spheres = vec![
+            BoundingSphere {
+                centre: Point3D::new(1., 0., 1.),
+                radius: 0.4,
+            },
+            BoundingSphere {
+                centre: Point3D::new(0., -1., -0.1),
+                radius: 0.3,
+            },
+        ];
+        let hints = ImportanceHints::new(spheres, 0.4).unwrap();
+        let n = 200_000;
+        let sampler = ImportanceSampler::new(&base, Point3D::new(0., 0., 0.), Some(&hints), n);
+        let mut rng = crate::rng::SensorRng::new(1, 0);
+        let mut sum = 0.0;
+        for j in 0..n {
+            let (_, w, _) = sampler.sample(j, rng.gen(), rng.gen());
+            sum += w;
+        }
+        assert_close!(sum / n as Float, PI, 0.01);
+
+        // What grazing samples lose is what they report, at the same density
+        let base = base.with_min_cosine(0.3);
+        let sampler = ImportanceSampler::new(&base, Point3D::new(0., 0., 0.), Some(&hints), n);
+        let (mut kept, mut clipped) = (0.0, 0.0);
+        for j in 0..n {
+            let (_, w, c) = sampler.sample(j, rng.gen(), rng.gen());
+            kept += w;
+            clipped += c;
+        }
+        assert_close!(kept / n as Float, PI * (1. - 0.09), 0.01);
+        assert_close!(clipped / n as Float, PI * 0.09, 0.01);
+    }
+
+    #[test]
+    fn test_thin_shelf() {
+        use crate::scene_loading::load_scene;
+        use crate::sensor::SensorSpec;
+        use crate::session::{DCOptions, DCSession};
+        use crate::sky::SkyBasis;
+        use geometry3d::Ray3D;
+
+        let (mut scene, report) = load_scene("./tests/importance/light_shelf.rad").unwrap();
+        scene.build_accelerator();
+        let shelf = report.bounding_sphere("shelf_mat").unwrap();
+        assert!(report.bounding_sphere("nothing").is_err());
+        let origin = Point3D::new(0., 0., 0.);
+        let sensors = vec![SensorSpec::from(Ray3D {
+            origin,
+            direction: Vector3D::new(0., 0., 1.),
+        })];
+
+        // The bins around the shelf
+        let basis = SkyBasis::new(4).unwrap();
+        let cone = Cone::new(origin, &shelf).unwrap();
+        let around: Vec<usize> = basis
+            .centroids()
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| cone.pdf(**d) > 0.0)
+            .map(|(bin, _)| bin)
+            .collect();
+        assert!(!around.is_empty());
+
+        // The sum of the coefficients around the shelf, with different seeds
+        let hints = ImportanceHints::new(vec![shelf], 0.5).unwrap();
+        let n_runs = 30;
+        let runs = |hints: Option<&ImportanceHints>| -> Vec<Vec<Float>> {
+            (0..n_runs)
+                .map(|seed| {
+                    let mut session = DCSession::new(
+                        4,
+                        DCOptions {
+                            max_depth: 0,
+                            n_ambient_samples: 3000,
+                            seed,
+                            ..DCOptions::default()
+                        },
+                    );
+                    if let Some(h) = hints {
+                        session = session.with_importance_hints(h.clone());
+                    }
+                    let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+                    around
+                        .iter()
+                        .map(|bin| dc.matrix.get(0, *bin).unwrap())
+                        .collect()
+                })
+                .collect()
+        };
+        let spread = |runs: &[Vec<Float>]| -> (Float, Float, Float) {
+            let n = runs.len() as Float;
+            let mut variance = 0.0;
+            for b in 0..around.len() {
+                let mean = runs.iter().map(|r| r[b]).sum::<Float>() / n;
+                variance += runs.iter().map(|r| (r[b] - mean).powi(2)).sum::<Float>() / (n - 1.);
+            }
+            let totals: Vec<Float> = runs.iter().map(|r| r.iter().sum()).collect();
+            let mean = totals.iter().sum::<Float>() / n;
+            let se =
+                (totals.iter().map(|t| (t - mean).powi(2)).sum::<Float>() / (n - 1.) / n).sqrt();
+            (variance, mean, se)
+        };
+        let (plain_variance, plain_mean, plain_se) = spread(&runs(None));
+        let (hinted_variance, hinted_mean, hinted_se) = spread(&runs(Some(&hints)));
+        assert!(
+            hinted_variance < 0.5 * plain_variance,
+            "{} vs {}",
+            hinted_variance,
+            plain_variance
+        );
+        // ... without moving the results
+        let se = (plain_se * plain_se + hinted_se * hinted_se).sqrt();
+        assert!((hinted_mean - plain_mean).abs() < 4. * se);
+
+        // Hints are only for the direct tracer
+        let session = DCSession::new(4, DCOptions::default()).with_importance_hints(hints);
+        let err = session.calc_sensor_dc(&sensors, &scene).unwrap_err();
+        assert!(err.contains("direct tracer"));
+    }
+}
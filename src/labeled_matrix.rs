@@ -0,0 +1,219 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::annual::apply_annual;
+use crate::events::EventLog;
+use crate::sensor::{AngularMask, ResponseCurve};
+use crate::sensor_id::{align, Alignment, SensorId};
+use crate::sky_matrix::{GroundConvention, SkyMatrix};
+use geometry3d::Ray3D;
+use matrix::Matrix;
+
+/// Describes a row of a [`LabeledMatrix`]
+#[derive(Debug, Clone)]
+pub struct RowMetadata {
+    /// The position and orientation of the sensor
+    pub ray: Ray3D,
+
+    /// The mask that was applied when sampling
+    pub mask: Option<AngularMask>,
+
+    /// The zone that the sensor belongs to
+    pub zone: Option<String>,
+
+    /// The number of samples sent from the sensor
+    pub n_samples: usize,
+
+    /// The id of the sensor (see [`SensorSpec::sensor_id`](crate::SensorSpec::sensor_id))
+    pub id: SensorId,
+
+    /// The angular response of the sensor, if it was not ideal
+    pub angular_response: Option<ResponseCurve>,
+}
+
+/// A matrix whose rows are described by a [`RowMetadata`]
+#[derive(Debug, Clone)]
+pub struct LabeledMatrix {
+    /// The values
+    pub matrix: Matrix,
+
+    /// One element per row of `matrix`
+    pub rows: Vec<RowMetadata>,
+
+    /// What happened while calculating the matrix
+    pub events: EventLog,
+
+    /// The light that the ground bin of the matrix (i.e., its first column) is
+    /// meant to be multiplied by. Matrices calculated by a [`DCSession`](crate::DCSession)
+    /// expect a ground of [`DEFAULT_GROUND_ALBEDO`](crate::DEFAULT_GROUND_ALBEDO),
+    /// except the sky part of two-sided ones, whose ground is apart.
+    pub ground: GroundConvention,
+}
+
+impl LabeledMatrix {
+    /// Multiplies the matrix by a sky matrix, after making the ground bin of
+    /// the sky follow the [`GroundConvention`] of the matrix (see
+    /// [`SkyMatrix::reconciled`]). The result has one row per sensor and one
+    /// column per timestep.
+    pub fn apply_sky(&self, sky: &SkyMatrix) -> Result<Matrix, String> {
+        sky.basis.check_dc(&self.matrix)?;
+        apply_annual(&self.matrix, &sky.reconciled(self.ground)?)
+    }
+
+    /// The id of the sensor of each row
+    pub fn ids(&self) -> Vec<SensorId> {
+        self.rows.iter().map(|r| r.id.clone()).collect()
+    }
+
+    /// Puts the rows of this matrix and of `other` side by side, matching
+    /// their sensors by id (see [`align`])
+    pub fn align(&self, other: &Self) -> Result<Alignment, String> {
+        align(&self.matrix, &self.ids(), &other.matrix, &other.ids())
+    }
+
+    /// The matrix of the mirror images of the sensors, whose coefficient for
+    /// each bin is that of the original sensor for the bin's mirror image. The
+    /// `permutation` gives the mirror image of each bin (see [`mirror_bins`](crate::mirror_bins)),
+    /// and the `rows` describe the mirrored sensors.
+    ///
+    /// This only holds if the scene is symmetric about the same plane.
+    pub fn mirrored(&self, permutation: &[usize], rows: Vec<RowMetadata>) -> Result<Self, String> {
+        let (nrows, ncols) = self.matrix.size();
+        if permutation.len() != ncols {
+            return Err(format!(
+                "A matrix with {} bins cannot be mirrored with a permutation of {}",
+                ncols,
+                permutation.len()
+            ));
+        }
+        let mut seen = vec![false; ncols];
+        for bin in permutation.iter() {
+            if *bin >= ncols || std::mem::replace(&mut seen[*bin], true) {
+                return Err(format!(
+                    "Bin {} is out of range or repeated in the permutation of a mirror",
+                    bin
+                ));
+            }
+        }
+        if rows.len() != nrows {
+            return Err(format!(
+                "A matrix with {} rows cannot be mirrored into {} sensors",
+                nrows,
+                rows.len()
+            ));
+        }
+        let mut matrix = Matrix::new(0.0, nrows, ncols);
+        for r in 0..nrows {
+            for (bin, from) in permutation.iter().enumerate() {
+                matrix.set(r, bin, self.matrix.get(r, *from)?)?;
+            }
+        }
+        Ok(Self {
+            matrix,
+            rows,
+            events: self.events.clone(),
+            ground: self.ground,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::Float;
+    use crate::{mirror_bins, DCOptions, DCSession, Material, SceneBuilder, SensorSpec, SkyBasis};
+    use geometry3d::{Point3D, Vector3D};
+
+    #[test]
+    fn test_mirrored() {
+        // Two walls, symmetric about the plane X = 0
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("black", Material::plastic(0.))
+            .unwrap();
+        for (name, x) in [("east", 1.5), ("west", -1.5)] {
+            let vertices =
+                [(-5., 0.), (5., 0.), (5., 2.), (-5., 2.)].map(|(y, z)| Point3D::new(x, y, z));
+            builder.add_polygon("black", name, &vertices).unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let at = |x: Float| -> SensorSpec {
+            Ray3D {
+                origin: Point3D::new(x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        };
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 40000,
+            seed: 7,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+        let (dc, stats) = session
+            .calc_sensor_dc_with_stats(&[at(0.5), at(-0.5)], &scene, true)
+            .unwrap();
+        let errors = stats.bin_standard_errors.unwrap();
+
+        let basis = SkyBasis::new(1).unwrap();
+        let mirror = mirror_bins(&basis, 0.).unwrap();
+        let mut rows = dc.rows.clone();
+        rows.reverse();
+        let mirrored = dc.mirrored(&mirror, rows.clone()).unwrap();
+        assert_eq!(mirrored.rows[0].ray.origin.x, -0.5);
+
+        // The mirror image of each sensor matches the other one, within noise
+        let (nrows, ncols) = dc.matrix.size();
+        for r in 0..nrows {
+            let other = nrows - 1 - r;
+            let mut worst: Float = 0.0;
+            for (c, from) in mirror.iter().enumerate() {
+                let (a, b) = (
+                    mirrored.matrix.get(r, c).unwrap(),
+                    dc.matrix.get(other, c).unwrap(),
+                );
+                let (sa, sb) = (errors.get(r, *from).unwrap(), errors.get(other, c).unwrap());
+                let sigma = (sa * sa + sb * sb).sqrt() + 1e-6;
+                assert!((a - b).abs() < 5. * sigma, "bin {}: {} vs {}", c, a, b);
+                // Without mirroring, they would not
+                let unmirrored = dc.matrix.get(r, c).unwrap();
+                worst = worst.max((unmirrored - b).abs() / sigma);
+            }
+            assert!(worst > 10., "{}", worst);
+        }
+
+        // Mirroring twice is the identity
+        let twice = mirrored.mirrored(&mirror, dc.rows.clone()).unwrap();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(twice.matrix.get(r, c), dc.matrix.get(r, c));
+            }
+        }
+
+        // Wrong permutations and rows
+        assert!(dc.mirrored(&mirror[1..], rows.clone()).is_err());
+        let mut repeated = mirror.clone();
+        repeated[1] = repeated[2];
+        assert!(dc.mirrored(&repeated, rows.clone()).is_err());
+        assert!(dc.mirrored(&mirror, rows[1..].to_vec()).is_err());
+    }
+}
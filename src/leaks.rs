@@ -0,0 +1,320 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Diagnostics for rooms that are not closed. Small gaps in the geometry
+//! (e.g., a missing strip of ceiling) let light in where there should be
+//! none, inflating the coefficients of the sensors close to them. These
+//! functions find the rays that escape and group them by direction, so that
+//! the gaps can be located.
+
+use crate::obstruction::escapes;
+use crate::rng::SensorRng;
+use crate::sensor::{DirectionSampler, SensorSpec};
+use crate::Float;
+use geometry3d::Vector3D;
+use rendering::Scene;
+use std::fmt;
+
+/// The largest number of clusters of escape directions reported for a sensor
+pub const MAX_LEAK_CLUSTERS: usize = 8;
+
+/// A direction that is further than this angle (in degrees) from every
+/// cluster starts a new one, as long as there are fewer than [`MAX_LEAK_CLUSTERS`]
+pub const LEAK_CLUSTER_ANGLE: Float = 25.;
+
+/// A group of rays that escaped a scene in similar directions
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeakCluster {
+    /// The mean direction of the rays, normalized
+    pub direction: Vector3D,
+
+    /// The root-mean-square angle (in degrees) between the rays and `direction`
+    pub spread: Float,
+
+    /// The fraction of all the samples of the sensor that escaped through this cluster
+    pub fraction: Float,
+}
+
+impl LeakCluster {
+    /// The altitude and azimuth of the mean direction, in degrees. Azimuths are
+    /// measured from North (`+Y`) towards East (`+X`).
+    pub fn altitude_azimuth(&self) -> (Float, Float) {
+        let d = self.direction;
+        let altitude = d.z.clamp(-1., 1.).asin().to_degrees();
+        let azimuth = d.x.atan2(d.y).to_degrees().rem_euclid(360.);
+        (altitude, azimuth)
+    }
+}
+
+/// The rays that escaped the scene from a sensor, as calculated by
+/// [`enclosure_leak_report`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeakReport {
+    /// The index of the sensor
+    pub sensor: usize,
+
+    /// The number of samples that the sensor can see (i.e., that are not masked)
+    pub n_samples: usize,
+
+    /// The number of samples that escaped the scene
+    pub escaped: usize,
+
+    /// The groups of escaped rays, from the largest to the smallest
+    pub clusters: Vec<LeakCluster>,
+}
+
+impl LeakReport {
+    /// The fraction of the samples that escaped the scene
+    pub fn escaped_fraction(&self) -> Float {
+        if self.n_samples == 0 {
+            return 0.0;
+        }
+        self.escaped as Float / self.n_samples as Float
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sensor {}: {} of {} samples escaped ({:.2}%)",
+            self.sensor,
+            self.escaped,
+            self.n_samples,
+            100. * self.escaped_fraction()
+        )?;
+        for c in &self.clusters {
+            let (altitude, azimuth) = c.altitude_azimuth();
+            writeln!(
+                f,
+                "    {:.2}% towards altitude {:.1}, azimuth {:.1} (spread of {:.1} degrees)",
+                100. * c.fraction,
+                altitude,
+                azimuth,
+                c.spread
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends `samples` rays from each sensor—over its hemisphere and respecting its
+/// mask, as the direct tracer does—and reports which of them escape the scene,
+/// grouping their directions with an online k-means on the unit sphere. Openings
+/// that were modelled (e.g., glazing) stop the rays like any other surface, so
+/// in a room that should be closed every cluster points to a gap.
+pub fn enclosure_leak_report(
+    sensors: &[SensorSpec],
+    scene: &Scene,
+    samples: usize,
+) -> Result<Vec<LeakReport>, String> {
+    let mut aux = Vec::with_capacity(2);
+    let mut ret = Vec::with_capacity(sensors.len());
+    for (i, sensor) in sensors.iter().enumerate() {
+        let sampler = DirectionSampler::new(sensor.ray.direction, sensor.mask.as_ref())?;
+        let mut rng = SensorRng::new(0, i as u64);
+        let mut n_samples = 0;
+        let mut leaks = Vec::new();
+        for _ in 0..samples {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            if weight <= 0.0 {
+                continue;
+            }
+            n_samples += 1;
+            if escapes(scene, sensor.ray.origin, dir, &mut aux) {
+                leaks.push(dir.get_normalized());
+            }
+        }
+        ret.push(LeakReport {
+            sensor: i,
+            n_samples,
+            escaped: leaks.len(),
+            clusters: cluster_directions(&leaks, n_samples),
+        });
+    }
+    Ok(ret)
+}
+
+/// Groups unit `directions` in a single pass: each one joins the cluster whose
+/// mean is closest, unless that is further than [`LEAK_CLUSTER_ANGLE`] and a new
+/// cluster can still be started. Fractions are relative to `n_samples`.
+fn cluster_directions(directions: &[Vector3D], n_samples: usize) -> Vec<LeakCluster> {
+    let min_cos = LEAK_CLUSTER_ANGLE.to_radians().cos();
+    // The sum of the directions of each cluster, and which cluster each direction joined
+    let mut sums: Vec<Vector3D> = Vec::new();
+    let mut members = Vec::with_capacity(directions.len());
+    for dir in directions {
+        let nearest = sums
+            .iter()
+            .map(|s| s.get_normalized() * *dir)
+            .enumerate()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        let c = match nearest {
+            Some((c, cos)) if cos >= min_cos || sums.len() == MAX_LEAK_CLUSTERS => c,
+            _ => {
+                sums.push(Vector3D::new(0., 0., 0.));
+                sums.len() - 1
+            }
+        };
+        sums[c] += *dir;
+        members.push(c);
+    }
+
+    let mut ret: Vec<(usize, LeakCluster)> = sums
+        .iter()
+        .enumerate()
+        .map(|(c, sum)| {
+            let direction = sum.get_normalized();
+            let angles: Vec<Float> = directions
+                .iter()
+                .zip(members.iter())
+                .filter(|(_, m)| **m == c)
+                .map(|(d, _)| (direction * *d).clamp(-1., 1.).acos().to_degrees())
+                .collect();
+            let n = angles.len();
+            let spread = (angles.iter().map(|a| a * a).sum::<Float>() / n as Float).sqrt();
+            let cluster = LeakCluster {
+                direction,
+                spread,
+                fraction: n as Float / n_samples as Float,
+            };
+            (n, cluster)
+        })
+        .collect();
+    ret.sort_by_key(|(n, _)| std::cmp::Reverse(*n));
+    ret.into_iter().map(|(_, c)| c).collect()
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{Material, SceneBuilder};
+    use geometry3d::{Point3D, Ray3D};
+    use validate::assert_close;
+
+    fn rectangle(x0: Float, x1: Float, y0: Float, y1: Float, z: Float) -> Vec<Point3D> {
+        vec![
+            Point3D::new(x0, y0, z),
+            Point3D::new(x1, y0, z),
+            Point3D::new(x1, y1, z),
+            Point3D::new(x0, y1, z),
+        ]
+    }
+
+    /// A 4x4x3 m room whose ceiling has a 0.3x0.6 m hole at `x = 3.5..3.8`,
+    /// `y = 1.7..2.3`, or no hole at all
+    fn room(hole: bool) -> Scene {
+        let mut builder = SceneBuilder::new();
+        builder.add_material("mat", Material::plastic(0.5)).unwrap();
+        let mut add = |name: &str, vertices: Vec<Point3D>| {
+            builder.add_polygon("mat", name, &vertices).unwrap();
+        };
+        add("floor", rectangle(0., 4., 0., 4., 0.));
+        if hole {
+            add("ceiling_1", rectangle(0., 3.5, 0., 4., 3.));
+            add("ceiling_2", rectangle(3.8, 4., 0., 4., 3.));
+            add("ceiling_3", rectangle(3.5, 3.8, 0., 1.7, 3.));
+            add("ceiling_4", rectangle(3.5, 3.8, 2.3, 4., 3.));
+        } else {
+            add("ceiling", rectangle(0., 4., 0., 4., 3.));
+        }
+        let walls: [(Point3D, Point3D); 4] = [
+            (Point3D::new(0., 0., 0.), Point3D::new(4., 0., 0.)),
+            (Point3D::new(4., 0., 0.), Point3D::new(4., 4., 0.)),
+            (Point3D::new(4., 4., 0.), Point3D::new(0., 4., 0.)),
+            (Point3D::new(0., 4., 0.), Point3D::new(0., 0., 0.)),
+        ];
+        for (i, (a, b)) in walls.iter().enumerate() {
+            let up = Vector3D::new(0., 0., 3.);
+            add(&format!("wall_{}", i), vec![*a, *b, *b + up, *a + up]);
+        }
+        builder.build().unwrap().0
+    }
+
+    fn sensor(normal: Vector3D) -> SensorSpec {
+        SensorSpec::from(Ray3D {
+            origin: Point3D::new(2., 2., 0.8),
+            direction: normal,
+        })
+    }
+
+    #[test]
+    fn test_clustering() {
+        let a = Vector3D::new(0., 0., 1.);
+        let b = Vector3D::new(1., 0., 0.);
+        let near_a = Vector3D::new(0.1, 0., 1.).get_normalized();
+        let clusters = cluster_directions(&[a, b, near_a], 10);
+        assert_eq!(clusters.len(), 2);
+        assert_close!(clusters[0].fraction, 0.2, 1e-6);
+        assert_close!(clusters[1].fraction, 0.1, 1e-6);
+        assert!(clusters[0].direction.x > 0.0 && clusters[0].direction.z > 0.99);
+        assert!(clusters[0].spread > 2. && clusters[0].spread < 3.);
+        assert_close!(clusters[1].spread, 0.0, 1e-3);
+        assert!(cluster_directions(&[], 10).is_empty());
+
+        // Directions all around are merged once there are too many clusters
+        let around: Vec<Vector3D> = (0..36)
+            .map(|i| {
+                let a = (i as Float * 10.).to_radians();
+                Vector3D::new(a.sin(), a.cos(), 0.)
+            })
+            .collect();
+        let clusters = cluster_directions(&around, 36);
+        assert_eq!(clusters.len(), MAX_LEAK_CLUSTERS);
+        let total: Float = clusters.iter().map(|c| c.fraction).sum();
+        assert_close!(total, 1.0, 1e-5);
+    }
+
+    #[test]
+    fn test_leak_in_ceiling() {
+        let sensors = [
+            sensor(Vector3D::new(0., 0., 1.)),
+            sensor(Vector3D::new(0., 0., -1.)),
+        ];
+        let report = enclosure_leak_report(&sensors, &room(true), 20000).unwrap();
+        let up = &report[0];
+        assert_eq!(up.n_samples, 20000);
+        assert!(up.escaped > 0);
+        assert!(up.escaped_fraction() < 0.02);
+        assert_eq!(up.clusters.len(), 1);
+
+        // The leak is towards the East, up through the hole
+        let leak = &up.clusters[0];
+        assert_close!(leak.fraction, up.escaped_fraction(), 1e-6);
+        let (altitude, azimuth) = leak.altitude_azimuth();
+        assert_close!(azimuth, 90., 5.);
+        assert!(altitude > 45. && altitude < 60.);
+        let t = (3. - 0.8) / leak.direction.z;
+        let hit = Point3D::new(2., 2., 0.8) + leak.direction * t;
+        assert!(hit.x > 3.4 && hit.x < 3.9, "hit the ceiling at {:?}", hit);
+        assert_close!(hit.y, 2.0, 0.15);
+        assert!(leak.spread < LEAK_CLUSTER_ANGLE);
+        assert!(format!("{}", up).contains("azimuth 9"));
+
+        // Looking down, nothing escapes
+        assert_eq!(report[1].escaped, 0);
+        assert!(report[1].clusters.is_empty());
+
+        // Nor does it in a closed room
+        let report = enclosure_leak_report(&sensors, &room(false), 2000).unwrap();
+        assert!(report.iter().all(|r| r.escaped == 0));
+        assert_eq!(report[0].escaped_fraction(), 0.0);
+    }
+}
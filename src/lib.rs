@@ -53,3 +53,352 @@ pub use model::SolarModel;
 mod optical_info;
 mod solar_surface;
 pub use optical_info::{IRViewFactorSet, OpticalInfo};
+
+/// Utilities for building and manipulating sky vectors
+pub mod sky;
+pub use sky::{
+    downsample_mf, mirror_bins, patch_overlaps, GroundBinPolicy, SkyBasis, SkyPatch, SunMapping,
+};
+
+/// Loading of Radiance scenes, with diagnostics
+pub mod scene_loading;
+pub use scene_loading::{
+    load_scene, load_scenes, load_scenes_transformed, load_scenes_with_coplanar,
+    load_scenes_with_instances, load_scenes_with_units, load_specular_reflectors, InstanceReport,
+    Instances, MaterialInfo, ObjectId, SceneReport, SurfaceBounds, SurfaceSide,
+};
+
+/// Daylight Coefficient calculations
+pub mod session;
+pub use session::{
+    DCComponents, DCError, DCOptions, DCSession, GlazingModel, TerminationPolicy,
+};
+
+/// Estimation of the resources needed by Daylight Coefficient calculations
+pub mod resources;
+pub use resources::{
+    benchmark_scene, estimate_resources, BudgetAction, RayBudget, ResourceEstimate,
+    MIN_CAPPED_SAMPLES,
+};
+
+/// Sensors and the part of the hemisphere they can see
+pub mod sensor;
+pub use sensor::{AngularMask, ResponseCurve, SensorSpec, TributaryArea};
+
+/// Matrices whose rows describe the sensors they come from
+pub mod labeled_matrix;
+pub use labeled_matrix::{LabeledMatrix, RowMetadata};
+
+/// Tracing of direct Daylight Coefficients
+mod direct;
+
+/// Sparse accumulation of the coefficients of each sensor
+mod accumulator;
+
+/// Single-axis trackers, whose orientation changes over time
+pub mod tracker;
+pub use tracker::{TrackerSpec, TrackingAlgorithm};
+
+/// Application of sky matrices to Daylight Coefficients over many timesteps
+pub mod annual;
+pub use annual::{
+    annual_irradiance, annual_irradiance_in_basis, annual_series, annual_standard_error,
+    apply_annual, tracker_annual_irradiance, uncertainty_band, ApplySky,
+};
+
+/// Deterministic random numbers
+mod rng;
+pub use rng::SamplingSequence;
+
+/// Accumulation of statistics during the calculations
+pub mod stats;
+pub use stats::{CullPolicy, CulledRow, DCStats, NoiseFloor, SampleClamp, Welford};
+
+/// Progressive calculation of Daylight Coefficients
+pub mod progressive;
+pub use progressive::ProgressiveSnapshot;
+
+/// Changes of coordinates and units
+pub mod transform;
+pub use transform::Transform;
+
+/// Sparse storage of Daylight Coefficients
+pub mod sparse;
+pub use sparse::SparseMatrix;
+
+/// Reading and writing matrices in binary format
+pub mod matrix_io;
+
+/// Obstruction angles and no-sky line analysis
+pub mod obstruction;
+pub use obstruction::{max_obstruction_altitude, no_sky_line, SkylineProfile};
+
+/// Environments (e.g., HDR images) that replace the sky patches
+pub mod environment;
+pub use environment::{
+    AngularFisheyeSky, EquirectangularSky, EscapeRadiance, HdrImage, SkyRadiance, UniformSky,
+};
+
+/// Grouping and aggregating results by room or zone
+pub mod zones;
+pub use zones::{
+    aggregate_by_zone, daylight_autonomy, grouped_csv, spatial_daylight_autonomy,
+    zone_annual_summary, zone_dc_rows, ZoneAggregation, ZoneGroups, ZoneMatrix, ZoneSummaries,
+    ZoneSummary,
+};
+
+/// Warnings collected during a calculation
+pub mod events;
+pub use events::{Event, EventKind, EventLog};
+
+/// Incidence angle modifiers for PV workflows
+pub mod iam;
+pub use iam::{annual_irradiance_with_iam, apply_iam, iam_factors, GroundIam, IamModel, IamOptions};
+
+/// Scenes whose acceleration structure is built before tracing
+pub mod prepared_scene;
+pub use prepared_scene::PreparedScene;
+
+/// The contract between direction samplers and the tracer
+pub mod sampling;
+pub use sampling::{
+    checked_direction, chi2_sampler_test, sample_weight, Chi2Report, HemisphereSampler,
+    DEFAULT_MAX_RESAMPLES, DIRECTION_TOLERANCE,
+};
+
+/// Annual metric reports
+pub mod report;
+pub use report::{
+    AnnualReport, DaylightSaving, Histogram, OccupancySchedule, ReportThresholds, SensorMetrics,
+    ZoneMetrics,
+};
+
+/// Units of length of scenes
+pub mod units;
+pub use units::{detect_unit, LengthUnit};
+
+/// Hours of direct sun received by sensors
+pub mod sun_hours;
+pub use sun_hours::{
+    direct_sun_hours, rotate_to_scene, sun_directions, sun_directions_on_axis,
+    sun_hours_from_directions, SunHours,
+};
+
+/// Reference scenes with closed-form results, for validating the estimators
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+/// A canonical side-lit office with reference results, for regression tests
+#[cfg(any(test, feature = "test-support"))]
+pub mod reference_office;
+
+/// Callbacks that decide which rays are traced
+pub mod ray_filter;
+pub use ray_filter::{RayAction, RayFilter};
+
+/// Daylight Coefficients that keep the sky and the ground apart
+pub mod two_sided;
+pub use two_sided::{GroundModel, TwoSidedDC};
+
+/// Sending more samples towards small but important objects
+pub mod importance;
+pub use importance::{BoundingSphere, ImportanceHints};
+
+/// Records of how results were calculated, for reproducing them
+pub mod manifest;
+pub use manifest::{hash_file, hash_sensors, verify_manifest, FileHash, RunManifest};
+
+/// Reflections of the sun on mirrors and glazing
+pub mod specular;
+pub use specular::{specular_sun_irradiance, PlanarReflector, SpecularMaterial};
+
+/// The view of sensors split between the sky, the ground and the surfaces, for
+/// longwave and Mean Radiant Temperature calculations
+pub mod longwave;
+pub use longwave::{view_fractions, ViewFractions, ViewSensor};
+
+/// Saving the progress of long calculations, for resuming them
+pub mod checkpoint;
+pub use checkpoint::{Checkpoint, CheckpointPolicy};
+
+/// The sensitivity of Daylight Coefficients to the reflectance of each material
+pub mod sensitivity;
+pub use sensitivity::ReflectanceTallies;
+
+/// Building scenes programmatically
+pub mod scene_builder;
+pub use scene_builder::{Material, SceneBuilder};
+
+/// Glare sources and Daylight Glare Probability
+pub mod glare;
+pub use glare::{
+    evaluate_glare, evaluate_image, guth_position_index, position_index, GlareCamera,
+    GlareOptions, GlareResult, GlareSource,
+};
+
+/// Finding and fixing coplanar surfaces that overlap
+pub mod coplanar;
+pub use coplanar::{CoplanarFix, CoplanarOptions, CoplanarOverlap, CoplanarPolicy};
+
+/// Daylight Coefficients in several bands of the spectrum (e.g., melanopic)
+pub mod spectral;
+pub use spectral::{
+    melanopic_ratio, SpectralBand, SpectralDC, SpectralOptions, MELANOPIC_RATIO_D65,
+};
+
+/// Running calculations from asynchronous code
+#[cfg(feature = "async")]
+pub mod async_session;
+#[cfg(feature = "async")]
+pub use async_session::{CancellationToken, RunControl, RunProgress};
+
+/// Light-loss factors of apertures, applied to Daylight Coefficients
+pub mod apertures;
+pub use apertures::{ApertureDC, ApertureGroup, LightLossFactors};
+
+/// A database of standard finishes, constructible by name
+pub mod materials;
+pub use materials::MaterialLibrary;
+
+/// Cumulative skies, adding up the sky vectors of a period
+pub mod cumulative;
+pub use cumulative::{cumulative_sky, to_sky_matrix, CumulativeSkyOptions};
+
+/// Diagnostics for energy leaking through unclosed geometry
+pub mod leaks;
+pub use leaks::{enclosure_leak_report, LeakCluster, LeakReport};
+
+/// The timesteps of annual series, and their conversion into dates
+pub mod time;
+pub use time::{
+    is_leap_year, AnnualSeries, DateRange, TimeAxis, Timestamp, Timestep, DAYS_PER_YEAR,
+    HOURS_PER_YEAR,
+};
+
+/// The Daylight Factor under a CIE standard overcast sky
+pub mod daylight_factor;
+pub use daylight_factor::{daylight_factor, DaylightFactorOptions, DaylightFactors};
+
+/// Distant obstructions described by the altitude of the horizon
+pub mod horizon;
+pub use horizon::HorizonProfile;
+
+/// Structured comparisons between Daylight Coefficient matrices
+pub mod compare;
+pub use compare::{compare, compare_matrices, ComparisonOptions, ComparisonReport};
+
+/// Grids of sensors over floor plans, and meshes for plotting their results
+pub mod grid;
+pub use grid::{auto_grids, FloorSelection, SensorGrid, ValueMesh};
+
+/// Parametric studies over many variants of a scene
+pub mod study;
+pub use study::{StudyRunner, StudySky, StudySummary, VariantScene, VariantStatus, VariantSummary};
+
+/// Progress of long calculations, in primary samples, and estimates of when they finish
+pub mod progress;
+pub use progress::{
+    Clock, EtaEstimator, ProgressEstimate, SampleCounter, SampleSnapshot, SystemClock,
+    DEFAULT_RATE_HALF_LIFE,
+};
+
+/// Illuminance at design hours under defined skies, and its compliance with thresholds
+pub mod design_hours;
+pub use design_hours::{
+    design_hours_illuminance, DesignClock, DesignHour, DesignHourIlluminance, DesignHourOptions,
+    DesignThreshold, HourCompliance, SkyCondition,
+};
+
+/// Coarse voxel grids that prove rays hit nothing before they are cast
+pub mod occupancy;
+pub use occupancy::{OccupancyGrid, DEFAULT_OCCUPANCY_RESOLUTION, MAX_OCCUPANCY_RESOLUTION};
+
+/// Electrochromic windows, blending the coefficients of their clear and tinted states
+pub mod electrochromic;
+pub use electrochromic::{ElectrochromicDC, TintControl, TintCurve, TintedSeries};
+
+/// Records of individual paths of the simplified tracer, for debugging
+pub mod path_recorder;
+pub use path_recorder::{PathRecord, PathRecorder, PathVertex, ESCAPE_SEGMENT_LENGTH};
+
+/// Sky matrices that say what their ground bin holds, reconciled with Daylight Coefficients
+pub mod sky_matrix;
+pub use sky_matrix::{GroundConvention, SkyMatrix, DEFAULT_GROUND_ALBEDO};
+
+/// Adding samples to Daylight Coefficient matrices that were already calculated
+pub mod refine;
+
+/// Profiles of per-sensor results against the distance from the façade
+pub mod depth_profile;
+pub use depth_profile::{DepthBin, DepthProfile, Facade, ProfileStatistic};
+
+/// A cache of annual sky matrices on disk, shared across calculations and processes
+pub mod sky_cache;
+pub use sky_cache::{SkyMatrixCache, SkyMatrixKey};
+
+/// Maps of percentiles and exceedance fractions of annual results
+pub mod percentile_map;
+pub use percentile_map::{exceedance_fraction, percentile_map, percentile_maps};
+
+/// How far rays start off surfaces, scaled with the size of the scene
+pub mod ray_offset;
+pub use ray_offset::{
+    RayOffset, DEFAULT_SCENE_EXTENT, MAX_NUDGE_FRACTION, MIN_NUDGE_OFFSETS, RELATIVE_RAY_OFFSET,
+};
+
+/// Annual results as EnergyPlus schedules of the illuminance of reference points
+pub mod energyplus;
+pub use energyplus::{EnergyPlusSchedules, SCHEDULE_HEADER_ROWS};
+
+/// Annual results split into beam, sky-diffuse and ground-reflected components
+pub mod components;
+pub use components::{annual_components, AnnualComponents, ComponentFractions, COMPONENT_TOLERANCE};
+
+/// Casting rays one by one or in packets, into the scene or another backend
+pub mod ray_caster;
+pub use ray_caster::{Hit, RayCaster, PACKET_SIZE};
+
+/// Temporal maps of annual results, by hour of the day and month
+pub mod temporal_map;
+pub use temporal_map::{monthly_temporal_map, temporal_map, TemporalReducer};
+
+/// Fast evaluations of a single sensor under many skies
+pub mod sensor_response;
+pub use sensor_response::{perez_sky_vec, SensorResponse};
+
+/// Projects tying a scene, sensors, options, skies and outputs together
+pub mod project;
+pub use project::{
+    parse_pts, GridRun, Project, ProjectGrid, ProjectOutput, ProjectSky, ProjectSummary,
+    SensorSource,
+};
+
+/// Annual results summarised one timestep at a time, without their whole series
+pub mod streaming;
+pub use streaming::{
+    stream_annual, AnnualAccumulator, AnnualStream, MetricCounters, PeakTracker, PeriodSums,
+};
+
+/// The five-phase method: the sun seen straight, put back with fine coefficients
+pub mod five_phase;
+pub use five_phase::{FivePhaseDC, FivePhaseSkies};
+
+/// Identifiers of sensors, for joining results across runs
+pub mod sensor_id;
+pub use sensor_id::{align, sensors_csv, Alignment, SensorId};
+
+/// Batches of independent items, which carry on when one of them fails
+pub mod batch;
+pub use batch::{BatchError, BatchOutcome};
+
+/// Skies whose rows are subdivided by multipliers of their own
+pub mod adaptive_basis;
+pub use adaptive_basis::AdaptiveRowBasis;
+
+/// The precision of the values of stored matrices
+pub mod dtype;
+pub use dtype::Dtype;
+
+/// Whether sensors see the sun through apertures, for view-based blind triggers
+pub mod sun_visibility;
+pub use sun_visibility::{direct_sun_visibility, SunView, SunVisibility};
@@ -0,0 +1,299 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::rng::SampleStream;
+use crate::scene_loading::SceneReport;
+use crate::sensor::DirectionSampler;
+use crate::session::DCOptions;
+use crate::{Float, PI};
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::{Ray, Scene};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A sensor whose view is split between the sky, the ground and the
+/// surfaces of the scene by [`view_fractions`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewSensor {
+    /// A flat sensor, which weights what it sees by the cosine of the angle with
+    /// its normal. The fractions are thus view factors, as used for the
+    /// longwave exchange of a surface (or of one side of a person).
+    Planar(Ray3D),
+
+    /// A point that sees equally in every direction (e.g., a globe thermometer),
+    /// for which the fractions are solid angles over the whole sphere.
+    Spherical(Point3D),
+}
+
+/// The fractions of the view of a set of sensors occupied by the sky, the ground,
+/// and each group of surfaces of the scene, as calculated by [`view_fractions`].
+/// The surfaces are grouped by their modifier, so that each group can later be
+/// given a temperature (e.g., for calculating the Mean Radiant Temperature).
+#[derive(Debug, Clone)]
+pub struct ViewFractions {
+    /// The name of each column: `sky`, `ground`, and then the modifier of
+    /// each group of surfaces, in the order in which they were loaded
+    pub groups: Vec<String>,
+
+    /// One row per sensor and one column per group. Rows add up to one.
+    pub matrix: Matrix,
+}
+
+impl ViewFractions {
+    /// The column of the sky (i.e., the upper hemisphere, where nothing is hit)
+    pub const SKY: usize = 0;
+
+    /// The column of the ground beyond the scene (i.e., the lower
+    /// hemisphere, where nothing is hit)
+    pub const GROUND: usize = 1;
+
+    /// The column of the surfaces made of `modifier`, if any
+    pub fn group(&self, modifier: &str) -> Option<usize> {
+        self.groups
+            .iter()
+            .skip(2)
+            .position(|g| g == modifier)
+            .map(|i| i + 2)
+    }
+}
+
+/// Calculates the fraction of the view of each sensor occupied by the sky, the
+/// ground and the surfaces made of each modifier of the scene. The `report` must
+/// be the one returned when loading the `scene`, as it says which surface each
+/// triangle belongs to.
+///
+/// Each sensor sends `n_ambient_samples` rays, drawn according to the `sampling`
+/// and `seed` of the `options` (the rest of them are ignored). Rays that escape
+/// upwards count as sky and those that escape downwards count as ground, as
+/// in the view factors of the thermal model.
+pub fn view_fractions(
+    sensors: &[ViewSensor],
+    scene: &Scene,
+    report: &SceneReport,
+    options: &DCOptions,
+) -> Result<ViewFractions, String> {
+    options.validate()?;
+    let triangles = report.triangle_surfaces()?;
+    let mut groups = vec!["sky".to_string(), "ground".to_string()];
+    let mut surface_groups = Vec::with_capacity(report.surfaces.len());
+    for s in &report.surfaces {
+        let column = match groups.iter().skip(2).position(|g| *g == s.modifier) {
+            Some(i) => i + 2,
+            None => {
+                groups.push(s.modifier.clone());
+                groups.len() - 1
+            }
+        };
+        surface_groups.push(column);
+    }
+    let n_groups = groups.len();
+    let n_samples = options.n_ambient_samples;
+
+    let trace = |(index, sensor): (usize, &ViewSensor)| -> Result<Vec<Float>, String> {
+        let (origin, sampler) = match sensor {
+            ViewSensor::Planar(ray) => (
+                ray.origin,
+                Some(DirectionSampler::new(ray.direction, None)?),
+            ),
+            ViewSensor::Spherical(point) => (*point, None),
+        };
+        let stream = SampleStream::new(options.sampling, options.seed, index as u64);
+        let mut aux = Vec::with_capacity(2);
+        let mut counts = vec![0.0; n_groups];
+        for i in 0..n_samples {
+            let (u1, u2) = stream.sample_2d(i as u64);
+            let direction = match &sampler {
+                // Sampled with a probability proportional to the cosine,
+                // so all the samples weigh the same
+                Some(sampler) => sampler.sample(u1, u2).0,
+                None => uniform_sphere(u1, u2),
+            };
+            let mut ray = Ray {
+                geometry: Ray3D { origin, direction },
+                ..Ray::default()
+            };
+            let column = match scene.cast_ray(&mut ray, &mut aux) {
+                Some(triangle) => surface_groups[triangles[triangle]],
+                None if direction.z > 0.0 => ViewFractions::SKY,
+                None => ViewFractions::GROUND,
+            };
+            counts[column] += 1.0;
+        }
+        Ok(counts.iter().map(|c| c / n_samples as Float).collect())
+    };
+    #[cfg(feature = "parallel")]
+    let rows: Vec<Vec<Float>> = if options.serial {
+        sensors
+            .iter()
+            .enumerate()
+            .map(trace)
+            .collect::<Result<_, _>>()?
+    } else {
+        sensors
+            .par_iter()
+            .enumerate()
+            .map(trace)
+            .collect::<Result<_, _>>()?
+    };
+    #[cfg(not(feature = "parallel"))]
+    let rows: Vec<Vec<Float>> = sensors
+        .iter()
+        .enumerate()
+        .map(trace)
+        .collect::<Result<_, _>>()?;
+
+    let mut matrix = Matrix::new(0.0, sensors.len(), n_groups);
+    for (r, row) in rows.iter().enumerate() {
+        for (c, v) in row.iter().enumerate() {
+            matrix.set(r, c, *v)?;
+        }
+    }
+    Ok(ViewFractions { groups, matrix })
+}
+
+/// Transforms two uniform random numbers into a direction that is uniformly
+/// distributed over the sphere
+fn uniform_sphere(u1: Float, u2: Float) -> Vector3D {
+    let z = 1. - 2. * u1;
+    let r = (1. - z * z).max(0.0).sqrt();
+    let (sin, cos) = (2. * PI * u2).sin_cos();
+    Vector3D::new(r * cos, r * sin, z)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::load_scene;
+    use validate::assert_close;
+
+    fn canyon() -> (Scene, SceneReport) {
+        let (mut scene, report) = load_scene("./tests/longwave/canyon.rad").unwrap();
+        scene.build_accelerator();
+        (scene, report)
+    }
+
+    fn options() -> DCOptions {
+        DCOptions {
+            n_ambient_samples: 20000,
+            seed: 3,
+            ..DCOptions::default()
+        }
+    }
+
+    fn row(views: &ViewFractions, r: usize) -> Vec<Float> {
+        (0..views.groups.len())
+            .map(|c| views.matrix.get(r, c).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_uniform_sphere() {
+        let n = 64;
+        let mut mean = Vector3D::new(0., 0., 0.);
+        for i in 0..n {
+            for j in 0..n {
+                let u1 = (i as Float + 0.5) / n as Float;
+                let u2 = (j as Float + 0.5) / n as Float;
+                let dir = uniform_sphere(u1, u2);
+                assert_close!(dir.length(), 1., 1e-5);
+                mean += dir / (n * n) as Float;
+            }
+        }
+        assert!(mean.length() < 1e-3);
+    }
+
+    #[test]
+    fn test_canyon() {
+        // Walls 10 m high, 10 m apart, and 400 m long, over an asphalt street.
+        // Oke (1981): from the middle of the street, an infinite canyon has a sky
+        // view factor of cos(atan(2H/W)), and each wall half of the rest.
+        let (scene, report) = canyon();
+        let sensors = [
+            ViewSensor::Planar(Ray3D {
+                origin: Point3D::new(0., 0., 0.01),
+                direction: Vector3D::new(0., 0., 1.),
+            }),
+            // Halfway up, the walls above are as tall as the canyon is half wide
+            ViewSensor::Planar(Ray3D {
+                origin: Point3D::new(0., 0., 5.),
+                direction: Vector3D::new(0., 0., 1.),
+            }),
+            ViewSensor::Spherical(Point3D::new(0., 0., 0.01)),
+            ViewSensor::Spherical(Point3D::new(0., 0., 5.)),
+        ];
+        let views = view_fractions(&sensors, &scene, &report, &options()).unwrap();
+        assert_eq!(views.groups, vec!["sky", "ground", "brick", "asphalt"]);
+        assert_eq!(views.matrix.size(), (4, 4));
+        let wall = views.group("brick").unwrap();
+        let street = views.group("asphalt").unwrap();
+        assert!(views.group("sky").is_none());
+
+        for r in 0..4 {
+            assert_close!(row(&views, r).iter().sum::<Float>(), 1., 1e-5);
+            // Only through the far ends of the canyon
+            assert!(views.matrix.get(r, ViewFractions::GROUND).unwrap() < 0.01);
+        }
+
+        let sky = |h_over_w: Float| (2. * h_over_w).atan().cos();
+        let at = |r: usize, c: usize| views.matrix.get(r, c).unwrap();
+        assert_close!(at(0, ViewFractions::SKY), sky(1.), 0.02);
+        assert_close!(at(0, wall), 1. - sky(1.), 0.02);
+        assert_eq!(at(0, street), 0.0);
+        assert_close!(at(1, ViewFractions::SKY), sky(0.5), 0.02);
+        assert_close!(at(1, wall), 1. - sky(0.5), 0.02);
+
+        // A sphere sees the sky between altitudes beta and 180 - beta
+        // of the section of the canyon, with tan(beta) = 2H/W
+        let beta = (2. as Float).atan();
+        assert_close!(
+            at(2, ViewFractions::SKY),
+            (PI - 2. * beta) / (2. * PI),
+            0.02
+        );
+        assert_close!(at(2, wall), beta / PI, 0.02);
+        assert_close!(at(2, street), 0.5, 0.02);
+        assert_close!(at(3, ViewFractions::SKY), 0.25, 0.02);
+        assert_close!(at(3, wall), 0.5, 0.02);
+        assert_close!(at(3, street), 0.25, 0.02);
+    }
+
+    #[test]
+    fn test_ground() {
+        // High above the canyon, half of the view is the sky and most of the
+        // other half is the ground beyond it
+        let (scene, report) = canyon();
+        let sensors = [ViewSensor::Spherical(Point3D::new(0., 0., 100.))];
+        let views = view_fractions(&sensors, &scene, &report, &options()).unwrap();
+        assert_close!(views.matrix.get(0, ViewFractions::SKY).unwrap(), 0.5, 0.02);
+        assert!(views.matrix.get(0, ViewFractions::GROUND).unwrap() > 0.3);
+
+        let bad = DCOptions {
+            n_ambient_samples: 0,
+            ..options()
+        };
+        assert!(view_fractions(&sensors, &scene, &report, &bad).is_err());
+
+        let mut report = report;
+        report.n_triangles += 1;
+        assert!(view_fractions(&sensors, &scene, &report, &options()).is_err());
+    }
+}
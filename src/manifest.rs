@@ -0,0 +1,422 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Records of how results were calculated, so that they can be audited and reproduced.
+
+use crate::dtype::Dtype;
+use crate::scene_loading::SceneReport;
+use crate::sensor::{AngularMask, SensorSpec};
+use crate::session::DCOptions;
+use crate::Float;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// The version of this crate
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The git commit this crate was built from, if known. It is taken from the
+/// `LIGHT_GIT_HASH` environment variable at build time, which the build script
+/// sets when building from a git checkout.
+pub const GIT_HASH: Option<&str> = option_env!("LIGHT_GIT_HASH");
+
+/// A 64-bit FNV-1a hash, written in hexadecimal. It is not cryptographic, but it
+/// is stable across platforms and Rust versions, which `std`'s hashers are not.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fnv(u64);
+
+impl Fnv {
+    pub(crate) fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub(crate) fn write_float(&mut self, v: Float) {
+        self.write(&v.to_le_bytes());
+    }
+
+    pub(crate) fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    pub(crate) fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+
+    pub(crate) fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the contents of a file
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String, String> {
+    let path = path.as_ref();
+    let content = std::fs::read(path)
+        .map_err(|e| format!("Unable to read '{}' for hashing: {}", path.display(), e))?;
+    let mut h = Fnv::new();
+    h.write(&content);
+    Ok(h.finish())
+}
+
+/// Hashes a list of sensors: their position, orientation, mask, zone, area, id and
+/// angular response, in order.
+/// Masks given as predicates cannot be inspected, so only their presence counts.
+pub fn hash_sensors(sensors: &[SensorSpec]) -> String {
+    let mut h = Fnv::new();
+    h.write(&(sensors.len() as u64).to_le_bytes());
+    for s in sensors {
+        let (o, d) = (s.ray.origin, s.ray.direction);
+        for v in [o.x, o.y, o.z, d.x, d.y, d.z] {
+            h.write_float(v);
+        }
+        match &s.mask {
+            None => h.write(&[0]),
+            Some(AngularMask::Range {
+                min_altitude,
+                max_altitude,
+                min_azimuth,
+                max_azimuth,
+            }) => {
+                h.write(&[1]);
+                for v in [min_altitude, max_altitude, min_azimuth, max_azimuth] {
+                    h.write_float(*v);
+                }
+            }
+            Some(AngularMask::Predicate(_)) => h.write(&[2]),
+        }
+        match &s.zone {
+            None => h.write(&[0]),
+            Some(z) => {
+                h.write(&[1]);
+                h.write_str(z);
+            }
+        }
+        // Only when present, so sensors without one keep their old hash
+        if let Some(a) = &s.area {
+            h.write(&[1]);
+            for v in [a.u.x, a.u.y, a.u.z, a.v.x, a.v.y, a.v.z] {
+                h.write_float(v);
+            }
+        }
+        if let Some(id) = &s.id {
+            h.write(&[2]);
+            h.write_str(id.as_str());
+        }
+        if let Some(response) = &s.angular_response {
+            h.write(&[3]);
+            for (angle, factor) in response.points() {
+                h.write_float(*angle);
+                h.write_float(*factor);
+            }
+        }
+    }
+    h.finish()
+}
+
+/// A file that was read for a calculation, and the hash of its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHash {
+    /// The path of the file, as it was read
+    pub path: String,
+
+    /// The hash of its contents (see [`hash_file`])
+    pub hash: String,
+}
+
+/// Everything needed for reproducing a result: the version of the code, the
+/// inputs (through their hashes), the configuration and the environment of the run.
+///
+/// [`DCSession::run_manifest`](crate::DCSession::run_manifest) assembles it, and it can be
+/// embedded in text and binary matrix files (see [`crate::matrix_io`]) and in
+/// [`AnnualReport`](crate::AnnualReport)s. [`verify_manifest`] checks whether a set of
+/// inputs is the one that was used.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunManifest {
+    /// The version of this crate (see [`CRATE_VERSION`])
+    pub crate_version: String,
+
+    /// The git commit this crate was built from, if known (see [`GIT_HASH`])
+    pub git_hash: Option<String>,
+
+    /// The scene files that were read, including those included through `!xform`
+    pub scene_files: Vec<FileHash>,
+
+    /// The number of sensors
+    pub n_sensors: usize,
+
+    /// The hash of the sensors (see [`hash_sensors`])
+    pub sensors_hash: String,
+
+    /// The subdivision of the Reinhart sky
+    pub mf: usize,
+
+    /// The options of the calculation, which include the seed
+    pub options: DCOptions,
+
+    /// Whether a [`RayFilter`](crate::RayFilter) was used. Filters are code, so
+    /// they cannot be recorded any further.
+    pub ray_filter: bool,
+
+    /// Whether [`ImportanceHints`](crate::ImportanceHints) were used
+    pub importance_hints: bool,
+
+    /// The features this crate was compiled with (e.g., `parallel`)
+    pub features: Vec<String>,
+
+    /// The [`Float`] of the build that ran the calculation, which bounds the
+    /// precision of the results whatever the type they are stored as. Missing
+    /// in manifests written before it was recorded.
+    #[serde(default)]
+    pub dtype: Option<Dtype>,
+
+    /// The number of threads available for tracing rays
+    pub n_threads: usize,
+
+    /// How long the calculation took, in seconds
+    pub wall_time: Float,
+}
+
+/// The features of this crate that change how results are calculated
+pub(crate) fn enabled_features() -> Vec<String> {
+    let mut ret = Vec::new();
+    if cfg!(feature = "parallel") {
+        ret.push("parallel".to_string());
+    }
+    if cfg!(feature = "float") {
+        ret.push("float".to_string());
+    }
+    ret
+}
+
+impl RunManifest {
+    /// Hashes the files of a scene, returning them in the same order
+    pub(crate) fn hash_scene(scene: &SceneReport) -> Result<Vec<FileHash>, String> {
+        scene
+            .files
+            .iter()
+            .map(|f| {
+                Ok(FileHash {
+                    path: f.clone(),
+                    hash: hash_file(f)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Assembles a manifest with the environment of the current process
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        scene: &SceneReport,
+        sensors: &[SensorSpec],
+        mf: usize,
+        options: DCOptions,
+        ray_filter: bool,
+        importance_hints: bool,
+        n_threads: usize,
+        wall_time: Duration,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            crate_version: CRATE_VERSION.to_string(),
+            git_hash: GIT_HASH.map(|h| h.to_string()),
+            scene_files: Self::hash_scene(scene)?,
+            n_sensors: sensors.len(),
+            sensors_hash: hash_sensors(sensors),
+            mf,
+            options,
+            ray_filter,
+            importance_hints,
+            features: enabled_features(),
+            dtype: Some(Dtype::NATIVE),
+            n_threads,
+            wall_time: wall_time.as_secs_f64() as Float,
+        })
+    }
+
+    /// Serialises the manifest into a single line of JSON
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string(self).map_err(|e| format!("Could not serialise manifest: {}", e))
+    }
+
+    /// Reads a manifest written by [`RunManifest::to_json`]
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("Could not read manifest: {}", e))
+    }
+}
+
+/// Checks that a scene (as reported when loading it again) and a list of sensors are
+/// the ones recorded in a manifest, hashing them again. Returns a description of
+/// every mismatch, so an empty list means that the inputs are the same. Differences
+/// in the version of the code or the features are reported as well.
+///
+/// Returns an error if the files of the scene cannot be read.
+pub fn verify_manifest(
+    manifest: &RunManifest,
+    scene: &SceneReport,
+    sensors: &[SensorSpec],
+) -> Result<Vec<String>, String> {
+    let mut ret = Vec::new();
+    if manifest.crate_version != CRATE_VERSION {
+        ret.push(format!(
+            "Calculated with version {} of the crate, but this is version {}",
+            manifest.crate_version, CRATE_VERSION
+        ));
+    }
+    if let (Some(recorded), Some(current)) = (&manifest.git_hash, GIT_HASH) {
+        if recorded != current {
+            ret.push(format!(
+                "Calculated with commit {}, but this is commit {}",
+                recorded, current
+            ));
+        }
+    }
+    if manifest.features != enabled_features() {
+        ret.push(format!(
+            "Calculated with features {:?}, but these are {:?}",
+            manifest.features,
+            enabled_features()
+        ));
+    }
+
+    let files = RunManifest::hash_scene(scene)?;
+    if files.len() != manifest.scene_files.len() {
+        ret.push(format!(
+            "The scene was read from {} files, but now it is read from {}",
+            manifest.scene_files.len(),
+            files.len()
+        ));
+    }
+    for (recorded, current) in manifest.scene_files.iter().zip(files.iter()) {
+        if recorded.path != current.path {
+            ret.push(format!(
+                "Scene file '{}' is now '{}'",
+                recorded.path, current.path
+            ));
+        } else if recorded.hash != current.hash {
+            ret.push(format!("Scene file '{}' has changed", recorded.path));
+        }
+    }
+
+    if manifest.n_sensors != sensors.len() {
+        ret.push(format!(
+            "There were {} sensors, but now there are {}",
+            manifest.n_sensors,
+            sensors.len()
+        ));
+    } else if manifest.sensors_hash != hash_sensors(sensors) {
+        ret.push("The sensors have changed".to_string());
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::scene_loading::load_scene;
+    use crate::session::DCSession;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+
+    fn sensors() -> Vec<SensorSpec> {
+        (0..3)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(i as Float, 0.5, 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect()
+    }
+
+    fn manifest(report: &SceneReport) -> RunManifest {
+        DCSession::new(1, DCOptions::default())
+            .run_manifest(report, &sensors(), Duration::from_millis(1500))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hash_scene_files() {
+        let dir = std::env::temp_dir().join(format!("light_manifest_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.rad");
+        let original = std::fs::read_to_string("./tests/scene_loading/room.rad").unwrap();
+        std::fs::write(&path, &original).unwrap();
+        let (_, report) = load_scene(&path).unwrap();
+
+        let m = manifest(&report);
+        assert_eq!(m.crate_version, CRATE_VERSION);
+        assert_eq!(m.scene_files.len(), report.files.len());
+        assert_eq!(m.scene_files[0].hash, hash_file(&path).unwrap());
+        assert_eq!(m.scene_files[0].hash.len(), 16);
+        assert_eq!(m.n_threads, crate::resources::n_threads());
+        assert_eq!(m.wall_time, 1.5);
+        assert_eq!(m.features, enabled_features());
+        assert_eq!(m.dtype, Some(Dtype::NATIVE));
+        assert!(verify_manifest(&m, &report, &sensors()).unwrap().is_empty());
+
+        // The same content hashes the same, wherever it is
+        assert_eq!(
+            hash_file(&path).unwrap(),
+            hash_file("./tests/scene_loading/room.rad").unwrap()
+        );
+
+        // Changing a single character is noticed
+        std::fs::write(&path, original.replacen("0.7", "0.8", 1)).unwrap();
+        let mismatches = verify_manifest(&m, &report, &sensors()).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("has changed"));
+
+        // Files that are gone cannot be verified
+        std::fs::remove_file(&path).unwrap();
+        assert!(verify_manifest(&m, &report, &sensors()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_modified_sensors() {
+        let (_, report) = load_scene("./tests/scene_loading/room.rad").unwrap();
+        let m = manifest(&report);
+        assert_eq!(m.sensors_hash, hash_sensors(&sensors()));
+
+        let mut moved = sensors();
+        moved[1].ray.origin.z += 1e-3;
+        let mismatches = verify_manifest(&m, &report, &moved).unwrap();
+        assert_eq!(mismatches, vec!["The sensors have changed".to_string()]);
+
+        let zoned: Vec<SensorSpec> = sensors()
+            .into_iter()
+            .map(|s| s.with_zone("kitchen"))
+            .collect();
+        assert_eq!(verify_manifest(&m, &report, &zoned).unwrap().len(), 1);
+
+        let mut reordered = sensors();
+        reordered.swap(0, 2);
+        assert_eq!(verify_manifest(&m, &report, &reordered).unwrap().len(), 1);
+
+        let fewer = &sensors()[..2];
+        let mismatches = verify_manifest(&m, &report, fewer).unwrap();
+        assert!(mismatches[0].contains("3 sensors"));
+    }
+}
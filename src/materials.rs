@@ -0,0 +1,256 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A database of standard finishes, so that scenes can be described by the
+//! names of their materials rather than by their optical properties.
+//!
+//! The values are typical of the design guides, and are meant for early-stage
+//! studies in which the actual finishes are not yet known.
+
+use crate::scene_builder::{check_name, Material};
+use crate::Float;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The reflectance of [`generic_interior_wall`]
+const INTERIOR_WALL_REFLECTANCE: Float = 0.5;
+
+/// The reflectance of [`generic_ceiling`]
+const CEILING_REFLECTANCE: Float = 0.7;
+
+/// The reflectance of [`generic_floor`]
+const FLOOR_REFLECTANCE: Float = 0.2;
+
+/// The normal visible transmittance of [`clear_double_glazing`]
+const DOUBLE_GLAZING_TRANSMITTANCE: Float = 0.78;
+
+/// The reflectance of [`exterior_ground_grass`]
+const GRASS_REFLECTANCE: Float = 0.2;
+
+/// The reflectance of [`exterior_ground_asphalt`]
+const ASPHALT_REFLECTANCE: Float = 0.1;
+
+/// A painted interior wall, with a reflectance of 0.5
+pub fn generic_interior_wall() -> Material {
+    Material::plastic(INTERIOR_WALL_REFLECTANCE)
+}
+
+/// A white interior ceiling, with a reflectance of 0.7
+pub fn generic_ceiling() -> Material {
+    Material::plastic(CEILING_REFLECTANCE)
+}
+
+/// An interior floor, with a reflectance of 0.2
+pub fn generic_floor() -> Material {
+    Material::plastic(FLOOR_REFLECTANCE)
+}
+
+/// A clear double glazing, with a normal visible transmittance of 0.78
+pub fn clear_double_glazing() -> Material {
+    Material::glass(DOUBLE_GLAZING_TRANSMITTANCE)
+}
+
+/// Grass covering the ground outside, with a reflectance of 0.2
+pub fn exterior_ground_grass() -> Material {
+    Material::plastic(GRASS_REFLECTANCE)
+}
+
+/// Asphalt covering the ground outside, with a reflectance of 0.1
+pub fn exterior_ground_asphalt() -> Material {
+    Material::plastic(ASPHALT_REFLECTANCE)
+}
+
+/// A standard finish: its name and the function that builds it
+type Finish = (&'static str, fn() -> Material);
+
+/// The standard finishes, by name
+const STANDARD: [Finish; 6] = [
+    ("generic_interior_wall", generic_interior_wall),
+    ("generic_ceiling", generic_ceiling),
+    ("generic_floor", generic_floor),
+    ("clear_double_glazing", clear_double_glazing),
+    ("exterior_ground_grass", exterior_ground_grass),
+    ("exterior_ground_asphalt", exterior_ground_asphalt),
+];
+
+impl Material {
+    /// One of the standard finishes (e.g., `"generic_ceiling"`). Fails if
+    /// there is no finish called `name`.
+    pub fn by_name(name: &str) -> Result<Self, String> {
+        MaterialLibrary::standard().get(name)
+    }
+}
+
+/// A collection of named materials, which starts with the standard finishes
+/// and can be extended with custom ones. It can be stored with the rest of
+/// the configuration of a study.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaterialLibrary {
+    materials: BTreeMap<String, Material>,
+}
+
+impl MaterialLibrary {
+    /// A library without any material
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A library with the standard finishes
+    pub fn standard() -> Self {
+        let materials = STANDARD
+            .iter()
+            .map(|(name, material)| (name.to_string(), material()))
+            .collect();
+        Self { materials }
+    }
+
+    /// Adds a custom material called `name`. Fails if the name is already
+    /// taken or cannot be written into a Radiance file, or if the material
+    /// is not valid.
+    pub fn register(&mut self, name: &str, material: Material) -> Result<(), String> {
+        check_name(name)?;
+        material.validate()?;
+        if self.materials.contains_key(name) {
+            return Err(format!("Material '{}' is already in the library", name));
+        }
+        self.materials.insert(name.to_string(), material);
+        Ok(())
+    }
+
+    /// The material called `name`
+    pub fn get(&self, name: &str) -> Result<Material, String> {
+        self.materials.get(name).copied().ok_or_else(|| {
+            format!(
+                "There is no material called '{}' in the library. Available ones are {:?}",
+                name,
+                self.names()
+            )
+        })
+    }
+
+    /// The names of the materials, in alphabetical order
+    pub fn names(&self) -> Vec<&str> {
+        self.materials.keys().map(|k| k.as_str()).collect()
+    }
+
+    /// Serialises the library into JSON
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Could not serialise material library: {}", e))
+    }
+
+    /// Reads a library written by [`MaterialLibrary::to_json`], checking
+    /// its names and materials as [`MaterialLibrary::register`] does
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let library: Self = serde_json::from_str(json)
+            .map_err(|e| format!("Could not read material library: {}", e))?;
+        for (name, material) in library.materials.iter() {
+            check_name(name)?;
+            material.validate()?;
+        }
+        Ok(library)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::SceneBuilder;
+    use geometry3d::Point3D;
+    use validate::assert_close;
+
+    #[test]
+    fn test_by_name() {
+        assert_eq!(
+            Material::by_name("generic_interior_wall").unwrap(),
+            Material::plastic(0.5)
+        );
+        assert_eq!(
+            Material::by_name("clear_double_glazing").unwrap(),
+            Material::glass(0.78)
+        );
+        for (name, material) in STANDARD {
+            assert_eq!(Material::by_name(name).unwrap(), material());
+            assert!(material().validate().is_ok());
+        }
+        assert!(Material::by_name("GENERIC_CEILING").is_err());
+
+        let mut library = MaterialLibrary::standard();
+        library
+            .register("oak_parquet", Material::plastic(0.35))
+            .unwrap();
+        assert_eq!(library.get("oak_parquet").unwrap(), Material::plastic(0.35));
+        assert_eq!(library.names().len(), STANDARD.len() + 1);
+        assert!(library
+            .register("generic_floor", Material::plastic(0.3))
+            .is_err());
+        assert!(library
+            .register("bad name", Material::plastic(0.3))
+            .is_err());
+        assert!(library
+            .register("too_bright", Material::plastic(1.3))
+            .is_err());
+        // Custom entries are only available through the library
+        assert!(Material::by_name("oak_parquet").is_err());
+    }
+
+    #[test]
+    fn test_library_json() {
+        let mut library = MaterialLibrary::standard();
+        library
+            .register("brushed_aluminium", Material::metal(0.8, 0.5, 0.1))
+            .unwrap();
+        let json = library.to_json().unwrap();
+        let read = MaterialLibrary::from_json(&json).unwrap();
+        assert_eq!(read, library);
+        assert!(MaterialLibrary::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_scene_with_named_materials() {
+        let floor = [
+            Point3D::new(0., 0., 0.),
+            Point3D::new(1., 0., 0.),
+            Point3D::new(1., 1., 0.),
+            Point3D::new(0., 1., 0.),
+        ];
+        let window: Vec<Point3D> = floor.iter().map(|p| Point3D::new(p.x, p.y, 3.)).collect();
+        let build = |floor_mat: Material, glass_mat: Material| {
+            let mut builder = SceneBuilder::new();
+            builder.add_material("floor_mat", floor_mat).unwrap();
+            builder.add_material("glass_mat", glass_mat).unwrap();
+            builder.add_polygon("floor_mat", "floor", &floor).unwrap();
+            builder
+                .add_polygon("glass_mat", "skylight", &window)
+                .unwrap();
+            builder
+        };
+        let named = build(
+            Material::by_name("generic_floor").unwrap(),
+            Material::by_name("clear_double_glazing").unwrap(),
+        );
+        let explicit = build(Material::plastic(0.2), Material::glass(0.78));
+        assert_eq!(named.to_radiance(), explicit.to_radiance());
+
+        let (_, report) = named.build().unwrap();
+        assert_close!(report.materials[0].rgb[0], 0.2, 1e-6);
+        assert_eq!(report.materials[1].kind, "glass");
+    }
+}
@@ -0,0 +1,1001 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::dtype::Dtype;
+use crate::manifest::RunManifest;
+use crate::sky::{GroundBinPolicy, SkyBasis};
+use crate::sparse::SparseMatrix;
+use crate::Float;
+use matrix::Matrix;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+/// The first bytes of every binary matrix file
+const MAGIC: &[u8; 8] = b"SLDCMTX\0";
+
+/// The version of the binary format. Version 2 added a metadata block
+/// (i.e., a [`RunManifest`]) after the size of the matrix. Version 3 added the
+/// [`Dtype`] of the build that wrote the file after the type of its elements.
+const VERSION: u8 = 3;
+
+/// The header variable of text matrices that holds the [`RunManifest`]
+const MANIFEST_VARIABLE: &str = "LIGHT_MANIFEST=";
+
+/// The header variable of text matrices that says where their ground bin is
+const GROUND_BIN_VARIABLE: &str = "GROUND_BIN=";
+
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+
+/// A matrix read from a binary file, which can be stored
+/// either densely or sparsely
+#[derive(Debug, Clone)]
+pub enum StoredMatrix {
+    /// A dense matrix
+    Dense(Matrix),
+    /// A sparse matrix
+    Sparse(SparseMatrix),
+}
+
+impl StoredMatrix {
+    /// Converts into a dense matrix, whatever the storage
+    pub fn into_dense(self) -> Result<Matrix, String> {
+        match self {
+            Self::Dense(m) => Ok(m),
+            Self::Sparse(m) => m.to_dense(),
+        }
+    }
+}
+
+fn io_err(e: std::io::Error) -> String {
+    format!("Error while reading or writing a matrix: {}", e)
+}
+
+/// The byte of the header that stores the subdivision of the sky of
+/// Daylight Coefficient matrices. It is `0` for other matrices, and for
+/// subdivisions that do not fit in it.
+fn mf_byte(basis: Option<&SkyBasis>) -> u8 {
+    basis.map_or(0, |b| u8::try_from(b.mf()).unwrap_or(0))
+}
+
+/// The optional information written along with the values of a matrix
+#[derive(Clone, Copy, Default)]
+struct Metadata<'a> {
+    basis: Option<&'a SkyBasis>,
+    manifest: Option<&'a RunManifest>,
+    /// The type the elements are stored as, [`Dtype::NATIVE`] by default
+    dtype: Option<Dtype>,
+}
+
+fn write_header<W: Write>(
+    w: &mut W,
+    kind: u8,
+    nrows: usize,
+    ncols: usize,
+    meta: Metadata,
+) -> Result<(), String> {
+    w.write_all(MAGIC).map_err(io_err)?;
+    let dtype = meta.dtype.unwrap_or(Dtype::NATIVE);
+    w.write_all(&[
+        VERSION,
+        kind,
+        dtype.bytes(),
+        mf_byte(meta.basis),
+        Dtype::NATIVE.bytes(),
+    ])
+    .map_err(io_err)?;
+    w.write_all(&(nrows as u64).to_le_bytes()).map_err(io_err)?;
+    w.write_all(&(ncols as u64).to_le_bytes()).map_err(io_err)?;
+    let manifest = match meta.manifest {
+        Some(m) => m.to_json()?,
+        None => String::new(),
+    };
+    w.write_all(&(manifest.len() as u64).to_le_bytes())
+        .map_err(io_err)?;
+    w.write_all(manifest.as_bytes()).map_err(io_err)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn write_float<W: Write>(w: &mut W, v: Float, dtype: Dtype) -> Result<(), String> {
+    match dtype {
+        Dtype::F32 => w.write_all(&(v as f32).to_le_bytes()),
+        Dtype::F64 => w.write_all(&(v as f64).to_le_bytes()),
+    }
+    .map_err(io_err)
+}
+
+/// Writes a dense matrix in binary format
+pub fn write_dense_binary<W: Write>(w: &mut W, m: &Matrix) -> Result<(), String> {
+    write_dense(w, m, Metadata::default())
+}
+
+/// Like [`write_dense_binary`], but stores the elements as `dtype` whatever
+/// the [`Float`] of this build (e.g., `f32` for halving the size of the file)
+pub fn write_dense_binary_as<W: Write>(w: &mut W, m: &Matrix, dtype: Dtype) -> Result<(), String> {
+    let meta = Metadata {
+        dtype: Some(dtype),
+        ..Metadata::default()
+    };
+    write_dense(w, m, meta)
+}
+
+/// Writes a dense Daylight Coefficient matrix in binary format, checking that it
+/// follows `basis` and recording its subdivision (see [`read_dc_binary`])
+pub fn write_dc_binary<W: Write>(w: &mut W, dc: &Matrix, basis: &SkyBasis) -> Result<(), String> {
+    basis.check_dc(dc)?;
+    let meta = Metadata {
+        basis: Some(basis),
+        ..Metadata::default()
+    };
+    write_dense(w, dc, meta)
+}
+
+/// Like [`write_dc_binary`], but also embeds the [`RunManifest`] of the
+/// calculation (see [`read_binary_with_manifest`])
+pub fn write_dc_binary_with_manifest<W: Write>(
+    w: &mut W,
+    dc: &Matrix,
+    basis: &SkyBasis,
+    manifest: &RunManifest,
+) -> Result<(), String> {
+    basis.check_dc(dc)?;
+    let meta = Metadata {
+        basis: Some(basis),
+        manifest: Some(manifest),
+        dtype: None,
+    };
+    write_dense(w, dc, meta)
+}
+
+fn write_dense<W: Write>(w: &mut W, m: &Matrix, meta: Metadata) -> Result<(), String> {
+    let (nrows, ncols) = m.size();
+    write_header(w, DENSE, nrows, ncols, meta)?;
+    let dtype = meta.dtype.unwrap_or(Dtype::NATIVE);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            write_float(w, m.get(r, c)?, dtype)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a sparse matrix in binary format
+pub fn write_sparse_binary<W: Write>(w: &mut W, m: &SparseMatrix) -> Result<(), String> {
+    write_sparse(w, m, Metadata::default())
+}
+
+/// Like [`write_sparse_binary`], but stores the values as `dtype` (see
+/// [`write_dense_binary_as`])
+pub fn write_sparse_binary_as<W: Write>(
+    w: &mut W,
+    m: &SparseMatrix,
+    dtype: Dtype,
+) -> Result<(), String> {
+    let meta = Metadata {
+        dtype: Some(dtype),
+        ..Metadata::default()
+    };
+    write_sparse(w, m, meta)
+}
+
+/// Like [`write_dc_binary`], for sparse Daylight Coefficient matrices
+pub fn write_sparse_dc_binary<W: Write>(
+    w: &mut W,
+    dc: &SparseMatrix,
+    basis: &SkyBasis,
+) -> Result<(), String> {
+    let (_, ncols) = dc.size();
+    if ncols != basis.n_bins() {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} bins, but a sky with MF {} has {}",
+            ncols,
+            basis.mf(),
+            basis.n_bins()
+        ));
+    }
+    let meta = Metadata {
+        basis: Some(basis),
+        ..Metadata::default()
+    };
+    write_sparse(w, dc, meta)
+}
+
+fn write_sparse<W: Write>(w: &mut W, m: &SparseMatrix, meta: Metadata) -> Result<(), String> {
+    let (nrows, ncols) = m.size();
+    write_header(w, SPARSE, nrows, ncols, meta)?;
+    let (row_ptr, col_idx, values) = m.parts();
+    w.write_all(&(values.len() as u64).to_le_bytes())
+        .map_err(io_err)?;
+    for p in row_ptr {
+        w.write_all(&(*p as u64).to_le_bytes()).map_err(io_err)?;
+    }
+    for c in col_idx {
+        w.write_all(&(*c as u32).to_le_bytes()).map_err(io_err)?;
+    }
+    let dtype = meta.dtype.unwrap_or(Dtype::NATIVE);
+    for v in values {
+        write_float(w, *v, dtype)?;
+    }
+    Ok(())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, String> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, String> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Reads a float that was stored as `dtype`
+fn read_float<R: Read>(r: &mut R, dtype: Dtype) -> Result<Float, String> {
+    match dtype {
+        Dtype::F32 => {
+            let mut buf = [0u8; 4];
+            r.read_exact(&mut buf).map_err(io_err)?;
+            Ok(f32::from_le_bytes(buf) as Float)
+        }
+        Dtype::F64 => {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf).map_err(io_err)?;
+            Ok(f64::from_le_bytes(buf) as Float)
+        }
+    }
+}
+
+/// A matrix read from a binary file, together with everything the file says
+/// about it
+#[derive(Debug, Clone)]
+pub struct BinaryMatrix {
+    /// The matrix, converted into the [`Float`] of this build
+    pub matrix: StoredMatrix,
+
+    /// The type its elements were stored as
+    pub dtype: Dtype,
+
+    /// The [`Float`] of the build that wrote the file, unknown for files
+    /// written before version 3 of the format
+    pub written_by: Option<Dtype>,
+
+    /// The [`RunManifest`] embedded in the file, if any
+    pub manifest: Option<RunManifest>,
+
+    /// The ways in which precision was lost, either when storing the matrix
+    /// or when reading it into this build
+    pub warnings: Vec<String>,
+}
+
+impl BinaryMatrix {
+    /// Forwards the warnings to the `log` crate, if the `log` feature is
+    /// enabled, and drops them
+    fn into_parts(self) -> (StoredMatrix, Option<RunManifest>) {
+        #[cfg(feature = "log")]
+        for w in &self.warnings {
+            log::warn!("{}", w);
+        }
+        (self.matrix, self.manifest)
+    }
+}
+
+/// Describes the precision lost by a matrix stored as `dtype`, which was
+/// calculated by a build with `calculated_as`, when reading it into this build
+fn precision_warnings(dtype: Dtype, calculated_as: Option<Dtype>) -> Vec<String> {
+    let mut ret = Vec::new();
+    if let Some(c) = calculated_as {
+        if c > dtype {
+            ret.push(format!(
+                "The matrix was calculated as {} but stored as {}, so it only has the precision of {}",
+                c, dtype, dtype
+            ));
+        }
+    }
+    if dtype > Dtype::NATIVE {
+        ret.push(format!(
+            "The matrix was stored as {}, and its values were rounded to the {} of this build",
+            dtype,
+            Dtype::NATIVE
+        ));
+    }
+    ret
+}
+
+/// Reads a matrix written by [`write_dense_binary`] or [`write_sparse_binary`]. Files
+/// written with a different `Float` are converted (see [`read_binary_tagged`]).
+pub fn read_binary<R: Read>(r: &mut R) -> Result<StoredMatrix, String> {
+    read_binary_with_manifest(r).map(|(m, _)| m)
+}
+
+/// Like [`read_binary`], but also returns the [`RunManifest`] embedded in
+/// the file, if any
+pub fn read_binary_with_manifest<R: Read>(
+    r: &mut R,
+) -> Result<(StoredMatrix, Option<RunManifest>), String> {
+    read(r).map(|(m, _)| m.into_parts())
+}
+
+/// Like [`read_binary`], but also returns the type the elements were stored
+/// as, the [`Float`] of the build that wrote them and whether precision was lost.
+/// The type that bounds the precision of the calculation is the one of its
+/// [`RunManifest`] if there is one, and that of the build that wrote the file otherwise.
+pub fn read_binary_tagged<R: Read>(r: &mut R) -> Result<BinaryMatrix, String> {
+    read(r).map(|(m, _)| m)
+}
+
+/// Reads a Daylight Coefficient matrix, checking that it follows `basis`. Files
+/// written by [`write_dc_binary`] also record the subdivision of their sky,
+/// which must match.
+pub fn read_dc_binary<R: Read>(r: &mut R, basis: &SkyBasis) -> Result<StoredMatrix, String> {
+    let (m, mf) = read(r)?;
+    let (m, _) = m.into_parts();
+    if mf != 0 && mf as usize != basis.mf() {
+        return Err(format!(
+            "Daylight Coefficient matrix was calculated with MF {}, but MF {} was expected",
+            mf,
+            basis.mf()
+        ));
+    }
+    let ncols = match &m {
+        StoredMatrix::Dense(m) => m.size().1,
+        StoredMatrix::Sparse(m) => m.size().1,
+    };
+    if ncols != basis.n_bins() {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} bins, but a sky with MF {} has {}",
+            ncols,
+            basis.mf(),
+            basis.n_bins()
+        ));
+    }
+    Ok(m)
+}
+
+/// Reads a matrix, together with the subdivision of its sky (`0` if unknown)
+fn read<R: Read>(r: &mut R) -> Result<(BinaryMatrix, u8), String> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic).map_err(io_err)?;
+    if &magic != MAGIC {
+        return Err("Not a binary matrix file".to_string());
+    }
+    let mut info = [0u8; 4];
+    r.read_exact(&mut info).map_err(io_err)?;
+    let [version, kind, float_bytes, mf] = info;
+    if version == 0 || version > VERSION {
+        return Err(format!("Unsupported binary matrix version {}", version));
+    }
+    let dtype = Dtype::from_bytes(float_bytes)?;
+    let mut written_by = None;
+    if version >= 3 {
+        let mut byte = [0u8; 1];
+        r.read_exact(&mut byte).map_err(io_err)?;
+        written_by = Some(Dtype::from_bytes(byte[0])?);
+    }
+    let nrows = read_u64(r)? as usize;
+    let ncols = read_u64(r)? as usize;
+    let mut manifest = None;
+    if version >= 2 {
+        let len = read_u64(r)? as usize;
+        let mut buf = Vec::new();
+        r.take(len as u64).read_to_end(&mut buf).map_err(io_err)?;
+        if buf.len() != len {
+            return Err("Corrupt metadata in binary matrix file".to_string());
+        }
+        if len > 0 {
+            let json = String::from_utf8(buf)
+                .map_err(|_| "Corrupt metadata in binary matrix file".to_string())?;
+            manifest = Some(RunManifest::from_json(&json)?);
+        }
+    }
+    let matrix = match kind {
+        DENSE => {
+            let mut m = Matrix::new(0.0, nrows, ncols);
+            for row in 0..nrows {
+                for col in 0..ncols {
+                    m.set(row, col, read_float(r, dtype)?)?;
+                }
+            }
+            StoredMatrix::Dense(m)
+        }
+        SPARSE => {
+            let nnz = read_u64(r)? as usize;
+            let row_ptr = (0..=nrows)
+                .map(|_| read_u64(r).map(|v| v as usize))
+                .collect::<Result<Vec<usize>, String>>()?;
+            let col_idx = (0..nnz)
+                .map(|_| read_u32(r).map(|v| v as usize))
+                .collect::<Result<Vec<usize>, String>>()?;
+            let values = (0..nnz)
+                .map(|_| read_float(r, dtype))
+                .collect::<Result<Vec<Float>, String>>()?;
+            let m = SparseMatrix::from_parts(ncols, row_ptr, col_idx, values)?;
+            if m.nrows() != nrows {
+                return Err("Corrupt sparse matrix file".to_string());
+            }
+            StoredMatrix::Sparse(m)
+        }
+        k => return Err(format!("Unknown kind of matrix {} in binary file", k)),
+    };
+    let calculated_as = manifest.as_ref().and_then(|m| m.dtype).or(written_by);
+    let warnings = precision_warnings(dtype, calculated_as);
+    let m = BinaryMatrix {
+        matrix,
+        dtype,
+        written_by,
+        manifest,
+        warnings,
+    };
+    Ok((m, mf))
+}
+
+/// Saves a dense matrix into a binary file
+pub fn save_dense_binary<P: AsRef<Path>>(path: P, m: &Matrix) -> Result<(), String> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+    write_dense_binary(&mut file, m)?;
+    file.flush().map_err(io_err)
+}
+
+/// Saves a sparse matrix into a binary file
+pub fn save_sparse_binary<P: AsRef<Path>>(path: P, m: &SparseMatrix) -> Result<(), String> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+    write_sparse_binary(&mut file, m)?;
+    file.flush().map_err(io_err)
+}
+
+/// Saves a dense Daylight Coefficient matrix into a binary file. See [`write_dc_binary`].
+pub fn save_dc_binary<P: AsRef<Path>>(
+    path: P,
+    dc: &Matrix,
+    basis: &SkyBasis,
+) -> Result<(), String> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+    write_dc_binary(&mut file, dc, basis)?;
+    file.flush().map_err(io_err)
+}
+
+/// Loads a binary matrix file
+pub fn load_binary<P: AsRef<Path>>(path: P) -> Result<StoredMatrix, String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    read_binary(&mut file)
+}
+
+/// Loads a binary matrix file, with everything it says about the matrix. See
+/// [`read_binary_tagged`].
+pub fn load_binary_tagged<P: AsRef<Path>>(path: P) -> Result<BinaryMatrix, String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    read_binary_tagged(&mut file)
+}
+
+/// Loads a binary Daylight Coefficient matrix file. See [`read_dc_binary`].
+pub fn load_dc_binary<P: AsRef<Path>>(path: P, basis: &SkyBasis) -> Result<StoredMatrix, String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    read_dc_binary(&mut file, basis)
+}
+
+/// Saves a dense Daylight Coefficient matrix and the [`RunManifest`] of its
+/// calculation into a binary file. See [`write_dc_binary_with_manifest`].
+pub fn save_dc_binary_with_manifest<P: AsRef<Path>>(
+    path: P,
+    dc: &Matrix,
+    basis: &SkyBasis,
+    manifest: &RunManifest,
+) -> Result<(), String> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+    write_dc_binary_with_manifest(&mut file, dc, basis, manifest)?;
+    file.flush().map_err(io_err)
+}
+
+/// Loads a binary matrix file, together with its manifest (if any). See
+/// [`read_binary_with_manifest`].
+pub fn load_binary_with_manifest<P: AsRef<Path>>(
+    path: P,
+) -> Result<(StoredMatrix, Option<RunManifest>), String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    read_binary_with_manifest(&mut file)
+}
+
+/// Writes a matrix in Radiance's text format (i.e., as produced by `rmtxop` or
+/// `rfluxmtx` with `-fa`), with a single component. The [`RunManifest`], if any,
+/// is embedded as a header line of JSON, which Radiance tools carry along.
+pub fn write_mtx<W: Write>(
+    w: &mut W,
+    m: &Matrix,
+    manifest: Option<&RunManifest>,
+) -> Result<(), String> {
+    write_text(w, m, manifest, None)
+}
+
+/// Writes a Daylight Coefficient matrix in Radiance's text format (see
+/// [`write_mtx`]), with its columns laid out as the [`GroundBinPolicy`] of
+/// the `basis` says. The policy is recorded in the header, so
+/// [`read_dc_mtx`] can tell where the ground is.
+pub fn write_dc_mtx<W: Write>(
+    w: &mut W,
+    dc: &Matrix,
+    basis: &SkyBasis,
+    manifest: Option<&RunManifest>,
+) -> Result<(), String> {
+    let external = basis.export_dc(dc)?;
+    write_text(w, &external, manifest, Some(basis.ground_policy()))
+}
+
+/// Like [`write_dc_mtx`], for sky vectors and matrices (one row per bin)
+pub fn write_sky_mtx<W: Write>(
+    w: &mut W,
+    sky: &Matrix,
+    basis: &SkyBasis,
+    manifest: Option<&RunManifest>,
+) -> Result<(), String> {
+    let external = basis.export_sky(sky)?;
+    write_text(w, &external, manifest, Some(basis.ground_policy()))
+}
+
+fn write_text<W: Write>(
+    w: &mut W,
+    m: &Matrix,
+    manifest: Option<&RunManifest>,
+    ground: Option<GroundBinPolicy>,
+) -> Result<(), String> {
+    let (nrows, ncols) = m.size();
+    writeln!(w, "#?RADIANCE").map_err(io_err)?;
+    if let Some(manifest) = manifest {
+        writeln!(w, "{}{}", MANIFEST_VARIABLE, manifest.to_json()?).map_err(io_err)?;
+    }
+    if let Some(ground) = ground {
+        writeln!(w, "{}{}", GROUND_BIN_VARIABLE, ground.name()).map_err(io_err)?;
+    }
+    writeln!(
+        w,
+        "NROWS={}\nNCOLS={}\nNCOMP=1\nFORMAT=ascii\n",
+        nrows, ncols
+    )
+    .map_err(io_err)?;
+    for r in 0..nrows {
+        let row = (0..ncols)
+            .map(|c| m.get(r, c).map(|v| format!("{}", v)))
+            .collect::<Result<Vec<String>, String>>()?;
+        writeln!(w, "{}", row.join("\t")).map_err(io_err)?;
+    }
+    Ok(())
+}
+
+/// Reads a matrix written by [`write_mtx`] (or any single-component text matrix
+/// written by Radiance), together with its [`RunManifest`], if any
+pub fn read_mtx<R: BufRead>(r: &mut R) -> Result<(Matrix, Option<RunManifest>), String> {
+    let (m, manifest, _) = read_text(r)?;
+    Ok((m, manifest))
+}
+
+/// Reads a Daylight Coefficient matrix written by [`write_dc_mtx`], with the
+/// ground moved back to the first column. Where the ground is comes from the
+/// header of the file or, for files that do not say, from the
+/// [`GroundBinPolicy`] of the `basis`.
+pub fn read_dc_mtx<R: BufRead>(
+    r: &mut R,
+    basis: &SkyBasis,
+) -> Result<(Matrix, Option<RunManifest>), String> {
+    let (m, manifest, ground) = read_text(r)?;
+    let basis = basis.with_ground_policy(ground.unwrap_or_else(|| basis.ground_policy()));
+    Ok((basis.import_dc(&m)?, manifest))
+}
+
+/// Like [`read_dc_mtx`], for sky vectors and matrices (one row per bin)
+pub fn read_sky_mtx<R: BufRead>(
+    r: &mut R,
+    basis: &SkyBasis,
+) -> Result<(Matrix, Option<RunManifest>), String> {
+    let (m, manifest, ground) = read_text(r)?;
+    let basis = basis.with_ground_policy(ground.unwrap_or_else(|| basis.ground_policy()));
+    Ok((basis.import_sky(&m)?, manifest))
+}
+
+#[allow(clippy::type_complexity)]
+fn read_text<R: BufRead>(
+    r: &mut R,
+) -> Result<(Matrix, Option<RunManifest>, Option<GroundBinPolicy>), String> {
+    let (mut nrows, mut ncols, mut ncomp) = (None, None, 1);
+    let mut manifest = None;
+    let mut ground = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line).map_err(io_err)? == 0 {
+            return Err("Unexpected end of file in matrix header".to_string());
+        }
+        let l = line.trim_end();
+        if l.is_empty() {
+            break;
+        }
+        let number = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid matrix header line '{}'", l))
+        };
+        if let Some(v) = l.strip_prefix("NROWS=") {
+            nrows = Some(number(v)?);
+        } else if let Some(v) = l.strip_prefix("NCOLS=") {
+            ncols = Some(number(v)?);
+        } else if let Some(v) = l.strip_prefix("NCOMP=") {
+            ncomp = number(v)?;
+        } else if let Some(v) = l.strip_prefix("FORMAT=") {
+            if v.trim() != "ascii" {
+                return Err(format!("Unsupported matrix format '{}'", v.trim()));
+            }
+        } else if let Some(v) = l.strip_prefix(MANIFEST_VARIABLE) {
+            manifest = Some(RunManifest::from_json(v)?);
+        } else if let Some(v) = l.strip_prefix(GROUND_BIN_VARIABLE) {
+            ground = Some(GroundBinPolicy::from_name(v)?);
+        }
+    }
+    let (nrows, ncols) = match (nrows, ncols) {
+        (Some(r), Some(c)) => (r, c),
+        _ => return Err("Matrix header does not declare NROWS and NCOLS".to_string()),
+    };
+    if ncomp != 1 {
+        return Err(format!(
+            "Only single-component matrices are supported, found NCOMP={}",
+            ncomp
+        ));
+    }
+    let mut content = String::new();
+    r.read_to_string(&mut content).map_err(io_err)?;
+    let values = content
+        .split_whitespace()
+        .map(|v| {
+            v.parse::<Float>()
+                .map_err(|_| format!("Invalid value '{}' in matrix", v))
+        })
+        .collect::<Result<Vec<Float>, String>>()?;
+    if values.len() != nrows * ncols {
+        return Err(format!(
+            "Matrix should have {} values, but it has {}",
+            nrows * ncols,
+            values.len()
+        ));
+    }
+    let mut m = Matrix::new(0.0, nrows, ncols);
+    for (i, v) in values.iter().enumerate() {
+        m.set(i / ncols, i % ncols, *v)?;
+    }
+    Ok((m, manifest, ground))
+}
+
+/// Saves a matrix into a text file. See [`write_mtx`].
+pub fn save_mtx<P: AsRef<Path>>(
+    path: P,
+    m: &Matrix,
+    manifest: Option<&RunManifest>,
+) -> Result<(), String> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+    write_mtx(&mut file, m, manifest)?;
+    file.flush().map_err(io_err)
+}
+
+/// Loads a text matrix file. See [`read_mtx`].
+pub fn load_mtx<P: AsRef<Path>>(path: P) -> Result<(Matrix, Option<RunManifest>), String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+    read_mtx(&mut file)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    fn example() -> Matrix {
+        let mut m = Matrix::new(0.0, 3, 5);
+        m.set(0, 1, 1.5).unwrap();
+        m.set(2, 4, -2.).unwrap();
+        m
+    }
+
+    fn assert_same(a: &Matrix, b: &Matrix) {
+        assert_eq!(a.size(), b.size());
+        let (nrows, ncols) = a.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(a.get(r, c).unwrap(), b.get(r, c).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_dense_round_trip() {
+        let m = example();
+        let mut buf = Vec::new();
+        write_dense_binary(&mut buf, &m).unwrap();
+        let back = read_binary(&mut buf.as_slice()).unwrap();
+        assert!(matches!(back, StoredMatrix::Dense(_)));
+        assert_same(&back.into_dense().unwrap(), &m);
+    }
+
+    #[test]
+    fn test_dc_round_trip() {
+        let basis = SkyBasis::new(1).unwrap();
+        let dc = Matrix::new(0.5, 2, basis.n_bins());
+        let mut buf = Vec::new();
+        write_dc_binary(&mut buf, &dc, &basis).unwrap();
+        let back = read_dc_binary(&mut buf.as_slice(), &basis).unwrap();
+        assert_same(&back.into_dense().unwrap(), &dc);
+
+        // The subdivision is recorded, and checked
+        let other = SkyBasis::new(2).unwrap();
+        let err = read_dc_binary(&mut buf.as_slice(), &other).unwrap_err();
+        assert!(err.contains("MF 1"));
+        assert!(read_binary(&mut buf.as_slice()).is_ok());
+        assert!(write_dc_binary(&mut Vec::new(), &dc, &other).is_err());
+
+        // Files without a subdivision are checked by their number of columns
+        let mut buf = Vec::new();
+        write_dense_binary(&mut buf, &dc).unwrap();
+        assert!(read_dc_binary(&mut buf.as_slice(), &basis).is_ok());
+        assert!(read_dc_binary(&mut buf.as_slice(), &other).is_err());
+        let mut buf = Vec::new();
+        write_dense_binary(&mut buf, &example()).unwrap();
+        assert!(read_dc_binary(&mut buf.as_slice(), &basis).is_err());
+
+        let sparse = SparseMatrix::from_dense(&dc, 0.0).unwrap();
+        let mut buf = Vec::new();
+        write_sparse_dc_binary(&mut buf, &sparse, &basis).unwrap();
+        assert!(read_dc_binary(&mut buf.as_slice(), &basis).is_ok());
+        assert!(read_dc_binary(&mut buf.as_slice(), &other).is_err());
+    }
+
+    #[test]
+    fn test_sparse_round_trip() {
+        let m = example();
+        let sparse = SparseMatrix::from_dense(&m, 0.0).unwrap();
+        let mut buf = Vec::new();
+        write_sparse_binary(&mut buf, &sparse).unwrap();
+        let back = read_binary(&mut buf.as_slice()).unwrap();
+        match &back {
+            StoredMatrix::Sparse(s) => assert_eq!(s, &sparse),
+            _ => panic!("Expecting a sparse matrix"),
+        }
+        assert_same(&back.into_dense().unwrap(), &m);
+
+        // The sparse file is smaller
+        let mut dense_buf = Vec::new();
+        let big = Matrix::new(0.0, 50, 1000);
+        write_dense_binary(&mut dense_buf, &big).unwrap();
+        let mut sparse_buf = Vec::new();
+        write_sparse_binary(
+            &mut sparse_buf,
+            &SparseMatrix::from_dense(&big, 0.0).unwrap(),
+        )
+        .unwrap();
+        assert!(sparse_buf.len() * 100 < dense_buf.len());
+    }
+
+    fn example_manifest() -> RunManifest {
+        RunManifest {
+            crate_version: crate::manifest::CRATE_VERSION.to_string(),
+            git_hash: None,
+            scene_files: vec![crate::manifest::FileHash {
+                path: "a.rad".to_string(),
+                hash: "0123456789abcdef".to_string(),
+            }],
+            n_sensors: 3,
+            sensors_hash: "fedcba9876543210".to_string(),
+            mf: 1,
+            options: crate::session::DCOptions::default(),
+            ray_filter: false,
+            importance_hints: false,
+            features: vec!["parallel".to_string()],
+            dtype: Some(Dtype::F64),
+            n_threads: 4,
+            wall_time: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let basis = SkyBasis::new(1).unwrap();
+        let dc = Matrix::new(0.25, 3, basis.n_bins());
+        let manifest = example_manifest();
+
+        // Binary files
+        let mut buf = Vec::new();
+        write_dc_binary_with_manifest(&mut buf, &dc, &basis, &manifest).unwrap();
+        let (back, read_manifest) = read_binary_with_manifest(&mut buf.as_slice()).unwrap();
+        assert_same(&back.into_dense().unwrap(), &dc);
+        assert_eq!(read_manifest, Some(manifest.clone()));
+        assert!(read_dc_binary(&mut buf.as_slice(), &basis).is_ok());
+
+        // The manifest says how the matrix was calculated
+        let mut buf = Vec::new();
+        let meta = Metadata {
+            manifest: Some(&manifest),
+            dtype: Some(Dtype::F32),
+            ..Metadata::default()
+        };
+        write_dense(&mut buf, &dc, meta).unwrap();
+        let back = read_binary_tagged(&mut buf.as_slice()).unwrap();
+        assert_eq!(back.dtype, Dtype::F32);
+        assert!(back.warnings[0].contains("calculated as f64"));
+
+        let mut buf = Vec::new();
+        write_dc_binary(&mut buf, &dc, &basis).unwrap();
+        let (_, read_manifest) = read_binary_with_manifest(&mut buf.as_slice()).unwrap();
+        assert!(read_manifest.is_none());
+
+        // Files of the first version have no metadata block
+        let mut old = buf[..12].to_vec();
+        old[8] = 1;
+        old.extend_from_slice(&buf[13..29]);
+        old.extend_from_slice(&buf[37..]);
+        assert_same(
+            &read_binary(&mut old.as_slice())
+                .unwrap()
+                .into_dense()
+                .unwrap(),
+            &dc,
+        );
+
+        // Text files
+        let m = example();
+        let mut buf = Vec::new();
+        write_mtx(&mut buf, &m, Some(&manifest)).unwrap();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.starts_with("#?RADIANCE\nLIGHT_MANIFEST={"));
+        assert!(text.contains("NROWS=3\nNCOLS=5\nNCOMP=1\nFORMAT=ascii\n\n"));
+        let (back, read_manifest) = read_mtx(&mut buf.as_slice()).unwrap();
+        assert_same(&back, &m);
+        assert_eq!(read_manifest, Some(manifest.clone()));
+        assert_eq!(
+            RunManifest::from_json(&manifest.to_json().unwrap()).unwrap(),
+            manifest
+        );
+
+        let mut buf = Vec::new();
+        write_mtx(&mut buf, &m, None).unwrap();
+        let (back, read_manifest) = read_mtx(&mut buf.as_slice()).unwrap();
+        assert_same(&back, &m);
+        assert!(read_manifest.is_none());
+        assert!(read_mtx(&mut b"#?RADIANCE\nNROWS=1\nNCOLS=2\n\n1".as_slice()).is_err());
+        assert!(
+            read_mtx(&mut b"#?RADIANCE\nNROWS=1\nNCOLS=1\nNCOMP=3\n\n1 1 1".as_slice()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_ground_bin_header() {
+        let basis = SkyBasis::new(1).unwrap();
+        let n = basis.n_bins();
+        let mut dc = Matrix::new(0.0, 1, n);
+        dc.set(0, SkyBasis::GROUND_BIN, 4.).unwrap();
+        dc.set(0, 1, 1.).unwrap();
+
+        let last = basis.with_ground_policy(GroundBinPolicy::Last);
+        let mut buf = Vec::new();
+        write_dc_mtx(&mut buf, &dc, &last, None).unwrap();
+        let text = String::from_utf8(buf.clone()).unwrap();
+        assert!(text.contains("GROUND_BIN=last"));
+
+        // The header wins over the policy of the basis
+        let none = basis.with_ground_policy(GroundBinPolicy::None);
+        let (back, _) = read_dc_mtx(&mut buf.as_slice(), &none).unwrap();
+        assert_same(&back, &dc);
+
+        // Without a header, the basis says where the ground is
+        let mut buf = Vec::new();
+        write_mtx(&mut buf, &last.export_dc(&dc).unwrap(), None).unwrap();
+        let (back, _) = read_dc_mtx(&mut buf.as_slice(), &last).unwrap();
+        assert_same(&back, &dc);
+        assert!(read_dc_mtx(&mut buf.as_slice(), &none).is_err());
+
+        let mut sky = Matrix::new(0.0, n, 1);
+        sky.set(SkyBasis::GROUND_BIN, 0, 2.).unwrap();
+        let mut buf = Vec::new();
+        write_sky_mtx(&mut buf, &sky, &none, None).unwrap();
+        let (back, _) = read_sky_mtx(&mut buf.as_slice(), &basis).unwrap();
+        assert_eq!(back.size(), (n, 1));
+        assert_eq!(back.get(SkyBasis::GROUND_BIN, 0).unwrap(), 0.);
+    }
+
+    /// Writes `m` as `dtype`, as if by a build whose `Float` was `built_with`
+    fn artifact(m: &Matrix, dtype: Dtype, built_with: Dtype) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_dense_binary_as(&mut buf, m, dtype).unwrap();
+        assert_eq!(buf[12], Dtype::NATIVE.bytes());
+        buf[12] = built_with.bytes();
+        buf
+    }
+
+    #[test]
+    fn test_mixed_precision() {
+        let mut m = example();
+        m.set(1, 2, 0.1).unwrap();
+        m.set(2, 0, 1. / 3.).unwrap();
+
+        // Written by an f32 build
+        let buf = artifact(&m, Dtype::F32, Dtype::F32);
+        let back = read_binary_tagged(&mut buf.as_slice()).unwrap();
+        assert_eq!(back.dtype, Dtype::F32);
+        assert_eq!(back.written_by, Some(Dtype::F32));
+        assert!(back.warnings.is_empty(), "{:?}", back.warnings);
+        let back = back.matrix.into_dense().unwrap();
+        assert_same(&back, &crate::dtype::round_to(&m, Dtype::F32).unwrap());
+        assert_eq!(back.get(1, 2).unwrap(), 0.1_f32 as Float);
+
+        // Written by an f64 build
+        let buf = artifact(&m, Dtype::F64, Dtype::F64);
+        let back = read_binary_tagged(&mut buf.as_slice()).unwrap();
+        assert_eq!(back.dtype, Dtype::F64);
+        assert_eq!(back.written_by, Some(Dtype::F64));
+        if Dtype::NATIVE == Dtype::F32 {
+            assert_eq!(back.warnings.len(), 1);
+            assert!(back.warnings[0].contains("rounded to the f32 of this build"));
+        } else {
+            assert!(back.warnings.is_empty(), "{:?}", back.warnings);
+        }
+        let back = back.matrix.into_dense().unwrap();
+        assert_same(&back, &m);
+        assert_eq!(back.get(1, 2).unwrap(), 0.1_f64 as Float);
+
+        // Calculated by an f64 build, but stored as f32
+        let buf = artifact(&m, Dtype::F32, Dtype::F64);
+        let back = read_binary_tagged(&mut buf.as_slice()).unwrap();
+        assert_eq!(back.warnings.len(), 1);
+        assert!(back.warnings[0].contains("calculated as f64 but stored as f32"));
+        assert_same(
+            &back.matrix.into_dense().unwrap(),
+            &crate::dtype::round_to(&m, Dtype::F32).unwrap(),
+        );
+
+        // The other readers convert too
+        let back = read_binary(&mut buf.as_slice()).unwrap();
+        assert_eq!(
+            back.into_dense().unwrap().get(1, 2).unwrap(),
+            0.1_f32 as Float
+        );
+
+        // Sparse matrices, and files that do not say who wrote them
+        let sparse = SparseMatrix::from_dense(&m, 0.0).unwrap();
+        let mut buf = Vec::new();
+        write_sparse_binary_as(&mut buf, &sparse, Dtype::F32).unwrap();
+        let mut old = buf[..12].to_vec();
+        old[8] = 2;
+        old.extend_from_slice(&buf[13..]);
+        let back = read_binary_tagged(&mut old.as_slice()).unwrap();
+        assert_eq!(back.dtype, Dtype::F32);
+        assert_eq!(back.written_by, None);
+        assert!(back.warnings.is_empty());
+        assert_eq!(
+            back.matrix.into_dense().unwrap().get(2, 0).unwrap(),
+            (1_f32 / 3.) as Float
+        );
+
+        // The type of the elements is checked
+        let mut buf = artifact(&m, Dtype::F32, Dtype::F32);
+        buf[10] = 2;
+        assert!(read_binary(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        assert!(read_binary(&mut b"nonsense".as_slice()).is_err());
+        let mut buf = Vec::new();
+        write_dense_binary(&mut buf, &example()).unwrap();
+        buf.truncate(buf.len() - 3);
+        assert!(read_binary(&mut buf.as_slice()).is_err());
+    }
+}
@@ -18,11 +18,12 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 use crate::{solar_surface::SolarSurface, Float};
+use crate::sky::SunMapping;
 use calendar::Date;
 use communication_protocols::{ErrorHandling, MetaOptions, SimulationModel};
 use matrix::Matrix;
 use simple_model::{Boundary, SimpleModel, SimulationState, SimulationStateHeader, SolarOptions};
-use solar::{PerezSky, SkyUnits, Solar};
+use solar::{SkyUnits, Solar};
 use std::borrow::Borrow;
 use std::fs::File;
 use std::io::Write;
@@ -57,9 +58,18 @@ pub struct SolarModel {
 
     /// The MF discretization scheme for the sky.
     solar_sky_discretization: usize,
+
+    /// How the sun is assigned to the patches of the sky
+    sun_mapping: SunMapping,
 }
 
 impl SolarModel {
+    /// Sets the [`SunMapping`] used for building the sky vectors
+    /// at each timestep. Defaults to [`SunMapping::Nearest`].
+    pub fn set_sun_mapping(&mut self, mapping: SunMapping) {
+        self.sun_mapping = mapping;
+    }
+
     /// This function makes the IR heat transfer Zero... we will try to fix this soon enough,
     /// just not now    
     fn update_ir_radiation(
@@ -197,18 +207,15 @@ impl SolarModel {
         let vec = if is_day {
             // Build sky vector
             let albedo = 0.2;
-            let add_sky = true;
-            let add_sun = true;
             let units = SkyUnits::Solar;
-            PerezSky::gen_sky_vec(
+            crate::sky::gen_sky_vec(
                 self.solar_sky_discretization,
                 &self.solar,
                 date,
                 weather_data,
                 units,
                 albedo,
-                add_sky,
-                add_sun,
+                self.sun_mapping,
             )?
         } else {
             Matrix::empty()
@@ -419,6 +426,7 @@ impl SimulationModel for SolarModel {
             optical_info,
             solar,
             solar_sky_discretization: mf,
+            sun_mapping: SunMapping::default(),
         })
     }
 
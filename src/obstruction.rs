@@ -0,0 +1,313 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::rng::SensorRng;
+use crate::sensor::{AngularMask, DirectionSampler, SensorSpec};
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use rendering::{Ray, Scene};
+use serde::{Deserialize, Serialize};
+
+/// The step (in degrees) used when marching rays upwards
+const ALTITUDE_STEP: Float = 1.;
+
+/// The number of bisections used for refining the altitude
+/// at which a ray stops being obstructed
+const N_BISECTIONS: usize = 12;
+
+/// Checks whether a ray leaving `origin` in `direction` escapes the scene
+pub(crate) fn escapes(scene: &Scene, origin: Point3D, direction: Vector3D, aux: &mut Vec<usize>) -> bool {
+    let mut ray = Ray {
+        geometry: Ray3D { origin, direction },
+        ..Ray::default()
+    };
+    scene.cast_ray(&mut ray, aux).is_none()
+}
+
+/// Checks whether `sensor` hits a surface within `distance` when looking
+/// along its own direction (i.e., it is probably facing into the surface
+/// it was meant to be placed on)
+pub(crate) fn faces_into_surface(scene: &Scene, sensor: &SensorSpec, distance: Float) -> bool {
+    let mut ray = Ray {
+        geometry: sensor.ray,
+        ..Ray::default()
+    };
+    let mut aux = Vec::with_capacity(2);
+    sensor.cast_ray(scene, &mut ray, &mut aux).is_some()
+        && ray.interaction.point.distance(sensor.ray.origin) <= distance
+}
+
+/// The fraction of the `offset` within which a surface in front of a sensor
+/// still counts as the one it lies on, as the hit point can land on either side
+const ON_SURFACE_TOLERANCE: Float = 1e-3;
+
+/// Finds the surface that a sensor lies on: the one whose distance to the origin
+/// of the sensor—measured along its direction—is within `offset` behind it (or
+/// zero, give or take rounding). Its host surface, if any, does not count, as
+/// its rays go through it anyway. Returns the origin moved `offset` in front of
+/// that surface, so that whether its rays hit it does not depend on rounding.
+pub(crate) fn off_surface_origin(
+    scene: &Scene,
+    sensor: &SensorSpec,
+    offset: Float,
+) -> Option<Point3D> {
+    let normal = sensor.ray.direction.get_normalized();
+    let start = sensor.ray.origin - normal * offset;
+    let mut ray = Ray {
+        geometry: Ray3D {
+            origin: start,
+            direction: normal,
+        },
+        ..Ray::default()
+    };
+    let mut aux = Vec::with_capacity(2);
+    sensor.cast_ray(scene, &mut ray, &mut aux)?;
+    let hit = ray.interaction.point;
+    // The signed distance from the origin to the surface, negative behind it
+    let along = (hit - start) * normal - offset;
+    if along <= ON_SURFACE_TOLERANCE * offset {
+        Some(hit + normal * offset)
+    } else {
+        None
+    }
+}
+
+/// Calculates the no-sky line of a grid of sensors: whether each of them
+/// can see any sky. Each sensor sends `samples` rays over its hemisphere
+/// (respecting its mask), and it sees the sky if any of them escapes the
+/// scene going upwards.
+pub fn no_sky_line(
+    grid: &[SensorSpec],
+    scene: &Scene,
+    samples: usize,
+) -> Result<Vec<bool>, String> {
+    let mut aux = Vec::with_capacity(2);
+    let mut ret = Vec::with_capacity(grid.len());
+    for (i, sensor) in grid.iter().enumerate() {
+        let sampler = DirectionSampler::new(sensor.ray.direction, sensor.mask.as_ref())?;
+        let mut rng = SensorRng::new(0, i as u64);
+        let mut sees_sky = false;
+        for _ in 0..samples {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            if weight > 0.0 && dir.z > 0.0 && escapes(scene, sensor.ray.origin, dir, &mut aux) {
+                sees_sky = true;
+                break;
+            }
+        }
+        ret.push(sees_sky);
+    }
+    Ok(ret)
+}
+
+/// The altitude of the obstructions around a point, for a set of
+/// azimuths. Angles are in degrees, and azimuths are measured from
+/// North (`+Y`) towards East (`+X`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SkylineProfile {
+    /// The azimuths, increasing from `0`
+    pub azimuths: Vec<Float>,
+
+    /// The altitude below which the sky is obstructed, for each azimuth. It
+    /// is `0` when nothing obstructs the horizon and `90` when no sky is visible.
+    pub altitudes: Vec<Float>,
+}
+
+impl SkylineProfile {
+    /// The obstruction altitude at any azimuth, interpolated linearly
+    pub fn altitude_at(&self, azimuth: Float) -> Float {
+        let n = self.azimuths.len();
+        if n == 0 {
+            return 0.0;
+        }
+        let azimuth = azimuth.rem_euclid(360.);
+        let next = self.azimuths.iter().position(|a| *a > azimuth).unwrap_or(n);
+        let (a0, h0) = if next == 0 {
+            (self.azimuths[n - 1] - 360., self.altitudes[n - 1])
+        } else {
+            (self.azimuths[next - 1], self.altitudes[next - 1])
+        };
+        let (a1, h1) = if next == n {
+            (self.azimuths[0] + 360., self.altitudes[0])
+        } else {
+            (self.azimuths[next], self.altitudes[next])
+        };
+        if (a1 - a0).abs() < 1e-9 {
+            return h0;
+        }
+        h0 + (h1 - h0) * (azimuth - a0) / (a1 - a0)
+    }
+
+    /// Writes the profile as CSV, with `azimuth` and `altitude` columns
+    pub fn to_csv(&self) -> String {
+        let mut ret = "azimuth,altitude\n".to_string();
+        for (a, h) in self.azimuths.iter().zip(self.altitudes.iter()) {
+            ret.push_str(&format!("{},{}\n", a, h));
+        }
+        ret
+    }
+
+    /// Builds an [`AngularMask`] that only sees the directions above
+    /// the skyline. This is handy for skipping the obstructions of a point
+    /// in later runs.
+    pub fn to_mask(&self) -> AngularMask {
+        let profile = self.clone();
+        AngularMask::predicate(move |dir: Vector3D| {
+            let d = dir.get_normalized();
+            let altitude = d.z.clamp(-1., 1.).asin().to_degrees();
+            let azimuth = d.x.atan2(d.y).to_degrees();
+            altitude > profile.altitude_at(azimuth)
+        })
+    }
+}
+
+/// Calculates the [`SkylineProfile`] of a point, every `azimuth_resolution`
+/// degrees. For each azimuth, rays are marched upwards from the horizon until
+/// one escapes the scene.
+pub fn max_obstruction_altitude(
+    point: Point3D,
+    scene: &Scene,
+    azimuth_resolution: Float,
+) -> Result<SkylineProfile, String> {
+    if !(azimuth_resolution > 0.0 && azimuth_resolution <= 360.) {
+        return Err(format!(
+            "The azimuth resolution of a skyline must be within (0, 360]... found {}",
+            azimuth_resolution
+        ));
+    }
+    let n = (360. / azimuth_resolution).round().max(1.) as usize;
+    let step = 360. / n as Float;
+    let mut aux = Vec::with_capacity(2);
+    let dir = |azimuth: Float, altitude: Float| {
+        let (sin_az, cos_az) = azimuth.to_radians().sin_cos();
+        let (sin_alt, cos_alt) = altitude.to_radians().sin_cos();
+        Vector3D::new(cos_alt * sin_az, cos_alt * cos_az, sin_alt)
+    };
+
+    let mut azimuths = Vec::with_capacity(n);
+    let mut altitudes = Vec::with_capacity(n);
+    for i in 0..n {
+        let azimuth = i as Float * step;
+        azimuths.push(azimuth);
+        if escapes(scene, point, dir(azimuth, 0.), &mut aux) {
+            altitudes.push(0.);
+            continue;
+        }
+        let mut blocked = 0.;
+        let mut free = None;
+        let mut alt = ALTITUDE_STEP;
+        while alt <= 90. {
+            if escapes(scene, point, dir(azimuth, alt), &mut aux) {
+                free = Some(alt);
+                break;
+            }
+            blocked = alt;
+            alt += ALTITUDE_STEP;
+        }
+        let mut free = match free {
+            Some(v) => v,
+            None => {
+                altitudes.push(90.);
+                continue;
+            }
+        };
+        for _ in 0..N_BISECTIONS {
+            let mid = 0.5 * (blocked + free);
+            if escapes(scene, point, dir(azimuth, mid), &mut aux) {
+                free = mid;
+            } else {
+                blocked = mid;
+            }
+        }
+        altitudes.push(0.5 * (blocked + free));
+    }
+    Ok(SkylineProfile {
+        azimuths,
+        altitudes,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::load_scene;
+    use validate::assert_close;
+
+    fn up(x: Float, y: Float) -> SensorSpec {
+        SensorSpec::from(Ray3D {
+            origin: Point3D::new(x, y, 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        })
+    }
+
+    #[test]
+    fn test_no_sky_line() {
+        let (mut scene, _) = load_scene("./tests/obstruction/courtyard.rad").unwrap();
+        scene.build_accelerator();
+        let grid = vec![up(5., 5.), up(0.5, 0.5)];
+        assert_eq!(no_sky_line(&grid, &scene, 500).unwrap(), vec![true, true]);
+
+        let (mut scene, _) = load_scene("./tests/obstruction/roofed_courtyard.rad").unwrap();
+        scene.build_accelerator();
+        assert_eq!(no_sky_line(&grid, &scene, 500).unwrap(), vec![false, false]);
+    }
+
+    #[test]
+    fn test_courtyard_skyline() {
+        let (mut scene, _) = load_scene("./tests/obstruction/courtyard.rad").unwrap();
+        scene.build_accelerator();
+        let profile = max_obstruction_altitude(Point3D::new(5., 5., 0.), &scene, 45.).unwrap();
+        assert_eq!(profile.azimuths.len(), 8);
+
+        // Walls 10 m tall, 5 m away... and 5√2 m away in the corners
+        let side = (10. as Float / 5.).atan().to_degrees();
+        let corner = (10. as Float / (50. as Float).sqrt()).atan().to_degrees();
+        for (i, alt) in profile.altitudes.iter().enumerate() {
+            let expected = if i % 2 == 0 { side } else { corner };
+            assert_close!(*alt, expected, 0.05);
+        }
+        assert!(profile.to_csv().starts_with("azimuth,altitude\n0,"));
+
+        // As a mask, only the directions above the walls are visible
+        let mask = profile.to_mask();
+        let n = Vector3D::new(0., 0., 1.);
+        assert!(mask.is_visible(n, Vector3D::new(0., 0.1, 1.)).unwrap());
+        assert!(!mask.is_visible(n, Vector3D::new(0., 1., 1.)).unwrap());
+
+        // Roofed: no sky at all
+        let (mut scene, _) = load_scene("./tests/obstruction/roofed_courtyard.rad").unwrap();
+        scene.build_accelerator();
+        let profile = max_obstruction_altitude(Point3D::new(5., 5., 0.), &scene, 90.).unwrap();
+        assert!(profile.altitudes.iter().all(|a| *a == 90.));
+
+        assert!(max_obstruction_altitude(Point3D::new(5., 5., 0.), &scene, 0.).is_err());
+    }
+
+    #[test]
+    fn test_profile_interpolation() {
+        let profile = SkylineProfile {
+            azimuths: vec![0., 90., 180., 270.],
+            altitudes: vec![10., 20., 30., 40.],
+        };
+        assert_close!(profile.altitude_at(45.), 15., 1e-6);
+        assert_close!(profile.altitude_at(315.), 25., 1e-6);
+        assert_close!(profile.altitude_at(-45.), 25., 1e-6);
+        assert_close!(profile.altitude_at(90.), 20., 1e-6);
+    }
+}
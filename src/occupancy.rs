@@ -0,0 +1,550 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A coarse occupancy grid over the geometry of a scene, which proves that some
+//! rays hit nothing before they are traced.
+//!
+//! Exterior sensor grids (e.g., over a sparse urban context) spend most of their
+//! time casting rays towards open sky, which still go down the acceleration
+//! structure of the `rendering` crate. An [`OccupancyGrid`] remembers which
+//! triangles touch each voxel—conservatively, so a voxel only misses a triangle
+//! if they do not overlap—and walks each ray through the voxels, testing it
+//! against their triangles with a tolerance. A ray that gets through the grid
+//! without coming close to any triangle cannot hit anything, so it goes straight
+//! to the sky. All other rays are cast as usual, so results are exactly the same
+//! with and without the grid.
+
+use crate::scene_loading::SceneReport;
+use crate::Float;
+use geometry3d::{Point3D, Ray3D};
+use rendering::Scene;
+
+/// The number of voxels along the longest side of the bounding box of
+/// a scene, if nothing else is said
+pub const DEFAULT_OCCUPANCY_RESOLUTION: usize = 64;
+
+/// The largest number of voxels along a side of the grid, which keeps it
+/// within a few tens of megabytes
+pub const MAX_OCCUPANCY_RESOLUTION: usize = 256;
+
+/// The fraction of the side of a voxel by which triangles are grown when they are
+/// voxelised, so that rounding errors in the walk cannot miss a surface
+const VOXEL_MARGIN: Float = 0.01;
+
+/// How far outside its edges (relative to their length) a ray may pass by a
+/// triangle and still be taken as hitting it, so that the tolerance of the
+/// intersections of the `rendering` crate cannot hit what the grid missed
+const EDGE_TOLERANCE: Float = 1e-3;
+
+/// How far behind its origin (in the units of the scene) a ray may hit a
+/// triangle and still be taken as hitting it
+const BEHIND_TOLERANCE: Float = 1e-3;
+
+/// Marks the voxels touched by something that rays cannot be tested against
+/// (i.e., spheres, which only occupy their bounding box)
+const SOLID: u32 = u32::MAX;
+
+/// The triangles that touch each voxel of the bounding box of a scene, used
+/// for skipping the rays that cannot hit anything (see
+/// [`DCSession::with_occupancy_grid`](crate::DCSession::with_occupancy_grid))
+#[derive(Debug, Clone)]
+pub struct OccupancyGrid {
+    /// The minimum corner of the grid
+    min: [Float; 3],
+    /// The side of the (cubic) voxels
+    size: Float,
+    /// The number of voxels along each axis
+    n: [usize; 3],
+    /// The triangles of the polygons, split as a fan from their first vertex
+    triangles: Vec<[[Float; 3]; 3]>,
+    /// Where the triangles of each voxel (with `x` varying fastest) start
+    /// within `contents`, plus where the last ones end
+    offsets: Vec<u32>,
+    /// The triangles of each voxel, or [`SOLID`]
+    contents: Vec<u32>,
+    /// The number of triangles of the scene, which tells whether the
+    /// grid is used with the scene it was built for
+    n_triangles: usize,
+}
+
+impl OccupancyGrid {
+    /// Voxelises the surfaces of a scene, given the `report` returned when
+    /// loading it, with `resolution` voxels along the longest side of its bounding
+    /// box. Polygons are split into triangles, which are added to each voxel they
+    /// overlap. Spheres fill every voxel of their bounding box.
+    pub fn new(report: &SceneReport, resolution: usize) -> Result<Self, String> {
+        if !(1..=MAX_OCCUPANCY_RESOLUTION).contains(&resolution) {
+            return Err(format!(
+                "The resolution of an occupancy grid must be between 1 and {}... found {}",
+                MAX_OCCUPANCY_RESOLUTION, resolution
+            ));
+        }
+        let (lo, hi) = report.bounding_box;
+        let (lo, hi) = (as_array(lo), as_array(hi));
+        if lo.iter().chain(hi.iter()).any(|v| !v.is_finite()) {
+            return Err("The bounding box of the scene is not finite".to_string());
+        }
+        let extent = [hi[0] - lo[0], hi[1] - lo[1], hi[2] - lo[2]];
+        let longest = extent.iter().cloned().fold(0.0, Float::max);
+        let size = if longest > 0.0 {
+            longest / resolution as Float
+        } else {
+            1.0
+        };
+        // A voxel of padding all around, so nothing lies on the boundary
+        let mut ret = Self {
+            min: [lo[0] - size, lo[1] - size, lo[2] - size],
+            size,
+            n: extent.map(|e| (e / size).ceil() as usize + 2),
+            triangles: Vec::new(),
+            offsets: Vec::new(),
+            contents: Vec::new(),
+            n_triangles: report.n_triangles,
+        };
+        // The (voxel, content) pairs, gathered by voxel afterwards
+        let mut pairs: Vec<(usize, u32)> = Vec::new();
+        for s in report.surfaces.iter() {
+            if s.vertices.len() >= 3 {
+                let first = as_array(s.vertices[0]);
+                for pair in s.vertices[1..].windows(2) {
+                    let triangle = [first, as_array(pair[0]), as_array(pair[1])];
+                    let id = ret.triangles.len() as u32;
+                    ret.triangles.push(triangle);
+                    ret.cells_of_triangle(&triangle, |cell| pairs.push((cell, id)));
+                }
+            } else {
+                ret.cells_of_box(as_array(s.min), as_array(s.max), |cell| {
+                    pairs.push((cell, SOLID))
+                });
+            }
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+        let n_cells: usize = ret.n.iter().product();
+        ret.offsets = Vec::with_capacity(n_cells + 1);
+        ret.contents = pairs.iter().map(|(_, id)| *id).collect();
+        let mut k = 0;
+        for cell in 0..n_cells {
+            ret.offsets.push(k as u32);
+            while k < pairs.len() && pairs[k].0 == cell {
+                k += 1;
+            }
+        }
+        ret.offsets.push(k as u32);
+        Ok(ret)
+    }
+
+    /// The number of voxels along each axis
+    pub fn resolution(&self) -> [usize; 3] {
+        self.n
+    }
+
+    /// The fraction of the voxels that something touches. The lower it is,
+    /// the fewer triangles each ray is tested against.
+    pub fn occupied_fraction(&self) -> Float {
+        let occupied = self.offsets.windows(2).filter(|w| w[1] > w[0]).count();
+        occupied as Float / (self.offsets.len() - 1) as Float
+    }
+
+    /// Whether the `ray` is certain to hit nothing, because it does not come
+    /// close to any triangle of the voxels it goes through. A `false` does
+    /// not mean that it hits something.
+    pub fn misses(&self, ray: &Ray3D) -> bool {
+        let (o, d) = (
+            as_array(ray.origin),
+            [ray.direction.x, ray.direction.y, ray.direction.z],
+        );
+        if d.iter().all(|v| *v == 0.0) {
+            return false;
+        }
+        // Where the ray enters and leaves the grid
+        let (mut t0, mut t1): (Float, Float) = (0.0, Float::INFINITY);
+        for i in 0..3 {
+            let lo = self.min[i];
+            let hi = lo + self.n[i] as Float * self.size;
+            if d[i] == 0.0 {
+                if o[i] < lo || o[i] > hi {
+                    return true;
+                }
+            } else {
+                let (a, b) = ((lo - o[i]) / d[i], (hi - o[i]) / d[i]);
+                t0 = t0.max(a.min(b));
+                t1 = t1.min(a.max(b));
+            }
+        }
+        if t0 > t1 {
+            return true;
+        }
+
+        // Walks the voxels, in the order the ray goes through them
+        let mut cell = [0; 3];
+        let mut next = [Float::INFINITY; 3];
+        let mut delta = [Float::INFINITY; 3];
+        for i in 0..3 {
+            cell[i] = self.index(i, o[i] + d[i] * t0);
+            let lo = self.min[i] + cell[i] as Float * self.size;
+            if d[i] > 0.0 {
+                next[i] = (lo + self.size - o[i]) / d[i];
+                delta[i] = self.size / d[i];
+            } else if d[i] < 0.0 {
+                next[i] = (lo - o[i]) / d[i];
+                delta[i] = -self.size / d[i];
+            }
+        }
+        loop {
+            let flat = self.flat(cell);
+            let (start, end) = (self.offsets[flat] as usize, self.offsets[flat + 1] as usize);
+            for id in self.contents[start..end].iter() {
+                if *id == SOLID || may_hit(&self.triangles[*id as usize], o, d) {
+                    return false;
+                }
+            }
+            let axis = (0..3)
+                .min_by(|a, b| next[*a].total_cmp(&next[*b]))
+                .unwrap_or(0);
+            if next[axis] > t1 {
+                return true;
+            }
+            if d[axis] > 0.0 {
+                if cell[axis] + 1 == self.n[axis] {
+                    return true;
+                }
+                cell[axis] += 1;
+            } else {
+                if cell[axis] == 0 {
+                    return true;
+                }
+                cell[axis] -= 1;
+            }
+            next[axis] += delta[axis];
+        }
+    }
+
+    /// Checks that the grid was built for the `scene`
+    pub(crate) fn check_scene(&self, scene: &Scene) -> Result<(), String> {
+        if scene.triangles.len() != self.n_triangles {
+            return Err(format!(
+                "The occupancy grid was built for a scene with {} triangles, but this one has {}",
+                self.n_triangles,
+                scene.triangles.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The voxel along the axis `i` that contains the coordinate `x`, or
+    /// the closest one
+    fn index(&self, i: usize, x: Float) -> usize {
+        (((x - self.min[i]) / self.size).floor().max(0.0) as usize).min(self.n[i] - 1)
+    }
+
+    /// The position of a voxel within `offsets`
+    fn flat(&self, cell: [usize; 3]) -> usize {
+        cell[0] + self.n[0] * (cell[1] + self.n[1] * cell[2])
+    }
+
+    /// Calls `f` with every voxel that overlaps a box grown by the margin
+    fn cells_of_box<F: FnMut(usize)>(&self, lo: [Float; 3], hi: [Float; 3], mut f: F) {
+        let margin = VOXEL_MARGIN * self.size;
+        let [(x0, x1), (y0, y1), (z0, z1)] =
+            [0, 1, 2].map(|i| (self.index(i, lo[i] - margin), self.index(i, hi[i] + margin)));
+        for z in z0..=z1 {
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    f(self.flat([x, y, z]));
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with every voxel that overlaps a triangle (grown by the margin)
+    fn cells_of_triangle<F: FnMut(usize)>(&self, triangle: &[[Float; 3]; 3], mut f: F) {
+        let lo = [0, 1, 2].map(|i| triangle.iter().map(|v| v[i]).fold(Float::MAX, Float::min));
+        let hi = [0, 1, 2].map(|i| triangle.iter().map(|v| v[i]).fold(Float::MIN, Float::max));
+        let half = self.size * (0.5 + VOXEL_MARGIN);
+        self.cells_of_box(lo, hi, |cell| {
+            let x = cell % self.n[0];
+            let y = (cell / self.n[0]) % self.n[1];
+            let z = cell / (self.n[0] * self.n[1]);
+            let centre = [x, y, z].map(|c| c as Float + 0.5);
+            let centre = [0, 1, 2].map(|i| self.min[i] + centre[i] * self.size);
+            if triangle_overlaps_box(triangle, centre, half) {
+                f(cell);
+            }
+        });
+    }
+}
+
+fn as_array(p: Point3D) -> [Float; 3] {
+    [p.x, p.y, p.z]
+}
+
+fn sub(a: [Float; 3], b: [Float; 3]) -> [Float; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [Float; 3], b: [Float; 3]) -> [Float; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: [Float; 3], b: [Float; 3]) -> Float {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Whether a ray from `o` along `d` may hit a triangle: it goes through the
+/// triangle grown by [`EDGE_TOLERANCE`], ahead of the origin or slightly behind
+/// it, or it is (almost) parallel to its plane (Möller and Trumbore's test)
+fn may_hit(triangle: &[[Float; 3]; 3], o: [Float; 3], d: [Float; 3]) -> bool {
+    let e1 = sub(triangle[1], triangle[0]);
+    let e2 = sub(triangle[2], triangle[0]);
+    let p = cross(d, e2);
+    let det = dot(e1, p);
+    let scale = dot(e1, e1).max(dot(e2, e2)) * dot(d, d).sqrt();
+    if det.abs() <= 1e-9 * scale {
+        return true;
+    }
+    let s = sub(o, triangle[0]);
+    let u = dot(s, p) / det;
+    let q = cross(s, e1);
+    let v = dot(d, q) / det;
+    let t = dot(e2, q) / det;
+    let tol = EDGE_TOLERANCE;
+    u >= -tol && v >= -tol && u + v <= 1. + 2. * tol && t * dot(d, d).sqrt() >= -BEHIND_TOLERANCE
+}
+
+/// Whether a triangle overlaps a cube with a certain `centre` and
+/// half side, by the separating axis theorem (Akenine-Möller's test): they
+/// do not overlap if and only if their projections onto one of the axes of
+/// the cube, the normal of the triangle, or the cross products of the edges
+/// of the triangle with the axes of the cube do not overlap.
+fn triangle_overlaps_box(triangle: &[[Float; 3]; 3], centre: [Float; 3], half: Float) -> bool {
+    let v = triangle.map(|p| sub(p, centre));
+    let separates = |axis: [Float; 3]| {
+        let p = v.map(|v| dot(axis, v));
+        let r = half * (axis[0].abs() + axis[1].abs() + axis[2].abs());
+        p.iter().cloned().fold(Float::MAX, Float::min) > r
+            || p.iter().cloned().fold(Float::MIN, Float::max) < -r
+    };
+    let edges = [0, 1, 2].map(|i| sub(v[(i + 1) % 3], v[i]));
+    let units = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+    if units.iter().any(|u| separates(*u)) || separates(cross(edges[0], edges[1])) {
+        return false;
+    }
+    !edges
+        .iter()
+        .any(|e| units.iter().any(|u| separates(cross(*e, *u))))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::rng::SensorRng;
+    use crate::{DCOptions, DCSession, Material, SceneBuilder, SensorSpec};
+    use geometry3d::Vector3D;
+    use rendering::Ray;
+
+    /// A ground with `n` by `n` blocks of 10 by 10 m on it, 40 m apart
+    fn urban_scene(n: usize) -> (Scene, SceneReport) {
+        let mut builder = SceneBuilder::new();
+        builder.add_material("mat", Material::plastic(0.3)).unwrap();
+        let side = 40. * n as Float;
+        let ground =
+            [(0., 0.), (side, 0.), (side, side), (0., side)].map(|(x, y)| Point3D::new(x, y, 0.));
+        builder.add_polygon("mat", "ground", &ground).unwrap();
+        for i in 0..n {
+            for j in 0..n {
+                let (x0, y0) = (40. * i as Float + 15., 40. * j as Float + 15.);
+                let (x1, y1, h) = (x0 + 10., y0 + 10., 10. + 5. * ((i + j) % 3) as Float);
+                let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+                let roof = corners.map(|(x, y)| Point3D::new(x, y, h));
+                builder
+                    .add_polygon("mat", &format!("roof_{}_{}", i, j), &roof)
+                    .unwrap();
+                for k in 0..4 {
+                    let (a, b) = (corners[k], corners[(k + 1) % 4]);
+                    let wall =
+                        [(a, 0.), (b, 0.), (b, h), (a, h)].map(|((x, y), z)| Point3D::new(x, y, z));
+                    builder
+                        .add_polygon("mat", &format!("wall_{}_{}_{}", i, j, k), &wall)
+                        .unwrap();
+                }
+            }
+        }
+        let (mut scene, report) = builder.build().unwrap();
+        scene.build_accelerator();
+        (scene, report)
+    }
+
+    /// Sensors 1.5 m above the streets, looking up
+    fn street_sensors(n: usize) -> Vec<SensorSpec> {
+        (0..n * n)
+            .map(|k| {
+                Ray3D {
+                    origin: Point3D::new(
+                        40. * (k % n) as Float + 5.,
+                        40. * (k / n) as Float + 5.,
+                        1.5,
+                    ),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect()
+    }
+
+    fn ray(origin: (Float, Float, Float), direction: (Float, Float, Float)) -> Ray3D {
+        Ray3D {
+            origin: Point3D::new(origin.0, origin.1, origin.2),
+            direction: Vector3D::new(direction.0, direction.1, direction.2).get_normalized(),
+        }
+    }
+
+    #[test]
+    fn test_triangle_box() {
+        let triangle = [[0., 0., 0.], [1., 0., 0.], [0., 1., 0.]];
+        assert!(triangle_overlaps_box(&triangle, [0.2, 0.2, 0.], 0.1));
+        assert!(triangle_overlaps_box(&triangle, [0.2, 0.2, 0.09], 0.1));
+        assert!(!triangle_overlaps_box(&triangle, [0.2, 0.2, 0.2], 0.1));
+        // Within the bounding box of the triangle, but beyond its hypotenuse
+        assert!(!triangle_overlaps_box(&triangle, [0.8, 0.8, 0.], 0.1));
+        // Larger than the triangle
+        assert!(triangle_overlaps_box(&triangle, [0., 0., 0.], 5.));
+    }
+
+    #[test]
+    fn test_misses() {
+        let (_, report) = urban_scene(2);
+        let grid = OccupancyGrid::new(&report, 32).unwrap();
+        assert_eq!(grid.resolution(), [34, 34, 10]);
+        assert!(grid.occupied_fraction() < 0.3);
+        assert!(OccupancyGrid::new(&report, 0).is_err());
+        assert!(OccupancyGrid::new(&report, MAX_OCCUPANCY_RESOLUTION + 1).is_err());
+
+        // Up from a street, and into the sky from far away: nothing to hit
+        assert!(grid.misses(&ray((5., 5., 1.5), (0., 0., 1.))));
+        assert!(grid.misses(&ray((5., 5., 1.5), (0.3, 0.1, 1.))));
+        assert!(grid.misses(&ray((-100., 5., 1.5), (-1., 0., 0.))));
+        // Towards a wall, the ground and a roof
+        assert!(!grid.misses(&ray((5., 20., 1.5), (1., 0., 0.))));
+        assert!(!grid.misses(&ray((5., 5., 1.5), (0., 0., -1.))));
+        assert!(!grid.misses(&ray((20., 20., 100.), (0., 0., -1.))));
+        // Along the street, but it ends by a block
+        assert!(!grid.misses(&ray((5., 0.5, 1.5), (1., 1., 0.))));
+    }
+
+    #[test]
+    fn test_conservative() {
+        let (scene, report) = urban_scene(3);
+        let mut rng = SensorRng::new(7, 0);
+        let mut aux = Vec::new();
+        for resolution in [8, 32, 100] {
+            let grid = OccupancyGrid::new(&report, resolution).unwrap();
+            let mut skipped = 0;
+            for _ in 0..2000 {
+                let origin = (
+                    rng.gen() * 140. - 10.,
+                    rng.gen() * 140. - 10.,
+                    rng.gen() * 30.,
+                );
+                let direction = (rng.gen() - 0.5, rng.gen() - 0.5, rng.gen() - 0.5);
+                let geometry = ray(origin, direction);
+                if grid.misses(&geometry) {
+                    skipped += 1;
+                    let mut ray = Ray {
+                        geometry,
+                        ..Ray::default()
+                    };
+                    assert!(scene.cast_ray(&mut ray, &mut aux).is_none());
+                }
+            }
+            assert!(skipped > 0);
+        }
+    }
+
+    #[test]
+    fn test_identical_dc() {
+        let (scene, report) = urban_scene(3);
+        let sensors = street_sensors(3);
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 500,
+            seed: 3,
+            ..DCOptions::default()
+        };
+        let plain = DCSession::new(1, options)
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap()
+            .matrix;
+        let grid = OccupancyGrid::new(&report, DEFAULT_OCCUPANCY_RESOLUTION).unwrap();
+        let session = DCSession::new(1, options).with_occupancy_grid(grid);
+        let filtered = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+        let (nrows, ncols) = plain.size();
+        assert_eq!(filtered.size(), (nrows, ncols));
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(filtered.get(r, c).unwrap(), plain.get(r, c).unwrap());
+            }
+        }
+
+        // A grid of another scene is refused
+        let (other, _) = urban_scene(2);
+        assert!(session.calc_sensor_dc(&sensors, &other).is_err());
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_sparse_urban --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_sparse_urban() {
+        let (scene, report) = urban_scene(20);
+        let sensors = street_sensors(20);
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 2000,
+            ..DCOptions::default()
+        };
+        let start = std::time::Instant::now();
+        let plain = DCSession::new(1, options)
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap();
+        let without = start.elapsed();
+        let start = std::time::Instant::now();
+        let grid = OccupancyGrid::new(&report, DEFAULT_OCCUPANCY_RESOLUTION).unwrap();
+        let built = start.elapsed();
+        let start = std::time::Instant::now();
+        let filtered = DCSession::new(1, options)
+            .with_occupancy_grid(grid)
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap();
+        let with = start.elapsed();
+        println!(
+            "{} sensors over {} blocks: {:?} without the grid, {:?} with it (plus {:?} building it)",
+            sensors.len(),
+            20 * 20,
+            without,
+            with,
+            built
+        );
+        assert_eq!(filtered.matrix.size(), plain.matrix.size());
+    }
+}
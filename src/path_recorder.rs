@@ -0,0 +1,304 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Records of individual paths, for finding out why a coefficient looks
+//! wrong.
+//!
+//! A [`PathRecorder`] is given to a [`DCSession`](crate::DCSession) (see
+//! [`DCSession::with_path_recorder`](crate::DCSession::with_path_recorder)),
+//! and the simplified tracer of
+//! [`DCSession::calc_reflectance_tallies`](crate::DCSession::calc_reflectance_tallies)
+//! and [`DCSession::calc_aperture_dc`](crate::DCSession::calc_aperture_dc) tells
+//! it about the first paths of some of the sensors: where they started, where
+//! they bounced, and where they escaped to the sky (if they did). Other sensors,
+//! and sessions without a recorder, only pay for checking whether there is one.
+//!
+//! Records can be written as JSON lines, or as OBJ polylines to be drawn over
+//! the model.
+
+use crate::Float;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// How long (in metres) the last segment of an escaping path is drawn by
+/// [`PathRecorder::to_obj`]
+pub const ESCAPE_SEGMENT_LENGTH: Float = 1.;
+
+/// A point where a path hit a surface
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathVertex {
+    /// Where the surface was hit
+    pub position: [Float; 3],
+
+    /// The index of the material of the surface, as in
+    /// [`SceneReport::materials`](crate::SceneReport::materials)
+    pub material: usize,
+
+    /// The cosine of the angle between the path and the normal of the surface
+    pub cos: Float,
+}
+
+/// A path traced from a sensor
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRecord {
+    /// The index of the sensor
+    pub sensor: usize,
+
+    /// The index of the sample of the sensor
+    pub sample: usize,
+
+    /// Where the path started
+    pub origin: [Float; 3],
+
+    /// The surfaces hit, in order (both the ones that reflected the path and
+    /// the ones that transmitted it)
+    pub vertices: Vec<PathVertex>,
+
+    /// The direction in which the path left the scene and the bin of the sky it
+    /// reached, if it did (rather than being absorbed or running out of bounces)
+    pub escape: Option<([Float; 3], usize)>,
+
+    /// The weight the path reached the sky with, before the reflectances of the
+    /// materials it bounced off are applied (`0` if it did not escape)
+    pub weight: Float,
+}
+
+/// Collects the [`PathRecord`]s of the first `max_paths` samples of some sensors
+#[derive(Debug)]
+pub struct PathRecorder {
+    /// The indices of the sensors whose paths are recorded
+    sensors: Vec<usize>,
+
+    /// The number of paths recorded per sensor
+    max_paths: usize,
+
+    /// The paths recorded so far
+    records: Mutex<Vec<PathRecord>>,
+}
+
+impl PathRecorder {
+    /// Creates a recorder of the first `max_paths` paths of each of the `sensors`
+    /// (given by their index)
+    pub fn new(sensors: &[usize], max_paths: usize) -> Self {
+        Self {
+            sensors: sensors.to_vec(),
+            max_paths,
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether sample `sample` of the sensor at `index` is to be recorded
+    pub(crate) fn wants(&self, index: usize, sample: usize) -> bool {
+        sample < self.max_paths && self.sensors.contains(&index)
+    }
+
+    /// Keeps a record
+    pub(crate) fn push(&self, record: PathRecord) {
+        if let Ok(mut records) = self.records.lock() {
+            records.push(record);
+        }
+    }
+
+    /// The paths recorded so far, sorted by sensor and sample
+    pub fn records(&self) -> Vec<PathRecord> {
+        let mut ret = match self.records.lock() {
+            Ok(records) => records.clone(),
+            Err(_) => Vec::new(),
+        };
+        ret.sort_by_key(|r| (r.sensor, r.sample));
+        ret
+    }
+
+    /// Forgets the paths recorded so far
+    pub fn clear(&self) {
+        if let Ok(mut records) = self.records.lock() {
+            records.clear();
+        }
+    }
+
+    /// Writes the records as JSON, one path per line
+    pub fn to_jsonl(&self) -> Result<String, String> {
+        let mut ret = String::new();
+        for record in self.records() {
+            let line = serde_json::to_string(&record)
+                .map_err(|e| format!("Could not serialise path record: {}", e))?;
+            ret.push_str(&line);
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+
+    /// Writes the records as an OBJ file with one polyline per path, from its
+    /// origin through its vertices. Paths that escaped end with a segment of
+    /// [`ESCAPE_SEGMENT_LENGTH`] in the direction they left in.
+    pub fn to_obj(&self) -> String {
+        let mut ret = String::new();
+        let mut n_vertices = 0;
+        for record in self.records() {
+            ret.push_str(&format!(
+                "o sensor_{}_sample_{}\n",
+                record.sensor, record.sample
+            ));
+            let mut points = vec![record.origin];
+            points.extend(record.vertices.iter().map(|v| v.position));
+            if let Some((direction, _)) = record.escape {
+                let last = points[points.len() - 1];
+                points.push([0, 1, 2].map(|k| last[k] + direction[k] * ESCAPE_SEGMENT_LENGTH));
+            }
+            for p in points.iter() {
+                ret.push_str(&format!("v {} {} {}\n", p[0], p[1], p[2]));
+            }
+            ret.push('l');
+            for k in 0..points.len() {
+                ret.push_str(&format!(" {}", n_vertices + k + 1));
+            }
+            ret.push('\n');
+            n_vertices += points.len();
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession};
+    use crate::{load_scene, SensorSpec};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_recorded_paths() {
+        let (mut scene, report) = load_scene("./tests/sensitivity/canopy.rad").unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 300,
+                max_depth: 3,
+                seed: 7,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = [0., 1.]
+            .iter()
+            .map(|y| {
+                Ray3D {
+                    origin: Point3D::new(0., *y, 1.),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect();
+        let recorder = Arc::new(PathRecorder::new(&[1], 40));
+        let recording = session.clone().with_path_recorder(recorder.clone());
+        let recorded = recording
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+        let records = recorder.records();
+        assert_eq!(records.len(), 40);
+
+        // Recording changes nothing
+        let plain = session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+        let (nrows, ncols) = plain.total().size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(
+                    recorded.total().get(r, c).unwrap(),
+                    plain.total().get(r, c).unwrap()
+                );
+            }
+        }
+
+        let n_bins = session.basis().unwrap().n_bins();
+        let mut n_bounces = 0;
+        for (j, record) in records.iter().enumerate() {
+            assert_eq!((record.sensor, record.sample), (1, j));
+            assert_eq!(record.origin, [0., 1., 1.]);
+            match record.escape {
+                Some((_, bin)) => assert!(bin < n_bins && record.weight > 0.0),
+                None => assert_eq!(record.weight, 0.0),
+            }
+            // Every vertex is on a surface made of its material
+            for v in record.vertices.iter() {
+                let p = Point3D::new(v.position[0], v.position[1], v.position[2]);
+                assert!((0.0..=1.).contains(&v.cos));
+                let on_surface = report.surfaces.iter().any(|s| {
+                    let normal = s.normal.unwrap().get_normalized();
+                    let inside = (0..3).all(|k| {
+                        let (x, lo, hi) = match k {
+                            0 => (p.x, s.min.x, s.max.x),
+                            1 => (p.y, s.min.y, s.max.y),
+                            _ => (p.z, s.min.z, s.max.z),
+                        };
+                        x > lo - 1e-3 && x < hi + 1e-3
+                    });
+                    inside
+                        && ((p - s.vertices[0]) * normal).abs() < 1e-3
+                        && report.materials[v.material].name == s.modifier
+                });
+                assert!(on_surface, "{:?}", v);
+            }
+            n_bounces += record.vertices.len();
+        }
+        assert!(n_bounces > 0);
+
+        let obj = recorder.to_obj();
+        assert_eq!(obj.lines().filter(|l| l.starts_with("l ")).count(), 40);
+        let n_points: usize = records
+            .iter()
+            .map(|r| 1 + r.vertices.len() + r.escape.is_some() as usize)
+            .sum();
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("v ")).count(),
+            n_points
+        );
+
+        recorder.clear();
+        assert!(recorder.records().is_empty());
+    }
+
+    #[test]
+    fn test_path_jsonl() {
+        let recorder = PathRecorder::new(&[0], 2);
+        for sample in [1, 0] {
+            recorder.push(PathRecord {
+                sensor: 0,
+                sample,
+                origin: [0., 0., 1.],
+                vertices: vec![PathVertex {
+                    position: [0., 0., 2.],
+                    material: 1,
+                    cos: 0.5,
+                }],
+                escape: Some(([0., 0.6, 0.8], 3)),
+                weight: 0.9,
+            });
+        }
+        let jsonl = recorder.to_jsonl().unwrap();
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: PathRecord = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first, recorder.records()[0]);
+        assert_eq!(first.sample, 0);
+    }
+}
@@ -0,0 +1,296 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Maps of percentiles and exceedance fractions of annual results, such as
+//! "the illuminance exceeded during 95% of the occupied hours" (i.e., the 5th
+//! percentile, a robust minimum) of each sensor.
+//!
+//! Percentiles are found by selection instead of sorting: each one partitions
+//! what is left of the values of a sensor above the previous one, so asking
+//! for a few percentiles costs little more than reading the values.
+//!
+//! Sensors with non-finite values (e.g., invalid sensors) get `NaN`, as in an
+//! [`AnnualReport`](crate::AnnualReport).
+
+use crate::report::OccupancySchedule;
+use crate::time::AnnualSeries;
+use crate::Float;
+
+/// The part of a `schedule` that covers a `series`, after checking that it
+/// has occupied timesteps
+fn occupancy(series: &AnnualSeries, schedule: &OccupancySchedule) -> Result<Vec<Float>, String> {
+    let weights = schedule.on_axis(&series.axis)?.weights;
+    if weights.iter().all(|w| *w <= 0.0) {
+        return Err("The schedule has no occupied timesteps".to_string());
+    }
+    Ok(weights)
+}
+
+/// Copies the values of the timesteps of row `r` whose weight is positive
+/// into `buffer`, returning `false` if any value of the row is not finite
+fn occupied_values(
+    series: &AnnualSeries,
+    weights: &[Float],
+    r: usize,
+    buffer: &mut Vec<Float>,
+) -> Result<bool, String> {
+    buffer.clear();
+    for (t, w) in weights.iter().enumerate() {
+        let v = series.values.get(r, t)?;
+        if !v.is_finite() {
+            return Ok(false);
+        }
+        if *w > 0.0 {
+            buffer.push(v);
+        }
+    }
+    Ok(true)
+}
+
+/// Checks that every timestep is either occupied (`1`) or not (`0`), so that
+/// each one counts once towards a percentile
+pub(crate) fn check_binary(weights: &[Float]) -> Result<(), String> {
+    if let Some(w) = weights.iter().find(|w| **w != 0.0 && **w != 1.0) {
+        return Err(format!(
+            "Percentiles need a schedule whose timesteps are either occupied (1) or not (0), but found a weight of {}",
+            w
+        ));
+    }
+    Ok(())
+}
+
+/// The percentiles `ps` (from `0` to `100`, sorted in ascending order) of
+/// some `values`, interpolated linearly between order statistics. The values
+/// are reordered.
+pub(crate) fn select_percentiles(values: &mut [Float], ps: &[Float]) -> Vec<Float> {
+    let n = values.len();
+    let mut ret = Vec::with_capacity(ps.len());
+    // Everything before `start` is no larger than anything after it
+    let mut start = 0;
+    for p in ps {
+        let position = p / 100. * (n - 1) as Float;
+        let low = (position.floor() as usize).min(n - 1);
+        let (_, v, _) = values[start..].select_nth_unstable_by(low - start, |a, b| a.total_cmp(b));
+        let v = *v;
+        let t = position - low as Float;
+        let next = if t > 0.0 && low + 1 < n {
+            values[low + 1..]
+                .iter()
+                .copied()
+                .min_by(|a, b| a.total_cmp(b))
+                .unwrap_or(v)
+        } else {
+            v
+        };
+        ret.push((1. - t) * v + t * next);
+        start = low;
+    }
+    ret
+}
+
+/// One map per percentile in `ps` (from `0` to `100`), with the percentile of
+/// the values of each sensor over the occupied timesteps of a `schedule`,
+/// interpolated linearly between the values of the timesteps.
+///
+/// Every occupied timestep counts once, so the schedule can only have weights
+/// of `0` and `1`.
+pub fn percentile_maps(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    ps: &[Float],
+) -> Result<Vec<Vec<Float>>, String> {
+    if let Some(p) = ps.iter().find(|p| !(0.0..=100.).contains(*p)) {
+        return Err(format!("Percentiles go from 0 to 100, but found {}", p));
+    }
+    let weights = occupancy(series, schedule)?;
+    check_binary(&weights)?;
+    // Selected in ascending order, and returned in the order they were given
+    let mut order: Vec<usize> = (0..ps.len()).collect();
+    order.sort_by(|a, b| ps[*a].total_cmp(&ps[*b]));
+    let sorted: Vec<Float> = order.iter().map(|i| ps[*i]).collect();
+
+    let (n_sensors, _) = series.values.size();
+    let mut ret = vec![Vec::with_capacity(n_sensors); ps.len()];
+    let mut buffer = Vec::with_capacity(weights.len());
+    for r in 0..n_sensors {
+        let values = if occupied_values(series, &weights, r, &mut buffer)? {
+            select_percentiles(&mut buffer, &sorted)
+        } else {
+            vec![Float::NAN; ps.len()]
+        };
+        for (i, v) in order.iter().zip(values) {
+            ret[*i].push(v);
+        }
+    }
+    Ok(ret)
+}
+
+/// Like [`percentile_maps`], for a single percentile `p`
+pub fn percentile_map(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    p: Float,
+) -> Result<Vec<Float>, String> {
+    Ok(percentile_maps(series, schedule, &[p])?.remove(0))
+}
+
+/// The fraction of the occupied time—weighted by occupancy, like the Daylight
+/// Autonomy—during which the value of each sensor is at or above a `threshold`
+pub fn exceedance_fraction(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    threshold: Float,
+) -> Result<Vec<Float>, String> {
+    let weights = occupancy(series, schedule)?;
+    let total: Float = weights.iter().sum();
+    let (n_sensors, _) = series.values.size();
+    let mut ret = Vec::with_capacity(n_sensors);
+    'sensors: for r in 0..n_sensors {
+        let mut exceeded = 0.0;
+        for (t, w) in weights.iter().enumerate() {
+            let v = series.values.get(r, t)?;
+            if !v.is_finite() {
+                ret.push(Float::NAN);
+                continue 'sensors;
+            }
+            if v >= threshold {
+                exceeded += w;
+            }
+        }
+        ret.push(exceeded / total);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::time::{TimeAxis, HOURS_PER_YEAR};
+    use matrix::Matrix;
+    use validate::assert_close;
+
+    /// Three sensors over eight hours, the fourth of which is not occupied
+    fn series() -> (AnnualSeries, OccupancySchedule) {
+        let rows: [[Float; 8]; 3] = [
+            [5., 1., 4., 100., 2., 3., 8., 7.],
+            [1., 2., 3., Float::NAN, 5., 6., 7., 8.],
+            [2.; 8],
+        ];
+        let mut values = Matrix::new(0.0, 3, 8);
+        for (r, row) in rows.iter().enumerate() {
+            for (c, v) in row.iter().enumerate() {
+                values.set(r, c, *v).unwrap();
+            }
+        }
+        let series = AnnualSeries::new(values, TimeAxis::new(1, 0, 8).unwrap()).unwrap();
+        let mut weights = vec![1.; HOURS_PER_YEAR];
+        weights[3] = 0.;
+        let schedule = OccupancySchedule::from_weights(weights, 1).unwrap();
+        (series, schedule)
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let (series, schedule) = series();
+        // The occupied values of the first sensor are 1, 2, 3, 4, 5, 7 and 8
+        let maps = percentile_maps(&series, &schedule, &[95., 5., 50., 25., 0., 100.]).unwrap();
+        let expected = [7.7, 1.3, 4., 2.5, 1., 8.];
+        for (map, e) in maps.iter().zip(expected) {
+            assert_eq!(map.len(), 3);
+            assert_close!(map[0], e, 1e-5);
+            // Invalid sensors get NaN, even if they are invalid while unoccupied
+            assert!(map[1].is_nan());
+            assert_close!(map[2], 2.);
+        }
+        let median = percentile_map(&series, &schedule, 50.).unwrap();
+        assert_close!(median[0], 4.);
+
+        // Only whole timesteps, and percentiles within range
+        let mut weights = schedule.weights.clone();
+        weights[0] = 0.5;
+        let partial = OccupancySchedule::from_weights(weights, 1).unwrap();
+        assert!(percentile_map(&series, &partial, 50.).is_err());
+        assert!(percentile_map(&series, &schedule, 101.).is_err());
+        let empty = OccupancySchedule::from_weights(vec![0.; HOURS_PER_YEAR], 1).unwrap();
+        assert!(percentile_map(&series, &empty, 50.).is_err());
+    }
+
+    #[test]
+    fn test_selection_matches_sorting() {
+        let n = 1000;
+        let values: Vec<Float> = (0..n)
+            .map(|i| ((i * 7919 + 13) % 1009) as Float / 10.)
+            .collect();
+        let mut sorted = values.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let ps: Vec<Float> = (0..=40).map(|i| i as Float * 2.5).collect();
+        let mut buffer = values.clone();
+        let selected = select_percentiles(&mut buffer, &ps);
+        for (p, v) in ps.iter().zip(selected) {
+            let position = p / 100. * (n - 1) as Float;
+            let low = position.floor() as usize;
+            let high = (low + 1).min(n - 1);
+            let t = position - low as Float;
+            assert_close!(v, (1. - t) * sorted[low] + t * sorted[high], 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_exceedance() {
+        let (series, schedule) = series();
+        let fractions = exceedance_fraction(&series, &schedule, 4.).unwrap();
+        assert_close!(fractions[0], 4. / 7.);
+        assert!(fractions[1].is_nan());
+        assert_close!(fractions[2], 0.);
+        assert_close!(exceedance_fraction(&series, &schedule, 2.).unwrap()[2], 1.);
+
+        // Partly occupied timesteps count partly
+        let mut weights = schedule.weights.clone();
+        weights[0] = 0.5;
+        let partial = OccupancySchedule::from_weights(weights, 1).unwrap();
+        let fractions = exceedance_fraction(&series, &partial, 4.).unwrap();
+        assert_close!(fractions[0], 3.5 / 6.5);
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_percentile_map --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_percentile_map() {
+        let (n_sensors, n_steps) = (10_000, HOURS_PER_YEAR);
+        let mut values = Matrix::new(0.0, n_sensors, n_steps);
+        for r in 0..n_sensors {
+            for c in 0..n_steps {
+                let v = ((r * 7919 + c * 104729) % 1000) as Float;
+                values.set(r, c, v).unwrap();
+            }
+        }
+        let series = AnnualSeries::new(values, TimeAxis::annual(1).unwrap()).unwrap();
+        let schedule = OccupancySchedule::office_hours(n_steps, 1, 8., 18., None).unwrap();
+        let start = std::time::Instant::now();
+        let maps = percentile_maps(&series, &schedule, &[5., 50., 95.]).unwrap();
+        println!(
+            "3 percentiles of {} sensors x {} timesteps: {:?}",
+            n_sensors,
+            n_steps,
+            start.elapsed()
+        );
+        assert_eq!(maps[0].len(), n_sensors);
+    }
+}
@@ -0,0 +1,134 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::session::DCSession;
+use rendering::Scene;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+/// A [`Scene`] whose acceleration structure has already been built.
+///
+/// It can only be created through [`DCSession::prepare`], and it only hands
+/// out shared references, so the scene seen by the workers of a calculation
+/// cannot change (nor be rebuilt) once it has been prepared. It dereferences to
+/// a [`Scene`], so it can be passed wherever a `&Scene` is expected.
+pub struct PreparedScene {
+    scene: Scene,
+    build_time: Duration,
+}
+
+impl PreparedScene {
+    /// The time it took to build the acceleration structure
+    pub fn build_time(&self) -> Duration {
+        self.build_time
+    }
+
+    /// The prepared scene
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    /// Gives the scene back, e.g., for modifying it. It will need to be
+    /// prepared again before being used.
+    pub fn into_inner(self) -> Scene {
+        self.scene
+    }
+}
+
+impl Deref for PreparedScene {
+    type Target = Scene;
+
+    fn deref(&self) -> &Scene {
+        &self.scene
+    }
+}
+
+impl DCSession {
+    /// Builds the acceleration structure of a scene once, before any
+    /// ray is cast, and measures how long it takes. The resulting
+    /// [`PreparedScene`] can then be shared by all the sensors (and
+    /// threads) of a calculation, so none of them pays for—or races
+    /// on—building it.
+    pub fn prepare(&self, mut scene: Scene) -> PreparedScene {
+        let start = Instant::now();
+        scene.build_accelerator();
+        PreparedScene {
+            scene,
+            build_time: start.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::load_scene;
+    use crate::sensor::SensorSpec;
+    use crate::session::DCOptions;
+    use crate::Float;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use solar::ReinhartSky;
+
+    #[test]
+    fn test_prepare() {
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 200,
+                ..DCOptions::default()
+            },
+        );
+        let (scene, _) = load_scene("./tests/obstruction/courtyard.rad").unwrap();
+        let prepared = session.prepare(scene);
+        assert!(prepared.build_time() < Duration::from_secs(60));
+
+        let sensors: Vec<SensorSpec> = (0..8)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(1. + i as Float, 5., 0.5),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect();
+
+        // The progressive calculation traces sensors in parallel when the
+        // `parallel` feature is enabled; it must match the sequential one.
+        let sequential = session.calc_sensor_dc(&sensors, &prepared).unwrap();
+        let progressive = session
+            .run_progressive(&sensors, &prepared, 50, |_| true)
+            .unwrap();
+        for _ in 0..3 {
+            let again = session
+                .run_progressive(&sensors, &prepared, 50, |_| true)
+                .unwrap();
+            for i in 0..sensors.len() {
+                for b in 0..ReinhartSky::n_bins(1) {
+                    let v = progressive.matrix.matrix.get(i, b).unwrap();
+                    assert_eq!(v, again.matrix.matrix.get(i, b).unwrap());
+                    assert!((v - sequential.matrix.get(i, b).unwrap()).abs() < 1e-4);
+                }
+            }
+        }
+
+        let scene = prepared.into_inner();
+        assert!(!scene.triangles.is_empty());
+    }
+}
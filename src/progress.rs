@@ -0,0 +1,353 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::Float;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The default half-life of the smoothed rate of an [`EtaEstimator`]
+pub const DEFAULT_RATE_HALF_LIFE: Duration = Duration::from_secs(5);
+
+/// A source of time for an [`EtaEstimator`], so that it can be driven by
+/// something other than the system clock (e.g., in tests)
+pub trait Clock {
+    /// The time since some fixed origin
+    fn now(&self) -> Duration;
+}
+
+/// A [`Clock`] that measures the real time since it was created
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock(Instant);
+
+impl SystemClock {
+    /// Creates a clock whose origin is now
+    pub fn start() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::start()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Tallies the primary samples of a calculation as the workers trace them
+/// (see [`DCSession::with_sample_counter`](crate::DCSession::with_sample_counter)).
+/// It can be shared with another thread that takes snapshots periodically.
+///
+/// The samples that were planned but will never be traced (e.g., because a
+/// ray cap reduced the budget of a sensor) are _settled_ rather than removed
+/// from the total, so that neither the count of finished samples nor the
+/// total ever go backwards. Updates are relaxed, as they are only ever read
+/// through snapshots that tolerate being slightly behind.
+#[derive(Debug, Default)]
+pub struct SampleCounter {
+    traced: AtomicUsize,
+    settled: AtomicUsize,
+    total: AtomicUsize,
+}
+
+/// The state of a [`SampleCounter`] at some point
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SampleSnapshot {
+    /// The samples that were traced
+    pub traced: usize,
+
+    /// The samples that were traced or will not be
+    pub done: usize,
+
+    /// The samples of the whole calculation
+    pub total: usize,
+}
+
+impl SampleCounter {
+    /// Creates a counter for a calculation that plans to trace `total` samples
+    pub fn new(total: usize) -> Self {
+        Self {
+            total: AtomicUsize::new(total),
+            ..Self::default()
+        }
+    }
+
+    /// Records that `n` samples were traced
+    pub(crate) fn trace(&self, n: usize) {
+        self.traced.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Records that a budget of `planned` samples was fixed to `actual`
+    /// samples, which are (or will be) recorded through [`SampleCounter::trace`]
+    pub(crate) fn revise(&self, planned: usize, actual: usize) {
+        if actual < planned {
+            self.settled.fetch_add(planned - actual, Ordering::Relaxed);
+        } else {
+            self.total.fetch_add(actual - planned, Ordering::Relaxed);
+        }
+    }
+
+    /// The current state of the counter
+    pub fn snapshot(&self) -> SampleSnapshot {
+        let traced = self.traced.load(Ordering::Relaxed);
+        let settled = self.settled.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let done = traced + settled;
+        SampleSnapshot {
+            traced,
+            done,
+            total: total.max(done),
+        }
+    }
+}
+
+/// How far a calculation has gone in terms of primary samples, and how long
+/// it is expected to take to finish
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ProgressEstimate {
+    /// The primary samples that are done, over the whole calculation
+    pub samples_done: usize,
+
+    /// The primary samples of the whole calculation. It can grow as the
+    /// budgets of the sensors are fixed, but never shrinks.
+    pub samples_total: usize,
+
+    /// The smoothed rate at which samples are traced, once it is known
+    pub samples_per_second: Option<Float>,
+
+    /// The expected time until the calculation finishes, once the rate is known
+    pub eta: Option<Duration>,
+}
+
+impl ProgressEstimate {
+    /// The fraction of the samples that are done, between 0 and 1
+    pub fn fraction(&self) -> Float {
+        if self.samples_total == 0 {
+            1.
+        } else {
+            self.samples_done as Float / self.samples_total as Float
+        }
+    }
+}
+
+/// Turns successive snapshots of the samples of a calculation into a
+/// [`ProgressEstimate`].
+///
+/// The rate is an exponentially weighted average whose weights depend on the
+/// time between snapshots, so irregular snapshots do not bias it. Only traced
+/// samples count towards it: samples skipped by an adaptive budget finish the
+/// calculation sooner, but do not make tracing faster. Until every budget is
+/// fixed, the remaining samples are the planned ones, so the ETA is an upper
+/// bound that tightens as budgets are cut.
+#[derive(Debug)]
+pub struct EtaEstimator<C: Clock = SystemClock> {
+    clock: C,
+    half_life: Duration,
+    last: Option<(Duration, usize)>,
+    rate: Option<Float>,
+    reported: ProgressEstimate,
+}
+
+impl<C: Clock> EtaEstimator<C> {
+    /// Creates an estimator that reads the time from `clock`, whose rate has
+    /// a half-life of [`DEFAULT_RATE_HALF_LIFE`]
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            half_life: DEFAULT_RATE_HALF_LIFE,
+            last: None,
+            rate: None,
+            reported: ProgressEstimate::default(),
+        }
+    }
+
+    /// Sets how long it takes for a past rate to weigh half as much as it did
+    pub fn with_half_life(mut self, half_life: Duration) -> Self {
+        self.half_life = half_life;
+        self
+    }
+
+    /// Updates the estimate with the samples `traced` so far, out of which
+    /// `done` count as finished (i.e., including the settled ones), out of `total`.
+    /// The reported counts never go backwards, even if the snapshots do.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn update(&mut self, traced: usize, done: usize, total: usize) -> ProgressEstimate {
+        let now = self.clock.now();
+        match self.last {
+            None => {
+                if traced > 0 && now > Duration::ZERO {
+                    self.rate = Some(traced as Float / now.as_secs_f64() as Float);
+                }
+                self.last = Some((now, traced));
+            }
+            Some((then, last_traced)) if now > then && traced >= last_traced => {
+                let dt = (now - then).as_secs_f64() as Float;
+                let rate = (traced - last_traced) as Float / dt;
+                self.rate = Some(match self.rate {
+                    None => rate,
+                    Some(old) => {
+                        let half_life = self.half_life.as_secs_f64().max(1e-9) as Float;
+                        let keep = (0.5 as Float).powf(dt / half_life);
+                        keep * old + (1. - keep) * rate
+                    }
+                });
+                self.last = Some((now, traced));
+            }
+            // Snapshots at the same time, or behind a previous one, carry no rate
+            Some(_) => {}
+        }
+
+        let samples_done = done.max(self.reported.samples_done);
+        let samples_total = total.max(self.reported.samples_total).max(samples_done);
+        let remaining = samples_total - samples_done;
+        let samples_per_second = self.rate;
+        let eta = if remaining == 0 {
+            Some(Duration::ZERO)
+        } else {
+            samples_per_second
+                .filter(|r| *r > 0.)
+                .map(|r| Duration::from_secs_f64(remaining as f64 / r as f64))
+        };
+        self.reported = ProgressEstimate {
+            samples_done,
+            samples_total,
+            samples_per_second,
+            eta,
+        };
+        self.reported
+    }
+
+    /// Like [`EtaEstimator::update`], from a [`SampleCounter`]
+    pub fn update_from(&mut self, counter: &SampleCounter) -> ProgressEstimate {
+        let s = counter.snapshot();
+        self.update(s.traced, s.done, s.total)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock that moves only when told to
+    #[derive(Clone, Default)]
+    struct MockClock(Rc<Cell<Duration>>);
+
+    impl MockClock {
+        fn advance(&self, secs: f64) {
+            self.0.set(self.0.get() + Duration::from_secs_f64(secs));
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_eta_converges() {
+        // 10000 samples at 100 samples per second, with snapshots at irregular
+        // intervals and a slow start
+        let clock = MockClock::default();
+        let mut eta = EtaEstimator::new(clock.clone()).with_half_life(Duration::from_secs(2));
+        let counter = SampleCounter::new(10000);
+        assert_eq!(eta.update_from(&counter).eta, None);
+
+        counter.trace(20);
+        clock.advance(1.);
+        let first = eta.update_from(&counter);
+        assert_eq!(first.samples_done, 20);
+        assert!(first.eta.unwrap() > Duration::from_secs(300));
+
+        let mut t = 1.;
+        let mut errors = Vec::new();
+        for i in 0..60 {
+            let dt = if i % 3 == 0 { 0.5 } else { 1.25 };
+            clock.advance(dt);
+            t += dt;
+            counter.trace((100. * dt) as usize);
+            let estimate = eta.update_from(&counter);
+            let truth = (10000 - estimate.samples_done) as f64 / 100.;
+            errors.push((estimate.eta.unwrap().as_secs_f64() - truth).abs() / truth);
+        }
+        assert!(t > 60.);
+        // The error shrinks, and ends up small
+        assert!(errors[30..].iter().all(|e| *e < errors[0]));
+        assert!(*errors.last().unwrap() < 0.01);
+        let rate = eta.update_from(&counter).samples_per_second.unwrap();
+        assert!((rate - 100.).abs() < 1.);
+    }
+
+    #[test]
+    fn test_adaptive_budgets() {
+        let clock = MockClock::default();
+        let mut eta = EtaEstimator::new(clock.clone());
+        // Two sensors of 1000 samples
+        let counter = SampleCounter::new(2000);
+
+        // The first is cut to 500 samples, traced in 5 seconds
+        counter.revise(1000, 500);
+        counter.trace(500);
+        clock.advance(5.);
+        let a = eta.update_from(&counter);
+        assert_eq!((a.samples_done, a.samples_total), (1000, 2000));
+        // The rate only counts what was traced, so 1000 samples are 10 s away
+        assert_eq!(a.samples_per_second, Some(100.));
+        assert_eq!(a.eta, Some(Duration::from_secs(10)));
+
+        // The second one needs more than planned: the total grows
+        counter.revise(1000, 1200);
+        counter.trace(600);
+        clock.advance(6.);
+        let b = eta.update_from(&counter);
+        assert_eq!((b.samples_done, b.samples_total), (1600, 2200));
+        assert!(b.fraction() > a.fraction());
+
+        counter.trace(600);
+        clock.advance(6.);
+        let c = eta.update_from(&counter);
+        assert_eq!((c.samples_done, c.samples_total), (2200, 2200));
+        assert_eq!(c.eta, Some(Duration::ZERO));
+        assert_eq!(c.fraction(), 1.);
+    }
+
+    #[test]
+    fn test_monotonic() {
+        let clock = MockClock::default();
+        let mut eta = EtaEstimator::new(clock.clone());
+        clock.advance(1.);
+        let a = eta.update(100, 150, 1000);
+        // A snapshot taken at the same time carries no rate
+        let b = eta.update(200, 250, 1000);
+        assert_eq!(b.samples_done, 250);
+        assert_eq!(b.samples_per_second, a.samples_per_second);
+        // A stale snapshot (e.g., read from a lagging worker) does not move anything back
+        clock.advance(1.);
+        let c = eta.update(80, 120, 900);
+        assert_eq!((c.samples_done, c.samples_total), (250, 1000));
+        assert_eq!(c.samples_per_second, a.samples_per_second);
+    }
+}
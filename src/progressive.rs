@@ -0,0 +1,444 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::direct::{direct_dc_row, report_enclosed};
+use crate::events::{EventKind, EventLog};
+use crate::labeled_matrix::{LabeledMatrix, RowMetadata};
+use crate::resources::{rays_per_sample, RayCap, MIN_CAPPED_SAMPLES};
+use crate::rng::SampleStream;
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::sky_matrix::GroundConvention;
+use crate::stats::{merge_means, Welford};
+use crate::Float;
+use geometry3d::Ray3D;
+use matrix::Matrix;
+use rendering::colour_matrix::colour_matrix_to_radiance;
+use rendering::Scene;
+use solar::ReinhartSky;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The state of a progressive calculation, passed to the callback
+/// of [`DCSession::run_progressive`] after each round of samples.
+#[derive(Debug, Clone)]
+pub struct ProgressiveSnapshot {
+    /// The running mean of the Daylight Coefficients of each sensor
+    pub matrix: LabeledMatrix,
+
+    /// The standard error of the sum of the coefficients of each sensor
+    /// (i.e., of each row). It is infinite when it cannot be estimated yet.
+    pub standard_errors: Vec<Float>,
+
+    /// The number of samples sent from each sensor so far, except from those
+    /// that the ray budget cut short (whose rows say how many they got)
+    pub samples_per_sensor: usize,
+
+    /// The number of rounds performed so far, starting from `1`
+    pub round: usize,
+}
+
+/// What has been accumulated for a sensor
+struct SensorProgress {
+    index: usize,
+    samples: SampleStream,
+    /// The samples it gets in total, which the ray budget may have cut short
+    target: usize,
+    /// The samples it got so far
+    done: usize,
+    mean: Vec<Float>,
+    totals: Welford,
+    escaped: usize,
+    events: EventLog,
+}
+
+impl DCSession {
+    /// Calculates the Daylight Coefficients of a set of sensors in rounds of
+    /// `batch_size` samples per sensor, until `n_ambient_samples` are reached.
+    ///
+    /// After each round, `callback` receives a snapshot with the running mean of
+    /// the coefficients and an estimate of their standard error. Returning `false`
+    /// from the callback stops the calculation. Every round goes through all the
+    /// sensors, so even the first snapshot covers the whole grid.
+    ///
+    /// The last snapshot is returned. When `max_depth` is `0`, the sensors are
+    /// moved off surfaces and the [`RayBudget`](crate::RayBudget) is shared among
+    /// them as by [`DCSession::calc_sensor_dc`], and the samples drawn are the same,
+    /// so the final matrix matches it (in a serial calculation if the budget runs
+    /// out, as otherwise the sensors reserve their samples in any order). The
+    /// [`DCOptions::noise_floor`](crate::DCOptions::noise_floor) is not applied,
+    /// though. Otherwise, each round is calculated by the `DCFactory`, and
+    /// standard errors are estimated from the spread between rounds, which stop
+    /// once the ray budget runs out (after the first one).
+    pub fn run_progressive<F>(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        batch_size: usize,
+        mut callback: F,
+    ) -> Result<ProgressiveSnapshot, String>
+    where
+        F: FnMut(&ProgressiveSnapshot) -> bool,
+    {
+        if batch_size == 0 {
+            return Err(
+                "The batch size of a progressive calculation must be at least 1".to_string(),
+            );
+        }
+        self.check_budget(sensors.len())?;
+        let options = self.options();
+        if !options.is_direct() {
+            self.check_factory_support(sensors)?;
+            self.check_factory_budget()?;
+        }
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut events);
+        if let Some((message, _)) = self.ray_budget_excess(sensors.len()) {
+            events.push(EventKind::RayBudgetExceeded, None, None, 1, message);
+        }
+        let cap = options.ray_budget.as_ref().map(RayCap::new);
+        let rays_per_sample = rays_per_sample(options);
+
+        let sky = ReinhartSky::new(self.mf());
+        let n_bins = ReinhartSky::n_bins(self.mf());
+        let requested = options.n_ambient_samples;
+        let mut states: Vec<SensorProgress> = sensors
+            .iter()
+            .enumerate()
+            .map(|(i, sensor)| {
+                let mut events = EventLog::new();
+                // The DCFactory is capped between rounds instead
+                let target = match (&cap, options.is_direct()) {
+                    (Some(cap), true) => {
+                        cap.reserve(requested, rays_per_sample, MIN_CAPPED_SAMPLES)
+                    }
+                    _ => requested,
+                };
+                if target < requested {
+                    events.push(
+                        EventKind::RayCapReached,
+                        Some(i),
+                        Some(sensor.ray),
+                        requested - target,
+                        format!(
+                            "the ray budget ran out, so the sensor was traced with {} samples instead of {}",
+                            target, requested
+                        ),
+                    );
+                }
+                SensorProgress {
+                    index: i,
+                    samples: SampleStream::new(options.sampling, options.seed, i as u64),
+                    target,
+                    done: 0,
+                    mean: vec![0.0; n_bins],
+                    totals: Welford::new(),
+                    escaped: 0,
+                    events,
+                }
+            })
+            .collect();
+        let rays: Vec<Ray3D> = sensors.iter().map(|s| s.ray).collect();
+
+        let mut done = 0;
+        let mut round = 0;
+        loop {
+            let batch = batch_size.min(requested - done);
+            if options.is_direct() {
+                let trace = |(state, sensor): (&mut SensorProgress, &SensorSpec)| {
+                    let batch = batch.min(state.target - state.done);
+                    if batch == 0 {
+                        return Ok(());
+                    }
+                    let row = direct_dc_row(
+                        scene,
+                        sensor,
+                        state.index,
+                        &sky,
+                        n_bins,
+                        batch,
+                        &mut state.samples,
+                        self.trace_hints(),
+                        false,
+                        &mut state.events,
+                    )?;
+                    merge_means(&mut state.mean, state.done, &row.bins.values(), batch);
+                    state.totals.merge(&row.totals);
+                    state.escaped += row.escaped;
+                    state.done += batch;
+                    Ok::<(), String>(())
+                };
+                #[cfg(feature = "parallel")]
+                let serial = options.serial;
+                #[cfg(not(feature = "parallel"))]
+                let serial = true;
+                if serial {
+                    states.iter_mut().zip(sensors.iter()).try_for_each(trace)?;
+                } else {
+                    #[cfg(feature = "parallel")]
+                    states
+                        .par_iter_mut()
+                        .zip(sensors.par_iter())
+                        .try_for_each(trace)?;
+                }
+            } else {
+                let batch_samples = sensors.len() * batch;
+                if let Some(cap) = &cap {
+                    let min = if round == 0 { batch_samples } else { 0 };
+                    if cap.reserve(batch_samples, rays_per_sample, min) < batch_samples {
+                        events.push(
+                            EventKind::RayCapReached,
+                            None,
+                            None,
+                            (requested - done) * sensors.len(),
+                            format!(
+                                "the ray budget ran out, so the sensors were traced with {} samples instead of {}",
+                                done, requested
+                            ),
+                        );
+                        return snapshot(sensors, &states, &events, n_bins, done, round, false);
+                    }
+                }
+                let dc = self.factory_dc(&rays, scene, batch)?;
+                let dc = colour_matrix_to_radiance(&dc);
+                let mut row = vec![0.0; n_bins];
+                for (i, state) in states.iter_mut().enumerate() {
+                    for (bin, v) in row.iter_mut().enumerate() {
+                        *v = dc.get(i, bin)?;
+                    }
+                    merge_means(&mut state.mean, state.done, &row, batch);
+                    state.totals.push(row.iter().sum());
+                    state.done += batch;
+                }
+            }
+            done += batch;
+            round += 1;
+
+            let direct = options.is_direct();
+            let snapshot = snapshot(sensors, &states, &events, n_bins, done, round, direct)?;
+            let keep_going = callback(&snapshot);
+            if !keep_going || done >= requested {
+                return Ok(snapshot);
+            }
+        }
+    }
+}
+
+fn snapshot(
+    sensors: &[SensorSpec],
+    states: &[SensorProgress],
+    run_events: &EventLog,
+    n_bins: usize,
+    samples_per_sensor: usize,
+    round: usize,
+    direct: bool,
+) -> Result<ProgressiveSnapshot, String> {
+    let mut matrix = Matrix::new(0.0, sensors.len(), n_bins);
+    let mut events = run_events.clone();
+    for (i, state) in states.iter().enumerate() {
+        for (bin, v) in state.mean.iter().enumerate() {
+            matrix.set(i, bin, *v)?;
+        }
+        events.merge(state.events.clone());
+        if direct {
+            report_enclosed(&mut events, i, &sensors[i], state.escaped, state.done);
+        }
+    }
+    let rows = sensors
+        .iter()
+        .zip(states.iter())
+        .map(|(s, state)| RowMetadata {
+            ray: s.ray,
+            mask: s.mask.clone(),
+            zone: s.zone.clone(),
+            n_samples: state.done,
+            id: s.sensor_id(),
+            angular_response: s.angular_response.clone(),
+        })
+        .collect();
+    Ok(ProgressiveSnapshot {
+        matrix: LabeledMatrix {
+            matrix,
+            rows,
+            events,
+            ground: GroundConvention::default(),
+        },
+        standard_errors: states.iter().map(|s| s.totals.standard_error()).collect(),
+        samples_per_sensor,
+        round,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::resources::{BudgetAction, RayBudget};
+    use crate::sensor::AngularMask;
+    use crate::session::DCOptions;
+    use crate::{Material, SceneBuilder};
+    use geometry3d::{Point3D, Vector3D};
+    use validate::assert_close;
+
+    fn sensors(n: usize) -> Vec<SensorSpec> {
+        (0..n)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(i as Float, 0., 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+                .with_mask(AngularMask::predicate(|dir| dir.x > 0.0))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_progressive() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 1000,
+                ..DCOptions::default()
+            },
+        );
+        let sensors = sensors(3);
+        let n_bins = ReinhartSky::n_bins(1);
+
+        let mut errors = Vec::new();
+        let last = session
+            .run_progressive(&sensors, &scene, 100, |snap| {
+                // Every sensor gets samples from the first round on
+                for i in 0..3 {
+                    let total: Float = (0..n_bins)
+                        .map(|b| snap.matrix.matrix.get(i, b).unwrap())
+                        .sum();
+                    assert!(total > 0.0);
+                }
+                assert_eq!(snap.matrix.rows[0].n_samples, snap.samples_per_sensor);
+                errors.push(snap.standard_errors[0]);
+                true
+            })
+            .unwrap();
+        assert_eq!(last.round, 10);
+        assert_eq!(last.samples_per_sensor, 1000);
+        assert!(errors[9] < errors[0]);
+
+        // Same samples as a one-shot calculation
+        let full = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        for i in 0..3 {
+            for b in 0..n_bins {
+                assert_close!(
+                    last.matrix.matrix.get(i, b).unwrap(),
+                    full.matrix.get(i, b).unwrap(),
+                    1e-4
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_progressive_on_floor() {
+        // Sensors on a floor, which is covered by a low canopy
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("grey", Material::plastic(0.2))
+            .unwrap();
+        let square = |z: Float| {
+            [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, z))
+        };
+        builder.add_polygon("grey", "floor", &square(0.)).unwrap();
+        builder.add_polygon("grey", "canopy", &square(3.)).unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = (0..3)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(i as Float, 0., 0.),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 300,
+            serial: true,
+            ..DCOptions::default()
+        };
+        // Enough rays for the first sensor and part of the second
+        let budget = RayBudget {
+            max_rays: 400,
+            action: BudgetAction::Warn,
+        };
+        for ray_budget in [None, Some(budget)] {
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    ray_budget,
+                    ..options
+                },
+            );
+            let last = session
+                .run_progressive(&sensors, &scene, 64, |_| true)
+                .unwrap();
+            let full = session.calc_sensor_dc(&sensors, &scene).unwrap();
+            assert_eq!(last.matrix.events.of_kind(EventKind::OnSurface).count(), 3);
+            for i in 0..3 {
+                assert_eq!(last.matrix.rows[i].ray, full.rows[i].ray);
+                assert_eq!(last.matrix.rows[i].n_samples, full.rows[i].n_samples);
+                for b in 0..ReinhartSky::n_bins(1) {
+                    assert_close!(
+                        last.matrix.matrix.get(i, b).unwrap(),
+                        full.matrix.get(i, b).unwrap(),
+                        1e-4
+                    );
+                }
+            }
+            let capped = last.matrix.events.of_kind(EventKind::RayCapReached).count();
+            assert_eq!(capped, if ray_budget.is_some() { 2 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_progressive_stop_early() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 1000,
+                ..DCOptions::default()
+            },
+        );
+        let last = session
+            .run_progressive(&sensors(2), &scene, 64, |snap| snap.round < 2)
+            .unwrap();
+        assert_eq!(last.round, 2);
+        assert_eq!(last.samples_per_sensor, 128);
+        assert!(last.standard_errors.iter().all(|e| e.is_finite()));
+
+        assert!(session
+            .run_progressive(&sensors(2), &scene, 0, |_| true)
+            .is_err());
+    }
+}
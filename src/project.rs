@@ -0,0 +1,616 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Projects: everything a calculation needs—the scene, the sensors, the
+//! options, the skies and where the results go—in a single JSON file.
+//!
+//! A project looks like this (paths are relative to the project file):
+//!
+//! ```json
+//! {
+//!     "scene": ["room.rad"],
+//!     "mf": 1,
+//!     "options": { "max_depth": 0, "n_ambient_samples": 1000 },
+//!     "grids": [
+//!         { "name": "floor", "sensors": { "File": "floor.pts" } },
+//!         { "name": "desk", "sensors": { "Points": [[1, 1, 0.8, 0, 0, 1]] } }
+//!     ],
+//!     "sky": { "matrix": "sky.mtx" },
+//!     "output": { "dir": "results" }
+//! }
+//! ```
+//!
+//! Without `options`, the defaults of [`DCOptions`] are used (within them,
+//! only `max_depth` and `n_ambient_samples` are required). [`Project::run`]
+//! checks the whole project (see [`Project::validate`]) before tracing
+//! anything, and then writes the Daylight Coefficients of each grid, their
+//! annual irradiance if there is a sky, and a `manifest.json` that records how
+//! each was calculated. A grid that fails is recorded there too, and the others
+//! carry on (see [`Project::run_batch`]). The command line runs projects as
+//! `simple_light run project.json`.
+
+use crate::annual::annual_irradiance;
+use crate::batch::{BatchError, BatchOutcome};
+use crate::manifest::RunManifest;
+use crate::matrix_io::{load_binary, read_sky_mtx, save_dc_binary_with_manifest, save_mtx};
+use crate::scene_builder::check_name;
+use crate::scene_loading::{load_scenes, SceneReport};
+use crate::sensor::SensorSpec;
+use crate::sensor_id::sensors_csv;
+use crate::session::{DCOptions, DCSession};
+use crate::sky::SkyBasis;
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::Scene;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Where the sensors of a grid come from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SensorSource {
+    /// The position and direction of each sensor, as `[x, y, z, dx, dy, dz]`
+    Points(Vec<[Float; 6]>),
+
+    /// A file with one sensor per line, as `x y z dx dy dz` (i.e., the
+    /// input of `rtrace`). See [`parse_pts`].
+    File(PathBuf),
+}
+
+/// A named set of sensors of a [`Project`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectGrid {
+    /// The name of the grid, which names its results
+    pub name: String,
+
+    /// The sensors of the grid
+    pub sensors: SensorSource,
+}
+
+/// The skies of a [`Project`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectSky {
+    /// An annual sky matrix (one row per bin and one column per timestep), in
+    /// text format if it ends in `.mtx` and in binary otherwise. Weather files
+    /// are turned into sky matrices beforehand (e.g., with `gendaymtx`, or
+    /// through a [`SkyMatrixCache`](crate::SkyMatrixCache)).
+    pub matrix: PathBuf,
+}
+
+/// Where, and how, the results of a [`Project`] are written
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectOutput {
+    /// The directory of the results, which is created if needed
+    pub dir: PathBuf,
+
+    /// Writes the Daylight Coefficients in binary (as `<grid>.bin`) rather
+    /// than in text (as `<grid>.mtx`)
+    #[serde(default)]
+    pub binary: bool,
+}
+
+/// A scene, some grids of sensors, the options of their calculation, the skies
+/// and where the results go (see the [module documentation](self))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    /// The Radiance files of the scene, loaded together as [`load_scenes`] does
+    pub scene: Vec<PathBuf>,
+
+    /// The subdivision of the Reinhart sky
+    pub mf: usize,
+
+    /// The options of the calculation
+    #[serde(default)]
+    pub options: DCOptions,
+
+    /// The grids of sensors, which are calculated in order
+    pub grids: Vec<ProjectGrid>,
+
+    /// The skies, if the annual irradiance is wanted
+    #[serde(default)]
+    pub sky: Option<ProjectSky>,
+
+    /// Where the results go
+    pub output: ProjectOutput,
+
+    /// The directory relative paths are relative to
+    #[serde(skip)]
+    root: PathBuf,
+}
+
+/// What was written for a grid of a [`Project`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GridRun {
+    /// The name of the grid
+    pub name: String,
+
+    /// The files written for the grid
+    pub files: Vec<PathBuf>,
+
+    /// How the Daylight Coefficients of the grid were calculated
+    pub manifest: RunManifest,
+}
+
+/// What [`Project::run`] did, which it also writes as `manifest.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectSummary {
+    /// One element per grid that worked, in order
+    pub grids: Vec<GridRun>,
+
+    /// Why each of the other grids failed
+    #[serde(default)]
+    pub failed: Vec<BatchError>,
+}
+
+/// Reads sensors written as `x y z dx dy dz`, one per line. Empty lines and
+/// those starting with `#` are skipped.
+pub fn parse_pts(text: &str) -> Result<Vec<SensorSpec>, String> {
+    read_pts(text).map_err(|(_, e)| e)
+}
+
+/// Like [`parse_pts`], but errors also tell the index of the sensor that could
+/// not be read
+fn read_pts(text: &str) -> Result<Vec<SensorSpec>, (usize, String)> {
+    let mut ret = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let index = ret.len();
+        let values = line
+            .split_whitespace()
+            .map(|v| v.parse::<Float>())
+            .collect::<Result<Vec<Float>, _>>()
+            .map_err(|e| (index, format!("Invalid sensor in line {}: {}", i + 1, e)))?;
+        let values: [Float; 6] = values.try_into().map_err(|v: Vec<Float>| {
+            (
+                index,
+                format!(
+                    "Sensors need 6 numbers (x y z dx dy dz), but line {} has {}",
+                    i + 1,
+                    v.len()
+                ),
+            )
+        })?;
+        ret.push(to_sensor(&values, i).map_err(|e| (index, e))?);
+    }
+    Ok(ret)
+}
+
+/// The sensor at `[x, y, z, dx, dy, dz]`, which is the `i`-th of its source
+fn to_sensor(values: &[Float; 6], i: usize) -> Result<SensorSpec, String> {
+    let [x, y, z, dx, dy, dz] = *values;
+    let direction = Vector3D::new(dx, dy, dz);
+    if values.iter().any(|v| !v.is_finite()) || direction.is_zero() {
+        return Err(format!(
+            "Sensor {} needs a finite position and a direction, but found {:?}",
+            i + 1,
+            values
+        ));
+    }
+    Ok(Ray3D {
+        origin: Point3D::new(x, y, z),
+        direction: direction.get_normalized(),
+    }
+    .into())
+}
+
+impl Project {
+    /// Reads a project from a JSON file. Its relative paths are taken
+    /// as relative to the directory of the file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read project '{}': {}", path.display(), e))?;
+        let root = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_json(&json, root)
+    }
+
+    /// Reads a project from JSON, whose relative paths are relative to `root`
+    pub fn from_json<P: AsRef<Path>>(json: &str, root: P) -> Result<Self, String> {
+        let mut ret: Self =
+            serde_json::from_str(json).map_err(|e| format!("Could not read project: {}", e))?;
+        ret.root = root.as_ref().to_path_buf();
+        Ok(ret)
+    }
+
+    /// Serialises the project into JSON. Its paths are written as they are,
+    /// so relative paths are still relative to the same directory.
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Could not serialise project: {}", e))
+    }
+
+    /// Sets the directory relative paths are relative to (by default, the
+    /// current one, or that of the file for [`Project::from_file`])
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    /// Where a path of the project is
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Checks the whole project without tracing anything: the options, the
+    /// names of the grids (which must be unique, as they name files) and that
+    /// every file it refers to exists.
+    pub fn validate(&self) -> Result<(), String> {
+        SkyBasis::new(self.mf)?;
+        self.options.validate()?;
+        if self.scene.is_empty() {
+            return Err("A project needs at least one scene file".to_string());
+        }
+        if self.grids.is_empty() {
+            return Err("A project needs at least one grid of sensors".to_string());
+        }
+        for (i, grid) in self.grids.iter().enumerate() {
+            check_name(&grid.name)?;
+            if grid.name.contains(['/', '\\']) {
+                return Err(format!(
+                    "Grid names cannot contain '/' or '\\', but found '{}'",
+                    grid.name
+                ));
+            }
+            if self.grids[..i].iter().any(|g| g.name == grid.name) {
+                return Err(format!("Grid '{}' appears more than once", grid.name));
+            }
+            if let SensorSource::Points(points) = &grid.sensors {
+                if points.is_empty() {
+                    return Err(format!("Grid '{}' has no sensors", grid.name));
+                }
+            }
+        }
+        let files = self
+            .scene
+            .iter()
+            .map(|f| ("scene", f))
+            .chain(self.grids.iter().filter_map(|g| match &g.sensors {
+                SensorSource::File(f) => Some(("sensor", f)),
+                SensorSource::Points(_) => None,
+            }))
+            .chain(self.sky.iter().map(|s| ("sky", &s.matrix)));
+        for (kind, file) in files {
+            let path = self.resolve(file);
+            if !path.is_file() {
+                return Err(format!(
+                    "The {} file '{}' does not exist",
+                    kind,
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The sensors of a grid
+    pub fn sensors(&self, grid: &ProjectGrid) -> Result<Vec<SensorSpec>, String> {
+        self.grid_sensors(grid)
+            .map_err(|e| format!("In grid '{}': {}", e.id, e.message))
+    }
+
+    /// The sensors of a grid, or why they could not be read (and which of
+    /// them, if one is to blame)
+    fn grid_sensors(&self, grid: &ProjectGrid) -> Result<Vec<SensorSpec>, BatchError> {
+        let ret = match &grid.sensors {
+            SensorSource::Points(points) => points
+                .iter()
+                .enumerate()
+                .map(|(i, p)| to_sensor(p, i).map_err(|e| (i, e)))
+                .collect::<Result<Vec<_>, _>>(),
+            SensorSource::File(file) => {
+                let path = self.resolve(file);
+                let text = std::fs::read_to_string(&path).map_err(|e| {
+                    BatchError::new(
+                        &grid.name,
+                        format!("Unable to read '{}': {}", path.display(), e),
+                    )
+                })?;
+                read_pts(&text)
+            }
+        }
+        .map_err(|(i, e)| BatchError::new(&grid.name, e).at_sensor(i))?;
+        if ret.is_empty() {
+            return Err(BatchError::new(&grid.name, "There are no sensors"));
+        }
+        Ok(ret)
+    }
+
+    /// The annual sky matrix, if any
+    fn sky_matrix(&self, basis: &SkyBasis) -> Result<Option<Matrix>, String> {
+        let sky = match &self.sky {
+            Some(sky) => sky,
+            None => return Ok(None),
+        };
+        let path = self.resolve(&sky.matrix);
+        let matrix = if path.extension().is_some_and(|e| e == "mtx") {
+            let file = std::fs::File::open(&path)
+                .map_err(|e| format!("Unable to read '{}': {}", path.display(), e))?;
+            read_sky_mtx(&mut std::io::BufReader::new(file), basis)?.0
+        } else {
+            load_binary(&path)?.into_dense()?
+        };
+        basis.check_sky(&matrix)?;
+        Ok(Some(matrix))
+    }
+
+    /// Runs the whole project: checks it, reads every input (so that mistakes
+    /// show before tracing), and then calculates each grid in order, writing
+    /// `<grid>.mtx` (or `<grid>.bin`) with its Daylight Coefficients,
+    /// `<grid>_irradiance.mtx` with its annual irradiance if there is a sky,
+    /// `<grid>_sensors.csv` with the ids of the sensors of their rows (see
+    /// [`sensors_csv`]), and finally `manifest.json` with the returned summary.
+    /// Every result embeds its [`RunManifest`].
+    ///
+    /// Grids that fail do not stop the others, whose results are written anyway,
+    /// but make this fail at the end (see [`Project::run_batch`]).
+    pub fn run(&self) -> Result<ProjectSummary, String> {
+        let outcome = self.run_batch()?;
+        if !outcome.is_success() {
+            return Err(outcome.error_report());
+        }
+        Ok(ProjectSummary {
+            grids: outcome.succeeded.into_iter().map(|(_, g)| g).collect(),
+            failed: Vec::new(),
+        })
+    }
+
+    /// Like [`Project::run`], but only fails if what the grids share is wrong
+    /// (e.g., the project does not validate, or the scene or the sky cannot be
+    /// read). Grids whose sensors cannot be read or calculated are recorded
+    /// in the outcome, and in `manifest.json`.
+    pub fn run_batch(&self) -> Result<BatchOutcome<GridRun>, String> {
+        self.validate()?;
+        let basis = SkyBasis::new(self.mf)?;
+        let sensors: Vec<Result<Vec<SensorSpec>, BatchError>> =
+            self.grids.iter().map(|g| self.grid_sensors(g)).collect();
+        let sky = self.sky_matrix(&basis)?;
+        let paths: Vec<PathBuf> = self.scene.iter().map(|f| self.resolve(f)).collect();
+        let (mut scene, report) = load_scenes(&paths)?;
+        scene.build_accelerator();
+
+        let dir = self.resolve(&self.output.dir);
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Unable to create output directory '{}': {}",
+                dir.display(),
+                e
+            )
+        })?;
+        let session = DCSession::from_basis(basis, self.options);
+        let mut outcome = BatchOutcome::default();
+        for (grid, sensors) in self.grids.iter().zip(sensors) {
+            let result = sensors.and_then(|sensors| {
+                self.run_grid(
+                    &session,
+                    grid,
+                    &sensors,
+                    &scene,
+                    &report,
+                    sky.as_ref(),
+                    &dir,
+                )
+                .map_err(|e| BatchError::new(&grid.name, e))
+            });
+            outcome.push(&grid.name, result);
+        }
+        let summary = ProjectSummary {
+            grids: outcome.succeeded.iter().map(|(_, g)| g.clone()).collect(),
+            failed: outcome.failed.clone(),
+        };
+        let json = serde_json::to_string_pretty(&summary)
+            .map_err(|e| format!("Could not serialise the summary of the project: {}", e))?;
+        let path = dir.join("manifest.json");
+        std::fs::write(&path, json)
+            .map_err(|e| format!("Unable to write '{}': {}", path.display(), e))?;
+        Ok(outcome)
+    }
+
+    /// Calculates a grid, writing its results into `dir`
+    #[allow(clippy::too_many_arguments)]
+    fn run_grid(
+        &self,
+        session: &DCSession,
+        grid: &ProjectGrid,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+        sky: Option<&Matrix>,
+        dir: &Path,
+    ) -> Result<GridRun, String> {
+        let (dc, manifest) = session.calc_sensor_dc_with_manifest(sensors, scene, report)?;
+        let mut files = Vec::new();
+        if self.output.binary {
+            let path = dir.join(format!("{}.bin", grid.name));
+            save_dc_binary_with_manifest(&path, &dc.matrix, &session.basis()?, &manifest)?;
+            files.push(path);
+        } else {
+            let path = dir.join(format!("{}.mtx", grid.name));
+            save_mtx(&path, &dc.matrix, Some(&manifest))?;
+            files.push(path);
+        }
+        if let Some(sky) = sky {
+            let path = dir.join(format!("{}_irradiance.mtx", grid.name));
+            save_mtx(&path, &annual_irradiance(&dc.matrix, sky)?, Some(&manifest))?;
+            files.push(path);
+        }
+        let path = dir.join(format!("{}_sensors.csv", grid.name));
+        std::fs::write(&path, sensors_csv(&dc.rows))
+            .map_err(|e| format!("Unable to write '{}': {}", path.display(), e))?;
+        files.push(path);
+        Ok(GridRun {
+            name: grid.name.clone(),
+            files,
+            manifest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// The project of `tests/project`, without reading its JSON
+    fn tiny() -> Project {
+        Project {
+            scene: vec!["courtyard.rad".into()],
+            mf: 1,
+            options: DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 100,
+                ..DCOptions::default()
+            },
+            grids: vec![
+                ProjectGrid {
+                    name: "floor".to_string(),
+                    sensors: SensorSource::File("floor.pts".into()),
+                },
+                ProjectGrid {
+                    name: "bench".to_string(),
+                    sensors: SensorSource::Points(vec![[2., 3.5, 0.45, 0., 0., 1.]]),
+                },
+            ],
+            sky: Some(ProjectSky {
+                matrix: "sky.mtx".into(),
+            }),
+            output: ProjectOutput {
+                dir: "results".into(),
+                binary: false,
+            },
+            root: PathBuf::new(),
+        }
+        .with_root("./tests/project")
+    }
+
+    #[test]
+    fn test_parse_pts() {
+        let sensors = parse_pts("# a comment\n1 2 3 0 0 2\n\n  4 5 6 1 0 0  \n").unwrap();
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(sensors[0].ray.origin, Point3D::new(1., 2., 3.));
+        assert_eq!(sensors[0].ray.direction, Vector3D::new(0., 0., 1.));
+        assert_eq!(sensors[1].ray.direction, Vector3D::new(1., 0., 0.));
+
+        let err = parse_pts("1 2 3 0 0 1\n1 2 3 0 0\n").unwrap_err();
+        assert!(err.contains("line 2 has 5"), "{}", err);
+        assert!(parse_pts("1 2 3 0 0 x").unwrap_err().contains("line 1"));
+        assert!(parse_pts("1 2 3 0 0 0").is_err());
+        assert!(parse_pts("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let project = tiny();
+        project.validate().unwrap();
+        assert_eq!(project.sensors(&project.grids[0]).unwrap().len(), 3);
+        assert_eq!(project.sensors(&project.grids[1]).unwrap().len(), 1);
+
+        // Dangling files, whatever refers to them
+        let mut missing = tiny();
+        missing.scene.push("nowhere.rad".into());
+        let err = missing.validate().unwrap_err();
+        assert!(
+            err.contains("scene file") && err.contains("nowhere.rad"),
+            "{}",
+            err
+        );
+        let mut missing = tiny();
+        missing.grids[0].sensors = SensorSource::File("nowhere.pts".into());
+        assert!(missing.validate().unwrap_err().contains("sensor file"));
+        let mut missing = tiny();
+        missing.sky.as_mut().unwrap().matrix = "nowhere.mtx".into();
+        assert!(missing.validate().unwrap_err().contains("sky file"));
+
+        // Names have to tell the results apart
+        let mut repeated = tiny();
+        repeated.grids[1].name = "floor".to_string();
+        let err = repeated.validate().unwrap_err();
+        assert!(err.contains("'floor' appears more than once"), "{}", err);
+        for name in ["", "a b", "a/b"] {
+            let mut bad = tiny();
+            bad.grids[1].name = name.to_string();
+            assert!(bad.validate().is_err());
+        }
+
+        // The rest of the project
+        let mut empty = tiny();
+        empty.grids[1].sensors = SensorSource::Points(Vec::new());
+        assert!(empty.validate().is_err());
+        empty.grids.clear();
+        assert!(empty.validate().is_err());
+        let mut bad = tiny();
+        bad.mf = 0;
+        assert!(bad.validate().is_err());
+        let mut bad = tiny();
+        bad.options.n_ambient_samples = 0;
+        assert!(bad.validate().is_err());
+
+        // Nothing is traced (or written) when the project is wrong
+        let dir = std::env::temp_dir().join(format!("light_project_bad_{}", std::process::id()));
+        let mut bad = tiny();
+        bad.output.dir = dir.clone();
+        bad.grids[1].name = "floor".to_string();
+        assert!(bad.run().is_err());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_failing_grid() {
+        let dir = std::env::temp_dir().join(format!("light_project_batch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pts = dir.join("broken.pts");
+        std::fs::write(&pts, "2 2 0.8 0 0 1\nnan 2 0.8 0 0 1\n").unwrap();
+        let mut project = tiny();
+        project.output.dir = dir.join("results");
+        project.grids.insert(
+            1,
+            ProjectGrid {
+                name: "broken".to_string(),
+                sensors: SensorSource::File(pts),
+            },
+        );
+        project.validate().unwrap();
+
+        // The grids around the broken one are written anyway
+        let outcome = project.run_batch().unwrap();
+        let names: Vec<&str> = outcome.succeeded.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["floor", "bench"]);
+        assert_eq!(outcome.failed.len(), 1);
+        let error = &outcome.failed[0];
+        assert_eq!(error.id, "broken");
+        assert_eq!(error.sensor, Some(1));
+        assert!(error.message.contains("Sensor 2 needs"), "{}", error);
+        for name in ["floor.mtx", "bench.mtx", "manifest.json"] {
+            assert!(
+                project.output.dir.join(name).is_file(),
+                "{} is missing",
+                name
+            );
+        }
+        assert!(!project.output.dir.join("broken.mtx").exists());
+
+        // ... but the project as a whole did not work
+        let err = project.run().unwrap_err();
+        assert!(err.starts_with("1 of the 3 items"), "{}", err);
+        assert!(err.contains("In 'broken'"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
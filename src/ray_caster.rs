@@ -0,0 +1,236 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A seam between the direct tracer and whatever intersects its rays.
+//!
+//! Most of the time goes into intersecting the rays that leave the sensors.
+//! Some backends (e.g., Embree) intersect several rays at once much faster
+//! than one after the other, so a [`RayCaster`] can take a packet of
+//! [`PACKET_SIZE`] rays. When one that reports [`RayCaster::supports_packets`]
+//! is set on a [`DCSession`](crate::DCSession) (see
+//! [`DCSession::with_ray_caster`](crate::DCSession::with_ray_caster)), the
+//! direct tracer gathers the first rays of each sensor into packets. Otherwise,
+//! they are cast one by one. The results are the same either way.
+
+use crate::Float;
+use geometry3d::{Point3D, Ray3D};
+use rendering::{Ray, Scene};
+use std::sync::Arc;
+
+/// The number of rays in a packet
+pub const PACKET_SIZE: usize = 8;
+
+/// The closest intersection of a ray with the scene
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    /// The index of the triangle that was hit, as in the `Scene`
+    pub triangle: usize,
+    /// Where the triangle was hit
+    pub point: Point3D,
+    /// How far from the origin of the ray the triangle was hit
+    pub distance: Float,
+}
+
+/// Something that intersects rays with a scene. Each method is implemented in
+/// terms of the other, so implementors must provide at least one of them.
+pub trait RayCaster: Send + Sync {
+    /// The closest intersection of a ray, if any
+    fn cast(&self, ray: &Ray3D) -> Option<Hit> {
+        self.cast_packet(&[*ray; PACKET_SIZE])[0]
+    }
+
+    /// The closest intersection of each ray of a packet, in order
+    fn cast_packet(&self, rays: &[Ray3D; PACKET_SIZE]) -> [Option<Hit>; PACKET_SIZE] {
+        rays.map(|ray| self.cast(&ray))
+    }
+
+    /// Whether casting a packet is faster than casting its rays one by one,
+    /// which makes the direct tracer gather its rays into packets. `false` by
+    /// default.
+    fn supports_packets(&self) -> bool {
+        false
+    }
+}
+
+/// The [`RayCaster`] of a [`DCSession`](crate::DCSession), shared by its clones
+#[derive(Clone)]
+pub(crate) struct SharedCaster(pub Arc<dyn RayCaster>);
+
+impl std::fmt::Debug for SharedCaster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "RayCaster {{ supports_packets: {} }}",
+            self.0.supports_packets()
+        )
+    }
+}
+
+/// Casts a ray into a `Scene`, reusing `aux`
+fn cast_into(scene: &Scene, ray: &Ray3D, aux: &mut Vec<usize>) -> Option<Hit> {
+    let mut r = Ray {
+        geometry: *ray,
+        ..Ray::default()
+    };
+    let triangle = scene.cast_ray(&mut r, aux)?;
+    Some(Hit {
+        triangle,
+        point: r.interaction.point,
+        distance: r.interaction.point.distance(ray.origin),
+    })
+}
+
+impl RayCaster for Scene {
+    fn cast(&self, ray: &Ray3D) -> Option<Hit> {
+        cast_into(self, ray, &mut Vec::with_capacity(2))
+    }
+
+    fn cast_packet(&self, rays: &[Ray3D; PACKET_SIZE]) -> [Option<Hit>; PACKET_SIZE] {
+        let mut aux = Vec::with_capacity(2);
+        rays.map(|ray| cast_into(self, &ray, &mut aux))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, DCSession, SensorGrid, SensorSpec};
+    use geometry3d::Vector3D;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A backend that only casts packets—through a `Scene`—counting them
+    struct Packets {
+        scene: Scene,
+        packets: AtomicUsize,
+    }
+
+    impl RayCaster for Packets {
+        fn cast_packet(&self, rays: &[Ray3D; PACKET_SIZE]) -> [Option<Hit>; PACKET_SIZE] {
+            self.packets.fetch_add(1, Ordering::Relaxed);
+            self.scene.cast_packet(rays)
+        }
+
+        fn supports_packets(&self) -> bool {
+            true
+        }
+    }
+
+    fn roof() -> Scene {
+        let (mut scene, _) = crate::load_scene("./tests/sensitivity/roof.rad").unwrap();
+        scene.build_accelerator();
+        scene
+    }
+
+    #[test]
+    fn test_packets_match_scalar() {
+        let scene = roof();
+        // Some hit the roof, some the ground or the wall, and some nothing
+        let rays: Vec<Ray3D> = (0..3 * PACKET_SIZE)
+            .map(|i| {
+                let a = i as Float * 0.7;
+                Ray3D {
+                    origin: Point3D::new(0.5 * a.cos(), 0.5 * a.sin(), 5.),
+                    direction: Vector3D::new(a.sin(), a.cos(), 1.5 - 0.15 * i as Float)
+                        .get_normalized(),
+                }
+            })
+            .collect();
+        let scalar: Vec<Option<Hit>> = rays.iter().map(|r| scene.cast(r)).collect();
+        assert!(scalar.iter().any(|h| h.is_some()));
+        assert!(scalar.iter().any(|h| h.is_none()));
+
+        let packets = Packets {
+            scene: roof(),
+            packets: AtomicUsize::new(0),
+        };
+        for (chunk, expected) in rays.chunks(PACKET_SIZE).zip(scalar.chunks(PACKET_SIZE)) {
+            let packet: [Ray3D; PACKET_SIZE] = chunk.try_into().unwrap();
+            assert_eq!(scene.cast_packet(&packet).to_vec(), expected);
+            assert_eq!(packets.cast_packet(&packet).to_vec(), expected);
+        }
+        // and the scalar casts of a backend that only casts packets
+        for (ray, expected) in rays.iter().zip(scalar.iter()) {
+            assert_eq!(packets.cast(ray), *expected);
+        }
+        if let Some(hit) = scalar.iter().flatten().next() {
+            assert!(hit.distance > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_session_packets() {
+        let scene = roof();
+        let (_, report) = crate::load_scene("./tests/sensitivity/roof.rad").unwrap();
+        // Sensors on the roof, looking down through it, and others above it
+        let mut sensors: Vec<SensorSpec> = SensorGrid::from_surface(&report, "roof", 1., 0.)
+            .unwrap()
+            .sensors()
+            .into_iter()
+            .map(|mut s| {
+                s.ray.direction = Vector3D::new(0., 0., -1.);
+                s
+            })
+            .collect();
+        sensors.push(
+            Ray3D {
+                origin: Point3D::new(0., 0., 4.),
+                direction: Vector3D::new(0.3, 0., 1.).get_normalized(),
+            }
+            .into(),
+        );
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                // Not a multiple of the size of the packets
+                n_ambient_samples: 1001,
+                seed: 9,
+                ..DCOptions::default()
+            },
+        );
+        let scalar = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+
+        let backend = Arc::new(Packets {
+            scene: roof(),
+            packets: AtomicUsize::new(0),
+        });
+        let packed = session
+            .clone()
+            .with_ray_caster(backend.clone())
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap()
+            .matrix;
+        assert_eq!(packed, scalar);
+        let n_packets = backend.packets.load(Ordering::Relaxed);
+        assert!(
+            n_packets >= sensors.len() * 1001 / PACKET_SIZE,
+            "{}",
+            n_packets
+        );
+
+        // A backend without packets casts the same
+        let one_by_one = session
+            .with_ray_caster(Arc::new(roof()))
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap()
+            .matrix;
+        assert_eq!(one_by_one, scalar);
+    }
+}
@@ -0,0 +1,80 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use geometry3d::Ray3D;
+use std::fmt;
+use std::sync::Arc;
+
+/// What to do with a ray, as decided by a [`RayFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RayAction {
+    /// Trace the ray as usual
+    Continue,
+
+    /// Drop the ray, which then contributes nothing
+    Kill,
+
+    /// Do not trace the ray, and credit it to the sky bin of its direction
+    /// as if it had escaped the scene
+    ForceSkyEscape,
+}
+
+/// A callback that decides, for every ray about to be traced, whether to trace
+/// it at all. It receives the ray and its depth (i.e., the number of bounces
+/// before it; `0` for the rays leaving the sensors).
+///
+/// This is meant for clipping the simulation domain (e.g., ignoring the parts of
+/// a city that are far from a courtyard), and it biases the results on purpose:
+/// killed rays underestimate the coefficients by whatever light would have arrived
+/// through them, and forced escapes overestimate the sky component by treating
+/// whatever they would have hit as if it were not there. The bias is only
+/// negligible when the filter fires on rays that would have contributed little anyway.
+///
+/// The direct tracer asks the filter about the rays leaving the sensors, and the
+/// simplified tracer of
+/// [`DCSession::calc_reflectance_tallies`](crate::DCSession::calc_reflectance_tallies)
+/// (and of the calculations built on it) about the rays of every bounce. The
+/// bounces of the `DCFactory` happen within the `rendering` crate, so it refuses
+/// sessions with a filter.
+#[derive(Clone)]
+pub struct RayFilter(Arc<FilterFn>);
+
+type FilterFn = dyn Fn(&Ray3D, usize) -> RayAction + Send + Sync;
+
+impl RayFilter {
+    /// Wraps a callback
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&Ray3D, usize) -> RayAction + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    /// What to do with `ray`, at a certain `depth`
+    pub fn action(&self, ray: &Ray3D, depth: usize) -> RayAction {
+        (self.0)(ray, depth)
+    }
+}
+
+impl fmt::Debug for RayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RayFilter")
+    }
+}
@@ -0,0 +1,223 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! How far rays start off the surfaces they leave, scaled with the size of
+//! the scene.
+//!
+//! A fixed offset fails at one end or the other: it is lost to rounding in
+//! city-scale models, and it jumps through the geometry of tiny ones. The
+//! offset is thus a fraction of the diagonal of the bounding box of the scene
+//! ([`RELATIVE_RAY_OFFSET`]), and grows with the distance that a ray travelled
+//! before hitting the surface, as the error of the hit point does.
+//!
+//! Sensors lying on a surface are moved off it (see
+//! [`DCOptions::surface_offset`](crate::DCOptions::surface_offset)), and the
+//! geometry right in front of them is looked for (see
+//! [`DCOptions::probe_distance`](crate::DCOptions::probe_distance)), by
+//! distances that are kept within the same scale (see [`RayOffset::nudge`]).
+
+use crate::scene_loading::SceneReport;
+use crate::Float;
+
+/// The offset of rays, as a fraction of the diagonal of the bounding box of the
+/// scene—and of the distance travelled by each ray
+pub const RELATIVE_RAY_OFFSET: Float = 1e-6;
+
+/// The diagonal (in metres) assumed for scenes whose extent is unknown, which
+/// gives an offset of `1e-4` metres
+pub const DEFAULT_SCENE_EXTENT: Float = 100.;
+
+/// The largest distance by which sensors are moved off their surfaces and
+/// geometry is probed, as a fraction of the extent of the scene
+pub const MAX_NUDGE_FRACTION: Float = 1e-3;
+
+/// The smallest distance by which sensors are moved off their surfaces and
+/// geometry is probed, as a multiple of the base offset
+pub const MIN_NUDGE_OFFSETS: Float = 2.;
+
+/// How far rays start off the surfaces they leave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayOffset {
+    /// The offset of rays that travelled a short distance
+    pub base: Float,
+
+    /// The diagonal of the bounding box of the scene
+    pub extent: Float,
+}
+
+impl std::default::Default for RayOffset {
+    fn default() -> Self {
+        Self::for_extent(DEFAULT_SCENE_EXTENT)
+    }
+}
+
+impl RayOffset {
+    /// The offset of a scene whose bounding box has a diagonal of `extent`
+    /// metres. Empty or degenerate scenes get the default.
+    pub fn for_extent(extent: Float) -> Self {
+        let extent = if extent.is_finite() && extent > 0.0 {
+            extent
+        } else {
+            DEFAULT_SCENE_EXTENT
+        };
+        Self {
+            base: RELATIVE_RAY_OFFSET * extent,
+            extent,
+        }
+    }
+
+    /// The offset of a scene, from its bounding box
+    pub fn for_scene(report: &SceneReport) -> Self {
+        let (min, max) = report.bounding_box;
+        Self::for_extent(min.distance(max))
+    }
+
+    /// The offset of a ray that travelled a `distance` before hitting the
+    /// surface it leaves
+    pub fn at(&self, distance: Float) -> Float {
+        self.base.max(RELATIVE_RAY_OFFSET * distance.abs())
+    }
+
+    /// Keeps a distance by which sensors are moved or geometry probed within
+    /// the scale of the scene: no larger than [`MAX_NUDGE_FRACTION`] of its
+    /// extent, and no smaller than [`MIN_NUDGE_OFFSETS`] offsets. Zero (i.e.,
+    /// disabled) stays zero.
+    pub fn nudge(&self, distance: Float) -> Float {
+        if distance <= 0.0 {
+            return distance;
+        }
+        distance
+            .min(MAX_NUDGE_FRACTION * self.extent)
+            .max(MIN_NUDGE_OFFSETS * self.base)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, DCSession, EventKind, Material, SceneBuilder, SensorSpec};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use rendering::Scene;
+    use validate::assert_close;
+
+    #[test]
+    fn test_offsets() {
+        assert_eq!(RayOffset::default(), RayOffset::for_extent(100.));
+        assert_close!(RayOffset::default().base, 1e-4, 1e-12);
+        assert_eq!(RayOffset::for_extent(0.), RayOffset::default());
+        assert_eq!(RayOffset::for_extent(Float::NAN), RayOffset::default());
+
+        // Rays that travelled far start further off
+        let offset = RayOffset::for_extent(1.);
+        assert_close!(offset.at(0.1), 1e-6, 1e-12);
+        assert_close!(offset.at(1e3), 1e-3, 1e-9);
+
+        // The defaults are kept in scenes of the default size...
+        let offset = RayOffset::default();
+        assert_close!(offset.nudge(0.001), 0.001, 1e-9);
+        assert_close!(offset.nudge(0.01), 0.01, 1e-9);
+        // ...and scaled in others
+        assert_close!(RayOffset::for_extent(0.1).nudge(0.001), 1e-4, 1e-9);
+        assert_close!(RayOffset::for_extent(1e5).nudge(0.001), 0.2, 1e-6);
+        assert_eq!(offset.nudge(0.), 0.);
+    }
+
+    /// The sky seen by a sensor lying at the centre of a floor `size` metres
+    /// across, under a ceiling at `ceiling` times that (if any), once the session
+    /// knows the extent of the scene or not
+    fn sky_on_floor(size: Float, ceiling: Option<Float>, scaled: bool) -> Float {
+        let mut builder = SceneBuilder::new();
+        builder.add_material("mat", Material::plastic(0.5)).unwrap();
+        let square = |z: Float| {
+            [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)]
+                .map(|(x, y)| Point3D::new(x * size, y * size, z))
+        };
+        builder.add_polygon("mat", "floor", &square(0.)).unwrap();
+        if let Some(h) = ceiling {
+            builder
+                .add_polygon("mat", "ceiling", &square(h * size))
+                .unwrap();
+        }
+        let (scene, report) = builder.build().unwrap();
+        let mut session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 500,
+                seed: 5,
+                ..DCOptions::default()
+            },
+        );
+        if scaled {
+            session = session.with_scene_extent(&report);
+            assert_close!(
+                session.ray_offset(None).extent,
+                size * (2.0 as Float).sqrt(),
+                1e-4 * size
+            );
+        }
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        assert_eq!(dc.events.of_kind(EventKind::OnSurface).count(), 1);
+        (0..dc.matrix.size().1)
+            .map(|c| dc.matrix.get(0, c).unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn test_scaled_nudges() {
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 500,
+                seed: 5,
+                ..DCOptions::default()
+            },
+        );
+        let dc = session.calc_sensor_dc(&sensors, &Scene::new()).unwrap();
+        let open: Float = (0..dc.matrix.size().1)
+            .map(|c| dc.matrix.get(0, c).unwrap())
+            .sum();
+
+        for size in [1e-2, 1., 1e4] {
+            // A floor alone hides nothing
+            let sky = sky_on_floor(size, None, true);
+            assert_close!(sky, open, 1e-3 * open);
+
+            // and the sensor stays under a ceiling close to it
+            let covered = sky_on_floor(size, Some(5e-3), true);
+            assert!(covered < 0.05 * open, "{} sees {}", size, covered);
+        }
+
+        // A fixed (1 mm) nudge goes through the ceiling of a 1 cm model
+        let pierced = sky_on_floor(1e-2, Some(5e-3), false);
+        assert!(pierced > 0.5 * open, "{}", pierced);
+    }
+}
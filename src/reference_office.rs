@@ -0,0 +1,502 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A canonical side-lit office, with reference results computed at a high
+//! number of samples, for catching regressions of the estimators between
+//! releases.
+//!
+//! The office is 4 m wide, 6 m deep and 3 m high, with a window taking 40% of
+//! its South wall and the standard finishes of the [`MaterialLibrary`]. Its
+//! sensors are a 0.5 m grid over the floor, at the height of the workplane.
+//!
+//! The reference results are the sum of the direct coefficients of each sensor
+//! and its illuminance under a CIE overcast sky of [`OFFICE_ILLUMINANCE`] lux,
+//! both with their standard errors. They are traced by the direct tracer (i.e.,
+//! `max_depth = 0`), which takes the window as an opening, so they do not
+//! depend on the bounces performed by the `rendering` crate. New results are
+//! compared with them through [`assert_consistent`], which tells statistically
+//! consistent noise apart from real regressions.
+
+use crate::environment::{EscapeRadiance, SkyRadiance};
+use crate::grid::SensorGrid;
+use crate::materials::MaterialLibrary;
+use crate::scene_builder::SceneBuilder;
+use crate::session::{DCOptions, DCSession};
+use crate::{Float, PI};
+use geometry3d::Point3D;
+use rendering::Scene;
+
+/// The width of the office (along `X`, where its window is), in metres
+pub const OFFICE_WIDTH: Float = 4.;
+
+/// The depth of the office (along `Y`, away from its window), in metres
+pub const OFFICE_DEPTH: Float = 6.;
+
+/// The height of the office, in metres
+pub const OFFICE_HEIGHT: Float = 3.;
+
+/// The fraction of the South wall taken by the window
+pub const OFFICE_WINDOW_TO_WALL: Float = 0.4;
+
+/// The height of the sill of the window, in metres
+pub const OFFICE_SILL_HEIGHT: Float = 0.9;
+
+/// The distance between the sensors of the grid, in metres
+pub const OFFICE_GRID_SPACING: Float = 0.5;
+
+/// The height of the workplane, in metres
+pub const OFFICE_WORKPLANE_HEIGHT: Float = 0.8;
+
+/// The horizontal illuminance of the overcast sky of the reference, in lux
+pub const OFFICE_ILLUMINANCE: Float = 10_000.;
+
+/// The reflectance of the ground outside the office
+pub const OFFICE_ALBEDO: Float = 0.2;
+
+/// The seed of the reference results
+pub const OFFICE_REFERENCE_SEED: u64 = 1;
+
+/// The number of samples per sensor of the reference results
+pub const OFFICE_REFERENCE_SAMPLES: usize = 50_000;
+
+/// The largest number of joint standard errors a single sensor is allowed to
+/// be away from the reference in [`assert_consistent`]. It is larger than
+/// [`ORACLE_Z`](crate::test_support::ORACLE_Z), as many sensors are checked at once.
+pub const MAX_SENSOR_Z: Float = 5.;
+
+/// The largest number of standard errors that the mean deviation of all the
+/// sensors is allowed to be away from zero in [`assert_consistent`]
+pub const MAX_MEAN_Z: Float = 4.;
+
+/// The reference office (see the module documentation)
+pub struct ReferenceOffice {
+    /// The scene, with its accelerator already built
+    pub scene: Scene,
+
+    /// The sensors of the office
+    pub grid: SensorGrid,
+
+    /// The corners of the window, counterclockwise seen from inside
+    pub window: [Point3D; 4],
+}
+
+/// Builds the reference office. The window is an opening, unless it is
+/// `glazed` with the standard double glazing.
+pub fn reference_office(glazed: bool) -> Result<ReferenceOffice, String> {
+    let (w, d, h) = (OFFICE_WIDTH, OFFICE_DEPTH, OFFICE_HEIGHT);
+    let library = MaterialLibrary::standard();
+    let mut builder = SceneBuilder::new();
+    for name in ["generic_interior_wall", "generic_ceiling", "generic_floor"] {
+        builder.add_material(name, library.get(name)?)?;
+    }
+    let p = |x: Float, y: Float, z: Float| Point3D::new(x, y, z);
+
+    // The surfaces face into the office
+    let floor = [p(0., 0., 0.), p(0., d, 0.), p(w, d, 0.), p(w, 0., 0.)];
+    builder.add_polygon("generic_floor", "floor", &floor)?;
+    let ceiling = [p(0., 0., h), p(w, 0., h), p(w, d, h), p(0., d, h)];
+    builder.add_polygon("generic_ceiling", "ceiling", &ceiling)?;
+    let walls = [
+        (
+            "north_wall",
+            [p(0., d, 0.), p(0., d, h), p(w, d, h), p(w, d, 0.)],
+        ),
+        (
+            "west_wall",
+            [p(0., 0., 0.), p(0., 0., h), p(0., d, h), p(0., d, 0.)],
+        ),
+        (
+            "east_wall",
+            [p(w, 0., 0.), p(w, d, 0.), p(w, d, h), p(w, 0., h)],
+        ),
+    ];
+    for (name, vertices) in walls {
+        builder.add_polygon("generic_interior_wall", name, &vertices)?;
+    }
+
+    // The South wall is split around a window 1.5 m high, centred on the wall
+    let window_height = 1.5;
+    let window_width = OFFICE_WINDOW_TO_WALL * w * h / window_height;
+    let (x0, x1) = (0.5 * (w - window_width), 0.5 * (w + window_width));
+    let (z0, z1) = (OFFICE_SILL_HEIGHT, OFFICE_SILL_HEIGHT + window_height);
+    let window = [p(x0, 0., z0), p(x1, 0., z0), p(x1, 0., z1), p(x0, 0., z1)];
+    let south = [
+        (
+            "south_wall_below",
+            [p(0., 0., 0.), p(w, 0., 0.), p(w, 0., z0), p(0., 0., z0)],
+        ),
+        (
+            "south_wall_above",
+            [p(0., 0., z1), p(w, 0., z1), p(w, 0., h), p(0., 0., h)],
+        ),
+        (
+            "south_wall_west",
+            [p(0., 0., z0), p(x0, 0., z0), p(x0, 0., z1), p(0., 0., z1)],
+        ),
+        (
+            "south_wall_east",
+            [p(x1, 0., z0), p(w, 0., z0), p(w, 0., z1), p(x1, 0., z1)],
+        ),
+    ];
+    for (name, vertices) in south {
+        builder.add_polygon("generic_interior_wall", name, &vertices)?;
+    }
+    if glazed {
+        builder.add_material("clear_double_glazing", library.get("clear_double_glazing")?)?;
+        builder.add_polygon("clear_double_glazing", "window", &window)?;
+    }
+
+    let (mut scene, _) = builder.build()?;
+    scene.build_accelerator();
+    let grid = SensorGrid::new(&floor, &[], OFFICE_GRID_SPACING, OFFICE_WORKPLANE_HEIGHT)?;
+    Ok(ReferenceOffice {
+        scene,
+        grid,
+        window,
+    })
+}
+
+/// The results of the reference office, each with its standard error
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct OfficeResults {
+    /// The sum of the direct coefficients of each sensor (`PI` times the view
+    /// factor of the sky and the ground seen through the window)
+    pub dc_sums: Vec<Float>,
+
+    /// The standard error of each element of `dc_sums`
+    pub dc_sum_errors: Vec<Float>,
+
+    /// The illuminance of each sensor under the overcast sky, in lux
+    pub illuminances: Vec<Float>,
+
+    /// The standard error of each element of `illuminances`
+    pub illuminance_errors: Vec<Float>,
+}
+
+/// The header of [`OfficeResults::to_csv`]
+const CSV_HEADER: &str = "sensor,dc_sum,dc_sum_error,illuminance,illuminance_error";
+
+impl OfficeResults {
+    /// Writes the results, one sensor per row
+    pub fn to_csv(&self) -> String {
+        let mut ret = format!("{}\n", CSV_HEADER);
+        for i in 0..self.dc_sums.len() {
+            ret.push_str(&format!(
+                "{},{},{},{},{}\n",
+                i,
+                self.dc_sums[i],
+                self.dc_sum_errors[i],
+                self.illuminances[i],
+                self.illuminance_errors[i]
+            ));
+        }
+        ret
+    }
+
+    /// Reads results written by [`OfficeResults::to_csv`]
+    pub fn from_csv(csv: &str) -> Result<Self, String> {
+        let mut lines = csv.lines();
+        if lines.next().map(|l| l.trim()) != Some(CSV_HEADER) {
+            return Err(format!(
+                "The reference results must start with the header '{}'",
+                CSV_HEADER
+            ));
+        }
+        let mut ret = Self::default();
+        for (i, line) in lines.filter(|l| !l.trim().is_empty()).enumerate() {
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if fields.len() != 5 || fields[0] != i.to_string() {
+                return Err(format!(
+                    "Row {} of the reference results is not valid: '{}'",
+                    i, line
+                ));
+            }
+            let mut values = [0.0; 4];
+            for (v, f) in values.iter_mut().zip(&fields[1..]) {
+                *v = f.parse::<Float>().map_err(|e| {
+                    format!("Row {} of the reference results is not valid: {}", i, e)
+                })?;
+            }
+            ret.dc_sums.push(values[0]);
+            ret.dc_sum_errors.push(values[1]);
+            ret.illuminances.push(values[2]);
+            ret.illuminance_errors.push(values[3]);
+        }
+        Ok(ret)
+    }
+}
+
+/// The radiance of the overcast sky of the reference, relative to a unit sky:
+/// the CIE overcast sky over a ground of reflectance [`OFFICE_ALBEDO`]
+fn overcast_with_ground() -> EscapeRadiance {
+    EscapeRadiance::custom(|direction| {
+        if direction.z < 0.0 {
+            OFFICE_ALBEDO
+        } else {
+            EscapeRadiance::CieOvercast.radiance(direction)
+        }
+    })
+}
+
+/// Calculates the results of the reference office (with the window as an
+/// opening) with `n_samples` samples per sensor and a `seed`
+pub fn office_results(n_samples: usize, seed: u64) -> Result<OfficeResults, String> {
+    let office = reference_office(false)?;
+    let sensors = office.grid.sensors();
+    let options = DCOptions {
+        max_depth: 0,
+        n_ambient_samples: n_samples,
+        seed,
+        ..DCOptions::default()
+    };
+    let session = DCSession::try_new(1, options).map_err(|e| e.to_string())?;
+    let (dc, stats) = session.calc_sensor_dc_with_stats(&sensors, &office.scene, false)?;
+    let (n_rows, n_cols) = dc.matrix.size();
+    let mut dc_sums = Vec::with_capacity(n_rows);
+    for r in 0..n_rows {
+        let mut sum = 0.0;
+        for c in 0..n_cols {
+            sum += dc.matrix.get(r, c)?;
+        }
+        dc_sums.push(sum);
+    }
+
+    // Under an escape radiance, the sum of each row is the irradiance, which is
+    // PI for an unobstructed sensor facing up
+    let overcast = session.with_escape_radiance(overcast_with_ground());
+    let (dc, stats_overcast) =
+        overcast.calc_sensor_dc_with_stats(&sensors, &office.scene, false)?;
+    let to_lux = OFFICE_ILLUMINANCE / PI;
+    let mut illuminances = Vec::with_capacity(n_rows);
+    for r in 0..n_rows {
+        let mut sum = 0.0;
+        for c in 0..n_cols {
+            sum += dc.matrix.get(r, c)?;
+        }
+        illuminances.push(sum * to_lux);
+    }
+    Ok(OfficeResults {
+        dc_sums,
+        dc_sum_errors: stats.standard_errors,
+        illuminances,
+        illuminance_errors: stats_overcast
+            .standard_errors
+            .iter()
+            .map(|e| e * to_lux)
+            .collect(),
+    })
+}
+
+/// How far a set of estimates is from a reference, in joint standard errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Consistency {
+    /// The largest deviation of a single estimate
+    pub max_z: Float,
+
+    /// The mean deviation of the estimates, in standard errors of the mean
+    /// (i.e., times the square root of their number), which reveals small
+    /// biases even if every estimate is within its own noise
+    pub mean_z: Float,
+}
+
+/// Compares some `estimates` with a `reference`, each value with its standard error
+pub fn consistency(
+    estimates: &[Float],
+    errors: &[Float],
+    reference: &[Float],
+    reference_errors: &[Float],
+) -> Result<Consistency, String> {
+    let n = estimates.len();
+    if errors.len() != n || reference.len() != n || reference_errors.len() != n {
+        return Err(format!(
+            "Cannot compare {} estimates ({} errors) with {} reference values ({} errors)",
+            n,
+            errors.len(),
+            reference.len(),
+            reference_errors.len()
+        ));
+    }
+    let mut max_z: Float = 0.0;
+    let mut sum_z = 0.0;
+    let mut n_compared = 0;
+    for i in 0..n {
+        let difference = estimates[i] - reference[i];
+        let error = (errors[i] * errors[i] + reference_errors[i] * reference_errors[i]).sqrt();
+        if error <= 0.0 {
+            // Both exact (e.g., a sensor that sees no sky at all), so they must agree
+            if difference.abs() > 1e-9 * reference[i].abs().max(1.) {
+                max_z = Float::INFINITY;
+            }
+            continue;
+        }
+        let z = difference / error;
+        max_z = max_z.max(z.abs());
+        sum_z += z;
+        n_compared += 1;
+    }
+    let mean_z = if n_compared == 0 {
+        0.0
+    } else {
+        sum_z / (n_compared as Float).sqrt()
+    };
+    Ok(Consistency { max_z, mean_z })
+}
+
+/// Checks that some `estimates` are consistent with a `reference`: every one is
+/// within [`MAX_SENSOR_Z`] joint standard errors of its reference value, and the
+/// mean deviation is within [`MAX_MEAN_Z`] standard errors of zero.
+///
+/// # Panics
+///
+/// If they are not, or if the lengths do not match.
+pub fn assert_consistent(
+    name: &str,
+    estimates: &[Float],
+    errors: &[Float],
+    reference: &[Float],
+    reference_errors: &[Float],
+) {
+    let c = match consistency(estimates, errors, reference, reference_errors) {
+        Ok(c) => c,
+        Err(e) => panic!("{}: {}", name, e),
+    };
+    assert!(
+        c.max_z <= MAX_SENSOR_Z && c.mean_z.abs() <= MAX_MEAN_Z,
+        "{}: inconsistent with the reference (largest deviation {} standard errors, mean deviation {})",
+        name,
+        c.max_z,
+        c.mean_z
+    );
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// The committed reference results
+    const REFERENCE: &str = include_str!("../tests/reference_office/reference.csv");
+
+    #[test]
+    fn test_office() {
+        let office = reference_office(true).unwrap();
+        assert_eq!(office.grid.len(), 8 * 12);
+        let window = &office.window;
+        let area = (window[1].x - window[0].x) * (window[3].z - window[0].z);
+        let ratio = area / (OFFICE_WIDTH * OFFICE_HEIGHT);
+        assert!((ratio - OFFICE_WINDOW_TO_WALL).abs() < 1e-6);
+        assert!(window[0].x > 0. && window[1].x < OFFICE_WIDTH);
+        assert!(window[3].z < OFFICE_HEIGHT);
+    }
+
+    #[test]
+    fn test_against_reference() {
+        let reference = OfficeResults::from_csv(REFERENCE).unwrap();
+        // A different seed and far fewer samples, so that only noise is shared
+        let found = office_results(2000, 7).unwrap();
+        assert_eq!(found.dc_sums.len(), reference.dc_sums.len());
+        assert_consistent(
+            "DC sums",
+            &found.dc_sums,
+            &found.dc_sum_errors,
+            &reference.dc_sums,
+            &reference.dc_sum_errors,
+        );
+        assert_consistent(
+            "illuminances",
+            &found.illuminances,
+            &found.illuminance_errors,
+            &reference.illuminances,
+            &reference.illuminance_errors,
+        );
+
+        // Light falls off away from the window
+        let n = reference.illuminances.len();
+        assert!(
+            reference.illuminances[..8].iter().sum::<Float>()
+                > 5. * reference.illuminances[n - 8..].iter().sum::<Float>()
+        );
+    }
+
+    #[test]
+    fn test_detects_regressions() {
+        let reference = OfficeResults::from_csv(REFERENCE).unwrap();
+        let found = office_results(2000, 7).unwrap();
+        let consistent = consistency(
+            &found.illuminances,
+            &found.illuminance_errors,
+            &reference.illuminances,
+            &reference.illuminance_errors,
+        )
+        .unwrap();
+        assert!(consistent.max_z <= MAX_SENSOR_Z);
+
+        // A bias of 5% is within the noise of each sensor, but not of all of them together
+        let biased: Vec<Float> = found.illuminances.iter().map(|v| 1.05 * v).collect();
+        let c = consistency(
+            &biased,
+            &found.illuminance_errors,
+            &reference.illuminances,
+            &reference.illuminance_errors,
+        )
+        .unwrap();
+        assert!(c.mean_z > MAX_MEAN_Z, "{:?}", c);
+
+        // A single broken sensor stands out
+        let mut broken = found.illuminances.clone();
+        broken[20] *= 0.5;
+        let c = consistency(
+            &broken,
+            &found.illuminance_errors,
+            &reference.illuminances,
+            &reference.illuminance_errors,
+        )
+        .unwrap();
+        assert!(c.max_z > MAX_SENSOR_Z, "{:?}", c);
+        assert!(consistency(
+            &broken[1..],
+            &found.illuminance_errors,
+            &reference.illuminances,
+            &reference.illuminance_errors
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let reference = OfficeResults::from_csv(REFERENCE).unwrap();
+        assert_eq!(
+            OfficeResults::from_csv(&reference.to_csv()).unwrap(),
+            reference
+        );
+        assert!(OfficeResults::from_csv("sensor,dc_sum\n0,1").is_err());
+        let bad = format!("{}\n0,1,2,3\n", CSV_HEADER);
+        assert!(OfficeResults::from_csv(&bad).is_err());
+    }
+
+    /// Regenerates the committed reference results, which should only be done
+    /// when a change of the results is intended:
+    /// `cargo test --features test-support --lib regenerate_reference -- --ignored`
+    #[test]
+    #[ignore]
+    fn regenerate_reference() {
+        let results = office_results(OFFICE_REFERENCE_SAMPLES, OFFICE_REFERENCE_SEED).unwrap();
+        std::fs::write("./tests/reference_office/reference.csv", results.to_csv()).unwrap();
+    }
+}
@@ -0,0 +1,452 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Adding samples to a Daylight Coefficient matrix that was already calculated.
+//!
+//! Every sample of the direct tracer depends only on the seed, the sensor and
+//! the index of the sample, so the samples after the first `n` of a sensor can
+//! be traced on their own and merged into its running mean. The result is the
+//! one that a calculation with all the samples from the start would have given.
+
+use crate::direct::direct_dc_row;
+use crate::events::EventLog;
+use crate::labeled_matrix::LabeledMatrix;
+use crate::rng::SampleStream;
+use crate::sensor::SensorSpec;
+use crate::session::{needs_direct_tracer, DCSession};
+use crate::stats::{merge_means, standard_error_from_moments, DCStats, Welford};
+use crate::Float;
+use rendering::Scene;
+use solar::ReinhartSky;
+
+impl DCSession {
+    /// Adds `extra_samples` samples (one number per sensor) to a matrix calculated
+    /// by [`DCSession::calc_sensor_dc_with_stats`] with the same session, the
+    /// same `sensors` and the same `scene`, returning the matrix and the
+    /// statistics that a calculation with all the samples would have given (up
+    /// to rounding). The `stats` say how many samples each sensor already has.
+    ///
+    /// Only the direct tracer can do this (see
+    /// [`DCOptions::max_depth`](crate::DCOptions::max_depth)), and neither
+    /// importance hints nor sample clamps can be used, as they depend on the
+    /// total number of samples. Neither can a noise floor, which loses the
+    /// coefficients it culls. The extra samples are not limited by the
+    /// [`RayBudget`](crate::RayBudget).
+    pub fn refine(
+        &self,
+        existing: &LabeledMatrix,
+        stats: &DCStats,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        extra_samples: &[usize],
+    ) -> Result<(LabeledMatrix, DCStats), String> {
+        let options = self.options();
+        if !options.is_direct() {
+            return Err(needs_direct_tracer("refining matrices"));
+        }
+        if self.trace_hints().importance.is_some() || options.sample_clamp.is_some() {
+            return Err(
+                "Matrices calculated with importance hints or sample clamps cannot be refined"
+                    .to_string(),
+            );
+        }
+        if options.noise_floor.is_some() {
+            return Err("Matrices calculated with a noise floor cannot be refined".to_string());
+        }
+        let n_bins = self.basis()?.n_bins();
+        let (nrows, ncols) = existing.matrix.size();
+        if ncols != n_bins {
+            return Err(format!(
+                "The matrix has {} bins, but the session has {}",
+                ncols, n_bins
+            ));
+        }
+        for (what, n) in [
+            ("rows", nrows),
+            ("row metadata", existing.rows.len()),
+            ("sample counts", stats.n_samples.len()),
+            ("standard errors", stats.standard_errors.len()),
+            ("extra sample counts", extra_samples.len()),
+        ] {
+            if n != sensors.len() {
+                return Err(format!(
+                    "There are {} sensors, but {} {}",
+                    sensors.len(),
+                    n,
+                    what
+                ));
+            }
+        }
+        // The sensors as they were traced
+        let mut preflight = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut preflight);
+        for (i, (sensor, row)) in sensors.iter().zip(existing.rows.iter()).enumerate() {
+            if sensor.ray != row.ray
+                || sensor.sensor_id() != row.id
+                || row.n_samples != stats.n_samples[i]
+            {
+                return Err(format!(
+                    "Sensor {} does not match row {} of the matrix, or its number of samples",
+                    i, i
+                ));
+            }
+        }
+
+        let sky = ReinhartSky::new(self.mf());
+        let trace = |(i, sensor): (usize, &SensorSpec)| {
+            let mut samples = SampleStream::new(options.sampling, options.seed, i as u64);
+            samples.seek(stats.n_samples[i] as u64);
+            let mut events = EventLog::new();
+            let row = direct_dc_row(
+                scene,
+                sensor,
+                i,
+                &sky,
+                n_bins,
+                extra_samples[i],
+                &mut samples,
+                self.trace_hints(),
+                false,
+                &mut events,
+            )?;
+            self.count_samples(extra_samples[i]);
+            Ok((row, events))
+        };
+        let rows = self.map_sensors(sensors, trace)?;
+
+        let mut matrix = existing.matrix.clone();
+        let mut events = existing.events.clone();
+        // The existing matrix already has these if it was calculated in this run
+        // rather than read back from a file
+        for e in preflight.events() {
+            if !e.sensor.is_some_and(|i| events.has(e.kind, i)) {
+                events.push(e.kind, e.sensor, e.ray, e.count, e.message.clone());
+            }
+        }
+        let mut bin_errors = stats.bin_standard_errors.clone();
+        let mut standard_errors = Vec::with_capacity(sensors.len());
+        let mut n_samples = Vec::with_capacity(sensors.len());
+        for (i, (row, row_events)) in rows.into_iter().enumerate() {
+            events.merge(row_events);
+            let (n, extra) = (stats.n_samples[i], extra_samples[i]);
+            let old = (0..n_bins)
+                .map(|bin| matrix.get(i, bin))
+                .collect::<Result<Vec<Float>, String>>()?;
+            let mut mean = old.clone();
+            merge_means(&mut mean, n, &row.bins.values(), extra);
+            for (bin, v) in mean.iter().enumerate() {
+                matrix.set(i, bin, *v)?;
+            }
+
+            // The mean contribution of the samples is the sum of the coefficients
+            let se = stats.standard_errors[i];
+            let mut totals = Welford::from_moments(n, old.iter().sum(), se * se * n as Float);
+            totals.merge(&row.totals);
+            standard_errors.push(totals.standard_error());
+            if let Some(errors) = &mut bin_errors {
+                let mut squares = vec![0.0; n_bins];
+                for (bin, _, sq) in row.bins.iter() {
+                    squares[bin] = sq;
+                }
+                for bin in 0..n_bins {
+                    let se = errors.get(i, bin)?;
+                    let mut old_square = old[bin] * old[bin];
+                    if se.is_finite() && n > 1 {
+                        old_square += se * se * (n - 1) as Float;
+                    }
+                    let square = (old_square * n as Float + squares[bin] * extra as Float)
+                        / (n + extra).max(1) as Float;
+                    errors.set(
+                        i,
+                        bin,
+                        standard_error_from_moments(mean[bin], square, n + extra),
+                    )?;
+                }
+            }
+            n_samples.push(n + extra);
+        }
+
+        let mut rows = existing.rows.clone();
+        for (row, n) in rows.iter_mut().zip(n_samples.iter()) {
+            row.n_samples = *n;
+        }
+        let dc = LabeledMatrix {
+            matrix,
+            rows,
+            events,
+            ground: existing.ground,
+        };
+        let stats = DCStats {
+            n_samples,
+            ids: stats.ids.clone(),
+            standard_errors,
+            bin_standard_errors: bin_errors,
+            clamped: stats.clamped.clone(),
+            grazing: stats.grazing.clone(),
+            skipped: stats.skipped.clone(),
+            ray_cap_hit: stats.ray_cap_hit,
+            factory_runs: stats.factory_runs.clone(),
+        };
+        Ok((dc, stats))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, EventKind, Material, SamplingSequence, SceneBuilder, TributaryArea};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    fn slats() -> Scene {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("slat_mat", Material::plastic(0.5))
+            .unwrap();
+        for i in -10..10 {
+            let x = i as Float * 0.2 - 0.05;
+            let vertices = [(x, -10.), (x + 0.1, -10.), (x + 0.1, 10.), (x, 10.)]
+                .map(|(x, y)| Point3D::new(x, y, 0.1));
+            builder
+                .add_polygon("slat_mat", &format!("slat_{}", i), &vertices)
+                .unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        scene
+    }
+
+    fn sensors() -> Vec<SensorSpec> {
+        let at = |x: Float| -> SensorSpec {
+            Ray3D {
+                origin: Point3D::new(x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        };
+        let area = TributaryArea::rectangle(0.2, 0.2).unwrap();
+        vec![at(0.), at(0.1).with_area(area).unwrap()]
+    }
+
+    #[test]
+    fn test_refine() {
+        let scene = slats();
+        let sensors = sensors();
+        for sampling in [SamplingSequence::Random, SamplingSequence::Sobol] {
+            let options = DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 300,
+                seed: 3,
+                sampling,
+                jitter_origins: true,
+                ..DCOptions::default()
+            };
+            let session = DCSession::new(1, options);
+            let (dc, stats) = session
+                .calc_sensor_dc_with_stats(&sensors, &scene, true)
+                .unwrap();
+            let (refined, refined_stats) = session
+                .refine(&dc, &stats, &sensors, &scene, &[500, 500])
+                .unwrap();
+            assert_eq!(refined_stats.n_samples, vec![800, 800]);
+            assert_eq!(refined.rows[1].n_samples, 800);
+
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    n_ambient_samples: 800,
+                    ..options
+                },
+            );
+            let (fresh, fresh_stats) = session
+                .calc_sensor_dc_with_stats(&sensors, &scene, true)
+                .unwrap();
+            let (nrows, ncols) = fresh.matrix.size();
+            let fresh_errors = fresh_stats.bin_standard_errors.unwrap();
+            let refined_errors = refined_stats.bin_standard_errors.unwrap();
+            for r in 0..nrows {
+                let (a, b) = (
+                    refined_stats.standard_errors[r],
+                    fresh_stats.standard_errors[r],
+                );
+                assert_close!(a, b, 1e-4 * (1. + b));
+                for c in 0..ncols {
+                    let (a, b) = (
+                        refined.matrix.get(r, c).unwrap(),
+                        fresh.matrix.get(r, c).unwrap(),
+                    );
+                    assert_close!(a, b, 1e-4 * (1. + b.abs()));
+                    let (a, b) = (
+                        refined_errors.get(r, c).unwrap(),
+                        fresh_errors.get(r, c).unwrap(),
+                    );
+                    if b.is_finite() {
+                        assert_close!(a, b, 1e-4 * (1. + b));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_refine_uneven() {
+        // Sensors can get different numbers of extra samples, including none
+        let scene = slats();
+        let sensors = sensors();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 200,
+            seed: 5,
+            ..DCOptions::default()
+        };
+        let run = |n: usize| {
+            DCSession::new(
+                1,
+                DCOptions {
+                    n_ambient_samples: n,
+                    ..options
+                },
+            )
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap()
+        };
+        let (dc, stats) = run(200);
+        let (refined, refined_stats) = DCSession::new(1, options)
+            .refine(&dc, &stats, &sensors, &scene, &[0, 400])
+            .unwrap();
+        assert_eq!(refined_stats.n_samples, vec![200, 600]);
+        for (row, (fresh, _)) in [(0, run(200)), (1, run(600))] {
+            for c in 0..fresh.matrix.size().1 {
+                let b = fresh.matrix.get(row, c).unwrap();
+                assert_close!(
+                    refined.matrix.get(row, c).unwrap(),
+                    b,
+                    1e-4 * (1. + b.abs())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_refine_on_surface() {
+        // Sensors on a floor are moved off it, and say so once
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("floor_mat", Material::plastic(0.2))
+            .unwrap();
+        let floor =
+            [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        builder.add_polygon("floor_mat", "floor", &floor).unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let sensors = sensors();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 100,
+                ..DCOptions::default()
+            },
+        );
+        let (dc, stats) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+        let on_surface = |dc: &LabeledMatrix| -> Vec<(Option<usize>, usize)> {
+            dc.events
+                .of_kind(EventKind::OnSurface)
+                .map(|e| (e.sensor, e.count))
+                .collect()
+        };
+        assert_eq!(on_surface(&dc), vec![(Some(0), 1), (Some(1), 1)]);
+        let (refined, _) = session
+            .refine(&dc, &stats, &sensors, &scene, &[100, 100])
+            .unwrap();
+        assert_eq!(on_surface(&refined), on_surface(&dc));
+
+        // Matrices read back from a file have lost them
+        let read = LabeledMatrix {
+            events: EventLog::new(),
+            ..dc.clone()
+        };
+        let (refined, _) = session
+            .refine(&read, &stats, &sensors, &scene, &[100, 100])
+            .unwrap();
+        assert_eq!(on_surface(&refined), on_surface(&dc));
+    }
+
+    #[test]
+    fn test_refine_refusals() {
+        let scene = slats();
+        let sensors = sensors();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 10,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+        let (dc, stats) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+
+        // Mismatched lengths
+        let err = session
+            .refine(&dc, &stats, &sensors, &scene, &[10])
+            .unwrap_err();
+        assert!(err.contains("extra sample counts"), "{}", err);
+        let err = session
+            .refine(&dc, &stats, &sensors[..1], &scene, &[10])
+            .unwrap_err();
+        assert!(err.contains("There are 1 sensors"), "{}", err);
+
+        // Different sensors, or a different number of samples
+        let swapped = vec![sensors[1].clone(), sensors[0].clone()];
+        assert!(session
+            .refine(&dc, &stats, &swapped, &scene, &[10, 10])
+            .is_err());
+        let mut wrong = stats.clone();
+        wrong.n_samples[0] = 20;
+        assert!(session
+            .refine(&dc, &wrong, &sensors, &scene, &[10, 10])
+            .is_err());
+
+        // Other tracers and options
+        let ambient = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 1,
+                ..options
+            },
+        );
+        let err = ambient
+            .refine(&dc, &stats, &sensors, &scene, &[10, 10])
+            .unwrap_err();
+        assert!(err.contains("direct tracer"), "{}", err);
+        let clamped = DCSession::new(
+            1,
+            DCOptions {
+                sample_clamp: Some(crate::SampleClamp::Absolute(1.0)),
+                ..options
+            },
+        );
+        assert!(clamped
+            .refine(&dc, &stats, &sensors, &scene, &[10, 10])
+            .is_err());
+    }
+}
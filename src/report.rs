@@ -0,0 +1,789 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::labeled_matrix::RowMetadata;
+use crate::manifest::RunManifest;
+use crate::sensor_id::SensorId;
+use crate::time::{AnnualSeries, TimeAxis, Timestep};
+use crate::zones::ZoneGroups;
+use crate::Float;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// The number of hours in a day
+const HOURS_PER_DAY: usize = 24;
+
+/// A period during which clocks are one hour ahead of standard time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DaylightSaving {
+    /// The first day (of the year, starting from `1`) of daylight saving
+    pub start_day: usize,
+
+    /// The first day (of the year, starting from `1`) after daylight saving.
+    /// If it is smaller than `start_day`, the period wraps around the end of the
+    /// year (as in the southern hemisphere).
+    pub end_day: usize,
+}
+
+impl DaylightSaving {
+    /// Whether a day of the year (starting from `1`) is within the period
+    pub fn contains(&self, day: usize) -> bool {
+        if self.start_day <= self.end_day {
+            day >= self.start_day && day < self.end_day
+        } else {
+            day >= self.start_day || day < self.end_day
+        }
+    }
+}
+
+/// How much each timestep of an annual series counts when calculating
+/// metrics. Timesteps are in standard time, starting on the 1st of January at
+/// midnight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OccupancySchedule {
+    /// The number of timesteps in an hour
+    pub timesteps_per_hour: usize,
+
+    /// The occupancy of each timestep, between `0` (empty) and `1` (fully occupied)
+    pub weights: Vec<Float>,
+}
+
+impl OccupancySchedule {
+    /// Creates a schedule from the occupancy of each timestep
+    pub fn from_weights(weights: Vec<Float>, timesteps_per_hour: usize) -> Result<Self, String> {
+        if timesteps_per_hour == 0 {
+            return Err("A schedule needs at least one timestep per hour".to_string());
+        }
+        if let Some(w) = weights.iter().find(|w| !(0.0..=1.).contains(*w)) {
+            return Err(format!(
+                "Occupancy weights must be between 0 and 1... found {}",
+                w
+            ));
+        }
+        Ok(Self {
+            timesteps_per_hour,
+            weights,
+        })
+    }
+
+    /// A schedule that is occupied every day between `start_hour` and `end_hour`
+    /// of local time (i.e., standard time shifted by `daylight_saving`, if any). A
+    /// timestep is occupied if its centre is.
+    pub fn office_hours(
+        n_timesteps: usize,
+        timesteps_per_hour: usize,
+        start_hour: Float,
+        end_hour: Float,
+        daylight_saving: Option<DaylightSaving>,
+    ) -> Result<Self, String> {
+        if timesteps_per_hour == 0 {
+            return Err("A schedule needs at least one timestep per hour".to_string());
+        }
+        if !(0.0..=24.).contains(&start_hour) || !(0.0..=24.).contains(&end_hour) {
+            return Err(format!(
+                "Office hours must be between 0 and 24... found {} to {}",
+                start_hour, end_hour
+            ));
+        }
+        let weights = (0..n_timesteps)
+            .map(|i| {
+                let centre = Timestep::new(i, timesteps_per_hour)?.centre();
+                let hour = centre.hour_of_year;
+                let day = centre.day_of_year() + 1;
+                let shift = match daylight_saving {
+                    Some(dst) if dst.contains(day) => 1.,
+                    _ => 0.,
+                };
+                let local = (hour + shift) % HOURS_PER_DAY as Float;
+                if local >= start_hour && local < end_hour {
+                    Ok(1.)
+                } else {
+                    Ok(0.)
+                }
+            })
+            .collect::<Result<Vec<Float>, String>>()?;
+        Ok(Self {
+            timesteps_per_hour,
+            weights,
+        })
+    }
+
+    /// The number of occupied hours, weighted by occupancy
+    pub fn occupied_hours(&self) -> Float {
+        self.weights.iter().sum::<Float>() / self.timesteps_per_hour as Float
+    }
+
+    /// The part of the schedule that covers the timesteps of an `axis`, which
+    /// may start anywhere in the year (and go through the New Year, if the
+    /// schedule covers the whole of it). Fails if the axis has a different number
+    /// of timesteps per hour, or if the schedule does not cover it.
+    pub fn on_axis(&self, axis: &TimeAxis) -> Result<Self, String> {
+        if axis.timesteps_per_hour != self.timesteps_per_hour {
+            return Err(format!(
+                "The schedule has {} timesteps per hour, but the time axis has {}",
+                self.timesteps_per_hour, axis.timesteps_per_hour
+            ));
+        }
+        let weights = axis
+            .timesteps()
+            .iter()
+            .map(|step| {
+                self.weights
+                    .get(step.index_in_year())
+                    .copied()
+                    .ok_or_else(|| {
+                        format!(
+                            "The schedule has {} timesteps, so it does not cover timestep {}",
+                            self.weights.len(),
+                            step.index_in_year()
+                        )
+                    })
+            })
+            .collect::<Result<Vec<Float>, String>>()?;
+        Ok(Self {
+            timesteps_per_hour: self.timesteps_per_hour,
+            weights,
+        })
+    }
+}
+
+/// The thresholds used by an [`AnnualReport`]. Illuminances are in lux.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportThresholds {
+    /// The illuminance needed for a timestep to count towards the
+    /// (continuous) Daylight Autonomy
+    pub daylight_autonomy: Float,
+
+    /// Below this, the Useful Daylight Illuminance fell short
+    pub udi_lower: Float,
+
+    /// Above this, the Useful Daylight Illuminance was exceeded
+    pub udi_upper: Float,
+
+    /// The Daylight Autonomy that a sensor needs for counting
+    /// towards the Spatial Daylight Autonomy
+    pub sda_min_autonomy: Float,
+
+    /// The direct illuminance above which a timestep counts towards
+    /// the Annual Sunlight Exposure
+    pub ase_illuminance: Float,
+
+    /// The number of (occupancy-weighted) hours of direct sun above which
+    /// a sensor counts towards the Annual Sunlight Exposure
+    pub ase_hours: Float,
+
+    /// The lower edges of the bins of the illuminance histogram. The
+    /// last bin has no upper limit.
+    pub histogram_edges: Vec<Float>,
+}
+
+impl Default for ReportThresholds {
+    fn default() -> Self {
+        Self {
+            daylight_autonomy: 300.,
+            udi_lower: 100.,
+            udi_upper: 3000.,
+            sda_min_autonomy: 0.5,
+            ase_illuminance: 1000.,
+            ase_hours: 250.,
+            histogram_edges: vec![0., 100., 300., 500., 1000., 2000., 3000.],
+        }
+    }
+}
+
+/// The metrics of a sensor. All of them are fractions of the occupied
+/// time, except `direct_sun_hours`. They are `NaN` for sensors with
+/// non-finite values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorMetrics {
+    /// Daylight Autonomy
+    pub daylight_autonomy: Float,
+
+    /// Continuous Daylight Autonomy, which gives partial credit to
+    /// timesteps below the threshold
+    pub continuous_daylight_autonomy: Float,
+
+    /// Useful Daylight Illuminance: below `udi_lower`
+    pub udi_fell_short: Float,
+
+    /// Useful Daylight Illuminance: between `udi_lower` and `udi_upper`
+    pub udi_achieved: Float,
+
+    /// Useful Daylight Illuminance: above `udi_upper`
+    pub udi_exceeded: Float,
+
+    /// The occupied hours with direct illuminance of at least `ase_illuminance`.
+    /// Only available when the direct illuminance is given.
+    pub direct_sun_hours: Option<Float>,
+
+    /// The id of the sensor, if known (see [`SensorId`])
+    #[serde(default)]
+    pub id: Option<SensorId>,
+}
+
+/// The metrics of a zone, aggregated from those of its valid sensors
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZoneMetrics {
+    /// The name of the zone
+    pub zone: String,
+
+    /// The number of sensors in the zone
+    pub n_sensors: usize,
+
+    /// The number of sensors with valid results
+    pub n_valid: usize,
+
+    /// The average Daylight Autonomy
+    pub mean_daylight_autonomy: Float,
+
+    /// The average Continuous Daylight Autonomy
+    pub mean_continuous_daylight_autonomy: Float,
+
+    /// The average fraction of the time within the Useful Daylight Illuminance range
+    pub mean_udi_achieved: Float,
+
+    /// The Spatial Daylight Autonomy of the zone
+    pub spatial_daylight_autonomy: Float,
+
+    /// The Annual Sunlight Exposure of the zone, if the direct illuminance was given
+    pub annual_sunlight_exposure: Option<Float>,
+}
+
+/// The fraction of the occupied time (of all valid sensors) that the
+/// illuminance spent within each bin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// The lower edge of each bin
+    pub edges: Vec<Float>,
+
+    /// One element per bin
+    pub fractions: Vec<Float>,
+}
+
+/// All the annual metrics of a grid of sensors, together with the
+/// configuration used for calculating them. It is meant to be serialised
+/// (e.g., through [`AnnualReport::to_json`]) and rendered without further processing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnnualReport {
+    /// The thresholds used
+    pub thresholds: ReportThresholds,
+
+    /// The occupancy used
+    pub schedule: OccupancySchedule,
+
+    /// The number of sensors
+    pub n_sensors: usize,
+
+    /// The number of timesteps in the annual series
+    pub n_timesteps: usize,
+
+    /// The occupied hours in the schedule
+    pub occupied_hours: Float,
+
+    /// The metrics of each sensor
+    pub sensors: Vec<SensorMetrics>,
+
+    /// The Spatial Daylight Autonomy of the whole grid
+    pub spatial_daylight_autonomy: Float,
+
+    /// The Annual Sunlight Exposure of the whole grid, if the direct
+    /// illuminance was given
+    pub annual_sunlight_exposure: Option<Float>,
+
+    /// The metrics of each zone, in order of first appearance
+    pub zones: Vec<ZoneMetrics>,
+
+    /// The histogram of the illuminance during occupied time
+    pub histogram: Histogram,
+
+    /// Issues found while building the report (e.g., invalid sensors)
+    pub warnings: Vec<String>,
+
+    /// How the Daylight Coefficients behind the report were calculated, if
+    /// known (see [`AnnualReport::with_manifest`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<RunManifest>,
+}
+
+/// The mean of the finite values, or `NaN` if there are none
+fn finite_mean<I: Iterator<Item = Float>>(values: I) -> Float {
+    let (mut sum, mut n) = (0.0, 0);
+    for v in values.filter(|v| v.is_finite()) {
+        sum += v;
+        n += 1;
+    }
+    if n == 0 {
+        Float::NAN
+    } else {
+        sum / n as Float
+    }
+}
+
+/// The fraction of `flags` that are `true`, or `NaN` if it is empty
+fn fraction(flags: &[bool]) -> Float {
+    if flags.is_empty() {
+        return Float::NAN;
+    }
+    flags.iter().filter(|f| **f).count() as Float / flags.len() as Float
+}
+
+impl AnnualReport {
+    /// Builds the report from the annual `illuminance`—with one row per sensor and
+    /// one column per timestep, as produced by [`crate::annual_irradiance`]—and,
+    /// optionally, the `direct` illuminance (same shape), which is needed for the
+    /// Annual Sunlight Exposure. The `rows` describe the sensors (including their
+    /// zones).
+    ///
+    /// Every sensor is processed in a single pass over its timesteps.
+    pub fn new(
+        illuminance: &Matrix,
+        direct: Option<&Matrix>,
+        rows: &[RowMetadata],
+        schedule: &OccupancySchedule,
+        thresholds: &ReportThresholds,
+    ) -> Result<Self, String> {
+        let (n_sensors, n_timesteps) = illuminance.size();
+        if rows.len() != n_sensors {
+            return Err(format!(
+                "Illuminance has {} rows, but {} rows are described",
+                n_sensors,
+                rows.len()
+            ));
+        }
+        if let Some(d) = direct {
+            if d.size() != illuminance.size() {
+                return Err(format!(
+                    "Direct illuminance has size {:?}, but the illuminance has size {:?}",
+                    d.size(),
+                    illuminance.size()
+                ));
+            }
+        }
+        if schedule.weights.len() != n_timesteps {
+            return Err(format!(
+                "Illuminance has {} timesteps, but the schedule has {}",
+                n_timesteps,
+                schedule.weights.len()
+            ));
+        }
+        let total_weight: Float = schedule.weights.iter().sum();
+        if total_weight <= 0.0 {
+            return Err("Cannot build a report without occupied timesteps".to_string());
+        }
+        let edges = &thresholds.histogram_edges;
+        if edges.is_empty() || edges.windows(2).any(|w| w[1] <= w[0]) {
+            return Err("Histogram edges must be non-empty and strictly increasing".to_string());
+        }
+
+        let dt = 1. / schedule.timesteps_per_hour as Float;
+        let mut warnings = Vec::new();
+        let non_ideal = rows
+            .iter()
+            .filter(|r| r.angular_response.as_ref().is_some_and(|c| !c.is_ideal()))
+            .count();
+        if non_ideal > 0 {
+            warnings.push(format!(
+                "{} of the {} sensors have a non-ideal angular response, so they give what a particular meter would measure",
+                non_ideal, n_sensors
+            ));
+        }
+        let mut sensors = Vec::with_capacity(n_sensors);
+        let mut valid = Vec::with_capacity(n_sensors);
+        let mut histogram = vec![0.0; edges.len()];
+        let mut row_histogram = vec![0.0; edges.len()];
+        for (r, row) in rows.iter().enumerate() {
+            let (mut da, mut cda) = (0.0, 0.0);
+            let (mut short, mut achieved, mut exceeded) = (0.0, 0.0, 0.0);
+            let mut sun_hours = 0.0;
+            let mut is_valid = true;
+            row_histogram.iter_mut().for_each(|v| *v = 0.0);
+            for (t, w) in schedule.weights.iter().enumerate() {
+                let e = illuminance.get(r, t)?;
+                let e_direct = match direct {
+                    Some(d) => d.get(r, t)?,
+                    None => 0.0,
+                };
+                if !e.is_finite() || !e_direct.is_finite() {
+                    is_valid = false;
+                    break;
+                }
+                if *w <= 0.0 {
+                    continue;
+                }
+                if e >= thresholds.daylight_autonomy {
+                    da += w;
+                }
+                cda += w * (e / thresholds.daylight_autonomy).clamp(0.0, 1.0);
+                if e < thresholds.udi_lower {
+                    short += w;
+                } else if e > thresholds.udi_upper {
+                    exceeded += w;
+                } else {
+                    achieved += w;
+                }
+                if e_direct >= thresholds.ase_illuminance {
+                    sun_hours += w * dt;
+                }
+                let bin = edges.iter().rposition(|edge| e >= *edge).unwrap_or(0);
+                row_histogram[bin] += w;
+            }
+
+            valid.push(is_valid);
+            if !is_valid {
+                warnings.push(format!(
+                    "Sensor {} has non-finite values, so its metrics are NaN",
+                    r
+                ));
+                sensors.push(SensorMetrics {
+                    daylight_autonomy: Float::NAN,
+                    continuous_daylight_autonomy: Float::NAN,
+                    udi_fell_short: Float::NAN,
+                    udi_achieved: Float::NAN,
+                    udi_exceeded: Float::NAN,
+                    direct_sun_hours: direct.map(|_| Float::NAN),
+                    id: Some(row.id.clone()),
+                });
+                continue;
+            }
+            for (h, v) in histogram.iter_mut().zip(row_histogram.iter()) {
+                *h += v;
+            }
+            sensors.push(SensorMetrics {
+                daylight_autonomy: da / total_weight,
+                continuous_daylight_autonomy: cda / total_weight,
+                udi_fell_short: short / total_weight,
+                udi_achieved: achieved / total_weight,
+                udi_exceeded: exceeded / total_weight,
+                direct_sun_hours: direct.map(|_| sun_hours),
+                id: Some(row.id.clone()),
+            });
+        }
+
+        // Spatial metrics, over a subset of the sensors
+        let spatial = |members: &[usize]| -> (Float, Option<Float>) {
+            let members: Vec<usize> = members.iter().copied().filter(|i| valid[*i]).collect();
+            let sda: Vec<bool> = members
+                .iter()
+                .map(|i| sensors[*i].daylight_autonomy >= thresholds.sda_min_autonomy)
+                .collect();
+            let ase = direct.map(|_| {
+                let flags: Vec<bool> = members
+                    .iter()
+                    .map(|i| sensors[*i].direct_sun_hours.unwrap_or(0.0) > thresholds.ase_hours)
+                    .collect();
+                fraction(&flags)
+            });
+            (fraction(&sda), ase)
+        };
+        let all: Vec<usize> = (0..n_sensors).collect();
+        let (spatial_daylight_autonomy, annual_sunlight_exposure) = spatial(&all);
+
+        let groups = ZoneGroups::new(rows);
+        let mut zones = Vec::with_capacity(groups.len());
+        for (name, members) in groups.names.iter().zip(groups.members.iter()) {
+            let n_valid = members.iter().filter(|i| valid[**i]).count();
+            if n_valid == 0 {
+                warnings.push(format!(
+                    "Zone '{}' has no valid sensors (out of {})",
+                    name,
+                    members.len()
+                ));
+            }
+            let of = |f: fn(&SensorMetrics) -> Float| {
+                finite_mean(members.iter().map(|i| f(&sensors[*i])))
+            };
+            let (sda, ase) = spatial(members);
+            zones.push(ZoneMetrics {
+                zone: name.clone(),
+                n_sensors: members.len(),
+                n_valid,
+                mean_daylight_autonomy: of(|s| s.daylight_autonomy),
+                mean_continuous_daylight_autonomy: of(|s| s.continuous_daylight_autonomy),
+                mean_udi_achieved: of(|s| s.udi_achieved),
+                spatial_daylight_autonomy: sda,
+                annual_sunlight_exposure: ase,
+            });
+        }
+
+        let n_valid = valid.iter().filter(|v| **v).count();
+        let histogram_total = total_weight * n_valid as Float;
+        let fractions = histogram
+            .iter()
+            .map(|h| {
+                if n_valid == 0 {
+                    Float::NAN
+                } else {
+                    h / histogram_total
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            thresholds: thresholds.clone(),
+            schedule: schedule.clone(),
+            n_sensors,
+            n_timesteps,
+            occupied_hours: schedule.occupied_hours(),
+            sensors,
+            spatial_daylight_autonomy,
+            annual_sunlight_exposure,
+            zones,
+            histogram: Histogram {
+                edges: edges.clone(),
+                fractions,
+            },
+            warnings,
+            manifest: None,
+        })
+    }
+
+    /// Like [`AnnualReport::new`], for series that carry their own timesteps.
+    /// The part of the `schedule` that covers them is used, so it must have the
+    /// same timesteps per hour (see [`OccupancySchedule::on_axis`]).
+    pub fn from_series(
+        illuminance: &AnnualSeries,
+        direct: Option<&AnnualSeries>,
+        rows: &[RowMetadata],
+        schedule: &OccupancySchedule,
+        thresholds: &ReportThresholds,
+    ) -> Result<Self, String> {
+        if let Some(d) = direct {
+            if d.axis != illuminance.axis {
+                return Err(format!(
+                    "Direct illuminance has time axis {:?}, but the illuminance has {:?}",
+                    d.axis, illuminance.axis
+                ));
+            }
+        }
+        let schedule = schedule.on_axis(&illuminance.axis)?;
+        Self::new(
+            &illuminance.values,
+            direct.map(|d| &d.values),
+            rows,
+            &schedule,
+            thresholds,
+        )
+    }
+
+    /// Attaches the [`RunManifest`] of the calculation of the Daylight
+    /// Coefficients behind the report, which is then serialised with it
+    pub fn with_manifest(mut self, manifest: RunManifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Serialises the report into JSON. `NaN` values become `null`.
+    pub fn to_json(&self) -> Result<String, String> {
+        serde_json::to_string_pretty(self).map_err(|e| format!("Could not serialise report: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    fn row(zone: &str) -> RowMetadata {
+        RowMetadata {
+            ray: Ray3D {
+                origin: Point3D::new(0., 0., 0.8),
+                direction: Vector3D::new(0., 0., 1.),
+            },
+            mask: None,
+            zone: Some(zone.to_string()),
+            n_samples: 1,
+            id: SensorId::new("sensor").unwrap(),
+            angular_response: None,
+        }
+    }
+
+    fn matrix(values: &[[Float; 10]]) -> Matrix {
+        let mut ret = Matrix::new(0.0, values.len(), 10);
+        for (r, row) in values.iter().enumerate() {
+            for (c, v) in row.iter().enumerate() {
+                ret.set(r, c, *v).unwrap();
+            }
+        }
+        ret
+    }
+
+    /// A small dataset whose results are exact in binary
+    fn synthetic() -> AnnualReport {
+        let nan = Float::NAN;
+        let illuminance = matrix(&[
+            [500., 500., 500., 500., 500., 500., 500., 500., 500., 5000.],
+            [0., 75., 150., 225., 300., 3500., 75., 150., 150., 999.],
+            [150., 150., 150., 150., 150., 150., 150., 150., 150., 0.],
+            [400., 400., 400., 400., 400., 400., 0., 0., 0., 0.],
+            [nan; 10],
+        ]);
+        let direct = matrix(&[
+            [2000.; 10],
+            [0.; 10],
+            [0.; 10],
+            [1500., 1500., 1500., 1500., 1500., 0., 0., 0., 0., 0.],
+            [nan; 10],
+        ]);
+        let rows = vec![
+            row("north"),
+            row("north"),
+            row("south"),
+            row("south"),
+            row("storage"),
+        ];
+        let schedule =
+            OccupancySchedule::from_weights(vec![1., 1., 1., 1., 1., 1., 1., 0.5, 0.5, 0.], 1)
+                .unwrap();
+        let thresholds = ReportThresholds {
+            ase_hours: 4.,
+            histogram_edges: vec![0., 100., 300., 1000.],
+            ..ReportThresholds::default()
+        };
+        AnnualReport::new(&illuminance, Some(&direct), &rows, &schedule, &thresholds).unwrap()
+    }
+
+    #[test]
+    fn test_annual_report() {
+        let report = synthetic();
+        assert_close!(report.occupied_hours, 8., 1e-9);
+
+        let s = &report.sensors[1];
+        assert_close!(s.daylight_autonomy, 0.25, 1e-9);
+        assert_close!(s.continuous_daylight_autonomy, 0.53125, 1e-9);
+        assert_close!(s.udi_fell_short, 0.375, 1e-9);
+        assert_close!(s.udi_achieved, 0.5, 1e-9);
+        assert_close!(s.udi_exceeded, 0.125, 1e-9);
+        assert_close!(report.sensors[3].direct_sun_hours.unwrap(), 5., 1e-9);
+        assert!(report.sensors[4].daylight_autonomy.is_nan());
+
+        assert_close!(report.spatial_daylight_autonomy, 0.5, 1e-9);
+        assert_close!(report.annual_sunlight_exposure.unwrap(), 0.5, 1e-9);
+
+        let north = &report.zones[0];
+        assert_close!(north.mean_daylight_autonomy, 0.625, 1e-9);
+        assert_close!(north.mean_continuous_daylight_autonomy, 0.765625, 1e-9);
+        let storage = &report.zones[2];
+        assert_eq!(storage.n_valid, 0);
+        assert!(storage.spatial_daylight_autonomy.is_nan());
+        assert_eq!(report.warnings.len(), 2);
+
+        let expected = [0.15625, 0.34375, 0.46875, 0.03125];
+        for (f, e) in report.histogram.fractions.iter().zip(expected.iter()) {
+            assert_close!(*f, *e, 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_annual_report_golden_json() {
+        let report = synthetic();
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        let golden = std::fs::read_to_string("./tests/report/golden.json").unwrap();
+        let golden: serde_json::Value = serde_json::from_str(&golden).unwrap();
+        assert_eq!(json, golden);
+    }
+
+    #[test]
+    fn test_non_ideal_response() {
+        let mut rows = vec![row("north"), row("north")];
+        let response = crate::ResponseCurve::new(vec![(0., 1.), (90., 0.8)]).unwrap();
+        rows[1].angular_response = Some(response);
+        let illuminance = matrix(&[[500.; 10], [500.; 10]]);
+        let schedule = OccupancySchedule::from_weights(vec![1.; 10], 1).unwrap();
+        let report = AnnualReport::new(
+            &illuminance,
+            None,
+            &rows,
+            &schedule,
+            &ReportThresholds::default(),
+        )
+        .unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert!(
+            report.warnings[0].starts_with("1 of the 2 sensors have a non-ideal angular response")
+        );
+        assert_eq!(report.sensors[1].id, Some(SensorId::new("sensor").unwrap()));
+    }
+
+    #[test]
+    fn test_office_hours() {
+        // Daylight saving on the 1st of January only: offices open one
+        // (standard) hour earlier that day
+        let dst = DaylightSaving {
+            start_day: 1,
+            end_day: 2,
+        };
+        let schedule = OccupancySchedule::office_hours(48, 1, 8., 17., Some(dst)).unwrap();
+        let occupied: Vec<usize> = (0..48).filter(|i| schedule.weights[*i] > 0.0).collect();
+        let expected: Vec<usize> = (7..16).chain(32..41).collect();
+        assert_eq!(occupied, expected);
+        assert_close!(schedule.occupied_hours(), 18., 1e-9);
+
+        // Half-hourly, without daylight saving
+        let schedule = OccupancySchedule::office_hours(48, 2, 8., 17., None).unwrap();
+        assert_close!(schedule.occupied_hours(), 9., 1e-9);
+        assert!(schedule.weights[15] == 0.0 && schedule.weights[16] == 1.0);
+
+        // Wrapping around the end of the year
+        let southern = DaylightSaving {
+            start_day: 270,
+            end_day: 90,
+        };
+        assert!(southern.contains(1) && southern.contains(300) && !southern.contains(180));
+
+        assert!(OccupancySchedule::from_weights(vec![1.5], 1).is_err());
+        assert!(OccupancySchedule::office_hours(24, 0, 8., 17., None).is_err());
+    }
+
+    #[test]
+    fn test_from_series() {
+        // The first ten hours of the 21st of June, of which 8am to 10am are occupied
+        let axis = TimeAxis::new(1, 171 * 24, 10).unwrap();
+        let schedule = OccupancySchedule::office_hours(8760, 1, 8., 17., None).unwrap();
+        let window = schedule.on_axis(&axis).unwrap();
+        assert_eq!(window.weights.len(), 10);
+        assert_close!(window.occupied_hours(), 2., 1e-9);
+
+        let values = matrix(&[[50., 50., 50., 50., 50., 50., 50., 50., 400., 400.]]);
+        let illuminance = AnnualSeries::new(values.clone(), axis).unwrap();
+        let thresholds = ReportThresholds::default();
+        let rows = vec![row("office")];
+        let report =
+            AnnualReport::from_series(&illuminance, None, &rows, &schedule, &thresholds).unwrap();
+        let expected = AnnualReport::new(&values, None, &rows, &window, &thresholds).unwrap();
+        assert_eq!(report, expected);
+        assert_close!(report.sensors[0].daylight_autonomy, 1., 1e-9);
+
+        // The axes must agree
+        let direct = AnnualSeries::new(values, TimeAxis::new(1, 0, 10).unwrap()).unwrap();
+        assert!(AnnualReport::from_series(
+            &illuminance,
+            Some(&direct),
+            &rows,
+            &schedule,
+            &thresholds
+        )
+        .is_err());
+        let half_hourly = OccupancySchedule::office_hours(8760, 2, 8., 17., None).unwrap();
+        assert!(half_hourly.on_axis(&axis).is_err());
+        let short = OccupancySchedule::office_hours(24, 1, 8., 17., None).unwrap();
+        assert!(short.on_axis(&axis).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::session::DCOptions;
+use crate::Float;
+use geometry3d::Ray3D;
+use rendering::{Ray, Scene};
+use serde::{Deserialize, Serialize};
+use solar::ReinhartSky;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The number of Floats stored for each element of a Daylight
+/// Coefficient matrix (i.e., one per colour channel)
+const FLOATS_PER_COEFFICIENT: usize = 3;
+
+/// A rough guess of the time it takes to cast a ray, used when
+/// the estimate has not been calibrated.
+pub const DEFAULT_SECONDS_PER_RAY: Float = 2e-6;
+
+/// The number of rays cast by [`benchmark_scene`]
+pub const N_BENCHMARK_RAYS: usize = 100;
+
+/// The number of samples that each sensor still gets once a [`RayBudget`] has
+/// been used up, so that every row has some coefficients
+pub const MIN_CAPPED_SAMPLES: usize = 16;
+
+/// What happens when the rays of a calculation are expected to exceed
+/// its [`RayBudget`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BudgetAction {
+    /// Record an [`EventKind::RayBudgetExceeded`](crate::events::EventKind::RayBudgetExceeded)
+    /// and start anyway, relying on the cap
+    #[default]
+    Warn,
+
+    /// Refuse to start
+    Error,
+}
+
+/// A limit on the total number of rays (primary and secondary) that a
+/// calculation can cast.
+///
+/// Before starting, [`ResourceEstimate::total_rays`] is compared with `max_rays`,
+/// and the `action` is taken if it exceeds it. While tracing, the
+/// rays of each sensor are counted against `max_rays` across all threads; once they
+/// run out, the remaining sensors are traced with fewer samples (but at least
+/// [`MIN_CAPPED_SAMPLES`]), so the run completes with noisier results instead of
+/// running away. Whether this happened is reported in [`DCStats::ray_cap_hit`](crate::DCStats::ray_cap_hit).
+/// Which sensors lose samples depends on the order in which they are traced, so
+/// it can change between parallel runs.
+///
+/// When the bounces are traced by the `DCFactory` (i.e., `max_depth > 0`), the rays
+/// are counted as if no path branched, which is a lower bound (see
+/// [`ResourceEstimate::secondary_rays`]): the actual number of rays can exceed the
+/// budget by orders of magnitude. Such calculations record an
+/// [`EventKind::RayBudgetUnenforced`](crate::events::EventKind::RayBudgetUnenforced)
+/// when the action is [`BudgetAction::Warn`], and are refused when it is
+/// [`BudgetAction::Error`], as the budget cannot be guaranteed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RayBudget {
+    /// The number of rays allowed
+    pub max_rays: usize,
+
+    /// What to do if the calculation is expected to need more rays
+    #[serde(default)]
+    pub action: BudgetAction,
+}
+
+/// The number of rays that each sample casts if its path follows a single
+/// direction at each bounce (as the tracers of this crate do) and bounces as
+/// many times as allowed
+pub(crate) fn rays_per_sample(options: &DCOptions) -> usize {
+    1 + options.max_bounces()
+}
+
+/// Counts the rays of a calculation against a [`RayBudget`], from any thread
+#[derive(Debug)]
+pub(crate) struct RayCap {
+    max_rays: usize,
+    used: AtomicUsize,
+    hit: AtomicBool,
+}
+
+impl RayCap {
+    pub(crate) fn new(budget: &RayBudget) -> Self {
+        Self {
+            max_rays: budget.max_rays,
+            used: AtomicUsize::new(0),
+            hit: AtomicBool::new(false),
+        }
+    }
+
+    /// Reserves the rays of `n_samples` samples that cast up to `rays_per_sample`
+    /// rays each, returning how many samples fit in what is left of the budget.
+    /// At least `min_samples` (or `n_samples`, if smaller) are always granted.
+    pub(crate) fn reserve(
+        &self,
+        n_samples: usize,
+        rays_per_sample: usize,
+        min_samples: usize,
+    ) -> usize {
+        let rays_per_sample = rays_per_sample.max(1);
+        let mut granted = n_samples;
+        // The closure always returns Some, so this cannot fail
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let left = self.max_rays.saturating_sub(used) / rays_per_sample;
+                granted = n_samples.min(left.max(min_samples));
+                Some(used.saturating_add(granted.saturating_mul(rays_per_sample)))
+            });
+        if granted < n_samples {
+            self.hit.store(true, Ordering::SeqCst);
+        }
+        granted
+    }
+
+    /// Whether any reservation got fewer samples than it asked for
+    pub(crate) fn was_hit(&self) -> bool {
+        self.hit.load(Ordering::SeqCst)
+    }
+}
+
+/// The expected cost of calculating a Daylight Coefficient matrix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResourceEstimate {
+    /// The number of bins (i.e., columns) of the matrix
+    pub n_bins: usize,
+
+    /// The memory (in bytes) needed for storing the matrix
+    pub matrix_bytes: usize,
+
+    /// The number of rays sent from the sensors
+    pub primary_rays: usize,
+
+    /// The number of rays spawned after bouncing if every path follows a single
+    /// direction at each bounce—as the tracers of this crate do—and bounces as
+    /// many times as allowed. The `DCFactory` (which traces the bounces when
+    /// `max_depth > 0`) branches its ambient samples at each bounce instead, so
+    /// for it this is a lower bound, which can be off by orders of magnitude.
+    pub secondary_rays: usize,
+
+    /// The time it takes to cast a ray, in seconds
+    pub seconds_per_ray: Float,
+
+    /// Whether `seconds_per_ray` was measured on the scene or
+    /// it is just [`DEFAULT_SECONDS_PER_RAY`]
+    pub calibrated: bool,
+
+    /// The expected wall time of the calculation
+    pub wall_time: Duration,
+}
+
+impl ResourceEstimate {
+    /// The total number of rays (primary and secondary). See
+    /// [`ResourceEstimate::secondary_rays`] for when this is a lower bound.
+    pub fn total_rays(&self) -> usize {
+        self.primary_rays.saturating_add(self.secondary_rays)
+    }
+
+    /// Recalculates the wall time using the time per ray measured
+    /// by [`benchmark_scene`].
+    pub fn calibrate(mut self, seconds_per_ray: Float) -> Self {
+        self.seconds_per_ray = seconds_per_ray;
+        self.calibrated = true;
+        self.wall_time = wall_time(self.total_rays(), seconds_per_ray);
+        self
+    }
+}
+
+/// The number of threads that will trace rays
+pub(crate) fn n_threads() -> usize {
+    #[cfg(feature = "parallel")]
+    {
+        rayon::current_num_threads().max(1)
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        1
+    }
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn wall_time(n_rays: usize, seconds_per_ray: Float) -> Duration {
+    let secs = n_rays as f64 * seconds_per_ray as f64 / n_threads() as f64;
+    Duration::from_secs_f64(secs.max(0.0))
+}
+
+/// Estimates the memory, number of rays and wall time needed for
+/// calculating the Daylight Coefficients of `n_sensors` sensors
+/// using a Reinhart sky with subdivision `mf`. The rays (and therefore the
+/// wall time) are those of paths that do not branch (see
+/// [`ResourceEstimate::secondary_rays`]).
+///
+/// The wall time is based on [`DEFAULT_SECONDS_PER_RAY`]. Use
+/// [`ResourceEstimate::calibrate`] with the results of [`benchmark_scene`]
+/// for a better guess.
+pub fn estimate_resources(n_sensors: usize, mf: usize, options: &DCOptions) -> ResourceEstimate {
+    let n_bins = ReinhartSky::n_bins(mf);
+    let matrix_bytes = n_sensors
+        .saturating_mul(n_bins)
+        .saturating_mul(FLOATS_PER_COEFFICIENT * std::mem::size_of::<Float>());
+    let primary_rays = n_sensors.saturating_mul(options.n_ambient_samples);
+    let secondary_rays = primary_rays.saturating_mul(rays_per_sample(options) - 1);
+    let total_rays = primary_rays.saturating_add(secondary_rays);
+
+    ResourceEstimate {
+        n_bins,
+        matrix_bytes,
+        primary_rays,
+        secondary_rays,
+        seconds_per_ray: DEFAULT_SECONDS_PER_RAY,
+        calibrated: false,
+        wall_time: wall_time(total_rays, DEFAULT_SECONDS_PER_RAY),
+    }
+}
+
+/// Casts [`N_BENCHMARK_RAYS`] rays from the `sensors` in random directions
+/// in their hemisphere, returning the average number of seconds it took
+/// to cast each of them.
+pub fn benchmark_scene(scene: &Scene, sensors: &[Ray3D]) -> Result<Float, String> {
+    if sensors.is_empty() {
+        return Err("Cannot benchmark a scene without sensors".to_string());
+    }
+    let mut rng = rendering::rand::get_rng();
+    let mut node_aux = Vec::with_capacity(2);
+
+    let start = Instant::now();
+    for i in 0..N_BENCHMARK_RAYS {
+        let sensor = sensors[i % sensors.len()];
+        let normal = sensor.direction;
+        let e1 = normal.get_perpendicular()?;
+        let e2 = normal.cross(e1);
+        let direction = rendering::samplers::uniform_sample_hemisphere(&mut rng, e1, e2, normal);
+        let mut ray = Ray {
+            geometry: Ray3D {
+                origin: sensor.origin,
+                direction,
+            },
+            ..Ray::default()
+        };
+        let _ = scene.cast_ray(&mut ray, &mut node_aux);
+    }
+    Ok((start.elapsed().as_secs_f64() / N_BENCHMARK_RAYS as f64) as Float)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use geometry3d::{Point3D, Vector3D};
+
+    #[test]
+    fn test_estimate_resources() {
+        let options = DCOptions {
+            max_depth: 2,
+            n_ambient_samples: 1000,
+            ..DCOptions::default()
+        };
+        let est = estimate_resources(10, 1, &options);
+        assert_eq!(est.n_bins, 146);
+        assert_eq!(
+            est.matrix_bytes,
+            10 * 146 * 3 * std::mem::size_of::<Float>()
+        );
+        assert_eq!(est.primary_rays, 10_000);
+        assert_eq!(est.secondary_rays, 20_000);
+        assert_eq!(est.total_rays(), 30_000);
+        assert!(!est.calibrated);
+
+        // The case that motivated all this: MF:6 over 100k sensors
+        let est = estimate_resources(100_000, 6, &options);
+        assert_eq!(est.n_bins, 5186);
+        assert_eq!(
+            est.matrix_bytes,
+            100_000 * 5186 * 3 * std::mem::size_of::<Float>()
+        );
+
+        // Calibrating scales the wall time
+        let est = estimate_resources(10, 1, &options);
+        let slow = est.calibrate(2. * DEFAULT_SECONDS_PER_RAY);
+        assert!(slow.calibrated);
+        let ratio = slow.wall_time.as_secs_f64() / est.wall_time.as_secs_f64();
+        assert!((ratio - 2.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_ray_cap() {
+        let cap = RayCap::new(&RayBudget {
+            max_rays: 1000,
+            action: BudgetAction::Warn,
+        });
+        assert_eq!(cap.reserve(100, 4, 10), 100);
+        assert!(!cap.was_hit());
+        // 600 rays are left, which is 150 samples of 4 rays
+        assert_eq!(cap.reserve(200, 4, 10), 150);
+        assert!(cap.was_hit());
+        // Once it runs out, the minimum is still granted
+        assert_eq!(cap.reserve(200, 4, 10), 10);
+        assert_eq!(cap.reserve(5, 4, 10), 5);
+    }
+
+    #[test]
+    fn test_benchmark_scene() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        assert!(benchmark_scene(&scene, &[]).is_err());
+
+        let sensor = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        };
+        let secs = benchmark_scene(&scene, &[sensor]).unwrap();
+        assert!(secs.is_finite());
+        assert!(secs >= 0.0);
+    }
+}
@@ -0,0 +1,283 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::Float;
+use serde::{Deserialize, Serialize};
+
+/// The sequence of numbers used for sampling the directions seen from
+/// the sensors. Only the first bounce (i.e., the direct tracer) uses
+/// it; deeper bounces are always pseudo-random.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SamplingSequence {
+    /// Pseudo-random numbers
+    #[default]
+    Random,
+
+    /// The first two dimensions of the Sobol sequence
+    Sobol,
+
+    /// The Halton sequence in bases 2 and 3
+    Halton,
+}
+
+/// A small and deterministic pseudo-random number generator (SplitMix64).
+///
+/// Each sensor gets its own stream, derived from a seed and the index of
+/// the sensor, so results do not depend on the order in which sensors
+/// are processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SensorRng {
+    state: u64,
+}
+
+/// The finaliser of SplitMix64
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+impl SensorRng {
+    const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+    /// Creates the stream `stream` of seed `seed`
+    pub fn new(seed: u64, stream: u64) -> Self {
+        Self {
+            state: mix(seed.wrapping_add(mix(stream.wrapping_add(Self::GOLDEN_GAMMA)))),
+        }
+    }
+
+    /// Returns the next `u64`
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(Self::GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    /// Returns a number in `[0, 1)`
+    pub fn gen(&mut self) -> Float {
+        #[cfg(not(feature = "float"))]
+        {
+            (self.next_u64() >> 11) as Float / (1u64 << 53) as Float
+        }
+        #[cfg(feature = "float")]
+        {
+            (self.next_u64() >> 40) as Float / (1u64 << 24) as Float
+        }
+    }
+}
+
+/// The largest `Float` below `1`
+const ONE_MINUS_EPSILON: Float = 1. - Float::EPSILON / 2.;
+
+/// Maps the 32 bits of `x` into `[0, 1)`
+fn unit_interval(x: u32) -> Float {
+    (x as Float / 4294967296.).min(ONE_MINUS_EPSILON)
+}
+
+/// The first dimension of the Sobol sequence (i.e., the Van der Corput
+/// sequence in base 2)
+fn sobol_1(index: u32) -> Float {
+    unit_interval(index.reverse_bits())
+}
+
+/// The second dimension of the Sobol sequence, whose direction numbers
+/// come from the primitive polynomial `x + 1`
+fn sobol_2(mut index: u32) -> Float {
+    let mut v = 1u32 << 31;
+    let mut x = 0;
+    while index != 0 {
+        if index & 1 == 1 {
+            x ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    unit_interval(x)
+}
+
+/// The radical inverse of `index` in base `base`
+fn radical_inverse(mut index: u64, base: u64) -> Float {
+    let inv_base = 1. / base as Float;
+    let mut factor = inv_base;
+    let mut ret = 0.0;
+    while index > 0 {
+        ret += (index % base) as Float * factor;
+        index /= base;
+        factor *= inv_base;
+    }
+    ret.min(ONE_MINUS_EPSILON)
+}
+
+/// Shifts `u` by `offset`, wrapping around `[0, 1)`
+fn rotate(u: Float, offset: Float) -> Float {
+    let v = u + offset;
+    if v >= 1. {
+        (v - 1.).min(ONE_MINUS_EPSILON)
+    } else {
+        v
+    }
+}
+
+/// Produces the pairs of numbers in `[0, 1)` used for sampling the directions
+/// seen from a sensor, following a [`SamplingSequence`].
+///
+/// Every pair depends only on the seed, the stream (i.e., the sensor) and the
+/// index of the sample, so results do not depend on how the work is split.
+/// Pseudo-random pairs come from a generator seeded with all three, rather than
+/// from a single generator per stream.
+///
+/// Low-discrepancy sequences are the same for every sensor, so each stream
+/// shifts its points by a random offset (i.e., a Cranley-Patterson rotation)
+/// derived from the seed and the stream. Otherwise, the errors of all the
+/// sensors would be correlated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SampleStream {
+    sequence: SamplingSequence,
+    key: u64,
+    index: u64,
+    offset: (Float, Float),
+}
+
+impl SampleStream {
+    /// Creates the stream `stream` of seed `seed`
+    pub fn new(sequence: SamplingSequence, seed: u64, stream: u64) -> Self {
+        let mut rng = SensorRng::new(seed, stream);
+        let key = rng.next_u64();
+        let offset = match sequence {
+            SamplingSequence::Random => (0.0, 0.0),
+            _ => (rng.gen(), rng.gen()),
+        };
+        Self {
+            sequence,
+            key,
+            index: 0,
+            offset,
+        }
+    }
+
+    /// Returns the next pair of numbers
+    pub fn next_2d(&mut self) -> (Float, Float) {
+        let i = self.index;
+        self.index += 1;
+        self.sample_2d(i)
+    }
+
+    /// The index of the next sample to be taken
+    pub fn position(&self) -> u64 {
+        self.index
+    }
+
+    /// Makes the stream go on from sample `i`, as if it had taken all the
+    /// samples before it
+    pub fn seek(&mut self, i: u64) {
+        self.index = i;
+    }
+
+    /// Returns the pair of numbers of the sample `i`, regardless
+    /// of how many have been taken from the stream
+    pub fn sample_2d(&self, i: u64) -> (Float, Float) {
+        let (u1, u2) = match self.sequence {
+            SamplingSequence::Random => {
+                let mut rng = SensorRng::new(self.key, i);
+                return (rng.gen(), rng.gen());
+            }
+            // The sequence repeats itself after 2^32 points
+            SamplingSequence::Sobol => (sobol_1(i as u32), sobol_2(i as u32)),
+            SamplingSequence::Halton => (radical_inverse(i, 2), radical_inverse(i, 3)),
+        };
+        (rotate(u1, self.offset.0), rotate(u2, self.offset.1))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    #[test]
+    fn test_streams() {
+        let mut a = SensorRng::new(1, 0);
+        let mut b = SensorRng::new(1, 0);
+        let mut c = SensorRng::new(1, 1);
+        let mut d = SensorRng::new(2, 0);
+        for _ in 0..100 {
+            let x = a.gen();
+            assert!((0.0..1.0).contains(&x));
+            assert_eq!(x, b.gen());
+            assert_ne!(a.next_u64(), c.next_u64());
+            let _ = b.next_u64();
+            let _ = d.next_u64();
+        }
+        assert_ne!(b, d);
+
+        // Roughly uniform
+        let mut rng = SensorRng::new(7, 3);
+        let mean: Float = (0..10000).map(|_| rng.gen()).sum::<Float>() / 10000.;
+        assert!((mean - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_sequences() {
+        // The first points of the sequences
+        let sobol: Vec<(Float, Float)> = (0..4).map(|i| (sobol_1(i), sobol_2(i))).collect();
+        assert_eq!(
+            sobol,
+            vec![(0., 0.), (0.5, 0.5), (0.25, 0.75), (0.75, 0.25)]
+        );
+        assert!((radical_inverse(1, 3) - 1. / 3.).abs() < 1e-6);
+        assert!((radical_inverse(5, 3) - 7. / 9.).abs() < 1e-6);
+        assert_eq!(radical_inverse(6, 2), 0.375);
+
+        // Every cell of a 16x16 grid gets one of the first 256 points
+        let mut cells = vec![0; 256];
+        for i in 0..256 {
+            cells[(sobol_1(i) * 16.) as usize * 16 + (sobol_2(i) * 16.) as usize] += 1;
+        }
+        assert!(cells.iter().all(|c| *c == 1));
+
+        // Rotated points stay within [0, 1)
+        for sequence in [SamplingSequence::Sobol, SamplingSequence::Halton] {
+            let mut s = SampleStream::new(sequence, 1, 0);
+            for _ in 0..1000 {
+                let (u1, u2) = s.next_2d();
+                assert!((0.0..1.0).contains(&u1) && (0.0..1.0).contains(&u2));
+            }
+        }
+
+        // Streams are rotated differently
+        let mut a = SampleStream::new(SamplingSequence::Halton, 1, 0);
+        let mut b = SampleStream::new(SamplingSequence::Halton, 1, 1);
+        assert_ne!(a.next_2d(), b.next_2d());
+
+        // Samples do not depend on the ones taken before them
+        for sequence in [
+            SamplingSequence::Random,
+            SamplingSequence::Sobol,
+            SamplingSequence::Halton,
+        ] {
+            let mut s = SampleStream::new(sequence, 4, 2);
+            let taken: Vec<(Float, Float)> = (0..10).map(|_| s.next_2d()).collect();
+            let fresh = SampleStream::new(sequence, 4, 2);
+            for i in (0..10).rev() {
+                assert_eq!(fresh.sample_2d(i), taken[i as usize]);
+            }
+            assert_ne!(taken[0], taken[1]);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::rng::SensorRng;
+use crate::sensor::LocalFrame;
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+
+/// Draws directions over the hemisphere around a normal.
+///
+/// This is the contract between samplers and whoever uses them: `sample_direction`
+/// draws directions with the probability density (per unit solid angle) returned
+/// by `pdf`, and `pdf` is nothing else than that density. In particular, the
+/// cosine of the angle with the normal is *never* folded into it or into the
+/// sampled values: the caller always applies the cosine explicitly and divides
+/// by the density, as [`sample_weight`] does. [`chi2_sampler_test`] checks that
+/// a sampler keeps its side of the contract.
+pub trait HemisphereSampler {
+    /// The normal of the hemisphere
+    fn normal(&self) -> Vector3D;
+
+    /// Transforms two uniform random numbers in `[0, 1)` into a direction
+    fn sample_direction(&self, u1: Float, u2: Float) -> Vector3D;
+
+    /// The probability density of drawing `direction`
+    fn pdf(&self, direction: Vector3D) -> Float;
+}
+
+/// The weight of a sample drawn from a [`HemisphereSampler`] when estimating
+/// irradiance: `cos(theta) / pdf`. Directions below the hemisphere, or with
+/// a density of zero, weigh nothing.
+pub fn sample_weight<S: HemisphereSampler + ?Sized>(sampler: &S, direction: Vector3D) -> Float {
+    let cos = direction.get_normalized() * sampler.normal().get_normalized();
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    let pdf = sampler.pdf(direction);
+    if pdf <= 0.0 {
+        return 0.0;
+    }
+    cos / pdf
+}
+
+/// How far from one the length of a sampled direction can be before the
+/// direction is taken as broken
+pub const DIRECTION_TOLERANCE: Float = 1e-3;
+
+/// The number of times a broken sample is drawn again by default before it
+/// is skipped (see [`DCOptions::max_resamples`](crate::DCOptions::max_resamples))
+pub const DEFAULT_MAX_RESAMPLES: usize = 4;
+
+/// Returns the direction, normalised, if it is finite and its length is within
+/// [`DIRECTION_TOLERANCE`] of one, which is what samplers draw. Anything else
+/// (e.g., the near-zero vectors drawn from a degenerate frame) would send a
+/// ray nowhere, and its bin would come out of a NaN.
+pub fn checked_direction(direction: Vector3D) -> Option<Vector3D> {
+    let length = direction.length();
+    if !length.is_finite() || (length - 1.).abs() > DIRECTION_TOLERANCE {
+        return None;
+    }
+    Some(direction / length)
+}
+
+/// Draws a sample (its direction and whatever goes with it) from two uniform
+/// numbers `u` through `draw`, which returns `None` if what it drew cannot be
+/// used (e.g., a weight that is not finite). Broken samples, or samples with a
+/// broken direction (see [`checked_direction`]), are drawn again from numbers
+/// of `rng`, up to `max_resamples` times. Returns `None` if they are all broken,
+/// so that the sample is skipped.
+pub(crate) fn draw_checked<T, F>(
+    u: (Float, Float),
+    rng: &mut SensorRng,
+    max_resamples: usize,
+    mut draw: F,
+) -> Option<(Vector3D, T)>
+where
+    F: FnMut(Float, Float) -> Option<(Vector3D, T)>,
+{
+    let (mut u1, mut u2) = u;
+    for attempt in 0..=max_resamples {
+        if attempt > 0 {
+            (u1, u2) = (rng.gen(), rng.gen());
+        }
+        if let Some((direction, value)) = draw(u1, u2) {
+            if let Some(direction) = checked_direction(direction) {
+                return Some((direction, value));
+            }
+        }
+    }
+    None
+}
+
+/// The result of a successful [`chi2_sampler_test`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chi2Report {
+    /// Pearson's chi-squared statistic
+    pub statistic: Float,
+
+    /// The degrees of freedom of the test
+    pub degrees_of_freedom: usize,
+
+    /// The value of the statistic above which the test fails
+    pub critical_value: Float,
+
+    /// The integral of the claimed density over the hemisphere, which should be `1`
+    pub pdf_integral: Float,
+}
+
+/// The number of bins in `cos(theta)` used by [`chi2_sampler_test`]
+const CHI2_COS_BINS: usize = 8;
+
+/// The number of bins in azimuth used by [`chi2_sampler_test`]
+const CHI2_AZIMUTH_BINS: usize = 16;
+
+/// The resolution used for integrating the density within each bin
+const CHI2_SUBDIVISIONS: usize = 16;
+
+/// The quantile of the standard normal distribution at the significance
+/// level of the test (i.e., 0.1%)
+const CHI2_Z: Float = 3.09;
+
+/// Checks that the directions drawn by a sampler follow the density it claims.
+///
+/// `n_samples` directions (a few thousand are enough) are binned over the
+/// hemisphere and compared with the integral of `pdf` over each bin through
+/// Pearson's chi-squared test, at a significance of 0.1%. The test also fails if
+/// the density does not integrate to one (e.g., because the cosine was
+/// counted twice) or if directions are drawn where the density is zero.
+pub fn chi2_sampler_test<S: HemisphereSampler + ?Sized>(
+    sampler: &S,
+    n_samples: usize,
+    seed: u64,
+) -> Result<Chi2Report, String> {
+    let frame = LocalFrame::new(sampler.normal())?;
+    let n_bins = CHI2_COS_BINS * CHI2_AZIMUTH_BINS;
+    let d_cos = 1. / CHI2_COS_BINS as Float;
+    let d_azimuth = 2. * PI / CHI2_AZIMUTH_BINS as Float;
+    let bin_of = |dir: Vector3D| -> Option<usize> {
+        let d = dir.get_normalized();
+        let cos = d * frame.normal;
+        if cos <= 0.0 {
+            return None;
+        }
+        let mut azimuth = (d * frame.side).atan2(d * frame.reference);
+        if azimuth < 0.0 {
+            azimuth += 2. * PI;
+        }
+        let i = ((cos / d_cos) as usize).min(CHI2_COS_BINS - 1);
+        let j = ((azimuth / d_azimuth) as usize).min(CHI2_AZIMUTH_BINS - 1);
+        Some(i * CHI2_AZIMUTH_BINS + j)
+    };
+
+    // Expected probabilities, integrating the density over each bin
+    let mut expected = vec![0.0; n_bins];
+    let sub = CHI2_SUBDIVISIONS as Float;
+    for (k, e) in expected.iter_mut().enumerate() {
+        let (i, j) = (k / CHI2_AZIMUTH_BINS, k % CHI2_AZIMUTH_BINS);
+        for a in 0..CHI2_SUBDIVISIONS {
+            let cos = (i as Float + (a as Float + 0.5) / sub) * d_cos;
+            let sin = (1. - cos * cos).max(0.0).sqrt();
+            for b in 0..CHI2_SUBDIVISIONS {
+                let azimuth = (j as Float + (b as Float + 0.5) / sub) * d_azimuth;
+                let dir = frame.direction(sin, cos, azimuth);
+                *e += sampler.pdf(dir) * d_cos * d_azimuth / (sub * sub);
+            }
+        }
+    }
+    let pdf_integral: Float = expected.iter().sum();
+    if (pdf_integral - 1.).abs() > 0.05 {
+        return Err(format!(
+            "The density of the sampler integrates to {} over the hemisphere, not 1",
+            pdf_integral
+        ));
+    }
+
+    // Observed counts
+    let mut rng = SensorRng::new(seed, 0);
+    let mut observed = vec![0usize; n_bins];
+    let mut impossible = 0;
+    for _ in 0..n_samples {
+        let dir = sampler.sample_direction(rng.gen(), rng.gen());
+        if sampler.pdf(dir) <= 0.0 {
+            impossible += 1;
+            continue;
+        }
+        match bin_of(dir) {
+            Some(k) => observed[k] += 1,
+            None => impossible += 1,
+        }
+    }
+    if impossible as Float > 1e-3 * n_samples as Float {
+        return Err(format!(
+            "The sampler drew {} of {} directions outside of the hemisphere or where its density is zero",
+            impossible, n_samples
+        ));
+    }
+
+    // Pool the bins with few expected samples, as usual for Pearson's test
+    let n = n_samples as Float;
+    let mut statistic = 0.0;
+    let mut n_categories = 0;
+    let (mut pooled_expected, mut pooled_observed) = (0.0, 0.0);
+    for (e, o) in expected.iter().zip(observed.iter()) {
+        let e = e * n;
+        if e < 5. {
+            pooled_expected += e;
+            pooled_observed += *o as Float;
+            continue;
+        }
+        statistic += (*o as Float - e).powi(2) / e;
+        n_categories += 1;
+    }
+    if pooled_expected >= 5. {
+        statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+        n_categories += 1;
+    } else if pooled_observed > 5. + 3. * pooled_expected {
+        return Err(format!(
+            "The sampler drew {} directions where about {:.1} were expected",
+            pooled_observed, pooled_expected
+        ));
+    }
+    if n_categories < 2 {
+        return Err(format!(
+            "{} samples are not enough for testing the sampler",
+            n_samples
+        ));
+    }
+
+    // Wilson-Hilferty approximation of the quantile of the chi-squared distribution
+    let dof = n_categories - 1;
+    let k = dof as Float;
+    let critical_value = k * (1. - 2. / (9. * k) + CHI2_Z * (2. / (9. * k)).sqrt()).powi(3);
+    if statistic > critical_value {
+        return Err(format!(
+            "The directions drawn by the sampler do not follow its density (chi-squared = {:.1}, with {} degrees of freedom; the limit is {:.1})",
+            statistic, dof, critical_value
+        ));
+    }
+    Ok(Chi2Report {
+        statistic,
+        degrees_of_freedom: dof,
+        critical_value,
+        pdf_integral,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::sensor::{AngularMask, DirectionSampler};
+    use validate::assert_close;
+
+    /// Draws uniformly over the hemisphere, but claims whatever
+    /// density `claim` says
+    struct UniformSampler {
+        frame: LocalFrame,
+        claim: Claim,
+    }
+
+    enum Claim {
+        Uniform,
+        Cosine,
+        DoubleCosine,
+    }
+
+    impl HemisphereSampler for UniformSampler {
+        fn normal(&self) -> Vector3D {
+            self.frame.normal
+        }
+        fn sample_direction(&self, u1: Float, u2: Float) -> Vector3D {
+            let cos = u1;
+            let sin = (1. - cos * cos).sqrt();
+            self.frame.direction(sin, cos, 2. * PI * u2)
+        }
+        fn pdf(&self, direction: Vector3D) -> Float {
+            let cos = direction.get_normalized() * self.frame.normal;
+            if cos <= 0.0 {
+                return 0.0;
+            }
+            match self.claim {
+                Claim::Uniform => 0.5 / PI,
+                Claim::Cosine => cos / PI,
+                Claim::DoubleCosine => cos * cos / PI,
+            }
+        }
+    }
+
+    fn uniform(claim: Claim) -> UniformSampler {
+        UniformSampler {
+            frame: LocalFrame::new(Vector3D::new(0.3, -0.2, 0.9)).unwrap(),
+            claim,
+        }
+    }
+
+    #[test]
+    fn test_chi2_direction_sampler() {
+        let normal = Vector3D::new(0., -1., 0.);
+        let sampler = DirectionSampler::new(normal, None).unwrap();
+        let report = chi2_sampler_test(&sampler, 5000, 1).unwrap();
+        assert_close!(report.pdf_integral, 1., 1e-3);
+        assert!(report.statistic < report.critical_value);
+
+        let mask = AngularMask::range(20., 60., 270., 90.).unwrap();
+        let sampler = DirectionSampler::new(normal, Some(&mask)).unwrap();
+        chi2_sampler_test(&sampler, 5000, 2).unwrap();
+    }
+
+    #[test]
+    fn test_chi2_catches_wrong_density() {
+        chi2_sampler_test(&uniform(Claim::Uniform), 5000, 3).unwrap();
+        // Normalised, but not what is drawn
+        let err = chi2_sampler_test(&uniform(Claim::Cosine), 5000, 4).unwrap_err();
+        assert!(err.contains("chi-squared"), "{}", err);
+        // The cosine counted twice: not even normalised
+        let err = chi2_sampler_test(&uniform(Claim::DoubleCosine), 5000, 5).unwrap_err();
+        assert!(err.contains("integrates"), "{}", err);
+    }
+
+    #[test]
+    fn test_sample_weight() {
+        // Uniform and cosine-weighted sampling estimate the same irradiance
+        // under a uniform sky of unit radiance (i.e., PI)
+        let n = 20000;
+        let mut rng = SensorRng::new(0, 0);
+        let sampler = uniform(Claim::Uniform);
+        let mut acc = 0.0;
+        for _ in 0..n {
+            let dir = sampler.sample_direction(rng.gen(), rng.gen());
+            acc += sample_weight(&sampler, dir);
+        }
+        assert_close!(acc / n as Float, PI, 0.05);
+
+        let sampler = DirectionSampler::new(sampler.normal(), None).unwrap();
+        for _ in 0..100 {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            assert_close!(weight, PI, 1e-3);
+            assert_close!(sample_weight(&sampler, dir), PI, 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_draw_checked() {
+        let up = Vector3D::new(0., 0., 1.);
+        assert_close!(checked_direction(up * 1.0005).unwrap().length(), 1.0, 1e-9);
+        assert!(checked_direction(up * 1e-30).is_none());
+        assert!(checked_direction(Vector3D::new(Float::NAN, 0., 1.)).is_none());
+        assert!(checked_direction(Vector3D::new(Float::INFINITY, 0., 0.)).is_none());
+
+        // A good draw takes the numbers it was given, nothing else
+        let mut rng = SensorRng::new(1, 2);
+        let (_, u) = draw_checked((0.25, 0.5), &mut rng, 4, |u1, u2| Some((up, (u1, u2)))).unwrap();
+        assert_eq!(u, (0.25, 0.5));
+        assert_eq!(rng.gen(), SensorRng::new(1, 2).gen());
+
+        // Broken draws are drawn again...
+        let mut attempts = 0;
+        let drawn = draw_checked((0.25, 0.5), &mut rng, 4, |_, _| {
+            attempts += 1;
+            let d = if attempts < 3 { up * 0.0 } else { up };
+            Some((d, attempts))
+        });
+        assert_eq!(drawn.unwrap().1, 3);
+
+        // ... up to a point
+        let mut attempts = 0;
+        let drawn = draw_checked((0.25, 0.5), &mut rng, 4, |_, _| {
+            attempts += 1;
+            Some((up * Float::NAN, ()))
+        });
+        assert!(drawn.is_none());
+        assert_eq!(attempts, 5);
+        let drawn = draw_checked((0.25, 0.5), &mut rng, 0, |_, _| None::<(Vector3D, ())>);
+        assert!(drawn.is_none());
+    }
+}
@@ -0,0 +1,606 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Builds scenes programmatically, as an alternative to writing Radiance
+//! files by hand.
+
+use crate::scene_loading::{load_scene, SceneReport};
+use crate::Float;
+use geometry3d::Point3D;
+use rendering::Scene;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A material that can be assigned to the surfaces of a [`SceneBuilder`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Material {
+    /// A dielectric material with a coloured diffuse reflectance, like paint or
+    /// concrete, as Radiance's `plastic`
+    Plastic {
+        /// The diffuse reflectance of each channel
+        rgb: [Float; 3],
+        /// The fraction of the light that is reflected specularly
+        specularity: Float,
+        /// The roughness of the surface (0 is a perfect mirror)
+        roughness: Float,
+    },
+    /// A metallic material, whose specular reflections take
+    /// its colour, as Radiance's `metal`
+    Metal {
+        /// The reflectance of each channel
+        rgb: [Float; 3],
+        /// The fraction of the light that is reflected specularly
+        specularity: Float,
+        /// The roughness of the surface (0 is a perfect mirror)
+        roughness: Float,
+    },
+    /// A thin glazing, as Radiance's `glass`
+    Glass {
+        /// The transmissivity of each channel, as defined by Radiance (i.e.,
+        /// about 1.09 times the normal transmittance)
+        transmissivity: [Float; 3],
+    },
+    /// A semi-transparent material that scatters the light it transmits, like a
+    /// fabric shade or frosted glass, as Radiance's `trans`
+    Trans {
+        /// The colour of each channel, which multiplies both what is
+        /// reflected and what is transmitted
+        rgb: [Float; 3],
+        /// The fraction of the light that is reflected specularly
+        specularity: Float,
+        /// The roughness of the surface (0 is a perfect mirror)
+        roughness: Float,
+        /// The fraction of what is not reflected specularly that is transmitted
+        transmissivity: Float,
+        /// The fraction of what is transmitted that goes straight through
+        transmitted_specularity: Float,
+    },
+}
+
+impl Material {
+    /// A grey, perfectly diffuse `plastic` with a certain `reflectance`
+    pub fn plastic(reflectance: Float) -> Self {
+        Self::Plastic {
+            rgb: [reflectance; 3],
+            specularity: 0.0,
+            roughness: 0.0,
+        }
+    }
+
+    /// A grey `metal` with a certain `reflectance`, `specularity` and `roughness`
+    pub fn metal(reflectance: Float, specularity: Float, roughness: Float) -> Self {
+        Self::Metal {
+            rgb: [reflectance; 3],
+            specularity,
+            roughness,
+        }
+    }
+
+    /// A clear `glass` with a normal `transmittance`, which is converted into the
+    /// transmissivity that Radiance expects
+    pub fn glass(transmittance: Float) -> Self {
+        Self::Glass {
+            transmissivity: [transmittance_to_transmissivity(transmittance); 3],
+        }
+    }
+
+    /// A grey `trans` that reflects a fraction `reflectance` of the light and
+    /// transmits a fraction `transmittance`, both of them diffusely
+    pub fn trans(reflectance: Float, transmittance: Float) -> Self {
+        let colour = reflectance + transmittance;
+        Self::Trans {
+            rgb: [colour; 3],
+            specularity: 0.0,
+            roughness: 0.0,
+            transmissivity: if colour > 0.0 {
+                transmittance / colour
+            } else {
+                0.0
+            },
+            transmitted_specularity: 0.0,
+        }
+    }
+
+    /// The Radiance type of the material
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Plastic { .. } => "plastic",
+            Self::Metal { .. } => "metal",
+            Self::Glass { .. } => "glass",
+            Self::Trans { .. } => "trans",
+        }
+    }
+
+    /// Checks that the arguments make physical sense
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        let (values, extra) = match self {
+            Self::Plastic {
+                rgb,
+                specularity,
+                roughness,
+            }
+            | Self::Metal {
+                rgb,
+                specularity,
+                roughness,
+            } => (rgb, vec![*specularity, *roughness]),
+            Self::Glass { transmissivity } => (transmissivity, vec![]),
+            Self::Trans {
+                rgb,
+                specularity,
+                roughness,
+                transmissivity,
+                transmitted_specularity,
+            } => (
+                rgb,
+                vec![
+                    *specularity,
+                    *roughness,
+                    *transmissivity,
+                    *transmitted_specularity,
+                ],
+            ),
+        };
+        // Transmissivities of clear glass are slightly above 1
+        let max = if let Self::Glass { .. } = self {
+            transmittance_to_transmissivity(1.)
+        } else {
+            1.
+        };
+        if values.iter().any(|v| !(0.0..=max).contains(v)) {
+            return Err(format!(
+                "The values of a {} must be between 0 and {}, but found {:?}",
+                self.kind(),
+                max,
+                values
+            ));
+        }
+        if extra.iter().any(|v| !(0.0..=1.0).contains(v)) {
+            return Err(format!(
+                "The specularity, roughness and other fractions of a {} must be between 0 and 1, but found {:?}",
+                self.kind(),
+                extra
+            ));
+        }
+        Ok(())
+    }
+
+    /// The Radiance definition of the material
+    fn to_radiance(self, name: &str) -> String {
+        let reals: Vec<Float> = match self {
+            Self::Plastic {
+                rgb,
+                specularity,
+                roughness,
+            }
+            | Self::Metal {
+                rgb,
+                specularity,
+                roughness,
+            } => vec![rgb[0], rgb[1], rgb[2], specularity, roughness],
+            Self::Glass { transmissivity } => transmissivity.to_vec(),
+            Self::Trans {
+                rgb,
+                specularity,
+                roughness,
+                transmissivity,
+                transmitted_specularity,
+            } => vec![
+                rgb[0],
+                rgb[1],
+                rgb[2],
+                specularity,
+                roughness,
+                transmissivity,
+                transmitted_specularity,
+            ],
+        };
+        let reals: Vec<String> = reals.iter().map(|v| format!("{}", v)).collect();
+        format!(
+            "void {} {}\n0\n0\n{} {}\n\n",
+            self.kind(),
+            name,
+            reals.len(),
+            reals.join(" ")
+        )
+    }
+}
+
+/// Converts the normal transmittance of a glazing with a refraction index of 1.52
+/// into the transmissivity used by Radiance's `glass` (the formula of its reference manual)
+fn transmittance_to_transmissivity(tn: Float) -> Float {
+    if tn <= 0.0 {
+        return 0.0;
+    }
+    ((0.8402528435 + 0.0072522239 * tn * tn).sqrt() - 0.9166530661) / 0.0036261119 / tn
+}
+
+/// The inverse of [`transmittance_to_transmissivity`]: the normal transmittance
+/// of a Radiance `glass` with a transmissivity `tau`, accounting for the
+/// reflections between its two faces
+pub(crate) fn transmissivity_to_transmittance(tau: Float) -> Float {
+    // The reflectance of each face at normal incidence, for an index of 1.52
+    let r: Float = (0.52 / 2.52) * (0.52 / 2.52);
+    tau * (1. - r) * (1. - r) / (1. - r * r * tau * tau)
+}
+
+/// The transmittance and reflectance of a Radiance `glass` with a transmissivity
+/// `tau`, for light arriving at an angle whose cosine is `cos_incidence` (the
+/// formula of its reference manual): the Fresnel reflection of each polarisation
+/// at both faces, the absorption along the refracted path and the reflections
+/// between the faces. At normal incidence, the transmittance is that of
+/// [`transmissivity_to_transmittance`].
+///
+/// Transmissivities above `1` are not physical (i.e., they would transmit more
+/// than a lossless pane), so they are taken as `1`.
+pub(crate) fn glass_at_incidence(tau: Float, cos_incidence: Float) -> (Float, Float) {
+    let n: Float = 1.52;
+    let c1 = cos_incidence.clamp(0.0, 1.0);
+    if c1 <= 0.0 {
+        return (0.0, 1.0);
+    }
+    // The cosine of the refracted direction
+    let c2 = (1. - (1. - c1 * c1) / (n * n)).sqrt();
+    // A path can only lose light
+    let path = tau.powf(1. / c2).min(1.);
+    let perpendicular = ((c1 - n * c2) / (c1 + n * c2)).powi(2);
+    let parallel = ((c2 - n * c1) / (c2 + n * c1)).powi(2);
+    let (mut t, mut r) = (0.0, 0.0);
+    for face in [perpendicular, parallel] {
+        let inter_reflected = 1. - face * face * path * path;
+        t += 0.5 * (1. - face) * (1. - face) * path / inter_reflected;
+        r += 0.5 * face * (1. + (1. - 2. * face) * path * path) / inter_reflected;
+    }
+    (t, r)
+}
+
+/// Builds a scene out of materials and polygons, producing
+/// the same results as loading the equivalent Radiance file.
+///
+/// ```ignore
+/// let mut builder = SceneBuilder::new();
+/// builder.add_material("floor_mat", Material::plastic(0.2))?;
+/// builder.add_polygon(
+///     "floor_mat",
+///     "floor",
+///     &[
+///         Point3D::new(0., 0., 0.),
+///         Point3D::new(4., 0., 0.),
+///         Point3D::new(4., 4., 0.),
+///         Point3D::new(0., 4., 0.),
+///     ],
+/// )?;
+/// let (scene, report) = builder.build()?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SceneBuilder {
+    content: String,
+    materials: HashSet<String>,
+    surfaces: HashSet<String>,
+    /// The two-sided materials that were written, by their front and back materials
+    two_sided: HashMap<(String, String), String>,
+}
+
+impl SceneBuilder {
+    /// An empty scene
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a material called `name`, which can then be used by
+    /// the surfaces. Fails if it was already added or if it is not valid.
+    pub fn add_material(&mut self, name: &str, material: Material) -> Result<(), String> {
+        check_name(name)?;
+        material.validate()?;
+        if !self.materials.insert(name.to_string()) {
+            return Err(format!("Material '{}' was already added", name));
+        }
+        self.content += &material.to_radiance(name);
+        Ok(())
+    }
+
+    /// Adds a polygon made of `modifier`, whose normal follows the order of its
+    /// `vertices` (i.e., the right-hand rule). Fails if the material does not
+    /// exist, if the name is repeated, or if there are fewer than three vertices.
+    pub fn add_polygon(
+        &mut self,
+        modifier: &str,
+        name: &str,
+        vertices: &[Point3D],
+    ) -> Result<(), String> {
+        self.add_surface(name, modifier, modifier, vertices)
+    }
+
+    /// Adds a polygon with a material on each side: `front` on the side its
+    /// normal points to (which follows the order of its `vertices`, as
+    /// for [`SceneBuilder::add_polygon`]) and `back` on the other. Surfaces
+    /// with different materials are written as a `mixfunc` of both (see
+    /// [`crate::scene_loading`]).
+    pub fn add_surface(
+        &mut self,
+        name: &str,
+        front: &str,
+        back: &str,
+        vertices: &[Point3D],
+    ) -> Result<(), String> {
+        check_name(name)?;
+        for modifier in [front, back] {
+            if !self.materials.contains(modifier) {
+                return Err(format!(
+                    "Polygon '{}' uses material '{}', which has not been added",
+                    name, modifier
+                ));
+            }
+        }
+        if vertices.len() < 3 {
+            return Err(format!(
+                "Polygon '{}' needs at least 3 vertices, but {} were given",
+                name,
+                vertices.len()
+            ));
+        }
+        if self.surfaces.contains(name) {
+            return Err(format!("Surface '{}' was already added", name));
+        }
+        let modifier = if front == back {
+            front.to_string()
+        } else {
+            self.two_sided_material(front, back)?
+        };
+        self.surfaces.insert(name.to_string());
+        self.content += &format!(
+            "{} polygon {}\n0\n0\n{}\n",
+            modifier,
+            name,
+            3 * vertices.len()
+        );
+        for v in vertices {
+            self.content += &format!("    {} {} {}\n", v.x, v.y, v.z);
+        }
+        self.content += "\n";
+        Ok(())
+    }
+
+    /// The name of the material that is `front` on one side and `back` on
+    /// the other, which is written the first time it is needed
+    fn two_sided_material(&mut self, front: &str, back: &str) -> Result<String, String> {
+        let key = (front.to_string(), back.to_string());
+        if let Some(name) = self.two_sided.get(&key) {
+            return Ok(name.clone());
+        }
+        let name = format!("{}_{}", front, back);
+        if !self.materials.insert(name.clone()) {
+            return Err(format!(
+                "The two-sided material of '{}' and '{}' would be called '{}', but there is already a material with that name",
+                front, back, name
+            ));
+        }
+        self.content += &format!(
+            "void mixfunc {}\n4 {} {} if(Rdot,1,0) .\n0\n0\n\n",
+            name, front, back
+        );
+        self.two_sided.insert(key, name.clone());
+        Ok(name)
+    }
+
+    /// The scene, as the content of a Radiance file
+    pub fn to_radiance(&self) -> String {
+        self.content.clone()
+    }
+
+    /// Builds the scene (with its accelerator) and the report of its contents,
+    /// as [`load_scene`] does
+    pub fn build(&self) -> Result<(Scene, SceneReport), String> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "light_scene_builder_{}_{}.rad",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, &self.content)
+            .map_err(|e| format!("Unable to write the scene: {}", e))?;
+        let loaded = load_scene(&path);
+        let _ = std::fs::remove_file(&path);
+        let (mut scene, report) = loaded?;
+        scene.build_accelerator();
+        Ok((scene, report))
+    }
+}
+
+/// Names are written into Radiance files, so they cannot be empty or
+/// contain whitespace
+pub(crate) fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!(
+            "Names cannot be empty or contain whitespace, but found '{}'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    fn square(z: Float) -> Vec<Point3D> {
+        vec![
+            Point3D::new(0., 0., z),
+            Point3D::new(1., 0., z),
+            Point3D::new(1., 1., z),
+            Point3D::new(0., 1., z),
+        ]
+    }
+
+    #[test]
+    fn test_materials() {
+        assert_eq!(
+            Material::plastic(0.3).to_radiance("paint"),
+            "void plastic paint\n0\n0\n5 0.3 0.3 0.3 0 0\n\n"
+        );
+        assert_eq!(Material::metal(0.8, 0.9, 0.05).kind(), "metal");
+
+        // The usual 0.96 of Radiance is a transmittance of 0.88
+        match Material::glass(0.88) {
+            Material::Glass { transmissivity } => {
+                assert_close!(transmissivity[0], 0.96, 2e-3)
+            }
+            _ => panic!("Expecting glass"),
+        }
+        assert!(Material::glass(1.).validate().is_ok());
+        for tn in [0.1, 0.5, 0.88] {
+            let tau = transmittance_to_transmissivity(tn);
+            assert_close!(transmissivity_to_transmittance(tau), tn, 1e-4);
+        }
+        assert!(Material::plastic(1.2).validate().is_err());
+        assert!(Material::metal(0.5, 1.5, 0.).validate().is_err());
+
+        // Radiance computes the diffuse transmittance as colour times transmissivity
+        match Material::trans(0.2, 0.4) {
+            Material::Trans {
+                rgb,
+                transmissivity,
+                ..
+            } => assert_close!(rgb[0] * transmissivity, 0.4, 1e-6),
+            _ => panic!("Expecting trans"),
+        }
+        assert!(Material::trans(0.2, 0.4).validate().is_ok());
+        assert!(Material::trans(0.7, 0.4).validate().is_err());
+    }
+
+    #[test]
+    fn test_glass_at_incidence() {
+        let tau = transmittance_to_transmissivity(0.8);
+        let (t, r) = glass_at_incidence(tau, 1.);
+        assert_close!(t, 0.8, 1e-4);
+        assert_close!(r, 0.0723, 1e-3);
+
+        // The Fresnel equations in terms of the angles, by Snell's law
+        let theta1: Float = (80.0 as Float).to_radians();
+        let theta2 = (theta1.sin() / 1.52).asin();
+        let path = tau.powf(1. / theta2.cos());
+        let perpendicular = ((theta1 - theta2).sin() / (theta1 + theta2).sin()).powi(2);
+        let parallel = ((theta1 - theta2).tan() / (theta1 + theta2).tan()).powi(2);
+        let expected: Float = [perpendicular, parallel]
+            .iter()
+            .map(|f| 0.5 * (1. - f) * (1. - f) * path / (1. - f * f * path * path))
+            .sum();
+        let (t, _) = glass_at_incidence(tau, theta1.cos());
+        assert_close!(t, expected, 1e-4);
+        // Less than half of what goes through at normal incidence
+        assert_close!(t, 0.362, 1e-3);
+        let (lossless, _) = glass_at_incidence(transmittance_to_transmissivity(1.), 1.);
+        // i.e., 2n / (n² + 1)
+        assert_close!(lossless, 0.9183, 1e-3);
+
+        // Energy is never created, and grazing light is reflected
+        for tn in [0.1, 0.5, 0.88, 0.91, 1.] {
+            let tau = transmittance_to_transmissivity(tn);
+            for i in 0..=100 {
+                let (t, r) = glass_at_incidence(tau, i as Float / 100.);
+                assert!(t >= 0.0 && r >= 0.0);
+                assert!(t + r <= 1. + 1e-6, "tn = {tn}, cos = {}", i as Float / 100.);
+            }
+            let (t, r) = glass_at_incidence(tau, 0.0);
+            assert_close!(t, 0.0, 1e-6);
+            assert_close!(r, 1.0, 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("floor_mat", Material::plastic(0.2))
+            .unwrap();
+        builder
+            .add_material("window_mat", Material::glass(0.8))
+            .unwrap();
+        builder
+            .add_polygon("floor_mat", "floor", &square(0.))
+            .unwrap();
+        builder
+            .add_polygon("window_mat", "skylight", &square(3.))
+            .unwrap();
+
+        let (_, report) = builder.build().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.n_surfaces, 2);
+        assert_eq!(report.surfaces[1].name, "skylight");
+        assert_eq!(report.materials.len(), 2);
+        assert_eq!(report.materials[0].name, "floor_mat");
+        assert_close!(report.materials[0].rgb[1], 0.2, 1e-6);
+        assert_eq!(report.materials[1].kind, "glass");
+
+        // Mistakes are caught before writing anything
+        assert!(builder
+            .add_material("floor_mat", Material::plastic(0.5))
+            .is_err());
+        assert!(builder
+            .add_material("bad name", Material::plastic(0.5))
+            .is_err());
+        assert!(builder
+            .add_polygon("floor_mat", "floor", &square(1.))
+            .is_err());
+        assert!(builder
+            .add_polygon("undefined", "other", &square(1.))
+            .is_err());
+        assert!(builder
+            .add_polygon("floor_mat", "line", &square(1.)[..2])
+            .is_err());
+        assert_eq!(builder.to_radiance().matches("polygon").count(), 2);
+    }
+
+    #[test]
+    fn test_two_sided() {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("white", Material::plastic(0.8))
+            .unwrap();
+        builder
+            .add_material("brick", Material::plastic(0.3))
+            .unwrap();
+        builder
+            .add_surface("ceiling", "brick", "white", &square(3.))
+            .unwrap();
+        builder
+            .add_surface("floor", "white", "white", &square(0.))
+            .unwrap();
+        builder
+            .add_surface("shelf", "brick", "white", &square(1.))
+            .unwrap();
+        assert!(builder
+            .add_surface("other", "brick", "undefined", &square(2.))
+            .is_err());
+        // Both two-sided surfaces share the same mixture
+        assert_eq!(builder.to_radiance().matches("mixfunc").count(), 1);
+
+        let (_, report) = builder.build().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.materials.len(), 2);
+        let ceiling = &report.surfaces[0];
+        assert_eq!(ceiling.modifier, "brick");
+        assert_eq!(ceiling.back_modifier, "white");
+        assert!(!report.surfaces[1].is_two_sided());
+        assert!(report.surfaces[2].is_two_sided());
+    }
+}
@@ -0,0 +1,1582 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Loads Radiance scenes while keeping track of everything that
+//! `Scene::from_radiance` would otherwise silently ignore.
+//!
+//! The files are pre-parsed here, so that unsupported primitives and
+//! bad modifier references can be reported. `!xform` commands are expanded
+//! (i.e., the scenes are flattened) before handing the result over to
+//! the `rendering` crate.
+//!
+//! Surfaces with a different material on each side are written as Radiance
+//! usually does, through a `mixfunc` that picks one or the other depending on the
+//! side that is hit:
+//!
+//! ```text
+//! void mixfunc white_brick
+//! 4 white brick if(Rdot,1,0) .
+//! 0
+//! 0
+//! ```
+//!
+//! `rendering` only sees the front material of these, but the simplified tracer
+//! of [`DCSession::calc_reflectance_tallies`](crate::DCSession::calc_reflectance_tallies)
+//! uses each side's.
+
+use crate::coplanar::{find_overlaps, CoplanarFix, CoplanarOptions, CoplanarOverlap, CoplanarPolicy};
+use crate::importance::BoundingSphere;
+use crate::specular::{PlanarReflector, SpecularMaterial, DEFAULT_GLASS_INDEX};
+use crate::transform::Transform;
+use crate::units::{detect_unit, LengthUnit};
+use crate::Float;
+use geometry3d::{Point3D, Vector3D};
+use rendering::Scene;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The materials that `rendering` knows how to read
+const SUPPORTED_MATERIALS: [&str; 6] =
+    ["plastic", "metal", "light", "glass", "mirror", "dielectric"];
+
+/// The materials that `rendering` does not know how to read, but which are
+/// handed over to it as an equivalent `plastic` (see [`Primitive::to_radiance`])
+/// and traced properly by the simplified tracer of this crate
+const STAND_IN_MATERIALS: [&str; 1] = ["trans"];
+
+/// The surfaces that `rendering` knows how to read
+const SUPPORTED_SURFACES: [&str; 2] = ["polygon", "sphere"];
+
+/// The maximum number of nested `!xform` commands. This is just
+/// to avoid looping forever when a file includes itself.
+const MAX_INCLUSION_DEPTH: usize = 32;
+
+/// A primitive that was found in a file but not loaded into the scene
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoredPrimitive {
+    /// The file where the primitive was found
+    pub file: String,
+    /// The type of primitive (e.g., `cone` or `brightfunc`)
+    pub kind: String,
+    /// The identifier of the primitive
+    pub name: String,
+}
+
+/// The side of a polygon: its front is the one its normal points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SurfaceSide {
+    /// The side the normal points to
+    Front,
+    /// The side opposite to the normal
+    Back,
+}
+
+impl SurfaceSide {
+    /// The side of a polygon with a certain `normal` that is hit by a ray going
+    /// in a certain `direction`
+    pub fn hit_by(direction: Vector3D, normal: Vector3D) -> Self {
+        if normal * direction > 0.0 {
+            Self::Back
+        } else {
+            Self::Front
+        }
+    }
+}
+
+/// The extent of a surface that was loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurfaceBounds {
+    /// The identifier of the surface
+    pub name: String,
+    /// The modifier (i.e., the material) of the front of the surface
+    pub modifier: String,
+    /// The modifier of the back of the surface, which is the same as
+    /// [`SurfaceBounds::modifier`] unless the surface is two-sided (see
+    /// the [module documentation](self))
+    pub back_modifier: String,
+    /// The minimum corner of the box containing the surface
+    pub min: Point3D,
+    /// The maximum corner of the box containing the surface
+    pub max: Point3D,
+    /// The number of vertices of polygons, which are split into that many
+    /// triangles minus two. Spheres have none.
+    pub n_vertices: usize,
+    /// The normal of polygons, following the order of their vertices
+    pub normal: Option<Vector3D>,
+    /// The vertices of polygons, in order. Spheres have none.
+    pub vertices: Vec<Point3D>,
+}
+
+impl SurfaceBounds {
+    /// The modifier of one `side` of the surface
+    pub fn modifier_of(&self, side: SurfaceSide) -> &str {
+        match side {
+            SurfaceSide::Front => &self.modifier,
+            SurfaceSide::Back => &self.back_modifier,
+        }
+    }
+
+    /// Whether the surface has a different modifier on each side
+    pub fn is_two_sided(&self) -> bool {
+        self.modifier != self.back_modifier
+    }
+}
+
+/// A material that was loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialInfo {
+    /// The identifier of the material
+    pub name: String,
+    /// The type of material (e.g., `plastic`)
+    pub kind: String,
+    /// The first three real arguments of the material (i.e., its reflectance for
+    /// `plastic`, `metal` and `mirror`, its transmissivity for `glass` and
+    /// `dielectric`, and its radiance for `light`)
+    pub rgb: [Float; 3],
+    /// All the real arguments of the material
+    pub reals: Vec<Float>,
+}
+
+/// A base geometry that is placed several times in a scene (e.g., the same tree
+/// or building block repeated all over an urban context), each copy with
+/// its own [`Transform`]
+#[derive(Debug, Clone)]
+pub struct Instances {
+    /// The Radiance file with the base geometry and its materials. It can include
+    /// other files through `!xform`.
+    pub path: PathBuf,
+
+    /// The transformation of each copy
+    pub transforms: Vec<Transform>,
+}
+
+/// How a set of [`Instances`] was expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceReport {
+    /// The file with the base geometry
+    pub file: String,
+
+    /// The number of copies
+    pub n_instances: usize,
+
+    /// The number of surfaces of the base geometry
+    pub surfaces_per_instance: usize,
+}
+
+/// A summary of what happened while loading a scene.
+#[derive(Debug, Clone, Default)]
+pub struct SceneReport {
+    /// The files that were read, including those included through `!xform`
+    pub files: Vec<String>,
+
+    /// The primitives that were not loaded because they are not supported
+    pub ignored_primitives: Vec<IgnoredPrimitive>,
+
+    /// Commands (i.e., lines starting with `!`) that could not be expanded
+    pub ignored_commands: Vec<String>,
+
+    /// Modifiers that were used by a surface but were never defined (or were not
+    /// supported). These are replaced by a default grey plastic.
+    pub defaulted_materials: Vec<String>,
+
+    /// The number of surfaces (i.e., polygons and spheres) loaded
+    pub n_surfaces: usize,
+
+    /// The number of triangles in the resulting `Scene`
+    pub n_triangles: usize,
+
+    /// The minimum and maximum corners of the box containing all the surfaces
+    pub bounding_box: (Point3D, Point3D),
+
+    /// The units declared in the header comments of the files, if any
+    pub detected_unit: Option<LengthUnit>,
+
+    /// The units in which the files were read. The scene is converted
+    /// from these units into metres.
+    pub scene_unit: LengthUnit,
+
+    /// The instanced geometry that was expanded into the scene
+    pub instances: Vec<InstanceReport>,
+
+    /// The extent of each surface, in the order in which they were loaded
+    pub surfaces: Vec<SurfaceBounds>,
+
+    /// The materials, including the default ones that replaced undefined
+    /// modifiers, in the order in which they were loaded
+    pub materials: Vec<MaterialInfo>,
+
+    /// The pairs of coplanar polygons that overlap, if they were looked
+    /// for (see [`load_scenes_with_coplanar`])
+    pub coplanar_overlaps: Vec<CoplanarOverlap>,
+}
+
+impl SceneReport {
+    /// Checks whether anything in the scene was ignored or defaulted
+    pub fn is_clean(&self) -> bool {
+        self.ignored_primitives.is_empty()
+            && self.ignored_commands.is_empty()
+            && self.defaulted_materials.is_empty()
+    }
+
+    /// The [`BoundingSphere`] of all the surfaces whose identifier or modifier
+    /// is `id` (e.g., every polygon made of `shelf_mat`), for marking them as
+    /// important (see [`ImportanceHints`](crate::ImportanceHints)).
+    pub fn bounding_sphere(&self, id: &str) -> Result<BoundingSphere, String> {
+        let mut min = [Float::MAX; 3];
+        let mut max = [Float::MIN; 3];
+        let mut found = false;
+        for s in self
+            .surfaces
+            .iter()
+            .filter(|s| s.name == id || s.modifier == id)
+        {
+            found = true;
+            for (i, (lo, hi)) in [(s.min.x, s.max.x), (s.min.y, s.max.y), (s.min.z, s.max.z)]
+                .iter()
+                .enumerate()
+            {
+                min[i] = min[i].min(*lo);
+                max[i] = max[i].max(*hi);
+            }
+        }
+        if !found {
+            return Err(format!("No surface or modifier called '{}' was loaded", id));
+        }
+        Ok(BoundingSphere::from_box(
+            Point3D::new(min[0], min[1], min[2]),
+            Point3D::new(max[0], max[1], max[2]),
+        ))
+    }
+
+    /// The index (within [`SceneReport::surfaces`]) of the surface that each
+    /// triangle of the `Scene` belongs to. Polygons are triangulated in the order
+    /// in which they were loaded; this fails if the scene has triangles that do not
+    /// come from them (e.g., from spheres).
+    pub fn triangle_surfaces(&self) -> Result<Vec<usize>, String> {
+        let mut ret = Vec::with_capacity(self.n_triangles);
+        for (i, s) in self.surfaces.iter().enumerate() {
+            if s.n_vertices >= 3 {
+                ret.extend(std::iter::repeat_n(i, s.n_vertices - 2));
+            }
+        }
+        if ret.len() != self.n_triangles {
+            return Err(format!(
+                "The scene has {} triangles, but its polygons make up {}... are there spheres in it?",
+                self.n_triangles,
+                ret.len()
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// The [`ObjectId`] of the polygon called `name`, failing if there is no such
+    /// polygon, if there are several, or if the triangles of the scene cannot be
+    /// told apart (see [`SceneReport::triangle_surfaces`])
+    pub fn object_id(&self, name: &str) -> Result<ObjectId, String> {
+        let triangles = self.triangle_surfaces()?;
+        let mut matches = self.surfaces.iter().enumerate().filter(|(_, s)| s.name == name);
+        let (index, _) = matches
+            .next()
+            .ok_or_else(|| format!("There is no surface called '{}' in the scene", name))?;
+        if matches.next().is_some() {
+            return Err(format!("There are several surfaces called '{}'", name));
+        }
+        let start = triangles
+            .iter()
+            .position(|t| *t == index)
+            .ok_or_else(|| format!("Surface '{}' is not a polygon", name))?;
+        Ok(ObjectId {
+            name: name.to_string(),
+            triangles: start..start + self.surfaces[index].n_vertices - 2,
+            normal: self.surfaces[index].normal,
+            side: None,
+        })
+    }
+}
+
+/// Identifies a polygon of a scene by its name and the triangles of the
+/// `Scene` it was split into (see [`SceneReport::object_id`]), and optionally
+/// one of its sides
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectId {
+    /// The name of the polygon
+    pub name: String,
+    /// The triangles that make it up
+    pub triangles: Range<usize>,
+    /// The normal of the polygon, which tells its sides apart
+    pub normal: Option<Vector3D>,
+    /// The side that is meant, if only one (e.g., a sensor on the inside of a
+    /// wall ignores only what hits that side)
+    pub side: Option<SurfaceSide>,
+}
+
+impl ObjectId {
+    /// The same polygon, but only its `side`
+    pub fn with_side(mut self, side: SurfaceSide) -> Self {
+        self.side = Some(side);
+        self
+    }
+
+    /// Whether a ray going in a certain `direction` that hits a `triangle`
+    /// hits this object (and the right side of it)
+    pub(crate) fn is_hit(&self, triangle: usize, direction: Vector3D) -> bool {
+        if !self.triangles.contains(&triangle) {
+            return false;
+        }
+        match (self.side, self.normal) {
+            (Some(side), Some(normal)) => SurfaceSide::hit_by(direction, normal) == side,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for SceneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (min, max) = self.bounding_box;
+        writeln!(f, "Files read: {}", self.files.join(", "))?;
+        writeln!(
+            f,
+            "Surfaces: {} ({} triangles)",
+            self.n_surfaces, self.n_triangles
+        )?;
+        writeln!(
+            f,
+            "Bounding box: ({}, {}, {}) -> ({}, {}, {})",
+            min.x, min.y, min.z, max.x, max.y, max.z
+        )?;
+        if let Some(unit) = self.detected_unit {
+            writeln!(f, "Units declared in the files: {:?}", unit)?;
+        }
+        if self.scene_unit != LengthUnit::Metre {
+            writeln!(f, "Converted from {:?} into metres", self.scene_unit)?;
+        }
+        for i in &self.instances {
+            writeln!(
+                f,
+                "Expanded {} instances of '{}' into {} surfaces (read once)",
+                i.n_instances,
+                i.file,
+                i.n_instances * i.surfaces_per_instance
+            )?;
+        }
+        for p in &self.ignored_primitives {
+            writeln!(
+                f,
+                "Ignored unsupported primitive '{}' of type '{}' in '{}'",
+                p.name, p.kind, p.file
+            )?;
+        }
+        for c in &self.ignored_commands {
+            writeln!(f, "Ignored command '{}'", c)?;
+        }
+        for m in &self.defaulted_materials {
+            writeln!(
+                f,
+                "Modifier '{}' is not defined... using default material",
+                m
+            )?;
+        }
+        for o in &self.coplanar_overlaps {
+            write!(
+                f,
+                "Polygons '{}' and '{}' are coplanar and overlap by {}",
+                o.first, o.second, o.area
+            )?;
+            match &o.fix {
+                CoplanarFix::None => writeln!(f)?,
+                CoplanarFix::Removed(name) => writeln!(f, "... removed '{}'", name)?,
+                CoplanarFix::Offset(name, d) => writeln!(f, "... moved '{}' by {}", name, d)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single transformation applied through `!xform`
+#[derive(Debug, Clone, Copy)]
+enum XformOp {
+    Translate(Float, Float, Float),
+    Scale(Float),
+    RotateX(Float),
+    RotateY(Float),
+    RotateZ(Float),
+    MirrorX,
+    MirrorY,
+    MirrorZ,
+    General(Transform),
+}
+
+impl XformOp {
+    fn apply(&self, p: [Float; 3]) -> [Float; 3] {
+        let [x, y, z] = p;
+        match *self {
+            Self::Translate(dx, dy, dz) => [x + dx, y + dy, z + dz],
+            Self::Scale(s) => [x * s, y * s, z * s],
+            Self::RotateX(a) => {
+                let (sin, cos) = a.to_radians().sin_cos();
+                [x, y * cos - z * sin, y * sin + z * cos]
+            }
+            Self::RotateY(a) => {
+                let (sin, cos) = a.to_radians().sin_cos();
+                [x * cos + z * sin, y, -x * sin + z * cos]
+            }
+            Self::RotateZ(a) => {
+                let (sin, cos) = a.to_radians().sin_cos();
+                [x * cos - y * sin, x * sin + y * cos, z]
+            }
+            Self::MirrorX => [-x, y, z],
+            Self::MirrorY => [x, -y, z],
+            Self::MirrorZ => [x, y, -z],
+            Self::General(t) => t.transform_array(x, y, z),
+        }
+    }
+
+    fn is_mirror(&self) -> bool {
+        matches!(self, Self::MirrorX | Self::MirrorY | Self::MirrorZ)
+    }
+
+    fn scale(&self) -> Float {
+        match *self {
+            Self::Scale(s) => s,
+            Self::General(t) => t.get_scale(),
+            _ => 1.,
+        }
+    }
+}
+
+/// A primitive as written in a Radiance file
+#[derive(Debug, Clone)]
+struct Primitive {
+    modifier: String,
+    kind: String,
+    name: String,
+    strings: Vec<String>,
+    ints: Vec<String>,
+    reals: Vec<Float>,
+}
+
+impl Primitive {
+    fn is_surface(&self) -> bool {
+        SUPPORTED_SURFACES.contains(&self.kind.as_str())
+    }
+
+    fn is_material(&self) -> bool {
+        SUPPORTED_MATERIALS.contains(&self.kind.as_str())
+            || STAND_IN_MATERIALS.contains(&self.kind.as_str())
+            || self.two_sided().is_some()
+    }
+
+    /// The front and back materials of a `mixfunc` that chooses between
+    /// them depending on the side that is hit (see the
+    /// [module documentation](self)), which is all that can be done with one
+    fn two_sided(&self) -> Option<(&str, &str)> {
+        if self.kind != "mixfunc" || self.strings.len() != 4 || self.strings[3] != "." {
+            return None;
+        }
+        let (a, b) = (self.strings[0].as_str(), self.strings[1].as_str());
+        match self.strings[2].as_str() {
+            "if(Rdot,1,0)" => Some((a, b)),
+            "if(Rdot,0,1)" | "if(-Rdot,1,0)" => Some((b, a)),
+            _ => None,
+        }
+    }
+
+    /// Transforms the geometry of a surface, innermost transformation first.
+    fn transform(&mut self, ops: &[XformOp]) {
+        if ops.is_empty() {
+            return;
+        }
+        match self.kind.as_str() {
+            "polygon" => {
+                for chunk in self.reals.chunks_exact_mut(3) {
+                    let mut p = [chunk[0], chunk[1], chunk[2]];
+                    for op in ops {
+                        p = op.apply(p);
+                    }
+                    chunk.copy_from_slice(&p);
+                }
+                // Mirroring flips the orientation of the polygon
+                if ops.iter().filter(|op| op.is_mirror()).count() % 2 == 1 {
+                    let mut vertices: Vec<&[Float]> = self.reals.chunks_exact(3).collect();
+                    vertices.reverse();
+                    self.reals = vertices.concat();
+                }
+            }
+            "sphere" if self.reals.len() == 4 => {
+                let mut p = [self.reals[0], self.reals[1], self.reals[2]];
+                let mut r = self.reals[3];
+                for op in ops {
+                    p = op.apply(p);
+                    r *= op.scale();
+                }
+                self.reals = vec![p[0], p[1], p[2], r];
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the primitive for `rendering`. A `trans` becomes a `plastic` that
+    /// reflects as much as it does, so the transmitted light only reaches the
+    /// sensors traced by [`LambertianTracer`](crate::sensitivity::LambertianTracer).
+    fn to_radiance(&self) -> String {
+        let (kind, reals) = if self.kind == "trans" {
+            let arg = |i: usize| self.reals.get(i).copied().unwrap_or(0.0);
+            let opaque = 1. - arg(5);
+            (
+                "plastic",
+                vec![
+                    arg(0) * opaque,
+                    arg(1) * opaque,
+                    arg(2) * opaque,
+                    arg(3),
+                    arg(4),
+                ],
+            )
+        } else {
+            (self.kind.as_str(), self.reals.clone())
+        };
+        let reals: Vec<String> = reals.iter().map(|v| format!("{}", v)).collect();
+        format!(
+            "{} {} {}\n{} {}\n{} {}\n{} {}\n",
+            self.modifier,
+            kind,
+            self.name,
+            self.strings.len(),
+            self.strings.join(" "),
+            self.ints.len(),
+            self.ints.join(" "),
+            reals.len(),
+            reals.join(" ")
+        )
+    }
+}
+
+/// Accumulates the contents of all the files being read
+#[derive(Default)]
+struct Loader {
+    primitives: Vec<Primitive>,
+    report: SceneReport,
+}
+
+impl Loader {
+    fn read_file(&mut self, path: &Path, ops: &[XformOp], depth: usize) -> Result<(), String> {
+        if depth > MAX_INCLUSION_DEPTH {
+            return Err(format!(
+                "Too many nested !xform commands when reading '{}'... is a file including itself?",
+                path.display()
+            ));
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read Radiance file '{}': {}", path.display(), e))?;
+        let file = path.display().to_string();
+        self.report.files.push(file.clone());
+        if depth == 0 {
+            self.report.detected_unit = files_unit(self.report.detected_unit, &content, &file)?;
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut tokens: Vec<String> = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if let Some(command) = line.strip_prefix('!') {
+                self.parse_primitives(&tokens, &file, ops)?;
+                tokens.clear();
+                self.run_command(command, dir, ops, depth)?;
+                continue;
+            }
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            tokens.extend(line.split_whitespace().map(|s| s.to_string()));
+        }
+        self.parse_primitives(&tokens, &file, ops)
+    }
+
+    fn run_command(
+        &mut self,
+        command: &str,
+        dir: &Path,
+        ops: &[XformOp],
+        depth: usize,
+    ) -> Result<(), String> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        if args.first() != Some(&"xform") {
+            self.report.ignored_commands.push(format!("!{}", command));
+            return Ok(());
+        }
+
+        fn number(args: &[&str], i: usize, command: &str) -> Result<Float, String> {
+            match args.get(i).map(|v| v.parse::<Float>()) {
+                Some(Ok(v)) => Ok(v),
+                _ => Err(format!("Expecting a number in command '!{}'", command)),
+            }
+        }
+
+        let mut new_ops = Vec::new();
+        let mut files = Vec::new();
+        let mut i = 1;
+        while i < args.len() {
+            match args[i] {
+                "-t" => {
+                    new_ops.push(XformOp::Translate(
+                        number(&args, i + 1, command)?,
+                        number(&args, i + 2, command)?,
+                        number(&args, i + 3, command)?,
+                    ));
+                    i += 4;
+                }
+                "-s" => {
+                    let s = number(&args, i + 1, command)?;
+                    if s <= 0.0 {
+                        return Err(format!("Invalid scale in command '!{}'", command));
+                    }
+                    new_ops.push(XformOp::Scale(s));
+                    i += 2;
+                }
+                "-rx" => {
+                    new_ops.push(XformOp::RotateX(number(&args, i + 1, command)?));
+                    i += 2;
+                }
+                "-ry" => {
+                    new_ops.push(XformOp::RotateY(number(&args, i + 1, command)?));
+                    i += 2;
+                }
+                "-rz" => {
+                    new_ops.push(XformOp::RotateZ(number(&args, i + 1, command)?));
+                    i += 2;
+                }
+                "-mx" => {
+                    new_ops.push(XformOp::MirrorX);
+                    i += 1;
+                }
+                "-my" => {
+                    new_ops.push(XformOp::MirrorY);
+                    i += 1;
+                }
+                "-mz" => {
+                    new_ops.push(XformOp::MirrorZ);
+                    i += 1;
+                }
+                "-n" => {
+                    // Only affects names
+                    i += 2;
+                }
+                a if a.starts_with('-') => {
+                    return Err(format!(
+                        "Unsupported option '{}' in command '!{}'",
+                        a, command
+                    ));
+                }
+                f => {
+                    files.push(f);
+                    i += 1;
+                }
+            }
+        }
+
+        if files.is_empty() {
+            self.report.ignored_commands.push(format!("!{}", command));
+            return Ok(());
+        }
+        // The transformations of this command go first, then the ones of the parents
+        new_ops.extend_from_slice(ops);
+        for f in files {
+            self.read_file(&dir.join(f), &new_ops, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn parse_primitives(
+        &mut self,
+        tokens: &[String],
+        file: &str,
+        ops: &[XformOp],
+    ) -> Result<(), String> {
+        let mut i = 0;
+        let next = |i: &mut usize| -> Result<String, String> {
+            let ret = tokens
+                .get(*i)
+                .cloned()
+                .ok_or_else(|| format!("Unexpected end of file '{}'", file))?;
+            *i += 1;
+            Ok(ret)
+        };
+        let count = |i: &mut usize| -> Result<usize, String> {
+            let t = next(i)?;
+            t.parse::<usize>().map_err(|_| {
+                format!(
+                    "Expecting a number of arguments in '{}', found '{}'",
+                    file, t
+                )
+            })
+        };
+
+        while i < tokens.len() {
+            let modifier = next(&mut i)?;
+            let kind = next(&mut i)?;
+            let name = next(&mut i)?;
+            if kind == "alias" {
+                // modifier alias name reference
+                next(&mut i)?;
+                self.ignore(file, &kind, &name);
+                continue;
+            }
+            let n = count(&mut i)?;
+            let mut strings = Vec::with_capacity(n);
+            for _ in 0..n {
+                strings.push(next(&mut i)?);
+            }
+            let n = count(&mut i)?;
+            let mut ints = Vec::with_capacity(n);
+            for _ in 0..n {
+                ints.push(next(&mut i)?);
+            }
+            let n = count(&mut i)?;
+            let mut reals = Vec::with_capacity(n);
+            for _ in 0..n {
+                let t = next(&mut i)?;
+                let v = t.parse::<Float>().map_err(|_| {
+                    format!(
+                        "Expecting a real argument for '{}' in '{}', found '{}'",
+                        name, file, t
+                    )
+                })?;
+                reals.push(v);
+            }
+            let mut primitive = Primitive {
+                modifier,
+                kind,
+                name,
+                strings,
+                ints,
+                reals,
+            };
+            if primitive.is_surface() || primitive.is_material() {
+                primitive.transform(ops);
+                self.primitives.push(primitive);
+            } else {
+                self.ignore(file, &primitive.kind, &primitive.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the base geometry of a set of [`Instances`] once, and adds a copy
+    /// of its surfaces for each transformation. Each transformation is applied
+    /// before `ops`. Materials are added only once, so all copies share them.
+    fn read_instances(&mut self, instances: &Instances, ops: &[XformOp]) -> Result<(), String> {
+        let mut base = Loader::default();
+        base.read_file(&instances.path, &[], 1)?;
+        self.report.files.extend(base.report.files);
+        self.report
+            .ignored_primitives
+            .extend(base.report.ignored_primitives);
+        self.report
+            .ignored_commands
+            .extend(base.report.ignored_commands);
+
+        let (materials, surfaces): (Vec<Primitive>, Vec<Primitive>) =
+            base.primitives.into_iter().partition(|p| p.is_material());
+        self.primitives.extend(materials);
+        for transform in &instances.transforms {
+            let mut instance_ops = vec![XformOp::General(*transform)];
+            instance_ops.extend_from_slice(ops);
+            for surface in &surfaces {
+                let mut surface = surface.clone();
+                surface.transform(&instance_ops);
+                self.primitives.push(surface);
+            }
+        }
+        self.report.instances.push(InstanceReport {
+            file: instances.path.display().to_string(),
+            n_instances: instances.transforms.len(),
+            surfaces_per_instance: surfaces.len(),
+        });
+        Ok(())
+    }
+
+    fn ignore(&mut self, file: &str, kind: &str, name: &str) {
+        self.report.ignored_primitives.push(IgnoredPrimitive {
+            file: file.to_string(),
+            kind: kind.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Finds the polygons that overlap others on the same plane, reports them and
+    /// fixes them according to the `options`. This is done before flattening, so
+    /// the report only includes the surfaces that were kept.
+    fn resolve_coplanar(&mut self, options: &CoplanarOptions) -> Result<(), String> {
+        if !options.tolerance.is_finite() || options.tolerance <= 0.0 {
+            return Err(format!(
+                "The tolerance for finding coplanar surfaces should be positive... found {}",
+                options.tolerance
+            ));
+        }
+        if let CoplanarPolicy::Offset(d) = options.policy {
+            if !d.is_finite() || d <= options.tolerance {
+                return Err(format!(
+                    "Coplanar surfaces should be offset by more than the tolerance ({})... found {}",
+                    options.tolerance, d
+                ));
+            }
+        }
+
+        let polygons: Vec<Option<Vec<Point3D>>> = self
+            .primitives
+            .iter()
+            .map(|p| {
+                if p.kind == "polygon" && p.reals.len() >= 9 && p.reals.len() % 3 == 0 {
+                    Some(
+                        p.reals
+                            .chunks_exact(3)
+                            .map(|v| Point3D::new(v[0], v[1], v[2]))
+                            .collect(),
+                    )
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let overlaps = find_overlaps(&polygons, options.tolerance);
+
+        // Undefined modifiers end up as the default grey plastic
+        let mut materials: HashMap<&str, Float> = HashMap::new();
+        for p in self.primitives.iter().filter(|p| p.is_material()) {
+            let r = if p.reals.len() >= 3 {
+                p.reals[..3].iter().sum::<Float>() / 3.
+            } else {
+                0.5
+            };
+            materials.insert(&p.name, r);
+        }
+        let reflectance: Vec<Float> = self
+            .primitives
+            .iter()
+            .map(|p| *materials.get(p.modifier.as_str()).unwrap_or(&0.5))
+            .collect();
+
+        // When offsetting, each polygon gets the lowest level that none of the
+        // polygons loaded before it and overlapping it has, so that no two of them
+        // end up on the same plane
+        let mut levels = vec![0; self.primitives.len()];
+        if let CoplanarPolicy::Offset(d) = options.policy {
+            let mut partners: Vec<Vec<usize>> = vec![Vec::new(); self.primitives.len()];
+            for (i, j, _) in &overlaps {
+                partners[*j].push(*i);
+            }
+            for j in 0..self.primitives.len() {
+                if partners[j].is_empty() {
+                    continue;
+                }
+                let used: HashSet<usize> = partners[j].iter().map(|i| levels[*i]).collect();
+                levels[j] = (1..).find(|l| !used.contains(l)).unwrap_or(1);
+                if let Some(n) = polygon_normal(&self.primitives[j].reals) {
+                    let shift = n * (d * levels[j] as Float);
+                    for v in self.primitives[j].reals.chunks_exact_mut(3) {
+                        v[0] += shift.x;
+                        v[1] += shift.y;
+                        v[2] += shift.z;
+                    }
+                }
+            }
+        }
+
+        let mut removed = vec![false; self.primitives.len()];
+        let mut report = Vec::with_capacity(overlaps.len());
+        for (i, j, area) in overlaps {
+            let fix = match options.policy {
+                CoplanarPolicy::Report => CoplanarFix::None,
+                CoplanarPolicy::KeepFirst | CoplanarPolicy::KeepHighestReflectance => {
+                    if removed[i] || removed[j] {
+                        // Already solved by removing one of them
+                        CoplanarFix::None
+                    } else {
+                        let loser = if options.policy == CoplanarPolicy::KeepHighestReflectance
+                            && reflectance[j] > reflectance[i]
+                        {
+                            i
+                        } else {
+                            j
+                        };
+                        removed[loser] = true;
+                        CoplanarFix::Removed(self.primitives[loser].name.clone())
+                    }
+                }
+                CoplanarPolicy::Offset(d) => CoplanarFix::Offset(
+                    self.primitives[j].name.clone(),
+                    d * levels[j] as Float,
+                ),
+            };
+            report.push(CoplanarOverlap {
+                first: self.primitives[i].name.clone(),
+                second: self.primitives[j].name.clone(),
+                area,
+                fix,
+            });
+        }
+
+        let mut index = 0;
+        self.primitives.retain(|_| {
+            index += 1;
+            !removed[index - 1]
+        });
+        self.report.coplanar_overlaps = report;
+        Ok(())
+    }
+
+    /// Checks the modifier references and builds the flattened Radiance
+    /// description of the scene.
+    fn flatten(&mut self) -> Result<String, String> {
+        let mut defined: HashSet<String> = HashSet::new();
+        let mut defaulted: HashSet<String> = HashSet::new();
+        let mut ret = String::new();
+        let mut min = [Float::MAX; 3];
+        let mut max = [Float::MIN; 3];
+        // The front and back materials of the two-sided ones
+        let mut sides: HashMap<String, (String, String)> = HashMap::new();
+
+        for p in self.primitives.iter_mut() {
+            if let Some((front, back)) = p.two_sided() {
+                let (front, back) = (front.to_string(), back.to_string());
+                for m in [&front, &back] {
+                    if !defined.contains(m) {
+                        if defaulted.insert(m.clone()) {
+                            default_material(&mut self.report, &mut ret, m);
+                        }
+                        defined.insert(m.clone());
+                    }
+                }
+                defined.insert(p.name.clone());
+                sides.insert(p.name.clone(), (front, back));
+                continue;
+            }
+            if p.is_material() {
+                // We do not support patterns or textures, so materials are never modified
+                p.modifier = "void".to_string();
+                defined.insert(p.name.clone());
+                if p.reals.len() >= 3 {
+                    self.report.materials.push(MaterialInfo {
+                        name: p.name.clone(),
+                        kind: p.kind.clone(),
+                        rgb: [p.reals[0], p.reals[1], p.reals[2]],
+                        reals: p.reals.clone(),
+                    });
+                }
+                ret.push_str(&p.to_radiance());
+                continue;
+            }
+
+            if !defined.contains(&p.modifier) {
+                if defaulted.insert(p.modifier.clone()) {
+                    default_material(&mut self.report, &mut ret, &p.modifier);
+                }
+                defined.insert(p.modifier.clone());
+            }
+            // `rendering` only gets the front of two-sided surfaces
+            let back_modifier = match sides.get(&p.modifier) {
+                Some((front, back)) => {
+                    p.modifier = front.clone();
+                    back.clone()
+                }
+                None => p.modifier.clone(),
+            };
+
+            let mut s_min = [Float::MAX; 3];
+            let mut s_max = [Float::MIN; 3];
+            match p.kind.as_str() {
+                "polygon" => {
+                    if p.reals.len() < 9 || p.reals.len() % 3 != 0 {
+                        return Err(format!("Polygon '{}' has invalid vertices", p.name));
+                    }
+                    for v in p.reals.chunks_exact(3) {
+                        for i in 0..3 {
+                            s_min[i] = s_min[i].min(v[i]);
+                            s_max[i] = s_max[i].max(v[i]);
+                        }
+                    }
+                }
+                "sphere" => {
+                    if p.reals.len() != 4 {
+                        return Err(format!("Sphere '{}' should have 4 real arguments", p.name));
+                    }
+                    for i in 0..3 {
+                        s_min[i] = p.reals[i] - p.reals[3];
+                        s_max[i] = p.reals[i] + p.reals[3];
+                    }
+                }
+                _ => unreachable!(),
+            }
+            for i in 0..3 {
+                min[i] = min[i].min(s_min[i]);
+                max[i] = max[i].max(s_max[i]);
+            }
+            self.report.surfaces.push(SurfaceBounds {
+                name: p.name.clone(),
+                modifier: p.modifier.clone(),
+                back_modifier,
+                min: Point3D::new(s_min[0], s_min[1], s_min[2]),
+                max: Point3D::new(s_max[0], s_max[1], s_max[2]),
+                n_vertices: if p.kind == "polygon" {
+                    p.reals.len() / 3
+                } else {
+                    0
+                },
+                normal: if p.kind == "polygon" {
+                    polygon_normal(&p.reals)
+                } else {
+                    None
+                },
+                vertices: if p.kind == "polygon" {
+                    p.reals
+                        .chunks_exact(3)
+                        .map(|v| Point3D::new(v[0], v[1], v[2]))
+                        .collect()
+                } else {
+                    Vec::new()
+                },
+            });
+            self.report.n_surfaces += 1;
+            ret.push_str(&p.to_radiance());
+        }
+
+        if self.report.n_surfaces == 0 {
+            return Err(format!(
+                "No geometry found in files {}",
+                self.report.files.join(", ")
+            ));
+        }
+        self.report.bounding_box = (
+            Point3D::new(min[0], min[1], min[2]),
+            Point3D::new(max[0], max[1], max[2]),
+        );
+        Ok(ret)
+    }
+}
+
+/// Replaces a modifier that was never defined with a grey plastic
+fn default_material(report: &mut SceneReport, flat: &mut String, name: &str) {
+    report.defaulted_materials.push(name.to_string());
+    report.materials.push(MaterialInfo {
+        name: name.to_string(),
+        kind: "plastic".to_string(),
+        rgb: [0.5; 3],
+        reals: vec![0.5, 0.5, 0.5, 0., 0.],
+    });
+    flat.push_str(&format!("void plastic {}\n0\n0\n5 0.5 0.5 0.5 0 0\n", name));
+}
+
+/// The normal of a polygon given by the coordinates of its vertices, through
+/// Newell's method (which works for non-convex polygons). Returns `None` if
+/// the polygon has no area.
+fn polygon_normal(reals: &[Float]) -> Option<Vector3D> {
+    let vertices: Vec<&[Float]> = reals.chunks_exact(3).collect();
+    let mut n = Vector3D::new(0., 0., 0.);
+    for (i, a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        n += Vector3D::new(
+            (a[1] - b[1]) * (a[2] + b[2]),
+            (a[2] - b[2]) * (a[0] + b[0]),
+            (a[0] - b[0]) * (a[1] + b[1]),
+        );
+    }
+    if n.is_zero() {
+        None
+    } else {
+        Some(n.get_normalized())
+    }
+}
+
+/// Merges the units declared in a file with those of the files read before,
+/// failing if they disagree
+fn files_unit(
+    previous: Option<LengthUnit>,
+    content: &str,
+    file: &str,
+) -> Result<Option<LengthUnit>, String> {
+    match (previous, detect_unit(content)) {
+        (Some(a), Some(b)) if a != b => Err(format!(
+            "File '{}' declares its units as {:?}, but previous files use {:?}",
+            file, b, a
+        )),
+        (a, b) => Ok(b.or(a)),
+    }
+}
+
+/// Loads a Radiance file (and whatever it includes through `!xform`) into a `Scene`,
+/// reporting everything that could not be loaded.
+///
+/// Returns an error if the files cannot be read or parsed, or if they contain no
+/// geometry at all.
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<(Scene, SceneReport), String> {
+    load_scenes(&[path])
+}
+
+/// Like [`load_scene`], but loads several files into a single `Scene`
+pub fn load_scenes<P: AsRef<Path>>(paths: &[P]) -> Result<(Scene, SceneReport), String> {
+    load_scenes_transformed(paths, &Transform::identity())
+}
+
+/// Like [`load_scenes`], but applies a [`Transform`] to all the geometry (e.g., for
+/// converting a scene modelled in millimetres). The transformation is applied
+/// after any `!xform` in the files.
+pub fn load_scenes_transformed<P: AsRef<Path>>(
+    paths: &[P],
+    transform: &Transform,
+) -> Result<(Scene, SceneReport), String> {
+    let ops = [XformOp::General(*transform)];
+    let mut loader = Loader::default();
+    for path in paths {
+        loader.read_file(path.as_ref(), &ops, 0)?;
+    }
+    build_scene(loader)
+}
+
+/// Like [`load_scenes`], but also adds [`Instances`] of other files.
+///
+/// The `rendering` crate has no instancing, so every copy ends up in the `Scene`
+/// as triangles of its own (and each of them keeps the materials of the base
+/// geometry). However, the files of each set of instances are read and parsed
+/// only once, which matters when they are repeated hundreds of times.
+pub fn load_scenes_with_instances<P: AsRef<Path>>(
+    paths: &[P],
+    instances: &[Instances],
+) -> Result<(Scene, SceneReport), String> {
+    let mut loader = Loader::default();
+    for path in paths {
+        loader.read_file(path.as_ref(), &[], 0)?;
+    }
+    for i in instances {
+        loader.read_instances(i, &[])?;
+    }
+    build_scene(loader)
+}
+
+/// Like [`load_scenes`], but also looks for polygons that overlap others on the
+/// same plane (e.g., a wall and its paint layer exported as separate surfaces).
+/// Which of them is hit by a ray depends on rounding errors, so the results would
+/// change with noise. These are always reported in
+/// [`SceneReport::coplanar_overlaps`], and fixed according to the `options`.
+pub fn load_scenes_with_coplanar<P: AsRef<Path>>(
+    paths: &[P],
+    options: &CoplanarOptions,
+) -> Result<(Scene, SceneReport), String> {
+    let mut loader = Loader::default();
+    for path in paths {
+        loader.read_file(path.as_ref(), &[], 0)?;
+    }
+    loader.resolve_coplanar(options)?;
+    build_scene(loader)
+}
+
+/// Flattens everything that was read and builds the `Scene`
+fn build_scene(mut loader: Loader) -> Result<(Scene, SceneReport), String> {
+    let flat = loader.flatten()?;
+
+    // Write the flattened scene and let the rendering crate read it
+    let tmp: PathBuf = std::env::temp_dir().join(format!(
+        "simple_light_scene_{}_{}.rad",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0)
+    ));
+    std::fs::write(&tmp, flat)
+        .map_err(|e| format!("Unable to write flattened scene '{}': {}", tmp.display(), e))?;
+    let scene = Scene::from_radiance(tmp.display().to_string());
+    let _ = std::fs::remove_file(&tmp);
+
+    let mut report = loader.report;
+    report.n_triangles = scene.triangles.len();
+    Ok((scene, report))
+}
+
+/// Reads the planar specular surfaces—i.e., polygons made of `mirror` or
+/// `glass`—of the same files that make up a scene, for calculating the
+/// reflections of the sun through [`specular_sun_irradiance`](crate::specular_sun_irradiance).
+pub fn load_specular_reflectors<P: AsRef<Path>>(
+    paths: &[P],
+) -> Result<Vec<PlanarReflector>, String> {
+    let mut loader = Loader::default();
+    for path in paths {
+        loader.read_file(path.as_ref(), &[], 0)?;
+    }
+    let mut materials: HashMap<&str, SpecularMaterial> = HashMap::new();
+    let mut ret = Vec::new();
+    for p in loader.primitives.iter() {
+        match (p.kind.as_str(), p.reals.len()) {
+            ("mirror", 3) => {
+                let reflectance = p.reals.iter().sum::<Float>() / 3.;
+                materials.insert(&p.name, SpecularMaterial::Mirror { reflectance });
+            }
+            ("glass", 3) | ("glass", 4) => {
+                let refraction_index = p.reals.get(3).copied().unwrap_or(DEFAULT_GLASS_INDEX);
+                materials.insert(&p.name, SpecularMaterial::Glass { refraction_index });
+            }
+            ("mirror", _) | ("glass", _) => {
+                return Err(format!(
+                    "Material '{}' has an invalid number of real arguments",
+                    p.name
+                ))
+            }
+            ("polygon", _) => {
+                if let Some(material) = materials.get(p.modifier.as_str()) {
+                    if p.reals.len() < 9 || p.reals.len() % 3 != 0 {
+                        return Err(format!("Polygon '{}' has invalid vertices", p.name));
+                    }
+                    let vertices = p
+                        .reals
+                        .chunks_exact(3)
+                        .map(|v| Point3D::new(v[0], v[1], v[2]))
+                        .collect();
+                    ret.push(PlanarReflector::new(&p.name, vertices, *material)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+/// Like [`load_scenes`], but for files modelled in units other than metres. The
+/// geometry is converted into metres using `unit` or, if it is `None`, the units
+/// declared in the header comments of the files (see [`detect_unit`]). Files
+/// without declared units are assumed to be in metres.
+///
+/// Sensors defined in the same units need to be converted as well, e.g., through
+/// [`LengthUnit::transform_to_metres`].
+pub fn load_scenes_with_units<P: AsRef<Path>>(
+    paths: &[P],
+    unit: Option<LengthUnit>,
+) -> Result<(Scene, SceneReport), String> {
+    let mut detected = None;
+    for path in paths {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read Radiance file '{}': {}", path.display(), e))?;
+        detected = files_unit(detected, &content, &path.display().to_string())?;
+    }
+    let scene_unit = unit.or(detected).unwrap_or_default();
+    let (scene, mut report) = load_scenes_transformed(paths, &scene_unit.transform_to_metres())?;
+    report.scene_unit = scene_unit;
+    Ok((scene, report))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    #[test]
+    fn test_units() {
+        use crate::session::{DCOptions, DCSession};
+        use crate::SensorSpec;
+        use geometry3d::{Ray3D, Vector3D};
+
+        let (m, report_m) = load_scene("./tests/obstruction/courtyard.rad").unwrap();
+        assert_eq!(report_m.detected_unit, None);
+        let (mm, report_mm) =
+            load_scenes_with_units(&["./tests/scene_loading/courtyard_mm.rad"], None).unwrap();
+        assert_eq!(report_mm.detected_unit, Some(LengthUnit::Millimetre));
+        assert_eq!(report_mm.scene_unit, LengthUnit::Millimetre);
+        assert_close!(report_mm.bounding_box.1.z, 10., 1e-9);
+
+        // Declared units win over detected ones
+        let (_, report) = load_scenes_with_units(
+            &["./tests/scene_loading/courtyard_mm.rad"],
+            Some(LengthUnit::Metre),
+        )
+        .unwrap();
+        assert_close!(report.bounding_box.1.z, 10000., 1e-9);
+
+        // Sensors in millimetres, converted like the scene
+        let sensors: Vec<SensorSpec> = [(1000., 5000.), (5000., 5000.), (9000., 2000.)]
+            .iter()
+            .map(|(x, y)| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(*x, *y, 800.),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect();
+        let sensors = LengthUnit::Millimetre
+            .transform_to_metres()
+            .transform_sensors(&sensors);
+
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 300,
+                ..DCOptions::default()
+            },
+        );
+        let (m, mm) = (session.prepare(m), session.prepare(mm));
+        let dc_m = session.calc_sensor_dc(&sensors, &m).unwrap();
+        let dc_mm = session.calc_sensor_dc(&sensors, &mm).unwrap();
+        let (nrows, ncols) = dc_m.matrix.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_close!(
+                    dc_m.matrix.get(r, c).unwrap(),
+                    dc_mm.matrix.get(r, c).unwrap(),
+                    1e-6
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_instances() {
+        use crate::session::{DCOptions, DCSession};
+        use crate::SensorSpec;
+        use geometry3d::{Ray3D, Vector3D};
+
+        // A 10 x 10 grid of obstructions, 3 m apart
+        let offsets: Vec<(Float, Float)> = (0..100)
+            .map(|i| (3. * (i % 10) as Float, 3. * (i / 10) as Float))
+            .collect();
+        let instances = Instances {
+            path: PathBuf::from("./tests/scene_loading/obstruction.rad"),
+            transforms: offsets
+                .iter()
+                .map(|(x, y)| Transform::translation(Vector3D::new(*x, *y, 0.)))
+                .collect(),
+        };
+        let (instanced, report) =
+            load_scenes_with_instances::<&str>(&[], std::slice::from_ref(&instances)).unwrap();
+        assert_eq!(
+            report.instances,
+            vec![InstanceReport {
+                file: "./tests/scene_loading/obstruction.rad".to_string(),
+                n_instances: 100,
+                surfaces_per_instance: 2,
+            }]
+        );
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.n_surfaces, 200);
+        assert!(report.is_clean());
+        assert!(format!("{}", report).contains("100 instances"));
+
+        // The same, duplicated explicitly
+        let mut content = "void plastic block_mat\n0\n0\n5 0.3 0.3 0.3 0 0\n".to_string();
+        for (x, y) in &offsets {
+            for (name, (x0, x1, y0, y1)) in [("a", (0., 1., 0., 0.)), ("b", (1., 1., 0., 1.))] {
+                content += &format!(
+                    "block_mat polygon {}\n0\n0\n12\n{} {} 0\n{} {} 0\n{} {} 2\n{} {} 2\n",
+                    name,
+                    x + x0,
+                    y + y0,
+                    x + x1,
+                    y + y1,
+                    x + x1,
+                    y + y1,
+                    x + x0,
+                    y + y0
+                );
+            }
+        }
+        let path = std::env::temp_dir().join(format!("light_instances_{}.rad", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let (explicit, explicit_report) = load_scene(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(explicit_report.n_triangles, report.n_triangles);
+        assert_eq!(explicit_report.bounding_box.1.x, report.bounding_box.1.x);
+
+        let sensors: Vec<SensorSpec> = [(1.5, 1.5), (10.5, 4.5), (20., 20.)]
+            .iter()
+            .map(|(x, y)| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(*x, *y, 0.5),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 500,
+                ..DCOptions::default()
+            },
+        );
+        let (instanced, explicit) = (session.prepare(instanced), session.prepare(explicit));
+        let a = session.calc_sensor_dc(&sensors, &instanced).unwrap();
+        let b = session.calc_sensor_dc(&sensors, &explicit).unwrap();
+        let (nrows, ncols) = a.matrix.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_close!(
+                    a.matrix.get(r, c).unwrap(),
+                    b.matrix.get(r, c).unwrap(),
+                    1e-9
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_clean_scene() {
+        let (_scene, report) = load_scene("./tests/scene_loading/room.rad").unwrap();
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(report.n_surfaces, 2);
+        assert_eq!(report.files.len(), 1);
+        let (min, max) = report.bounding_box;
+        assert_close!(min.x, 0.0);
+        assert_close!(min.z, 0.0);
+        assert_close!(max.x, 4.0);
+        assert_close!(max.y, 6.0);
+        assert_close!(max.z, 3.0);
+    }
+
+    #[test]
+    fn test_unknown_primitives() {
+        let (_scene, report) = load_scene("./tests/scene_loading/unknown_primitive.rad").unwrap();
+        assert_eq!(report.n_surfaces, 1);
+        let kinds: Vec<&str> = report
+            .ignored_primitives
+            .iter()
+            .map(|p| p.kind.as_str())
+            .collect();
+        assert_eq!(kinds, vec!["cone", "brightfunc", "alias"]);
+        assert_eq!(report.ignored_primitives[0].name, "lamp_shade");
+        assert!(report.defaulted_materials.is_empty());
+    }
+
+    #[test]
+    fn test_bad_modifier() {
+        let (_scene, report) = load_scene("./tests/scene_loading/bad_modifier.rad").unwrap();
+        assert_eq!(report.n_surfaces, 3);
+        // 'concrete' is used before being defined, and 'paint' is never defined
+        assert_eq!(report.defaulted_materials, vec!["concrete", "paint"]);
+        // 'ghost' is a 'trans' material, which is kept as it is in the report
+        let ghost = report.materials.iter().find(|m| m.name == "ghost").unwrap();
+        assert_eq!(ghost.kind, "trans");
+        assert_eq!(ghost.reals.len(), 7);
+    }
+
+    #[test]
+    fn test_two_sided() {
+        let (_scene, report) = load_scene("./tests/scene_loading/two_sided.rad").unwrap();
+        assert!(report.ignored_primitives.is_empty());
+        assert_eq!(report.defaulted_materials, vec!["brick"]);
+        let names: Vec<&str> = report.materials.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["white", "brick"]);
+
+        let wall = &report.surfaces[0];
+        assert!(wall.is_two_sided());
+        assert_eq!(wall.modifier_of(SurfaceSide::Front), "brick");
+        assert_eq!(wall.modifier_of(SurfaceSide::Back), "white");
+        assert!(!report.surfaces[1].is_two_sided());
+
+        // The wall faces -y, away from the room, so rays from the room hit its back
+        let normal = wall.normal.unwrap();
+        let from_room = Vector3D::new(0., -1., 0.);
+        assert_eq!(SurfaceSide::hit_by(from_room, normal), SurfaceSide::Back);
+        assert_eq!(SurfaceSide::hit_by(from_room * -1., normal), SurfaceSide::Front);
+        let id = report.object_id("wall").unwrap();
+        let t = id.triangles.start;
+        assert!(id.is_hit(t, from_room) && id.is_hit(t, from_room * -1.));
+        let inside = id.with_side(SurfaceSide::Back);
+        assert!(inside.is_hit(t, from_room));
+        assert!(!inside.is_hit(t, from_room * -1.));
+        assert!(!inside.is_hit(inside.triangles.end, from_room));
+    }
+
+    #[test]
+    fn test_empty_geometry() {
+        let r = load_scene("./tests/scene_loading/empty.rad");
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_ignored_commands() {
+        let (_scene, report) = load_scene("./tests/scene_loading/commands.rad").unwrap();
+        assert_eq!(report.ignored_commands, vec!["!genbox white box 1 1 1"]);
+        assert_eq!(report.n_surfaces, 1);
+    }
+
+    #[test]
+    fn test_xform_inclusion() {
+        let (_scene, report) = load_scene("./tests/scene_loading/project/main.rad").unwrap();
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(report.files.len(), 3);
+        // room.rad (2 polygons) included twice, the second time moved 10m up.
+        assert_eq!(report.n_surfaces, 4);
+        let (min, max) = report.bounding_box;
+        assert_close!(min.z, 0.0);
+        assert_close!(max.z, 13.0);
+
+        // with a rotation... room spans 0 < x < 4 and 0 < y < 6,
+        // rotated 90 degrees it spans -6 < x < 0 and 0 < y < 4
+        let (_scene, report) = load_scene("./tests/scene_loading/project/rotated.rad").unwrap();
+        let (min, max) = report.bounding_box;
+        assert_close!(min.x, -6.0, 1e-5);
+        assert_close!(max.x, 0.0, 1e-5);
+        assert_close!(min.y, 0.0, 1e-5);
+        assert_close!(max.y, 4.0, 1e-5);
+    }
+
+    #[test]
+    fn test_multiple_files() {
+        let (_scene, report) = load_scenes(&[
+            "./tests/scene_loading/room.rad",
+            "./tests/scene_loading/unknown_primitive.rad",
+        ])
+        .unwrap();
+        assert_eq!(report.files.len(), 2);
+        assert_eq!(report.n_surfaces, 3);
+    }
+
+    #[test]
+    fn test_xform_cycle() {
+        assert!(load_scene("./tests/scene_loading/project/cycle.rad").is_err());
+    }
+
+    #[test]
+    fn test_xform_mirror() {
+        let mut p = Primitive {
+            modifier: "white".into(),
+            kind: "polygon".into(),
+            name: "floor".into(),
+            strings: vec![],
+            ints: vec![],
+            reals: vec![0., 0., 0., 1., 0., 0., 1., 1., 0.],
+        };
+        p.transform(&[XformOp::MirrorX]);
+        // reversed, so that the normal keeps pointing up
+        assert_eq!(p.reals, vec![-1., 1., 0., -1., 0., 0., 0., 0., 0.]);
+    }
+
+    #[test]
+    fn test_load_transformed() {
+        let t = Transform::scale(2.).unwrap();
+        let (_, report) = load_scenes_transformed(&["./tests/scene_loading/room.rad"], &t).unwrap();
+        let (min, max) = report.bounding_box;
+        assert_close!(min.x, 0., 1e-6);
+        assert_close!(max.x, 8., 1e-6);
+        assert_close!(max.y, 12., 1e-6);
+        assert_close!(max.z, 6., 1e-6);
+    }
+}
@@ -0,0 +1,1070 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::direct::OriginJitter;
+use crate::path_recorder::{PathRecord, PathVertex};
+use crate::ray_filter::RayAction;
+use crate::ray_offset::RayOffset;
+use crate::rng::{SampleStream, SensorRng};
+use crate::sampling::draw_checked;
+use crate::scene_builder::{glass_at_incidence, transmissivity_to_transmittance};
+use crate::scene_loading::{SceneReport, SurfaceSide};
+use crate::sensor::{DirectionSampler, SensorSpec};
+use crate::session::{DCSession, GlazingModel, TerminationPolicy};
+use crate::sky::SkyBasis;
+use crate::Float;
+use geometry3d::{Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::{Ray, Scene};
+use solar::ReinhartSky;
+
+/// Mixed into the seed of the random numbers used after the first bounce, so
+/// that they are independent from those of the directions seen from the sensors
+const BOUNCE_SEED: u64 = 0x5EED_B0B5;
+
+/// The number of surfaces a path can go through before it is given up, so that
+/// rays cannot get trapped between transmitting surfaces
+pub(crate) const MAX_TRANSMISSIONS: usize = 16;
+
+/// The Daylight Coefficients of a set of sensors, split according to how many
+/// times each path was reflected by each material, as calculated by
+/// [`DCSession::calc_reflectance_tallies`].
+///
+/// A path that bounces `k` times off a material contributes in proportion to its
+/// reflectance to the power of `k`, so changing the reflectance of one material
+/// from `ρ` to `ρ'` scales that contribution by `(ρ'/ρ)^k`. This allows
+/// [`ReflectanceTallies::reweight`] to answer "what if" questions exactly,
+/// without tracing again.
+///
+/// Paths are tallied by the material of the side of the surface they hit, so
+/// the two sides of a two-sided surface are told apart.
+#[derive(Debug, Clone)]
+pub struct ReflectanceTallies {
+    /// The discretisation of the sky of the coefficients
+    pub basis: SkyBasis,
+
+    /// The name of each material, as in [`SceneReport::materials`]
+    pub materials: Vec<String>,
+
+    /// The reflectance each material had in the calculation
+    pub reflectances: Vec<Float>,
+
+    /// For each material and number of reflections `k`, the coefficients of
+    /// the paths that were reflected `k` times by it (one row per sensor
+    /// and one column per bin)
+    tallies: Vec<Vec<Matrix>>,
+
+    /// The coefficients of all the paths
+    total: Matrix,
+
+    /// The number of paths of each sensor that were skipped because their
+    /// direction kept coming out broken (see
+    /// [`DCOptions::max_resamples`](crate::DCOptions::max_resamples)), or because
+    /// they hit a polygon without area
+    pub skipped: Vec<usize>,
+}
+
+impl ReflectanceTallies {
+    /// The Daylight Coefficients, as calculated
+    pub fn total(&self) -> &Matrix {
+        &self.total
+    }
+
+    /// The coefficients of the paths that were reflected exactly `k` times
+    /// by `material`, if it exists
+    pub fn tally(&self, material: &str, k: usize) -> Option<&Matrix> {
+        let m = self.materials.iter().position(|n| n == material)?;
+        self.tallies[m].get(k)
+    }
+
+    /// The Daylight Coefficients that would have been obtained if `material` had a
+    /// reflectance of `new_reflectance` (and everything else stayed the same).
+    ///
+    /// Fails if the material does not exist, if the new reflectance is not between
+    /// 0 and 1, or if the material was black (as then no path ever left it).
+    pub fn reweight(&self, material: &str, new_reflectance: Float) -> Result<Matrix, String> {
+        let m = self
+            .materials
+            .iter()
+            .position(|n| n == material)
+            .ok_or_else(|| format!("There is no material called '{}'", material))?;
+        if !(0.0..=1.0).contains(&new_reflectance) {
+            return Err(format!(
+                "Reflectances must be between 0 and 1, but {} was given",
+                new_reflectance
+            ));
+        }
+        let old = self.reflectances[m];
+        if old <= 0.0 {
+            return Err(format!(
+                "Material '{}' did not reflect any light, so it cannot be reweighted",
+                material
+            ));
+        }
+        let ratio = new_reflectance / old;
+        let (nrows, ncols) = self.total.size();
+        let mut ret = Matrix::new(0.0, nrows, ncols);
+        for (k, tally) in self.tallies[m].iter().enumerate() {
+            let factor = ratio.powi(k as i32);
+            for r in 0..nrows {
+                for c in 0..ncols {
+                    ret.set(r, c, ret.get(r, c)? + tally.get(r, c)? * factor)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// The fraction of the light that a material reflects diffusely, in the
+/// simplified model of [`DCSession::calc_reflectance_tallies`]
+pub(crate) fn diffuse_reflectance(kind: &str, rgb: [Float; 3]) -> Float {
+    match kind {
+        "plastic" | "metal" => (rgb[0] + rgb[1] + rgb[2]) / 3.,
+        _ => 0.0,
+    }
+}
+
+/// The transmissivity of a material, in the simplified model of
+/// [`DCSession::calc_aperture_dc`](crate::DCSession::calc_aperture_dc):
+/// `glass` transmits as Radiance's `glass` with its average transmissivity,
+/// and nothing else transmits.
+pub(crate) fn glass_transmissivity(kind: &str, rgb: [Float; 3]) -> Float {
+    match kind {
+        "glass" => (rgb[0] + rgb[1] + rgb[2]) / 3.,
+        _ => 0.0,
+    }
+}
+
+/// How a semi-transparent material (Radiance's `trans`, like a fabric shade
+/// or frosted glass) splits the light that hits it, in the simplified model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TransLobes {
+    /// The fraction reflected diffusely, on the side the light arrived from
+    pub diffuse_reflectance: Float,
+    /// The fraction transmitted diffusely, to the other side
+    pub diffuse_transmittance: Float,
+    /// The fraction that goes through without changing direction
+    pub direct_transmittance: Float,
+}
+
+impl TransLobes {
+    /// The lobes of a `trans` material, whose real arguments are its colour,
+    /// specularity, roughness, transmissivity and transmitted specularity.
+    /// The specular reflection is neglected, as in `plastic`.
+    pub(crate) fn new(kind: &str, reals: &[Float]) -> Option<Self> {
+        if kind != "trans" || reals.len() != 7 {
+            return None;
+        }
+        let colour = (reals[0] + reals[1] + reals[2]) / 3. * (1. - reals[3]);
+        let (trans, tspec) = (reals[5], reals[6]);
+        Some(Self {
+            diffuse_reflectance: colour * (1. - trans),
+            diffuse_transmittance: colour * trans * (1. - tspec),
+            direct_transmittance: colour * trans * tspec,
+        })
+    }
+
+    /// The fraction of the light that is not absorbed
+    pub(crate) fn total(&self) -> Float {
+        self.diffuse_reflectance + self.diffuse_transmittance + self.direct_transmittance
+    }
+}
+
+/// What the simplified model does with the paths that hit each material:
+/// semi-transparent ones scatter, and the rest follow their `response`
+pub(crate) fn material_responses<F>(report: &SceneReport, response: F) -> Vec<Response>
+where
+    F: Fn(usize) -> Response,
+{
+    report
+        .materials
+        .iter()
+        .enumerate()
+        .map(|(i, m)| match TransLobes::new(&m.kind, &m.reals) {
+            Some(lobes) => Response::Scatter(lobes),
+            None => response(i),
+        })
+        .collect()
+}
+
+/// What the simplified model does with the paths that hit a material
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Response {
+    /// The path ends
+    Absorb,
+    /// The path is reflected diffusely, on the side it arrived from
+    Reflect,
+    /// The path goes through a glass with this transmissivity without changing
+    /// direction, or is reflected specularly by it, as the `glazing` of the
+    /// options says (neither counts as a bounce)
+    Glass(Float),
+    /// The path is reflected diffusely, transmitted diffusely (to the other
+    /// side) or goes straight through, with probabilities proportional to
+    /// the energy of each lobe
+    Scatter(TransLobes),
+}
+
+/// Draws the directions of the diffuse bounces off a surface whose `normal`
+/// points to the side the light leaves from. Cosine sampling cancels the cosine
+/// and the 1/π of the BRDF, so [`LambertianTracer`] does not weight the bounces
+/// (other than by the reflectance).
+pub(crate) fn bounce_sampler(normal: Vector3D) -> Result<DirectionSampler, String> {
+    DirectionSampler::new(normal, None)
+}
+
+/// Traces the paths of the simplified model of
+/// [`DCSession::calc_reflectance_tallies`], leaving it to the caller to weight
+/// them according to the materials they were reflected by.
+pub(crate) struct LambertianTracer<'a> {
+    session: &'a DCSession,
+    scene: &'a Scene,
+    /// The surface each triangle belongs to
+    triangles: Vec<usize>,
+    /// The materials (within [`SceneReport::materials`]) of the front and the
+    /// back of each surface, and its normal (if it has any area)
+    surfaces: Vec<(usize, usize, Option<Vector3D>)>,
+    sky: ReinhartSky,
+    /// How far from a surface the reflected rays start, so that they do not
+    /// hit it again
+    offset: RayOffset,
+}
+
+impl<'a> LambertianTracer<'a> {
+    /// Checks that the session can be traced by this model, and finds out
+    /// what each triangle of the `scene` is made of
+    pub(crate) fn new(
+        session: &'a DCSession,
+        scene: &'a Scene,
+        report: &SceneReport,
+    ) -> Result<Self, String> {
+        if session.options().termination != TerminationPolicy::FixedDepth {
+            return Err(
+                "The simplified tracer needs a fixed number of bounces (i.e., TerminationPolicy::FixedDepth)"
+                    .to_string(),
+            );
+        }
+        if session.importance_hints().is_some()
+            || session.horizon().is_some()
+            || !session.escape_radiance().is_unit()
+        {
+            return Err(
+                "The simplified tracer does not support importance hints, horizon profiles or escape radiances"
+                    .to_string(),
+            );
+        }
+        let triangles = report.triangle_surfaces()?;
+        let surfaces = report
+            .surfaces
+            .iter()
+            .map(|s| {
+                let material = |modifier: &str| {
+                    report
+                        .materials
+                        .iter()
+                        .position(|m| m.name == modifier)
+                        .ok_or_else(|| {
+                            format!(
+                                "Surface '{}' uses an unknown material '{}'",
+                                s.name, modifier
+                            )
+                        })
+                };
+                // Polygons without area can still be hit, through rounding
+                if s.normal.is_none() && s.n_vertices == 0 {
+                    return Err(format!("Surface '{}' is not a polygon", s.name));
+                }
+                Ok((
+                    material(&s.modifier)?,
+                    material(&s.back_modifier)?,
+                    s.normal,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            session,
+            scene,
+            triangles,
+            surfaces,
+            sky: ReinhartSky::new(session.mf()),
+            offset: session.ray_offset(Some(report)),
+        })
+    }
+
+    /// Traces the paths of a sensor. Each one that reaches the sky calls `visit`
+    /// with the throughput of its first direction (before any reflection, but
+    /// including what the semi-transparent materials scattered), the materials
+    /// it was reflected by (in order), the surfaces with a `Glass` response it
+    /// went through (in order), the number of times it bounced diffusely
+    /// (including the scattering by semi-transparent materials) and the bin it
+    /// reached. What glass transmits and
+    /// reflects is part of the throughput.
+    ///
+    /// What happens to the paths that hit each material is given by its
+    /// `responses` (i.e., one per material).
+    ///
+    /// The [`RayFilter`](crate::RayFilter) of the session, if any, is asked
+    /// about every ray before it is cast, with the number of times that its path
+    /// bounced diffusely as its depth. Killed rays end their path, and forced
+    /// escapes reach the bin of their direction.
+    ///
+    /// Paths whose direction comes out broken, even after drawing it again up
+    /// to [`DCOptions::max_resamples`](crate::DCOptions::max_resamples) times (see
+    /// [`draw_checked`]), are skipped, and those that hit a polygon without
+    /// area (whose normal is unknown) end there. Returns how many paths were
+    /// cut short in either way.
+    pub(crate) fn trace<F>(
+        &self,
+        index: usize,
+        sensor: &SensorSpec,
+        responses: &[Response],
+        mut visit: F,
+    ) -> Result<usize, String>
+    where
+        F: FnMut(Float, &[usize], &[usize], usize, usize),
+    {
+        let options = self.session.options();
+        let n_depths = options.max_depth + 1;
+        let sampler = DirectionSampler::new(sensor.ray.direction, sensor.mask.as_ref())?
+            .with_response(sensor.angular_response.as_ref());
+        let stream = SampleStream::new(options.sampling, options.seed, index as u64);
+        let key = SensorRng::new(options.seed ^ BOUNCE_SEED, index as u64).next_u64();
+        let jitter = OriginJitter::new(
+            sensor,
+            index,
+            options.jitter_origins.then_some(options.seed),
+        );
+        let recorder = self.session.path_recorder();
+        let filter = self.session.ray_filter();
+        let mut aux = Vec::with_capacity(2);
+        let mut reflections = Vec::with_capacity(n_depths);
+        let mut transmissions = Vec::new();
+        let mut skipped = 0;
+        for j in 0..options.n_ambient_samples {
+            let mut rng = SensorRng::new(key, j as u64);
+            let u = stream.sample_2d(j as u64);
+            let drawn = draw_checked(u, &mut rng, options.max_resamples, |u1, u2| {
+                let (direction, weight) = sampler.sample(u1, u2);
+                weight.is_finite().then_some((direction, weight))
+            });
+            let Some((mut direction, mut first_throughput)) = drawn else {
+                skipped += 1;
+                continue;
+            };
+            let mut origin = match &jitter {
+                Some(jitter) => jitter.origin(sensor, j),
+                None => sensor.ray.origin,
+            };
+            reflections.clear();
+            transmissions.clear();
+            if first_throughput <= 0.0 {
+                continue;
+            }
+            let mut path = recorder.filter(|r| r.wants(index, j)).map(|_| PathRecord {
+                sensor: index,
+                sample: j,
+                origin: [origin.x, origin.y, origin.z],
+                vertices: Vec::new(),
+                escape: None,
+                weight: 0.0,
+            });
+            let mut depth = 0;
+            let mut crossings = 0;
+            while depth < n_depths {
+                let mut ray = Ray {
+                    geometry: Ray3D { origin, direction },
+                    ..Ray::default()
+                };
+                let hit = match filter.map(|f| f.action(&ray.geometry, depth)) {
+                    None | Some(RayAction::Continue) => {
+                        sensor.cast_ray(self.scene, &mut ray, &mut aux)
+                    }
+                    Some(RayAction::Kill) => break,
+                    Some(RayAction::ForceSkyEscape) => None,
+                };
+                let triangle = match hit {
+                    Some(t) => t,
+                    None => {
+                        let bin = self.sky.dir_to_bin(direction);
+                        if let Some(path) = path.as_mut() {
+                            path.escape = Some(([direction.x, direction.y, direction.z], bin));
+                            path.weight = first_throughput;
+                        }
+                        visit(first_throughput, &reflections, &transmissions, depth, bin);
+                        break;
+                    }
+                };
+                let surface = self.triangles[triangle];
+                let (front, back, normal) = self.surfaces[surface];
+                let Some(normal) = normal else {
+                    skipped += 1;
+                    break;
+                };
+                let m = match SurfaceSide::hit_by(direction, normal) {
+                    SurfaceSide::Front => front,
+                    SurfaceSide::Back => back,
+                };
+                let offset = self.offset.at(ray.interaction.point.distance(origin));
+                if let Some(path) = path.as_mut() {
+                    let p = ray.interaction.point;
+                    path.vertices.push(PathVertex {
+                        position: [p.x, p.y, p.z],
+                        material: m,
+                        cos: (normal * direction).abs(),
+                    });
+                }
+                // The side the ray arrived from
+                let mut normal = if normal * direction > 0.0 {
+                    normal * -1.
+                } else {
+                    normal
+                };
+                let mut transmit = false;
+                if let Response::Glass(tau) = responses[m] {
+                    let (t, r) = match options.glazing {
+                        GlazingModel::Angular => glass_at_incidence(tau, -(normal * direction)),
+                        GlazingModel::Constant => (transmissivity_to_transmittance(tau), 0.0),
+                    };
+                    if t + r <= 0.0 || crossings == MAX_TRANSMISSIONS {
+                        break;
+                    }
+                    crossings += 1;
+                    // As for the lobes below, but no number is drawn if nothing
+                    // is reflected, which keeps the paths of the constant model
+                    first_throughput *= t + r;
+                    if r > 0.0 && rng.gen() * (t + r) >= t {
+                        origin = ray.interaction.point + normal * offset;
+                        direction = direction - normal * (2. * (direction * normal));
+                    } else {
+                        transmissions.push(surface);
+                        origin = ray.interaction.point + direction * offset;
+                    }
+                    continue;
+                }
+                if let Response::Scatter(lobes) = responses[m] {
+                    let total = lobes.total();
+                    if total <= 0.0 {
+                        break;
+                    }
+                    // Choosing each lobe with a probability proportional to its
+                    // energy leaves the same weight (value over probability) for all
+                    first_throughput *= total;
+                    let u = rng.gen() * total;
+                    if u < lobes.direct_transmittance {
+                        transmit = true;
+                    } else if u < lobes.direct_transmittance + lobes.diffuse_transmittance {
+                        normal = normal * -1.;
+                    }
+                }
+                if transmit {
+                    if crossings == MAX_TRANSMISSIONS {
+                        break;
+                    }
+                    crossings += 1;
+                    origin = ray.interaction.point + direction * offset;
+                    continue;
+                }
+                depth += 1;
+                if depth == n_depths || responses[m] == Response::Absorb {
+                    break;
+                }
+                if responses[m] == Response::Reflect {
+                    reflections.push(m);
+                }
+                origin = ray.interaction.point + normal * offset;
+                let bounce = bounce_sampler(normal)?;
+                let u = (rng.gen(), rng.gen());
+                match draw_checked(u, &mut rng, options.max_resamples, |u1, u2| {
+                    Some((bounce.sample(u1, u2).0, ()))
+                }) {
+                    Some((d, _)) => direction = d,
+                    None => {
+                        skipped += 1;
+                        break;
+                    }
+                }
+            }
+            if let (Some(recorder), Some(path)) = (recorder, path) {
+                recorder.push(path);
+            }
+        }
+        Ok(skipped)
+    }
+}
+
+/// What a sensor accumulates: the coefficients per material and
+/// number of reflections, and the total ones
+struct SensorTallies {
+    tallies: Vec<Vec<Vec<Float>>>,
+    total: Vec<Float>,
+    skipped: usize,
+}
+
+impl DCSession {
+    /// Calculates the Daylight Coefficients of a set of sensors, keeping track of how
+    /// many times each path is reflected by each material (see [`ReflectanceTallies`]).
+    /// The `report` must be the one returned when loading the `scene`, as it says
+    /// which material each triangle is made of.
+    ///
+    /// This is traced by this crate, with a simplified model: every `plastic` and
+    /// `metal` is a Lambertian reflector whose reflectance is the average of its
+    /// RGB values, every `trans` reflects and transmits diffusely (and transmits
+    /// straight through) as much as its arguments say, and every other material
+    /// absorbs all light. Scattering by `trans` is not tallied, so it cannot be
+    /// reweighted. Paths bounce up to
+    /// `max_depth` times (so the termination must be
+    /// [`TerminationPolicy::FixedDepth`]), without Russian roulette, which is what
+    /// makes reweighting exact. The first direction (and origin) of each path is
+    /// sampled as by the direct tracer, respecting masks.
+    pub fn calc_reflectance_tallies(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+    ) -> Result<ReflectanceTallies, String> {
+        let tracer = LambertianTracer::new(self, scene, report)?;
+        let options = *self.options();
+        let n_depths = options.max_depth + 1;
+        let materials: Vec<String> = report.materials.iter().map(|m| m.name.clone()).collect();
+        let reflectances: Vec<Float> = report
+            .materials
+            .iter()
+            .map(|m| diffuse_reflectance(&m.kind, m.rgb))
+            .collect();
+        let responses = material_responses(report, |m| {
+            if reflectances[m] > 0.0 {
+                Response::Reflect
+            } else {
+                Response::Absorb
+            }
+        });
+        // The tallies of every material, plus the total
+        self.check_budget(sensors.len() * (1 + materials.len() * n_depths))?;
+
+        let basis = self.basis()?;
+        let n_bins = basis.n_bins();
+        let one_over_samples = 1. / options.n_ambient_samples as Float;
+        let trace = |(index, sensor): (usize, &SensorSpec)| {
+            let mut ret = SensorTallies {
+                tallies: vec![vec![vec![0.0; n_bins]; n_depths]; materials.len()],
+                total: vec![0.0; n_bins],
+                skipped: 0,
+            };
+            let mut counts = vec![0; materials.len()];
+            ret.skipped = tracer.trace(
+                index,
+                sensor,
+                &responses,
+                |throughput, reflections, _, _, bin| {
+                    let v = reflections
+                        .iter()
+                        .fold(throughput, |t, m| t * reflectances[*m])
+                        * one_over_samples;
+                    counts.iter_mut().for_each(|c| *c = 0);
+                    reflections.iter().for_each(|m| counts[*m] += 1);
+                    ret.total[bin] += v;
+                    for (m, k) in counts.iter().enumerate() {
+                        ret.tallies[m][*k][bin] += v;
+                    }
+                },
+            )?;
+            Ok(ret)
+        };
+        let rows = self.map_sensors(sensors, trace)?;
+
+        let mut total = Matrix::new(0.0, sensors.len(), n_bins);
+        let mut tallies =
+            vec![vec![Matrix::new(0.0, sensors.len(), n_bins); n_depths]; materials.len()];
+        for (r, row) in rows.iter().enumerate() {
+            for (bin, v) in row.total.iter().enumerate() {
+                total.set(r, bin, *v)?;
+            }
+            for (m, per_depth) in row.tallies.iter().enumerate() {
+                for (k, values) in per_depth.iter().enumerate() {
+                    for (bin, v) in values.iter().enumerate() {
+                        tallies[m][k].set(r, bin, *v)?;
+                    }
+                }
+            }
+        }
+        Ok(ReflectanceTallies {
+            basis,
+            materials,
+            reflectances,
+            tallies,
+            total,
+            skipped: rows.iter().map(|row| row.skipped).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::load_scene;
+    use crate::sampling::chi2_sampler_test;
+    use crate::session::DCOptions;
+    use crate::{ApertureGroup, EventKind, LightLossFactors, Material, SceneBuilder, SensorGrid};
+    use geometry3d::{Point3D, Vector3D};
+    use std::path::Path;
+    use validate::assert_close;
+
+    fn tallies(path: &Path) -> ReflectanceTallies {
+        let (mut scene, report) = load_scene(path).unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 2000,
+                max_depth: 3,
+                seed: 7,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = [
+            (Point3D::new(0., 0., 1.), Vector3D::new(0., 0., 1.)),
+            (Point3D::new(0., 1., 1.), Vector3D::new(0., -1., 0.)),
+        ]
+        .iter()
+        .map(|(origin, direction)| {
+            Ray3D {
+                origin: *origin,
+                direction: *direction,
+            }
+            .into()
+        })
+        .collect();
+        session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap()
+    }
+
+    fn assert_matrices_close(a: &Matrix, b: &Matrix) {
+        let (nrows, ncols) = a.size();
+        assert_eq!((nrows, ncols), b.size());
+        for r in 0..nrows {
+            for c in 0..ncols {
+                let (a, b) = (a.get(r, c).unwrap(), b.get(r, c).unwrap());
+                assert_close!(a, b, 1e-4 * (1. + b.abs()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_chi2_bounce_sampler() {
+        // Off a floor, a ceiling and a tilted wall
+        let normals = [
+            Vector3D::new(0., 0., 1.),
+            Vector3D::new(0., 0., -1.),
+            Vector3D::new(0.6, -0.8, 0.).get_normalized(),
+        ];
+        for (seed, normal) in normals.into_iter().enumerate() {
+            let sampler = bounce_sampler(normal).unwrap();
+            let report = chi2_sampler_test(&sampler, 5000, seed as u64).unwrap();
+            assert_close!(report.pdf_integral, 1., 1e-3);
+            // The cosine over the density, times the 1/π of the BRDF, is one
+            for (u1, u2) in [(0.1, 0.2), (0.5, 0.5), (0.99, 0.7)] {
+                let (direction, weight) = sampler.sample(u1, u2);
+                assert!(direction * normal > 0.0);
+                assert_close!(weight / crate::PI, 1., 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tallies() {
+        let tallies = tallies(Path::new("./tests/sensitivity/canopy.rad"));
+        assert_eq!(
+            tallies.materials,
+            vec!["ceiling_mat", "wall_mat", "floor_mat"]
+        );
+        for (found, exp) in tallies.reflectances.iter().zip([0.7, 0.5, 0.2]) {
+            assert_close!(*found, exp, 1e-6);
+        }
+
+        // Every path is counted once per material
+        let (nrows, ncols) = tallies.total().size();
+        for material in &tallies.materials {
+            let mut sum = Matrix::new(0.0, nrows, ncols);
+            for k in 0..4 {
+                let tally = tallies.tally(material, k).unwrap();
+                for r in 0..nrows {
+                    for c in 0..ncols {
+                        sum.set(r, c, sum.get(r, c).unwrap() + tally.get(r, c).unwrap())
+                            .unwrap();
+                    }
+                }
+            }
+            assert_matrices_close(&sum, tallies.total());
+        }
+        assert!(tallies.tally("ceiling_mat", 4).is_none());
+
+        // Light does reach the sensors through the ceiling
+        let reflected = tallies.tally("ceiling_mat", 1).unwrap();
+        assert!((0..ncols).any(|c| reflected.get(0, c).unwrap() > 0.0));
+
+        // Not changing anything gives the original coefficients
+        let same = tallies.reweight("ceiling_mat", 0.7).unwrap();
+        assert_matrices_close(&same, tallies.total());
+
+        assert!(tallies.reweight("glass_mat", 0.5).is_err());
+        assert!(tallies.reweight("wall_mat", 1.5).is_err());
+    }
+
+    #[test]
+    fn test_reweight_matches_fresh_run() {
+        let original = "./tests/sensitivity/canopy.rad";
+        let tallies_before = tallies(Path::new(original));
+
+        let content = std::fs::read_to_string(original).unwrap();
+        let content = content.replace("5 0.7 0.7 0.7 0 0", "5 0.85 0.85 0.85 0 0");
+        let modified = std::env::temp_dir().join("light_sensitivity_canopy.rad");
+        std::fs::write(&modified, content).unwrap();
+        let tallies_after = tallies(&modified);
+        std::fs::remove_file(&modified).unwrap();
+        assert_close!(tallies_after.reflectances[0], 0.85, 1e-6);
+
+        let reweighted = tallies_before.reweight("ceiling_mat", 0.85).unwrap();
+        assert_matrices_close(&reweighted, tallies_after.total());
+
+        // and back
+        let back = tallies_after.reweight("ceiling_mat", 0.7).unwrap();
+        assert_matrices_close(&back, tallies_before.total());
+    }
+
+    /// The coefficients of a sensor under a window in a black roof, and
+    /// the number of bins they reach, with and without a shade under it
+    fn shaded_window(shade: Option<Material>) -> (Float, usize) {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("roof_mat", Material::plastic(0.))
+            .unwrap();
+        builder
+            .add_material("glass_mat", Material::glass(0.8))
+            .unwrap();
+        let square = |x0: Float, x1: Float, y0: Float, y1: Float, z: Float| {
+            [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|(x, y)| Point3D::new(x, y, z))
+        };
+        builder
+            .add_polygon("glass_mat", "window", &square(-1., 1., -1., 1., 1.))
+            .unwrap();
+        for (name, x0, x1, y0, y1) in [
+            ("south", -20., 20., -20., -1.),
+            ("north", -20., 20., 1., 20.),
+            ("west", -20., -1., -1., 1.),
+            ("east", 1., 20., -1., 1.),
+        ] {
+            builder
+                .add_polygon("roof_mat", name, &square(x0, x1, y0, y1, 1.))
+                .unwrap();
+        }
+        if let Some(shade) = shade {
+            builder.add_material("shade_mat", shade).unwrap();
+            builder
+                .add_polygon("shade_mat", "shade", &square(-1., 1., -1., 1., 0.99))
+                .unwrap();
+        }
+        let (scene, report) = builder.build().unwrap();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 4000,
+                max_depth: 1,
+                seed: 11,
+                // Glass that reflects draws random numbers, which would
+                // differ once the shade draws some too
+                glazing: GlazingModel::Constant,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let groups = [ApertureGroup::new(
+            "window",
+            &["window"],
+            LightLossFactors::constant(1., 1.).unwrap(),
+        )];
+        let dc = session
+            .calc_aperture_dc(&sensors, &scene, &report, &groups)
+            .unwrap()
+            .through("window")
+            .unwrap();
+        let values: Vec<Float> = (0..dc.size().1).map(|c| dc.get(0, c).unwrap()).collect();
+        (
+            values.iter().sum(),
+            values.iter().filter(|v| **v > 0.0).count(),
+        )
+    }
+
+    #[test]
+    fn test_diffuse_shade() {
+        let (bare, bare_bins) = shaded_window(None);
+        let (shaded, shaded_bins) = shaded_window(Some(Material::trans(0., 0.4)));
+        assert!(bare > 0.0);
+        let ratio = shaded / bare;
+        assert!((0.36..0.44).contains(&ratio), "ratio is {}", ratio);
+        // The window alone is seen within 45 degrees of the zenith, but the
+        // shade sends light to the sensor from the whole sky behind the window
+        assert!(
+            shaded_bins > 2 * bare_bins,
+            "{} vs {}",
+            shaded_bins,
+            bare_bins
+        );
+
+        // A shade that lets everything straight through changes nothing
+        let clear = Material::Trans {
+            rgb: [1.; 3],
+            specularity: 0.,
+            roughness: 0.,
+            transmissivity: 1.,
+            transmitted_specularity: 1.,
+        };
+        let (through, through_bins) = shaded_window(Some(clear));
+        assert_close!(through, bare, 1e-4 * bare);
+        assert_eq!(through_bins, bare_bins);
+    }
+
+    /// The tallies of a sensor under a ceiling made of `outside` on its upper
+    /// side and of `inside` on the side the sensor sees
+    fn under_ceiling(outside: &str, inside: &str) -> ReflectanceTallies {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("white", Material::plastic(0.8))
+            .unwrap();
+        builder
+            .add_material("black", Material::plastic(0.))
+            .unwrap();
+        let ceiling = [(-20., -20.), (20., -20.), (20., 20.), (-20., 20.)]
+            .map(|(x, y)| Point3D::new(x, y, 1.));
+        builder
+            .add_surface("ceiling", outside, inside, &ceiling)
+            .unwrap();
+        let (scene, report) = builder.build().unwrap();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 500,
+                max_depth: 1,
+                seed: 5,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_two_sided_ceiling() {
+        let sum = |m: &Matrix| -> Float {
+            let (nrows, ncols) = m.size();
+            (0..nrows)
+                .flat_map(|r| (0..ncols).map(move |c| (r, c)))
+                .map(|(r, c)| m.get(r, c).unwrap())
+                .sum()
+        };
+        // Painting the outside black changes nothing
+        let painted = under_ceiling("black", "white");
+        let white = under_ceiling("white", "white");
+        assert!(sum(painted.total()) > 0.0);
+        assert_matrices_close(painted.total(), white.total());
+        assert_matrices_close(painted.tally("white", 1).unwrap(), painted.total());
+        assert_eq!(sum(painted.tally("black", 1).unwrap()), 0.0);
+
+        // ... but painting the inside does
+        let inverted = under_ceiling("white", "black");
+        assert_eq!(sum(inverted.total()), 0.0);
+    }
+
+    #[test]
+    fn test_needs_fixed_depth() {
+        let (scene, report) = load_scene("./tests/sensitivity/canopy.rad").unwrap();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                termination: TerminationPolicy::Throughput {
+                    min_contribution: 0.01,
+                },
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        assert!(session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .is_err());
+    }
+
+    #[test]
+    fn test_roof_grid() {
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 500,
+                max_depth: 2,
+                seed: 3,
+                ..DCOptions::default()
+            },
+        );
+        let (mut scene, report) = load_scene("./tests/sensitivity/roof.rad").unwrap();
+        scene.build_accelerator();
+        let sensors = SensorGrid::from_surface(&report, "roof", 1., 0.)
+            .unwrap()
+            .sensors();
+        let with_roof = session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+
+        // Otherwise, the roof gets in the way of the light reflected by the wall
+        let bare: Vec<SensorSpec> = sensors.iter().map(|s| SensorSpec::from(s.ray)).collect();
+        let blocked = session
+            .calc_reflectance_tallies(&bare, &scene, &report)
+            .unwrap();
+
+        // Sensors on their host surface cannot tell whether it is there
+        let (mut scene, report) = load_scene("./tests/sensitivity/no_roof.rad").unwrap();
+        scene.build_accelerator();
+        let without_roof = session
+            .calc_reflectance_tallies(&bare, &scene, &report)
+            .unwrap();
+        assert_matrices_close(with_roof.total(), without_roof.total());
+        let (a, b) = (with_roof.total(), blocked.total());
+        let (nrows, ncols) = a.size();
+        assert!((0..nrows)
+            .flat_map(|r| (0..ncols).map(move |c| (r, c)))
+            .any(|(r, c)| (a.get(r, c).unwrap() - b.get(r, c).unwrap()).abs() > 1e-3));
+
+        // ... but they do see the light reflected by the wall and the ground
+        for material in ["wall_mat", "ground_mat"] {
+            let tally = with_roof.tally(material, 1).unwrap();
+            let (nrows, ncols) = tally.size();
+            let total: Float = (0..nrows)
+                .flat_map(|r| (0..ncols).map(move |c| (r, c)))
+                .map(|(r, c)| tally.get(r, c).unwrap())
+                .sum();
+            assert!(total > 0.0, "{}", material);
+        }
+    }
+
+    #[test]
+    fn test_degenerate_geometry() {
+        // Rooms littered with polygons without area (repeated or collinear
+        // vertices), seen by sensors whose frames cannot be built: nothing
+        // panics, and nothing comes out of a NaN
+        let finite = |m: &Matrix| {
+            let (nrows, ncols) = m.size();
+            (0..nrows).all(|r| (0..ncols).all(|c| m.get(r, c).unwrap().is_finite()))
+        };
+        for seed in 0..4 {
+            let mut rng = SensorRng::new(seed, 0);
+            let mut point = || {
+                let mut x = || 4. * rng.gen() - 2.;
+                Point3D::new(x(), x(), x().abs())
+            };
+            let mut builder = SceneBuilder::new();
+            builder
+                .add_material("white", Material::plastic(0.8))
+                .unwrap();
+            let ceiling =
+                [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, 2.5));
+            builder
+                .add_surface("ceiling", "white", "white", &ceiling)
+                .unwrap();
+            for i in 0..20 {
+                let (a, b) = (point(), point());
+                let vertices = if i % 2 == 0 {
+                    [a, a, b]
+                } else {
+                    [a, b, a + (b - a) * 0.5]
+                };
+                builder
+                    .add_polygon("white", &format!("sliver_{}", i), &vertices)
+                    .unwrap();
+            }
+            let (mut scene, report) = builder.build().unwrap();
+            scene.build_accelerator();
+            let sensors: Vec<SensorSpec> = [
+                Vector3D::new(0., 0., 1.),
+                Vector3D::new(Float::NAN, 0., 1.),
+                Vector3D::new(Float::MAX, 0., Float::MAX),
+            ]
+            .iter()
+            .map(|direction| {
+                Ray3D {
+                    origin: Point3D::new(0., 0., 0.5),
+                    direction: *direction,
+                }
+                .into()
+            })
+            .collect();
+            let options = DCOptions {
+                n_ambient_samples: 200,
+                max_depth: 3,
+                seed,
+                ..DCOptions::default()
+            };
+
+            let tallies = DCSession::new(1, options)
+                .calc_reflectance_tallies(&sensors, &scene, &report)
+                .unwrap();
+            assert!(finite(tallies.total()));
+            assert!(tallies.skipped[0] < options.n_ambient_samples);
+            assert_eq!(tallies.skipped[1], options.n_ambient_samples);
+            assert_eq!(tallies.skipped[2], options.n_ambient_samples);
+
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    max_depth: 0,
+                    ..options
+                },
+            );
+            let (dc, stats) = session
+                .calc_sensor_dc_with_stats(&sensors, &scene, false)
+                .unwrap();
+            assert!(finite(&dc.matrix));
+            assert!(stats.standard_errors.iter().all(|e| e.is_finite()));
+            assert_eq!(stats.skipped[0], 0);
+            assert_eq!(stats.skipped[1], options.n_ambient_samples);
+            let mut into = Matrix::new(0.0, sensors.len(), session.basis().unwrap().n_bins());
+            let events = session
+                .calc_dc_into(&mut into, 0, &sensors, &scene)
+                .unwrap();
+            assert!(finite(&into));
+            let bad: Vec<Option<usize>> = events
+                .of_kind(EventKind::BadSample)
+                .map(|e| e.sensor)
+                .collect();
+            assert_eq!(bad, vec![Some(1), Some(2)]);
+        }
+    }
+}
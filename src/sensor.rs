@@ -0,0 +1,923 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::iam::interpolate;
+use crate::ray_caster::{Hit, RayCaster};
+use crate::sampling::{sample_weight, HemisphereSampler};
+use crate::scene_loading::ObjectId;
+use crate::sensor_id::SensorId;
+use crate::{Float, PI};
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use rendering::{Ray, Scene};
+use std::sync::Arc;
+
+/// How far past the host surface of a sensor the rays that hit it start again
+const HOST_OFFSET: Float = 1e-4;
+
+/// The number of times a ray can go through the host surface of a sensor. A
+/// polygon can only be crossed once, but the hit may land on two of its triangles.
+const MAX_HOST_CROSSINGS: usize = 4;
+
+/// A function deciding whether a direction (in world coordinates)
+/// can be seen by a sensor
+pub type DirectionPredicate = Arc<dyn Fn(Vector3D) -> bool + Send + Sync>;
+
+/// Restricts the part of the hemisphere of a sensor that is sampled.
+///
+/// Angles are in degrees and relative to the sensor. The altitude is
+/// measured from the plane of the sensor (i.e., `90` is the direction of its normal),
+/// and the azimuth is measured from the projection of the world's `Z` axis onto
+/// that plane (or from `Y`, for sensors that look up or down), growing
+/// towards the right-hand side when looking along the normal. For instance,
+/// for a sensor looking up, azimuth `0` is North and `90` is East.
+#[derive(Clone)]
+pub enum AngularMask {
+    /// Only the directions within these ranges are visible. If `min_azimuth` is
+    /// larger than `max_azimuth`, the range wraps around `360`.
+    Range {
+        /// The minimum altitude, between `0` and `90`
+        min_altitude: Float,
+        /// The maximum altitude, between `0` and `90`
+        max_altitude: Float,
+        /// The minimum azimuth, between `0` and `360`
+        min_azimuth: Float,
+        /// The maximum azimuth, between `0` and `360`
+        max_azimuth: Float,
+    },
+
+    /// Only the directions for which the predicate returns `true` are visible. The
+    /// whole hemisphere is sampled, so this is less efficient than a `Range`.
+    Predicate(DirectionPredicate),
+}
+
+impl std::fmt::Debug for AngularMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Range {
+                min_altitude,
+                max_altitude,
+                min_azimuth,
+                max_azimuth,
+            } => f
+                .debug_struct("Range")
+                .field("min_altitude", min_altitude)
+                .field("max_altitude", max_altitude)
+                .field("min_azimuth", min_azimuth)
+                .field("max_azimuth", max_azimuth)
+                .finish(),
+            Self::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+impl AngularMask {
+    /// Creates a `Range` mask, checking that the angles make sense
+    pub fn range(
+        min_altitude: Float,
+        max_altitude: Float,
+        min_azimuth: Float,
+        max_azimuth: Float,
+    ) -> Result<Self, String> {
+        if !(0.0..=90.).contains(&min_altitude)
+            || !(0.0..=90.).contains(&max_altitude)
+            || min_altitude >= max_altitude
+        {
+            return Err(format!(
+                "Invalid altitude range in AngularMask: {} to {} (they must be within 0 and 90, and increasing)",
+                min_altitude, max_altitude
+            ));
+        }
+        if !(0.0..=360.).contains(&min_azimuth)
+            || !(0.0..=360.).contains(&max_azimuth)
+            || min_azimuth == max_azimuth
+        {
+            return Err(format!(
+                "Invalid azimuth range in AngularMask: {} to {} (they must be within 0 and 360, and different)",
+                min_azimuth, max_azimuth
+            ));
+        }
+        Ok(Self::Range {
+            min_altitude,
+            max_altitude,
+            min_azimuth,
+            max_azimuth,
+        })
+    }
+
+    /// A mask that only sees the directions that satisfy `predicate`
+    pub fn predicate<F>(predicate: F) -> Self
+    where
+        F: Fn(Vector3D) -> bool + Send + Sync + 'static,
+    {
+        Self::Predicate(Arc::new(predicate))
+    }
+
+    /// Checks whether a direction (in world coordinates) is visible
+    /// through the mask of a sensor with a certain `normal`
+    pub fn is_visible(&self, normal: Vector3D, direction: Vector3D) -> Result<bool, String> {
+        match self {
+            Self::Predicate(p) => Ok(direction * normal > 0.0 && p(direction)),
+            Self::Range {
+                min_altitude,
+                max_altitude,
+                min_azimuth,
+                max_azimuth,
+            } => {
+                let frame = LocalFrame::new(normal)?;
+                let (alt, azi) = frame.altitude_azimuth(direction);
+                let in_azimuth = if min_azimuth < max_azimuth {
+                    azi >= *min_azimuth && azi <= *max_azimuth
+                } else {
+                    azi >= *min_azimuth || azi <= *max_azimuth
+                };
+                Ok(alt >= *min_altitude && alt <= *max_altitude && in_azimuth)
+            }
+        }
+    }
+}
+
+/// The angular response of a sensor (e.g., the cosine correction curve of an
+/// illuminance meter): the fraction of the light arriving at a certain angle from
+/// its normal that it measures, relative to an ideal cosine response. It is given
+/// as `(angle in degrees, response)` pairs, sorted by angle and interpolated
+/// linearly, and angles outside of the curve take the value of the closest end.
+///
+/// An ideal sensor has a response of `1` at every angle, which is what sensors
+/// without a curve get (the cosine is already part of their estimates).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseCurve {
+    points: Vec<(Float, Float)>,
+}
+
+impl ResponseCurve {
+    /// Builds a curve, checking that the angles are increasing and within
+    /// `[0, 90]` degrees, and that the responses are non-negative
+    pub fn new(points: Vec<(Float, Float)>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("A response curve needs at least one point".to_string());
+        }
+        for (angle, response) in points.iter() {
+            if !(0.0..=90.).contains(angle) {
+                return Err(format!(
+                    "The angles of a response curve must be between 0 and 90 degrees, but found {}",
+                    angle
+                ));
+            }
+            if !response.is_finite() || *response < 0.0 {
+                return Err(format!(
+                    "The responses of a response curve must be non-negative, but found {}",
+                    response
+                ));
+            }
+        }
+        if points.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return Err("The angles of a response curve must be strictly increasing".to_string());
+        }
+        Ok(Self { points })
+    }
+
+    /// The points of the curve
+    pub fn points(&self) -> &[(Float, Float)] {
+        &self.points
+    }
+
+    /// The response to light arriving at `angle` degrees from the normal
+    pub fn factor(&self, angle: Float) -> Float {
+        interpolate(&self.points, angle)
+    }
+
+    /// Whether the response is `1` at every angle
+    pub fn is_ideal(&self) -> bool {
+        self.points.iter().all(|(_, r)| *r == 1.0)
+    }
+
+    /// The response to light arriving from `direction` on a sensor with a
+    /// certain `normal`, both normalised
+    pub(crate) fn at(&self, normal: Vector3D, direction: Vector3D) -> Float {
+        self.factor((normal * direction).clamp(-1., 1.).acos().to_degrees())
+    }
+}
+
+/// An orthonormal base attached to a sensor, used for
+/// measuring altitudes and azimuths
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LocalFrame {
+    pub normal: Vector3D,
+    /// Azimuth 0
+    pub reference: Vector3D,
+    /// Azimuth 90
+    pub side: Vector3D,
+}
+
+impl LocalFrame {
+    pub fn new(normal: Vector3D) -> Result<Self, String> {
+        if normal.is_zero() {
+            return Err("Cannot build a local frame from a zero-length normal".to_string());
+        }
+        let normal = normal.get_normalized();
+        let up = if normal.z.abs() > 0.999 {
+            Vector3D::new(0., 1., 0.)
+        } else {
+            Vector3D::new(0., 0., 1.)
+        };
+        let reference = (up - normal * (up * normal)).get_normalized();
+        let side = reference.cross(normal);
+        Ok(Self {
+            normal,
+            reference,
+            side,
+        })
+    }
+
+    /// Altitude and azimuth (in degrees) of a direction
+    pub fn altitude_azimuth(&self, direction: Vector3D) -> (Float, Float) {
+        let d = direction.get_normalized();
+        let alt = (d * self.normal).clamp(-1., 1.).asin().to_degrees();
+        let mut azi = (d * self.side).atan2(d * self.reference).to_degrees();
+        if azi < 0.0 {
+            azi += 360.;
+        }
+        (alt, azi)
+    }
+
+    /// The world direction of an altitude and azimuth (in radians)
+    pub fn direction(&self, cos_alt: Float, sin_alt: Float, azimuth: Float) -> Vector3D {
+        self.reference * (cos_alt * azimuth.cos())
+            + self.side * (cos_alt * azimuth.sin())
+            + self.normal * sin_alt
+    }
+}
+
+/// Samples directions over the visible part of the hemisphere of
+/// a sensor, with a probability proportional to the cosine of the
+/// angle with the normal.
+#[derive(Clone)]
+pub(crate) struct DirectionSampler {
+    frame: LocalFrame,
+    /// Whether the mask restricts the sampled directions
+    restricted: bool,
+    predicate: Option<DirectionPredicate>,
+    /// Range of the squared cosine of the altitude
+    cos2_alt: (Float, Float),
+    /// Start and width of the azimuth range, in radians
+    azimuth: (Float, Float),
+    /// The fraction of the cosine-weighted hemisphere that is sampled
+    fraction: Float,
+    /// Directions closer to the plane of the sensor than this cosine weigh nothing
+    min_cosine: Float,
+    /// The angular response of the sensor, if it is not ideal
+    response: Option<ResponseCurve>,
+}
+
+impl DirectionSampler {
+    pub fn new(normal: Vector3D, mask: Option<&AngularMask>) -> Result<Self, String> {
+        let frame = LocalFrame::new(normal)?;
+        let mut ret = Self {
+            frame,
+            restricted: false,
+            predicate: None,
+            cos2_alt: (1., 0.),
+            azimuth: (0., 2. * PI),
+            fraction: 1.,
+            min_cosine: 0.,
+            response: None,
+        };
+        match mask {
+            None => {}
+            Some(AngularMask::Predicate(p)) => ret.predicate = Some(Arc::clone(p)),
+            Some(AngularMask::Range {
+                min_altitude,
+                max_altitude,
+                min_azimuth,
+                max_azimuth,
+            }) => {
+                let c_min = min_altitude.to_radians().cos();
+                let c_max = max_altitude.to_radians().cos();
+                ret.cos2_alt = (c_min * c_min, c_max * c_max);
+                let mut width = max_azimuth - min_azimuth;
+                if width < 0.0 {
+                    width += 360.;
+                }
+                ret.azimuth = (min_azimuth.to_radians(), width.to_radians());
+                ret.restricted = true;
+                ret.fraction = (ret.cos2_alt.0 - ret.cos2_alt.1) * width / 360.;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Makes the directions whose cosine with the normal is below `min_cosine`
+    /// weigh nothing, in [`DirectionSampler::sample`] and in
+    /// [`DirectionSampler::integrand`] alike. They are still drawn (so `pdf` is
+    /// unchanged), and what they would have weighed is given by
+    /// [`DirectionSampler::clipped`].
+    pub fn with_min_cosine(mut self, min_cosine: Float) -> Self {
+        self.min_cosine = min_cosine;
+        self
+    }
+
+    /// Weighs every direction by the angular `response` of the sensor, if any
+    /// (see [`ResponseCurve`]), on top of its cosine. Directions are still drawn
+    /// as for an ideal sensor, so `pdf` is unchanged.
+    pub fn with_response(mut self, response: Option<&ResponseCurve>) -> Self {
+        self.response = response.filter(|r| !r.is_ideal()).cloned();
+        self
+    }
+
+    /// The angular response of the sensor to a direction
+    fn response(&self, direction: Vector3D) -> Float {
+        match &self.response {
+            Some(r) => r.at(self.frame.normal, direction.get_normalized()),
+            None => 1.,
+        }
+    }
+
+    /// Transforms two uniform random numbers into a direction and its weight,
+    /// `cos(theta)/pdf` times the response of the sensor (which is `0` for
+    /// directions excluded by a predicate or grazing the plane of the sensor).
+    /// See [`sample_weight`].
+    pub fn sample(&self, u1: Float, u2: Float) -> (Vector3D, Float) {
+        let dir = self.sample_direction(u1, u2);
+        let weight = if self.integrand(dir) > 0.0 {
+            sample_weight(self, dir) * self.response(dir)
+        } else {
+            0.0
+        };
+        (dir, weight)
+    }
+
+    /// The weight that [`DirectionSampler::sample`] would have given to a
+    /// direction if it were not grazing the plane of the sensor (see
+    /// [`DirectionSampler::with_min_cosine`]), or `0` for the rest.
+    pub fn clipped(&self, direction: Vector3D) -> Float {
+        if self.grazing(direction) > 0.0 {
+            sample_weight(self, direction) * self.response(direction)
+        } else {
+            0.0
+        }
+    }
+
+    /// The cosine of the angle between a direction that the sensor sees but
+    /// that grazes its plane and the normal, times the response of the sensor,
+    /// or `0` for the rest
+    pub fn grazing(&self, direction: Vector3D) -> Float {
+        let cos = self.visible_cos(direction);
+        if cos < self.min_cosine {
+            cos * self.response(direction)
+        } else {
+            0.0
+        }
+    }
+
+    /// The function whose integral the weights of [`DirectionSampler::sample`]
+    /// estimate: the cosine of the angle between `direction` and the normal times
+    /// the response of the sensor, or `0` for directions that the sensor does not
+    /// see or that graze its plane.
+    pub fn integrand(&self, direction: Vector3D) -> Float {
+        let cos = self.visible_cos(direction);
+        if cos < self.min_cosine {
+            return 0.0;
+        }
+        cos * self.response(direction)
+    }
+
+    /// The cosine of the angle between `direction` and the normal, or `0`
+    /// for directions that the sensor does not see
+    fn visible_cos(&self, direction: Vector3D) -> Float {
+        let cos = direction.get_normalized() * self.frame.normal;
+        if cos <= 0.0 || !self.in_range(direction) {
+            return 0.0;
+        }
+        match &self.predicate {
+            Some(p) if !p(direction) => 0.0,
+            _ => cos,
+        }
+    }
+
+    /// Whether a direction is within the altitude and azimuth ranges
+    /// that are sampled. A small tolerance accounts for rounding errors in
+    /// directions that were sampled on the edges.
+    fn in_range(&self, direction: Vector3D) -> bool {
+        if !self.restricted {
+            return true;
+        }
+        const TOLERANCE: Float = 1e-6;
+        let d = direction.get_normalized();
+        let sin_alt = d * self.frame.normal;
+        let cos2_alt = 1. - sin_alt * sin_alt;
+        let (c2_max, c2_min) = self.cos2_alt;
+        if cos2_alt < c2_min - TOLERANCE || cos2_alt > c2_max + TOLERANCE {
+            return false;
+        }
+        let mut delta = (d * self.frame.side).atan2(d * self.frame.reference) - self.azimuth.0;
+        while delta < -TOLERANCE {
+            delta += 2. * PI;
+        }
+        delta <= self.azimuth.1 + TOLERANCE
+    }
+}
+
+impl HemisphereSampler for DirectionSampler {
+    fn normal(&self) -> Vector3D {
+        self.frame.normal
+    }
+
+    fn sample_direction(&self, u1: Float, u2: Float) -> Vector3D {
+        let (c2_max, c2_min) = self.cos2_alt;
+        let cos2_alt = c2_min + u1 * (c2_max - c2_min);
+        let cos_alt = cos2_alt.max(0.0).sqrt();
+        let sin_alt = (1. - cos2_alt).max(0.0).sqrt();
+        let azimuth = self.azimuth.0 + u2 * self.azimuth.1;
+        self.frame.direction(cos_alt, sin_alt, azimuth)
+    }
+
+    /// Cosine-weighted over the sampled ranges, so `cos(theta) / (PI * fraction)`
+    fn pdf(&self, direction: Vector3D) -> Float {
+        let cos = direction.get_normalized() * self.frame.normal;
+        if cos <= 0.0 || !self.in_range(direction) {
+            return 0.0;
+        }
+        cos / (PI * self.fraction)
+    }
+}
+
+/// The rectangle that a sensor stands for (e.g., its cell within a grid),
+/// centred on the sensor and lying on its plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TributaryArea {
+    /// One side of the rectangle
+    pub u: Vector3D,
+
+    /// The other side of the rectangle, perpendicular to `u`
+    pub v: Vector3D,
+}
+
+impl TributaryArea {
+    /// A horizontal rectangle of `dx` by `dy`, aligned with the axes
+    pub fn rectangle(dx: Float, dy: Float) -> Result<Self, String> {
+        for (name, value) in [("dx", dx), ("dy", dy)] {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(format!(
+                    "The sides of a tributary area must be positive, but {} is {}",
+                    name, value
+                ));
+            }
+        }
+        Ok(Self {
+            u: Vector3D::new(dx, 0., 0.),
+            v: Vector3D::new(0., dy, 0.),
+        })
+    }
+
+    /// The area of the rectangle
+    pub fn area(&self) -> Float {
+        self.u.cross(self.v).length()
+    }
+
+    /// The point of the rectangle centred on `centre` at the
+    /// coordinates `(u1, u2)`, each between 0 and 1
+    pub(crate) fn point(&self, centre: Point3D, u1: Float, u2: Float) -> Point3D {
+        centre + self.u * (u1 - 0.5) + self.v * (u2 - 0.5)
+    }
+}
+
+/// A sensor for which Daylight Coefficients are calculated
+#[derive(Debug, Clone)]
+pub struct SensorSpec {
+    /// The position and orientation of the sensor
+    pub ray: Ray3D,
+
+    /// The part of the hemisphere seen by the sensor. `None` means
+    /// that the whole hemisphere is visible.
+    pub mask: Option<AngularMask>,
+
+    /// The room or zone that the sensor belongs to, if any. This is
+    /// used for grouping results (see [`crate::zones`]).
+    pub zone: Option<String>,
+
+    /// The area that the sensor stands for, if any. When
+    /// [`DCOptions::jitter_origins`](crate::DCOptions::jitter_origins) is set, the
+    /// samples of the direct tracer start anywhere within it, which averages the
+    /// coefficients over the area.
+    pub area: Option<TributaryArea>,
+
+    /// The surface the sensor is mounted on (e.g., the roof under an irradiance
+    /// sensor), which its rays go through as if it were not there. Calculations
+    /// for incident radiation then do not count the light reflected by it, which
+    /// no other sensor needs to ignore. Only this crate's tracers (i.e., the direct
+    /// one and the simplified one of
+    /// [`DCSession::calc_reflectance_tallies`](crate::DCSession::calc_reflectance_tallies))
+    /// can do this, so the `DCFactory` refuses such sensors. If the host is only
+    /// one side of the surface (see [`ObjectId::with_side`]), the rays only go
+    /// through it when they hit that side.
+    pub exclude_host_surface: Option<ObjectId>,
+
+    /// The id of the sensor, for joining results across runs. `None` means
+    /// that it is hashed from the position, direction and zone of the sensor
+    /// (see [`SensorSpec::sensor_id`]).
+    pub id: Option<SensorId>,
+
+    /// The angular response of the sensor, if it is not ideal. Only this
+    /// crate's tracers (i.e., the direct one and the simplified one of
+    /// [`DCSession::calc_reflectance_tallies`](crate::DCSession::calc_reflectance_tallies))
+    /// can weigh their samples by it, so the `DCFactory` refuses such sensors.
+    pub angular_response: Option<ResponseCurve>,
+}
+
+impl From<Ray3D> for SensorSpec {
+    fn from(ray: Ray3D) -> Self {
+        Self {
+            ray,
+            mask: None,
+            zone: None,
+            area: None,
+            exclude_host_surface: None,
+            id: None,
+            angular_response: None,
+        }
+    }
+}
+
+impl SensorSpec {
+    /// Adds an [`AngularMask`] to the sensor
+    pub fn with_mask(mut self, mask: AngularMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    /// Sets the surface the sensor is mounted on (see
+    /// [`SensorSpec::exclude_host_surface`])
+    pub fn with_host_surface(mut self, host: ObjectId) -> Self {
+        self.exclude_host_surface = Some(host);
+        self
+    }
+
+    /// Sets the angular response of the sensor (see [`SensorSpec::angular_response`])
+    pub fn with_angular_response(mut self, response: ResponseCurve) -> Self {
+        self.angular_response = Some(response);
+        self
+    }
+
+    /// Whether the sensor has an angular response other than the ideal one
+    pub fn has_non_ideal_response(&self) -> bool {
+        self.angular_response
+            .as_ref()
+            .is_some_and(|r| !r.is_ideal())
+    }
+
+    /// Gives the sensor an id of its own (see [`SensorSpec::id`])
+    pub fn with_id(mut self, id: SensorId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// The id of the sensor: the one it was given, or else the one hashed from
+    /// its position, direction and zone (see [`SensorId::generated`])
+    pub fn sensor_id(&self) -> SensorId {
+        match &self.id {
+            Some(id) => id.clone(),
+            None => SensorId::generated(&self.ray, self.zone.as_deref()),
+        }
+    }
+
+    /// Tags the sensor as part of a zone
+    pub fn with_zone<S: Into<String>>(mut self, zone: S) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Sets the [`TributaryArea`] of the sensor, which must lie on the plane of
+    /// the sensor (i.e., its sides must be perpendicular to its direction)
+    pub fn with_area(mut self, area: TributaryArea) -> Result<Self, String> {
+        let normal = self.ray.direction.get_normalized();
+        for side in [area.u, area.v] {
+            if side.is_zero() || (side.get_normalized() * normal).abs() > 1e-6 {
+                return Err(
+                    "The sides of a tributary area must be perpendicular to the direction of the sensor"
+                        .to_string(),
+                );
+            }
+        }
+        if (area.u.get_normalized() * area.v.get_normalized()).abs() > 1e-6 {
+            return Err("The sides of a tributary area must be perpendicular".to_string());
+        }
+        self.area = Some(area);
+        Ok(self)
+    }
+
+    /// Casts a ray as seen by the sensor: hits on its host surface, if
+    /// any, are skipped, and the ray goes on from past them. The origin
+    /// of the ray is left as it was.
+    pub(crate) fn cast_ray(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        aux: &mut Vec<usize>,
+    ) -> Option<usize> {
+        self.cast_ray_counting(scene, ray, aux, &mut 0)
+    }
+
+    /// Like [`SensorSpec::cast_ray`], adding the number of times that the ray
+    /// went through the host surface to `crossings`
+    pub(crate) fn cast_ray_counting(
+        &self,
+        scene: &Scene,
+        ray: &mut Ray,
+        aux: &mut Vec<usize>,
+        crossings: &mut usize,
+    ) -> Option<usize> {
+        let host = match &self.exclude_host_surface {
+            Some(host) => host,
+            None => return scene.cast_ray(ray, aux),
+        };
+        let origin = ray.geometry.origin;
+        let mut hit = scene.cast_ray(ray, aux);
+        for _ in 0..MAX_HOST_CROSSINGS {
+            match hit {
+                Some(triangle) if host.is_hit(triangle, ray.geometry.direction) => {
+                    *crossings += 1;
+                    ray.geometry.origin =
+                        ray.interaction.point + ray.geometry.direction * HOST_OFFSET;
+                    hit = scene.cast_ray(ray, aux);
+                }
+                _ => break,
+            }
+        }
+        ray.geometry.origin = origin;
+        hit
+    }
+
+    /// Like [`SensorSpec::cast_ray_counting`], with a [`RayCaster`]
+    pub(crate) fn cast_with(
+        &self,
+        caster: &dyn RayCaster,
+        ray: &Ray3D,
+        crossings: &mut usize,
+    ) -> Option<Hit> {
+        self.past_host(caster, ray, caster.cast(ray), crossings)
+    }
+
+    /// Goes on through the host surface of the sensor, if any, from where a
+    /// `ray` first `hit` the scene, adding the number of times it went through
+    /// it to `crossings`
+    pub(crate) fn past_host(
+        &self,
+        caster: &dyn RayCaster,
+        ray: &Ray3D,
+        mut hit: Option<Hit>,
+        crossings: &mut usize,
+    ) -> Option<Hit> {
+        let host = match &self.exclude_host_surface {
+            Some(host) => host,
+            None => return hit,
+        };
+        for _ in 0..MAX_HOST_CROSSINGS {
+            match hit {
+                Some(h) if host.is_hit(h.triangle, ray.direction) => {
+                    *crossings += 1;
+                    hit = caster
+                        .cast(&Ray3D {
+                            origin: h.point + ray.direction * HOST_OFFSET,
+                            direction: ray.direction,
+                        })
+                        .map(|h| Hit {
+                            distance: h.point.distance(ray.origin),
+                            ..h
+                        });
+                }
+                _ => break,
+            }
+        }
+        hit
+    }
+
+    /// A grid of sensors facing up at a height `z`, covering the rectangle
+    /// that goes from `min` to `max` (as `(x, y)`) with cells of side `spacing`.
+    /// Sensors are at the centre of the cells, and the cells that do not fit
+    /// entirely in the rectangle are left out. Each cell is the
+    /// [`TributaryArea`] of its sensor.
+    pub fn horizontal_grid(
+        min: (Float, Float),
+        max: (Float, Float),
+        z: Float,
+        spacing: Float,
+    ) -> Result<Vec<Self>, String> {
+        if !spacing.is_finite() || spacing <= 0.0 {
+            return Err(format!(
+                "The spacing of a grid of sensors must be positive, but found {}",
+                spacing
+            ));
+        }
+        let n_cells = |from: Float, to: Float| ((to - from) / spacing + 1e-6).floor().max(0.0);
+        let (nx, ny) = (n_cells(min.0, max.0), n_cells(min.1, max.1));
+        if nx < 1. || ny < 1. {
+            return Err(format!(
+                "A grid from {:?} to {:?} is too small for a spacing of {}",
+                min, max, spacing
+            ));
+        }
+        // Centre the cells within the rectangle
+        let x0 = 0.5 * (min.0 + max.0 - (nx - 1.) * spacing);
+        let y0 = 0.5 * (min.1 + max.1 - (ny - 1.) * spacing);
+        let area = TributaryArea::rectangle(spacing, spacing)?;
+        let mut ret = Vec::new();
+        for j in 0..ny as usize {
+            for i in 0..nx as usize {
+                let mut sensor = Self::from(Ray3D {
+                    origin: Point3D::new(x0 + i as Float * spacing, y0 + j as Float * spacing, z),
+                    direction: Vector3D::new(0., 0., 1.),
+                });
+                sensor.area = Some(area);
+                ret.push(sensor);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    #[test]
+    fn test_local_frame() {
+        // Looking up: North is 0, East is 90
+        let frame = LocalFrame::new(Vector3D::new(0., 0., 1.)).unwrap();
+        let (alt, azi) = frame.altitude_azimuth(Vector3D::new(0., 1., 1.));
+        assert_close!(alt, 45., 1e-4);
+        assert_close!(azi, 0., 1e-4);
+        let (_, azi) = frame.altitude_azimuth(Vector3D::new(1., 0., 0.1));
+        assert_close!(azi, 90., 1e-4);
+
+        // Looking South: up is 0, East is 90
+        let frame = LocalFrame::new(Vector3D::new(0., -1., 0.)).unwrap();
+        let (_, azi) = frame.altitude_azimuth(Vector3D::new(0., -1., 1.));
+        assert_close!(azi, 0., 1e-4);
+        let (_, azi) = frame.altitude_azimuth(Vector3D::new(1., -1., 0.));
+        assert_close!(azi, 90., 1e-4);
+
+        assert!(LocalFrame::new(Vector3D::new(0., 0., 0.)).is_err());
+    }
+
+    #[test]
+    fn test_horizontal_grid() {
+        let grid = SensorSpec::horizontal_grid((0., 0.), (4., 3.), 0.8, 1.).unwrap();
+        assert_eq!(grid.len(), 12);
+        assert_eq!(grid[0].ray.origin, Point3D::new(0.5, 0.5, 0.8));
+        assert_eq!(grid[11].ray.origin, Point3D::new(3.5, 2.5, 0.8));
+        assert!(grid
+            .iter()
+            .all(|s| s.ray.direction == Vector3D::new(0., 0., 1.)));
+
+        // Cells that do not fit are dropped, and the rest are centred
+        let grid = SensorSpec::horizontal_grid((0., 0.), (2.5, 1.), 0., 1.).unwrap();
+        assert_eq!(grid.len(), 2);
+        assert_close!(grid[0].ray.origin.x, 0.75, 1e-6);
+        assert_close!(grid[1].ray.origin.x, 1.75, 1e-6);
+
+        assert!(SensorSpec::horizontal_grid((0., 0.), (0.5, 4.), 0., 1.).is_err());
+        assert!(SensorSpec::horizontal_grid((0., 0.), (4., 4.), 0., 0.).is_err());
+
+        // Each sensor stands for its cell
+        let area = grid[0].area.unwrap();
+        assert_close!(area.area(), 1., 1e-6);
+        let corner = area.point(grid[0].ray.origin, 0., 1.);
+        assert_eq!(corner, Point3D::new(0.25, 1., 0.));
+    }
+
+    #[test]
+    fn test_tributary_area() {
+        let sensor = SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(0., -1., 0.),
+        });
+        // Not on the plane of the sensor
+        let horizontal = TributaryArea::rectangle(1., 2.).unwrap();
+        assert!(sensor.clone().with_area(horizontal).is_err());
+
+        let vertical = TributaryArea {
+            u: Vector3D::new(2., 0., 0.),
+            v: Vector3D::new(0., 0., 0.5),
+        };
+        let sensor = sensor.with_area(vertical).unwrap();
+        assert_close!(sensor.area.unwrap().area(), 1., 1e-6);
+        let skewed = TributaryArea {
+            u: Vector3D::new(1., 0., 0.),
+            v: Vector3D::new(1., 0., 1.),
+        };
+        assert!(sensor.with_area(skewed).is_err());
+        assert!(TributaryArea::rectangle(0., 1.).is_err());
+    }
+
+    #[test]
+    fn test_mask_visibility() {
+        assert!(AngularMask::range(10., 5., 0., 90.).is_err());
+        assert!(AngularMask::range(0., 95., 0., 90.).is_err());
+        assert!(AngularMask::range(0., 90., 30., 30.).is_err());
+
+        // The upper half of a South-facing sensor, wrapping around 0
+        let up_only = AngularMask::range(0., 90., 270., 90.).unwrap();
+        let south = Vector3D::new(0., -1., 0.);
+        assert!(up_only
+            .is_visible(south, Vector3D::new(0., -1., 0.5))
+            .unwrap());
+        assert!(!up_only
+            .is_visible(south, Vector3D::new(0., -1., -0.5))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_sampler_respects_mask() {
+        let normal = Vector3D::new(0., -1., 0.);
+        let mask = AngularMask::range(20., 60., 270., 90.).unwrap();
+        let sampler = DirectionSampler::new(normal, Some(&mask)).unwrap();
+        let mut rng = rendering::rand::get_rng();
+        for _ in 0..1000 {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            assert!(weight > 0.0);
+            assert!(mask.is_visible(normal, dir).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_min_cosine() {
+        // The band below the minimum cosine holds min_cosine² of the cosine
+        let min_cosine = 0.2;
+        let sampler = DirectionSampler::new(Vector3D::new(0., 0., 1.), None)
+            .unwrap()
+            .with_min_cosine(min_cosine);
+        let mut rng = crate::rng::SensorRng::new(3, 0);
+        let n = 200_000;
+        let (mut kept, mut clipped) = (0.0, 0.0);
+        for _ in 0..n {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            assert!(weight.is_finite());
+            let cos = dir * sampler.normal();
+            if cos < min_cosine {
+                assert_eq!(weight, 0.0);
+                assert_eq!(sampler.integrand(dir), 0.0);
+                assert_close!(sampler.grazing(dir), cos, 1e-6);
+            } else {
+                assert_eq!(sampler.clipped(dir), 0.0);
+            }
+            kept += weight;
+            clipped += sampler.clipped(dir);
+        }
+        let band = min_cosine * min_cosine;
+        assert_close!(kept / n as Float, PI * (1. - band), 0.01);
+        assert_close!(clipped / n as Float, PI * band, 0.01);
+
+        // The density is that of the whole hemisphere
+        assert!(crate::sampling::chi2_sampler_test(&sampler, 4000, 7).is_ok());
+    }
+
+    #[test]
+    fn test_response_curve() {
+        // Falling linearly from the normal to the plane of the sensor, which
+        // halves the irradiance of a uniform sky
+        let response = ResponseCurve::new(vec![(0., 1.), (90., 0.)]).unwrap();
+        assert!(!response.is_ideal());
+        assert_close!(response.factor(45.), 0.5, 1e-6);
+        let sampler = DirectionSampler::new(Vector3D::new(0., 0., 1.), None)
+            .unwrap()
+            .with_response(Some(&response));
+        let mut rng = crate::rng::SensorRng::new(3, 0);
+        let n = 200_000;
+        let mut total = 0.0;
+        for _ in 0..n {
+            let (dir, weight) = sampler.sample(rng.gen(), rng.gen());
+            let angle = dir.z.clamp(-1., 1.).acos().to_degrees();
+            assert_close!(weight, PI * (1. - angle / 90.), 1e-3);
+            total += weight;
+        }
+        assert_close!(total / n as Float, 0.5 * PI, 0.01);
+
+        // The ends are held beyond the curve
+        let flat = ResponseCurve::new(vec![(10., 1.), (70., 1.)]).unwrap();
+        assert!(flat.is_ideal());
+        assert_eq!(flat.factor(85.), 1.);
+
+        assert!(ResponseCurve::new(Vec::new()).is_err());
+        assert!(ResponseCurve::new(vec![(0., 1.), (95., 0.)]).is_err());
+        assert!(ResponseCurve::new(vec![(30., 1.), (30., 0.)]).is_err());
+        assert!(ResponseCurve::new(vec![(0., -0.1)]).is_err());
+    }
+}
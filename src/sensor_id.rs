@@ -0,0 +1,353 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Identifiers of sensors, for joining the results of different runs.
+//!
+//! Rows of results follow the order of the sensors they were calculated for,
+//! which stops meaning anything as soon as one of the runs filters or reorders
+//! its grid. Each sensor therefore has a [`SensorId`]: either one given by the
+//! user (see [`SensorSpec::with_id`](crate::SensorSpec::with_id)) or one
+//! hashed from its position, direction and zone. The latter is rounded to a
+//! tenth of a millimetre, so it does not change with the precision of
+//! [`Float`] or with the order of the other sensors. The ids end up in the
+//! [`RowMetadata`](crate::RowMetadata) of the matrices, and [`align`] uses them
+//! to put the rows of two results side by side.
+
+use crate::labeled_matrix::RowMetadata;
+use crate::manifest::Fnv;
+use crate::zones::csv_field;
+use crate::Float;
+use geometry3d::Ray3D;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The size (in metres) of the steps that positions are rounded to when hashed
+const POSITION_RESOLUTION: Float = 1e-4;
+
+/// The size of the steps that the components of normalised directions are
+/// rounded to when hashed
+const DIRECTION_RESOLUTION: Float = 1e-5;
+
+/// Identifies a sensor across runs (see the [module documentation](self))
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SensorId(String);
+
+impl SensorId {
+    /// An id given by the user. It cannot be empty, start or end with
+    /// whitespace, or contain control characters (e.g., new lines).
+    pub fn new<S: Into<String>>(id: S) -> Result<Self, String> {
+        let id = id.into();
+        if id.is_empty() || id.trim() != id || id.contains(char::is_control) {
+            return Err(format!(
+                "Sensor ids cannot be empty, have whitespace around them or contain control characters, but found {:?}",
+                id
+            ));
+        }
+        Ok(Self(id))
+    }
+
+    /// The id hashed from the position and direction of a sensor, and from
+    /// its zone. Sensors less than a tenth of a millimetre apart and facing the
+    /// same way usually get the same id, but this is not guaranteed when they stand
+    /// right between two steps.
+    pub fn generated(ray: &Ray3D, zone: Option<&str>) -> Self {
+        let mut h = Fnv::new();
+        let round = |v: Float, step: Float| -> i64 { (v / step).round() as i64 };
+        let (o, d) = (ray.origin, ray.direction.get_normalized());
+        for v in [o.x, o.y, o.z] {
+            h.write(&round(v, POSITION_RESOLUTION).to_le_bytes());
+        }
+        for v in [d.x, d.y, d.z] {
+            h.write(&round(v, DIRECTION_RESOLUTION).to_le_bytes());
+        }
+        match zone {
+            None => h.write(&[0]),
+            Some(z) => {
+                h.write(&[1]);
+                h.write_str(z);
+            }
+        }
+        Self(h.finish())
+    }
+
+    /// The id, as a string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for SensorId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Two results with their rows put side by side by [`align`]
+#[derive(Debug, Clone)]
+pub struct Alignment {
+    /// The rows of the first result that are also in the second one
+    pub a: Matrix,
+
+    /// The rows of the second result, in the same order as those of `a`
+    pub b: Matrix,
+
+    /// The id of each row of `a` and `b`
+    pub ids: Vec<SensorId>,
+
+    /// The sensors of the first result that are not in the second one
+    pub only_in_a: Vec<SensorId>,
+
+    /// The sensors of the second result that are not in the first one
+    pub only_in_b: Vec<SensorId>,
+}
+
+impl Alignment {
+    /// Whether every sensor of each result was found in the other one
+    pub fn is_complete(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty()
+    }
+
+    /// The aligned matrices
+    pub fn into_matrices(self) -> (Matrix, Matrix) {
+        (self.a, self.b)
+    }
+}
+
+/// Indexes the rows of a matrix by their ids, checking that there is one per
+/// row and that none of them is repeated
+fn index_rows<'a>(
+    what: &str,
+    m: &Matrix,
+    ids: &'a [SensorId],
+) -> Result<HashMap<&'a SensorId, usize>, String> {
+    let (nrows, _) = m.size();
+    if ids.len() != nrows {
+        return Err(format!(
+            "The {} matrix has {} rows, but {} sensor ids",
+            what,
+            nrows,
+            ids.len()
+        ));
+    }
+    let mut ret = HashMap::with_capacity(nrows);
+    for (r, id) in ids.iter().enumerate() {
+        if let Some(first) = ret.insert(id, r) {
+            return Err(format!(
+                "Sensor id '{}' is repeated in the {} matrix (rows {} and {})",
+                id, what, first, r
+            ));
+        }
+    }
+    Ok(ret)
+}
+
+/// Copies some rows of a matrix, in order
+fn pick_rows(m: &Matrix, rows: &[usize]) -> Result<Matrix, String> {
+    let (_, ncols) = m.size();
+    let mut ret = Matrix::new(0.0, rows.len(), ncols);
+    for (to, from) in rows.iter().enumerate() {
+        for c in 0..ncols {
+            ret.set(to, c, m.get(*from, c)?)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Puts the rows of two results (e.g., Daylight Coefficients or annual
+/// series) side by side, given the id of the sensor of each row. Only the
+/// sensors found in both are kept, in the order of `a`, and the rest are
+/// reported in the returned [`Alignment`]. Fails if an id is repeated within
+/// either result.
+pub fn align(
+    a: &Matrix,
+    a_ids: &[SensorId],
+    b: &Matrix,
+    b_ids: &[SensorId],
+) -> Result<Alignment, String> {
+    index_rows("first", a, a_ids)?;
+    let in_b = index_rows("second", b, b_ids)?;
+    let mut rows_a = Vec::with_capacity(a_ids.len());
+    let mut rows_b = Vec::with_capacity(a_ids.len());
+    let mut ids = Vec::with_capacity(a_ids.len());
+    let mut only_in_a = Vec::new();
+    for (r, id) in a_ids.iter().enumerate() {
+        match in_b.get(id) {
+            Some(rb) => {
+                rows_a.push(r);
+                rows_b.push(*rb);
+                ids.push(id.clone());
+            }
+            None => only_in_a.push(id.clone()),
+        }
+    }
+    let found: HashSet<&SensorId> = ids.iter().collect();
+    let only_in_b = b_ids
+        .iter()
+        .filter(|id| !found.contains(id))
+        .cloned()
+        .collect();
+    Ok(Alignment {
+        a: pick_rows(a, &rows_a)?,
+        b: pick_rows(b, &rows_b)?,
+        ids,
+        only_in_a,
+        only_in_b,
+    })
+}
+
+/// Writes the sensors of the `rows` of a matrix as CSV, with their id,
+/// position, direction and zone, one per line and in order. Results saved
+/// without their [`RowMetadata`] (e.g., as `.mtx` files) can be joined through it.
+pub fn sensors_csv(rows: &[RowMetadata]) -> String {
+    let mut ret = "id,x,y,z,dx,dy,dz,zone\n".to_string();
+    for row in rows {
+        let (o, d) = (row.ray.origin, row.ray.direction);
+        ret.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            csv_field(row.id.as_str()),
+            o.x,
+            o.y,
+            o.z,
+            d.x,
+            d.y,
+            d.z,
+            row.zone.as_deref().map_or(String::new(), csv_field)
+        ));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{DCOptions, DCSession, Material, SceneBuilder, SensorGrid, SensorSpec};
+    use geometry3d::{Point3D, Vector3D};
+
+    fn ray(x: Float, y: Float, z: Float, direction: Vector3D) -> Ray3D {
+        Ray3D {
+            origin: Point3D::new(x, y, z),
+            direction,
+        }
+    }
+
+    #[test]
+    fn test_generated() {
+        let up = Vector3D::new(0., 0., 1.);
+        let id = SensorId::generated(&ray(1.23, -4.5, 0.8, up), None);
+        // The same on every platform, and with either precision
+        assert_eq!(id.as_str(), "c949d051f51a3a7b");
+
+        // Rounded, and blind to the length of the direction
+        let close = ray(1.23 + 2e-6, -4.5, 0.8 - 2e-6, up * 3.);
+        assert_eq!(SensorId::generated(&close, None), id);
+
+        for other in [
+            SensorId::generated(&ray(1.23, -4.5, 0.8, up), Some("office")),
+            SensorId::generated(&ray(1.24, -4.5, 0.8, up), None),
+            SensorId::generated(&ray(1.23, -4.5, 0.8, Vector3D::new(0., 1., 0.)), None),
+        ] {
+            assert_ne!(other, id);
+        }
+
+        // Given ones
+        assert_eq!(SensorId::new("desk 1").unwrap().to_string(), "desk 1");
+        for bad in ["", " desk", "desk\n1"] {
+            assert!(SensorId::new(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        // A wall at X = 0, next to a floor
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("black", Material::plastic(0.))
+            .unwrap();
+        let wall = [(-5., 0.), (5., 0.), (5., 3.), (-5., 3.)].map(|(y, z)| Point3D::new(0., y, z));
+        builder.add_polygon("black", "wall", &wall).unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let floor =
+            [(0.5, -1.), (3.5, -1.), (3.5, 1.), (0.5, 1.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        let sensors = SensorGrid::new(&floor, &[], 1., 0.8)
+            .unwrap()
+            .with_zone("office")
+            .sensors();
+        assert_eq!(sensors.len(), 6);
+
+        let run = |sensors: &[SensorSpec], seed: u64| {
+            let options = DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 20000,
+                seed,
+                ..DCOptions::default()
+            };
+            DCSession::new(1, options)
+                .calc_sensor_dc(sensors, &scene)
+                .unwrap()
+        };
+        let before = run(&sensors, 1);
+
+        // The second run drops two sensors and reverses the rest
+        let mut kept: Vec<SensorSpec> = sensors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 1)
+            .map(|(_, s)| s.clone())
+            .collect();
+        kept.reverse();
+        let after = run(&kept, 2);
+        assert_eq!(after.rows.len(), 4);
+
+        let aligned = before.align(&after).unwrap();
+        assert!(!aligned.is_complete());
+        assert_eq!(aligned.ids.len(), 4);
+        assert_eq!(
+            aligned.only_in_a,
+            vec![sensors[1].sensor_id(), sensors[4].sensor_id()]
+        );
+        assert!(aligned.only_in_b.is_empty());
+
+        // Each pair of rows is the same sensor, which sees less sky the closer it is to the wall
+        let sum =
+            |m: &Matrix, r: usize| -> Float { (0..m.size().1).map(|c| m.get(r, c).unwrap()).sum() };
+        for (r, id) in aligned.ids.iter().enumerate() {
+            let row = before.rows.iter().position(|row| &row.id == id).unwrap();
+            assert_eq!(sum(&aligned.a, r), sum(&before.matrix, row));
+            let (a, b) = (sum(&aligned.a, r), sum(&aligned.b, r));
+            assert!((a - b).abs() < 0.02 * a, "{} vs {}", a, b);
+        }
+        let (a, b) = aligned.into_matrices();
+        assert!((sum(&a, 0) - sum(&b, 3)).abs() > 0.1 * sum(&a, 0));
+
+        // Ids that do not fit the matrices
+        let ids = before.ids();
+        assert!(align(&before.matrix, &ids[1..], &after.matrix, &after.ids()).is_err());
+        let mut repeated = after.ids();
+        repeated[1] = repeated[0].clone();
+        let err = align(&before.matrix, &ids, &after.matrix, &repeated).unwrap_err();
+        assert!(err.contains("repeated"), "{}", err);
+
+        let csv = sensors_csv(&after.rows);
+        assert_eq!(csv.lines().count(), 5);
+        assert!(csv.lines().nth(1).unwrap().ends_with(",office"));
+    }
+}
@@ -0,0 +1,490 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Fast evaluations of a single sensor under many skies, for interactive
+//! tools (e.g., a dashboard with sliders for the position of the sun and
+//! the cloudiness of the sky).
+//!
+//! A [`SensorResponse`] keeps the Daylight Coefficients of one sensor next to
+//! whatever the sky model needs of each patch, in contiguous arrays, so the
+//! Perez sky is never built: its luminance is weighted by the coefficients
+//! and normalised in a single pass over the patches, without allocating.
+//! [`perez_sky_vec`] builds the same sky as a sky vector, for the general path.
+//!
+//! The sky follows the Perez all-weather model of luminance distribution
+//! (Perez, Seals and Michalsky, 1993), normalised so that it produces the
+//! diffuse horizontal irradiance, and the sun goes into the patch whose
+//! centre is closest to it (as `PerezSky` does). The ground bin is a uniform
+//! ground of a certain albedo, lit by the sky and the sun.
+
+use crate::sky::{reinhart_rows, sky_direction, SkyBasis};
+use crate::sky_matrix::DEFAULT_GROUND_ALBEDO;
+use crate::{Float, PI};
+use geometry3d::Vector3D;
+use matrix::Matrix;
+
+/// The upper limits of the sky clearness `ε` of the first seven categories of
+/// the Perez model. The eighth one has no upper limit.
+const CLEARNESS_LIMITS: [Float; 7] = [1.065, 1.23, 1.5, 1.95, 2.8, 4.5, 6.2];
+
+/// The coefficients `[x1, x2, x3, x4]` of each of the parameters `a` to `e` of
+/// the Perez model, for each category of clearness. Each parameter is
+/// `x1 + x2 Z + Δ (x3 + x4 Z)`, except for `c` and `d` in the first category.
+const PEREZ_COEFFICIENTS: [[[Float; 4]; 5]; 8] = [
+    [
+        [1.3525, -0.2576, -0.269, -1.4366],
+        [-0.767, 0.0007, 1.2734, -0.1233],
+        [2.8, 0.6004, 1.2375, 1.],
+        [1.8734, 0.6297, 0.9738, 0.2809],
+        [0.0356, -0.1246, -0.5718, 0.9938],
+    ],
+    [
+        [-1.2219, -0.773, 1.4148, 1.1016],
+        [-0.2054, 0.0367, -3.9128, 0.9156],
+        [6.975, 0.1774, 6.4477, -0.1239],
+        [-1.5798, -0.5081, -1.7812, 0.108],
+        [0.2624, 0.0672, -0.219, -0.4285],
+    ],
+    [
+        [-1.1, -0.2515, 0.8952, 0.0156],
+        [0.2782, -0.1812, -4.5, 1.1766],
+        [24.7219, -13.0812, -37.7, 34.8438],
+        [-5., 1.5218, 3.9229, -2.6204],
+        [-0.0156, 0.1597, 0.4199, -0.5562],
+    ],
+    [
+        [-0.5484, -0.6654, -0.2672, 0.7117],
+        [0.7234, -0.6219, -5.6812, 2.6297],
+        [33.3389, -18.3, -62.25, 52.0781],
+        [-3.5, 0.0016, 1.1477, 0.1062],
+        [0.4659, -0.3296, -0.0876, -0.0329],
+    ],
+    [
+        [-0.6, -0.3566, -2.5, 2.325],
+        [0.2937, 0.0496, -5.6812, 1.8415],
+        [21., -4.7656, -21.5906, 7.2492],
+        [-3.5, -0.1554, 1.4062, 0.3988],
+        [0.0032, 0.0766, -0.0656, -0.1294],
+    ],
+    [
+        [-1.0156, -0.367, 1.0078, 1.4051],
+        [0.2875, -0.5328, -3.85, 3.375],
+        [14., -0.9999, -7.1406, 7.5469],
+        [-3.4, -0.1078, -1.075, 1.5702],
+        [-0.0672, 0.4016, 0.3017, -0.4844],
+    ],
+    [
+        [-1., 0.0211, 0.5025, -0.5119],
+        [-0.3, 0.1922, 0.7023, -1.6317],
+        [19., -5., 1.2438, -1.9094],
+        [-4., 0.025, 0.3844, 0.2656],
+        [-1.05, 0.0289, 0.426, 0.359],
+    ],
+    [
+        [-1.05, 0.0289, 0.426, 0.359],
+        [-0.325, 0.1156, 0.7781, 0.0025],
+        [31.0625, -14.5, -46.1148, 55.375],
+        [-7.2312, 0.405, 13.35, 0.6234],
+        [1.5, -0.6426, 1.8564, 0.5636],
+    ],
+];
+
+/// The irradiance outside the atmosphere, in W/m², with which the
+/// brightness of the sky is calculated
+const SOLAR_CONSTANT: Float = 1367.;
+
+/// The range of brightness `Δ` of the sky within which the model is
+/// used, as `gendaylit` does. Beyond it, the luminance of low skies can
+/// become negative everywhere.
+const BRIGHTNESS_RANGE: (Float, Float) = (0.01, 0.6);
+
+/// The parameters `a` to `e` of the Perez model for a sun at a certain
+/// `zenith` angle (in radians)
+fn perez_parameters(zenith: Float, dni: Float, dhi: Float) -> [Float; 5] {
+    let k = 1.041 * zenith.powi(3);
+    let clearness = ((dhi + dni) / dhi + k) / (1. + k);
+    let air_mass = 1. / (zenith.cos() + 0.15 * (93.885 - zenith.to_degrees()).powf(-1.253));
+    let brightness =
+        (dhi * air_mass / SOLAR_CONSTANT).clamp(BRIGHTNESS_RANGE.0, BRIGHTNESS_RANGE.1);
+    let category = CLEARNESS_LIMITS
+        .iter()
+        .position(|limit| clearness < *limit)
+        .unwrap_or(CLEARNESS_LIMITS.len());
+    let x = &PEREZ_COEFFICIENTS[category];
+    let mut ret = x.map(|x| x[0] + x[1] * zenith + brightness * (x[2] + x[3] * zenith));
+    if category == 0 {
+        let (c, d) = (x[2], x[3]);
+        ret[2] = (brightness * (c[0] + c[1] * zenith)).powf(c[2]).exp() - c[3];
+        ret[3] = -(brightness * (d[0] + d[1] * zenith)).exp() + d[2] + brightness * d[3];
+    }
+    ret
+}
+
+/// The part of the relative luminance of the Perez model with certain
+/// `parameters` that depends on the cosine of the zenith angle (`cos_theta`)
+fn perez_gradation(parameters: &[Float; 5], cos_theta: Float) -> Float {
+    1. + parameters[0] * (parameters[1] / cos_theta).exp()
+}
+
+/// The part of the relative luminance of the Perez model with certain
+/// `parameters` that depends on the angle `γ` from the sun (whose cosine is
+/// `cos_gamma`)
+fn perez_indicatrix(parameters: &[Float; 5], cos_gamma: Float) -> Float {
+    let [_, _, c, d, e] = *parameters;
+    1. + c * (d * cos_gamma.acos()).exp() + e * cos_gamma * cos_gamma
+}
+
+/// The relative luminance of the Perez model with certain `parameters`, at a
+/// point of the sky whose zenith angle has a cosine `cos_theta` and which is at
+/// an angle `γ` (whose cosine is `cos_gamma`) from the sun. Negative values,
+/// which the model produces for some skies, are taken as `0`.
+fn perez_luminance(parameters: &[Float; 5], cos_theta: Float, cos_gamma: Float) -> Float {
+    (perez_gradation(parameters, cos_theta) * perez_indicatrix(parameters, cos_gamma)).max(0.0)
+}
+
+/// Checks the arguments of a Perez sky, and returns the zenith angle of the sun
+/// (in radians) and its direction if it is up
+fn check_perez(
+    sun_altitude: Float,
+    sun_azimuth: Float,
+    dni: Float,
+    dhi: Float,
+) -> Result<(Float, Option<Vector3D>), String> {
+    if !sun_altitude.is_finite() || !sun_azimuth.is_finite() {
+        return Err(format!(
+            "The position of the sun must be finite, but found altitude {} and azimuth {}",
+            sun_altitude, sun_azimuth
+        ));
+    }
+    if !dni.is_finite() || !dhi.is_finite() || dni < 0.0 || dhi < 0.0 {
+        return Err(format!(
+            "The direct normal and diffuse horizontal irradiances must be non-negative numbers, but found {} and {}",
+            dni, dhi
+        ));
+    }
+    let zenith = (90. - sun_altitude.clamp(0., 90.)).to_radians();
+    let sun = (sun_altitude > 0.0 && dni > 0.0)
+        .then(|| sky_direction(sun_altitude.to_radians(), sun_azimuth.to_radians()));
+    Ok((zenith, sun))
+}
+
+/// Builds the sky vector of a Perez sky (see the module documentation)
+/// with the sun at a certain `sun_altitude` and `sun_azimuth` (in degrees,
+/// from North towards East), and certain direct normal (`dni`) and diffuse
+/// horizontal (`dhi`) irradiances, in W/m². The ground bin is lit by both, and
+/// reflects a fraction `albedo` of them.
+///
+/// The sky vector is in the same units as the irradiances (i.e., W/m²/sr).
+pub fn perez_sky_vec(
+    basis: &SkyBasis,
+    sun_altitude: Float,
+    sun_azimuth: Float,
+    dni: Float,
+    dhi: Float,
+    albedo: Float,
+) -> Result<Matrix, String> {
+    let (zenith, sun) = check_perez(sun_altitude, sun_azimuth, dni, dhi)?;
+    let centroids = basis.centroids();
+    let towards = sun.unwrap_or_else(|| sky_direction(0., sun_azimuth.to_radians()));
+    let parameters = perez_parameters(zenith, dni, dhi);
+    let relative: Vec<Float> = centroids
+        .iter()
+        .enumerate()
+        .map(|(bin, dir)| {
+            if bin == SkyBasis::GROUND_BIN {
+                0.0
+            } else if dhi > 0.0 {
+                perez_luminance(&parameters, dir.z, (*dir * towards).clamp(-1., 1.))
+            } else {
+                1.
+            }
+        })
+        .collect();
+    let mut ret = basis.scaled_sky_vec(&relative, dhi, albedo)?;
+    if let Some(sun) = sun {
+        let mut nearest = (Float::MIN, 0);
+        for (bin, dir) in centroids.iter().enumerate().skip(1) {
+            let cos = *dir * sun;
+            if cos > nearest.0 {
+                nearest = (cos, bin);
+            }
+        }
+        let bin = nearest.1;
+        let omega = basis.solid_angles()[bin];
+        ret.set(bin, 0, ret.get(bin, 0)? + dni / omega)?;
+        let ground = SkyBasis::GROUND_BIN;
+        ret.set(ground, 0, ret.get(ground, 0)? + albedo * dni * sun.z / PI)?;
+    }
+    Ok(ret)
+}
+
+/// The Daylight Coefficients of a single sensor, laid out for evaluating
+/// them against many skies quickly (see the module documentation)
+#[derive(Debug, Clone)]
+pub struct SensorResponse {
+    basis: SkyBasis,
+    albedo: Float,
+    /// The coefficient of the ground bin
+    ground: Float,
+    /// The coefficient of each sky patch (i.e., every bin but the ground)
+    coefficients: Vec<Float>,
+    /// The components of the direction of the centre of each patch
+    x: Vec<Float>,
+    y: Vec<Float>,
+    z: Vec<Float>,
+    /// The solid angle of each patch times the cosine of its zenith angle,
+    /// for normalising the sky to its horizontal irradiance
+    horizontal: Vec<Float>,
+    /// The coefficient of each patch divided by its solid angle, for the sun
+    sun_coefficients: Vec<Float>,
+    /// The first and last (excluded) patch of each row of the sky, all of
+    /// which share a zenith angle
+    rows: Vec<(usize, usize)>,
+}
+
+impl SensorResponse {
+    /// Prepares a row of Daylight Coefficients (i.e., a matrix with a single row
+    /// and one column per bin of the `basis`). The ground reflects
+    /// [`DEFAULT_GROUND_ALBEDO`] until [`SensorResponse::with_albedo`] says otherwise.
+    pub fn new(dc_row: &Matrix, basis: SkyBasis) -> Result<Self, String> {
+        basis.check_dc(dc_row)?;
+        let (nrows, ncols) = dc_row.size();
+        if nrows != 1 {
+            return Err(format!(
+                "A sensor response needs a single row of Daylight Coefficients, but found {}",
+                nrows
+            ));
+        }
+        let row = (0..ncols)
+            .map(|c| dc_row.get(0, c))
+            .collect::<Result<Vec<Float>, String>>()?;
+        let centroids = basis.centroids();
+        let omegas = basis.solid_angles();
+        let patches = 1..ncols;
+        let mut rows = Vec::new();
+        let mut start = 0;
+        for (_, _, n_patches) in reinhart_rows(basis.mf()) {
+            rows.push((start, start + n_patches));
+            start += n_patches;
+        }
+        Ok(Self {
+            basis,
+            albedo: DEFAULT_GROUND_ALBEDO,
+            ground: row[SkyBasis::GROUND_BIN],
+            coefficients: row[patches.clone()].to_vec(),
+            x: centroids[patches.clone()].iter().map(|d| d.x).collect(),
+            y: centroids[patches.clone()].iter().map(|d| d.y).collect(),
+            z: centroids[patches.clone()].iter().map(|d| d.z).collect(),
+            horizontal: patches
+                .clone()
+                .map(|bin| omegas[bin] * centroids[bin].z)
+                .collect(),
+            sun_coefficients: patches.map(|bin| row[bin] / omegas[bin]).collect(),
+            rows,
+        })
+    }
+
+    /// Sets the fraction of the light that the ground of the Perez skies reflects
+    pub fn with_albedo(mut self, albedo: Float) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&albedo) {
+            return Err(format!(
+                "The albedo must be between 0 and 1, but found {}",
+                albedo
+            ));
+        }
+        self.albedo = albedo;
+        Ok(self)
+    }
+
+    /// The discretisation of the sky of the coefficients
+    pub fn basis(&self) -> &SkyBasis {
+        &self.basis
+    }
+
+    /// The illuminance (or irradiance, or whatever the units of the sky are) of
+    /// the sensor under a sky vector (i.e., one row per bin of the basis and a
+    /// single column)
+    pub fn illuminance(&self, sky: &Matrix) -> Result<Float, String> {
+        self.basis.check_sky(sky)?;
+        if sky.size().1 != 1 {
+            return Err(format!(
+                "A sky vector has a single column, but found {}",
+                sky.size().1
+            ));
+        }
+        let mut ret = self.ground * sky.get(SkyBasis::GROUND_BIN, 0)?;
+        for (patch, c) in self.coefficients.iter().enumerate() {
+            ret += c * sky.get(patch + 1, 0)?;
+        }
+        Ok(ret)
+    }
+
+    /// The response of the sensor under the Perez sky of [`perez_sky_vec`], with
+    /// the ground albedo of the response, but without building it. The result is
+    /// in the units of the irradiances (i.e., W/m²), which is what the model needs
+    /// to tell how bright the sky is; a luminous efficacy turns it into an
+    /// illuminance.
+    pub fn illuminance_perez(
+        &self,
+        sun_altitude: Float,
+        sun_azimuth: Float,
+        dni: Float,
+        dhi: Float,
+    ) -> Result<Float, String> {
+        let (zenith, sun) = check_perez(sun_altitude, sun_azimuth, dni, dhi)?;
+        let towards = sun.unwrap_or_else(|| sky_direction(0., sun_azimuth.to_radians()));
+        let parameters = perez_parameters(zenith, dni, dhi);
+        let (mut weighted, mut horizontal) = (0.0, 0.0);
+        let mut nearest = (Float::MIN, 0);
+        for &(start, end) in &self.rows {
+            let gradation = perez_gradation(&parameters, self.z[start]);
+            for i in start..end {
+                let cos_gamma =
+                    self.x[i] * towards.x + self.y[i] * towards.y + self.z[i] * towards.z;
+                if cos_gamma > nearest.0 {
+                    nearest = (cos_gamma, i);
+                }
+                let luminance = if dhi > 0.0 {
+                    let indicatrix = perez_indicatrix(&parameters, cos_gamma.clamp(-1., 1.));
+                    (gradation * indicatrix).max(0.0)
+                } else {
+                    1.
+                };
+                weighted += self.coefficients[i] * luminance;
+                horizontal += self.horizontal[i] * luminance;
+            }
+        }
+        if horizontal <= 0.0 {
+            return Err("The sky does not light an unobstructed horizontal plane".to_string());
+        }
+        let mut global = dhi;
+        let mut ret = dhi * weighted / horizontal;
+        if let Some(sun) = sun {
+            global += dni * sun.z;
+            ret += dni * self.sun_coefficients[nearest.1];
+        }
+        Ok(ret + self.ground * self.albedo * global / PI)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::annual::apply_annual;
+    use validate::assert_close;
+
+    fn pseudo_random_row(basis: &SkyBasis, seed: usize) -> Matrix {
+        let mut m = Matrix::new(0.0, 1, basis.n_bins());
+        for c in 0..basis.n_bins() {
+            let v = ((c * 104729 + seed) % 1000) as Float / 1000.;
+            m.set(0, c, v).unwrap();
+        }
+        m
+    }
+
+    /// The coefficients of an unobstructed sensor facing up
+    fn unobstructed(basis: &SkyBasis) -> Matrix {
+        let mut m = Matrix::new(0.0, 1, basis.n_bins());
+        for (bin, (dir, omega)) in basis
+            .centroids()
+            .iter()
+            .zip(basis.solid_angles())
+            .enumerate()
+            .skip(1)
+        {
+            m.set(0, bin, dir.z * omega).unwrap();
+        }
+        m
+    }
+
+    #[test]
+    fn test_matches_general_path() {
+        let basis = SkyBasis::new(2).unwrap();
+        let dc = pseudo_random_row(&basis, 7);
+        let response = SensorResponse::new(&dc, basis)
+            .unwrap()
+            .with_albedo(0.3)
+            .unwrap();
+        for (altitude, azimuth, dni, dhi) in [
+            (60., 180., 800., 100.),
+            (25., 95., 300., 250.),
+            (5., 270., 20., 60.),
+            (40., 10., 0., 400.),
+            (-3., 0., 0., 15.),
+        ] {
+            let sky = perez_sky_vec(&basis, altitude, azimuth, dni, dhi, 0.3).unwrap();
+            let expected = apply_annual(&dc, &sky).unwrap().get(0, 0).unwrap();
+            assert!(expected > 0.0);
+            let fast = response
+                .illuminance_perez(altitude, azimuth, dni, dhi)
+                .unwrap();
+            assert_close!(fast, expected, 1e-4 * expected);
+            let slow = response.illuminance(&sky).unwrap();
+            assert_close!(slow, expected, 1e-4 * expected);
+        }
+        assert!(response.illuminance(&Matrix::new(1., 10, 1)).is_err());
+        assert!(response.illuminance_perez(30., 0., -1., 100.).is_err());
+        assert!(response
+            .illuminance_perez(Float::NAN, 0., 1., 100.)
+            .is_err());
+        assert!(SensorResponse::new(&Matrix::new(0., 2, basis.n_bins()), basis).is_err());
+        assert!(SensorResponse::new(&dc, basis)
+            .unwrap()
+            .with_albedo(2.)
+            .is_err());
+    }
+
+    #[test]
+    fn test_unobstructed() {
+        let basis = SkyBasis::new(4).unwrap();
+        let dc = unobstructed(&basis);
+        let response = SensorResponse::new(&dc, basis).unwrap();
+        // The sky is normalised to its diffuse horizontal irradiance
+        let diffuse = response.illuminance_perez(35., 120., 0., 150.).unwrap();
+        assert_close!(diffuse, 150., 1e-3 * 150.);
+        // ... and the sun adds what it sends to a horizontal plane, give or take
+        // the size of its patch
+        let global = response.illuminance_perez(35., 120., 700., 150.).unwrap();
+        let beam = 700. * (35. as Float).to_radians().sin();
+        assert_close!(global - diffuse, beam, 0.05 * beam);
+        // ... unless it is below the horizon
+        let night = response.illuminance_perez(-5., 120., 700., 0.).unwrap();
+        assert_eq!(night, 0.0);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_sensor_response() {
+        let basis = SkyBasis::new(1).unwrap();
+        let dc = pseudo_random_row(&basis, 3);
+        let response = SensorResponse::new(&dc, basis).unwrap();
+        let n = 10_000;
+        let start = std::time::Instant::now();
+        let mut total = 0.0;
+        for i in 0..n {
+            let t = i as Float / n as Float;
+            total += response
+                .illuminance_perez(5. + 80. * t, 360. * t, 900. * t, 50. + 200. * (1. - t))
+                .unwrap();
+        }
+        println!("{} Perez skies with MF 1: {:?}", n, start.elapsed());
+        assert!(total > 0.0);
+    }
+}
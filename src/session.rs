@@ -0,0 +1,3905 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::direct::{
+    direct_dc_row, direct_dc_rows, direct_environment_irradiance, report_enclosed, DirectRow,
+    TraceHints,
+};
+use crate::environment::{EscapeRadiance, SkyRadiance};
+use crate::events::{EventKind, EventLog};
+use crate::horizon::HorizonProfile;
+use crate::importance::ImportanceHints;
+use crate::labeled_matrix::{LabeledMatrix, RowMetadata};
+use crate::manifest::RunManifest;
+use crate::obstruction::{faces_into_surface, off_surface_origin};
+use crate::occupancy::OccupancyGrid;
+use crate::path_recorder::PathRecorder;
+use crate::progress::SampleCounter;
+use crate::ray_caster::{RayCaster, SharedCaster};
+use crate::ray_filter::RayFilter;
+use crate::ray_offset::RayOffset;
+use crate::resources::{
+    estimate_resources, n_threads, rays_per_sample, BudgetAction, RayBudget, RayCap,
+    ResourceEstimate, MIN_CAPPED_SAMPLES,
+};
+use crate::rng::{SampleStream, SamplingSequence};
+use crate::sampling::DEFAULT_MAX_RESAMPLES;
+use crate::scene_loading::SceneReport;
+use crate::sensitivity::{diffuse_reflectance, material_responses, LambertianTracer, Response};
+use crate::sensor::SensorSpec;
+use crate::sky::SkyBasis;
+use crate::sky_matrix::GroundConvention;
+use crate::sparse::SparseMatrix;
+use crate::stats::{
+    report_culled, standard_error_from_moments, DCStats, NoiseFloor, SampleClamp, Welford,
+};
+use crate::two_sided::TwoSidedDC;
+use crate::Float;
+use geometry3d::{Point3D, Ray3D};
+use matrix::Matrix;
+use rendering::colour_matrix::colour_matrix_to_radiance;
+use rendering::{DCFactory, Scene};
+use serde::{Deserialize, Serialize};
+use solar::ReinhartSky;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The number of batches in which the `DCFactory` is run when statistics
+/// are requested from [`DCSession::calc_sensor_dc_with_stats`]
+pub const STATS_BATCHES: usize = 8;
+
+/// The hard limit on the number of bounces of a path when using
+/// [`TerminationPolicy::Throughput`]
+pub const MAX_SAFE_DEPTH: usize = 64;
+
+/// The largest subdivision of the Reinhart sky accepted by a [`DCSession`]
+pub const MAX_MF: usize = 12;
+
+/// How far in front of each sensor geometry is looked for by default (see
+/// [`DCOptions::probe_distance`]), in metres
+pub const DEFAULT_PROBE_DISTANCE: Float = 0.01;
+
+fn default_probe_distance() -> Float {
+    DEFAULT_PROBE_DISTANCE
+}
+
+/// How far in front of the surface they lie on sensors are moved by default
+/// (see [`DCOptions::surface_offset`]), in metres
+pub const DEFAULT_SURFACE_OFFSET: Float = 0.001;
+
+fn default_surface_offset() -> Float {
+    DEFAULT_SURFACE_OFFSET
+}
+
+/// The cosine with the normal of their sensor below which samples weigh
+/// nothing by default (see [`DCOptions::min_cosine`]), i.e., about 0.06°
+/// above its plane
+pub const DEFAULT_MIN_COSINE: Float = 1e-3;
+
+fn default_min_cosine() -> Float {
+    DEFAULT_MIN_COSINE
+}
+
+fn default_max_resamples() -> usize {
+    DEFAULT_MAX_RESAMPLES
+}
+
+/// The most rays that the `DCFactory` sends from each bounce by default (see
+/// [`DCOptions::max_branching`])
+pub const DEFAULT_MAX_BRANCHING: usize = 16;
+
+fn default_max_branching() -> Option<usize> {
+    Some(DEFAULT_MAX_BRANCHING)
+}
+
+/// The error of a calculation that needs the direct tracer for `what` (see
+/// [`DCOptions::max_depth`]), but bounces
+pub(crate) fn needs_direct_tracer(what: &str) -> String {
+    format!(
+        "Only the direct tracer (i.e., max_depth = 0) supports {}, as the bounces are traced by the DCFactory",
+        what
+    )
+}
+
+/// A parameter of a [`DCSession`] that is out of its valid range,
+/// as reported by [`DCSession::try_new`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DCError {
+    /// The name of the offending parameter (e.g., `n_ambient_samples`)
+    pub field: &'static str,
+
+    /// The value it had
+    pub value: String,
+
+    /// What was expected instead
+    pub expected: &'static str,
+}
+
+impl std::fmt::Display for DCError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid value of '{}': {} (expected {})",
+            self.field, self.value, self.expected
+        )
+    }
+}
+
+impl std::error::Error for DCError {}
+
+impl From<DCError> for String {
+    fn from(e: DCError) -> Self {
+        e.to_string()
+    }
+}
+
+impl DCError {
+    fn new(field: &'static str, value: impl std::fmt::Display, expected: &'static str) -> Self {
+        Self {
+            field,
+            value: value.to_string(),
+            expected,
+        }
+    }
+}
+
+/// Decides when paths stop bouncing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TerminationPolicy {
+    /// Paths bounce up to `max_depth` times
+    #[default]
+    FixedDepth,
+
+    /// Paths continue while their throughput—which bounds what they can still
+    /// contribute, as reflectances are never above one—is at least
+    /// `min_contribution`, up to [`MAX_SAFE_DEPTH`] bounces. This is passed to
+    /// the `DCFactory` as its `limit_weight`, and `max_depth` is ignored.
+    ///
+    /// The `DCFactory` does not say how deep its paths went, so no statistics of
+    /// the depths reached are available (e.g., in [`DCStats`]). Comparing against
+    /// a run with a fixed and larger `max_depth` tells whether the bounces were needed.
+    Throughput {
+        /// The smallest throughput worth following
+        min_contribution: Float,
+    },
+}
+
+/// How the tracer of this crate (e.g., [`DCSession::calc_aperture_dc`]) lets
+/// light through `glass`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GlazingModel {
+    /// Glass transmits and reflects as Radiance's `glass` does at the angle the
+    /// light arrives with, so less goes through at grazing incidence (e.g., when
+    /// the sun is low) and the rest is reflected specularly
+    #[default]
+    Angular,
+
+    /// Glass transmits its normal transmittance whatever the angle of
+    /// incidence, and reflects nothing, as this crate used to do
+    Constant,
+}
+
+/// The options used for calculating Daylight Coefficient matrices
+/// through a [`DCSession`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DCOptions {
+    /// The maximum number of bounces of each sample. Without bounces (i.e.,
+    /// `max_depth = 0`), samples are traced by the direct tracer of this crate.
+    /// The bounces are otherwise traced by the `DCFactory` of the `rendering`
+    /// crate, which samples the whole hemisphere of every sensor and knows
+    /// nothing of ray filters, importance hints, horizon profiles, escape
+    /// radiances, jittered origins, sample clamps, angular masks, angular
+    /// responses or host surfaces, so calculations with any of them are refused.
+    pub max_depth: usize,
+
+    /// The number of samples sent from each sensor
+    pub n_ambient_samples: usize,
+
+    /// The maximum size (in bytes) that a Daylight Coefficient matrix
+    /// is allowed to have. If the matrix is expected to be larger than this,
+    /// the calculation will refuse to start.
+    #[serde(default)]
+    pub memory_budget: Option<usize>,
+
+    /// How paths stop bouncing
+    #[serde(default)]
+    pub termination: TerminationPolicy,
+
+    /// The seed of the random numbers used by the direct tracer. Each
+    /// sensor gets its own stream, so results are reproducible.
+    #[serde(default)]
+    pub seed: u64,
+
+    /// The sequence used for sampling the directions seen from the sensors
+    /// by the direct tracer. The `DCFactory` always uses pseudo-random numbers.
+    #[serde(default)]
+    pub sampling: SamplingSequence,
+
+    /// Processes the sensors one after the other even if the `parallel` feature
+    /// is enabled. Results are the same either way, sample by sample and sum by
+    /// sum, so this only matters for debugging and profiling.
+    #[serde(default)]
+    pub serial: bool,
+
+    /// Removes the coefficients that are mostly noise once each sensor has been
+    /// traced (see [`NoiseFloor`]). This needs the standard error of each
+    /// coefficient, so with `max_depth > 0` the `DCFactory` is run in batches
+    /// as for [`DCSession::calc_sensor_dc_with_stats`]. It is off by default.
+    #[serde(default)]
+    pub noise_floor: Option<NoiseFloor>,
+
+    /// Starts each sample of the direct tracer at a random point of the
+    /// [`TributaryArea`](crate::TributaryArea) of its sensor, rather than at the
+    /// sensor itself, which gives the average coefficients over that area. The
+    /// points depend on the `seed`. Sensors without an area are not affected.
+    /// Only the direct tracer can do this.
+    #[serde(default)]
+    pub jitter_origins: bool,
+
+    /// How far along the direction of each sensor geometry is looked for before
+    /// the calculation. A sensor that finds a surface this close is most likely
+    /// facing into it (e.g., a workplane exported with its normals pointing down),
+    /// which is recorded as an [`EventKind::FacingIntoSurface`](crate::events::EventKind::FacingIntoSurface).
+    /// Zero disables the check.
+    #[serde(default = "default_probe_distance")]
+    pub probe_distance: Float,
+
+    /// Flips the sensors that face into a surface (see `probe_distance`) instead
+    /// of only reporting them
+    #[serde(default)]
+    pub auto_flip_into_surface: bool,
+
+    /// Sensors that lie on a surface—or within this distance behind it, along
+    /// their direction—are moved this far in front of it before the calculation,
+    /// which is recorded as an [`EventKind::OnSurface`](crate::events::EventKind::OnSurface).
+    /// Otherwise, whether the rays of a sensor placed exactly at the height of a
+    /// floor hit the floor depends on rounding, which shows as noisy stripes
+    /// across grids. Zero disables it.
+    #[serde(default = "default_surface_offset")]
+    pub surface_offset: Float,
+
+    /// Limits the contribution of each sample to the coefficients, suppressing
+    /// fireflies at the cost of some bias (see [`SampleClamp`]), which is reported
+    /// in [`DCStats::clamped`]. Only the direct tracer can do this, since the
+    /// bounces of the `DCFactory` happen within the `rendering` crate. It is off
+    /// by default.
+    #[serde(default)]
+    pub sample_clamp: Option<SampleClamp>,
+
+    /// The samples whose cosine with the normal of their sensor is below this
+    /// weigh nothing. Directions that graze the plane of a sensor carry almost
+    /// no energy, yet they are where rays run along the surfaces next to it
+    /// (e.g., a sensor on a wall) and where the weights of mixed densities are
+    /// least reliable. They make up a fraction `min_cosine²` of the
+    /// cosine-weighted hemisphere, which bounds the bias, and the most they
+    /// could have brought is reported in [`DCStats::grazing`]. Like `sample_clamp`, only
+    /// the direct tracer does this. Zero disables it.
+    #[serde(default = "default_min_cosine")]
+    pub min_cosine: Float,
+
+    /// How many times a sample whose direction comes out broken (i.e., not a
+    /// finite unit vector, see [`checked_direction`](crate::checked_direction))
+    /// is drawn again before it is skipped. Skipped samples are counted in
+    /// [`DCStats::skipped`] and count as dark ones. The bounces traced by the
+    /// rendering crate are not checked.
+    #[serde(default = "default_max_resamples")]
+    pub max_resamples: usize,
+
+    /// Limits the total number of rays of a calculation (see [`RayBudget`]). It
+    /// is checked by every calculation before starting, and enforced while tracing
+    /// by [`DCSession::calc_sensor_dc`] and [`DCSession::calc_sensor_dc_with_stats`],
+    /// for the rays of each call. The bounces of the `DCFactory` cannot be counted
+    /// from here, so with `max_depth > 0` it is run in batches as for
+    /// [`DCSession::calc_sensor_dc_with_stats`], and the budget is enforced between
+    /// them, as a lower bound (see [`RayBudget`]). It is off by default.
+    #[serde(default)]
+    pub ray_budget: Option<RayBudget>,
+
+    /// How the tracer of this crate lets light through `glass` (see
+    /// [`GlazingModel`]). It does not affect the `DCFactory`, whose glass is
+    /// that of the `rendering` crate.
+    #[serde(default)]
+    pub glazing: GlazingModel,
+
+    /// How far (in metres) rays start off the surfaces they leave, overriding
+    /// the one derived from the extent of the scene (see [`RayOffset`]). It
+    /// does not affect the `DCFactory`, which has its own. It is derived by
+    /// default.
+    #[serde(default)]
+    pub ray_offset: Option<Float>,
+
+    /// Limits how many rays the `DCFactory` sends from each point its paths
+    /// bounce at. It sends about `sqrt(n_ambient_samples × weight)` of them, where
+    /// the weight of a path is at most one, which in bright scenes (e.g., a
+    /// mirror-walled corridor) makes the cost grow much faster than the number of
+    /// samples. The branching cannot be changed from here, so the factory is run
+    /// several times with fewer samples (at most `max_branching² - 1`, which keeps
+    /// every branching at or below `max_branching`) and the runs are averaged,
+    /// weighted by their samples. Each run is unbiased, and so is their average.
+    /// The runs are reported in [`DCStats::factory_runs`]. It must be at least
+    /// `2`, and it is [`DEFAULT_MAX_BRANCHING`] by default (i.e., `255` samples
+    /// per run). `None` sends all the samples in a single run.
+    #[serde(default = "default_max_branching")]
+    pub max_branching: Option<usize>,
+}
+
+impl Default for DCOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            n_ambient_samples: 300,
+            memory_budget: None,
+            termination: TerminationPolicy::FixedDepth,
+            seed: 0,
+            sampling: SamplingSequence::Random,
+            serial: false,
+            noise_floor: None,
+            jitter_origins: false,
+            probe_distance: default_probe_distance(),
+            auto_flip_into_surface: false,
+            surface_offset: default_surface_offset(),
+            sample_clamp: None,
+            min_cosine: default_min_cosine(),
+            max_resamples: default_max_resamples(),
+            ray_budget: None,
+            glazing: GlazingModel::Angular,
+            ray_offset: None,
+            max_branching: default_max_branching(),
+        }
+    }
+}
+
+impl DCOptions {
+    /// The largest number of bounces that a path can do
+    pub fn max_bounces(&self) -> usize {
+        match self.termination {
+            TerminationPolicy::FixedDepth => self.max_depth,
+            TerminationPolicy::Throughput { .. } => MAX_SAFE_DEPTH,
+        }
+    }
+
+    /// Whether only the sky seen directly from the sensors is considered. Direct
+    /// calculations are performed by this crate's own tracer, which accepts some
+    /// features that the `DCFactory` does not (e.g., masks).
+    pub fn is_direct(&self) -> bool {
+        self.max_bounces() == 0
+    }
+
+    /// Checks that the options are within their valid ranges
+    pub fn validate(&self) -> Result<(), DCError> {
+        if self.n_ambient_samples == 0 {
+            return Err(DCError::new("n_ambient_samples", 0, "at least 1"));
+        }
+        if self.max_depth > MAX_SAFE_DEPTH {
+            return Err(DCError::new("max_depth", self.max_depth, "at most 64"));
+        }
+        if let TerminationPolicy::Throughput { min_contribution } = self.termination {
+            if !min_contribution.is_finite() || min_contribution < 0.0 {
+                return Err(DCError::new(
+                    "min_contribution",
+                    min_contribution,
+                    "a finite number, not below 0",
+                ));
+            }
+        }
+        if let Some(floor) = &self.noise_floor {
+            if floor.validate().is_err() {
+                return Err(DCError::new(
+                    "noise_floor",
+                    floor.k,
+                    "a finite number of standard errors, not below 0",
+                ));
+            }
+        }
+        if let Some(clamp) = &self.sample_clamp {
+            if clamp.validate().is_err() {
+                return Err(DCError::new(
+                    "sample_clamp",
+                    clamp.limit(),
+                    "a positive number",
+                ));
+            }
+        }
+        if let Some(budget) = &self.ray_budget {
+            if budget.max_rays == 0 {
+                return Err(DCError::new("max_rays", 0, "at least 1"));
+            }
+        }
+        if !self.probe_distance.is_finite() || self.probe_distance < 0.0 {
+            return Err(DCError::new(
+                "probe_distance",
+                self.probe_distance,
+                "a finite number, not below 0",
+            ));
+        }
+        if !self.surface_offset.is_finite() || self.surface_offset < 0.0 {
+            return Err(DCError::new(
+                "surface_offset",
+                self.surface_offset,
+                "a finite number, not below 0",
+            ));
+        }
+        if let Some(offset) = self.ray_offset {
+            if !(offset.is_finite() && offset > 0.0) {
+                return Err(DCError::new("ray_offset", offset, "a positive number"));
+            }
+        }
+        if !(0.0..1.0).contains(&self.min_cosine) {
+            return Err(DCError::new(
+                "min_cosine",
+                self.min_cosine,
+                "a number in [0, 1)",
+            ));
+        }
+        if let Some(max) = self.max_branching {
+            if max < 2 {
+                return Err(DCError::new("max_branching", max, "at least 2"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The Daylight Coefficients of a set of sensors, split by whether the light
+/// arrives straight from the sky or after bouncing off the scene, as calculated
+/// by [`DCSession::calc_externally_reflected_dc`]. Both matrices have one row
+/// per sensor and one column per sky bin.
+///
+/// Going through glass or straight through a semi-transparent material does not
+/// count as a bounce, so the sky seen through a window is part of the sky component.
+#[derive(Debug, Clone)]
+pub struct DCComponents {
+    /// The sky component, made of the paths that escape the scene
+    /// without bouncing
+    pub sky: Matrix,
+
+    /// The externally reflected component, made of the paths that bounce
+    /// at least once before escaping
+    pub externally_reflected: Matrix,
+}
+
+impl DCComponents {
+    /// The combined Daylight Coefficients (i.e., the sum of both components)
+    pub fn total(&self) -> Result<Matrix, String> {
+        let (nrows, ncols) = self.sky.size();
+        let mut total = self.sky.clone();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                total.set(
+                    r,
+                    c,
+                    self.sky.get(r, c)? + self.externally_reflected.get(r, c)?,
+                )?;
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// Calculates Daylight Coefficient matrices for a Reinhart sky of a certain
+/// subdivision, checking beforehand that the calculation is
+/// within the resources allowed by its [`DCOptions`].
+#[derive(Debug, Clone)]
+pub struct DCSession {
+    mf: usize,
+    options: DCOptions,
+    ray_filter: Option<RayFilter>,
+    importance: Option<ImportanceHints>,
+    horizon: Option<HorizonProfile>,
+    escape: EscapeRadiance,
+    samples: Option<Arc<SampleCounter>>,
+    occupancy: Option<OccupancyGrid>,
+    paths: Option<Arc<PathRecorder>>,
+    extent: Option<Float>,
+    caster: Option<SharedCaster>,
+}
+
+impl DCSession {
+    /// Creates a new `DCSession` for a Reinhart sky with subdivision `mf`
+    ///
+    /// # Panics
+    ///
+    /// If `mf` or the `options` are invalid (see [`DCSession::try_new`])
+    pub fn new(mf: usize, options: DCOptions) -> Self {
+        match Self::try_new(mf, options) {
+            Ok(session) => session,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Creates a new `DCSession` for a Reinhart sky with subdivision `mf`,
+    /// which must be between 1 and [`MAX_MF`], failing if any of the `options`
+    /// is out of range (see [`DCOptions::validate`]).
+    pub fn try_new(mf: usize, options: DCOptions) -> Result<Self, DCError> {
+        if !(1..=MAX_MF).contains(&mf) {
+            return Err(DCError::new("mf", mf, "between 1 and 12"));
+        }
+        options.validate()?;
+        Ok(Self {
+            mf,
+            options,
+            ray_filter: None,
+            importance: None,
+            horizon: None,
+            escape: EscapeRadiance::Unit,
+            samples: None,
+            occupancy: None,
+            paths: None,
+            extent: None,
+            caster: None,
+        })
+    }
+
+    /// Sets a [`RayFilter`], which decides which rays are traced. The
+    /// `DCFactory` cannot use it (see [`DCOptions::max_depth`]).
+    pub fn with_ray_filter(mut self, filter: RayFilter) -> Self {
+        self.ray_filter = Some(filter);
+        self
+    }
+
+    /// The [`RayFilter`] of the session, if any
+    pub fn ray_filter(&self) -> Option<&RayFilter> {
+        self.ray_filter.as_ref()
+    }
+
+    /// Sets the [`ImportanceHints`], which send part of the samples of each
+    /// sensor towards small but important objects. They need the direct tracer
+    /// (see [`DCOptions::max_depth`]).
+    pub fn with_importance_hints(mut self, hints: ImportanceHints) -> Self {
+        self.importance = Some(hints);
+        self
+    }
+
+    /// The [`ImportanceHints`] of the session, if any
+    pub fn importance_hints(&self) -> Option<&ImportanceHints> {
+        self.importance.as_ref()
+    }
+
+    /// Sets a [`HorizonProfile`]: distant obstructions that rays escaping the
+    /// scene can still hit. It needs the direct tracer (see
+    /// [`DCOptions::max_depth`]), but Daylight Coefficients calculated without
+    /// one can be combined with [`HorizonProfile::attenuate_sky`] instead.
+    pub fn with_horizon(mut self, horizon: HorizonProfile) -> Self {
+        self.horizon = Some(horizon);
+        self
+    }
+
+    /// The [`HorizonProfile`] of the session, if any
+    pub fn horizon(&self) -> Option<&HorizonProfile> {
+        self.horizon.as_ref()
+    }
+
+    /// Sets the radiance of the rays that escape the scene, which is
+    /// [`EscapeRadiance::Unit`] by default. With any other, each coefficient is
+    /// already weighted by the sky, so the irradiance is the sum of the row (and
+    /// multiplying by a sky vector would count the sky twice). Any other than
+    /// [`EscapeRadiance::Unit`] needs the direct tracer (see [`DCOptions::max_depth`]).
+    pub fn with_escape_radiance(mut self, escape: EscapeRadiance) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// The radiance of the rays that escape the scene
+    pub fn escape_radiance(&self) -> &EscapeRadiance {
+        &self.escape
+    }
+
+    /// Sets an [`OccupancyGrid`] of the scene, which lets the direct tracer skip
+    /// the rays that it proves to hit nothing. Results are exactly the same
+    /// without it. Calculations with a different scene fail, and the bounces of
+    /// the `DCFactory` do not use it.
+    pub fn with_occupancy_grid(mut self, grid: OccupancyGrid) -> Self {
+        self.occupancy = Some(grid);
+        self
+    }
+
+    /// The [`OccupancyGrid`] of the session, if any
+    pub fn occupancy_grid(&self) -> Option<&OccupancyGrid> {
+        self.occupancy.as_ref()
+    }
+
+    /// Makes the simplified tracer of the session record some of its paths in
+    /// `recorder`, which can be read once the calculations are done. The direct
+    /// tracer and the `DCFactory` do not record paths.
+    pub fn with_path_recorder(mut self, recorder: Arc<PathRecorder>) -> Self {
+        self.paths = Some(recorder);
+        self
+    }
+
+    /// The [`PathRecorder`] of the session, if any
+    pub(crate) fn path_recorder(&self) -> Option<&PathRecorder> {
+        self.paths.as_deref()
+    }
+
+    /// Makes the direct tracer cast the rays that leave the sensors with a
+    /// [`RayCaster`] instead of the `Scene`, gathering them into packets if it
+    /// supports them. It must hold the same geometry as the scenes given to the
+    /// calculations. Everything else (e.g., moving sensors off surfaces, or the
+    /// `DCFactory`) still uses the `Scene`.
+    pub fn with_ray_caster(mut self, caster: Arc<dyn RayCaster>) -> Self {
+        self.caster = Some(SharedCaster(caster));
+        self
+    }
+
+    /// Scales the offsets of rays, and the distances by which sensors are
+    /// moved off surfaces and geometry is probed, with the extent of the scene
+    /// described by `report` (see [`RayOffset`]). Without it, scenes are
+    /// assumed to be [`DEFAULT_SCENE_EXTENT`](crate::DEFAULT_SCENE_EXTENT)
+    /// across. Calculations that are given a report find the extent by
+    /// themselves.
+    pub fn with_scene_extent(mut self, report: &SceneReport) -> Self {
+        self.extent = Some(RayOffset::for_scene(report).extent);
+        self
+    }
+
+    /// How far rays start off surfaces, in the scene described by `report` or—if
+    /// there is none—in the one given to [`DCSession::with_scene_extent`], unless
+    /// [`DCOptions::ray_offset`] overrides it
+    pub fn ray_offset(&self, report: Option<&SceneReport>) -> RayOffset {
+        let mut ret = match (report, self.extent) {
+            (Some(report), _) => RayOffset::for_scene(report),
+            (None, Some(extent)) => RayOffset::for_extent(extent),
+            (None, None) => RayOffset::default(),
+        };
+        if let Some(base) = self.options.ray_offset {
+            ret.base = base;
+        }
+        ret
+    }
+
+    /// Makes the calculations of the session tally their primary samples in
+    /// `counter`, which can be followed from another thread (e.g., through an
+    /// [`EtaEstimator`](crate::EtaEstimator)) while they run
+    pub fn with_sample_counter(mut self, counter: Arc<SampleCounter>) -> Self {
+        self.samples = Some(counter);
+        self
+    }
+
+    /// Records that `actual` samples were (or will be) traced out of `planned`
+    fn revise_samples(&self, planned: usize, actual: usize) {
+        if let Some(counter) = &self.samples {
+            counter.revise(planned, actual);
+        }
+    }
+
+    /// Records that `n` samples were traced
+    pub(crate) fn count_samples(&self, n: usize) {
+        if let Some(counter) = &self.samples {
+            counter.trace(n);
+        }
+    }
+
+    /// The options of the direct tracer
+    pub(crate) fn trace_hints(&self) -> TraceHints<'_> {
+        TraceHints {
+            filter: self.ray_filter.as_ref(),
+            importance: self.importance.as_ref(),
+            jitter_seed: if self.options.jitter_origins {
+                Some(self.options.seed)
+            } else {
+                None
+            },
+            clamp: self.options.sample_clamp,
+            min_cosine: self.options.min_cosine,
+            max_resamples: self.options.max_resamples,
+            horizon: self.horizon.as_ref(),
+            escape: Some(&self.escape).filter(|e| !e.is_unit()),
+            occupancy: self.occupancy.as_ref(),
+            caster: self.caster.as_ref(),
+        }
+    }
+
+    /// Creates a new `DCSession` for a certain discretisation of the sky
+    pub fn from_basis(basis: SkyBasis, options: DCOptions) -> Self {
+        Self::new(basis.mf(), options)
+    }
+
+    /// The subdivision of the Reinhart sky
+    pub fn mf(&self) -> usize {
+        self.mf
+    }
+
+    /// The discretisation of the sky, which determines the columns of the
+    /// matrices calculated by this session. This is useful for allocating
+    /// results, or for checking the sky vectors, before running.
+    pub fn basis(&self) -> Result<SkyBasis, String> {
+        SkyBasis::new(self.mf)
+    }
+
+    /// The options of the session
+    pub fn options(&self) -> &DCOptions {
+        &self.options
+    }
+
+    /// Builds the `DCFactory` that performs the ray-tracing. It sends all the
+    /// samples at once, whatever the [`DCOptions::max_branching`].
+    pub fn factory(&self) -> DCFactory {
+        self.factory_with_samples(self.options.n_ambient_samples)
+    }
+
+    /// Builds a `DCFactory` that sends `n_ambient_samples` from each sensor
+    pub(crate) fn factory_with_samples(&self, n_ambient_samples: usize) -> DCFactory {
+        match self.options.termination {
+            TerminationPolicy::FixedDepth => DCFactory {
+                max_depth: self.options.max_depth,
+                n_ambient_samples,
+                reinhart: ReinhartSky::new(self.mf),
+                ..DCFactory::default()
+            },
+            TerminationPolicy::Throughput { min_contribution } => DCFactory {
+                max_depth: MAX_SAFE_DEPTH,
+                n_ambient_samples,
+                reinhart: ReinhartSky::new(self.mf),
+                limit_weight: min_contribution,
+                ..DCFactory::default()
+            },
+        }
+    }
+
+    /// How many samples each run of the `DCFactory` sends from each sensor when
+    /// `n_samples` are needed, so that it never branches more than
+    /// [`DCOptions::max_branching`]. There is a single run if that is not set.
+    pub(crate) fn factory_runs(&self, n_samples: usize) -> Vec<usize> {
+        let per_run = match self.options.max_branching {
+            Some(max) if n_samples > 0 => max * max - 1,
+            _ => return vec![n_samples],
+        };
+        let mut runs = vec![per_run; n_samples / per_run];
+        if !n_samples.is_multiple_of(per_run) {
+            runs.push(n_samples % per_run);
+        }
+        runs
+    }
+
+    /// The most rays that a run of the `DCFactory` with `n_samples` from each
+    /// sensor can send from a bounce, which happens when a path keeps all its weight
+    pub(crate) fn factory_branching(n_samples: usize) -> usize {
+        ((n_samples as Float).sqrt() + 0.5).round() as usize
+    }
+
+    /// Traces `rays` through the `DCFactory` with `n_samples` from each of them,
+    /// in as many runs as [`DCSession::factory_runs`] says, returning the
+    /// average of their colour matrices weighted by their samples
+    pub(crate) fn factory_dc(
+        &self,
+        rays: &[Ray3D],
+        scene: &Scene,
+        n_samples: usize,
+    ) -> Result<Matrix, String> {
+        let runs = self.factory_runs(n_samples);
+        if let [n] = runs[..] {
+            return Ok(self.factory_with_samples(n).calc_dc(rays, scene));
+        }
+        let mut ret: Option<Matrix> = None;
+        for n in runs {
+            let dc = self.factory_with_samples(n).calc_dc(rays, scene);
+            let w = n as Float / n_samples as Float;
+            let (nrows, ncols) = dc.size();
+            let sum = ret.get_or_insert_with(|| Matrix::new(0.0, nrows, ncols));
+            for r in 0..nrows {
+                for c in 0..ncols {
+                    sum.set(r, c, sum.get(r, c)? + w * dc.get(r, c)?)?;
+                }
+            }
+        }
+        ret.ok_or_else(|| "The DCFactory was not run".to_string())
+    }
+
+    /// Describes the rows produced by a set of sensors
+    fn row_metadata(sensors: &[SensorSpec], n_samples: usize) -> Vec<RowMetadata> {
+        sensors
+            .iter()
+            .map(|s| RowMetadata {
+                ray: s.ray,
+                mask: s.mask.clone(),
+                zone: s.zone.clone(),
+                n_samples,
+                id: s.sensor_id(),
+                angular_response: s.angular_response.clone(),
+            })
+            .collect()
+    }
+
+    /// Applies `f` to each sensor and its index—in parallel if the `parallel`
+    /// feature is enabled, unless the options ask for a serial calculation—and
+    /// returns the results in the order of the sensors.
+    pub(crate) fn map_sensors<T, F>(&self, sensors: &[SensorSpec], f: F) -> Result<Vec<T>, String>
+    where
+        T: Send,
+        F: Fn((usize, &SensorSpec)) -> Result<T, String> + Send + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        if !self.options.serial {
+            return sensors.par_iter().enumerate().map(f).collect();
+        }
+        sensors.iter().enumerate().map(f).collect()
+    }
+
+    /// The `DCFactory` branches its ambient samples at each bounce, so the rays of
+    /// the calculations that bounce through it cannot be bounded beforehand (see
+    /// [`RayBudget`]). Budgets that refuse to start when exceeded are thus refused
+    /// for them.
+    pub(crate) fn check_factory_budget(&self) -> Result<(), String> {
+        match self.options.ray_budget {
+            Some(budget) if budget.action == BudgetAction::Error && !self.options.is_direct() => {
+                Err(format!(
+                    "A ray budget of {} rays cannot be guaranteed when the bounces are traced by the DCFactory (i.e., when max_depth is {}), which branches its paths. Use BudgetAction::Warn, which caps the samples between batches as a lower bound.",
+                    budget.max_rays, self.options.max_depth
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the session has nothing that only the direct tracer can
+    /// handle (see [`DCOptions::max_depth`])
+    pub(crate) fn check_direct_only(&self) -> Result<(), String> {
+        let unsupported = [
+            (self.ray_filter.is_some(), "ray filters"),
+            (self.importance.is_some(), "importance hints"),
+            (self.horizon.is_some(), "horizon profiles"),
+            (!self.escape.is_unit(), "escape radiances other than Unit"),
+            (self.options.jitter_origins, "jittered origins"),
+            (self.options.sample_clamp.is_some(), "sample clamps"),
+        ];
+        match unsupported.iter().find(|(found, _)| *found) {
+            Some((_, what)) => Err(needs_direct_tracer(what)),
+            None => Ok(()),
+        }
+    }
+
+    /// Like [`DCSession::check_direct_only`], also checking that the `sensors`
+    /// have nothing that only the direct tracer can handle
+    pub(crate) fn check_factory_support(&self, sensors: &[SensorSpec]) -> Result<(), String> {
+        let unsupported = [
+            (sensors.iter().any(|s| s.mask.is_some()), "angular masks"),
+            (
+                sensors.iter().any(|s| s.has_non_ideal_response()),
+                "non-ideal angular responses",
+            ),
+            (
+                sensors.iter().any(|s| s.exclude_host_surface.is_some()),
+                "host surfaces",
+            ),
+        ];
+        if let Some((_, what)) = unsupported.iter().find(|(found, _)| *found) {
+            return Err(needs_direct_tracer(what));
+        }
+        self.check_direct_only()
+    }
+
+    /// Moves the sensors that lie on a surface off it (see
+    /// [`DCOptions::surface_offset`]) and then looks for geometry right in front
+    /// of each sensor (see [`DCOptions::probe_distance`]), recording the sensors
+    /// that face into a surface in `events` and flipping them if the options say so.
+    pub(crate) fn preflight<'a>(
+        &self,
+        sensors: &'a [SensorSpec],
+        scene: &Scene,
+        first_index: usize,
+        events: &mut EventLog,
+    ) -> Cow<'a, [SensorSpec]> {
+        let sensors = self.move_off_surfaces(sensors, scene, first_index, events);
+        let distance = self.ray_offset(None).nudge(self.options.probe_distance);
+        if distance <= 0.0 {
+            return sensors;
+        }
+        let facing: Vec<usize> = (0..sensors.len())
+            .filter(|i| faces_into_surface(scene, &sensors[*i], distance))
+            .collect();
+        if facing.is_empty() {
+            return sensors;
+        }
+        let flip = self.options.auto_flip_into_surface;
+        for i in &facing {
+            let message = if flip {
+                format!(
+                    "the sensor faces a surface closer than {} m, so it was flipped",
+                    distance
+                )
+            } else {
+                format!(
+                    "the sensor faces a surface closer than {} m, so it probably needs flipping (see DCOptions::auto_flip_into_surface)",
+                    distance
+                )
+            };
+            events.push(
+                EventKind::FacingIntoSurface,
+                Some(first_index + i),
+                Some(sensors[*i].ray),
+                1,
+                message,
+            );
+        }
+        if !flip {
+            return sensors;
+        }
+        let mut flipped = sensors.into_owned();
+        for i in facing {
+            // It keeps the id of the direction it was given
+            flipped[i].id = Some(flipped[i].sensor_id());
+            flipped[i].ray.direction = flipped[i].ray.direction * -1.;
+        }
+        Cow::Owned(flipped)
+    }
+
+    /// Moves the sensors that lie on a surface [`DCOptions::surface_offset`] in
+    /// front of it, recording them in `events`
+    fn move_off_surfaces<'a>(
+        &self,
+        sensors: &'a [SensorSpec],
+        scene: &Scene,
+        first_index: usize,
+        events: &mut EventLog,
+    ) -> Cow<'a, [SensorSpec]> {
+        let offset = self.ray_offset(None).nudge(self.options.surface_offset);
+        if offset <= 0.0 {
+            return Cow::Borrowed(sensors);
+        }
+        let moved: Vec<(usize, Point3D)> = sensors
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| off_surface_origin(scene, s, offset).map(|p| (i, p)))
+            .collect();
+        if moved.is_empty() {
+            return Cow::Borrowed(sensors);
+        }
+        let mut ret = sensors.to_vec();
+        for (i, origin) in moved {
+            events.push(
+                EventKind::OnSurface,
+                Some(first_index + i),
+                Some(sensors[i].ray),
+                1,
+                format!(
+                    "the sensor lies on a surface, so it was moved {} m in front of it (see DCOptions::surface_offset)",
+                    offset
+                ),
+            );
+            // It keeps the id of the place it was given
+            ret[i].id = Some(ret[i].sensor_id());
+            ret[i].ray.origin = origin;
+        }
+        Cow::Owned(ret)
+    }
+
+    /// Estimates the resources needed for calculating the Daylight
+    /// Coefficients of `n_sensors` sensors
+    pub fn estimate(&self, n_sensors: usize) -> ResourceEstimate {
+        estimate_resources(n_sensors, self.mf, &self.options)
+    }
+
+    /// Checks that a matrix with `n_sensors` rows fits within the
+    /// memory budget, and that its rays fit within the [`RayBudget`] if
+    /// exceeding it is an error.
+    pub(crate) fn check_budget(&self, n_sensors: usize) -> Result<(), String> {
+        if let Some(budget) = self.options.memory_budget {
+            let estimate = self.estimate(n_sensors);
+            if estimate.matrix_bytes > budget {
+                return Err(format!(
+                    "The Daylight Coefficient matrix of {} sensors and {} bins is expected to use {} bytes, which exceeds the memory budget of {} bytes. Consider using DCSession::calc_dc_streaming(), which processes the sensors in smaller batches.",
+                    n_sensors, estimate.n_bins, estimate.matrix_bytes, budget
+                ));
+            }
+        }
+        match self.ray_budget_excess(n_sensors) {
+            Some((message, BudgetAction::Error)) => Err(message),
+            _ => Ok(()),
+        }
+    }
+
+    /// Describes how the rays of `n_sensors` sensors are expected to exceed
+    /// the [`RayBudget`], if they are, and what should be done about it
+    pub(crate) fn ray_budget_excess(&self, n_sensors: usize) -> Option<(String, BudgetAction)> {
+        let budget = self.options.ray_budget?;
+        let estimate = self.estimate(n_sensors);
+        if estimate.total_rays() <= budget.max_rays {
+            return None;
+        }
+        let message = format!(
+            "The Daylight Coefficients of {} sensors may need {}{} rays ({} primary and {} secondary), which exceeds the ray budget of {}",
+            n_sensors,
+            if self.options.is_direct() { "up to " } else { "at least " },
+            estimate.total_rays(),
+            estimate.primary_rays,
+            estimate.secondary_rays,
+            budget.max_rays
+        );
+        Some((message, budget.action))
+    }
+
+    /// Calculates the Daylight Coefficient matrix of a set of sensors. Each
+    /// `Ray3D` in `rays` is a sensor, and each of them produces one row of
+    /// radiance-weighted coefficients, as those of [`DCSession::calc_sensor_dc`].
+    ///
+    /// Returns an error if the matrix is expected to exceed the
+    /// memory budget, or if the session has a [`RayFilter`].
+    pub fn calc_dc(&self, rays: &[Ray3D], scene: &Scene) -> Result<Matrix, String> {
+        let basis = self.basis()?;
+        self.check_direct_only()?;
+        self.check_factory_budget()?;
+        self.check_budget(rays.len())?;
+        let dc = colour_matrix_to_radiance(&self.factory_dc(
+            rays,
+            scene,
+            self.options.n_ambient_samples,
+        )?);
+        basis.check_dc(&dc)?;
+        Ok(dc)
+    }
+
+    /// Calculates the radiance-weighted Daylight Coefficients of a set of sensors,
+    /// split into their sky and externally reflected components (see [`DCComponents`]).
+    /// The `report` must be the one returned when loading the `scene`.
+    ///
+    /// Both components come from the same paths, traced with the simplified model
+    /// of [`DCSession::calc_reflectance_tallies`]: the paths that escape before
+    /// their first bounce make the sky component, and the rest the externally
+    /// reflected one. Neither is thus ever negative, and they add up to what the
+    /// simplified model gets in a single run.
+    pub fn calc_externally_reflected_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+    ) -> Result<DCComponents, String> {
+        let tracer = LambertianTracer::new(self, scene, report)?;
+        // Both components are held in memory
+        self.check_budget(2 * sensors.len())?;
+        let reflectances: Vec<Float> = report
+            .materials
+            .iter()
+            .map(|m| diffuse_reflectance(&m.kind, m.rgb))
+            .collect();
+        let responses = material_responses(report, |m| {
+            if reflectances[m] > 0.0 {
+                Response::Reflect
+            } else {
+                Response::Absorb
+            }
+        });
+        let n_bins = self.basis()?.n_bins();
+        let one_over_samples = 1. / self.options.n_ambient_samples as Float;
+        let rows = self.map_sensors(sensors, |(index, sensor)| {
+            let mut sky = vec![0.0; n_bins];
+            let mut reflected = vec![0.0; n_bins];
+            tracer.trace(
+                index,
+                sensor,
+                &responses,
+                |throughput, reflections, _, depth, bin| {
+                    let v = reflections
+                        .iter()
+                        .fold(throughput, |t, m| t * reflectances[*m])
+                        * one_over_samples;
+                    if depth == 0 {
+                        sky[bin] += v;
+                    } else {
+                        reflected[bin] += v;
+                    }
+                },
+            )?;
+            Ok((sky, reflected))
+        })?;
+        let mut sky = Matrix::new(0.0, sensors.len(), n_bins);
+        let mut externally_reflected = Matrix::new(0.0, sensors.len(), n_bins);
+        for (r, (sky_row, reflected_row)) in rows.iter().enumerate() {
+            for bin in 0..n_bins {
+                sky.set(r, bin, sky_row[bin])?;
+                externally_reflected.set(r, bin, reflected_row[bin])?;
+            }
+        }
+        Ok(DCComponents {
+            sky,
+            externally_reflected,
+        })
+    }
+
+    /// Calculates the Daylight Coefficient matrix of a set of [`SensorSpec`], returning
+    /// the radiance-weighted coefficients together with a description of each row.
+    ///
+    /// When `max_depth` is `0`, the coefficients are calculated by tracing the
+    /// sky directly from each sensor, respecting their [`AngularMask`](crate::AngularMask).
+    /// Otherwise, the ray-tracing is performed by the `DCFactory`, which cannot
+    /// handle masks.
+    pub fn calc_sensor_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+    ) -> Result<LabeledMatrix, String> {
+        self.sensor_dc(sensors, scene, 0)
+    }
+
+    /// Like [`DCSession::calc_sensor_dc`], but writes the coefficients into a matrix
+    /// allocated by the caller (e.g., one that gathers several batches of sensors),
+    /// starting at row `first_row`. Sensors get the random numbers of their row, so
+    /// filling a matrix batch by batch gives the same result as doing it at once.
+    ///
+    /// The shape of `into` is checked before tracing anything: it must have one
+    /// column per bin of [`DCSession::basis`], and room for every sensor. Returns
+    /// what happened during the calculation.
+    pub fn calc_dc_into(
+        &self,
+        into: &mut Matrix,
+        first_row: usize,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+    ) -> Result<EventLog, String> {
+        let n_bins = self.basis()?.n_bins();
+        let (nrows, ncols) = into.size();
+        if ncols != n_bins || first_row + sensors.len() > nrows {
+            return Err(format!(
+                "Cannot write {} sensors from row {} of a {}x{} matrix: a sky with MF {} needs {} columns and the sensors need {} rows",
+                sensors.len(),
+                first_row,
+                nrows,
+                ncols,
+                self.mf,
+                n_bins,
+                first_row + sensors.len()
+            ));
+        }
+        let dc = self.sensor_dc(sensors, scene, first_row)?;
+        for r in 0..sensors.len() {
+            for c in 0..n_bins {
+                into.set(first_row + r, c, dc.matrix.get(r, c)?)?;
+            }
+        }
+        Ok(dc.events)
+    }
+
+    /// Calculates the Daylight Coefficient matrix of a set of sensors, the first of
+    /// which has index `first_index` within a larger set. Indices determine the
+    /// random numbers of each sensor and are the ones recorded in the events.
+    pub(crate) fn sensor_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        first_index: usize,
+    ) -> Result<LabeledMatrix, String> {
+        if self.options.is_direct()
+            || self.options.noise_floor.is_some()
+            || self.options.ray_budget.is_some()
+        {
+            return self
+                .sensor_dc_with_stats(sensors, scene, false, first_index)
+                .map(|(dc, _)| dc);
+        }
+        let basis = self.basis()?;
+        self.check_budget(sensors.len())?;
+        self.check_factory_support(sensors)?;
+        self.check_factory_budget()?;
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, first_index, &mut events);
+        let rays: Vec<Ray3D> = sensors.iter().map(|s| s.ray).collect();
+        let matrix = colour_matrix_to_radiance(&self.factory_dc(
+            &rays,
+            scene,
+            self.options.n_ambient_samples,
+        )?);
+        self.count_samples(rays.len() * self.options.n_ambient_samples);
+        basis.check_dc(&matrix)?;
+        Ok(LabeledMatrix {
+            matrix,
+            rows: Self::row_metadata(sensors, self.options.n_ambient_samples),
+            events,
+            ground: GroundConvention::default(),
+        })
+    }
+
+    /// Assembles the [`RunManifest`] of a calculation performed by this session for
+    /// some `sensors`, in a scene loaded as described by `scene`, which took `wall_time`
+    pub fn run_manifest(
+        &self,
+        scene: &SceneReport,
+        sensors: &[SensorSpec],
+        wall_time: Duration,
+    ) -> Result<RunManifest, String> {
+        RunManifest::new(
+            scene,
+            sensors,
+            self.mf,
+            self.options,
+            self.ray_filter.is_some(),
+            self.importance.is_some(),
+            n_threads(),
+            wall_time,
+        )
+    }
+
+    /// Like [`DCSession::calc_sensor_dc`], but also returns the [`RunManifest`] of the
+    /// calculation. The `report` describes how the `scene` was loaded (see
+    /// [`load_scene`](crate::load_scene)), and its files are hashed.
+    pub fn calc_sensor_dc_with_manifest(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+    ) -> Result<(LabeledMatrix, RunManifest), String> {
+        let start = Instant::now();
+        let dc = self.calc_sensor_dc(sensors, scene)?;
+        let manifest = self.run_manifest(report, sensors, start.elapsed())?;
+        Ok((dc, manifest))
+    }
+
+    /// Like [`DCSession::calc_sensor_dc`], but also returns the standard error of
+    /// the results, for each sensor and—if `per_bin` is `true`—for each coefficient.
+    ///
+    /// The unit of the statistics is the contribution of each primary sample. When
+    /// `max_depth` is larger than `0`, individual samples are not visible from here,
+    /// so the `DCFactory` is run in [`STATS_BATCHES`] batches and the statistics
+    /// are derived from the spread between them.
+    pub fn calc_sensor_dc_with_stats(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        per_bin: bool,
+    ) -> Result<(LabeledMatrix, DCStats), String> {
+        self.sensor_dc_with_stats(sensors, scene, per_bin, 0)
+    }
+
+    /// Like [`DCSession::calc_sensor_dc_with_stats`], for sensors whose indices
+    /// start at `first_index` (see [`DCSession::sensor_dc`])
+    fn sensor_dc_with_stats(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        per_bin: bool,
+        first_index: usize,
+    ) -> Result<(LabeledMatrix, DCStats), String> {
+        self.check_budget(sensors.len())?;
+        let n_bins = self.basis()?.n_bins();
+        let mut matrix = Matrix::new(0.0, sensors.len(), n_bins);
+        let mut bin_errors = if per_bin || self.options.noise_floor.is_some() {
+            Some(Matrix::new(0.0, sensors.len(), n_bins))
+        } else {
+            None
+        };
+        let mut standard_errors = Vec::with_capacity(sensors.len());
+        let mut clamped = Vec::with_capacity(sensors.len());
+        let mut grazing = Vec::with_capacity(sensors.len());
+        let mut skipped = Vec::with_capacity(sensors.len());
+        let mut factory_runs = Vec::new();
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, first_index, &mut events);
+        if let Some((message, _)) = self.ray_budget_excess(sensors.len()) {
+            events.push(EventKind::RayBudgetExceeded, None, None, 1, message);
+        }
+        if let (Some(budget), false) = (self.options.ray_budget, self.options.is_direct()) {
+            events.push(
+                EventKind::RayBudgetUnenforced,
+                None,
+                None,
+                1,
+                format!(
+                    "the bounces are traced by the DCFactory, so the budget of {} rays only holds if its paths do not branch",
+                    budget.max_rays
+                ),
+            );
+        }
+        let cap = self.options.ray_budget.as_ref().map(RayCap::new);
+        let rays_per_sample = rays_per_sample(&self.options);
+
+        let n_samples: Vec<usize> = if self.options.is_direct() {
+            let sky = ReinhartSky::new(self.mf);
+            let trace = |(i, sensor): (usize, &SensorSpec)| {
+                let index = first_index + i;
+                let mut samples =
+                    SampleStream::new(self.options.sampling, self.options.seed, index as u64);
+                let mut events = EventLog::new();
+                let requested = self.options.n_ambient_samples;
+                let n_samples = match &cap {
+                    Some(cap) => cap.reserve(requested, rays_per_sample, MIN_CAPPED_SAMPLES),
+                    None => requested,
+                };
+                if n_samples < requested {
+                    events.push(
+                        EventKind::RayCapReached,
+                        Some(index),
+                        Some(sensor.ray),
+                        requested - n_samples,
+                        format!(
+                            "the ray budget ran out, so the sensor was traced with {} samples instead of {}",
+                            n_samples, requested
+                        ),
+                    );
+                }
+                let row = direct_dc_row(
+                    scene,
+                    sensor,
+                    index,
+                    &sky,
+                    n_bins,
+                    n_samples,
+                    &mut samples,
+                    self.trace_hints(),
+                    false,
+                    &mut events,
+                )?;
+                report_enclosed(&mut events, index, sensor, row.escaped, n_samples);
+                self.revise_samples(requested, n_samples);
+                self.count_samples(n_samples);
+                Ok::<(DirectRow, EventLog, usize), String>((row, events, n_samples))
+            };
+            let rows = self.map_sensors(sensors, trace)?;
+            let mut n_samples = Vec::with_capacity(sensors.len());
+            for (i, (row, row_events, n)) in rows.into_iter().enumerate() {
+                events.merge(row_events);
+                clamped.push(row.clamped);
+                grazing.push(row.grazing);
+                skipped.push(row.skipped);
+                row.bins.write_row(&mut matrix, i)?;
+                if let Some(errors) = &mut bin_errors {
+                    for (bin, v, sq) in row.bins.iter() {
+                        errors.set(i, bin, standard_error_from_moments(v, sq, n))?;
+                    }
+                }
+                standard_errors.push(row.totals.standard_error());
+                n_samples.push(n);
+            }
+            n_samples
+        } else {
+            self.check_factory_support(sensors)?;
+            self.check_factory_budget()?;
+            let rays: Vec<Ray3D> = sensors.iter().map(|s| s.ray).collect();
+            let batch = (self.options.n_ambient_samples / STATS_BATCHES).max(1);
+            let mut totals = vec![Welford::new(); sensors.len()];
+            let per_bin = bin_errors.is_some();
+            let mut bins = vec![Welford::new(); if per_bin { sensors.len() * n_bins } else { 0 }];
+            // The bounces happen within the `DCFactory`, so the ray budget can only
+            // be enforced between batches: once it runs out, no more batches are
+            // traced (but the first two always are, for the statistics)
+            let batch_samples = sensors.len() * batch;
+            let mut n_batches = 0;
+            while n_batches < STATS_BATCHES {
+                if let Some(cap) = &cap {
+                    let min = if n_batches < 2 { batch_samples } else { 0 };
+                    if cap.reserve(batch_samples, rays_per_sample, min) < batch_samples {
+                        break;
+                    }
+                }
+                n_batches += 1;
+                factory_runs.extend(self.factory_runs(batch));
+                let dc = colour_matrix_to_radiance(&self.factory_dc(&rays, scene, batch)?);
+                self.count_samples(batch_samples);
+                for (i, total) in totals.iter_mut().enumerate() {
+                    let mut sum = 0.0;
+                    for bin in 0..n_bins {
+                        let v = dc.get(i, bin)?;
+                        sum += v;
+                        matrix.set(i, bin, matrix.get(i, bin)? + v)?;
+                        if per_bin {
+                            bins[i * n_bins + bin].push(v);
+                        }
+                    }
+                    total.push(sum);
+                }
+            }
+            for i in 0..sensors.len() {
+                for bin in 0..n_bins {
+                    matrix.set(i, bin, matrix.get(i, bin)? / n_batches as Float)?;
+                }
+            }
+            self.revise_samples(
+                sensors.len() * self.options.n_ambient_samples,
+                batch_samples * n_batches,
+            );
+            if n_batches < STATS_BATCHES {
+                events.push(
+                    EventKind::RayCapReached,
+                    None,
+                    None,
+                    (STATS_BATCHES - n_batches) * batch_samples,
+                    format!(
+                        "the ray budget ran out, so the sensors were traced with {} batches of samples instead of {}",
+                        n_batches, STATS_BATCHES
+                    ),
+                );
+            }
+            standard_errors.extend(totals.iter().map(|t| t.standard_error()));
+            clamped.resize(sensors.len(), 0.0);
+            grazing.resize(sensors.len(), 0.0);
+            skipped.resize(sensors.len(), 0);
+            if let Some(errors) = &mut bin_errors {
+                for (k, w) in bins.iter().enumerate() {
+                    errors.set(k / n_bins, k % n_bins, w.standard_error())?;
+                }
+            }
+            vec![batch * n_batches; sensors.len()]
+        };
+
+        if let (Some(floor), Some(errors)) = (&self.options.noise_floor, &bin_errors) {
+            let culled = floor.apply(&mut matrix, errors)?;
+            for (i, row) in culled.iter().enumerate() {
+                report_culled(&mut events, first_index + i, floor, row);
+            }
+        }
+        if !per_bin {
+            bin_errors = None;
+        }
+
+        let mut rows = Self::row_metadata(sensors, 0);
+        for (row, n) in rows.iter_mut().zip(n_samples.iter()) {
+            row.n_samples = *n;
+        }
+        let ids = rows.iter().map(|r| r.id.clone()).collect();
+        let dc = LabeledMatrix {
+            matrix,
+            rows,
+            events,
+            ground: GroundConvention::default(),
+        };
+        let stats = DCStats {
+            n_samples,
+            ids,
+            standard_errors,
+            bin_standard_errors: bin_errors,
+            clamped,
+            grazing,
+            skipped,
+            ray_cap_hit: cap.is_some_and(|c| c.was_hit()),
+            factory_runs,
+        };
+        Ok((dc, stats))
+    }
+
+    /// Calculates the Daylight Coefficient matrices of a set of sensors, keeping the
+    /// ground apart from the sky (see [`TwoSidedDC`]). Rays that escape below the
+    /// horizon contribute to the ground bin they exit through rather than to the
+    /// ground bin of the sky, which is left empty.
+    ///
+    /// Only the direct tracer can tell where rays escape (see [`DCOptions::max_depth`]).
+    pub fn calc_two_sided_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+    ) -> Result<TwoSidedDC, String> {
+        if !self.options.is_direct() {
+            return Err(needs_direct_tracer("two-sided Daylight Coefficients"));
+        }
+        // Both halves are held in memory
+        self.check_budget(2 * sensors.len())?;
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut events);
+        let basis = self.basis()?;
+        let sky = basis.reinhart();
+        let n_bins = basis.n_bins();
+        let n_samples = self.options.n_ambient_samples;
+        let trace = |(i, sensor): (usize, &SensorSpec)| {
+            let mut samples = SampleStream::new(self.options.sampling, self.options.seed, i as u64);
+            let mut events = EventLog::new();
+            let row = direct_dc_row(
+                scene,
+                sensor,
+                i,
+                &sky,
+                n_bins,
+                n_samples,
+                &mut samples,
+                self.trace_hints(),
+                true,
+                &mut events,
+            )?;
+            report_enclosed(&mut events, i, sensor, row.escaped, n_samples);
+            Ok::<(DirectRow, EventLog), String>((row, events))
+        };
+        let rows = self.map_sensors(sensors, trace)?;
+
+        let mut matrix = Matrix::new(0.0, sensors.len(), n_bins);
+        let mut ground = Matrix::new(0.0, sensors.len(), basis.n_ground_bins());
+        for (i, (row, row_events)) in rows.into_iter().enumerate() {
+            events.merge(row_events);
+            row.bins.write_row(&mut matrix, i)?;
+            row.ground.write_row(&mut ground, i)?;
+        }
+        Ok(TwoSidedDC {
+            basis,
+            sky: LabeledMatrix {
+                matrix,
+                rows: Self::row_metadata(sensors, n_samples),
+                events,
+                ground: GroundConvention::GroundExcluded,
+            },
+            ground,
+        })
+    }
+
+    /// Calculates the irradiance received by each sensor from an environment
+    /// (e.g., an HDR image of the sky), without going through sky patches. Rays that
+    /// escape the scene evaluate the radiance of `sky` in their direction.
+    ///
+    /// It needs the direct tracer (see [`DCOptions::max_depth`]). The standard
+    /// error of each value is returned as well, together with what happened
+    /// during the calculation.
+    pub fn calc_environment_irradiance<S: SkyRadiance>(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        sky: &S,
+    ) -> Result<(Vec<Float>, Vec<Float>, EventLog), String> {
+        if !self.options.is_direct() {
+            return Err(needs_direct_tracer("environment skies"));
+        }
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut events);
+        let mut values = Vec::with_capacity(sensors.len());
+        let mut errors = Vec::with_capacity(sensors.len());
+        for (i, sensor) in sensors.iter().enumerate() {
+            let mut samples = SampleStream::new(self.options.sampling, self.options.seed, i as u64);
+            let w = direct_environment_irradiance(
+                scene,
+                sensor,
+                i,
+                sky,
+                self.options.n_ambient_samples,
+                &mut samples,
+                self.trace_hints(),
+                &mut events,
+            )?;
+            values.push(w.mean());
+            errors.push(w.standard_error());
+        }
+        Ok((values, errors, events))
+    }
+
+    /// Like [`DCSession::calc_sensor_dc`], but returns a [`SparseMatrix`] that only
+    /// keeps the coefficients larger than `threshold`. When `max_depth` is `0` the
+    /// rows are sparsified as they are calculated, so the dense matrix is never
+    /// held in memory (and the memory budget is not checked). Events are not
+    /// returned, although they are still forwarded to `log` when the feature is enabled.
+    /// The [`DCOptions::noise_floor`], if any, is applied before the `threshold`.
+    pub fn calc_sparse_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        threshold: Float,
+    ) -> Result<SparseMatrix, String> {
+        if !self.options.is_direct() {
+            let dc = self.calc_sensor_dc(sensors, scene)?;
+            return SparseMatrix::from_dense(&dc.matrix, threshold);
+        }
+        let sky = ReinhartSky::new(self.mf);
+        let n_bins = ReinhartSky::n_bins(self.mf);
+        let mut ret = SparseMatrix::new(n_bins);
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut events);
+        for (i, sensor) in sensors.iter().enumerate() {
+            let mut samples = SampleStream::new(self.options.sampling, self.options.seed, i as u64);
+            let row = direct_dc_row(
+                scene,
+                sensor,
+                i,
+                &sky,
+                n_bins,
+                self.options.n_ambient_samples,
+                &mut samples,
+                self.trace_hints(),
+                false,
+                &mut events,
+            )?;
+            report_enclosed(
+                &mut events,
+                i,
+                sensor,
+                row.escaped,
+                self.options.n_ambient_samples,
+            );
+            let mut bins = row.bins;
+            if let Some(floor) = &self.options.noise_floor {
+                let culled = bins.cull(floor, self.options.n_ambient_samples);
+                report_culled(&mut events, i, floor, &culled);
+            }
+            bins.push_into(&mut ret, threshold)?;
+        }
+        Ok(ret)
+    }
+
+    /// Calculates the Daylight Coefficient matrices of a set of sensors in several
+    /// discretisations of the sky at once (e.g., the MF 1 and MF 6 matrices of the
+    /// five-phase method), returning one per basis. The subdivision of the session
+    /// itself does not matter.
+    ///
+    /// Each ray is traced once and binned into every basis, so this costs little
+    /// more than the finest basis alone, and each matrix is exactly what a session
+    /// with its subdivision (and the same options) would calculate. It needs the
+    /// direct tracer (see [`DCOptions::max_depth`]).
+    pub fn calc_sensor_dc_in_bases(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        bases: &[SkyBasis],
+    ) -> Result<Vec<LabeledMatrix>, String> {
+        if !self.options.is_direct() {
+            return Err(needs_direct_tracer("several bases at once"));
+        }
+        if bases.is_empty() {
+            return Err("At least one basis is needed".to_string());
+        }
+        if let Some(budget) = self.options.memory_budget {
+            let bytes: usize = bases
+                .iter()
+                .map(|b| estimate_resources(sensors.len(), b.mf(), &self.options).matrix_bytes)
+                .sum();
+            if bytes > budget {
+                return Err(format!(
+                    "The Daylight Coefficient matrices of {} sensors in {} bases are expected to use {} bytes, which exceeds the memory budget of {} bytes",
+                    sensors.len(),
+                    bases.len(),
+                    bytes,
+                    budget
+                ));
+            }
+        }
+        let mut events = EventLog::new();
+        let sensors = &*self.preflight(sensors, scene, 0, &mut events);
+        let skies: Vec<(ReinhartSky, usize)> =
+            bases.iter().map(|b| (b.reinhart(), b.n_bins())).collect();
+        let skies: Vec<(&ReinhartSky, usize)> = skies.iter().map(|(s, n)| (s, *n)).collect();
+        let n_samples = self.options.n_ambient_samples;
+        let trace = |(index, sensor): (usize, &SensorSpec)| {
+            let mut samples =
+                SampleStream::new(self.options.sampling, self.options.seed, index as u64);
+            let mut events = EventLog::new();
+            let mut rows = direct_dc_rows(
+                scene,
+                sensor,
+                index,
+                &skies,
+                n_samples,
+                &mut samples,
+                self.trace_hints(),
+                false,
+                &mut events,
+            )?;
+            report_enclosed(&mut events, index, sensor, rows[0].escaped, n_samples);
+            if let Some(floor) = &self.options.noise_floor {
+                // Every basis sees the same samples, so they are reported once
+                let mut culled = Vec::with_capacity(rows.len());
+                for row in rows.iter_mut() {
+                    culled.push(row.bins.cull(floor, n_samples));
+                }
+                report_culled(&mut events, index, floor, &culled[0]);
+            }
+            Ok::<(Vec<DirectRow>, EventLog), String>((rows, events))
+        };
+        let rows = self.map_sensors(sensors, trace)?;
+
+        let mut matrices: Vec<Matrix> = bases
+            .iter()
+            .map(|b| Matrix::new(0.0, sensors.len(), b.n_bins()))
+            .collect();
+        for (i, (sensor_rows, row_events)) in rows.into_iter().enumerate() {
+            events.merge(row_events);
+            for (matrix, row) in matrices.iter_mut().zip(sensor_rows.iter()) {
+                row.bins.write_row(matrix, i)?;
+            }
+        }
+        Ok(matrices
+            .into_iter()
+            .map(|matrix| LabeledMatrix {
+                matrix,
+                rows: Self::row_metadata(sensors, n_samples),
+                events: events.clone(),
+                ground: GroundConvention::default(),
+            })
+            .collect())
+    }
+
+    /// Calculates the Daylight Coefficient matrix of a set of sensors in batches
+    /// of `batch_size` sensors, which means that only a part of the matrix
+    /// is held in memory at any time. Each batch of radiance-weighted coefficients
+    /// (see [`DCSession::calc_dc`]) is passed to `sink`, together with the index
+    /// of the sensor of its first row.
+    ///
+    /// Returns an error if a single batch is expected to exceed the memory budget,
+    /// or if `sink` fails.
+    pub fn calc_dc_streaming<F>(
+        &self,
+        rays: &[Ray3D],
+        scene: &Scene,
+        batch_size: usize,
+        mut sink: F,
+    ) -> Result<(), String>
+    where
+        F: FnMut(usize, Matrix) -> Result<(), String>,
+    {
+        if batch_size == 0 {
+            return Err("The batch size of a streaming calculation must be at least 1".to_string());
+        }
+        self.check_direct_only()?;
+        self.check_factory_budget()?;
+        self.check_budget(batch_size.min(rays.len()))?;
+        for (i, batch) in rays.chunks(batch_size).enumerate() {
+            sink(
+                i * batch_size,
+                colour_matrix_to_radiance(&self.factory_dc(
+                    batch,
+                    scene,
+                    self.options.n_ambient_samples,
+                )?),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Like [`DCSession::calc_sensor_dc`], but pulling the sensors from an iterator
+    /// as they are needed, so that neither the sensors nor the matrix are ever
+    /// held in memory as a whole. Sensors are processed in batches of up to
+    /// `batch_size`—in parallel, if the `parallel` feature is enabled—and each
+    /// batch is passed to `sink` as soon as it is done, together with the index of
+    /// the sensor of its first row. Batches arrive in order, and only one is in
+    /// flight at any time.
+    ///
+    /// The results do not depend on `batch_size`: the random numbers of each
+    /// sensor and the events it produces are keyed by its index within
+    /// the whole iterator.
+    pub fn calc_dc_iter<I, F>(
+        &self,
+        sensors: I,
+        scene: &Scene,
+        batch_size: usize,
+        mut sink: F,
+    ) -> Result<(), String>
+    where
+        I: IntoIterator<Item = SensorSpec>,
+        F: FnMut(usize, LabeledMatrix) -> Result<(), String>,
+    {
+        if batch_size == 0 {
+            return Err("The batch size of a streaming calculation must be at least 1".to_string());
+        }
+        self.check_budget(batch_size)?;
+        let mut sensors = sensors.into_iter();
+        let mut first_index = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        loop {
+            batch.clear();
+            batch.extend(sensors.by_ref().take(batch_size));
+            if batch.is_empty() {
+                return Ok(());
+            }
+            sink(first_index, self.sensor_dc(&batch, scene, first_index)?)?;
+            first_index += batch.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::environment::UniformSky;
+    use crate::events::{Event, EventKind};
+    use crate::sensor::{AngularMask, ResponseCurve};
+    use crate::sensor_id::SensorId;
+    use crate::stats::CullPolicy;
+    use crate::test_support::{peak_memory_of, report_peak_memory, MEMORY_PROBE};
+    use crate::{Float, PI};
+    use geometry3d::Vector3D;
+    use validate::assert_close;
+
+    fn sensors(n: usize) -> Vec<Ray3D> {
+        (0..n)
+            .map(|i| Ray3D {
+                origin: Point3D::new(i as crate::Float, 0., 0.8),
+                direction: Vector3D::new(0., 0., 1.),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_refuse_over_budget() {
+        let scene = Scene::new();
+        let options = DCOptions {
+            memory_budget: Some(1_000_000),
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(6, options);
+        let err = session.calc_dc(&sensors(100), &scene).unwrap_err();
+        assert!(err.contains("memory budget"));
+        assert!(err.contains("calc_dc_streaming"));
+
+        // Streaming helps, as long as batches are small enough
+        assert!(session.check_budget(1).is_ok());
+        assert!(session
+            .calc_dc_streaming(&sensors(100), &scene, 100, |_, _| Ok(()))
+            .is_err());
+        assert!(session
+            .calc_dc_streaming(&sensors(100), &scene, 0, |_, _| Ok(()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_ray_budget() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let budget = RayBudget {
+            max_rays: 50_000,
+            action: BudgetAction::Warn,
+        };
+        // Far more samples than the budget allows
+        let explosive = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 1_000_000,
+            ray_budget: Some(budget),
+            ..DCOptions::default()
+        };
+        let sensors: Vec<SensorSpec> = sensors(10).into_iter().map(SensorSpec::from).collect();
+        let session = DCSession::new(1, explosive);
+        let (dc, stats) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+        assert!(stats.ray_cap_hit);
+        assert!(dc
+            .events
+            .of_kind(EventKind::RayBudgetExceeded)
+            .next()
+            .is_some());
+        assert!(dc.events.of_kind(EventKind::RayCapReached).next().is_some());
+        let total: usize = stats.n_samples.iter().sum();
+        assert!(total <= budget.max_rays + sensors.len() * MIN_CAPPED_SAMPLES);
+        for (row, n) in dc.rows.iter().zip(stats.n_samples.iter()) {
+            assert!(*n >= MIN_CAPPED_SAMPLES);
+            assert_eq!(row.n_samples, *n);
+        }
+        // Every sensor sees the whole sky, however many samples it got
+        for r in 0..sensors.len() {
+            let sum: Float = (0..146).map(|c| dc.matrix.get(r, c).unwrap()).sum();
+            assert!((sum - PI).abs() < 1e-3);
+        }
+
+        // Within the budget, nothing changes
+        let generous = DCOptions {
+            n_ambient_samples: 100,
+            ..explosive
+        };
+        let session = DCSession::new(1, generous);
+        let (dc, stats) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+        assert!(!stats.ray_cap_hit);
+        assert!(dc.events.is_empty());
+        assert_eq!(stats.n_samples, vec![100; sensors.len()]);
+
+        // Bouncing a lot from many sensors is refused before starting
+        let refuse = DCOptions {
+            max_depth: 3,
+            n_ambient_samples: 3000,
+            ray_budget: Some(RayBudget {
+                action: BudgetAction::Error,
+                ..budget
+            }),
+            ..DCOptions::default()
+        };
+        let rays: Vec<Ray3D> = sensors.iter().map(|s| s.ray).collect();
+        let err = DCSession::new(1, refuse)
+            .calc_dc(&rays, &scene)
+            .unwrap_err();
+        assert!(err.contains("ray budget"), "{}", err);
+
+        // Bounces through the DCFactory cannot be bounded, however few
+        let few = DCOptions {
+            max_depth: 1,
+            n_ambient_samples: 1,
+            ray_budget: Some(RayBudget {
+                action: BudgetAction::Error,
+                ..budget
+            }),
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, few);
+        assert!(session.estimate(sensors.len()).total_rays() < budget.max_rays);
+        let err = session.calc_dc(&rays, &scene).unwrap_err();
+        assert!(err.contains("cannot be guaranteed"), "{}", err);
+        let err = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap_err();
+        assert!(err.contains("cannot be guaranteed"), "{}", err);
+        let invalid = DCOptions {
+            ray_budget: Some(RayBudget {
+                max_rays: 0,
+                action: BudgetAction::Error,
+            }),
+            ..DCOptions::default()
+        };
+        assert!(DCSession::try_new(1, invalid).is_err());
+    }
+
+    #[test]
+    fn test_half_azimuth_mask() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let mf = 1;
+        let session = DCSession::new(
+            mf,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 2000,
+                ..DCOptions::default()
+            },
+        );
+        let up = sensors(1)[0];
+        let half = AngularMask::range(0., 90., 0., 180.).unwrap();
+        let sensors = vec![SensorSpec::from(up), SensorSpec::from(up).with_mask(half)];
+        let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        assert!(dc.rows[0].mask.is_none());
+        assert!(dc.rows[1].mask.is_some());
+
+        let n_bins = ReinhartSky::n_bins(mf);
+        let full: Float = (0..n_bins).map(|b| dc.matrix.get(0, b).unwrap()).sum();
+        let masked: Float = (0..n_bins).map(|b| dc.matrix.get(1, b).unwrap()).sum();
+        assert_close!(full, PI, 1e-4);
+        assert_close!(masked, 0.5 * full, 1e-4);
+
+        // Nothing comes from the West
+        let sky = ReinhartSky::new(mf);
+        for b in 1..n_bins {
+            let dir = sky.bin_dir(b);
+            if dir.x < -0.2 {
+                assert_eq!(dc.matrix.get(1, b).unwrap(), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_cutoff() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let mf = 1;
+        let session = DCSession::new(
+            mf,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 20000,
+                ..DCOptions::default()
+            },
+        );
+        // Blind beyond 60 degrees from the normal, as a mask or as a response
+        let up = sensors(1)[0];
+        let cutoff = ResponseCurve::new(vec![(0., 1.), (60., 1.), (60.01, 0.)]).unwrap();
+        let ideal = ResponseCurve::new(vec![(0., 1.), (90., 1.)]).unwrap();
+        let sensors = [
+            SensorSpec::from(up).with_mask(AngularMask::range(30., 90., 0., 360.).unwrap()),
+            SensorSpec::from(up).with_angular_response(cutoff),
+            SensorSpec::from(up),
+            SensorSpec::from(up).with_angular_response(ideal),
+        ];
+        let dc = session.calc_sensor_dc(&sensors[..2], &scene).unwrap();
+        assert!(dc.rows[0].angular_response.is_none());
+        assert!(dc.rows[1].angular_response.is_some());
+
+        let n_bins = ReinhartSky::n_bins(mf);
+        let sum = |r: usize| -> Float { (0..n_bins).map(|b| dc.matrix.get(r, b).unwrap()).sum() };
+        // A quarter of the cosine-weighted hemisphere is beyond 60 degrees
+        assert_close!(sum(0), 0.75 * PI, 1e-4);
+        assert_close!(sum(1), sum(0), 0.03);
+
+        // Nothing comes from the lowest bands
+        let sky = ReinhartSky::new(mf);
+        for b in 1..n_bins {
+            if sky.bin_dir(b).get_normalized().z < 0.4 {
+                assert_eq!(dc.matrix.get(0, b).unwrap(), 0.0);
+                assert_eq!(dc.matrix.get(1, b).unwrap(), 0.0);
+            }
+        }
+
+        // An ideal curve is the same as none
+        let none = session.calc_sensor_dc(&sensors[2..3], &scene).unwrap();
+        let ideal = session.calc_sensor_dc(&sensors[3..], &scene).unwrap();
+        for b in 0..n_bins {
+            assert_eq!(
+                none.matrix.get(0, b).unwrap(),
+                ideal.matrix.get(0, b).unwrap()
+            );
+        }
+
+        // Only the direct tracer can weigh the samples
+        let bounces = DCSession::new(
+            mf,
+            DCOptions {
+                max_depth: 1,
+                n_ambient_samples: 10,
+                ..DCOptions::default()
+            },
+        );
+        let err = bounces.calc_sensor_dc(&sensors[1..2], &scene).unwrap_err();
+        assert!(err.contains("angular responses"), "{}", err);
+    }
+
+    #[test]
+    fn test_predicate_mask() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 20000,
+                ..DCOptions::default()
+            },
+        );
+        let east = AngularMask::predicate(|dir| dir.x > 0.0);
+        let sensors = vec![SensorSpec::from(sensors(1)[0]).with_mask(east)];
+        let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        let total: Float = (0..ReinhartSky::n_bins(1))
+            .map(|b| dc.matrix.get(0, b).unwrap())
+            .sum();
+        assert_close!(total, 0.5 * PI, 0.05);
+
+        // Masks need the direct tracer
+        let session = DCSession::new(1, DCOptions::default());
+        assert!(session.calc_sensor_dc(&sensors, &scene).is_err());
+    }
+
+    #[test]
+    fn test_sparse_dc() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            2,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 50,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = sensors(4).into_iter().map(|s| s.into()).collect();
+        let dense = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        let sparse = session.calc_sparse_dc(&sensors, &scene, 0.0).unwrap();
+        assert_eq!(sparse.size(), dense.matrix.size());
+        assert!(sparse.nnz() <= 4 * 50);
+        let back = sparse.to_dense().unwrap();
+        let (nrows, ncols) = back.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(back.get(r, c).unwrap(), dense.matrix.get(r, c).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise_floor() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = sensors(2).into_iter().map(|s| s.into()).collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 5000,
+            seed: 5,
+            ..DCOptions::default()
+        };
+        let sky = SkyBasis::new(4).unwrap().cie_overcast(10000., 0.2).unwrap();
+        let run = |noise_floor: Option<NoiseFloor>| {
+            let session = DCSession::new(
+                4,
+                DCOptions {
+                    noise_floor,
+                    ..options
+                },
+            );
+            let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+            let sparse = session.calc_sparse_dc(&sensors, &scene, 0.0).unwrap();
+            let illuminance = crate::annual::apply_annual(&dc.matrix, &sky).unwrap();
+            (dc, sparse.nnz(), illuminance.get(0, 0).unwrap())
+        };
+        let row_sum =
+            |m: &Matrix, r: usize| -> Float { (0..m.size().1).map(|c| m.get(r, c).unwrap()).sum() };
+
+        // With about two samples per bin, most bins are mostly noise
+        let (raw, raw_nnz, raw_e) = run(None);
+        let floor = NoiseFloor {
+            k: 1.5,
+            policy: CullPolicy::Redistribute,
+        };
+        let (kept, kept_nnz, kept_e) = run(Some(floor));
+        assert!(
+            (kept_nnz as Float) < 0.6 * raw_nnz as Float,
+            "{} of {}",
+            kept_nnz,
+            raw_nnz
+        );
+        assert!(
+            ((kept_e - raw_e) / raw_e).abs() < 0.06,
+            "{} vs {}",
+            kept_e,
+            raw_e
+        );
+        for r in 0..2 {
+            assert_close!(
+                row_sum(&kept.matrix, r),
+                row_sum(&raw.matrix, r),
+                1e-4 * row_sum(&raw.matrix, r)
+            );
+        }
+        let events: Vec<&Event> = kept.events.of_kind(EventKind::NoiseCulled).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].message.contains("redistributed"));
+
+        // Dropping loses exactly what was culled, which is what applying
+        // the floor to the statistics gives
+        let floor = NoiseFloor::new(1.5);
+        let (dropped, dropped_nnz, dropped_e) = run(Some(floor));
+        assert_eq!(dropped_nnz, kept_nnz);
+        assert!(dropped_e < raw_e);
+        let session = DCSession::new(4, options);
+        let (mut dc, stats) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, true)
+            .unwrap();
+        let culled = floor
+            .apply(&mut dc.matrix, stats.bin_standard_errors.as_ref().unwrap())
+            .unwrap();
+        for (r, row) in culled.iter().enumerate() {
+            assert_close!(
+                row.total_energy,
+                row_sum(&raw.matrix, r),
+                1e-6 * row.total_energy
+            );
+            assert_close!(
+                row.total_energy - row.culled_energy,
+                row_sum(&dropped.matrix, r),
+                1e-4 * row.total_energy
+            );
+            assert_eq!(
+                row.n_culled,
+                dropped
+                    .events
+                    .of_kind(EventKind::NoiseCulled)
+                    .nth(r)
+                    .unwrap()
+                    .count
+            );
+        }
+
+        let invalid = DCOptions {
+            noise_floor: Some(NoiseFloor::new(-1.)),
+            ..options
+        };
+        assert!(DCSession::try_new(4, invalid).is_err());
+    }
+
+    #[test]
+    fn test_standard_errors_shrink() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let half = AngularMask::predicate(|dir| dir.x > 0.0);
+        let sensors = vec![SensorSpec::from(sensors(1)[0]).with_mask(half)];
+
+        let run = |n_ambient_samples: usize, seed: u64| {
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    max_depth: 0,
+                    n_ambient_samples,
+                    seed,
+                    ..DCOptions::default()
+                },
+            );
+            session
+                .calc_sensor_dc_with_stats(&sensors, &scene, true)
+                .unwrap()
+        };
+        let (dc, small) = run(4000, 1);
+        let (_, large) = run(8000, 2);
+        assert_eq!(small.n_samples, vec![4000]);
+        let ratio = large.standard_errors[0] / small.standard_errors[0];
+        assert_close!(ratio, 1. / (2. as Float).sqrt(), 0.05);
+
+        // Half of the samples contribute PI, so the standard deviation is PI/2
+        assert_close!(
+            small.standard_errors[0],
+            0.5 * PI / (4000. as Float).sqrt(),
+            1e-3
+        );
+
+        // Per-bin errors come with the same shape as the coefficients
+        let bins = small.bin_standard_errors.unwrap();
+        assert_eq!(bins.size(), dc.matrix.size());
+        let (_, ncols) = bins.size();
+        for c in 0..ncols {
+            let v = dc.matrix.get(0, c).unwrap();
+            let e = bins.get(0, c).unwrap();
+            assert!(e >= 0.0);
+            if v == 0.0 {
+                assert_eq!(e, 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_uniform_environment() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 300,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = sensors(2).into_iter().map(|s| s.into()).collect();
+        let (values, errors, events) = session
+            .calc_environment_irradiance(&sensors, &scene, &UniformSky(1.))
+            .unwrap();
+        assert!(events.is_empty());
+        for (v, e) in values.iter().zip(errors.iter()) {
+            assert_close!(*v, PI, 1e-6);
+            assert_close!(*e, 0., 1e-6);
+        }
+
+        let session = DCSession::new(1, DCOptions::default());
+        assert!(session
+            .calc_environment_irradiance(&sensors, &scene, &UniformSky(1.))
+            .is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut scene, _) = crate::load_scene("./tests/obstruction/roofed_courtyard.rad").unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 200,
+                ..DCOptions::default()
+            },
+        );
+        let sensors = vec![
+            // In the roofed courtyard
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(5., 5., 1.),
+                direction: Vector3D::new(0., 0., 1.),
+            }),
+            // Above the roof, looking sideways (i.e., half towards the ground)
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(5., 5., 20.),
+                direction: Vector3D::new(1., 0., 0.),
+            }),
+        ];
+        let (dc, _) = session
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+        assert!(dc.events.has(EventKind::EnclosedSensor, 0));
+        assert!(!dc.events.has(EventKind::EnclosedSensor, 1));
+        assert!(dc.events.has(EventKind::BelowHorizonEscape, 1));
+        assert!(!dc.events.has(EventKind::BelowHorizonEscape, 0));
+
+        // A sensor mounted on a roof and looking down, through it
+        let (mut roofed, report) = crate::load_scene("./tests/sensitivity/roof.rad").unwrap();
+        roofed.build_accelerator();
+        let under = SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., 0., 3.01),
+            direction: Vector3D::new(0., 0., -1.),
+        });
+        let mounted = vec![
+            under.clone(),
+            under.with_host_surface(report.object_id("roof").unwrap()),
+        ];
+        let (dc, _) = session
+            .calc_sensor_dc_with_stats(&mounted, &roofed, false)
+            .unwrap();
+        assert!(!dc.events.has(EventKind::ExcludedObjectHit, 0));
+        assert!(dc.events.has(EventKind::ExcludedObjectHit, 1));
+
+        // An environment that returns invalid radiances
+        struct BrokenSky;
+        impl SkyRadiance for BrokenSky {
+            fn radiance(&self, direction: Vector3D) -> Float {
+                if direction.x > 0.5 {
+                    Float::NAN
+                } else if direction.y > 0.5 {
+                    -1.
+                } else {
+                    1.
+                }
+            }
+        }
+        let scene = Scene::new();
+        let up = vec![SensorSpec::from(sensors[0].ray)];
+        let (values, _, events) = session
+            .calc_environment_irradiance(&up, &scene, &BrokenSky)
+            .unwrap();
+        assert!(values[0].is_finite() && values[0] >= 0.0);
+        assert!(events.has(EventKind::BadSample, 0));
+        assert!(events.has(EventKind::ClampedValue, 0));
+        assert!(!events.has(EventKind::EnclosedSensor, 0));
+
+        // The broken samples count as dark ones, like the clamped ones
+        struct DarkSky;
+        impl SkyRadiance for DarkSky {
+            fn radiance(&self, direction: Vector3D) -> Float {
+                if direction.x > 0.5 || direction.y > 0.5 {
+                    0.
+                } else {
+                    1.
+                }
+            }
+        }
+        let (dark, _, _) = session
+            .calc_environment_irradiance(&up, &scene, &DarkSky)
+            .unwrap();
+        assert_close!(values[0], dark[0], 1e-6);
+    }
+
+    #[test]
+    fn test_termination_policy() {
+        let options = DCOptions {
+            max_depth: 0,
+            termination: TerminationPolicy::Throughput {
+                min_contribution: 1e-3,
+            },
+            ..DCOptions::default()
+        };
+        assert_eq!(options.max_bounces(), MAX_SAFE_DEPTH);
+        assert!(!options.is_direct());
+        let factory = DCSession::new(1, options).factory();
+        assert_eq!(factory.max_depth, MAX_SAFE_DEPTH);
+        assert_close!(factory.limit_weight, 1e-3, 1e-9);
+
+        // The deepest single path is used for estimating resources
+        let est = estimate_resources(1, 1, &options);
+        assert_eq!(
+            est.secondary_rays,
+            MAX_SAFE_DEPTH * options.n_ambient_samples
+        );
+
+        let fixed = DCOptions {
+            max_depth: 3,
+            ..DCOptions::default()
+        };
+        assert_eq!(DCSession::new(1, fixed).factory().max_depth, 3);
+    }
+
+    #[test]
+    fn test_externally_reflected_dc() {
+        // A large grey floor, seen from a sensor looking down and one looking up
+        let rho = 0.5;
+        let mut builder = crate::SceneBuilder::new();
+        builder
+            .add_material("floor_mat", crate::Material::plastic(rho))
+            .unwrap();
+        let l = 200.;
+        builder
+            .add_polygon(
+                "floor_mat",
+                "floor",
+                &[
+                    Point3D::new(-l, -l, 0.),
+                    Point3D::new(l, -l, 0.),
+                    Point3D::new(l, l, 0.),
+                    Point3D::new(-l, l, 0.),
+                ],
+            )
+            .unwrap();
+        let (scene, report) = builder.build().unwrap();
+        let sensor = |z: Float| {
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(0., 0., 1.),
+                direction: Vector3D::new(0., 0., z),
+            })
+        };
+        let sensors = [sensor(-1.), sensor(1.)];
+        let options = DCOptions {
+            max_depth: 2,
+            n_ambient_samples: 2000,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+        let components = session
+            .calc_externally_reflected_dc(&sensors, &scene, &report)
+            .unwrap();
+        let (nrows, ncols) = components.sky.size();
+        assert_eq!((nrows, ncols), (2, session.basis().unwrap().n_bins()));
+        assert_eq!(components.externally_reflected.size(), (nrows, ncols));
+        let row = |m: &Matrix, r: usize| -> Vec<Float> {
+            (0..ncols).map(|c| m.get(r, c).unwrap()).collect()
+        };
+        let ground = SkyBasis::GROUND_BIN;
+
+        // Looking down, the sky is only reached after the floor reflects it,
+        // and the reflected light comes from above
+        let (sky, reflected) = (
+            row(&components.sky, 0),
+            row(&components.externally_reflected, 0),
+        );
+        assert!(sky.iter().all(|v| *v >= 0.0));
+        assert!(reflected.iter().all(|v| *v >= 0.0));
+        for c in (0..ncols).filter(|c| *c != ground) {
+            assert_eq!(sky[c], 0.0);
+        }
+        assert_eq!(reflected[ground], 0.0);
+        let sum: Float = reflected.iter().sum();
+        assert_close!(sum, rho * PI, 0.05 * rho * PI);
+        // Only the paths that graze the floor go past its edge
+        assert!(sky[ground] < 0.01 * PI);
+
+        // Looking up, nothing is reflected, and the sky is that of the direct tracer
+        let direct = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                ..options
+            },
+        )
+        .calc_sensor_dc(&sensors, &scene)
+        .unwrap();
+        let (sky, reflected) = (
+            row(&components.sky, 1),
+            row(&components.externally_reflected, 1),
+        );
+        assert!(reflected.iter().all(|v| *v == 0.0));
+        for (c, v) in sky.iter().enumerate() {
+            assert_close!(*v, direct.matrix.get(1, c).unwrap(), 1e-4);
+        }
+
+        // Without bounces, everything comes from the sky
+        let components = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                ..options
+            },
+        )
+        .calc_externally_reflected_dc(&sensors, &scene, &report)
+        .unwrap();
+        for r in 0..nrows {
+            assert!(row(&components.externally_reflected, r)
+                .iter()
+                .all(|v| *v == 0.0));
+        }
+    }
+
+    #[test]
+    fn test_filtered_bounces() {
+        use crate::ray_filter::{RayAction, RayFilter};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // A floor around a sensor looking sideways, and a large wall far
+        // away in front of it
+        let scene = |far_wall: bool| {
+            let mut builder = crate::SceneBuilder::new();
+            builder
+                .add_material("mat", crate::Material::plastic(0.5))
+                .unwrap();
+            let (l, d, h) = (20., 300., 200.);
+            let mut quads = vec![(
+                "floor",
+                [(-l, -l, 0.), (l, -l, 0.), (l, l, 0.), (-l, l, 0.)],
+            )];
+            if far_wall {
+                quads.push(("wall", [(d, -d, 0.), (d, d, 0.), (d, d, h), (d, -d, h)]));
+            }
+            for (name, quad) in quads {
+                let vertices = quad.map(|(x, y, z)| Point3D::new(x, y, z));
+                builder.add_polygon("mat", name, &vertices).unwrap();
+            }
+            let (mut scene, report) = builder.build().unwrap();
+            scene.build_accelerator();
+            (scene, report)
+        };
+        let sensors = [SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(1., 0., 0.),
+        })];
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 2,
+                n_ambient_samples: 2000,
+                ..DCOptions::default()
+            },
+        );
+        // Filters that count the rays they let through, and the deepest ray they saw
+        let filtered = |max_distance: Float| {
+            let (traced, deepest) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+            let (t, d) = (traced.clone(), deepest.clone());
+            let filter = RayFilter::new(move |ray, depth| {
+                d.fetch_max(depth, Ordering::Relaxed);
+                if ray.origin.distance(Point3D::new(0., 0., 1.)) > max_distance {
+                    return RayAction::Kill;
+                }
+                t.fetch_add(1, Ordering::Relaxed);
+                RayAction::Continue
+            });
+            (session.clone().with_ray_filter(filter), traced, deepest)
+        };
+        let matrices = |c: &DCComponents| [c.sky.clone(), c.externally_reflected.clone()];
+
+        for far_wall in [false, true] {
+            let (scene, report) = scene(far_wall);
+            let baseline = session
+                .calc_externally_reflected_dc(&sensors, &scene, &report)
+                .unwrap();
+            let (never, all_rays, deepest) = filtered(Float::MAX);
+            let unfiltered = never
+                .calc_externally_reflected_dc(&sensors, &scene, &report)
+                .unwrap();
+            // The filter sees every bounce: off the floor, and then off the wall
+            let bounces = if far_wall { 2 } else { 1 };
+            assert_eq!(deepest.load(Ordering::Relaxed), bounces);
+            let (near, near_rays, _) = filtered(100.);
+            let clipped = near
+                .calc_externally_reflected_dc(&sensors, &scene, &report)
+                .unwrap();
+            let (baseline, unfiltered, clipped) = (
+                matrices(&baseline),
+                matrices(&unfiltered),
+                matrices(&clipped),
+            );
+            let (all_rays, near_rays) = (
+                all_rays.load(Ordering::Relaxed),
+                near_rays.load(Ordering::Relaxed),
+            );
+            let ncols = baseline[0].size().1;
+            let mut lost = 0.0;
+            for (i, m) in baseline.iter().enumerate() {
+                for c in 0..ncols {
+                    let v = m.get(0, c).unwrap();
+                    // A filter that never fires changes nothing
+                    assert_eq!(unfiltered[i].get(0, c).unwrap(), v);
+                    let clipped = clipped[i].get(0, c).unwrap();
+                    if far_wall && i == 1 {
+                        // Only the light reflected by the far wall is lost
+                        assert!(clipped <= v);
+                        lost += v - clipped;
+                    } else {
+                        assert_eq!(clipped, v);
+                    }
+                }
+            }
+            if far_wall {
+                // Fewer rays are traced, at the cost of what they would have brought
+                assert!(near_rays < all_rays);
+                assert!(lost > 0.0);
+            } else {
+                assert_eq!(near_rays, all_rays);
+            }
+        }
+    }
+
+    #[test]
+    fn test_low_discrepancy_sampling() {
+        // The exact coefficients of an unobstructed sensor facing up
+        let mf = 1;
+        let mut exact = vec![0.0];
+        for (min_alt, max_alt, min_az, max_az) in crate::sky::patch_bounds(mf) {
+            let sin2 = |a: Float| a.sin() * a.sin();
+            exact.push(0.5 * (max_az - min_az) * (sin2(max_alt) - sin2(min_alt)));
+        }
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = sensors(16).into_iter().map(SensorSpec::from).collect();
+        let rms_error = |sampling: SamplingSequence, n_ambient_samples: usize| {
+            let session = DCSession::new(
+                mf,
+                DCOptions {
+                    max_depth: 0,
+                    n_ambient_samples,
+                    sampling,
+                    ..DCOptions::default()
+                },
+            );
+            let dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+            let mut sum = 0.0;
+            for r in 0..sensors.len() {
+                for (c, e) in exact.iter().enumerate() {
+                    sum += (dc.matrix.get(r, c).unwrap() - e).powi(2);
+                }
+            }
+            (sum / (sensors.len() * exact.len()) as Float).sqrt()
+        };
+        let random = rms_error(SamplingSequence::Random, 4096);
+        assert!(random < 6e-3);
+        // Halving the error takes 4 times more pseudo-random samples, but
+        // low-discrepancy sequences get there with a quarter of them
+        for sampling in [SamplingSequence::Sobol, SamplingSequence::Halton] {
+            let qmc = rms_error(sampling, 1024);
+            assert!(qmc <= random, "{:?}: {} > {}", sampling, qmc, random);
+        }
+    }
+
+    #[test]
+    fn test_calc_dc_iter() {
+        use std::cell::Cell;
+
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 4,
+                ..DCOptions::default()
+            },
+        );
+        let n = 10_000;
+        let batch_size = 256;
+        let generated = Cell::new(0);
+        let grid = (0..n).map(|i| {
+            generated.set(generated.get() + 1);
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new((i % 100) as Float, (i / 100) as Float, 0.8),
+                direction: Vector3D::new(0., 0., 1.),
+            })
+        });
+        let mut received = 0;
+        session
+            .calc_dc_iter(grid, &scene, batch_size, |first, dc| {
+                // Batches arrive in order, and sensors are not pulled ahead
+                assert_eq!(first, received);
+                assert!(dc.rows.len() <= batch_size);
+                assert!(generated.get() - first <= batch_size);
+                received += dc.rows.len();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(received, n);
+
+        // Batching does not change the results
+        let sensors: Vec<SensorSpec> = sensors(3).into_iter().map(SensorSpec::from).collect();
+        let whole = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        let mut rows = Vec::new();
+        session
+            .calc_dc_iter(sensors.clone(), &scene, 2, |first, dc| {
+                for r in 0..dc.rows.len() {
+                    rows.push((first + r, dc.matrix.get(r, 5).unwrap()));
+                }
+                Ok(())
+            })
+            .unwrap();
+        for (r, v) in rows {
+            assert_eq!(v, whole.matrix.get(r, 5).unwrap());
+        }
+
+        // Errors of the sink stop the calculation
+        let mut calls = 0;
+        let err = session.calc_dc_iter(sensors, &scene, 1, |_, _| {
+            calls += 1;
+            Err("full disk".to_string())
+        });
+        assert!(err.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_ray_filters() {
+        use crate::ray_filter::{RayAction, RayFilter};
+        use crate::test_support::square_aperture;
+        use std::time::Instant;
+
+        let reference = square_aperture(1., 1.).unwrap();
+        let sensors: Vec<SensorSpec> = reference
+            .sensors
+            .iter()
+            .map(|s| SensorSpec::from(s.ray))
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20_000,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+        let start = Instant::now();
+        let baseline = session.calc_sensor_dc(&sensors, &reference.scene).unwrap();
+        let baseline_time = start.elapsed();
+
+        // A filter that never fires changes nothing
+        let never = session
+            .clone()
+            .with_ray_filter(RayFilter::new(|_, _| RayAction::Continue));
+        let dc = never.calc_sensor_dc(&sensors, &reference.scene).unwrap();
+        let n_bins = dc.matrix.size().1;
+        for c in 0..n_bins {
+            assert_eq!(
+                dc.matrix.get(0, c).unwrap(),
+                baseline.matrix.get(0, c).unwrap()
+            );
+        }
+
+        // Killing every ray leaves nothing, and is faster than tracing them
+        let kill = session
+            .clone()
+            .with_ray_filter(RayFilter::new(|_, _| RayAction::Kill));
+        let start = Instant::now();
+        let dc = kill.calc_sensor_dc(&sensors, &reference.scene).unwrap();
+        assert!(start.elapsed() < baseline_time);
+        for c in 0..n_bins {
+            assert_eq!(dc.matrix.get(0, c).unwrap(), 0.0);
+        }
+
+        // Forcing every ray to escape ignores the roof
+        let escape = session
+            .clone()
+            .with_ray_filter(RayFilter::new(|_, _| RayAction::ForceSkyEscape));
+        let dc = escape.calc_sensor_dc(&sensors, &reference.scene).unwrap();
+        let mut empty = Scene::new();
+        empty.build_accelerator();
+        let unobstructed = session.calc_sensor_dc(&sensors, &empty).unwrap();
+        for c in 0..n_bins {
+            assert_eq!(
+                dc.matrix.get(0, c).unwrap(),
+                unobstructed.matrix.get(0, c).unwrap()
+            );
+        }
+
+        // The filter sees the rays leaving the sensor, at depth 0
+        let checked = session
+            .clone()
+            .with_ray_filter(RayFilter::new(|ray, depth| {
+                assert_eq!(depth, 0);
+                assert!(ray.direction.z > 0.);
+                RayAction::Continue
+            }));
+        assert!(checked.calc_sensor_dc(&sensors, &reference.scene).is_ok());
+
+        // The bounces cannot be filtered
+        let bouncing = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 2,
+                ..options
+            },
+        )
+        .with_ray_filter(RayFilter::new(|_, _| RayAction::Kill));
+        let err = bouncing
+            .calc_sensor_dc(&sensors, &reference.scene)
+            .unwrap_err();
+        assert!(err.contains("direct tracer"));
+        assert!(bouncing
+            .calc_dc(&[reference.sensors[0].ray], &reference.scene)
+            .is_err());
+    }
+
+    #[test]
+    fn test_escape_radiance() {
+        use crate::environment::{EscapeRadiance, SkyRadiance};
+        use crate::test_support::square_aperture;
+
+        let reference = square_aperture(1., 1.).unwrap();
+        let sensors: Vec<SensorSpec> = reference
+            .sensors
+            .iter()
+            .map(|s| SensorSpec::from(s.ray))
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20_000,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+        let calc = |escape: EscapeRadiance| -> Vec<Float> {
+            let dc = session
+                .clone()
+                .with_escape_radiance(escape)
+                .calc_sensor_dc(&sensors, &reference.scene)
+                .unwrap()
+                .matrix;
+            (0..dc.size().1).map(|c| dc.get(0, c).unwrap()).collect()
+        };
+        let unit = calc(EscapeRadiance::default());
+        let times = |sky: &dyn Fn(usize) -> Float| -> Float {
+            unit.iter().enumerate().map(|(bin, v)| v * sky(bin)).sum()
+        };
+
+        // Tracing a uniform sky is the same as multiplying by it afterwards
+        let uniform = calc(EscapeRadiance::custom(|_| 2.));
+        for (u, v) in unit.iter().zip(uniform.iter()) {
+            assert_close!(*v, 2. * u, 1e-6);
+        }
+        assert_close!(uniform.iter().sum::<Float>(), times(&|_| 2.), 1e-5);
+
+        // The overcast sky is brighter at the zenith, which is all that the
+        // sensor sees, so it differs from a uniform sky...
+        let overcast = calc(EscapeRadiance::CieOvercast);
+        let traced: Float = overcast.iter().sum();
+        let uniform: Float = times(&|_| 1.);
+        assert!(traced > 1.2 * uniform, "{} vs {}", traced, uniform);
+        // ... but it matches the discretised overcast sky
+        let patches = session.basis().unwrap().patches();
+        let discretised = times(&|bin| match bin {
+            0 => 1.,
+            _ => EscapeRadiance::CieOvercast.radiance(patches[bin - 1].centroid),
+        });
+        assert!((traced - discretised).abs() < 0.02 * traced);
+        // ... and it is as bright as the unit sky on the horizontal
+        let mut empty = Scene::new();
+        empty.build_accelerator();
+        let up = session
+            .clone()
+            .with_escape_radiance(EscapeRadiance::CieOvercast)
+            .calc_sensor_dc(&sensors, &empty)
+            .unwrap()
+            .matrix;
+        let horizontal: Float = (0..up.size().1).map(|c| up.get(0, c).unwrap()).sum();
+        assert!((horizontal - crate::PI).abs() < 0.01 * crate::PI);
+
+        // Only the direct tracer weights the escaping rays
+        let bounces = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 1,
+                ..options
+            },
+        )
+        .with_escape_radiance(EscapeRadiance::CieOvercast);
+        assert!(bounces.calc_sensor_dc(&sensors, &reference.scene).is_err());
+    }
+
+    #[test]
+    fn test_serial_matches_parallel() {
+        use crate::test_support::square_aperture;
+
+        let reference = square_aperture(1., 1.).unwrap();
+        let sensors: Vec<SensorSpec> = (0..16)
+            .map(|i| {
+                SensorSpec::from(Ray3D {
+                    origin: Point3D::new(0.1 * (i % 4) as Float, 0.1 * (i / 4) as Float, 0.),
+                    direction: Vector3D::new(0., 0., 1.),
+                })
+            })
+            .collect();
+        for sampling in [SamplingSequence::Random, SamplingSequence::Sobol] {
+            let options = DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 500,
+                seed: 3,
+                sampling,
+                ..DCOptions::default()
+            };
+            let serial = DCSession::new(
+                1,
+                DCOptions {
+                    serial: true,
+                    ..options
+                },
+            );
+            let parallel = DCSession::new(1, options);
+            let (a, a_stats) = serial
+                .calc_sensor_dc_with_stats(&sensors, &reference.scene, true)
+                .unwrap();
+            let (b, b_stats) = parallel
+                .calc_sensor_dc_with_stats(&sensors, &reference.scene, true)
+                .unwrap();
+            let (nrows, ncols) = a.matrix.size();
+            let a_errors = a_stats.bin_standard_errors.unwrap();
+            let b_errors = b_stats.bin_standard_errors.unwrap();
+            for r in 0..nrows {
+                for c in 0..ncols {
+                    assert_eq!(a.matrix.get(r, c).unwrap(), b.matrix.get(r, c).unwrap());
+                    assert_eq!(a_errors.get(r, c).unwrap(), b_errors.get(r, c).unwrap());
+                }
+            }
+            assert_eq!(a_stats.standard_errors, b_stats.standard_errors);
+
+            // Nor do results depend on how sensors are batched
+            let mut batched: Vec<Vec<Float>> = Vec::new();
+            parallel
+                .calc_dc_iter(sensors.clone(), &reference.scene, 3, |_, dc| {
+                    for r in 0..dc.rows.len() {
+                        batched.push((0..ncols).map(|c| dc.matrix.get(r, c).unwrap()).collect());
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            for (r, row) in batched.iter().enumerate() {
+                let expected: Vec<Float> =
+                    (0..ncols).map(|c| a.matrix.get(r, c).unwrap()).collect();
+                assert_eq!(row, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_basis() {
+        let session = DCSession::new(2, DCOptions::default());
+        let basis = session.basis().unwrap();
+        assert_eq!(basis.n_bins(), ReinhartSky::n_bins(2));
+        assert_eq!(DCSession::from_basis(basis, DCOptions::default()).mf(), 2);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let field = |mf: usize, options: DCOptions| DCSession::try_new(mf, options).unwrap_err();
+        let default = DCOptions::default();
+        for mf in [0, 13] {
+            let e = field(mf, default);
+            assert_eq!(e.field, "mf");
+            assert_eq!(e.value, format!("{}", mf));
+            assert!(e.to_string().contains("'mf'"));
+        }
+        assert!(DCSession::try_new(12, default).is_ok());
+
+        let e = field(
+            1,
+            DCOptions {
+                n_ambient_samples: 0,
+                ..default
+            },
+        );
+        assert_eq!(e.field, "n_ambient_samples");
+        assert_eq!(e.value, "0");
+
+        let e = field(
+            1,
+            DCOptions {
+                max_depth: 65,
+                ..default
+            },
+        );
+        assert_eq!(e.field, "max_depth");
+        assert_eq!(e.value, "65");
+        assert!(DCSession::try_new(
+            1,
+            DCOptions {
+                max_depth: MAX_SAFE_DEPTH,
+                ..default
+            }
+        )
+        .is_ok());
+
+        for bad in [-1.0, Float::NAN, Float::INFINITY] {
+            let e = field(
+                1,
+                DCOptions {
+                    termination: TerminationPolicy::Throughput {
+                        min_contribution: bad,
+                    },
+                    ..default
+                },
+            );
+            assert_eq!(e.field, "min_contribution");
+            assert_eq!(e.value, format!("{}", bad));
+        }
+
+        // The old constructor panics, naming the field
+        let panic = std::panic::catch_unwind(|| DCSession::new(0, default)).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains("'mf'"));
+    }
+
+    #[test]
+    fn test_no_budget() {
+        let session = DCSession::new(6, DCOptions::default());
+        assert!(session.check_budget(100_000).is_ok());
+    }
+
+    #[test]
+    fn test_jitter_origins() {
+        use crate::{Material, SceneBuilder, TributaryArea};
+
+        // Slats 0.1 wide every 0.2, just above the workplane
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("slat_mat", Material::plastic(0.5))
+            .unwrap();
+        for i in -50..50 {
+            let x = i as Float * 0.2 - 0.05;
+            let vertices = [(x, -10.), (x + 0.1, -10.), (x + 0.1, 10.), (x, 10.)]
+                .map(|(x, y)| Point3D::new(x, y, 0.1));
+            builder
+                .add_polygon("slat_mat", &format!("slat_{}", i), &vertices)
+                .unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let total = |options: DCOptions, sensors: &[SensorSpec]| -> Vec<Float> {
+            let dc = DCSession::new(1, options)
+                .calc_sensor_dc(sensors, &scene)
+                .unwrap()
+                .matrix;
+            (0..sensors.len())
+                .map(|r| (0..dc.size().1).map(|c| dc.get(r, c).unwrap()).sum())
+                .collect()
+        };
+        let at = |x: Float| -> SensorSpec {
+            Ray3D {
+                origin: Point3D::new(x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        };
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20000,
+            seed: 1,
+            ..DCOptions::default()
+        };
+
+        // Under the middle of a slat, and in the middle of a gap
+        let points = total(options, &[at(0.), at(0.1)]);
+        let (worst, best) = (points[0], points[1]);
+        assert!(best > 1.2 * worst, "{} vs {}", best, worst);
+
+        // A cell covering one period, centred under a slat
+        let area = TributaryArea::rectangle(0.2, 0.2).unwrap();
+        let sensor = at(0.).with_area(area).unwrap();
+        let jittered = DCOptions {
+            jitter_origins: true,
+            n_ambient_samples: 80000,
+            ..options
+        };
+        let averaged = total(jittered, std::slice::from_ref(&sensor))[0];
+        assert!(averaged > worst && averaged < best);
+
+        // The average of a dense grid of points within the cell
+        let dense: Vec<SensorSpec> = (0..40)
+            .map(|i| at(-0.1 + (i as Float + 0.5) * 0.005))
+            .collect();
+        let dense = total(
+            DCOptions {
+                n_ambient_samples: 2000,
+                ..options
+            },
+            &dense,
+        );
+        let expected = dense.iter().sum::<Float>() / dense.len() as Float;
+        assert_close!(averaged, expected, 0.02 * expected);
+
+        // Reproducible, and only samples of sensors with an area move
+        assert_eq!(averaged, total(jittered, &[sensor])[0]);
+        assert_eq!(total(options, &[at(0.)])[0], worst);
+        assert_eq!(
+            total(jittered, &[at(0.)])[0],
+            total(
+                DCOptions {
+                    jitter_origins: false,
+                    ..jittered
+                },
+                &[at(0.)]
+            )[0]
+        );
+
+        // The DCFactory cannot jitter
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 2,
+                ..jittered
+            },
+        );
+        assert!(session.calc_sensor_dc(&[at(0.)], &scene).is_err());
+    }
+
+    #[test]
+    fn test_facing_into_surface() {
+        use crate::{Material, SceneBuilder};
+
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("floor_mat", Material::plastic(0.2))
+            .unwrap();
+        let vertices =
+            [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        builder
+            .add_polygon("floor_mat", "floor", &vertices)
+            .unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+
+        // A grid 1 mm above the floor, exported upside down
+        let grid = |dz: Float| -> Vec<SensorSpec> {
+            (0..3)
+                .map(|i| {
+                    Ray3D {
+                        origin: Point3D::new(i as Float - 1., 0., 0.001),
+                        direction: Vector3D::new(0., 0., dz),
+                    }
+                    .into()
+                })
+                .collect()
+        };
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 2000,
+            seed: 1,
+            ..DCOptions::default()
+        };
+        let run = |options: DCOptions, sensors: &[SensorSpec]| {
+            DCSession::new(1, options)
+                .calc_sensor_dc(sensors, &scene)
+                .unwrap()
+        };
+        let total = |dc: &LabeledMatrix, r: usize| -> Float {
+            (0..dc.matrix.size().1)
+                .map(|c| dc.matrix.get(r, c).unwrap())
+                .sum()
+        };
+
+        // Facing up is fine
+        let up = run(options, &grid(1.));
+        assert_eq!(up.events.of_kind(EventKind::FacingIntoSurface).count(), 0);
+
+        // Facing down is reported, but not corrected
+        let down = run(options, &grid(-1.));
+        let flagged: Vec<&Event> = down.events.of_kind(EventKind::FacingIntoSurface).collect();
+        assert_eq!(flagged.len(), 3);
+        assert!(flagged
+            .iter()
+            .all(|e| e.message.contains("auto_flip_into_surface")));
+        assert!(total(&down, 0) < 1e-3 * total(&up, 0));
+
+        // Unless asked to
+        let flipped = run(
+            DCOptions {
+                auto_flip_into_surface: true,
+                ..options
+            },
+            &grid(-1.),
+        );
+        assert_eq!(
+            flipped.events.of_kind(EventKind::FacingIntoSurface).count(),
+            3
+        );
+        for r in 0..3 {
+            assert_eq!(total(&flipped, r), total(&up, r));
+        }
+
+        // A shorter probe does not see the floor
+        let short = run(
+            DCOptions {
+                probe_distance: 0.0005,
+                ..options
+            },
+            &grid(-1.),
+        );
+        assert_eq!(
+            short.events.of_kind(EventKind::FacingIntoSurface).count(),
+            0
+        );
+
+        assert!(DCOptions {
+            probe_distance: -1.,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_sensors_on_surface() {
+        use crate::{Material, SceneBuilder};
+
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("floor_mat", Material::plastic(0.2))
+            .unwrap();
+        let floor =
+            [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)].map(|(x, y)| Point3D::new(x, y, 0.));
+        builder.add_polygon("floor_mat", "floor", &floor).unwrap();
+        let wall =
+            [(0., 0.), (0., 3.), (10., 3.), (10., 0.)].map(|(y, z)| Point3D::new(2., y - 5., z));
+        builder.add_polygon("floor_mat", "wall", &wall).unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+
+        let grid = |z: Float| -> Vec<SensorSpec> {
+            (0..9)
+                .map(|i| {
+                    Ray3D {
+                        origin: Point3D::new(0.25 * i as Float - 0.5, 0.1 * i as Float, z),
+                        direction: Vector3D::new(0., 0., 1.),
+                    }
+                    .into()
+                })
+                .collect()
+        };
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 500,
+            seed: 3,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(1, options);
+
+        // Sensors at the height of the floor are moved to 1 mm above it, and reported
+        let on = session.calc_sensor_dc(&grid(0.), &scene).unwrap();
+        let above = session.calc_sensor_dc(&grid(0.001), &scene).unwrap();
+        assert_eq!(on.events.of_kind(EventKind::OnSurface).count(), 9);
+        assert!(on
+            .events
+            .of_kind(EventKind::OnSurface)
+            .all(|e| e.message.contains("surface_offset")));
+        let same = |a: &LabeledMatrix, b: &LabeledMatrix| {
+            let (nrows, ncols) = a.matrix.size();
+            for r in 0..nrows {
+                for c in 0..ncols {
+                    assert_eq!(a.matrix.get(r, c).unwrap(), b.matrix.get(r, c).unwrap());
+                }
+            }
+        };
+        same(&on, &above);
+        // They keep the ids of where they were placed
+        for (row, sensor) in on.rows.iter().zip(grid(0.).iter()) {
+            assert_eq!(row.id, sensor.sensor_id());
+            assert_ne!(row.id, SensorId::generated(&row.ray, None));
+        }
+
+        // Whichever way the direct tracer is called
+        let two_sided = |z: Float| session.calc_two_sided_dc(&grid(z), &scene).unwrap();
+        let (on_two_sided, above_two_sided) = (two_sided(0.), two_sided(0.001));
+        assert_eq!(
+            on_two_sided
+                .sky
+                .events
+                .of_kind(EventKind::OnSurface)
+                .count(),
+            9
+        );
+        same(&on_two_sided.sky, &above_two_sided.sky);
+        let environment = |z: Float| {
+            session
+                .calc_environment_irradiance(&grid(z), &scene, &UniformSky(1.))
+                .unwrap()
+        };
+        let (on_environment, above_environment) = (environment(0.), environment(0.001));
+        assert_eq!(on_environment.0, above_environment.0);
+        assert_eq!(on_environment.2.of_kind(EventKind::OnSurface).count(), 9);
+        let sparse = session.calc_sparse_dc(&grid(0.), &scene, 0.0).unwrap();
+        let (nrows, ncols) = above.matrix.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(sparse.get(r, c).unwrap(), above.matrix.get(r, c).unwrap());
+            }
+        }
+
+        // And so are those closer than that
+        let close = session.calc_sensor_dc(&grid(0.0005), &scene).unwrap();
+        assert_eq!(close.events.of_kind(EventKind::OnSurface).count(), 9);
+        same(&close, &above);
+
+        // But not those further up, or when disabled
+        let far = session.calc_sensor_dc(&grid(0.01), &scene).unwrap();
+        assert_eq!(far.events.of_kind(EventKind::OnSurface).count(), 0);
+        let disabled = DCSession::new(
+            1,
+            DCOptions {
+                surface_offset: 0.,
+                ..options
+            },
+        )
+        .calc_sensor_dc(&grid(0.), &scene)
+        .unwrap();
+        assert_eq!(disabled.events.of_kind(EventKind::OnSurface).count(), 0);
+
+        assert!(DCOptions {
+            surface_offset: Float::NAN,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_calc_dc_into() {
+        let scene = Scene::new();
+        let sensors: Vec<SensorSpec> = (0..4)
+            .map(|i| {
+                Ray3D {
+                    origin: Point3D::new(i as Float, 0., 0.),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 300,
+            ..DCOptions::default()
+        };
+        let session = DCSession::new(2, options);
+        let n_bins = session.basis().unwrap().n_bins();
+
+        // Filling the matrix in batches is the same as doing it at once
+        let mut into = Matrix::new(0.0, 4, n_bins);
+        session
+            .calc_dc_into(&mut into, 0, &sensors[..2], &scene)
+            .unwrap();
+        session
+            .calc_dc_into(&mut into, 2, &sensors[2..], &scene)
+            .unwrap();
+        let dc = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+        for r in 0..4 {
+            for c in 0..n_bins {
+                assert_eq!(into.get(r, c).unwrap(), dc.get(r, c).unwrap());
+            }
+        }
+
+        // Shapes are checked before tracing (the factory would take ages)
+        let session = DCSession::new(
+            2,
+            DCOptions {
+                max_depth: 3,
+                n_ambient_samples: 100_000_000,
+                ..options
+            },
+        );
+        let mut mf1 = Matrix::new(-1.0, 4, ReinhartSky::n_bins(1));
+        let err = session
+            .calc_dc_into(&mut mf1, 0, &sensors, &scene)
+            .unwrap_err();
+        let expected = format!("4x{}", ReinhartSky::n_bins(1));
+        assert!(err.contains(&expected), "{}", err);
+        assert!(err.contains(&format!("{} columns", n_bins)), "{}", err);
+        assert_eq!(mf1.get(0, 0).unwrap(), -1.0);
+        let mut short = Matrix::new(0.0, 4, n_bins);
+        let err = session
+            .calc_dc_into(&mut short, 1, &sensors, &scene)
+            .unwrap_err();
+        assert!(err.contains("need 5 rows"), "{}", err);
+    }
+
+    #[test]
+    fn test_sample_clamp() {
+        use crate::importance::{BoundingSphere, ImportanceHints};
+
+        // Nearly every sample goes towards an object that hides all but the
+        // horizon, so the few that reach the horizon have a tiny probability
+        // and a huge weight
+        let hints = ImportanceHints::new(
+            vec![BoundingSphere {
+                centre: Point3D::new(0., 0., 1.),
+                radius: 0.99,
+            }],
+            0.99,
+        )
+        .unwrap();
+        let scene = Scene::new();
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20000,
+            seed: 5,
+            ..DCOptions::default()
+        };
+        let run = |options: DCOptions, hints: Option<&ImportanceHints>| {
+            let mut session = DCSession::new(1, options);
+            if let Some(hints) = hints {
+                session = session.with_importance_hints(hints.clone());
+            }
+            let (dc, stats) = session
+                .calc_sensor_dc_with_stats(&sensors, &scene, false)
+                .unwrap();
+            let row: Vec<Float> = (0..dc.matrix.size().1)
+                .map(|c| dc.matrix.get(0, c).unwrap())
+                .collect();
+            (row, stats)
+        };
+        let (reference, _) = run(options, None);
+        let (free, free_stats) = run(options, Some(&hints));
+        let clamp = DCOptions {
+            sample_clamp: Some(SampleClamp::Relative(20.)),
+            ..options
+        };
+        let (clamped, stats) = run(clamp, Some(&hints));
+        let max = |v: &[Float]| v.iter().cloned().fold(0.0, Float::max);
+        let sum = |v: &[Float]| v.iter().sum::<Float>();
+
+        // The fireflies land on the lowest row of the sky, which is mostly clamped
+        let horizon = 1..31;
+        let reference_max = max(&reference[horizon.clone()]);
+        assert!(max(&free[horizon.clone()]) > 2. * reference_max);
+        assert!(max(&clamped[horizon]) < 1.5 * reference_max);
+
+        // What was removed is what is reported, which is only a small bias
+        assert_eq!(free_stats.clamped, vec![0.0]);
+        let removed = sum(&free) - sum(&clamped);
+        assert!(stats.clamped[0] > 0.0 && stats.clamped[0] < 0.05 * sum(&free));
+        assert_close!(removed, stats.clamped[0], 1e-3 * sum(&free));
+
+        assert!(DCOptions {
+            sample_clamp: Some(SampleClamp::Absolute(0.)),
+            ..options
+        }
+        .validate()
+        .is_err());
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 2,
+                ..clamp
+            },
+        );
+        assert!(session.calc_sensor_dc(&sensors, &scene).is_err());
+    }
+
+    #[test]
+    fn test_min_cosine() {
+        use crate::{Material, SceneBuilder};
+
+        // A sensor on the floor, right next to a tall glossy wall, so that
+        // the directions grazing the floor towards the wall run along it
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("mirror", Material::metal(0.9, 0.9, 0.))
+            .unwrap();
+        builder
+            .add_polygon(
+                "mirror",
+                "wall",
+                &[
+                    Point3D::new(0., -50., 0.),
+                    Point3D::new(0., 50., 0.),
+                    Point3D::new(0., 50., 50.),
+                    Point3D::new(0., -50., 50.),
+                ],
+            )
+            .unwrap();
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let sensors: Vec<SensorSpec> = vec![Ray3D {
+            origin: Point3D::new(0.01, 0., 0.001),
+            direction: Vector3D::new(0., 0., 1.),
+        }
+        .into()];
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20000,
+            seed: 9,
+            ..DCOptions::default()
+        };
+        let run = |min_cosine: Float| {
+            let (dc, stats) = DCSession::new(
+                1,
+                DCOptions {
+                    min_cosine,
+                    ..options
+                },
+            )
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+            let row: Vec<Float> = (0..dc.matrix.size().1)
+                .map(|c| dc.matrix.get(0, c).unwrap())
+                .collect();
+            assert!(row.iter().all(|v| v.is_finite()));
+            (row.iter().sum::<Float>(), stats.grazing[0])
+        };
+        let (free, none) = run(0.);
+        assert_eq!(none, 0.0);
+        let (default, tiny) = run(DEFAULT_MIN_COSINE);
+        assert!(tiny < 1e-3 && free - default <= tiny + 1e-9);
+
+        // One sample in a hundred grazes the floor, and the wall hides half of them
+        let min_cosine = 0.1;
+        let (clipped, grazing) = run(min_cosine);
+        let band = min_cosine * min_cosine;
+        assert_close!(grazing, crate::PI * band, 0.2 * crate::PI * band);
+        let removed = free - clipped;
+        assert!(removed > 0.3 * grazing && removed < 0.7 * grazing);
+
+        assert!(DCOptions {
+            min_cosine: 1.,
+            ..options
+        }
+        .validate()
+        .is_err());
+        assert!(DCOptions {
+            min_cosine: -0.1,
+            ..options
+        }
+        .validate()
+        .is_err());
+    }
+
+    /// A sensor below some slats, which block part of the sky
+    fn slatted_scene(n_slats: usize) -> Scene {
+        use crate::{Material, SceneBuilder};
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("slat_mat", Material::plastic(0.5))
+            .unwrap();
+        for i in 0..n_slats {
+            let x = i as Float * 0.3 - 0.15 * n_slats as Float;
+            let vertices = [(x, -10.), (x + 0.1, -10.), (x + 0.1, 10.), (x, 10.)]
+                .map(|(x, y)| Point3D::new(x, y, 0.5));
+            builder
+                .add_polygon("slat_mat", &format!("slat_{}", i), &vertices)
+                .unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        scene
+    }
+
+    #[test]
+    fn test_dc_in_bases() {
+        let scene = slatted_scene(20);
+        let sensors: Vec<SensorSpec> = (0..3)
+            .map(|i| {
+                Ray3D {
+                    origin: Point3D::new(0.1 * i as Float, 0., 0.),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 2000,
+            seed: 11,
+            ..DCOptions::default()
+        };
+        let bases = [SkyBasis::new(1).unwrap(), SkyBasis::new(6).unwrap()];
+        let session = DCSession::new(2, options);
+        let both = session
+            .calc_sensor_dc_in_bases(&sensors, &scene, &bases)
+            .unwrap();
+        assert_eq!(both.len(), 2);
+        for (basis, dc) in bases.iter().zip(both.iter()) {
+            let alone = DCSession::new(basis.mf(), options)
+                .calc_sensor_dc(&sensors, &scene)
+                .unwrap();
+            assert_eq!(dc.matrix.size(), (3, basis.n_bins()));
+            for r in 0..3 {
+                for c in 0..basis.n_bins() {
+                    assert_eq!(
+                        dc.matrix.get(r, c).unwrap(),
+                        alone.matrix.get(r, c).unwrap()
+                    );
+                }
+            }
+        }
+
+        assert!(session
+            .calc_sensor_dc_in_bases(&sensors, &scene, &[])
+            .is_err());
+        let bounces = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 2,
+                ..options
+            },
+        );
+        assert!(bounces
+            .calc_sensor_dc_in_bases(&sensors, &scene, &bases)
+            .is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_dc_in_bases() {
+        let scene = slatted_scene(200);
+        let sensors: Vec<SensorSpec> = (0..50)
+            .map(|i| {
+                Ray3D {
+                    origin: Point3D::new(0.02 * i as Float, 0., 0.),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect();
+        let options = DCOptions {
+            max_depth: 0,
+            n_ambient_samples: 20000,
+            ..DCOptions::default()
+        };
+        let start = Instant::now();
+        for mf in [1, 6] {
+            DCSession::new(mf, options)
+                .calc_sensor_dc(&sensors, &scene)
+                .unwrap();
+        }
+        let separately = start.elapsed();
+        let start = Instant::now();
+        let bases = [SkyBasis::new(1).unwrap(), SkyBasis::new(6).unwrap()];
+        DCSession::new(1, options)
+            .calc_sensor_dc_in_bases(&sensors, &scene, &bases)
+            .unwrap();
+        let together = start.elapsed();
+        // Tracing once and binning twice costs less than tracing twice
+        assert!(
+            together < separately,
+            "MF 1 and MF 6 took {:?} together and {:?} separately",
+            together,
+            separately
+        );
+    }
+
+    /// A 100 m long and 1 m wide corridor, open at both ends and with a
+    /// 0.2 m wide slot along its 3 m high ceiling
+    fn corridor_scene() -> Scene {
+        use crate::{Material, SceneBuilder};
+        let mut builder = SceneBuilder::new();
+        builder.add_material("mat", Material::plastic(0.5)).unwrap();
+        let quads = [
+            [
+                (-50., -0.5, 0.),
+                (50., -0.5, 0.),
+                (50., -0.5, 3.),
+                (-50., -0.5, 3.),
+            ],
+            [
+                (-50., 0.5, 0.),
+                (50., 0.5, 0.),
+                (50., 0.5, 3.),
+                (-50., 0.5, 3.),
+            ],
+            [
+                (-50., -0.5, 3.),
+                (50., -0.5, 3.),
+                (50., -0.1, 3.),
+                (-50., -0.1, 3.),
+            ],
+            [
+                (-50., 0.1, 3.),
+                (50., 0.1, 3.),
+                (50., 0.5, 3.),
+                (-50., 0.5, 3.),
+            ],
+        ];
+        for (i, quad) in quads.iter().enumerate() {
+            let vertices = quad.map(|(x, y, z)| Point3D::new(x, y, z));
+            builder
+                .add_polygon("mat", &format!("corridor_{}", i), &vertices)
+                .unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        scene
+    }
+
+    fn corridor_sensors(n: usize) -> Vec<SensorSpec> {
+        (0..n)
+            .map(|i| {
+                Ray3D {
+                    origin: Point3D::new(0.01 * i as Float, 0., 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                }
+                .into()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_corridor_rows() {
+        // Corridor sensors see few bins, so their rows stay sparse
+        let scene = corridor_scene();
+        let sensors = corridor_sensors(3);
+        let session = DCSession::new(
+            6,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 2000,
+                ..DCOptions::default()
+            },
+        );
+        let dense = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+        let sparse = session.calc_sparse_dc(&sensors, &scene, 0.0).unwrap();
+        let n_bins = SkyBasis::new(6).unwrap().n_bins();
+        for r in 0..sensors.len() {
+            let row: Vec<(usize, Float)> = sparse.row(r).collect();
+            assert!(!row.is_empty());
+            assert!((row.len() as Float) < 0.1 * n_bins as Float);
+            let sum: Float = row.iter().map(|(_, v)| v).sum();
+            for bin in 0..n_bins {
+                let v = row.iter().find(|(c, _)| *c == bin).map_or(0.0, |e| e.1);
+                assert_eq!(dense.get(r, bin).unwrap(), v);
+            }
+            // A sensor in the middle sees the slot, and the ends at the horizon
+            assert!(sum > 0.0 && sum < 0.2 * PI);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_corridor_memory() {
+        const TEST: &str = "session::testing::bench_corridor_memory";
+        let n_sensors = 10_000;
+        match std::env::var(MEMORY_PROBE).as_deref() {
+            Ok("setup") => {
+                let _scene = corridor_scene();
+                let _sensors = corridor_sensors(n_sensors);
+                report_peak_memory().unwrap();
+            }
+            Ok("dc") => {
+                let scene = corridor_scene();
+                let sensors = corridor_sensors(n_sensors);
+                let options = DCOptions {
+                    max_depth: 0,
+                    n_ambient_samples: 500,
+                    ..DCOptions::default()
+                };
+                let _dc = DCSession::new(6, options)
+                    .calc_sensor_dc(&sensors, &scene)
+                    .unwrap();
+                report_peak_memory().unwrap();
+            }
+            _ => {
+                let setup = peak_memory_of(TEST, "setup").unwrap();
+                let dc = peak_memory_of(TEST, "dc").unwrap();
+                let n_bins = SkyBasis::new(6).unwrap().n_bins();
+                let matrix_kb = n_sensors * n_bins * std::mem::size_of::<Float>() / 1024;
+                // Dense accumulators kept two rows of n_bins per sensor until
+                // they were written, so the calculation took three times the
+                // memory of its matrix
+                assert!(
+                    dc - setup < matrix_kb * 3 / 2,
+                    "The calculation took {} kB for a matrix of {} kB",
+                    dc - setup,
+                    matrix_kb
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_branching() {
+        let session = |max_branching: Option<usize>| {
+            DCSession::new(
+                1,
+                DCOptions {
+                    max_branching,
+                    ..DCOptions::default()
+                },
+            )
+        };
+        assert_eq!(session(None).factory_runs(300), vec![300]);
+        assert_eq!(session(Some(16)).factory_runs(300), vec![255, 45]);
+        assert_eq!(session(Some(16)).factory_runs(255), vec![255]);
+        assert_eq!(session(Some(16)).factory_runs(0), vec![0]);
+        for max in [2, 3, 16] {
+            let runs = session(Some(max)).factory_runs(1000);
+            assert_eq!(runs.iter().sum::<usize>(), 1000);
+            // What the DCFactory branches into, even off a perfect reflector
+            for n in runs {
+                assert!(DCSession::factory_branching(n) <= max);
+            }
+        }
+        assert_eq!(
+            DCOptions::default().max_branching,
+            Some(DEFAULT_MAX_BRANCHING)
+        );
+        assert_eq!(session(Some(2)).factory_runs(7), vec![3, 3, 1]);
+        assert_eq!(DCSession::factory_branching(255), 16);
+        assert_eq!(DCSession::factory_branching(256), 17);
+
+        assert!(DCOptions {
+            max_branching: Some(1),
+            ..DCOptions::default()
+        }
+        .validate()
+        .is_err());
+        assert!(DCOptions {
+            max_branching: Some(2),
+            ..DCOptions::default()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_factory_runs_in_stats() {
+        let (mut scene, _) = crate::load_scene("./tests/obstruction/roofed_courtyard.rad").unwrap();
+        scene.build_accelerator();
+        let sensors = [SensorSpec::from(Ray3D {
+            origin: Point3D::new(5., 5., 1.),
+            direction: Vector3D::new(0., 0., 1.),
+        })];
+        let options = DCOptions {
+            max_depth: 1,
+            n_ambient_samples: 1000,
+            max_branching: Some(8),
+            ..DCOptions::default()
+        };
+        let (_, stats) = DCSession::new(1, options)
+            .calc_sensor_dc_with_stats(&sensors, &scene, false)
+            .unwrap();
+        // Each batch of samples is split into runs that branch into 8 rays at most
+        let batch = options.n_ambient_samples / STATS_BATCHES;
+        assert_eq!(
+            stats.factory_runs.iter().sum::<usize>(),
+            batch * STATS_BATCHES
+        );
+        assert!(stats.factory_runs.iter().all(|n| *n <= 63));
+        assert_eq!(stats.max_branching(), Some(8));
+
+        // Without bounces, there are no runs
+        let (_, stats) = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                ..options
+            },
+        )
+        .calc_sensor_dc_with_stats(&sensors, &scene, false)
+        .unwrap();
+        assert!(stats.factory_runs.is_empty());
+        assert_eq!(stats.max_branching(), None);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_mirror_corridor() {
+        // A closed corridor of almost perfect mirrors, where every path keeps
+        // most of its weight at every bounce
+        use crate::{Material, SceneBuilder};
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("mirror", Material::metal(0.95, 1.0, 0.0))
+            .unwrap();
+        let (l, w, h) = (20., 1., 3.);
+        let quads = [
+            [(-l, -w, 0.), (l, -w, 0.), (l, -w, h), (-l, -w, h)],
+            [(-l, w, 0.), (l, w, 0.), (l, w, h), (-l, w, h)],
+            [(-l, -w, 0.), (l, -w, 0.), (l, w, 0.), (-l, w, 0.)],
+            [(-l, -w, h), (-l, w, h), (-l, w, 0.), (-l, -w, 0.)],
+            [(l, -w, h), (l, w, h), (l, w, 0.), (l, -w, 0.)],
+        ];
+        for (i, quad) in quads.iter().enumerate() {
+            let vertices = quad.map(|(x, y, z)| Point3D::new(x, y, z));
+            builder
+                .add_polygon("mirror", &format!("wall_{}", i), &vertices)
+                .unwrap();
+        }
+        let (mut scene, _) = builder.build().unwrap();
+        scene.build_accelerator();
+        let rays: Vec<Ray3D> = (0..4)
+            .map(|i| Ray3D {
+                origin: Point3D::new(i as Float - 2., 0., 0.8),
+                direction: Vector3D::new(0., 0., 1.),
+            })
+            .collect();
+        let options = DCOptions {
+            max_depth: 6,
+            n_ambient_samples: 1000,
+            ..DCOptions::default()
+        };
+        let run = |max_branching: Option<usize>| {
+            let session = DCSession::new(
+                1,
+                DCOptions {
+                    max_branching,
+                    ..options
+                },
+            );
+            let start = Instant::now();
+            let dc = session.calc_dc(&rays, &scene).unwrap();
+            let total: Float = (0..dc.size().1).map(|c| dc.get(0, c).unwrap()).sum();
+            (start.elapsed(), total)
+        };
+        let (unbounded_time, unbounded_total) = run(None);
+        for max_branching in [8, 16] {
+            let (time, total) = run(Some(max_branching));
+            // Bounding the branching is faster, but it does not change the
+            // expected results
+            assert!(
+                time < unbounded_time,
+                "A max branching of {} took {:?}, and no limit took {:?}",
+                max_branching,
+                time,
+                unbounded_time
+            );
+            assert!(
+                (total - unbounded_total).abs() < 0.1 * unbounded_total,
+                "A max branching of {} gave {}, and no limit gave {}",
+                max_branching,
+                total,
+                unbounded_total
+            );
+        }
+    }
+}
@@ -0,0 +1,1304 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::{Float, PI};
+use calendar::Date;
+use geometry3d::Vector3D;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use solar::{PerezSky, ReinhartSky, SkyUnits, Solar, Time};
+use std::io::Write;
+use std::path::Path;
+use weather::CurrentWeather;
+
+/// The number of patches in each row of a Tregenza sky (i.e., MF = 1),
+/// from the horizon up. The zenith cap is not included.
+pub(crate) const TREGENZA_ROW_PATCHES: [usize; 7] = [30, 30, 24, 24, 18, 12, 6];
+
+/// The number of patches that gendaymtx spreads the sun into.
+pub const GENDAYMTX_SUN_PATCHES: usize = 4;
+
+/// The number of segments into which [`SkyBasis::to_obj`] splits each
+/// edge of a patch, so that they follow the dome
+pub const OBJ_EDGE_SEGMENTS: usize = 4;
+
+/// A patch of the sky of a [`SkyBasis`] (see [`SkyBasis::patches`]).
+/// Angles are in radians, and azimuths go from North (i.e., `+Y`) towards
+/// East (i.e., `+X`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyPatch {
+    /// The bin of the patch
+    pub bin: usize,
+
+    /// The row of the patch, counting from the horizon (i.e., `0`) up
+    pub row: usize,
+
+    /// The altitudes at the bottom and at the top of the patch
+    pub altitude: (Float, Float),
+
+    /// The azimuths at the two sides of the patch. Patches are centred on
+    /// their azimuth, so the first one of each row starts at a negative
+    /// azimuth. The zenith cap covers all azimuths.
+    pub azimuth: (Float, Float),
+
+    /// The solid angle of the patch
+    pub solid_angle: Float,
+
+    /// The direction of the centre of the patch
+    pub centroid: Vector3D,
+}
+
+/// The direction of a point of the sky
+pub(crate) fn sky_direction(altitude: Float, azimuth: Float) -> Vector3D {
+    Vector3D::new(
+        azimuth.sin() * altitude.cos(),
+        azimuth.cos() * altitude.cos(),
+        altitude.sin(),
+    )
+}
+
+/// Describes how the sun is assigned to the patches of the sky
+/// when building sky vectors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SunMapping {
+    /// All the energy of the sun goes into the patch that contains it. This is
+    /// the convention used by `PerezSky`, and the reason why the direct
+    /// component jumps when the sun crosses the boundary between two patches.
+    #[default]
+    Nearest,
+
+    /// The energy of the sun is shared between the `n` patches whose centres
+    /// are closest to it, weighted by the inverse of their angular distance. This
+    /// is what `gendaymtx` does (with `n = 4`, see [`GENDAYMTX_SUN_PATCHES`]).
+    Shared(usize),
+}
+
+/// Where the ground bin goes in the matrices exchanged with other tools. Some
+/// (e.g., `gendaymtx`) put it first, others last, and others leave it out.
+///
+/// Matrices in memory always have it first (see [`SkyBasis::GROUND_BIN`]); the
+/// policy of a [`SkyBasis`] says how they are written and read (see
+/// [`SkyBasis::export_dc`] and [`write_dc_mtx`](crate::matrix_io::write_dc_mtx)).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GroundBinPolicy {
+    /// The ground is the first bin, followed by the sky patches
+    #[default]
+    First,
+    /// The sky patches come first, and the ground is the last bin
+    Last,
+    /// There is no ground bin, only the sky patches
+    None,
+}
+
+impl GroundBinPolicy {
+    /// The name of the policy in the header of text matrices
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::First => "first",
+            Self::Last => "last",
+            Self::None => "none",
+        }
+    }
+
+    /// The policy with a certain [`GroundBinPolicy::name`]
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.trim() {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "The ground bin can be 'first', 'last' or 'none', but found '{}'",
+                name.trim()
+            )),
+        }
+    }
+}
+
+/// Returns the rows of a Reinhart sky with subdivision `mf`, from the
+/// horizon up, as `(min_altitude, max_altitude, n_patches)`. Altitudes are
+/// in radians, and the last row is the zenith cap.
+///
+/// This follows Radiance's `reinhart.cal`, which is also what `solar::ReinhartSky` does.
+pub(crate) fn reinhart_rows(mf: usize) -> Vec<(Float, Float, usize)> {
+    let row_height = (0.5 * PI) / (7. * mf as Float + 0.5);
+    let mut ret = Vec::with_capacity(7 * mf + 1);
+    for row in 0..7 * mf {
+        let n = mf * TREGENZA_ROW_PATCHES[row / mf];
+        ret.push((
+            row as Float * row_height,
+            (row + 1) as Float * row_height,
+            n,
+        ));
+    }
+    ret.push((7. * mf as Float * row_height, 0.5 * PI, 1));
+    ret
+}
+
+/// The discretisation of the sky shared by Daylight Coefficient matrices and
+/// sky vectors: a Reinhart sky with subdivision `mf`.
+///
+/// Bins are ordered as in `gendaymtx` and `solar::ReinhartSky`: the ground
+/// comes first (see [`SkyBasis::GROUND_BIN`]), followed by the sky patches row
+/// by row from the horizon up—each row starting at North and going towards
+/// East—and the zenith cap last. Daylight Coefficient matrices have one column
+/// per bin, and sky matrices one row per bin.
+///
+/// Outside of this crate, the ground bin goes wherever its
+/// [`GroundBinPolicy`] says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SkyBasis {
+    mf: usize,
+    #[serde(default)]
+    ground_policy: GroundBinPolicy,
+}
+
+impl SkyBasis {
+    /// The index of the bin that represents the ground
+    pub const GROUND_BIN: usize = 0;
+
+    /// The Reinhart sky with subdivision `mf`
+    pub fn new(mf: usize) -> Result<Self, String> {
+        if mf == 0 {
+            return Err("The subdivision of a Reinhart sky must be at least 1".to_string());
+        }
+        Ok(Self {
+            mf,
+            ground_policy: GroundBinPolicy::First,
+        })
+    }
+
+    /// Like [`SkyBasis::new`], for subdivisions that have already been checked
+    pub(crate) fn unchecked(mf: usize) -> Self {
+        Self {
+            mf,
+            ground_policy: GroundBinPolicy::First,
+        }
+    }
+
+    /// The subdivision of the Reinhart sky
+    pub fn mf(&self) -> usize {
+        self.mf
+    }
+
+    /// The number of bins, including the ground
+    pub fn n_bins(&self) -> usize {
+        ReinhartSky::n_bins(self.mf)
+    }
+
+    /// The `ReinhartSky` with the same discretisation
+    pub fn reinhart(&self) -> ReinhartSky {
+        ReinhartSky::new(self.mf)
+    }
+
+    /// Sets where the ground bin goes in the matrices exchanged with other tools
+    pub fn with_ground_policy(mut self, policy: GroundBinPolicy) -> Self {
+        self.ground_policy = policy;
+        self
+    }
+
+    /// Where the ground bin goes in the matrices exchanged with other tools
+    pub fn ground_policy(&self) -> GroundBinPolicy {
+        self.ground_policy
+    }
+
+    /// The bin that a `direction` falls into: the ground (i.e.,
+    /// [`SkyBasis::GROUND_BIN`]) for those that point down, whatever
+    /// `solar::ReinhartSky` does with them
+    pub fn bin(&self, direction: Vector3D) -> usize {
+        if direction.z < 0.0 {
+            Self::GROUND_BIN
+        } else {
+            self.reinhart().dir_to_bin(direction)
+        }
+    }
+
+    /// The number of bins of the matrices exchanged with other tools, which
+    /// have no ground bin with [`GroundBinPolicy::None`]
+    pub fn n_external_bins(&self) -> usize {
+        match self.ground_policy {
+            GroundBinPolicy::None => self.n_bins() - 1,
+            _ => self.n_bins(),
+        }
+    }
+
+    /// Where a `bin` goes in the matrices exchanged with other tools (see
+    /// [`GroundBinPolicy`]), if anywhere
+    pub fn external_index(&self, bin: usize) -> Option<usize> {
+        match (self.ground_policy, bin) {
+            (GroundBinPolicy::First, _) => Some(bin),
+            (GroundBinPolicy::Last, Self::GROUND_BIN) => Some(self.n_bins() - 1),
+            (GroundBinPolicy::None, Self::GROUND_BIN) => None,
+            (_, bin) => Some(bin - 1),
+        }
+    }
+
+    /// Moves the bins of a matrix—which are its columns, or its rows if
+    /// `bins_in_rows`—from the layout of this crate to the one of the
+    /// [`GroundBinPolicy`] (or back, if `import`). The ground of imported
+    /// matrices without one is left dark.
+    fn relayout(&self, m: &Matrix, bins_in_rows: bool, import: bool) -> Result<Matrix, String> {
+        let (nrows, ncols) = m.size();
+        let (from, to) = if import {
+            (self.n_external_bins(), self.n_bins())
+        } else {
+            (self.n_bins(), self.n_external_bins())
+        };
+        let (n_bins, n_other) = if bins_in_rows {
+            (nrows, ncols)
+        } else {
+            (ncols, nrows)
+        };
+        if n_bins != from {
+            return Err(format!(
+                "The matrix has {} bins, but a sky with MF {} has {} with the ground bin {}",
+                n_bins,
+                self.mf,
+                from,
+                if import {
+                    self.ground_policy.name()
+                } else {
+                    GroundBinPolicy::First.name()
+                }
+            ));
+        }
+        let mut ret = if bins_in_rows {
+            Matrix::new(0.0, to, n_other)
+        } else {
+            Matrix::new(0.0, n_other, to)
+        };
+        for bin in 0..self.n_bins() {
+            let external = match self.external_index(bin) {
+                Some(i) => i,
+                None => continue,
+            };
+            let (src, dst) = if import {
+                (external, bin)
+            } else {
+                (bin, external)
+            };
+            for i in 0..n_other {
+                if bins_in_rows {
+                    ret.set(dst, i, m.get(src, i)?)?;
+                } else {
+                    ret.set(i, dst, m.get(i, src)?)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// A Daylight Coefficient matrix (one column per bin) laid out as its
+    /// [`GroundBinPolicy`] says
+    pub fn export_dc(&self, dc: &Matrix) -> Result<Matrix, String> {
+        self.relayout(dc, false, false)
+    }
+
+    /// A Daylight Coefficient matrix laid out as the [`GroundBinPolicy`] says,
+    /// with the ground first (as in the rest of this crate)
+    pub fn import_dc(&self, dc: &Matrix) -> Result<Matrix, String> {
+        self.relayout(dc, false, true)
+    }
+
+    /// A sky vector or matrix (one row per bin) laid out as its
+    /// [`GroundBinPolicy`] says
+    pub fn export_sky(&self, sky: &Matrix) -> Result<Matrix, String> {
+        self.relayout(sky, true, false)
+    }
+
+    /// A sky vector or matrix laid out as the [`GroundBinPolicy`] says, with
+    /// the ground first (as in the rest of this crate)
+    pub fn import_sky(&self, sky: &Matrix) -> Result<Matrix, String> {
+        self.relayout(sky, true, true)
+    }
+
+    /// The direction of the centre of each bin. The ground points down.
+    pub fn centroids(&self) -> Vec<Vector3D> {
+        let sky = self.reinhart();
+        let mut ret = Vec::with_capacity(self.n_bins());
+        ret.push(Vector3D::new(0., 0., -1.));
+        ret.extend((1..self.n_bins()).map(|bin| sky.bin_dir(bin)));
+        ret
+    }
+
+    /// The solid angle of each bin. The ground is a full hemisphere.
+    pub fn solid_angles(&self) -> Vec<Float> {
+        let mut ret = Vec::with_capacity(self.n_bins());
+        ret.push(2. * PI);
+        for (min_alt, max_alt, n) in reinhart_rows(self.mf) {
+            let omega = 2. * PI * (max_alt.sin() - min_alt.sin()) / n as Float;
+            for _ in 0..n {
+                ret.push(omega);
+            }
+        }
+        ret
+    }
+
+    /// The patches of the sky, in the order of their bins (i.e., without
+    /// the ground, so the first one is bin `1`)
+    pub fn patches(&self) -> Vec<SkyPatch> {
+        let omegas = self.solid_angles();
+        let centroids = self.centroids();
+        let mut ret = Vec::with_capacity(self.n_bins() - 1);
+        for (row, (min_alt, max_alt, n)) in reinhart_rows(self.mf).into_iter().enumerate() {
+            let width = 2. * PI / n as Float;
+            for j in 0..n {
+                let bin = ret.len() + 1;
+                let centre = j as Float * width;
+                ret.push(SkyPatch {
+                    bin,
+                    row,
+                    altitude: (min_alt, max_alt),
+                    azimuth: (centre - 0.5 * width, centre + 0.5 * width),
+                    solid_angle: omegas[bin],
+                    centroid: centroids[bin],
+                });
+            }
+        }
+        ret
+    }
+
+    /// A table with every bin—its row, the ranges of altitude and azimuth it
+    /// covers (in degrees), its solid angle and the direction of its centre—for
+    /// checking the order of the bins by eye
+    pub fn describe(&self) -> String {
+        let rows = reinhart_rows(self.mf);
+        let mut ret = format!(
+            "Reinhart sky with MF {}: {} bins (the ground and {} rows of patches)\n",
+            self.mf,
+            self.n_bins(),
+            rows.len()
+        );
+        ret += &format!(
+            "{:>6} {:>4} {:>17} {:>17} {:>12}  centroid\n",
+            "bin", "row", "altitude", "azimuth", "solid angle"
+        );
+        let range =
+            |(a, b): (Float, Float)| format!("{:7.2} - {:7.2}", a.to_degrees(), b.to_degrees());
+        let vector = |v: Vector3D| format!("({:.4}, {:.4}, {:.4})", v.x, v.y, v.z);
+        ret += &format!(
+            "{:>6} {:>4} {:>17} {:>17} {:>12.6}  {}\n",
+            Self::GROUND_BIN,
+            "-",
+            "below horizon",
+            "all",
+            2. * PI,
+            vector(Vector3D::new(0., 0., -1.))
+        );
+        for p in self.patches() {
+            ret += &format!(
+                "{:>6} {:>4} {:>17} {:>17} {:>12.6}  {}\n",
+                p.bin,
+                p.row,
+                range(p.altitude),
+                range(p.azimuth),
+                p.solid_angle,
+                vector(p.centroid)
+            );
+        }
+        ret
+    }
+
+    /// Writes the outline of every patch on a dome of unit radius as a
+    /// wireframe in the Wavefront OBJ format, with `+Y` pointing North and `+Z`
+    /// up, for overlaying it on a scene. Each patch is a closed line (named after
+    /// its bin) of `4 * OBJ_EDGE_SEGMENTS` vertices, so edges shared by two
+    /// patches are written twice.
+    pub fn write_obj<W: Write>(&self, w: &mut W) -> Result<(), String> {
+        let io_err = |e: std::io::Error| format!("Error while writing the sky as OBJ: {}", e);
+        writeln!(w, "# Reinhart sky with MF {}", self.mf).map_err(io_err)?;
+        let n = OBJ_EDGE_SEGMENTS;
+        let mut n_vertices = 0;
+        for p in self.patches() {
+            let (alt0, alt1) = p.altitude;
+            let (az0, az1) = p.azimuth;
+            let lerp = |a: Float, b: Float, k: usize| a + (b - a) * k as Float / n as Float;
+            let outline: Vec<Vector3D> = if alt1 >= 0.5 * PI {
+                // The zenith cap is a circle
+                (0..4 * n)
+                    .map(|k| sky_direction(alt0, 2. * PI * k as Float / (4 * n) as Float))
+                    .collect()
+            } else {
+                // Bottom, right side, top and left side, anticlockwise seen from below
+                (0..n)
+                    .map(|k| sky_direction(alt0, lerp(az0, az1, k)))
+                    .chain((0..n).map(|k| sky_direction(lerp(alt0, alt1, k), az1)))
+                    .chain((0..n).map(|k| sky_direction(alt1, lerp(az1, az0, k))))
+                    .chain((0..n).map(|k| sky_direction(lerp(alt1, alt0, k), az0)))
+                    .collect()
+            };
+            writeln!(w, "o bin_{}", p.bin).map_err(io_err)?;
+            for v in outline.iter() {
+                writeln!(w, "v {:.6} {:.6} {:.6}", v.x, v.y, v.z).map_err(io_err)?;
+            }
+            let indices: Vec<String> = (1..=outline.len())
+                .chain(std::iter::once(1))
+                .map(|i| format!("{}", n_vertices + i))
+                .collect();
+            writeln!(w, "l {}", indices.join(" ")).map_err(io_err)?;
+            n_vertices += outline.len();
+        }
+        Ok(())
+    }
+
+    /// Saves the wireframe of [`SkyBasis::write_obj`] into a file
+    pub fn to_obj<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Could not create '{}': {}", path.display(), e))?;
+        let mut file = std::io::BufWriter::new(file);
+        self.write_obj(&mut file)?;
+        file.flush()
+            .map_err(|e| format!("Could not write '{}': {}", path.display(), e))
+    }
+
+    /// The number of ground bins of two-sided matrices (see
+    /// [`TwoSidedDC`](crate::TwoSidedDC)), which mirror the sky patches below the
+    /// horizon. There is one per sky patch, so one fewer than [`SkyBasis::n_bins`].
+    pub fn n_ground_bins(&self) -> usize {
+        self.n_bins() - 1
+    }
+
+    /// The ground bin that a `direction` pointing down falls into, which
+    /// is the sky patch of its mirror image above the horizon, counted from `0`.
+    pub fn ground_bin(&self, direction: Vector3D) -> usize {
+        mirrored_ground_bin(&self.reinhart(), direction)
+    }
+
+    /// The direction of the centre of each ground bin, pointing down
+    pub fn ground_centroids(&self) -> Vec<Vector3D> {
+        self.centroids()
+            .iter()
+            .skip(1)
+            .map(|d| Vector3D::new(d.x, d.y, -d.z))
+            .collect()
+    }
+
+    /// The solid angle of each ground bin
+    pub fn ground_solid_angles(&self) -> Vec<Float> {
+        self.solid_angles().split_off(1)
+    }
+
+    /// Checks that a Daylight Coefficient matrix has one column per bin
+    pub fn check_dc(&self, dc: &Matrix) -> Result<(), String> {
+        let (_, ncols) = dc.size();
+        if ncols != self.n_bins() {
+            return Err(format!(
+                "Daylight Coefficient matrix has {} bins, but a sky with MF {} has {}",
+                ncols,
+                self.mf,
+                self.n_bins()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that a sky vector or matrix has one row per bin
+    pub fn check_sky(&self, sky: &Matrix) -> Result<(), String> {
+        let (nrows, _) = sky.size();
+        if nrows != self.n_bins() {
+            return Err(format!(
+                "Sky vector has {} elements, but a sky with MF {} has {} bins",
+                nrows,
+                self.mf,
+                self.n_bins()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Calculates, for each bin, the fraction of its solid angle that falls
+    /// within each bin of `to`. The result contains `(from_bin, to_bin, fraction)`
+    /// for every pair that overlaps, and the fractions of each `from_bin` add up to one.
+    ///
+    /// Reinhart skies with different subdivisions are not nested (e.g., the
+    /// rows of MF 2 do not end where the rows of MF 1 do, and with even subdivisions
+    /// some patches straddle two coarse patches), so fine patches are often shared.
+    pub fn overlaps(&self, to: &SkyBasis) -> Vec<(usize, usize, Float)> {
+        patch_overlaps_between(&self.patches(), &to.patches())
+    }
+
+    /// Converts a Daylight Coefficient matrix calculated with this sky into one
+    /// for a coarser sky `to`, so that it can be multiplied by coarser sky vectors.
+    ///
+    /// Each fine coefficient is shared between the coarse patches it overlaps,
+    /// in proportion to the solid angle of the overlap (see [`SkyBasis::overlaps`]). As a
+    /// consequence, the sum of the coefficients of each row is conserved, and
+    /// multiplying the result by a coarse sky gives the same result as multiplying the
+    /// original matrix by the same sky (i.e., constant within each coarse patch)
+    /// expressed in fine patches.
+    pub fn downsample(&self, matrix: &Matrix, to: &SkyBasis) -> Result<Matrix, String> {
+        if to.mf > self.mf {
+            return Err(format!(
+                "Cannot upsample a Daylight Coefficient matrix from MF {} to MF {}",
+                self.mf, to.mf
+            ));
+        }
+        self.check_dc(matrix)?;
+        if to == self {
+            return Ok(matrix.clone());
+        }
+        let (nrows, _) = matrix.size();
+        let mut ret = Matrix::new(0.0, nrows, to.n_bins());
+        let overlaps = self.overlaps(to);
+        for r in 0..nrows {
+            for (from, to, fraction) in overlaps.iter() {
+                let v = matrix.get(r, *from)?;
+                if v != 0.0 {
+                    ret.set(r, *to, ret.get(r, *to)? + v * fraction)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Integrates a sky vector over the sky hemisphere, i.e., adds up the radiance
+    /// of each sky patch multiplied by its solid angle. The ground bin is
+    /// not included.
+    pub fn integrate_sky_vec(&self, vec: &Matrix) -> Result<Float, String> {
+        self.check_sky(vec)?;
+        let mut ret = 0.0;
+        for (i, omega) in self.solid_angles().iter().enumerate().skip(1) {
+            ret += vec.get(i, 0)? * omega;
+        }
+        Ok(ret)
+    }
+
+    /// Receives a sky vector containing only the sun (assigned to its nearest patch, as
+    /// `PerezSky` does) and redistributes its energy according to a [`SunMapping`].
+    ///
+    /// The energy of the vector (see [`SkyBasis::integrate_sky_vec`]) is conserved.
+    pub fn map_sun(
+        &self,
+        sun_vec: &Matrix,
+        sun_direction: Vector3D,
+        mapping: SunMapping,
+    ) -> Result<Matrix, String> {
+        let n_patches = match mapping {
+            SunMapping::Nearest => return Ok(sun_vec.clone()),
+            SunMapping::Shared(n) => n,
+        };
+        if n_patches == 0 {
+            return Err("Cannot share the sun between zero patches".to_string());
+        }
+
+        let energy = self.integrate_sky_vec(sun_vec)?;
+        let omegas = self.solid_angles();
+        let mut ret = Matrix::new(0.0, omegas.len(), 1);
+        if energy.abs() < 1e-12 {
+            return Ok(ret);
+        }
+
+        // Find the patches closest to the sun
+        let mut cosines: Vec<(usize, Float)> = self
+            .centroids()
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(bin, dir)| (bin, *dir * sun_direction))
+            .collect();
+        cosines.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        cosines.truncate(n_patches);
+
+        // Same weighting as gendaymtx
+        let weights: Vec<Float> = cosines.iter().map(|(_, c)| 1. / (1.002 - c)).collect();
+        let total_weight: Float = weights.iter().sum();
+        for ((bin, _), w) in cosines.iter().zip(weights.iter()) {
+            ret.set(*bin, 0, energy * w / total_weight / omegas[*bin])?;
+        }
+        Ok(ret)
+    }
+
+    /// Builds the sky vector of a CIE standard overcast sky, whose radiance at an
+    /// altitude `α` is `Lz (1 + 2 sin α) / 3`, producing a `horizontal_illuminance`
+    /// on an unobstructed horizontal plane. The ground bin is a uniform ground of
+    /// a certain `albedo`, lit by that same illuminance.
+    ///
+    /// Each patch takes the radiance of its centre, and the zenith radiance `Lz`
+    /// is set so that the discretised sky produces exactly `horizontal_illuminance`
+    /// (i.e., `7π/9` times `Lz`, in the limit of small patches).
+    pub fn cie_overcast(
+        &self,
+        horizontal_illuminance: Float,
+        albedo: Float,
+    ) -> Result<Matrix, String> {
+        if !horizontal_illuminance.is_finite() || horizontal_illuminance < 0.0 {
+            return Err(format!(
+                "The horizontal illuminance of a sky must be a non-negative number, but found {}",
+                horizontal_illuminance
+            ));
+        }
+        let relative: Vec<Float> = self
+            .centroids()
+            .iter()
+            .map(|d| (1. + 2. * d.z) / 3.)
+            .collect();
+        self.scaled_sky_vec(&relative, horizontal_illuminance, albedo)
+    }
+
+    /// Builds a sky vector whose patches have radiances proportional to `relative`
+    /// (one element per bin, ignoring the ground), scaled so that the discretised
+    /// sky produces `horizontal_illuminance` on an unobstructed horizontal plane.
+    /// The ground bin is a uniform ground of a certain `albedo`, lit by that same
+    /// illuminance.
+    pub(crate) fn scaled_sky_vec(
+        &self,
+        relative: &[Float],
+        horizontal_illuminance: Float,
+        albedo: Float,
+    ) -> Result<Matrix, String> {
+        if !(0.0..=1.0).contains(&albedo) {
+            return Err(format!(
+                "The albedo must be between 0 and 1, but found {}",
+                albedo
+            ));
+        }
+        let horizontal: Float = relative
+            .iter()
+            .zip(self.solid_angles().iter())
+            .zip(self.centroids().iter())
+            .skip(1)
+            .map(|((l, omega), d)| l * omega * d.z)
+            .sum();
+        if horizontal <= 0.0 {
+            return Err("The sky does not light an unobstructed horizontal plane".to_string());
+        }
+        let scale = horizontal_illuminance / horizontal;
+        let mut ret = Matrix::new(0.0, self.n_bins(), 1);
+        ret.set(Self::GROUND_BIN, 0, albedo * horizontal_illuminance / PI)?;
+        for (bin, l) in relative.iter().enumerate().skip(1) {
+            ret.set(bin, 0, scale * l)?;
+        }
+        Ok(ret)
+    }
+
+    /// Builds a Perez sky vector, including both sky and sun, with the sun
+    /// assigned to the sky patches according to a [`SunMapping`]. Every mapping
+    /// keeps the energy of the sun (see [`SkyBasis::integrate_sky_vec`]) and
+    /// what it adds to the ground bin.
+    pub fn gen_sky_vec(
+        &self,
+        solar: &Solar,
+        date: Date,
+        weather_data: CurrentWeather,
+        units: SkyUnits,
+        albedo: Float,
+        mapping: SunMapping,
+    ) -> Result<Matrix, String> {
+        let mf = self.mf;
+        if let SunMapping::Nearest = mapping {
+            return PerezSky::gen_sky_vec(mf, solar, date, weather_data, units, albedo, true, true);
+        }
+
+        let sky_vec =
+            PerezSky::gen_sky_vec(mf, solar, date, weather_data, units, albedo, true, false)?;
+        let sun_direction = match solar.sun_position(Time::Standard(date.day_of_year())) {
+            Some(v) => v,
+            None => return Ok(sky_vec), // sun is below the horizon
+        };
+        let sun_vec =
+            PerezSky::gen_sky_vec(mf, solar, date, weather_data, units, albedo, false, true)?;
+        let mut mapped = self.map_sun(&sun_vec, sun_direction, mapping)?;
+        // Whatever the sun adds to the ground stays there
+        let ground = Self::GROUND_BIN;
+        mapped.set(ground, 0, sun_vec.get(ground, 0)?)?;
+        Ok(&sky_vec + &mapped)
+    }
+}
+
+/// Calculates the solid angle of each bin of a Reinhart sky
+/// with subdivision `mf`. The first element corresponds to the
+/// ground, which is a full hemisphere. See [`SkyBasis::solid_angles`].
+pub fn patch_solid_angles(mf: usize) -> Vec<Float> {
+    SkyBasis::unchecked(mf).solid_angles()
+}
+
+/// The bins of a Reinhart sky with subdivision `mf`—excluding the ground—as
+/// `(min_altitude, max_altitude, min_azimuth, max_azimuth)`, in radians. Patches
+/// are centred on their azimuth, so the first one of each row starts at a negative
+/// azimuth. The zenith cap covers all azimuths.
+#[cfg(test)]
+pub(crate) fn patch_bounds(mf: usize) -> Vec<(Float, Float, Float, Float)> {
+    SkyBasis::unchecked(mf)
+        .patches()
+        .iter()
+        .map(|p| (p.altitude.0, p.altitude.1, p.azimuth.0, p.azimuth.1))
+        .collect()
+}
+
+/// Like [`SkyBasis::ground_bin`], with a `ReinhartSky` that has already been built
+pub(crate) fn mirrored_ground_bin(sky: &ReinhartSky, direction: Vector3D) -> usize {
+    let mirror = Vector3D::new(direction.x, direction.y, direction.z.abs());
+    sky.dir_to_bin(mirror) - 1
+}
+
+/// Calculates, for each patch of `from`, the fraction of its solid angle that
+/// falls within each patch of `to`, as `(from_bin, to_bin, fraction)` for every
+/// pair that overlaps (see [`SkyBasis::overlaps`]). The ground bins are matched.
+pub(crate) fn patch_overlaps_between(
+    from: &[SkyPatch],
+    to: &[SkyPatch],
+) -> Vec<(usize, usize, Float)> {
+    let mut ret = vec![(SkyBasis::GROUND_BIN, SkyBasis::GROUND_BIN, 1.)];
+    for f in from {
+        let f_height = f.altitude.1.sin() - f.altitude.0.sin();
+        let f_width = f.azimuth.1 - f.azimuth.0;
+        for c in to {
+            let height =
+                c.altitude.1.min(f.altitude.1).sin() - c.altitude.0.max(f.altitude.0).sin();
+            if height <= 0.0 {
+                continue;
+            }
+            let width = azimuth_overlap(f.azimuth, c.azimuth).min(f_width);
+            let fraction = (height / f_height) * (width / f_width);
+            if fraction > 1e-9 {
+                ret.push((f.bin, c.bin, fraction));
+            }
+        }
+    }
+    ret
+}
+
+/// The length of the overlap between two azimuth intervals, on the circle
+fn azimuth_overlap(a: (Float, Float), b: (Float, Float)) -> Float {
+    [-2. * PI, 0., 2. * PI]
+        .iter()
+        .map(|shift| (a.1.min(b.1 + shift) - a.0.max(b.0 + shift)).max(0.0))
+        .sum()
+}
+
+/// The bin that each bin of a `basis` becomes when mirrored on a vertical plane
+/// at an `axis_azimuth` (in radians, from North towards East), which takes the
+/// azimuth `φ` to `2 * axis_azimuth - φ`. A North-South axis is `0`.
+///
+/// Mirroring is only exact if every patch becomes another one, i.e., if the
+/// plane splits the patches of every row in halves or runs along their sides.
+/// The ground and the zenith cap are their own mirror images.
+pub fn mirror_bins(basis: &SkyBasis, axis_azimuth: Float) -> Result<Vec<usize>, String> {
+    if !axis_azimuth.is_finite() {
+        return Err(format!(
+            "The azimuth of a mirror must be finite, but found {}",
+            axis_azimuth
+        ));
+    }
+    let mut ret = Vec::with_capacity(basis.n_bins());
+    ret.push(SkyBasis::GROUND_BIN);
+    for (row, (.., n)) in reinhart_rows(basis.mf()).into_iter().enumerate() {
+        let first = ret.len();
+        if n == 1 {
+            ret.push(first);
+            continue;
+        }
+        // The centre of patch `j` goes to `shift - j` patches
+        let shift = axis_azimuth * n as Float / PI;
+        let rounded = shift.round();
+        if (shift - rounded).abs() > 1e-3 {
+            return Err(format!(
+                "A mirror at an azimuth of {} degrees does not follow the patches of row {} of a sky with MF {}, which are {} degrees wide",
+                axis_azimuth.to_degrees(),
+                row,
+                basis.mf(),
+                360. / n as Float
+            ));
+        }
+        let shift = (rounded as i64).rem_euclid(n as i64) as usize;
+        ret.extend((0..n).map(|j| first + (shift + n - j) % n));
+    }
+    Ok(ret)
+}
+
+/// Like [`SkyBasis::overlaps`], for Reinhart skies with subdivisions `from_mf` and `to_mf`
+pub fn patch_overlaps(from_mf: usize, to_mf: usize) -> Result<Vec<(usize, usize, Float)>, String> {
+    Ok(SkyBasis::new(from_mf)?.overlaps(&SkyBasis::new(to_mf)?))
+}
+
+/// Like [`SkyBasis::downsample`], for Reinhart skies with subdivisions `from_mf` and `to_mf`
+pub fn downsample_mf(matrix: &Matrix, from_mf: usize, to_mf: usize) -> Result<Matrix, String> {
+    SkyBasis::new(from_mf)?.downsample(matrix, &SkyBasis::new(to_mf)?)
+}
+
+/// Like [`SkyBasis::integrate_sky_vec`], for a Reinhart sky with subdivision `mf`
+pub fn integrate_sky_vec(vec: &Matrix, mf: usize) -> Result<Float, String> {
+    SkyBasis::new(mf)?.integrate_sky_vec(vec)
+}
+
+/// Like [`SkyBasis::map_sun`], for a Reinhart sky with subdivision `mf`
+pub fn map_sun(
+    sun_vec: &Matrix,
+    mf: usize,
+    sun_direction: Vector3D,
+    mapping: SunMapping,
+) -> Result<Matrix, String> {
+    SkyBasis::new(mf)?.map_sun(sun_vec, sun_direction, mapping)
+}
+
+/// Like [`SkyBasis::gen_sky_vec`], for a Reinhart sky with subdivision `mf`
+pub fn gen_sky_vec(
+    mf: usize,
+    solar: &Solar,
+    date: Date,
+    weather_data: CurrentWeather,
+    units: SkyUnits,
+    albedo: Float,
+    mapping: SunMapping,
+) -> Result<Matrix, String> {
+    SkyBasis::new(mf)?.gen_sky_vec(solar, date, weather_data, units, albedo, mapping)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    fn nearest_sun_vec(mf: usize, dir: Vector3D, energy: Float) -> Matrix {
+        let sky = ReinhartSky::new(mf);
+        let omegas = patch_solid_angles(mf);
+        let bin = sky.dir_to_bin(dir);
+        let mut ret = Matrix::new(0.0, omegas.len(), 1);
+        ret.set(bin, 0, energy / omegas[bin]).unwrap();
+        ret
+    }
+
+    #[test]
+    fn test_mirror_bins() {
+        for mf in 1..=3 {
+            let basis = SkyBasis::new(mf).unwrap();
+            let centroids = basis.centroids();
+            for degrees in [0., 90., 180., -90.] {
+                let axis: Float = (degrees as Float).to_radians();
+                let mirror = mirror_bins(&basis, axis).unwrap();
+                assert_eq!(mirror.len(), basis.n_bins());
+                assert_eq!(mirror[0], 0);
+                for (bin, m) in mirror.iter().enumerate() {
+                    // Twice is the identity
+                    assert_eq!(mirror[*m], bin);
+                    // The centroids are mirror images of each other
+                    let (a, b) = (centroids[bin], centroids[*m]);
+                    let (s, c) = ((2. * axis).sin(), (2. * axis).cos());
+                    assert_close!(b.x, -c * a.x + s * a.y, 1e-4);
+                    assert_close!(b.y, s * a.x + c * a.y, 1e-4);
+                    assert_close!(b.z, a.z, 1e-5);
+                }
+            }
+        }
+        // East and West swap on a North-South axis
+        let basis = SkyBasis::new(1).unwrap();
+        let mirror = mirror_bins(&basis, 0.).unwrap();
+        let sky = basis.reinhart();
+        let east = sky.dir_to_bin(Vector3D::new(1., 0.05, 0.1));
+        assert_eq!(mirror[east], sky.dir_to_bin(Vector3D::new(-1., 0.05, 0.1)));
+
+        // Planes that cut through patches
+        let err = mirror_bins(&basis, (10. as Float).to_radians()).unwrap_err();
+        assert!(err.contains("row 0"), "{}", err);
+        assert!(mirror_bins(&basis, (45. as Float).to_radians()).is_err());
+        assert!(mirror_bins(&basis, Float::NAN).is_err());
+    }
+
+    #[test]
+    fn test_describe_patches() {
+        for mf in 1..=4 {
+            let basis = SkyBasis::new(mf).unwrap();
+            let patches = basis.patches();
+            assert_eq!(patches.len(), basis.n_bins() - 1);
+            // 7 rows per MF plus the cap, and every bin is where it says
+            let sky = basis.reinhart();
+            assert_eq!(patches.last().unwrap().row, 7 * mf);
+            for (i, p) in patches.iter().enumerate() {
+                assert_eq!(p.bin, i + 1);
+                let middle = sky_direction(
+                    0.5 * (p.altitude.0 + p.altitude.1),
+                    0.5 * (p.azimuth.0 + p.azimuth.1),
+                );
+                if p.row < 7 * mf {
+                    assert_eq!(sky.dir_to_bin(middle), p.bin);
+                }
+                assert_eq!(sky.dir_to_bin(p.centroid), p.bin);
+            }
+            let total: Float = patches.iter().map(|p| p.solid_angle).sum();
+            assert_close!(total, 2. * PI, 1e-4);
+
+            // One line per bin, plus the two of the header
+            let table = basis.describe();
+            assert_eq!(table.lines().count(), basis.n_bins() + 2);
+            assert!(table.contains(&format!("MF {}", mf)));
+        }
+        let table = SkyBasis::new(1).unwrap().describe();
+        let first = table.lines().nth(3).unwrap();
+        assert!(first.contains("0.00 -   12.00"), "{}", first);
+        assert!(first.contains("-6.00 -    6.00"), "{}", first);
+
+        // The outline of every patch on the dome
+        let basis = SkyBasis::new(2).unwrap();
+        let mut obj = Vec::new();
+        basis.write_obj(&mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        let vertices: Vec<&str> = obj.lines().filter(|l| l.starts_with("v ")).collect();
+        assert_eq!(vertices.len(), (basis.n_bins() - 1) * 4 * OBJ_EDGE_SEGMENTS);
+        assert_eq!(
+            obj.lines().filter(|l| l.starts_with("l ")).count(),
+            basis.n_bins() - 1
+        );
+        for v in vertices {
+            let xyz: Vec<Float> = v[2..].split(' ').map(|x| x.parse().unwrap()).collect();
+            let r = (xyz[0] * xyz[0] + xyz[1] * xyz[1] + xyz[2] * xyz[2]).sqrt();
+            assert_close!(r, 1., 1e-5);
+            assert!(xyz[2] >= 0.0);
+        }
+
+        let path = std::env::temp_dir().join(format!("light_sky_{}.obj", std::process::id()));
+        basis.to_obj(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), obj);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sky_basis() {
+        assert!(SkyBasis::new(0).is_err());
+        let basis = SkyBasis::new(2).unwrap();
+        assert_eq!(basis.mf(), 2);
+        assert_eq!(basis.n_bins(), ReinhartSky::n_bins(2));
+        let centroids = basis.centroids();
+        assert_eq!(centroids.len(), basis.n_bins());
+        assert_eq!(centroids[SkyBasis::GROUND_BIN], Vector3D::new(0., 0., -1.));
+        let sky = basis.reinhart();
+        for (bin, dir) in centroids.iter().enumerate().skip(1) {
+            assert_eq!(sky.dir_to_bin(*dir), bin);
+        }
+        assert_eq!(basis.solid_angles(), patch_solid_angles(2));
+
+        // Shapes are checked
+        assert!(basis.check_dc(&Matrix::new(0.0, 3, basis.n_bins())).is_ok());
+        assert!(basis
+            .check_dc(&Matrix::new(0.0, 3, ReinhartSky::n_bins(1)))
+            .is_err());
+        assert!(basis
+            .check_sky(&Matrix::new(0.0, basis.n_bins(), 8))
+            .is_ok());
+        assert!(basis
+            .check_sky(&Matrix::new(0.0, basis.n_bins() - 1, 8))
+            .is_err());
+        assert!(basis.integrate_sky_vec(&Matrix::new(1.0, 10, 1)).is_err());
+
+        // The numeric entry points agree
+        let coarse = SkyBasis::new(1).unwrap();
+        assert_eq!(basis.overlaps(&coarse), patch_overlaps(2, 1).unwrap());
+        assert!(basis.downsample(&Matrix::new(0.0, 1, 10), &coarse).is_err());
+        assert!(coarse
+            .downsample(&Matrix::new(0.0, 1, coarse.n_bins()), &basis)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cie_overcast() {
+        let basis = SkyBasis::new(2).unwrap();
+        let sky = basis.cie_overcast(10000., 0.2).unwrap();
+        let centroids = basis.centroids();
+        let omegas = basis.solid_angles();
+        let horizontal: Float = (1..basis.n_bins())
+            .map(|bin| sky.get(bin, 0).unwrap() * omegas[bin] * centroids[bin].z)
+            .sum();
+        assert_close!(horizontal, 10000., 0.1);
+
+        // The zenith is three times brighter than the horizon, and
+        // Lz is close to 9/7π of the horizontal illuminance
+        let zenith = sky.get(basis.n_bins() - 1, 0).unwrap();
+        assert_close!(zenith, 9. * 10000. / 7. / PI, 0.02 * zenith);
+        let horizon = sky.get(1, 0).unwrap();
+        assert!(zenith / horizon > 2.5 && zenith / horizon < 3.);
+        assert_close!(
+            sky.get(SkyBasis::GROUND_BIN, 0).unwrap(),
+            0.2 * 10000. / PI,
+            1e-3
+        );
+
+        assert!(basis.cie_overcast(-1., 0.2).is_err());
+        assert!(basis.cie_overcast(1000., 1.2).is_err());
+    }
+
+    #[test]
+    fn test_ground_bins() {
+        let basis = SkyBasis::new(2).unwrap();
+        let centroids = basis.ground_centroids();
+        assert_eq!(centroids.len(), basis.n_ground_bins());
+        assert_eq!(basis.ground_solid_angles().len(), basis.n_ground_bins());
+        let total: Float = basis.ground_solid_angles().iter().sum();
+        assert_close!(total, 2. * PI, 1e-4);
+        for (bin, dir) in centroids.iter().enumerate() {
+            assert!(dir.z < 0.);
+            assert_eq!(basis.ground_bin(*dir), bin);
+        }
+    }
+
+    #[test]
+    fn test_ground_policies() {
+        let first = SkyBasis::new(1).unwrap();
+        assert_eq!(first.ground_policy(), GroundBinPolicy::First);
+        assert_eq!(
+            first.bin(Vector3D::new(0.3, 0.1, -1.)),
+            SkyBasis::GROUND_BIN
+        );
+        assert_ne!(first.bin(Vector3D::new(0., 0., 1.)), SkyBasis::GROUND_BIN);
+
+        let n = first.n_bins();
+        let lit = 17;
+        let mut sky = Matrix::new(0.0, n, 1);
+        sky.set(SkyBasis::GROUND_BIN, 0, 3.).unwrap();
+        sky.set(lit, 0, 1.).unwrap();
+        let mut dc = Matrix::new(0.0, 2, n);
+        dc.set(1, SkyBasis::GROUND_BIN, 5.).unwrap();
+        dc.set(1, lit, 2.).unwrap();
+
+        for (policy, n_external, ground, patch) in [
+            (GroundBinPolicy::First, n, Some(0), lit),
+            (GroundBinPolicy::Last, n, Some(n - 1), lit - 1),
+            (GroundBinPolicy::None, n - 1, None, lit - 1),
+        ] {
+            let basis = first.with_ground_policy(policy);
+            assert_eq!(basis.n_external_bins(), n_external);
+            assert_eq!(basis.external_index(SkyBasis::GROUND_BIN), ground);
+            assert_eq!(basis.external_index(lit), Some(patch));
+
+            let external = basis.export_sky(&sky).unwrap();
+            assert_eq!(external.size(), (n_external, 1));
+            assert_eq!(external.get(patch, 0).unwrap(), 1.);
+            if let Some(g) = ground {
+                assert_eq!(external.get(g, 0).unwrap(), 3.);
+            }
+            let back = basis.import_sky(&external).unwrap();
+            assert_eq!(back.get(lit, 0).unwrap(), 1.);
+            let expected_ground = if ground.is_some() { 3. } else { 0. };
+            assert_eq!(back.get(SkyBasis::GROUND_BIN, 0).unwrap(), expected_ground);
+
+            let external = basis.export_dc(&dc).unwrap();
+            assert_eq!(external.size(), (2, n_external));
+            assert_eq!(external.get(1, patch).unwrap(), 2.);
+            let back = basis.import_dc(&external).unwrap();
+            assert_eq!(back.get(1, lit).unwrap(), 2.);
+            let expected_ground = if ground.is_some() { 5. } else { 0. };
+            assert_eq!(back.get(1, SkyBasis::GROUND_BIN).unwrap(), expected_ground);
+
+            assert_eq!(GroundBinPolicy::from_name(policy.name()).unwrap(), policy);
+        }
+        assert!(first
+            .with_ground_policy(GroundBinPolicy::None)
+            .import_sky(&sky)
+            .is_err());
+        assert!(GroundBinPolicy::from_name("middle").is_err());
+    }
+
+    #[test]
+    fn test_patch_solid_angles() {
+        for mf in [1, 2, 4] {
+            let omegas = patch_solid_angles(mf);
+            assert_eq!(omegas.len(), ReinhartSky::n_bins(mf));
+            let total: Float = omegas.iter().sum();
+            assert_close!(total, 4. * PI, 1e-4);
+        }
+    }
+
+    fn row_sum(m: &Matrix, r: usize) -> Float {
+        let (_, ncols) = m.size();
+        (0..ncols).map(|c| m.get(r, c).unwrap()).sum()
+    }
+
+    #[test]
+    fn test_downsample_mf_conserves_energy() {
+        for (from, to) in [(2, 1), (3, 1), (4, 1), (4, 2), (6, 2)] {
+            let fine_bins = ReinhartSky::n_bins(from);
+            let mut fine = Matrix::new(0.0, 2, fine_bins);
+            for c in 0..fine_bins {
+                fine.set(0, c, 1.).unwrap();
+                fine.set(1, c, ((c * 7919) % 13) as Float).unwrap();
+            }
+            let coarse = downsample_mf(&fine, from, to).unwrap();
+            assert_eq!(coarse.size(), (2, ReinhartSky::n_bins(to)));
+            for r in 0..2 {
+                let total = row_sum(&fine, r);
+                assert_close!(row_sum(&coarse, r), total, 1e-5 * total);
+            }
+            // Fractions of every fine bin add up to one
+            let mut totals = vec![0.0; fine_bins];
+            for (f, _, w) in patch_overlaps(from, to).unwrap() {
+                totals[f] += w;
+            }
+            for t in totals {
+                assert_close!(t, 1., 1e-4);
+            }
+        }
+
+        let m = Matrix::new(0.0, 1, ReinhartSky::n_bins(1));
+        assert!(downsample_mf(&m, 1, 2).is_err());
+        assert!(downsample_mf(&m, 2, 1).is_err()); // wrong number of bins
+        assert_eq!(downsample_mf(&m, 1, 1).unwrap().size(), m.size());
+    }
+
+    #[test]
+    fn test_downsample_mf_near_zenith() {
+        // MF 1 rows are 12 degrees high, so the zenith cap starts at 84 degrees.
+        // MF 2 rows are 90/14.5 degrees high: the last row spans the start of the
+        // MF 1 cap, and the MF 2 cap is fully inside the MF 1 cap.
+        let (from, to) = (2, 1);
+        let fine_bins = ReinhartSky::n_bins(from);
+        let coarse_bins = ReinhartSky::n_bins(to);
+        let h = 0.5 * PI / 14.5;
+        let cap_start: Float = 84. * PI / 180.;
+
+        // The cap goes entirely into the cap
+        let mut fine = Matrix::new(0.0, 1, fine_bins);
+        fine.set(0, fine_bins - 1, 1.).unwrap();
+        let coarse = downsample_mf(&fine, from, to).unwrap();
+        assert_close!(coarse.get(0, coarse_bins - 1).unwrap(), 1., 1e-9);
+
+        // The first patch of the last MF 2 row is shared between the MF 1 cap
+        // and the first patch of the last MF 1 row, according to their solid angle
+        let patch = fine_bins - 1 - 12;
+        let mut fine = Matrix::new(0.0, 1, fine_bins);
+        fine.set(0, patch, 1.).unwrap();
+        let coarse = downsample_mf(&fine, from, to).unwrap();
+        let (lo, hi) = (13. * h, 14. * h);
+        let to_cap = (hi.sin() - cap_start.sin()) / (hi.sin() - lo.sin());
+        assert_close!(coarse.get(0, coarse_bins - 1).unwrap(), to_cap, 1e-6);
+        assert_close!(
+            coarse.get(0, coarse_bins - 1 - 6).unwrap(),
+            1. - to_cap,
+            1e-6
+        );
+        assert_close!(row_sum(&coarse, 0), 1., 1e-9);
+    }
+
+    #[test]
+    fn test_downsample_mf_patchwise_constant_sky() {
+        // A coarse sky, expressed in fine patches by averaging it over each of them
+        let (from, to) = (2, 1);
+        let coarse_sky = ReinhartSky::new(to);
+        let coarse_bins = ReinhartSky::n_bins(to);
+        let radiance = |bin: usize| 1. + (bin % 7) as Float;
+        let mut fine_vec = vec![radiance(0)];
+        let n = 40;
+        for (min_alt, max_alt, min_az, max_az) in patch_bounds(from) {
+            let mut acc = 0.0;
+            for a in 0..n {
+                let sin_alt = min_alt.sin()
+                    + (max_alt.sin() - min_alt.sin()) * (a as Float + 0.5) / n as Float;
+                let cos_alt = (1. - sin_alt * sin_alt).sqrt();
+                for b in 0..n {
+                    let az = min_az + (max_az - min_az) * (b as Float + 0.5) / n as Float;
+                    let dir = Vector3D::new(az.sin() * cos_alt, az.cos() * cos_alt, sin_alt);
+                    acc += radiance(coarse_sky.dir_to_bin(dir));
+                }
+            }
+            fine_vec.push(acc / (n * n) as Float);
+        }
+
+        let fine_bins = ReinhartSky::n_bins(from);
+        let mut fine = Matrix::new(0.0, 1, fine_bins);
+        for c in 0..fine_bins {
+            fine.set(0, c, 0.01 * (1 + c % 5) as Float).unwrap();
+        }
+        let coarse = downsample_mf(&fine, from, to).unwrap();
+        let fine_result: Float = (0..fine_bins)
+            .map(|c| fine.get(0, c).unwrap() * fine_vec[c])
+            .sum();
+        let coarse_result: Float = (0..coarse_bins)
+            .map(|c| coarse.get(0, c).unwrap() * radiance(c))
+            .sum();
+        assert_close!(fine_result, coarse_result, 1e-2 * fine_result);
+    }
+
+    #[test]
+    fn test_map_sun_conserves_energy() {
+        let energy = 850.;
+        let dir = Vector3D::new(0.3, -0.5, 0.6).get_normalized();
+        for mf in [1, 2] {
+            let nearest = nearest_sun_vec(mf, dir, energy);
+            assert_close!(integrate_sky_vec(&nearest, mf).unwrap(), energy, 1e-3);
+
+            for mapping in [
+                SunMapping::Nearest,
+                SunMapping::Shared(3),
+                SunMapping::Shared(GENDAYMTX_SUN_PATCHES),
+            ] {
+                let mapped = map_sun(&nearest, mf, dir, mapping).unwrap();
+                assert_close!(integrate_sky_vec(&mapped, mf).unwrap(), energy, 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gen_sky_vec_keeps_sun_energy() {
+        // A clear summer morning, with the sun well above the horizon
+        let solar = Solar::new(51.5, 0.0, 0.0);
+        let date = Date {
+            month: 6,
+            day: 21,
+            hour: 9.5,
+        };
+        let weather_data = CurrentWeather {
+            direct_normal_radiation: Some(700.),
+            diffuse_horizontal_radiation: Some(120.),
+            ..CurrentWeather::default()
+        };
+        for mf in [1, 2] {
+            let basis = SkyBasis::new(mf).unwrap();
+            let sky_vec = |mapping: SunMapping| {
+                basis
+                    .gen_sky_vec(&solar, date, weather_data, SkyUnits::Solar, 0.2, mapping)
+                    .unwrap()
+            };
+            let nearest = sky_vec(SunMapping::Nearest);
+            let energy = basis.integrate_sky_vec(&nearest).unwrap();
+            assert!(energy > 0.0);
+            let n_lit = |vec: &Matrix| {
+                (1..basis.n_bins())
+                    .filter(|bin| vec.get(*bin, 0).unwrap() > nearest.get(*bin, 0).unwrap())
+                    .count()
+            };
+            for n in [3, GENDAYMTX_SUN_PATCHES] {
+                let shared = sky_vec(SunMapping::Shared(n));
+                assert_close!(
+                    basis.integrate_sky_vec(&shared).unwrap(),
+                    energy,
+                    1e-4 * energy
+                );
+                // The patch of the sun gets less, and some of its neighbours more
+                assert!(n_lit(&shared) >= n - 1);
+                assert_eq!(
+                    shared.get(SkyBasis::GROUND_BIN, 0),
+                    nearest.get(SkyBasis::GROUND_BIN, 0)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_sun_shared_patches() {
+        let mf = 1;
+        let dir = Vector3D::new(0.1, 0.7, 0.4).get_normalized();
+        let nearest = nearest_sun_vec(mf, dir, 100.);
+        let mapped = map_sun(&nearest, mf, dir, SunMapping::Shared(4)).unwrap();
+        let (nrows, ..) = mapped.size();
+        let n_lit = (0..nrows)
+            .filter(|i| mapped.get(*i, 0).unwrap() > 0.0)
+            .count();
+        assert_eq!(n_lit, 4);
+
+        assert!(map_sun(&nearest, mf, dir, SunMapping::Shared(0)).is_err());
+    }
+}
@@ -0,0 +1,364 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A cache of annual sky matrices on disk, so that they are generated once
+//! per weather file and location instead of once per calculation.
+//!
+//! Sky matrices depend on the weather, the location and the discretisation of
+//! the sky, but not on the scene or the sensors. Each one is stored in the
+//! binary matrix format (see [`save_dense_binary`]), in a file named after the
+//! hash of everything it depends on (a [`SkyMatrixKey`]), so that other
+//! processes sharing the same directory reuse it too.
+
+use crate::manifest::{hash_file, Fnv};
+use crate::matrix_io::{load_binary, save_dense_binary};
+use crate::sky::{SkyBasis, SunMapping};
+use crate::sky_matrix::{GroundConvention, SkyMatrix};
+use crate::time::TimeAxis;
+use crate::Float;
+use matrix::Matrix;
+use solar::SkyUnits;
+use std::path::{Path, PathBuf};
+
+/// The version of the key, to be increased whenever the way sky matrices
+/// are generated changes
+const KEY_VERSION: u8 = 1;
+
+/// Everything an annual sky matrix depends on
+#[derive(Debug, Clone)]
+pub struct SkyMatrixKey {
+    /// The hash of the weather file (see [`hash_file`])
+    pub weather: String,
+
+    /// The latitude of the site, in degrees
+    pub latitude: Float,
+
+    /// The longitude of the site, in degrees
+    pub longitude: Float,
+
+    /// The standard meridian of the site, in degrees
+    pub standard_meridian: Float,
+
+    /// The subdivision of the Reinhart sky
+    pub mf: usize,
+
+    /// How the sun is assigned to the patches
+    pub sun_mapping: SunMapping,
+
+    /// Whether the matrix holds solar or visible radiance
+    pub units: SkyUnits,
+
+    /// The number of timesteps per hour, over a whole year
+    pub timesteps_per_hour: usize,
+
+    /// The azimuth of the true North in the axes of the scene, in degrees
+    /// (see [`rotate_to_scene`](crate::rotate_to_scene))
+    pub north_offset: Float,
+
+    /// What the ground bin of the matrix holds
+    pub ground: GroundConvention,
+}
+
+impl SkyMatrixKey {
+    /// A key for the weather file at `epw`, at a site and with the default
+    /// ground, no north offset and one timestep per hour
+    pub fn from_epw<P: AsRef<Path>>(
+        epw: P,
+        latitude: Float,
+        longitude: Float,
+        standard_meridian: Float,
+        mf: usize,
+        sun_mapping: SunMapping,
+        units: SkyUnits,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            weather: hash_file(epw)?,
+            latitude,
+            longitude,
+            standard_meridian,
+            mf,
+            sun_mapping,
+            units,
+            timesteps_per_hour: 1,
+            north_offset: 0.,
+            ground: GroundConvention::default(),
+        })
+    }
+
+    /// The hash of the key, which names the file of its matrix
+    pub fn hash(&self) -> String {
+        let mut h = Fnv::new();
+        h.write(&[KEY_VERSION]);
+        h.write_str(&self.weather);
+        for v in [
+            self.latitude,
+            self.longitude,
+            self.standard_meridian,
+            self.north_offset,
+        ] {
+            h.write_float(v);
+        }
+        for n in [self.mf, self.timesteps_per_hour] {
+            h.write(&(n as u64).to_le_bytes());
+        }
+        match self.sun_mapping {
+            SunMapping::Nearest => h.write(&[0]),
+            SunMapping::Shared(n) => {
+                h.write(&[1]);
+                h.write(&(n as u64).to_le_bytes());
+            }
+        }
+        match self.units {
+            SkyUnits::Solar => h.write(&[0]),
+            SkyUnits::Visible => h.write(&[1]),
+        }
+        match self.ground {
+            GroundConvention::GroundIncluded { albedo } => {
+                h.write(&[0]);
+                h.write_float(albedo);
+            }
+            GroundConvention::GroundExcluded => h.write(&[1]),
+        }
+        h.finish()
+    }
+}
+
+/// A directory of annual sky matrices (see the module documentation)
+#[derive(Debug, Clone)]
+pub struct SkyMatrixCache {
+    dir: PathBuf,
+}
+
+impl SkyMatrixCache {
+    /// A cache in `dir`, which is created if it does not exist
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Unable to create sky matrix cache '{}': {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(Self { dir })
+    }
+
+    /// The file where the matrix of a `key` is (or would be) stored
+    pub fn path(&self, key: &SkyMatrixKey) -> PathBuf {
+        self.dir.join(format!("sky_{}.bin", key.hash()))
+    }
+
+    /// The matrix of a `key`, if it is in the cache
+    pub fn get(&self, key: &SkyMatrixKey) -> Result<Option<SkyMatrix>, String> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let matrix = load_binary(&path)?.into_dense()?;
+        Self::wrap(key, matrix)
+            .map(Some)
+            .map_err(|e| format!("Corrupt sky matrix '{}': {}", path.display(), e))
+    }
+
+    /// The matrix of a `key`, from the cache or—if it is not there—from
+    /// `generate`, in which case it is stored for next time. The matrix that
+    /// `generate` returns must have one row per bin and one column per
+    /// timestep of the year, and follow everything in the `key`.
+    pub fn get_or_generate<F>(&self, key: &SkyMatrixKey, generate: F) -> Result<SkyMatrix, String>
+    where
+        F: FnOnce() -> Result<Matrix, String>,
+    {
+        if let Some(sky) = self.get(key)? {
+            return Ok(sky);
+        }
+        let sky = Self::wrap(key, generate()?)?;
+        // Written aside first, so that other processes never read half a file
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        save_dense_binary(&tmp, &sky.values)?;
+        std::fs::rename(&tmp, &path)
+            .map_err(|e| format!("Unable to write sky matrix '{}': {}", path.display(), e))?;
+        Ok(sky)
+    }
+
+    /// Removes the matrix of a `key` from the cache, if it is there
+    pub fn invalidate(&self, key: &SkyMatrixKey) -> Result<(), String> {
+        let path = self.path(key);
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .map_err(|e| format!("Unable to remove sky matrix '{}': {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a matrix fits a `key`, and makes it a [`SkyMatrix`]
+    fn wrap(key: &SkyMatrixKey, values: Matrix) -> Result<SkyMatrix, String> {
+        TimeAxis::annual(key.timesteps_per_hour)?.check_columns(&values)?;
+        SkyMatrix::new(SkyBasis::new(key.mf)?, values, key.ground)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use std::cell::Cell;
+
+    fn key() -> SkyMatrixKey {
+        SkyMatrixKey::from_epw(
+            "./tests/wellington.epw",
+            -41.3,
+            174.78,
+            180.,
+            1,
+            SunMapping::Nearest,
+            SkyUnits::Solar,
+        )
+        .unwrap()
+    }
+
+    /// A made-up annual sky matrix
+    fn generate(key: &SkyMatrixKey, calls: &Cell<usize>) -> Result<Matrix, String> {
+        calls.set(calls.get() + 1);
+        let n_bins = SkyBasis::new(key.mf)?.n_bins();
+        let n_timesteps = TimeAxis::annual(key.timesteps_per_hour)?.n_timesteps;
+        let mut ret = Matrix::new(0.0, n_bins, n_timesteps);
+        for bin in 0..n_bins {
+            for t in (bin..n_timesteps).step_by(97) {
+                ret.set(bin, t, ((bin * 31 + t) % 17) as Float + 0.25)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    fn cache(name: &str) -> SkyMatrixCache {
+        let dir = std::env::temp_dir().join(format!("light_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        SkyMatrixCache::new(dir).unwrap()
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let cache = cache("sky_cache_hit");
+        let key = key();
+        let calls = Cell::new(0);
+        assert!(cache.get(&key).unwrap().is_none());
+        let first = cache
+            .get_or_generate(&key, || generate(&key, &calls))
+            .unwrap();
+        assert_eq!(calls.get(), 1);
+        assert!(cache.path(&key).exists());
+
+        // Hits skip the generation, in this cache or in another one on the
+        // same directory (e.g., of another process)
+        let second = cache
+            .get_or_generate(&key, || generate(&key, &calls))
+            .unwrap();
+        let other = SkyMatrixCache::new(&cache.dir).unwrap();
+        let third = other
+            .get_or_generate(&key, || generate(&key, &calls))
+            .unwrap();
+        assert_eq!(calls.get(), 1);
+        for sky in [&second, &third] {
+            assert_eq!(sky.values, first.values);
+            assert_eq!(sky.ground, key.ground);
+            assert_eq!(sky.basis, first.basis);
+        }
+
+        cache.invalidate(&key).unwrap();
+        assert!(!cache.path(&key).exists());
+        cache
+            .get_or_generate(&key, || generate(&key, &calls))
+            .unwrap();
+        assert_eq!(calls.get(), 2);
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_keys() {
+        let cache = cache("sky_cache_keys");
+        let base = key();
+        let calls = Cell::new(0);
+        cache
+            .get_or_generate(&base, || generate(&base, &calls))
+            .unwrap();
+
+        // Anything the matrix depends on makes a different entry
+        let mut keys = vec![
+            SkyMatrixKey {
+                north_offset: 15.,
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                ground: GroundConvention::GroundExcluded,
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                ground: GroundConvention::GroundIncluded { albedo: 0.3 },
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                sun_mapping: SunMapping::Shared(4),
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                units: SkyUnits::Visible,
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                latitude: -41.,
+                ..base.clone()
+            },
+            SkyMatrixKey {
+                mf: 2,
+                ..base.clone()
+            },
+        ];
+        keys.push(
+            SkyMatrixKey::from_epw(
+                "./tests/barcelona.epw",
+                base.latitude,
+                base.longitude,
+                base.standard_meridian,
+                base.mf,
+                base.sun_mapping,
+                base.units,
+            )
+            .unwrap(),
+        );
+        for (i, key) in keys.iter().enumerate() {
+            assert_ne!(key.hash(), base.hash());
+            cache
+                .get_or_generate(key, || generate(key, &calls))
+                .unwrap();
+            assert_eq!(calls.get(), i + 2);
+        }
+
+        // Matrices that do not fit their key are neither returned nor stored
+        let key = SkyMatrixKey {
+            timesteps_per_hour: 2,
+            ..base.clone()
+        };
+        assert!(cache
+            .get_or_generate(&key, || generate(&base, &calls))
+            .is_err());
+        assert!(!cache.path(&key).exists());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
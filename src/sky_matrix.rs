@@ -0,0 +1,295 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Sky matrices that say how their ground bin was filled.
+//!
+//! The first row of a sky matrix is the ground bin. `gendaymtx` fills it with
+//! the radiance of a uniform ground of a certain albedo (`-g`, `0.2` by default),
+//! lit by the sky and the sun of each timestep. Daylight Coefficients, in turn,
+//! might expect that light (e.g., if the paths that went below the horizon
+//! escaped into the ground bin) or not (e.g., if the ground is part of the scene).
+//! Multiplying one by the other without looking would count the light of the
+//! ground twice, or not at all, so both carry a [`GroundConvention`], and
+//! [`LabeledMatrix::apply_sky`](crate::LabeledMatrix::apply_sky) reconciles them.
+
+use crate::sky::SkyBasis;
+use crate::{Float, PI};
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// The albedo of the ground with which `gendaymtx` fills the ground bin by default
+pub const DEFAULT_GROUND_ALBEDO: Float = 0.2;
+
+/// How the ground bin of a sky matrix or of a Daylight Coefficient matrix is
+/// meant to be used
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GroundConvention {
+    /// The ground bin is a uniform ground of a certain `albedo`, lit by the
+    /// sky (and the sun) of each timestep, as `gendaymtx` does
+    GroundIncluded {
+        /// The albedo of the ground
+        albedo: Float,
+    },
+
+    /// The ground bin carries no light (e.g., because the ground is part of
+    /// the scene)
+    GroundExcluded,
+}
+
+impl std::default::Default for GroundConvention {
+    fn default() -> Self {
+        Self::GroundIncluded {
+            albedo: DEFAULT_GROUND_ALBEDO,
+        }
+    }
+}
+
+impl GroundConvention {
+    /// Checks that the albedo, if any, is between `0` and `1`
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::GroundIncluded { albedo } if !(0.0..=1.).contains(albedo) => Err(format!(
+                "The albedo of the ground must be between 0 and 1, but found {}",
+                albedo
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A sky matrix (one row per bin and one column per timestep) that knows what
+/// its ground bin holds
+#[derive(Debug, Clone)]
+pub struct SkyMatrix {
+    /// The discretisation of the sky
+    pub basis: SkyBasis,
+
+    /// The radiance of each bin at each timestep
+    pub values: Matrix,
+
+    /// What the ground bin holds
+    pub ground: GroundConvention,
+}
+
+impl SkyMatrix {
+    /// Attaches a [`GroundConvention`] to a matrix, checking that it has one row
+    /// per bin of the `basis`
+    pub fn new(basis: SkyBasis, values: Matrix, ground: GroundConvention) -> Result<Self, String> {
+        basis.check_sky(&values)?;
+        ground.validate()?;
+        Ok(Self {
+            basis,
+            values,
+            ground,
+        })
+    }
+
+    /// A matrix produced by `gendaymtx` with a ground of a certain `albedo` (i.e.,
+    /// its `-g` option)
+    pub fn from_gendaymtx(basis: SkyBasis, values: Matrix, albedo: Float) -> Result<Self, String> {
+        Self::new(basis, values, GroundConvention::GroundIncluded { albedo })
+    }
+
+    /// The irradiance on an unobstructed horizontal plane at each timestep, from
+    /// every bin but the ground
+    pub fn horizontal_irradiance(&self) -> Result<Vec<Float>, String> {
+        let omegas = self.basis.solid_angles();
+        let centroids = self.basis.centroids();
+        let (_, n_steps) = self.values.size();
+        (0..n_steps)
+            .map(|c| {
+                let mut ret = 0.0;
+                for bin in 1..self.basis.n_bins() {
+                    ret += self.values.get(bin, c)? * omegas[bin] * centroids[bin].z;
+                }
+                Ok(ret)
+            })
+            .collect()
+    }
+
+    /// The values, with a ground bin that follows the `target` convention. Ground
+    /// light is removed for [`GroundConvention::GroundExcluded`], and it is
+    /// rescaled to another albedo—or calculated from the rest of the sky, if the
+    /// matrix does not have it—for [`GroundConvention::GroundIncluded`].
+    ///
+    /// Fails if the matrix has a ground with an albedo of `0` and light is needed
+    /// from a brighter one: that ground row says nothing about how bright the
+    /// ground would be. Declaring the matrix [`GroundConvention::GroundExcluded`]
+    /// calculates it from the sky instead.
+    pub fn reconciled(&self, target: GroundConvention) -> Result<Matrix, String> {
+        target.validate()?;
+        let bin = SkyBasis::GROUND_BIN;
+        let (_, n_steps) = self.values.size();
+        let mut ret = self.values.clone();
+        match (self.ground, target) {
+            (_, GroundConvention::GroundExcluded) => {
+                for c in 0..n_steps {
+                    ret.set(bin, c, 0.0)?;
+                }
+            }
+            (
+                GroundConvention::GroundIncluded { albedo: from },
+                GroundConvention::GroundIncluded { albedo: to },
+            ) => {
+                if from == to {
+                    return Ok(ret);
+                }
+                if from <= 0.0 {
+                    return Err(format!(
+                        "The ground bin of a sky with a ground of albedo 0 cannot be rescaled to an albedo of {}. Declare the sky as GroundExcluded to calculate the ground from the rest of the sky.",
+                        to
+                    ));
+                }
+                for c in 0..n_steps {
+                    ret.set(bin, c, self.values.get(bin, c)? * to / from)?;
+                }
+            }
+            (GroundConvention::GroundExcluded, GroundConvention::GroundIncluded { albedo }) => {
+                for (c, e) in self.horizontal_irradiance()?.iter().enumerate() {
+                    ret.set(bin, c, albedo * e / PI)?;
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::annual::apply_annual;
+    use crate::session::{DCOptions, DCSession};
+    use crate::SensorSpec;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use rendering::Scene;
+    use validate::assert_close;
+
+    /// A single hour of an overcast sky, with its ground bin filled as
+    /// `gendaymtx` does
+    fn overcast(albedo: Float) -> SkyMatrix {
+        let basis = SkyBasis::new(1).unwrap();
+        let values = basis.cie_overcast(10000., albedo).unwrap();
+        SkyMatrix::from_gendaymtx(basis, values, albedo).unwrap()
+    }
+
+    #[test]
+    fn test_reconciled() {
+        let sky = overcast(0.2);
+        let ground = sky.values.get(SkyBasis::GROUND_BIN, 0).unwrap();
+        assert_close!(ground, 0.2 * 10000. / PI, 1e-3);
+        assert_close!(sky.horizontal_irradiance().unwrap()[0], 10000., 1e-2);
+        let n_bins = sky.basis.n_bins();
+        let same_sky = |m: &Matrix| {
+            for bin in 1..n_bins {
+                assert_eq!(m.get(bin, 0).unwrap(), sky.values.get(bin, 0).unwrap());
+            }
+        };
+
+        let kept = sky.reconciled(sky.ground).unwrap();
+        assert_eq!(kept.get(0, 0).unwrap(), ground);
+        same_sky(&kept);
+        let brighter = sky
+            .reconciled(GroundConvention::GroundIncluded { albedo: 0.4 })
+            .unwrap();
+        assert_close!(brighter.get(0, 0).unwrap(), 2. * ground, 1e-3);
+        same_sky(&brighter);
+        let stripped = sky.reconciled(GroundConvention::GroundExcluded).unwrap();
+        assert_eq!(stripped.get(0, 0).unwrap(), 0.0);
+        same_sky(&stripped);
+
+        // The ground is injected back from the rest of the sky
+        let without =
+            SkyMatrix::new(sky.basis, stripped, GroundConvention::GroundExcluded).unwrap();
+        let injected = without.reconciled(GroundConvention::default()).unwrap();
+        assert_close!(injected.get(0, 0).unwrap(), ground, 1e-3);
+        same_sky(&injected);
+
+        // A black ground says nothing about a brighter one
+        let black = overcast(0.);
+        assert!(black.reconciled(GroundConvention::default()).is_err());
+        assert!(black.reconciled(GroundConvention::GroundExcluded).is_ok());
+        assert!(sky
+            .reconciled(GroundConvention::GroundIncluded { albedo: 1.5 })
+            .is_err());
+        assert!(SkyMatrix::from_gendaymtx(sky.basis, sky.values.clone(), -0.1).is_err());
+        let short = Matrix::new(0.0, n_bins - 1, 1);
+        assert!(SkyMatrix::new(sky.basis, short, GroundConvention::GroundExcluded).is_err());
+    }
+
+    #[test]
+    fn test_apply_sky() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 2000,
+                ..DCOptions::default()
+            },
+        );
+        // One sensor sees only the sky, and the other one only the ground
+        let sensors: Vec<SensorSpec> = [1., -1.]
+            .iter()
+            .map(|z| {
+                Ray3D {
+                    origin: Point3D::new(0., 0., 1.),
+                    direction: Vector3D::new(0., 0., *z),
+                }
+                .into()
+            })
+            .collect();
+        let mut dc = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        assert_eq!(dc.ground, GroundConvention::default());
+
+        // With the same convention, this is a plain multiplication (as
+        // `dctimestep` does)
+        let sky = overcast(DEFAULT_GROUND_ALBEDO);
+        let plain = apply_annual(&dc.matrix, &sky.values).unwrap();
+        let found = dc.apply_sky(&sky).unwrap();
+        for r in 0..2 {
+            assert_eq!(found.get(r, 0).unwrap(), plain.get(r, 0).unwrap());
+        }
+        assert_close!(found.get(0, 0).unwrap(), 10000., 300.);
+        assert_close!(found.get(1, 0).unwrap(), 0.2 * 10000., 1.);
+
+        // A sky with another ground is brought to the one of the matrix
+        let darker = dc.apply_sky(&overcast(0.1)).unwrap();
+        for r in 0..2 {
+            let expected = plain.get(r, 0).unwrap();
+            assert_close!(darker.get(r, 0).unwrap(), expected, 1e-3 * (1. + expected));
+        }
+
+        // ... and a matrix that does not want the ground does not get it
+        dc.ground = GroundConvention::GroundExcluded;
+        let excluded = dc.apply_sky(&sky).unwrap();
+        assert_eq!(excluded.get(0, 0).unwrap(), plain.get(0, 0).unwrap());
+        assert_eq!(excluded.get(1, 0).unwrap(), 0.0);
+
+        let other = SkyBasis::new(2).unwrap();
+        let other = SkyMatrix::new(
+            other,
+            Matrix::new(0.0, other.n_bins(), 1),
+            GroundConvention::GroundExcluded,
+        )
+        .unwrap();
+        assert!(dc.apply_sky(&other).is_err());
+    }
+}
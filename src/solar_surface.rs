@@ -20,6 +20,7 @@ SOFTWARE.
 
 use std::sync::Arc;
 
+use crate::events::{EventKind, EventLog};
 use crate::Float;
 
 use matrix::Matrix;
@@ -118,6 +119,20 @@ impl SolarSurface {
         scene: &Scene,
         dc_factory: &DCFactory,
         front_side: bool,
+    ) -> Result<Matrix, String> {
+        let mut events = EventLog::new();
+        Self::calc_solar_dc_matrix_with_events(list, scene, dc_factory, front_side, &mut events)
+    }
+
+    /// Like `calc_solar_dc_matrix`, but records the sides that are
+    /// skipped (see [`EventKind::NonApplicableSide`]) in `events`, under the
+    /// index of the surface in `list`.
+    pub fn calc_solar_dc_matrix_with_events(
+        list: &[SolarSurface],
+        scene: &Scene,
+        dc_factory: &DCFactory,
+        front_side: bool,
+        events: &mut EventLog,
     ) -> Result<Matrix, String> {
         if list.is_empty() {
             return Ok(Matrix::empty());
@@ -125,13 +140,22 @@ impl SolarSurface {
 
         let mut dcs: Vec<Matrix> = Vec::with_capacity(list.len());
 
-        for s in list.iter() {
-            // Skip front ones that do not receive front sun
-            if front_side && !s.receives_sun_front {
-                continue;
-            }
-            // Skip back ones that do not receive back side.
-            if !front_side && !s.receives_sun_back {
+        for (i, s) in list.iter().enumerate() {
+            // Skip front ones that do not receive front sun, and back
+            // ones that do not receive back sun.
+            let (receives_sun, side) = if front_side {
+                (s.receives_sun_front, "front")
+            } else {
+                (s.receives_sun_back, "back")
+            };
+            if !receives_sun {
+                events.push(
+                    EventKind::NonApplicableSide,
+                    Some(i),
+                    None,
+                    1,
+                    format!("the {} side does not receive sun", side),
+                );
                 continue;
             }
             let rays = if front_side {
@@ -340,6 +364,32 @@ mod testing {
     use geometry3d::Loop3D;
     use validate::assert_close;
 
+    #[test]
+    fn test_non_applicable_side_events() {
+        let surface = |front, back| SolarSurface {
+            points: Vec::new(),
+            normal: Vector3D::new(0., 0., 1.),
+            receives_sun_front: front,
+            receives_sun_back: back,
+        };
+        let list = vec![surface(false, true), surface(false, false)];
+        let scene = Scene::new();
+        let factory = DCFactory::default();
+        let mut events = EventLog::new();
+        let dc = SolarSurface::calc_solar_dc_matrix_with_events(
+            &list,
+            &scene,
+            &factory,
+            true,
+            &mut events,
+        )
+        .unwrap();
+        assert!(dc.is_empty());
+        assert_eq!(events.len(), 2);
+        assert!(events.has(EventKind::NonApplicableSide, 0));
+        assert!(events.has(EventKind::NonApplicableSide, 1));
+    }
+
     #[test]
     fn test_view_factors_empty_scene_vertical() {
         let mut the_loop = Loop3D::new();
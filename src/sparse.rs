@@ -0,0 +1,336 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::dtype::Wide;
+use crate::Float;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// A matrix in Compressed Sparse Row format, which is a lot lighter than
+/// a dense `Matrix` when most elements are zero (e.g., direct-sun
+/// Daylight Coefficients at high sky subdivisions).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseMatrix {
+    ncols: usize,
+    /// The elements of row `i` are in `row_ptr[i]..row_ptr[i + 1]`
+    row_ptr: Vec<usize>,
+    col_idx: Vec<usize>,
+    values: Vec<Float>,
+}
+
+impl SparseMatrix {
+    /// Creates a matrix with `ncols` columns and no rows
+    pub fn new(ncols: usize) -> Self {
+        Self {
+            ncols,
+            row_ptr: vec![0],
+            col_idx: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Builds a matrix from its raw parts, checking that they are consistent
+    pub fn from_parts(
+        ncols: usize,
+        row_ptr: Vec<usize>,
+        col_idx: Vec<usize>,
+        values: Vec<Float>,
+    ) -> Result<Self, String> {
+        if row_ptr.first() != Some(&0) || row_ptr.windows(2).any(|w| w[0] > w[1]) {
+            return Err("Sparse matrix has invalid row pointers".to_string());
+        }
+        let nnz = *row_ptr.last().unwrap_or(&0);
+        if col_idx.len() != nnz || values.len() != nnz {
+            return Err(format!(
+                "Sparse matrix declares {} elements but has {} columns indices and {} values",
+                nnz,
+                col_idx.len(),
+                values.len()
+            ));
+        }
+        if let Some(c) = col_idx.iter().find(|c| **c >= ncols) {
+            return Err(format!(
+                "Sparse matrix has an element in column {}, but only {} columns",
+                c, ncols
+            ));
+        }
+        Ok(Self {
+            ncols,
+            row_ptr,
+            col_idx,
+            values,
+        })
+    }
+
+    /// Appends a row, given as a dense slice. Elements whose absolute
+    /// value is not larger than `threshold` are dropped.
+    pub fn push_dense_row(&mut self, row: &[Float], threshold: Float) -> Result<(), String> {
+        if row.len() != self.ncols {
+            return Err(format!(
+                "Cannot push a row of {} elements into a sparse matrix with {} columns",
+                row.len(),
+                self.ncols
+            ));
+        }
+        for (c, v) in row.iter().enumerate() {
+            if v.abs() > threshold {
+                self.col_idx.push(c);
+                self.values.push(*v);
+            }
+        }
+        self.row_ptr.push(self.values.len());
+        Ok(())
+    }
+
+    /// Appends a row, given as its `(column, value)` elements in increasing order
+    /// of column. Elements whose absolute value is not larger than `threshold`
+    /// are dropped.
+    pub fn push_row<I: IntoIterator<Item = (usize, Float)>>(
+        &mut self,
+        elements: I,
+        threshold: Float,
+    ) -> Result<(), String> {
+        let start = self.values.len();
+        let mut previous: Option<usize> = None;
+        for (c, v) in elements {
+            if c >= self.ncols || previous.is_some_and(|p| c <= p) {
+                self.col_idx.truncate(start);
+                self.values.truncate(start);
+                return Err(format!(
+                    "The elements of a sparse row must be in increasing order of column, and below column {}... found column {} after {:?}",
+                    self.ncols, c, previous
+                ));
+            }
+            previous = Some(c);
+            if v.abs() > threshold {
+                self.col_idx.push(c);
+                self.values.push(v);
+            }
+        }
+        self.row_ptr.push(self.values.len());
+        Ok(())
+    }
+
+    /// Converts a dense matrix, dropping the elements whose absolute value
+    /// is not larger than `threshold` (use `0.0` for keeping all non-zeros)
+    pub fn from_dense(m: &Matrix, threshold: Float) -> Result<Self, String> {
+        let (nrows, ncols) = m.size();
+        let mut ret = Self::new(ncols);
+        let mut row = vec![0.0; ncols];
+        for r in 0..nrows {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = m.get(r, c)?;
+            }
+            ret.push_dense_row(&row, threshold)?;
+        }
+        Ok(ret)
+    }
+
+    /// Converts into a dense matrix
+    pub fn to_dense(&self) -> Result<Matrix, String> {
+        let mut ret = Matrix::new(0.0, self.nrows(), self.ncols);
+        for r in 0..self.nrows() {
+            for (c, v) in self.row(r) {
+                ret.set(r, c, v)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The number of rows
+    pub fn nrows(&self) -> usize {
+        self.row_ptr.len() - 1
+    }
+
+    /// The number of columns
+    pub fn ncols(&self) -> usize {
+        self.ncols
+    }
+
+    /// The number of rows and columns
+    pub fn size(&self) -> (usize, usize) {
+        (self.nrows(), self.ncols)
+    }
+
+    /// The number of elements stored
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The fraction of elements that are stored
+    pub fn density(&self) -> Float {
+        let n = self.nrows() * self.ncols;
+        if n == 0 {
+            return 0.0;
+        }
+        self.nnz() as Float / n as Float
+    }
+
+    /// The approximate memory used by the elements of the matrix, in bytes
+    pub fn memory_bytes(&self) -> usize {
+        self.row_ptr.len() * std::mem::size_of::<usize>()
+            + self.col_idx.len() * std::mem::size_of::<usize>()
+            + self.values.len() * std::mem::size_of::<Float>()
+    }
+
+    /// Iterates over the `(column, value)` pairs stored in a row
+    pub fn row(&self, r: usize) -> impl Iterator<Item = (usize, Float)> + '_ {
+        let range = self.row_ptr[r]..self.row_ptr[r + 1];
+        self.col_idx[range.clone()]
+            .iter()
+            .copied()
+            .zip(self.values[range].iter().copied())
+    }
+
+    /// Gets an element (zero if it is not stored)
+    pub fn get(&self, r: usize, c: usize) -> Result<Float, String> {
+        if r >= self.nrows() || c >= self.ncols {
+            return Err(format!(
+                "Element ({},{}) is out of bounds of a {}x{} sparse matrix",
+                r,
+                c,
+                self.nrows(),
+                self.ncols
+            ));
+        }
+        Ok(self
+            .row(r)
+            .find(|(col, _)| *col == c)
+            .map(|(_, v)| v)
+            .unwrap_or(0.0))
+    }
+
+    /// The raw parts of the matrix: row pointers, column indices and values
+    pub fn parts(&self) -> (&[usize], &[usize], &[Float]) {
+        (&self.row_ptr, &self.col_idx, &self.values)
+    }
+
+    /// Multiplies by a dense matrix, only visiting the stored elements. Products are
+    /// accumulated in [`Wide`] (see [`ApplySky`](crate::ApplySky)).
+    pub fn mul_dense(&self, other: &Matrix) -> Result<Matrix, String> {
+        let (other_rows, other_cols) = other.size();
+        if other_rows != self.ncols {
+            return Err(format!(
+                "Cannot multiply a {}x{} sparse matrix by a {}x{} matrix",
+                self.nrows(),
+                self.ncols,
+                other_rows,
+                other_cols
+            ));
+        }
+        let mut ret = Matrix::new(0.0, self.nrows(), other_cols);
+        let mut acc: Vec<Wide> = vec![0.0; other_cols];
+        for r in 0..self.nrows() {
+            acc.iter_mut().for_each(|v| *v = 0.0);
+            for (c, v) in self.row(r) {
+                let v = Wide::from(v);
+                for (j, a) in acc.iter_mut().enumerate() {
+                    *a += v * Wide::from(other.get(c, j)?);
+                }
+            }
+            for (j, a) in acc.iter().enumerate() {
+                ret.set(r, j, *a as Float)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    /// A matrix like those of direct sun, with a single non-zero per row
+    fn mostly_zeros(nrows: usize, ncols: usize) -> Matrix {
+        let mut m = Matrix::new(0.0, nrows, ncols);
+        for r in 0..nrows {
+            m.set(r, (r * 7) % ncols, 1. + r as Float).unwrap();
+        }
+        m
+    }
+
+    #[test]
+    fn test_dense_round_trip() {
+        let dense = mostly_zeros(20, 146);
+        let sparse = SparseMatrix::from_dense(&dense, 0.0).unwrap();
+        assert_eq!(sparse.size(), (20, 146));
+        assert_eq!(sparse.nnz(), 20);
+        let back = sparse.to_dense().unwrap();
+        for r in 0..20 {
+            for c in 0..146 {
+                assert_eq!(back.get(r, c).unwrap(), dense.get(r, c).unwrap());
+            }
+        }
+        assert_close!(sparse.get(3, 21).unwrap(), 4., 1e-9);
+        assert_close!(sparse.get(3, 22).unwrap(), 0., 1e-9);
+        assert!(sparse.get(20, 0).is_err());
+
+        // Thresholds drop small values
+        let sparse = SparseMatrix::from_dense(&dense, 5.5).unwrap();
+        assert_eq!(sparse.nnz(), 15);
+    }
+
+    #[test]
+    fn test_from_parts() {
+        assert!(SparseMatrix::from_parts(3, vec![0, 1], vec![0], vec![1.]).is_ok());
+        assert!(SparseMatrix::from_parts(3, vec![0, 1], vec![3], vec![1.]).is_err());
+        assert!(SparseMatrix::from_parts(3, vec![0, 2], vec![0], vec![1.]).is_err());
+        assert!(SparseMatrix::from_parts(3, vec![1, 1], vec![0], vec![1.]).is_err());
+    }
+
+    #[test]
+    fn test_push_row() {
+        let mut m = SparseMatrix::new(4);
+        m.push_row([(0, 1.), (2, 1e-9), (3, 2.)], 1e-6).unwrap();
+        m.push_row([], 0.0).unwrap();
+        assert_eq!(m.size(), (2, 4));
+        assert_eq!(m.nnz(), 2);
+        assert_eq!(m.get(0, 3).unwrap(), 2.);
+
+        // Out of order or out of bounds, nothing is added
+        assert!(m.push_row([(1, 1.), (1, 1.)], 0.0).is_err());
+        assert!(m.push_row([(1, 1.), (4, 1.)], 0.0).is_err());
+        assert_eq!(m.size(), (2, 4));
+        assert_eq!(m.nnz(), 2);
+    }
+
+    #[test]
+    fn test_savings() {
+        // A direct-sun-like matrix at MF:6
+        let ncols = 5186;
+        let dense = mostly_zeros(50, ncols);
+        let sparse = SparseMatrix::from_dense(&dense, 0.0).unwrap();
+        let dense_bytes = 50 * ncols * std::mem::size_of::<Float>();
+        assert!(sparse.memory_bytes() * 100 < dense_bytes);
+
+        // Multiplying gives the same as the dense product
+        let skies = Matrix::new(2.0, ncols, 3);
+        let expected = &dense * &skies;
+        let found = sparse.mul_dense(&skies).unwrap();
+        assert_eq!(found.size(), (50, 3));
+        for r in 0..50 {
+            for c in 0..3 {
+                assert_close!(found.get(r, c).unwrap(), expected.get(r, c).unwrap(), 1e-9);
+            }
+        }
+        assert!(sparse.mul_dense(&Matrix::new(1., 3, 3)).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Daylight Coefficients in several bands of the spectrum (e.g., photopic and
+//! melanopic), for circadian lighting metrics.
+//!
+//! Everything is calculated as in the photopic case, except that each band has
+//! its own reflectances and its own sky. Skies are derived from photopic ones
+//! through the ratio between the radiance in the band and the photopic radiance
+//! of typical daylight, which avoids generating spectral skies.
+
+use crate::annual::apply_annual;
+use crate::scene_loading::SceneReport;
+use crate::sensitivity::{diffuse_reflectance, material_responses, LambertianTracer, Response};
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::sky::SkyBasis;
+use crate::Float;
+use matrix::Matrix;
+use rendering::Scene;
+use std::collections::HashMap;
+
+/// The melanopic/photopic ratio of daylight with a correlated colour
+/// temperature of 6500 K, as tabulated by the WELL Building Standard after
+/// the toolbox of Lucas et al. (2014)
+pub const MELANOPIC_RATIO_D65: Float = 1.1;
+
+/// A band of the spectrum, defined by how the sky looks in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectralBand {
+    /// The name of the band (e.g., `melanopic`)
+    pub name: String,
+
+    /// The ratio between the radiance of the sky in this band and its
+    /// photopic radiance
+    pub sky_ratio: Float,
+
+    /// The ratio between the radiance of the ground bin in this band and its
+    /// photopic radiance
+    pub ground_ratio: Float,
+}
+
+impl SpectralBand {
+    /// Creates a new band, checking that the ratios are non-negative
+    pub fn new(name: &str, sky_ratio: Float, ground_ratio: Float) -> Result<Self, String> {
+        for (what, v) in [("sky", sky_ratio), ("ground", ground_ratio)] {
+            if !v.is_finite() || v < 0.0 {
+                return Err(format!(
+                    "The {} ratio of band '{}' must be a non-negative number, but found {}",
+                    what, name, v
+                ));
+            }
+        }
+        Ok(Self {
+            name: name.to_string(),
+            sky_ratio,
+            ground_ratio,
+        })
+    }
+
+    /// The photopic band, in which skies are used as they are
+    pub fn photopic() -> Self {
+        Self {
+            name: "photopic".to_string(),
+            sky_ratio: 1.,
+            ground_ratio: 1.,
+        }
+    }
+
+    /// The melanopic band, for a sky and a ground lit by daylight of
+    /// 6500 K (see [`MELANOPIC_RATIO_D65`])
+    pub fn melanopic() -> Self {
+        Self {
+            name: "melanopic".to_string(),
+            sky_ratio: MELANOPIC_RATIO_D65,
+            ground_ratio: MELANOPIC_RATIO_D65,
+        }
+    }
+
+    /// Converts photopic skies—one row per bin, with the ground first, and
+    /// one column per timestep—into skies in this band
+    pub fn sky(&self, photopic: &Matrix) -> Result<Matrix, String> {
+        let (nrows, ncols) = photopic.size();
+        let mut ret = Matrix::new(0.0, nrows, ncols);
+        for r in 0..nrows {
+            let ratio = if r == SkyBasis::GROUND_BIN {
+                self.ground_ratio
+            } else {
+                self.sky_ratio
+            };
+            for c in 0..ncols {
+                ret.set(r, c, photopic.get(r, c)? * ratio)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// The bands of a spectral calculation, and the reflectance of the
+/// materials in each of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectralOptions {
+    /// The bands
+    pub bands: Vec<SpectralBand>,
+
+    /// The reflectance of some materials in each band. Materials that are not here
+    /// reflect the same in every band, as in the single-band calculation (i.e., the
+    /// average of their RGB reflectance).
+    pub reflectances: HashMap<String, Vec<Float>>,
+}
+
+impl SpectralOptions {
+    /// Creates options for some bands, with every material reflecting
+    /// the same in all of them
+    pub fn new(bands: Vec<SpectralBand>) -> Result<Self, String> {
+        if bands.is_empty() {
+            return Err("At least one spectral band is needed".to_string());
+        }
+        for (i, b) in bands.iter().enumerate() {
+            if bands[..i].iter().any(|other| other.name == b.name) {
+                return Err(format!("Spectral band '{}' is repeated", b.name));
+            }
+        }
+        Ok(Self {
+            bands,
+            reflectances: HashMap::new(),
+        })
+    }
+
+    /// Sets the reflectance of a material in each band
+    pub fn set_reflectance(&mut self, material: &str, values: Vec<Float>) -> Result<(), String> {
+        if values.len() != self.bands.len() {
+            return Err(format!(
+                "Material '{}' needs one reflectance per band ({}), but {} were given",
+                material,
+                self.bands.len(),
+                values.len()
+            ));
+        }
+        if let Some(v) = values.iter().find(|v| !(0.0..=1.0).contains(*v)) {
+            return Err(format!(
+                "Reflectances must be between 0 and 1, but material '{}' has {}",
+                material, v
+            ));
+        }
+        self.reflectances.insert(material.to_string(), values);
+        Ok(())
+    }
+}
+
+/// Daylight Coefficients in several bands of the spectrum, as calculated by
+/// [`DCSession::calc_spectral_dc`]
+#[derive(Debug, Clone)]
+pub struct SpectralDC {
+    /// The discretisation of the sky of the coefficients
+    pub basis: SkyBasis,
+
+    /// The bands, in the order of the matrices
+    pub bands: Vec<SpectralBand>,
+
+    /// One matrix per band, with one row per sensor and one column per bin
+    dcs: Vec<Matrix>,
+}
+
+impl SpectralDC {
+    /// The matrices of all bands
+    pub fn dcs(&self) -> &[Matrix] {
+        &self.dcs
+    }
+
+    /// The matrix of the band called `name`, if there is one
+    pub fn band(&self, name: &str) -> Option<&Matrix> {
+        let i = self.bands.iter().position(|b| b.name == name)?;
+        Some(&self.dcs[i])
+    }
+
+    /// Applies photopic skies (one column per timestep) to each band, converting
+    /// them through [`SpectralBand::sky`]. Returns one matrix per band, with one
+    /// row per sensor and one column per timestep.
+    pub fn annual(&self, photopic_skies: &Matrix) -> Result<Vec<Matrix>, String> {
+        self.basis.check_sky(photopic_skies)?;
+        self.bands
+            .iter()
+            .zip(self.dcs.iter())
+            .map(|(band, dc)| apply_annual(dc, &band.sky(photopic_skies)?))
+            .collect()
+    }
+}
+
+/// The ratio between melanopic and photopic results (e.g., as returned
+/// by [`SpectralDC::annual`]) for each sensor and timestep. This is zero
+/// when there is no photopic light (e.g., at night).
+pub fn melanopic_ratio(melanopic: &Matrix, photopic: &Matrix) -> Result<Matrix, String> {
+    if melanopic.size() != photopic.size() {
+        return Err(format!(
+            "Melanopic results have size {:?}, but photopic ones have {:?}",
+            melanopic.size(),
+            photopic.size()
+        ));
+    }
+    let (nrows, ncols) = photopic.size();
+    let mut ret = Matrix::new(0.0, nrows, ncols);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            let p = photopic.get(r, c)?;
+            if p > 0.0 {
+                ret.set(r, c, melanopic.get(r, c)? / p)?;
+            }
+        }
+    }
+    Ok(ret)
+}
+
+impl DCSession {
+    /// Calculates Daylight Coefficients in several bands of the spectrum (see
+    /// [`SpectralOptions`]). The `report` must be the one returned when loading
+    /// the `scene`, as it says which material each triangle is made of.
+    ///
+    /// This follows the simplified model of
+    /// [`DCSession::calc_reflectance_tallies`], carrying the throughput of each
+    /// band along the same paths. Therefore, bands with the same reflectances give
+    /// exactly the same (i.e., single-band) coefficients.
+    pub fn calc_spectral_dc(
+        &self,
+        sensors: &[SensorSpec],
+        scene: &Scene,
+        report: &SceneReport,
+        spectral: &SpectralOptions,
+    ) -> Result<SpectralDC, String> {
+        let tracer = LambertianTracer::new(self, scene, report)?;
+        let n_bands = spectral.bands.len();
+        if n_bands == 0 {
+            return Err("At least one spectral band is needed".to_string());
+        }
+        let mut reflectances: Vec<Vec<Float>> = Vec::with_capacity(report.materials.len());
+        for m in report.materials.iter() {
+            match spectral.reflectances.get(&m.name) {
+                Some(values) if values.len() != n_bands => {
+                    return Err(format!(
+                        "Material '{}' has {} reflectances, but there are {} bands",
+                        m.name,
+                        values.len(),
+                        n_bands
+                    ))
+                }
+                Some(values) => reflectances.push(values.clone()),
+                None => reflectances.push(vec![diffuse_reflectance(&m.kind, m.rgb); n_bands]),
+            }
+        }
+        if let Some(name) = spectral
+            .reflectances
+            .keys()
+            .find(|name| !report.materials.iter().any(|m| m.name == **name))
+        {
+            return Err(format!(
+                "There is no material called '{}' in the scene",
+                name
+            ));
+        }
+        let responses = material_responses(report, |m| {
+            if reflectances[m].iter().any(|v| *v > 0.0) {
+                Response::Reflect
+            } else {
+                Response::Absorb
+            }
+        });
+        self.check_budget(sensors.len() * n_bands)?;
+
+        let basis = self.basis()?;
+        let n_bins = basis.n_bins();
+        let one_over_samples = 1. / self.options().n_ambient_samples as Float;
+        let trace = |(index, sensor): (usize, &SensorSpec)| {
+            let mut ret = vec![vec![0.0; n_bins]; n_bands];
+            tracer.trace(
+                index,
+                sensor,
+                &responses,
+                |throughput, reflections, _, _, bin| {
+                    for (band, values) in ret.iter_mut().enumerate() {
+                        values[bin] += reflections
+                            .iter()
+                            .fold(throughput, |t, m| t * reflectances[*m][band])
+                            * one_over_samples;
+                    }
+                },
+            )?;
+            Ok(ret)
+        };
+        let rows = self.map_sensors(sensors, trace)?;
+
+        let mut dcs = vec![Matrix::new(0.0, sensors.len(), n_bins); n_bands];
+        for (r, row) in rows.iter().enumerate() {
+            for (dc, values) in dcs.iter_mut().zip(row.iter()) {
+                for (bin, v) in values.iter().enumerate() {
+                    dc.set(r, bin, *v)?;
+                }
+            }
+        }
+        Ok(SpectralDC {
+            basis,
+            bands: spectral.bands.clone(),
+            dcs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::load_scene;
+    use crate::session::DCOptions;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    fn setup() -> (DCSession, Scene, SceneReport, Vec<SensorSpec>) {
+        let (mut scene, report) = load_scene("./tests/sensitivity/canopy.rad").unwrap();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                n_ambient_samples: 1000,
+                max_depth: 3,
+                seed: 3,
+                ..DCOptions::default()
+            },
+        );
+        let sensors: Vec<SensorSpec> = [
+            (Point3D::new(0., 0., 1.), Vector3D::new(0., 0., 1.)),
+            (Point3D::new(0., 1., 1.), Vector3D::new(0., -1., 0.)),
+        ]
+        .iter()
+        .map(|(origin, direction)| {
+            Ray3D {
+                origin: *origin,
+                direction: *direction,
+            }
+            .into()
+        })
+        .collect();
+        (session, scene, report, sensors)
+    }
+
+    fn assert_matrices_eq(a: &Matrix, b: &Matrix) {
+        assert_eq!(a.size(), b.size());
+        let (nrows, ncols) = a.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_eq!(a.get(r, c).unwrap(), b.get(r, c).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_equal_bands() {
+        let (session, scene, report, sensors) = setup();
+        let single = session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+
+        let bands = vec![
+            SpectralBand::photopic(),
+            SpectralBand::melanopic(),
+            SpectralBand::new("blue", 1.3, 0.8).unwrap(),
+        ];
+        let mut spectral = SpectralOptions::new(bands).unwrap();
+        // Explicitly the same as the default
+        spectral.set_reflectance("wall_mat", vec![0.5; 3]).unwrap();
+        let dc = session
+            .calc_spectral_dc(&sensors, &scene, &report, &spectral)
+            .unwrap();
+        assert_eq!(dc.dcs().len(), 3);
+        for m in dc.dcs() {
+            assert_matrices_eq(m, single.total());
+        }
+    }
+
+    #[test]
+    fn test_band_reflectances() {
+        let (session, scene, report, sensors) = setup();
+        let single = session
+            .calc_reflectance_tallies(&sensors, &scene, &report)
+            .unwrap();
+        let mut spectral =
+            SpectralOptions::new(vec![SpectralBand::photopic(), SpectralBand::melanopic()])
+                .unwrap();
+        // A wall that is warmer (i.e., darker in the melanopic band)
+        spectral
+            .set_reflectance("wall_mat", vec![0.5, 0.25])
+            .unwrap();
+        let dc = session
+            .calc_spectral_dc(&sensors, &scene, &report, &spectral)
+            .unwrap();
+        assert_matrices_eq(dc.band("photopic").unwrap(), single.total());
+
+        // The same as changing the reflectance of the wall after tracing
+        let expected = single.reweight("wall_mat", 0.25).unwrap();
+        let melanopic = dc.band("melanopic").unwrap();
+        let (nrows, ncols) = expected.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                let e = expected.get(r, c).unwrap();
+                assert_close!(melanopic.get(r, c).unwrap(), e, 1e-4 * (1e-3 + e));
+            }
+        }
+
+        // Under daylight, the ratio is below that of the sky because of the wall
+        let skies = dc.basis.cie_overcast(10000., 0.2).unwrap();
+        let results = dc.annual(&skies).unwrap();
+        let ratio = melanopic_ratio(&results[1], &results[0]).unwrap();
+        for r in 0..nrows {
+            let v = ratio.get(r, 0).unwrap();
+            assert!(v > 0.5 && v < MELANOPIC_RATIO_D65, "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_melanopic_ratio() {
+        let mut photopic = Matrix::new(0.0, 2, 2);
+        let mut melanopic = Matrix::new(0.0, 2, 2);
+        photopic.set(0, 0, 100.).unwrap();
+        melanopic.set(0, 0, 110.).unwrap();
+        photopic.set(1, 1, 50.).unwrap();
+        melanopic.set(1, 1, 20.).unwrap();
+        let ratio = melanopic_ratio(&melanopic, &photopic).unwrap();
+        assert_close!(ratio.get(0, 0).unwrap(), 1.1, 1e-6);
+        assert_close!(ratio.get(1, 1).unwrap(), 0.4, 1e-6);
+        // Night
+        assert_eq!(ratio.get(0, 1).unwrap(), 0.0);
+        assert!(melanopic_ratio(&Matrix::new(0.0, 1, 2), &photopic).is_err());
+
+        // Skies
+        let sky = SkyBasis::new(1).unwrap().cie_overcast(1000., 0.2).unwrap();
+        let m = SpectralBand::new("m", 1.2, 0.5).unwrap().sky(&sky).unwrap();
+        assert_close!(
+            m.get(0, 0).unwrap(),
+            0.5 * sky.get(0, 0).unwrap(),
+            1e-6 * sky.get(0, 0).unwrap()
+        );
+        assert_close!(
+            m.get(5, 0).unwrap(),
+            1.2 * sky.get(5, 0).unwrap(),
+            1e-6 * sky.get(5, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(SpectralBand::new("m", -1., 1.).is_err());
+        assert!(SpectralOptions::new(vec![]).is_err());
+        assert!(
+            SpectralOptions::new(vec![SpectralBand::photopic(), SpectralBand::photopic()]).is_err()
+        );
+        let mut spectral = SpectralOptions::new(vec![SpectralBand::photopic()]).unwrap();
+        assert!(spectral
+            .set_reflectance("wall_mat", vec![0.5, 0.5])
+            .is_err());
+        assert!(spectral.set_reflectance("wall_mat", vec![1.5]).is_err());
+
+        let (session, scene, report, sensors) = setup();
+        spectral.set_reflectance("paint", vec![0.5]).unwrap();
+        assert!(session
+            .calc_spectral_dc(&sensors, &scene, &report, &spectral)
+            .is_err());
+    }
+}
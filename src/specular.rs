@@ -0,0 +1,531 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::{Ray, Scene};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The refraction index of glass, when the material does not specify one
+pub const DEFAULT_GLASS_INDEX: Float = 1.52;
+
+/// How far from a reflector the reflected rays start, so that they
+/// do not hit it again
+const SURFACE_OFFSET: Float = 1e-4;
+
+/// The tolerance, relative to its distance, for deciding that the first thing
+/// a ray hits is the intended reflector
+const HIT_TOLERANCE: Float = 1e-3;
+
+/// How specular surfaces reflect the sun
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpecularMaterial {
+    /// A Radiance `mirror`, which reflects the same fraction of light at every angle
+    Mirror {
+        /// The (average) reflectance of the mirror
+        reflectance: Float,
+    },
+
+    /// A pane of Radiance `glass`, which reflects following the Fresnel
+    /// equations at both of its faces. Absorption within the pane is neglected.
+    Glass {
+        /// The refraction index of the glass
+        refraction_index: Float,
+    },
+}
+
+impl SpecularMaterial {
+    /// The fraction of the light arriving at an angle whose cosine is `cos_incidence`
+    /// that gets reflected
+    pub fn reflectance(&self, cos_incidence: Float) -> Float {
+        match self {
+            Self::Mirror { reflectance } => *reflectance,
+            Self::Glass { refraction_index } => {
+                let cos_i = cos_incidence.abs().min(1.0);
+                let sin_t = (1. - cos_i * cos_i).sqrt() / refraction_index;
+                let cos_t = (1. - sin_t * sin_t).max(0.0).sqrt();
+                let n = *refraction_index;
+                let rs = (cos_i - n * cos_t) / (cos_i + n * cos_t);
+                let rp = (n * cos_i - cos_t) / (n * cos_i + cos_t);
+                let r = 0.5 * (rs * rs + rp * rp);
+                // Infinite inter-reflections between the two faces of the pane
+                2. * r / (1. + r)
+            }
+        }
+    }
+}
+
+/// A planar polygon that reflects the sun specularly, as loaded by
+/// [`load_specular_reflectors`](crate::load_specular_reflectors)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanarReflector {
+    /// The identifier of the polygon
+    pub name: String,
+
+    /// The vertices of the polygon
+    pub vertices: Vec<Point3D>,
+
+    /// The normal of the plane of the polygon. Both sides of it reflect.
+    pub normal: Vector3D,
+
+    /// How the polygon reflects
+    pub material: SpecularMaterial,
+}
+
+impl PlanarReflector {
+    /// Builds a reflector, failing if the vertices do not define a planar polygon
+    pub fn new(
+        name: &str,
+        vertices: Vec<Point3D>,
+        material: SpecularMaterial,
+    ) -> Result<Self, String> {
+        if vertices.len() < 3 {
+            return Err(format!("Reflector '{}' has less than 3 vertices", name));
+        }
+        // Newell's method, which works for non-convex polygons
+        let mut normal = Vector3D::new(0., 0., 0.);
+        for (i, a) in vertices.iter().enumerate() {
+            let b = vertices[(i + 1) % vertices.len()];
+            normal += Vector3D::new(
+                (a.y - b.y) * (a.z + b.z),
+                (a.z - b.z) * (a.x + b.x),
+                (a.x - b.x) * (a.y + b.y),
+            );
+        }
+        if normal.is_zero() {
+            return Err(format!("Reflector '{}' has no area", name));
+        }
+        let normal = normal.get_normalized();
+        let size = vertices
+            .iter()
+            .map(|v| v.distance(vertices[0]))
+            .fold(0.0, Float::max);
+        if vertices
+            .iter()
+            .any(|v| ((*v - vertices[0]) * normal).abs() > 1e-4 * size)
+        {
+            return Err(format!("Reflector '{}' is not planar", name));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            vertices,
+            normal,
+            material,
+        })
+    }
+
+    /// Reflects a direction on the plane of the reflector
+    pub fn reflect(&self, direction: Vector3D) -> Vector3D {
+        direction - self.normal * (2. * (direction * self.normal))
+    }
+
+    /// The distance from `origin`, along `direction`, at which a ray hits
+    /// the reflector (if it does)
+    fn intersect(&self, origin: Point3D, direction: Vector3D) -> Option<Float> {
+        let cos = direction * self.normal;
+        if cos.abs() < 1e-9 {
+            return None;
+        }
+        let t = ((self.vertices[0] - origin) * self.normal) / cos;
+        if t <= 0.0 {
+            return None;
+        }
+        let p = origin + direction * t;
+        if self.contains(p) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether a point on the plane of the reflector is within the polygon,
+    /// using the crossing-number test on the projection that loses the least area
+    fn contains(&self, p: Point3D) -> bool {
+        let (nx, ny, nz) = (
+            self.normal.x.abs(),
+            self.normal.y.abs(),
+            self.normal.z.abs(),
+        );
+        let project = |v: &Point3D| -> (Float, Float) {
+            if nz >= nx && nz >= ny {
+                (v.x, v.y)
+            } else if ny >= nx {
+                (v.z, v.x)
+            } else {
+                (v.y, v.z)
+            }
+        };
+        let (px, py) = project(&p);
+        let mut inside = false;
+        let n = self.vertices.len();
+        for i in 0..n {
+            let (ax, ay) = project(&self.vertices[i]);
+            let (bx, by) = project(&self.vertices[(i + 1) % n]);
+            if (ay > py) != (by > py) && px < ax + (py - ay) * (bx - ax) / (by - ay) {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+/// Calculates the irradiance that each sensor (row) receives from the sun at each
+/// timestep (column) after being specularly reflected by up to `max_bounces` of
+/// the `reflectors`. The `suns` are the directions towards the sun—in the axes of
+/// the scene, see [`sun_directions`](crate::sun_directions)—and `normal_irradiance`
+/// is the direct normal irradiance at each of them. `None` means that the sun is down.
+///
+/// This complements the Daylight Coefficients, which smear the solar disc over
+/// a whole sky patch and thus cannot capture the glare reflected by glazed
+/// façades. Candidate paths are found through the method of images: for every
+/// sequence of reflectors, the sun direction is mirrored on each of them (in
+/// reverse order) to find where the sensor needs to look. The path is then traced
+/// through the `scene`, and it contributes only if it hits every reflector of
+/// the sequence inside its bounds, nothing else obstructs it, and the last ray
+/// escapes towards the sun. The `reflectors` should also be part of the `scene`.
+///
+/// The number of sequences grows as the number of reflectors to the power of
+/// `max_bounces`, so this is meant for a few bounces over the relevant façades.
+pub fn specular_sun_irradiance(
+    points: &[Ray3D],
+    scene: &Scene,
+    reflectors: &[PlanarReflector],
+    suns: &[Option<Vector3D>],
+    normal_irradiance: &[Float],
+    max_bounces: usize,
+) -> Result<Matrix, String> {
+    if suns.len() != normal_irradiance.len() {
+        return Err(format!(
+            "There are {} sun positions but {} values of direct normal irradiance",
+            suns.len(),
+            normal_irradiance.len()
+        ));
+    }
+    if let Some(dni) = normal_irradiance.iter().find(|v| v.is_nan() || **v < 0.0) {
+        return Err(format!(
+            "The direct normal irradiance must not be negative, but found {}",
+            dni
+        ));
+    }
+    let chains = reflector_chains(reflectors.len(), max_bounces);
+    let suns: Vec<Option<Vector3D>> = suns
+        .iter()
+        .map(|s| s.filter(|dir| dir.z > 0.0).map(|dir| dir.get_normalized()))
+        .collect();
+
+    let trace = |point: &Ray3D| -> Vec<Float> {
+        let mut aux = Vec::with_capacity(2);
+        suns.iter()
+            .zip(normal_irradiance)
+            .map(|(sun, dni)| match sun {
+                Some(sun) if *dni > 0.0 => {
+                    let gain: Float = chains
+                        .iter()
+                        .map(|chain| chain_gain(point, scene, reflectors, chain, *sun, &mut aux))
+                        .sum();
+                    dni * gain
+                }
+                _ => 0.0,
+            })
+            .collect()
+    };
+    #[cfg(feature = "parallel")]
+    let rows: Vec<Vec<Float>> = points.par_iter().map(trace).collect();
+    #[cfg(not(feature = "parallel"))]
+    let rows: Vec<Vec<Float>> = points.iter().map(trace).collect();
+
+    let mut ret = Matrix::new(0.0, points.len(), suns.len());
+    for (row, values) in rows.iter().enumerate() {
+        for (col, v) in values.iter().enumerate() {
+            ret.set(row, col, *v)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// All the sequences of between 1 and `max_bounces` reflectors (out of `n`)
+/// that do not hit the same reflector twice in a row
+fn reflector_chains(n: usize, max_bounces: usize) -> Vec<Vec<usize>> {
+    let mut ret: Vec<Vec<usize>> = Vec::new();
+    let mut last: Vec<Vec<usize>> = vec![vec![]];
+    for _ in 0..max_bounces {
+        let next: Vec<Vec<usize>> = last
+            .iter()
+            .flat_map(|chain| {
+                (0..n)
+                    .filter(move |i| chain.last() != Some(i))
+                    .map(move |i| {
+                        let mut c = chain.clone();
+                        c.push(i);
+                        c
+                    })
+            })
+            .collect();
+        ret.extend(next.iter().cloned());
+        last = next;
+    }
+    ret
+}
+
+/// The fraction of the direct normal irradiance that reaches a sensor from the
+/// direction `sun` after reflecting on the reflectors of `chain`, in order
+fn chain_gain(
+    point: &Ray3D,
+    scene: &Scene,
+    reflectors: &[PlanarReflector],
+    chain: &[usize],
+    sun: Vector3D,
+    aux: &mut Vec<usize>,
+) -> Float {
+    // Method of images: the sun, seen through the chain of mirrors
+    let mut direction = chain
+        .iter()
+        .rev()
+        .fold(sun, |dir, i| reflectors[*i].reflect(dir));
+    let cos = direction * point.direction;
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    let mut gain = cos;
+    let mut origin = point.origin;
+    for i in chain {
+        let reflector = &reflectors[*i];
+        let t = match reflector.intersect(origin, direction) {
+            Some(t) => t,
+            None => return 0.0,
+        };
+        if let Some(d) = first_hit(scene, origin, direction, aux) {
+            if d < t * (1. - HIT_TOLERANCE) {
+                // Something is in front of the reflector
+                return 0.0;
+            }
+        }
+        gain *= reflector.material.reflectance(direction * reflector.normal);
+        origin = origin + direction * t;
+        direction = reflector.reflect(direction);
+        origin = origin + direction * SURFACE_OFFSET;
+    }
+    if first_hit(scene, origin, direction, aux).is_some() {
+        return 0.0;
+    }
+    gain
+}
+
+/// The distance to the first thing in the scene hit by a ray, if any
+fn first_hit(
+    scene: &Scene,
+    origin: Point3D,
+    direction: Vector3D,
+    aux: &mut Vec<usize>,
+) -> Option<Float> {
+    let mut ray = Ray {
+        geometry: Ray3D { origin, direction },
+        ..Ray::default()
+    };
+    scene
+        .cast_ray(&mut ray, aux)
+        .map(|_| ray.interaction.point.distance(origin))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::{load_scenes, load_specular_reflectors, PI};
+    use validate::assert_close;
+
+    /// The scene and the reflectors in `files`
+    fn load(files: &[&str]) -> (Scene, Vec<PlanarReflector>) {
+        let (mut scene, _) = load_scenes(files).unwrap();
+        scene.build_accelerator();
+        let reflectors = load_specular_reflectors(files).unwrap();
+        (scene, reflectors)
+    }
+
+    /// A sun tilted `angle` degrees from the zenith towards `+X`
+    fn tilted_sun(angle: Float) -> Option<Vector3D> {
+        let angle = angle * PI / 180.;
+        Some(Vector3D::new(angle.sin(), 0., angle.cos()))
+    }
+
+    #[test]
+    fn test_reflectance() {
+        let mirror = SpecularMaterial::Mirror { reflectance: 0.9 };
+        assert_eq!(mirror.reflectance(0.1), 0.9);
+
+        // At normal incidence, each face reflects ((n-1)/(n+1))^2
+        let glass = SpecularMaterial::Glass {
+            refraction_index: 1.52,
+        };
+        let r = (0.52 as Float / 2.52).powi(2);
+        assert_close!(glass.reflectance(1.0), 2. * r / (1. + r), 1e-6);
+        assert_close!(glass.reflectance(-1.0), 2. * r / (1. + r), 1e-6);
+        assert!(glass.reflectance(0.3) > glass.reflectance(1.0));
+        assert_close!(glass.reflectance(0.0), 1.0, 1e-6);
+    }
+
+    #[test]
+    fn test_reflector() {
+        let square = vec![
+            Point3D::new(0., 0., 0.),
+            Point3D::new(1., 0., 0.),
+            Point3D::new(1., 1., 0.),
+            Point3D::new(0., 1., 0.),
+        ];
+        let material = SpecularMaterial::Mirror { reflectance: 1. };
+        let r = PlanarReflector::new("square", square.clone(), material).unwrap();
+        assert_close!(r.normal.z, 1., 1e-9);
+        let d = r.reflect(Vector3D::new(1., 0., -1.));
+        assert_close!(d.x, 1., 1e-9);
+        assert_close!(d.z, 1., 1e-9);
+
+        let up = Vector3D::new(0., 0., 1.);
+        assert!(r.intersect(Point3D::new(0.5, 0.5, -1.), up).is_some());
+        assert!(r.intersect(Point3D::new(1.5, 0.5, -1.), up).is_none());
+        assert!(r.intersect(Point3D::new(0.5, 0.5, 1.), up).is_none());
+
+        let mut warped = square;
+        warped[2].z = 0.5;
+        assert!(PlanarReflector::new("warped", warped, material).is_err());
+    }
+
+    #[test]
+    fn test_tilted_mirror() {
+        let (scene, reflectors) = load(&["./tests/specular/tilted_mirror.rad"]);
+        assert_eq!(reflectors.len(), 1);
+        match reflectors[0].material {
+            SpecularMaterial::Mirror { reflectance } => {
+                assert_close!(reflectance, 0.9, 1e-6);
+            }
+            _ => panic!("Expecting a mirror"),
+        }
+
+        // Facing the mirror, and tilted 45 degrees up
+        let sensor = Ray3D {
+            origin: Point3D::new(0., 0., 2.),
+            direction: Vector3D::new(0., 1., 1.).get_normalized(),
+        };
+        // A sun tilted by `a` from the zenith is seen horizontally
+        // at (sin a, cos a, 0), which is within the mirror if
+        // 5 tan(a) < 1 m. Then, E = DNI * 0.9 * cos(a) / sqrt(2).
+        let suns = vec![
+            tilted_sun(0.),
+            tilted_sun(5.),
+            tilted_sun(-10.),
+            tilted_sun(20.),
+            // Behind the mirror
+            Some(Vector3D::new(0., 0.6, 0.8)),
+            None,
+        ];
+        let dni = vec![800., 800., 500., 800., 800., 800.];
+        let res = specular_sun_irradiance(&[sensor], &scene, &reflectors, &suns, &dni, 1).unwrap();
+        assert_eq!(res.size(), (1, 6));
+        let expected =
+            |dni: Float, a: Float| dni * 0.9 * (a * PI / 180.).cos() / (2. as Float).sqrt();
+        assert_close!(res.get(0, 0).unwrap(), expected(800., 0.), 1e-3);
+        assert_close!(res.get(0, 1).unwrap(), expected(800., 5.), 1e-3);
+        assert_close!(res.get(0, 2).unwrap(), expected(500., 10.), 1e-3);
+        for col in 3..6 {
+            assert_eq!(res.get(0, col).unwrap(), 0.0);
+        }
+
+        // Facing away from the mirror
+        let away = Ray3D {
+            direction: Vector3D::new(0., -1., 0.),
+            ..sensor
+        };
+        let res = specular_sun_irradiance(&[away], &scene, &reflectors, &suns, &dni, 1).unwrap();
+        assert_eq!(res.get(0, 0).unwrap(), 0.0);
+
+        // An obstruction in front of the mirror blocks the reflection
+        // arriving straight at the sensor, but not the one arriving from the side
+        let (scene, reflectors) = load(&[
+            "./tests/specular/tilted_mirror.rad",
+            "./tests/specular/blocker.rad",
+        ]);
+        assert_eq!(reflectors.len(), 1);
+        let res = specular_sun_irradiance(&[sensor], &scene, &reflectors, &suns, &dni, 1).unwrap();
+        assert_eq!(res.get(0, 0).unwrap(), 0.0);
+        assert_close!(res.get(0, 1).unwrap(), expected(800., 5.), 1e-3);
+
+        assert!(
+            specular_sun_irradiance(&[sensor], &scene, &reflectors, &suns, &dni[1..], 1).is_err()
+        );
+    }
+
+    #[test]
+    fn test_tilted_glass() {
+        let (scene, reflectors) = load(&["./tests/specular/tilted_glass.rad"]);
+        let glass = SpecularMaterial::Glass {
+            refraction_index: 1.5,
+        };
+        assert_eq!(reflectors[0].material, glass);
+        let sensor = Ray3D {
+            origin: Point3D::new(0., 0., 2.),
+            direction: Vector3D::new(0., 1., 0.),
+        };
+        // The sun at the zenith arrives at 45 degrees
+        let cos = (0.5 as Float).sqrt();
+        let res = specular_sun_irradiance(
+            &[sensor],
+            &scene,
+            &reflectors,
+            &[tilted_sun(0.)],
+            &[1000.],
+            1,
+        )
+        .unwrap();
+        assert_close!(res.get(0, 0).unwrap(), 1000. * glass.reflectance(cos), 1e-3);
+    }
+
+    #[test]
+    fn test_periscope() {
+        let (scene, reflectors) = load(&["./tests/specular/periscope.rad"]);
+        assert_eq!(reflectors.len(), 2);
+        let sensor = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 1., 0.),
+        };
+        // The sun 10 degrees high, in +Y, is seen through both mirrors
+        // at that same altitude
+        let alt = 10. * PI / 180.;
+        let suns = [Some(Vector3D::new(0., alt.cos(), alt.sin()))];
+        let one = specular_sun_irradiance(&[sensor], &scene, &reflectors, &suns, &[1.], 1).unwrap();
+        assert_eq!(one.get(0, 0).unwrap(), 0.0);
+        for bounces in [2, 3] {
+            let res =
+                specular_sun_irradiance(&[sensor], &scene, &reflectors, &suns, &[1.], bounces)
+                    .unwrap();
+            assert_close!(res.get(0, 0).unwrap(), 0.9 * 0.8 * alt.cos(), 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_chains() {
+        assert_eq!(reflector_chains(3, 0).len(), 0);
+        assert_eq!(reflector_chains(3, 1).len(), 3);
+        // 3 + 3 * 2
+        let chains = reflector_chains(3, 2);
+        assert_eq!(chains.len(), 9);
+        assert!(chains.iter().all(|c| c.windows(2).all(|w| w[0] != w[1])));
+    }
+}
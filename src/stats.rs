@@ -0,0 +1,477 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::events::{EventKind, EventLog};
+use crate::sensor_id::SensorId;
+use crate::session::DCSession;
+use crate::Float;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// Statistics gathered while calculating a Daylight Coefficient matrix,
+/// with one element per sensor (i.e., per row).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DCStats {
+    /// The number of samples sent from each sensor
+    pub n_samples: Vec<usize>,
+
+    /// The id of each sensor (see [`SensorSpec::sensor_id`](crate::SensorSpec::sensor_id))
+    #[serde(default)]
+    pub ids: Vec<SensorId>,
+
+    /// The standard error of the sum of the coefficients of each sensor
+    pub standard_errors: Vec<Float>,
+
+    /// The standard error of each coefficient, if requested. It has the
+    /// same shape as the Daylight Coefficient matrix.
+    pub bin_standard_errors: Option<Matrix>,
+
+    /// The energy that a [`SampleClamp`] removed from each sensor (i.e., how
+    /// much the sum of its coefficients went down). All zeros without a clamp.
+    #[serde(default)]
+    pub clamped: Vec<Float>,
+
+    /// The most energy that the samples dropped for grazing the plane of their
+    /// sensor could have brought to it (see [`DCOptions::min_cosine`](crate::DCOptions::min_cosine)),
+    /// i.e., what they would have added to the sum of its coefficients had
+    /// they all reached the sky. All zeros when the bounces are traced.
+    #[serde(default)]
+    pub grazing: Vec<Float>,
+
+    /// The number of samples of each sensor that were skipped because their
+    /// direction kept coming out broken (see [`DCOptions::max_resamples`](crate::DCOptions::max_resamples)).
+    /// All zeros when the bounces are traced.
+    #[serde(default)]
+    pub skipped: Vec<usize>,
+
+    /// Whether the [`RayBudget`](crate::RayBudget) ran out, so some sensors got
+    /// fewer samples than requested (see `n_samples`) and are noisier
+    #[serde(default)]
+    pub ray_cap_hit: bool,
+
+    /// The samples that each run of the `DCFactory` sent from every sensor, in
+    /// order (see [`DCOptions::max_branching`](crate::DCOptions::max_branching)).
+    /// Empty when the bounces are not traced.
+    #[serde(default)]
+    pub factory_runs: Vec<usize>,
+}
+
+impl DCStats {
+    /// The most rays that any of the `factory_runs` could send from a bounce,
+    /// which happens when a path keeps all its weight. `None` when the bounces
+    /// are not traced.
+    pub fn max_branching(&self) -> Option<usize> {
+        self.factory_runs
+            .iter()
+            .map(|n| DCSession::factory_branching(*n))
+            .max()
+    }
+}
+
+/// Running mean and variance of a series of values, using
+/// Welford's algorithm, which is numerically stable.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Welford {
+    n: usize,
+    mean: Float,
+    m2: Float,
+}
+
+impl Welford {
+    /// Creates an empty accumulator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value
+    pub fn push(&mut self, x: Float) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as Float;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// Adds all the values accumulated by `other` (Chan et al.'s
+    /// parallel algorithm)
+    pub fn merge(&mut self, other: &Self) {
+        if other.n == 0 {
+            return;
+        }
+        if self.n == 0 {
+            *self = *other;
+            return;
+        }
+        let n = self.n + other.n;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.n as Float / n as Float;
+        self.m2 += other.m2 + delta * delta * (self.n as Float * other.n as Float) / n as Float;
+        self.n = n;
+    }
+
+    /// An accumulator of `n` values with a certain `mean` and (unbiased)
+    /// `variance`, which is taken as `0` if it is not finite
+    pub(crate) fn from_moments(n: usize, mean: Float, variance: Float) -> Self {
+        let m2 = if variance.is_finite() && n > 1 {
+            variance * (n - 1) as Float
+        } else {
+            0.0
+        };
+        Self { n, mean, m2 }
+    }
+
+    /// The number of values
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    /// The mean of the values
+    pub fn mean(&self) -> Float {
+        self.mean
+    }
+
+    /// The (unbiased) sample variance. It is infinite with fewer
+    /// than two values.
+    pub fn variance(&self) -> Float {
+        if self.n < 2 {
+            return Float::INFINITY;
+        }
+        self.m2 / (self.n - 1) as Float
+    }
+
+    /// The standard error of the mean
+    pub fn standard_error(&self) -> Float {
+        (self.variance() / self.n as Float).sqrt()
+    }
+}
+
+/// The number of samples each sensor sends before a [`SampleClamp::Relative`]
+/// starts clamping, so that the running mean means something
+pub const CLAMP_WARMUP: usize = 64;
+
+/// Limits the contribution of a single sample to a single bin, which
+/// suppresses "fireflies": the rare samples with a tiny probability (and thus
+/// a huge weight) that make some coefficients wildly overestimated.
+///
+/// This biases the results down by whatever it removes, which is reported
+/// in [`DCStats::clamped`] so that it can be judged.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SampleClamp {
+    /// The largest weight of a sample (i.e., its contribution to the sum of
+    /// the coefficients, times the number of samples)
+    Absolute(Float),
+
+    /// The largest weight of a sample, as a multiple of the mean weight of
+    /// the (clamped) samples sent so far from the same sensor. The first
+    /// [`CLAMP_WARMUP`] samples are not clamped.
+    Relative(Float),
+}
+
+impl SampleClamp {
+    /// The limit, in the units of the variant
+    pub fn limit(&self) -> Float {
+        match self {
+            Self::Absolute(v) | Self::Relative(v) => *v,
+        }
+    }
+
+    /// Checks that the limit is a positive number
+    pub fn validate(&self) -> Result<(), String> {
+        let v = self.limit();
+        if !v.is_finite() || v <= 0.0 {
+            return Err(format!(
+                "Samples must be clamped to a positive number, but found {}",
+                v
+            ));
+        }
+        Ok(())
+    }
+
+    /// Clamps the `weight` of a sample, given the statistics of the
+    /// previous samples of the same sensor
+    pub(crate) fn clamp(&self, weight: Float, previous: &Welford) -> Float {
+        let max = match self {
+            Self::Absolute(v) => *v,
+            Self::Relative(k) => {
+                if previous.n() < CLAMP_WARMUP {
+                    return weight;
+                }
+                k * previous.mean()
+            }
+        };
+        weight.min(max)
+    }
+}
+
+/// What happens to the energy of the coefficients removed by a [`NoiseFloor`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CullPolicy {
+    /// The energy is lost, which biases the results down by (at most)
+    /// the culled fraction
+    #[default]
+    Drop,
+
+    /// The energy is shared among the coefficients that remain in the same
+    /// row, in proportion to their values, so the sum of each row is kept.
+    /// Rows in which nothing remains lose their energy.
+    Redistribute,
+}
+
+/// Removes the coefficients that are mostly noise: those whose value is
+/// below `k` times their own standard error. These are typically bins hit by
+/// one or two samples, which add little (but noisy) energy and make sparse
+/// matrices much denser than they need to be. A coefficient hit by `m` samples
+/// is about `√m` standard errors away from zero.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NoiseFloor {
+    /// The number of standard errors that a coefficient must reach to be kept
+    pub k: Float,
+
+    /// What to do with the energy of the coefficients that are removed
+    #[serde(default)]
+    pub policy: CullPolicy,
+}
+
+/// What a [`NoiseFloor`] removed from a row of a Daylight Coefficient matrix
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CulledRow {
+    /// The number of coefficients that were set to zero
+    pub n_culled: usize,
+
+    /// The sum of the coefficients that were set to zero
+    pub culled_energy: Float,
+
+    /// The sum of the coefficients of the row, before culling
+    pub total_energy: Float,
+}
+
+impl CulledRow {
+    /// The fraction of the energy of the row that was culled
+    pub fn culled_fraction(&self) -> Float {
+        if self.total_energy > 0.0 {
+            self.culled_energy / self.total_energy
+        } else {
+            0.0
+        }
+    }
+}
+
+impl NoiseFloor {
+    /// A noise floor of `k` standard errors that drops the culled energy
+    pub fn new(k: Float) -> Self {
+        Self {
+            k,
+            policy: CullPolicy::Drop,
+        }
+    }
+
+    /// Checks that `k` makes sense
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.k.is_finite() || self.k < 0.0 {
+            return Err(format!(
+                "The noise floor must be a non-negative number of standard errors, but found {}",
+                self.k
+            ));
+        }
+        Ok(())
+    }
+
+    /// Culls a row of coefficients, given the standard errors of each of them
+    pub(crate) fn cull(&self, values: &mut [Float], errors: &[Float]) -> CulledRow {
+        let mut ret = CulledRow {
+            total_energy: values.iter().sum(),
+            ..CulledRow::default()
+        };
+        for (v, se) in values.iter_mut().zip(errors.iter()) {
+            if *v != 0.0 && v.abs() < self.k * se {
+                ret.n_culled += 1;
+                ret.culled_energy += *v;
+                *v = 0.0;
+            }
+        }
+        let kept = ret.total_energy - ret.culled_energy;
+        if self.policy == CullPolicy::Redistribute && kept > 0.0 {
+            let factor = ret.total_energy / kept;
+            values.iter_mut().for_each(|v| *v *= factor);
+        }
+        ret
+    }
+
+    /// Culls a Daylight Coefficient matrix, given the standard error of each of
+    /// its coefficients (e.g., [`DCStats::bin_standard_errors`]). Returns what
+    /// was removed from each row.
+    pub fn apply(&self, dc: &mut Matrix, errors: &Matrix) -> Result<Vec<CulledRow>, String> {
+        self.validate()?;
+        let (nrows, ncols) = dc.size();
+        if errors.size() != (nrows, ncols) {
+            return Err(format!(
+                "The standard errors have {:?} elements, but the matrix has {:?}",
+                errors.size(),
+                (nrows, ncols)
+            ));
+        }
+        let mut ret = Vec::with_capacity(nrows);
+        let mut values = vec![0.0; ncols];
+        let mut row_errors = vec![0.0; ncols];
+        for r in 0..nrows {
+            for c in 0..ncols {
+                values[c] = dc.get(r, c)?;
+                row_errors[c] = errors.get(r, c)?;
+            }
+            ret.push(self.cull(&mut values, &row_errors));
+            for (c, v) in values.iter().enumerate() {
+                dc.set(r, c, *v)?;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Records an [`EventKind::NoiseCulled`] for the sensor with index `index`, if
+/// the `floor` removed anything from its row
+pub(crate) fn report_culled(
+    events: &mut EventLog,
+    index: usize,
+    floor: &NoiseFloor,
+    row: &CulledRow,
+) {
+    let fate = match floor.policy {
+        CullPolicy::Drop => "dropped",
+        CullPolicy::Redistribute => "redistributed",
+    };
+    events.push(
+        EventKind::NoiseCulled,
+        Some(index),
+        None,
+        row.n_culled,
+        format!(
+            "coefficients below {} standard errors were culled, and their energy ({}% of the total) was {}",
+            floor.k,
+            100. * row.culled_fraction(),
+            fate
+        ),
+    );
+}
+
+/// The standard error of a mean of `n` samples, given the mean of the
+/// samples and the mean of their squares
+pub(crate) fn standard_error_from_moments(mean: Float, mean_sq: Float, n: usize) -> Float {
+    if n < 2 {
+        return Float::INFINITY;
+    }
+    let n = n as Float;
+    let variance = ((mean_sq - mean * mean) * n / (n - 1.)).max(0.0);
+    (variance / n).sqrt()
+}
+
+/// Merges a batch of `n_batch` samples, whose mean is `batch_mean`, into
+/// the mean `mean` of `n` samples.
+pub(crate) fn merge_means(mean: &mut [Float], n: usize, batch_mean: &[Float], n_batch: usize) {
+    let total = (n + n_batch) as Float;
+    if total == 0.0 {
+        return;
+    }
+    let f = n_batch as Float / total;
+    for (m, b) in mean.iter_mut().zip(batch_mean.iter()) {
+        *m += (b - *m) * f;
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    #[test]
+    fn test_welford() {
+        let values = [2., 4., 4., 4., 5., 5., 7., 9.];
+        let mut w = Welford::new();
+        assert!(w.variance().is_infinite());
+        for v in values {
+            w.push(v);
+        }
+        assert_eq!(w.n(), 8);
+        assert_close!(w.mean(), 5., 1e-6);
+        assert_close!(w.variance(), 32. / 7., 1e-5);
+
+        // Merging two halves gives the same
+        let mut a = Welford::new();
+        let mut b = Welford::new();
+        values[..3].iter().for_each(|v| a.push(*v));
+        values[3..].iter().for_each(|v| b.push(*v));
+        a.merge(&b);
+        assert_eq!(a.n(), 8);
+        assert_close!(a.mean(), w.mean(), 1e-6);
+        assert_close!(a.variance(), w.variance(), 1e-5);
+    }
+
+    #[test]
+    fn test_standard_error_from_moments() {
+        let values = [2., 4., 4., 4., 5., 5., 7., 9.];
+        let mut w = Welford::new();
+        values.iter().for_each(|v| w.push(*v));
+        let mean = values.iter().sum::<Float>() / 8.;
+        let mean_sq = values.iter().map(|v| v * v).sum::<Float>() / 8.;
+        assert_close!(
+            standard_error_from_moments(mean, mean_sq, 8),
+            w.standard_error(),
+            1e-5
+        );
+        assert!(standard_error_from_moments(1., 1., 1).is_infinite());
+    }
+
+    #[test]
+    fn test_noise_floor() {
+        let errors = [1., 1., 1., 0.1];
+        let mut values = [3., 1.5, 0., 0.5];
+        let culled = NoiseFloor::new(2.).cull(&mut values, &errors);
+        assert_eq!(values, [3., 0., 0., 0.5]);
+        assert_eq!(culled.n_culled, 1);
+        assert_close!(culled.culled_fraction(), 0.3, 1e-6);
+
+        let redistribute = NoiseFloor {
+            k: 2.,
+            policy: CullPolicy::Redistribute,
+        };
+        let mut values = [3., 1.5, 0., 0.5];
+        redistribute.cull(&mut values, &errors);
+        assert_close!(values[0], 3. * 5. / 3.5, 1e-6);
+        assert_close!(values.iter().sum::<Float>(), 5., 1e-6);
+
+        // Nothing is left to take the energy
+        let mut values = [1., 1.];
+        let culled = redistribute.cull(&mut values, &errors);
+        assert_eq!(values, [0., 0.]);
+        assert_close!(culled.culled_fraction(), 1., 1e-6);
+
+        assert!(NoiseFloor::new(Float::NAN).validate().is_err());
+        let mut dc = Matrix::new(1.0, 2, 3);
+        assert!(NoiseFloor::new(1.)
+            .apply(&mut dc, &Matrix::new(0.1, 2, 2))
+            .is_err());
+    }
+
+    #[test]
+    fn test_merge_means() {
+        let mut mean = vec![1., 2.];
+        merge_means(&mut mean, 2, &[4., 2.], 1);
+        assert_close!(mean[0], 2., 1e-6);
+        assert_close!(mean[1], 2., 1e-6);
+    }
+}
@@ -0,0 +1,645 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Annual results summarised while they are calculated, one timestep at a time,
+//! so that grids too large for their annual series (one value per sensor and
+//! timestep) can still get monthly totals and metrics.
+//!
+//! An [`AnnualStream`] applies one sky vector at a time to a Daylight
+//! Coefficient matrix, and hands the value of every sensor at that timestep to a
+//! set of [`AnnualAccumulator`]s, which keep whatever they need. Only the
+//! coefficients, the values of the current timestep and the accumulators are
+//! kept in memory. New metrics plug in by implementing the trait; this module
+//! provides totals over periods of the year ([`PeriodSums`]), the Daylight
+//! Autonomy and Useful Daylight Illuminance of each sensor ([`MetricCounters`])
+//! and the peak of each sensor ([`PeakTracker`]).
+//!
+//! The results are the same as calculating the whole series (e.g., through
+//! [`apply_annual`](crate::apply_annual)) and summarising it afterwards, but for
+//! rounding.
+
+use crate::annual::to_row_major;
+use crate::report::{OccupancySchedule, ReportThresholds, SensorMetrics};
+use crate::sensor_id::SensorId;
+use crate::time::{DateRange, TimeAxis, Timestep, DAYS_PER_MONTH, DAYS_PER_YEAR};
+use crate::Float;
+use matrix::Matrix;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Something that summarises an annual result as it is calculated
+pub trait AnnualAccumulator {
+    /// Takes the `values` of every sensor at a `step`, whose occupancy (between `0`
+    /// and `1`) is `occupancy`. Timesteps come in the order in which they are
+    /// calculated.
+    fn push(&mut self, step: &Timestep, occupancy: Float, values: &[Float]) -> Result<(), String>;
+}
+
+/// Checks that an accumulator of `n_sensors` sensors is given the values of as many
+fn check_sensors(n_sensors: usize, values: &[Float]) -> Result<(), String> {
+    if values.len() != n_sensors {
+        return Err(format!(
+            "The accumulator has {} sensors, but {} values were given",
+            n_sensors,
+            values.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Applies sky vectors to a Daylight Coefficient matrix one timestep at a time
+/// (see the module documentation)
+pub struct AnnualStream {
+    /// The coefficients, in row-major order
+    dc: Vec<Float>,
+    /// Whether each sensor has coefficients that are not finite, which make
+    /// all of its values `NaN` (even under dark skies)
+    broken: Vec<bool>,
+    n_sensors: usize,
+    n_bins: usize,
+    /// The non-zero patches of the current sky, as `(bin, value)`
+    patches: Vec<(usize, Float)>,
+    /// The value of each sensor at the current timestep
+    values: Vec<Float>,
+    n_timesteps: usize,
+}
+
+impl AnnualStream {
+    /// Prepares the coefficients of a Daylight Coefficient matrix, with one row
+    /// per sensor and one column per bin
+    pub fn new(dc: &Matrix) -> Result<Self, String> {
+        let (n_sensors, n_bins) = dc.size();
+        let dc = to_row_major(dc)?;
+        let broken = (0..n_sensors)
+            .map(|r| {
+                dc[r * n_bins..(r + 1) * n_bins]
+                    .iter()
+                    .any(|v| !v.is_finite())
+            })
+            .collect();
+        Ok(Self {
+            dc,
+            broken,
+            n_sensors,
+            n_bins,
+            patches: Vec::with_capacity(n_bins),
+            values: vec![0.0; n_sensors],
+            n_timesteps: 0,
+        })
+    }
+
+    /// The number of sensors
+    pub fn n_sensors(&self) -> usize {
+        self.n_sensors
+    }
+
+    /// The number of timesteps pushed so far
+    pub fn n_timesteps(&self) -> usize {
+        self.n_timesteps
+    }
+
+    /// Applies the sky vector of a `step` (a matrix with one row per bin and a
+    /// single column, such as those of [`perez_sky_vec`](crate::perez_sky_vec)),
+    /// whose occupancy is `occupancy`, and hands the result to the `accumulators`.
+    /// Returns the value of each sensor at that timestep.
+    pub fn push(
+        &mut self,
+        step: &Timestep,
+        sky: &Matrix,
+        occupancy: Float,
+        accumulators: &mut [&mut dyn AnnualAccumulator],
+    ) -> Result<&[Float], String> {
+        if sky.size() != (self.n_bins, 1) {
+            return Err(format!(
+                "The Daylight Coefficient matrix has {} bins, so sky vectors must be {}x1... found {:?}",
+                self.n_bins,
+                self.n_bins,
+                sky.size()
+            ));
+        }
+        self.push_column(sky, 0, step, occupancy, accumulators)
+    }
+
+    /// Applies column `column` of `skies` (see [`AnnualStream::push`])
+    fn push_column(
+        &mut self,
+        skies: &Matrix,
+        column: usize,
+        step: &Timestep,
+        occupancy: Float,
+        accumulators: &mut [&mut dyn AnnualAccumulator],
+    ) -> Result<&[Float], String> {
+        if !(0.0..=1.).contains(&occupancy) {
+            return Err(format!(
+                "Occupancy must be between 0 and 1... found {}",
+                occupancy
+            ));
+        }
+        self.patches.clear();
+        for bin in 0..self.n_bins {
+            let v = skies.get(bin, column)?;
+            if v != 0.0 {
+                self.patches.push((bin, v));
+            }
+        }
+        let (dc, patches, n_bins) = (&self.dc, &self.patches, self.n_bins);
+        let broken = &self.broken;
+        let apply = |(r, v): (usize, &mut Float)| {
+            if broken[r] {
+                *v = Float::NAN;
+                return;
+            }
+            let row = &dc[r * n_bins..(r + 1) * n_bins];
+            *v = patches.iter().map(|(bin, s)| row[*bin] * s).sum();
+        };
+        #[cfg(feature = "parallel")]
+        self.values.par_iter_mut().enumerate().for_each(apply);
+        #[cfg(not(feature = "parallel"))]
+        self.values.iter_mut().enumerate().for_each(apply);
+
+        for accumulator in accumulators.iter_mut() {
+            accumulator.push(step, occupancy, &self.values)?;
+        }
+        self.n_timesteps += 1;
+        Ok(&self.values)
+    }
+}
+
+/// Applies the `skies`—one column per timestep of an `axis`—to a Daylight
+/// Coefficient matrix one timestep at a time, handing the results to the
+/// `accumulators` (see [`AnnualStream`]). Timesteps are weighted by the part of
+/// the `schedule` that covers the `axis` (see [`OccupancySchedule::on_axis`]);
+/// without one, they are all fully occupied.
+///
+/// The sky matrix is much smaller than the annual series of large grids, so it
+/// is taken whole. Skies that come one by one (e.g., from a weather file) can be
+/// pushed into an [`AnnualStream`] directly.
+pub fn stream_annual(
+    dc: &Matrix,
+    skies: &Matrix,
+    axis: &TimeAxis,
+    schedule: Option<&OccupancySchedule>,
+    accumulators: &mut [&mut dyn AnnualAccumulator],
+) -> Result<(), String> {
+    axis.check_columns(skies)?;
+    let (_, n_bins) = dc.size();
+    let (sky_rows, _) = skies.size();
+    if sky_rows != n_bins {
+        return Err(format!(
+            "Daylight Coefficient matrix has {} bins but the sky matrix has {}",
+            n_bins, sky_rows
+        ));
+    }
+    let occupancy = match schedule {
+        Some(schedule) => schedule.on_axis(axis)?.weights,
+        None => vec![1.0; axis.n_timesteps],
+    };
+    let mut stream = AnnualStream::new(dc)?;
+    for (column, w) in occupancy.iter().enumerate() {
+        let step = axis.timestep(column);
+        stream.push_column(skies, column, &step, *w, accumulators)?;
+    }
+    Ok(())
+}
+
+/// The totals of each sensor over some periods of the year: the sum of its values
+/// times the length (in hours) of each timestep, whether it is occupied or not.
+/// Values in W/m² give Wh/m², and values in lux give lux-hours. Timesteps belong
+/// to the day of their centre.
+#[derive(Debug, Clone)]
+pub struct PeriodSums {
+    /// The periods
+    pub periods: Vec<DateRange>,
+    /// The periods to which each day of the year belongs
+    days: Vec<Vec<usize>>,
+    n_sensors: usize,
+    /// One row per sensor and one column per period, in row-major order
+    sums: Vec<Float>,
+}
+
+impl PeriodSums {
+    /// Totals of `n_sensors` sensors over some `periods`, which may overlap (e.g.,
+    /// the whole year and a winter going from December to February)
+    pub fn new(n_sensors: usize, periods: Vec<DateRange>) -> Result<Self, String> {
+        let mut days = vec![Vec::new(); DAYS_PER_YEAR];
+        for (i, period) in periods.iter().enumerate() {
+            for (day, _) in period.days()? {
+                days[day].push(i);
+            }
+        }
+        let n_periods = periods.len();
+        Ok(Self {
+            periods,
+            days,
+            n_sensors,
+            sums: vec![0.0; n_sensors * n_periods],
+        })
+    }
+
+    /// Totals of `n_sensors` sensors over each month, from January
+    pub fn monthly(n_sensors: usize) -> Self {
+        let periods = DAYS_PER_MONTH
+            .iter()
+            .enumerate()
+            .map(|(m, days)| DateRange {
+                start: (m as u8 + 1, 1),
+                end: (m as u8 + 1, *days as u8),
+            })
+            .collect();
+        // The months are valid dates
+        Self::new(n_sensors, periods).unwrap()
+    }
+
+    /// The totals, with one row per sensor and one column per period
+    pub fn sums(&self) -> Result<Matrix, String> {
+        let n_periods = self.periods.len();
+        let mut ret = Matrix::new(0.0, self.n_sensors, n_periods);
+        for (i, v) in self.sums.iter().enumerate() {
+            ret.set(i / n_periods, i % n_periods, *v)?;
+        }
+        Ok(ret)
+    }
+}
+
+impl AnnualAccumulator for PeriodSums {
+    fn push(&mut self, step: &Timestep, _: Float, values: &[Float]) -> Result<(), String> {
+        check_sensors(self.n_sensors, values)?;
+        let n_periods = self.periods.len();
+        let hours = step.hours();
+        for p in &self.days[step.centre().day_of_year()] {
+            for (r, v) in values.iter().enumerate() {
+                self.sums[r * n_periods + p] += v * hours;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The running counts of a sensor, weighted by occupancy
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    da: Float,
+    cda: Float,
+    short: Float,
+    achieved: Float,
+    exceeded: Float,
+    valid: bool,
+}
+
+/// The Daylight Autonomy and Useful Daylight Illuminance of each sensor, counted
+/// as in [`AnnualReport`](crate::AnnualReport) (i.e., weighted by occupancy, and
+/// `NaN` for sensors with non-finite values)
+#[derive(Debug, Clone)]
+pub struct MetricCounters {
+    /// The thresholds used
+    pub thresholds: ReportThresholds,
+    counts: Vec<Counts>,
+    /// The total occupancy so far
+    occupied: Float,
+    /// The id of each sensor, if given
+    ids: Option<Vec<SensorId>>,
+}
+
+impl MetricCounters {
+    /// Counters for `n_sensors` sensors. Only the thresholds of the Daylight
+    /// Autonomy and the Useful Daylight Illuminance are used.
+    pub fn new(n_sensors: usize, thresholds: &ReportThresholds) -> Self {
+        let counts = Counts {
+            valid: true,
+            ..Counts::default()
+        };
+        Self {
+            thresholds: thresholds.clone(),
+            counts: vec![counts; n_sensors],
+            occupied: 0.0,
+            ids: None,
+        }
+    }
+
+    /// Attaches the id of each sensor to its metrics
+    pub fn with_ids(mut self, ids: Vec<SensorId>) -> Result<Self, String> {
+        if ids.len() != self.counts.len() {
+            return Err(format!(
+                "There are {} sensors, but {} ids",
+                self.counts.len(),
+                ids.len()
+            ));
+        }
+        self.ids = Some(ids);
+        Ok(self)
+    }
+
+    /// The metrics of each sensor, without `direct_sun_hours`. Fails if no
+    /// occupied timestep was pushed.
+    pub fn metrics(&self) -> Result<Vec<SensorMetrics>, String> {
+        if self.occupied <= 0.0 {
+            return Err("Cannot count metrics without occupied timesteps".to_string());
+        }
+        let total = self.occupied;
+        Ok(self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let of = |v: Float| if c.valid { v / total } else { Float::NAN };
+                SensorMetrics {
+                    daylight_autonomy: of(c.da),
+                    continuous_daylight_autonomy: of(c.cda),
+                    udi_fell_short: of(c.short),
+                    udi_achieved: of(c.achieved),
+                    udi_exceeded: of(c.exceeded),
+                    direct_sun_hours: None,
+                    id: self.ids.as_ref().map(|ids| ids[i].clone()),
+                }
+            })
+            .collect())
+    }
+}
+
+impl AnnualAccumulator for MetricCounters {
+    fn push(&mut self, _: &Timestep, occupancy: Float, values: &[Float]) -> Result<(), String> {
+        check_sensors(self.counts.len(), values)?;
+        let t = &self.thresholds;
+        for (c, e) in self.counts.iter_mut().zip(values) {
+            if !e.is_finite() {
+                c.valid = false;
+            }
+            if !c.valid || occupancy <= 0.0 {
+                continue;
+            }
+            let w = occupancy;
+            if *e >= t.daylight_autonomy {
+                c.da += w;
+            }
+            c.cda += w * (e / t.daylight_autonomy).clamp(0.0, 1.0);
+            if *e < t.udi_lower {
+                c.short += w;
+            } else if *e > t.udi_upper {
+                c.exceeded += w;
+            } else {
+                c.achieved += w;
+            }
+        }
+        self.occupied += occupancy;
+        Ok(())
+    }
+}
+
+/// The largest value of each sensor and the (first) timestep at which it
+/// happened, whether occupied or not. Values that are not finite are left out.
+#[derive(Debug, Clone)]
+pub struct PeakTracker {
+    /// The peak of each sensor, if it had any finite value
+    pub peaks: Vec<Option<(Float, Timestep)>>,
+}
+
+impl PeakTracker {
+    /// A tracker of `n_sensors` sensors
+    pub fn new(n_sensors: usize) -> Self {
+        Self {
+            peaks: vec![None; n_sensors],
+        }
+    }
+}
+
+impl AnnualAccumulator for PeakTracker {
+    fn push(&mut self, step: &Timestep, _: Float, values: &[Float]) -> Result<(), String> {
+        check_sensors(self.peaks.len(), values)?;
+        for (peak, v) in self.peaks.iter_mut().zip(values) {
+            if !v.is_finite() {
+                continue;
+            }
+            match peak {
+                Some((max, _)) if *max >= *v => {}
+                _ => *peak = Some((*v, *step)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::labeled_matrix::RowMetadata;
+    use crate::test_support::{peak_memory_of, pseudo_random, report_peak_memory, MEMORY_PROBE};
+    use crate::{apply_annual, AnnualReport};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    /// Hourly skies of a whole year, dark at night and brighter in summer
+    fn skies(n_bins: usize) -> Matrix {
+        let axis = TimeAxis::annual(1).unwrap();
+        let mut skies = pseudo_random(n_bins, axis.n_timesteps, 2);
+        for (c, step) in axis.timesteps().iter().enumerate() {
+            let hour = step.start().hour_of_year % 24.;
+            let season = 1. + (step.month() as Float - 1.).min(12. - step.month() as Float);
+            for bin in 0..n_bins {
+                let sun = (crate::PI * (hour - 6.) / 12.).sin().max(0.0);
+                let v = 60. * season * sun * skies.get(bin, c).unwrap();
+                skies.set(bin, c, v).unwrap();
+            }
+        }
+        skies
+    }
+
+    #[test]
+    fn test_matches_full_series() {
+        let (n_sensors, n_bins) = (6, 11);
+        let mut dc = pseudo_random(n_sensors, n_bins, 1);
+        dc.set(5, 3, Float::NAN).unwrap();
+        let skies = skies(n_bins);
+        let axis = TimeAxis::annual(1).unwrap();
+        let schedule = OccupancySchedule::office_hours(axis.n_timesteps, 1, 8., 17., None).unwrap();
+        let thresholds = ReportThresholds::default();
+        let winter = DateRange::new((12, 1), (2, 28)).unwrap();
+        let ids: Vec<SensorId> = (0..n_sensors)
+            .map(|i| SensorId::new(format!("sensor {}", i)).unwrap())
+            .collect();
+
+        let mut monthly = PeriodSums::monthly(n_sensors);
+        let mut seasons = PeriodSums::new(n_sensors, vec![winter, DateRange::year()]).unwrap();
+        let mut counters = MetricCounters::new(n_sensors, &thresholds)
+            .with_ids(ids.clone())
+            .unwrap();
+        let mut peaks = PeakTracker::new(n_sensors);
+        stream_annual(
+            &dc,
+            &skies,
+            &axis,
+            Some(&schedule),
+            &mut [&mut monthly, &mut seasons, &mut counters, &mut peaks],
+        )
+        .unwrap();
+
+        let full = apply_annual(&dc, &skies).unwrap();
+        let rows: Vec<RowMetadata> = (0..n_sensors)
+            .map(|i| RowMetadata {
+                ray: Ray3D {
+                    origin: Point3D::new(0., 0., 0.8),
+                    direction: Vector3D::new(0., 0., 1.),
+                },
+                mask: None,
+                zone: None,
+                n_samples: 1,
+                id: ids[i].clone(),
+                angular_response: None,
+            })
+            .collect();
+        let report = AnnualReport::new(&full, None, &rows, &schedule, &thresholds).unwrap();
+        assert_eq!(counters.metrics().unwrap().len(), n_sensors);
+        for (found, exp) in counters.metrics().unwrap().iter().zip(&report.sensors) {
+            assert_eq!(found.id, exp.id);
+            let pairs = [
+                (found.daylight_autonomy, exp.daylight_autonomy),
+                (
+                    found.continuous_daylight_autonomy,
+                    exp.continuous_daylight_autonomy,
+                ),
+                (found.udi_fell_short, exp.udi_fell_short),
+                (found.udi_achieved, exp.udi_achieved),
+                (found.udi_exceeded, exp.udi_exceeded),
+            ];
+            for (f, e) in pairs {
+                assert!(f.is_nan() == e.is_nan(), "{} vs {}", f, e);
+                if !e.is_nan() {
+                    assert_close!(f, e, 1e-6);
+                }
+            }
+        }
+        assert!(report.sensors[0].udi_achieved > 0.0 && report.sensors[0].udi_fell_short > 0.0);
+
+        let monthly = monthly.sums().unwrap();
+        let seasons = seasons.sums().unwrap();
+        assert_eq!(monthly.size(), (n_sensors, 12));
+        for r in 0..5 {
+            let mut expected = [0.0; 12];
+            let mut peak = (Float::NEG_INFINITY, 0);
+            for (c, step) in axis.timesteps().iter().enumerate() {
+                let v = full.get(r, c).unwrap();
+                expected[step.month() - 1] += v;
+                if v > peak.0 {
+                    peak = (v, c);
+                }
+            }
+            for (m, e) in expected.iter().enumerate() {
+                assert_close!(monthly.get(r, m).unwrap(), *e, 1e-4 * e);
+            }
+            let winter = expected[11] + expected[0] + expected[1];
+            assert_close!(seasons.get(r, 0).unwrap(), winter, 1e-4 * winter);
+            let year: Float = expected.iter().sum();
+            assert_close!(seasons.get(r, 1).unwrap(), year, 1e-4 * year);
+
+            let (max, step) = peaks.peaks[r].unwrap();
+            assert_close!(max, peak.0, 1e-4 * max);
+            assert_eq!(step, axis.timestep(peak.1));
+        }
+        assert!(monthly.get(5, 0).unwrap().is_nan());
+        assert!(peaks.peaks[5].is_none());
+    }
+
+    #[test]
+    fn test_stream() {
+        let dc = Matrix::new(1.0, 2, 3);
+        let mut stream = AnnualStream::new(&dc).unwrap();
+        let mut sums = PeriodSums::monthly(2);
+        let mut peaks = PeakTracker::new(2);
+        // Half-hourly: each timestep counts for half an hour
+        let axis = TimeAxis::new(2, 2 * 24 * 40, 4).unwrap();
+        for (i, step) in axis.timesteps().iter().enumerate() {
+            let sky = Matrix::new(i as Float, 3, 1);
+            let values = stream
+                .push(step, &sky, 1., &mut [&mut sums, &mut peaks])
+                .unwrap();
+            assert_close!(values[1], 3. * i as Float, 1e-9);
+        }
+        assert_eq!(stream.n_timesteps(), 4);
+        // The 10th of February
+        let sums = sums.sums().unwrap();
+        assert_close!(sums.get(0, 1).unwrap(), 9., 1e-9);
+        assert_close!(sums.get(1, 0).unwrap(), 0., 1e-9);
+        assert_eq!(peaks.peaks[0].unwrap().1, axis.timestep(3));
+
+        let step = axis.timestep(0);
+        let sky = Matrix::new(1.0, 3, 1);
+        assert!(stream
+            .push(&step, &Matrix::new(1.0, 4, 1), 1., &mut [])
+            .is_err());
+        assert!(stream.push(&step, &sky, 1.5, &mut []).is_err());
+        let mut wrong = PeakTracker::new(3);
+        assert!(stream.push(&step, &sky, 1., &mut [&mut wrong]).is_err());
+
+        // Nothing occupied, nothing counted
+        let mut counters = MetricCounters::new(2, &ReportThresholds::default());
+        stream.push(&step, &sky, 0., &mut [&mut counters]).unwrap();
+        assert!(counters.metrics().is_err());
+        assert!(stream_annual(&dc, &Matrix::new(1.0, 3, 5), &axis, None, &mut []).is_err());
+        assert!(PeriodSums::new(
+            2,
+            vec![DateRange {
+                start: (2, 30),
+                end: (3, 1)
+            }]
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_streaming_memory() {
+        const TEST: &str = "streaming::testing::bench_streaming_memory";
+        let n_bins = solar::ReinhartSky::n_bins(1);
+        let n_sensors = 50_000;
+        let axis = TimeAxis::annual(1).unwrap();
+        let probe = std::env::var(MEMORY_PROBE);
+        if let Ok(probe) = probe.as_deref() {
+            let dc = pseudo_random(n_sensors, n_bins, 1);
+            let skies = skies(n_bins);
+            let schedule =
+                OccupancySchedule::office_hours(axis.n_timesteps, 1, 8., 17., None).unwrap();
+            let mut monthly = PeriodSums::monthly(n_sensors);
+            let mut counters = MetricCounters::new(n_sensors, &ReportThresholds::default());
+            let mut peaks = PeakTracker::new(n_sensors);
+            if probe == "stream" {
+                stream_annual(
+                    &dc,
+                    &skies,
+                    &axis,
+                    Some(&schedule),
+                    &mut [&mut monthly, &mut counters, &mut peaks],
+                )
+                .unwrap();
+                assert_eq!(counters.metrics().unwrap().len(), n_sensors);
+            }
+            report_peak_memory().unwrap();
+            return;
+        }
+        let setup = peak_memory_of(TEST, "setup").unwrap();
+        let stream = peak_memory_of(TEST, "stream").unwrap();
+        let series_kb = n_sensors * axis.n_timesteps * std::mem::size_of::<Float>() / 1024;
+        assert!(
+            stream - setup < series_kb / 20,
+            "Streaming took {} kB, and the series would take {} kB",
+            stream - setup,
+            series_kb
+        );
+    }
+}
@@ -0,0 +1,387 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Parametric studies: the same sensors, under the same skies, traced against
+//! many variants of a design (e.g., façades with different windows).
+//!
+//! Every variant is run with the same [`DCSession`], one after the other.
+//! Sensors are spread over the threads of the session (see
+//! [`DCOptions::serial`](crate::DCOptions::serial)), so all the variants share
+//! the same pool. A variant that fails is recorded as such in the
+//! [`StudySummary`] (or in the [`BatchOutcome`] of [`StudyRunner::run_batch`]),
+//! and the rest carry on.
+
+use crate::annual::annual_irradiance;
+use crate::batch::{BatchError, BatchOutcome};
+use crate::matrix_io::save_mtx;
+use crate::report::{AnnualReport, OccupancySchedule, ReportThresholds};
+use crate::scene_builder::{check_name, SceneBuilder};
+use crate::scene_loading::load_scene;
+use crate::sensor::SensorSpec;
+use crate::session::DCSession;
+use crate::Float;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The scene of a variant of a study
+#[derive(Debug, Clone)]
+pub enum VariantScene {
+    /// A Radiance file, loaded as [`load_scene`] does
+    File(PathBuf),
+
+    /// A scene built programmatically
+    Builder(SceneBuilder),
+}
+
+/// The skies (and how to judge them) that every variant of a study is run under.
+/// Both sets of sky vectors have one row per bin of the session and one column
+/// per timestep.
+#[derive(Debug, Clone)]
+pub struct StudySky {
+    /// The sky vectors of luminance (in cd/m²), which give the illuminance (in
+    /// lux) used for the Daylight Autonomy
+    pub luminance: Matrix,
+
+    /// The sky vectors of radiance (in W/m²/sr), which give the annual
+    /// radiation. Without them, it is not calculated.
+    pub radiance: Option<Matrix>,
+
+    /// The occupancy of each timestep, which also sets their duration
+    pub schedule: OccupancySchedule,
+
+    /// The thresholds of the metrics
+    pub thresholds: ReportThresholds,
+}
+
+/// What happened to a variant of a study
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VariantStatus {
+    /// The variant was calculated, and its results written (if asked to)
+    Done,
+
+    /// The variant could not be calculated, for this reason
+    Failed(String),
+}
+
+/// The results of a variant of a study. Metrics are `None` if it failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VariantSummary {
+    /// The identifier of the variant
+    pub id: String,
+
+    /// Whether the variant was calculated
+    pub status: VariantStatus,
+
+    /// The Spatial Daylight Autonomy of all the sensors
+    pub spatial_daylight_autonomy: Option<Float>,
+
+    /// The average Daylight Autonomy of the (valid) sensors
+    pub mean_daylight_autonomy: Option<Float>,
+
+    /// The average radiation received by the sensors over all the
+    /// timesteps, in kWh/m², if the radiance of the skies was given
+    pub annual_radiation: Option<Float>,
+}
+
+/// The results of every variant of a study, in the order they were added
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StudySummary {
+    /// One element per variant
+    pub variants: Vec<VariantSummary>,
+}
+
+impl StudySummary {
+    /// The number of variants that failed
+    pub fn n_failed(&self) -> usize {
+        self.variants
+            .iter()
+            .filter(|v| v.status != VariantStatus::Done)
+            .count()
+    }
+
+    /// The variants from best to worst: by Spatial Daylight Autonomy, then by
+    /// mean Daylight Autonomy. Failed variants go last, in their order.
+    pub fn ranked(&self) -> Vec<&VariantSummary> {
+        let key = |v: &VariantSummary| -> (Float, Float) {
+            (
+                v.spatial_daylight_autonomy
+                    .filter(|x| x.is_finite())
+                    .unwrap_or(Float::NEG_INFINITY),
+                v.mean_daylight_autonomy
+                    .filter(|x| x.is_finite())
+                    .unwrap_or(Float::NEG_INFINITY),
+            )
+        };
+        let mut ret: Vec<&VariantSummary> = self.variants.iter().collect();
+        // Stable, so ties keep their order
+        ret.sort_by(|a, b| {
+            let (a, b) = (key(a), key(b));
+            b.0.total_cmp(&a.0).then(b.1.total_cmp(&a.1))
+        });
+        ret
+    }
+
+    /// Writes the summary as CSV, with one row per variant. Missing
+    /// metrics are left empty.
+    pub fn to_csv(&self) -> String {
+        let value = |v: Option<Float>| v.map_or(String::new(), |v| format!("{}", v));
+        let mut ret = "variant,status,sda,mean_da,annual_radiation,error\n".to_string();
+        for v in &self.variants {
+            let (status, error) = match &v.status {
+                VariantStatus::Done => ("done", String::new()),
+                VariantStatus::Failed(e) => ("failed", e.replace(['\n', ','], " ")),
+            };
+            ret.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                v.id,
+                status,
+                value(v.spatial_daylight_autonomy),
+                value(v.mean_daylight_autonomy),
+                value(v.annual_radiation),
+                error
+            ));
+        }
+        ret
+    }
+}
+
+/// Runs the same sensors and skies against many scenes (see the
+/// [module documentation](self))
+#[derive(Debug, Clone)]
+pub struct StudyRunner {
+    session: DCSession,
+    sensors: Vec<SensorSpec>,
+    sky: StudySky,
+    variants: Vec<(String, VariantScene)>,
+    output: Option<PathBuf>,
+}
+
+impl StudyRunner {
+    /// A study without variants
+    pub fn new(session: DCSession, sensors: Vec<SensorSpec>, sky: StudySky) -> Self {
+        Self {
+            session,
+            sensors,
+            sky,
+            variants: Vec::new(),
+            output: None,
+        }
+    }
+
+    /// Writes the results of each variant into `dir` (which is created if
+    /// needed): the Daylight Coefficients as `<id>.mtx`, the metrics of each
+    /// sensor as `<id>_metrics.csv`, and the summary as `summary.csv`.
+    pub fn with_output_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.output = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Adds a variant, failing if its `id` is repeated or cannot be
+    /// used as a file name
+    pub fn add_variant(&mut self, id: &str, scene: VariantScene) -> Result<(), String> {
+        check_name(id)?;
+        if id.contains(['/', '\\', ',']) {
+            return Err(format!(
+                "Variant identifiers cannot contain '/', '\\' or ',', but found '{}'",
+                id
+            ));
+        }
+        if self.variants.iter().any(|(other, _)| other == id) {
+            return Err(format!("Variant '{}' was already added", id));
+        }
+        self.variants.push((id.to_string(), scene));
+        Ok(())
+    }
+
+    /// Adds a variant whose scene is in a Radiance file
+    pub fn add_file<P: AsRef<Path>>(&mut self, id: &str, path: P) -> Result<(), String> {
+        self.add_variant(id, VariantScene::File(path.as_ref().to_path_buf()))
+    }
+
+    /// Adds a variant whose scene is built programmatically
+    pub fn add_builder(&mut self, id: &str, builder: SceneBuilder) -> Result<(), String> {
+        self.add_variant(id, VariantScene::Builder(builder))
+    }
+
+    /// The number of variants
+    pub fn len(&self) -> usize {
+        self.variants.len()
+    }
+
+    /// Whether there are no variants
+    pub fn is_empty(&self) -> bool {
+        self.variants.is_empty()
+    }
+
+    /// Checks what is shared by all the variants, as a mistake
+    /// there would make all of them fail
+    fn check(&self) -> Result<(), String> {
+        let basis = self.session.basis()?;
+        basis.check_sky(&self.sky.luminance)?;
+        let n_timesteps = self.sky.luminance.size().1;
+        if let Some(radiance) = &self.sky.radiance {
+            basis.check_sky(radiance)?;
+            if radiance.size().1 != n_timesteps {
+                return Err(format!(
+                    "There are {} skies of luminance, but {} of radiance",
+                    n_timesteps,
+                    radiance.size().1
+                ));
+            }
+        }
+        if self.sky.schedule.weights.len() != n_timesteps {
+            return Err(format!(
+                "There are {} skies, but the schedule has {} timesteps",
+                n_timesteps,
+                self.sky.schedule.weights.len()
+            ));
+        }
+        if let Some(dir) = &self.output {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                format!(
+                    "Unable to create output directory '{}': {}",
+                    dir.display(),
+                    e
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Runs every variant, in the order they were added. This only fails if
+    /// what they share is wrong (e.g., the skies do not match the session, or
+    /// the output directory cannot be created); the failures of each variant
+    /// are recorded in the summary.
+    pub fn run(&self) -> Result<StudySummary, String> {
+        self.run_batch().map(|outcome| self.summary(&outcome))
+    }
+
+    /// Like [`StudyRunner::run`], but returns the summary of each variant that
+    /// worked, and why each of the others failed
+    pub fn run_batch(&self) -> Result<BatchOutcome<VariantSummary>, String> {
+        self.check()?;
+        let mut outcome = BatchOutcome::default();
+        for (id, scene) in &self.variants {
+            let result = self
+                .run_variant(id, scene)
+                .map_err(|e| BatchError::new(id, e));
+            outcome.push(id, result);
+        }
+        if let Some(dir) = &self.output {
+            write_file(&dir.join("summary.csv"), &self.summary(&outcome).to_csv())?;
+        }
+        Ok(outcome)
+    }
+
+    /// Puts the variants of an outcome back in the order they were added
+    fn summary(&self, outcome: &BatchOutcome<VariantSummary>) -> StudySummary {
+        let variants = self
+            .variants
+            .iter()
+            .map(|(id, _)| match (outcome.get(id), outcome.error(id)) {
+                (Some(summary), _) => summary.clone(),
+                (None, error) => VariantSummary {
+                    id: id.clone(),
+                    status: VariantStatus::Failed(
+                        error.map_or(String::new(), |e| e.message.clone()),
+                    ),
+                    spatial_daylight_autonomy: None,
+                    mean_daylight_autonomy: None,
+                    annual_radiation: None,
+                },
+            })
+            .collect();
+        StudySummary { variants }
+    }
+
+    /// Calculates (and writes) the results of a variant
+    fn run_variant(&self, id: &str, scene: &VariantScene) -> Result<VariantSummary, String> {
+        let scene = match scene {
+            VariantScene::File(path) => {
+                let (mut scene, _) = load_scene(path)?;
+                scene.build_accelerator();
+                scene
+            }
+            VariantScene::Builder(builder) => builder.build()?.0,
+        };
+        let dc = self.session.calc_sensor_dc(&self.sensors, &scene)?;
+        let illuminance = annual_irradiance(&dc.matrix, &self.sky.luminance)?;
+        let report = AnnualReport::new(
+            &illuminance,
+            None,
+            &dc.rows,
+            &self.sky.schedule,
+            &self.sky.thresholds,
+        )?;
+        // kWh/m² of each sensor
+        let radiation = match &self.sky.radiance {
+            Some(radiance) => {
+                let irradiance = annual_irradiance(&dc.matrix, radiance)?;
+                let hours = 1. / self.sky.schedule.timesteps_per_hour as Float;
+                let (nrows, ncols) = irradiance.size();
+                let mut ret = Vec::with_capacity(nrows);
+                for r in 0..nrows {
+                    let mut sum = 0.0;
+                    for c in 0..ncols {
+                        sum += irradiance.get(r, c)?;
+                    }
+                    ret.push(sum * hours / 1000.);
+                }
+                Some(ret)
+            }
+            None => None,
+        };
+
+        if let Some(dir) = &self.output {
+            save_mtx(dir.join(format!("{}.mtx", id)), &dc.matrix, None)?;
+            let mut csv = "sensor,daylight_autonomy,continuous_daylight_autonomy,udi_achieved,annual_radiation\n".to_string();
+            for (i, s) in report.sensors.iter().enumerate() {
+                let r = radiation
+                    .as_ref()
+                    .map_or(String::new(), |r| format!("{}", r[i]));
+                csv.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    i, s.daylight_autonomy, s.continuous_daylight_autonomy, s.udi_achieved, r
+                ));
+            }
+            write_file(&dir.join(format!("{}_metrics.csv", id)), &csv)?;
+        }
+
+        let das: Vec<Float> = report
+            .sensors
+            .iter()
+            .map(|s| s.daylight_autonomy)
+            .filter(|v| v.is_finite())
+            .collect();
+        Ok(VariantSummary {
+            id: id.to_string(),
+            status: VariantStatus::Done,
+            spatial_daylight_autonomy: Some(report.spatial_daylight_autonomy),
+            mean_daylight_autonomy: Some(das.iter().sum::<Float>() / das.len() as Float),
+            annual_radiation: radiation.map(|r| r.iter().sum::<Float>() / r.len() as Float),
+        })
+    }
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    std::fs::write(path, content)
+        .map_err(|e| format!("Unable to write '{}': {}", path.display(), e))
+}
@@ -0,0 +1,269 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::time::TimeAxis;
+use crate::{Float, PI};
+use calendar::Date;
+use geometry3d::{Ray3D, Vector3D};
+use rendering::{Ray, Scene};
+use solar::{Solar, Time};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// The hours of direct sun received by a set of sensors, as calculated
+/// by [`direct_sun_hours`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SunHours {
+    /// The length of each timestep, in hours
+    pub timestep_hours: Float,
+
+    /// Whether each sensor (row) sees the sun at each timestep (column)
+    pub timetable: Vec<Vec<bool>>,
+
+    /// The number of hours of direct sun received by each sensor
+    pub hours: Vec<Float>,
+}
+
+/// Rotates a direction around the Z axis so that a direction measured with
+/// respect to the true North is expressed in the axes of the scene. The
+/// `north_offset` is the azimuth of the true North (in degrees), measured from
+/// the `+Y` axis of the scene towards `+X`.
+pub fn rotate_to_scene(direction: Vector3D, north_offset: Float) -> Vector3D {
+    let (sin, cos) = (north_offset * PI / 180.).sin_cos();
+    Vector3D::new(
+        direction.x * cos + direction.y * sin,
+        -direction.x * sin + direction.y * cos,
+        direction.z,
+    )
+}
+
+/// The direction of the sun—in the axes of the scene—in the middle of each of
+/// the `timesteps_per_hour` timesteps of every hour of `dates`, as used by
+/// [`direct_sun_hours`]. `None` means that the position of the sun is unknown.
+pub fn sun_directions(
+    dates: &[Date],
+    solar: &Solar,
+    north_offset: Float,
+    timesteps_per_hour: usize,
+) -> Result<Vec<Option<Vector3D>>, String> {
+    if timesteps_per_hour == 0 {
+        return Err("There must be at least one timestep per hour".to_string());
+    }
+    let timestep_hours = 1. / timesteps_per_hour as Float;
+    let mut suns = Vec::with_capacity(dates.len() * 24 * timesteps_per_hour);
+    for date in dates {
+        for step in 0..24 * timesteps_per_hour {
+            let date = Date {
+                hour: (step as Float + 0.5) * timestep_hours,
+                ..*date
+            };
+            let sun = solar
+                .sun_position(Time::Standard(date.day_of_year()))
+                .map(|dir| rotate_to_scene(dir, north_offset));
+            suns.push(sun);
+        }
+    }
+    Ok(suns)
+}
+
+/// Like [`sun_directions`], for the timesteps of a [`TimeAxis`] (the sun is in
+/// the middle of each of them)
+pub fn sun_directions_on_axis(
+    axis: &TimeAxis,
+    solar: &Solar,
+    north_offset: Float,
+) -> Vec<Option<Vector3D>> {
+    axis.timesteps()
+        .iter()
+        .map(|step| {
+            solar
+                .sun_position(Time::Standard(step.date().day_of_year()))
+                .map(|dir| rotate_to_scene(dir, north_offset))
+        })
+        .collect()
+}
+
+/// Calculates the hours of direct sun received by each sensor during each of
+/// `dates`, which are split into `timesteps_per_hour` timesteps per hour. The sun is
+/// placed in the middle of each timestep, and every sensor sends a single
+/// shadow ray towards it.
+///
+/// The position of the sun comes from `solar`, and is rotated
+/// according to the `north_offset` of the scene (see [`rotate_to_scene`]).
+pub fn direct_sun_hours(
+    points: &[Ray3D],
+    scene: &Scene,
+    dates: &[Date],
+    solar: &Solar,
+    north_offset: Float,
+    timesteps_per_hour: usize,
+) -> Result<SunHours, String> {
+    let suns = sun_directions(dates, solar, north_offset, timesteps_per_hour)?;
+    let timestep_hours = 1. / timesteps_per_hour as Float;
+    sun_hours_from_directions(points, scene, &suns, timestep_hours)
+}
+
+/// Like [`direct_sun_hours`], but receiving the direction of the sun—in the axes of
+/// the scene—at each timestep. `None` means that the sun is down.
+///
+/// Sensors do not see the sun when it is below the horizon or behind them.
+pub fn sun_hours_from_directions(
+    points: &[Ray3D],
+    scene: &Scene,
+    suns: &[Option<Vector3D>],
+    timestep_hours: Float,
+) -> Result<SunHours, String> {
+    if timestep_hours <= 0.0 {
+        return Err(format!(
+            "The length of a timestep must be positive, but it was {}",
+            timestep_hours
+        ));
+    }
+    let suns: Vec<Option<Vector3D>> = suns
+        .iter()
+        .map(|s| s.filter(|dir| dir.z > 0.0).map(|dir| dir.get_normalized()))
+        .collect();
+    let trace = |point: &Ray3D| -> Vec<bool> {
+        let mut aux = Vec::with_capacity(2);
+        suns.iter()
+            .map(|sun| match sun {
+                Some(direction) if *direction * point.direction > 0.0 => {
+                    let mut ray = Ray {
+                        geometry: Ray3D {
+                            origin: point.origin,
+                            direction: *direction,
+                        },
+                        ..Ray::default()
+                    };
+                    scene.cast_ray(&mut ray, &mut aux).is_none()
+                }
+                _ => false,
+            })
+            .collect()
+    };
+    #[cfg(feature = "parallel")]
+    let timetable: Vec<Vec<bool>> = points.par_iter().map(trace).collect();
+    #[cfg(not(feature = "parallel"))]
+    let timetable: Vec<Vec<bool>> = points.iter().map(trace).collect();
+
+    let hours = timetable
+        .iter()
+        .map(|row| row.iter().filter(|lit| **lit).count() as Float * timestep_hours)
+        .collect();
+    Ok(SunHours {
+        timestep_hours,
+        timetable,
+        hours,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use geometry3d::Point3D;
+    use validate::assert_close;
+
+    /// The sun, due South, at an altitude of `alt` degrees
+    fn south_sun(alt: Float) -> Option<Vector3D> {
+        let alt = alt * PI / 180.;
+        Some(Vector3D::new(0., -alt.cos(), alt.sin()))
+    }
+
+    #[test]
+    fn test_rotate_to_scene() {
+        // True North is towards +X: a sun in the true East is in -Y
+        let east = Vector3D::new(1., 0., 0.5);
+        let dir = rotate_to_scene(east, 90.);
+        assert_close!(dir.x, 0., 1e-6);
+        assert_close!(dir.y, -1., 1e-6);
+        assert_close!(dir.z, 0.5, 1e-6);
+        let dir = rotate_to_scene(Vector3D::new(0., 1., 0.), 30.);
+        assert_close!(dir.x, (30. * PI / 180.).sin(), 1e-6);
+    }
+
+    #[test]
+    fn test_overhang() {
+        let (mut scene, _) = crate::load_scene("./tests/sun_hours/overhang.rad").unwrap();
+        scene.build_accelerator();
+        let up = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        };
+        // Facing North, so that the sun in the South is behind it
+        let north = Ray3D {
+            origin: Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 1., 0.),
+        };
+
+        // The overhang shades the sensor when the sun is between
+        // atan(1/10) = 5.7 and atan(1/1) = 45 degrees high
+        let altitudes = [-10., 3., 10., 30., 44., 46., 60., 89.];
+        let suns: Vec<Option<Vector3D>> = altitudes
+            .iter()
+            .map(|a| south_sun(*a))
+            .chain(std::iter::once(None))
+            .collect();
+        let res = sun_hours_from_directions(&[up, north], &scene, &suns, 0.25).unwrap();
+        assert_eq!(
+            res.timetable[0],
+            vec![false, true, false, false, false, true, true, true, false]
+        );
+        assert_close!(res.hours[0], 1., 1e-6);
+        assert!(res.timetable[1].iter().all(|lit| !lit));
+        assert_eq!(res.hours[1], 0.);
+
+        // A scene rotated by 180 degrees has the overhang in the North
+        let rotated: Vec<Option<Vector3D>> = suns
+            .iter()
+            .map(|s| s.map(|d| rotate_to_scene(d, 180.)))
+            .collect();
+        let res = sun_hours_from_directions(&[up], &scene, &rotated, 0.25).unwrap();
+        assert_close!(res.hours[0], 1.75, 1e-6);
+
+        assert!(sun_hours_from_directions(&[up], &scene, &suns, 0.).is_err());
+    }
+
+    #[test]
+    fn test_direct_sun_hours() {
+        let scene = Scene::new();
+        let solar = Solar::new(51.5, 0.0, 0.0);
+        let dates = [
+            Date {
+                month: 3,
+                day: 21,
+                hour: 0.,
+            },
+            Date {
+                month: 6,
+                day: 21,
+                hour: 0.,
+            },
+        ];
+        let up = Ray3D {
+            origin: geometry3d::Point3D::new(0., 0., 0.),
+            direction: Vector3D::new(0., 0., 1.),
+        };
+        let res = direct_sun_hours(&[up], &scene, &dates, &solar, 0., 4).unwrap();
+        assert_eq!(res.timetable[0].len(), 2 * 24 * 4);
+        assert!(res.hours[0] <= 48.);
+        assert!(direct_sun_hours(&[up], &scene, &dates, &solar, 0., 0).is_err());
+    }
+}
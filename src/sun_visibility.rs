@@ -0,0 +1,330 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Whether sensors see the disc of the sun through the windows of a scene, as
+//! needed by blind-control rules that only react to occupants with a direct
+//! view of the sun (e.g., those of IES LM-83).
+//!
+//! Unlike the shadow rays of [`crate::sun_hours`], which stop at anything,
+//! these go through `glass` and carry on, so obstructions behind the glazing
+//! (e.g., fins, overhangs or neighbouring buildings) still block the view.
+//! The windows are tagged through [`ApertureGroup`]s, as for
+//! [`DCSession::calc_aperture_dc`](crate::DCSession::calc_aperture_dc).
+
+use crate::apertures::{surface_groups, ApertureGroup};
+use crate::ray_offset::RayOffset;
+use crate::scene_loading::{SceneReport, SurfaceSide};
+use crate::sensitivity::{glass_transmissivity, MAX_TRANSMISSIONS};
+use crate::sensor::SensorSpec;
+use geometry3d::{Ray3D, Vector3D};
+use rendering::{Ray, Scene};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// What a sensor sees in the direction of the sun at one timestep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SunView {
+    /// The sun is down, behind the sensor or outside of its
+    /// [`AngularMask`](crate::AngularMask), so no ray was cast
+    Down,
+
+    /// Nothing is in the way, not even glass (e.g., the sensor is outside)
+    Exterior,
+
+    /// The sun is seen through the glass of an aperture group (the index of
+    /// the first group crossed, within the groups that were given)
+    Aperture(usize),
+
+    /// The sun is seen through glass that is not in any aperture group
+    Glass,
+
+    /// Something that is not glass is in the way, in front of the glazing or
+    /// behind it
+    Blocked,
+}
+
+/// What a set of sensors see in the direction of the sun, as calculated by
+/// [`direct_sun_visibility`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SunVisibility {
+    /// The names of the aperture groups, to which [`SunView::Aperture`] points
+    pub groups: Vec<String>,
+
+    /// What each sensor (row) sees at each timestep (column)
+    pub views: Vec<Vec<SunView>>,
+
+    /// Whether each sensor (row) sees the sun through an aperture at each
+    /// timestep (column), which is what view-based blind triggers react to
+    pub mask: Vec<Vec<bool>>,
+}
+
+impl SunVisibility {
+    /// Like [`SunVisibility::mask`], for the views through the first aperture
+    /// group crossed being the one called `name`
+    pub fn through(&self, name: &str) -> Result<Vec<Vec<bool>>, String> {
+        let g = self
+            .groups
+            .iter()
+            .position(|group| group == name)
+            .ok_or_else(|| format!("There is no aperture group called '{}'", name))?;
+        Ok(self
+            .views
+            .iter()
+            .map(|row| row.iter().map(|v| *v == SunView::Aperture(g)).collect())
+            .collect())
+    }
+}
+
+/// Casts one ray per sensor and timestep towards the sun—whose direction is
+/// given in the axes of the scene, `None` meaning that it is down—and finds out
+/// what is in the way (see [`SunView`]). Rays go straight through `glass`,
+/// recording the first aperture group they cross, until they leave the scene
+/// or hit something else. The `report` must be the one returned when loading
+/// the `scene`, as it says which surface each triangle belongs to.
+pub fn direct_sun_visibility(
+    sensors: &[SensorSpec],
+    scene: &Scene,
+    report: &SceneReport,
+    suns: &[Option<Vector3D>],
+    apertures: &[ApertureGroup],
+) -> Result<SunVisibility, String> {
+    let groups = surface_groups(report, apertures)?;
+    let triangles = report.triangle_surfaces()?;
+    // Whether the front and the back of each surface are glass, and its normal
+    let surfaces = report
+        .surfaces
+        .iter()
+        .map(|s| {
+            let is_glass = |modifier: &str| {
+                report
+                    .materials
+                    .iter()
+                    .find(|m| m.name == modifier)
+                    .is_some_and(|m| glass_transmissivity(&m.kind, m.rgb) > 0.0)
+            };
+            (is_glass(&s.modifier), is_glass(&s.back_modifier), s.normal)
+        })
+        .collect::<Vec<(bool, bool, Option<Vector3D>)>>();
+    let offset = RayOffset::for_scene(report);
+    let suns: Vec<Option<Vector3D>> = suns
+        .iter()
+        .map(|s| s.filter(|dir| dir.z > 0.0).map(|dir| dir.get_normalized()))
+        .collect();
+
+    let trace = |sensor: &SensorSpec| -> Result<Vec<SunView>, String> {
+        let mut aux = Vec::with_capacity(2);
+        let mut ret = Vec::with_capacity(suns.len());
+        for sun in suns.iter() {
+            let direction = match sun {
+                Some(direction) if *direction * sensor.ray.direction > 0.0 => *direction,
+                _ => {
+                    ret.push(SunView::Down);
+                    continue;
+                }
+            };
+            if let Some(mask) = &sensor.mask {
+                if !mask.is_visible(sensor.ray.direction, direction)? {
+                    ret.push(SunView::Down);
+                    continue;
+                }
+            }
+            let mut origin = sensor.ray.origin;
+            let mut crossed_glass = false;
+            let mut first_group = None;
+            let mut view = SunView::Blocked;
+            for _ in 0..=MAX_TRANSMISSIONS {
+                let mut ray = Ray {
+                    geometry: Ray3D { origin, direction },
+                    ..Ray::default()
+                };
+                let Some(triangle) = sensor.cast_ray(scene, &mut ray, &mut aux) else {
+                    view = match (first_group, crossed_glass) {
+                        (Some(g), _) => SunView::Aperture(g),
+                        (None, true) => SunView::Glass,
+                        (None, false) => SunView::Exterior,
+                    };
+                    break;
+                };
+                let surface = triangles[triangle];
+                let (front, back, normal) = surfaces[surface];
+                let side = normal.map_or(SurfaceSide::Front, |n| SurfaceSide::hit_by(direction, n));
+                let glass = match side {
+                    SurfaceSide::Front => front,
+                    SurfaceSide::Back => back,
+                };
+                if !glass {
+                    break;
+                }
+                crossed_glass = true;
+                first_group = first_group.or(groups[surface]);
+                let travelled = ray.interaction.point.distance(origin);
+                origin = ray.interaction.point + direction * offset.at(travelled);
+            }
+            ret.push(view);
+        }
+        Ok(ret)
+    };
+    #[cfg(feature = "parallel")]
+    let views: Vec<Vec<SunView>> = sensors
+        .par_iter()
+        .map(trace)
+        .collect::<Result<_, String>>()?;
+    #[cfg(not(feature = "parallel"))]
+    let views: Vec<Vec<SunView>> = sensors.iter().map(trace).collect::<Result<_, String>>()?;
+
+    let mask = views
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|v| matches!(v, SunView::Aperture(_)))
+                .collect()
+        })
+        .collect();
+    Ok(SunVisibility {
+        groups: apertures.iter().map(|g| g.name.clone()).collect(),
+        views,
+        mask,
+    })
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::apertures::LightLossFactors;
+    use crate::scene_builder::{Material, SceneBuilder};
+    use crate::Float;
+    use geometry3d::Point3D;
+
+    /// A wall along the `X` axis with a window in it, facing South (i.e.,
+    /// towards `-Y`), and a fin sticking out of it to the East of the window
+    fn window_and_fin() -> (Scene, SceneReport) {
+        let mut builder = SceneBuilder::new();
+        builder
+            .add_material("wall_mat", Material::plastic(0.5))
+            .unwrap();
+        builder
+            .add_material("glass_mat", Material::glass(0.8))
+            .unwrap();
+        // A rectangle in the plane of the wall
+        let wall = |x0: Float, x1: Float, z0: Float, z1: Float| {
+            [(x0, z0), (x1, z0), (x1, z1), (x0, z1)].map(|(x, z)| Point3D::new(x, 0., z))
+        };
+        builder
+            .add_polygon("glass_mat", "window", &wall(-1., 1., 0.5, 1.5))
+            .unwrap();
+        for (name, x0, x1, z0, z1) in [
+            ("wall_west", -5., -1., 0., 3.),
+            ("wall_east", 1., 5., 0., 3.),
+            ("wall_below", -1., 1., 0., 0.5),
+            ("wall_above", -1., 1., 1.5, 3.),
+        ] {
+            builder
+                .add_polygon("wall_mat", name, &wall(x0, x1, z0, z1))
+                .unwrap();
+        }
+        let fin = [(0., 0.), (-2., 0.), (-2., 3.), (0., 3.)].map(|(y, z)| Point3D::new(1.2, y, z));
+        builder.add_polygon("wall_mat", "fin", &fin).unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_window_and_fin() {
+        let (scene, report) = window_and_fin();
+        let groups = [ApertureGroup::new(
+            "south",
+            &["window"],
+            LightLossFactors::default(),
+        )];
+        // Behind the window, looking out
+        let inside = SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., 1., 1.),
+            direction: Vector3D::new(0., -1., 0.),
+        });
+        // Outside, looking up
+        let outside = SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., -3., 1.),
+            direction: Vector3D::new(0., 0., 1.),
+        });
+        let sensors = [inside, outside];
+        let suns = [
+            // From the South-West, through the window
+            Some(Vector3D::new(-0.5, -1., 0.2)),
+            // From the South-East, through the window and into the fin
+            Some(Vector3D::new(0.5, -1., 0.2)),
+            // Due South, through the window
+            Some(Vector3D::new(0., -1., 0.2)),
+            // High in the South, into the wall above the window
+            Some(Vector3D::new(0., -1., 1.)),
+            // From the North, behind the sensor inside
+            Some(Vector3D::new(0., 1., 0.2)),
+            // Below the horizon, and down
+            Some(Vector3D::new(0., -1., -0.1)),
+            None,
+        ];
+        let res = direct_sun_visibility(&sensors, &scene, &report, &suns, &groups).unwrap();
+        assert_eq!(res.groups, vec!["south".to_string()]);
+        assert_eq!(
+            res.views[0],
+            vec![
+                SunView::Aperture(0),
+                SunView::Blocked,
+                SunView::Aperture(0),
+                SunView::Blocked,
+                SunView::Down,
+                SunView::Down,
+                SunView::Down,
+            ]
+        );
+        assert_eq!(
+            res.mask[0],
+            vec![true, false, true, false, false, false, false]
+        );
+        assert_eq!(
+            res.views[1],
+            vec![
+                SunView::Exterior,
+                SunView::Exterior,
+                SunView::Exterior,
+                SunView::Exterior,
+                SunView::Blocked,
+                SunView::Down,
+                SunView::Down,
+            ]
+        );
+        assert!(res.mask[1].iter().all(|seen| !seen));
+        assert_eq!(res.through("south").unwrap(), res.mask);
+        assert!(res.through("north").is_err());
+
+        // Glass that is not tagged still lets the sun through
+        let untagged = direct_sun_visibility(&sensors[..1], &scene, &report, &suns, &[]).unwrap();
+        assert_eq!(untagged.views[0][0], SunView::Glass);
+        assert_eq!(untagged.views[0][1], SunView::Blocked);
+        assert!(untagged.mask[0].iter().all(|seen| !seen));
+
+        // Apertures must be glass
+        let wrong = [ApertureGroup::new(
+            "fin",
+            &["fin"],
+            LightLossFactors::default(),
+        )];
+        assert!(direct_sun_visibility(&sensors[..1], &scene, &report, &suns, &wrong).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Temporal maps of annual results: how the value of each sensor changes with
+//! the hour of the day (and the month), such as the median vertical illuminance
+//! at the eye of each occupant from 8:00 to 9:00, which tells mornings and
+//! afternoons apart in circadian studies.
+//!
+//! Hours are those of standard time, as everywhere in the crate. Each timestep
+//! belongs to the clock hour in which it starts, so sub-hourly timesteps are
+//! gathered into their hour.
+//!
+//! Only the occupied timesteps of the schedule count, and the values that are
+//! not finite (e.g., missing or invalid entries) are left out. Cells that are
+//! left with no value are `NaN`.
+
+use crate::percentile_map::{check_binary, select_percentiles};
+use crate::report::OccupancySchedule;
+use crate::time::{AnnualSeries, Timestep};
+use crate::Float;
+use matrix::Matrix;
+
+/// How the values that fall into each cell of a temporal map are reduced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemporalReducer {
+    /// Their mean, weighted by occupancy
+    Mean,
+    /// Their median
+    Median,
+    /// Their 95th percentile
+    P95,
+}
+
+impl TemporalReducer {
+    /// Reduces some `values`—where the weight of each comes after it—which
+    /// are reordered. `NaN` if there are none.
+    fn reduce(&self, values: &mut [(Float, Float)], buffer: &mut Vec<Float>) -> Float {
+        if values.is_empty() {
+            return Float::NAN;
+        }
+        let p = match self {
+            Self::Mean => {
+                let (sum, weights) = values
+                    .iter()
+                    .fold((0.0, 0.0), |(s, t), (v, w)| (s + v * w, t + w));
+                return sum / weights;
+            }
+            Self::Median => 50.,
+            Self::P95 => 95.,
+        };
+        buffer.clear();
+        buffer.extend(values.iter().map(|(v, _)| *v));
+        select_percentiles(buffer, &[p])[0]
+    }
+}
+
+/// The occupancy of each timestep of a `series` and the cell of the map it
+/// falls into (as given by `cell`), after checking that the schedule suits the
+/// `reducer`
+fn cells<F: Fn(&Timestep) -> usize>(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    reducer: TemporalReducer,
+    cell: F,
+) -> Result<Vec<(Float, usize)>, String> {
+    let weights = schedule.on_axis(&series.axis)?.weights;
+    if weights.iter().all(|w| *w <= 0.0) {
+        return Err("The schedule has no occupied timesteps".to_string());
+    }
+    if reducer != TemporalReducer::Mean {
+        check_binary(&weights)?;
+    }
+    Ok(weights
+        .iter()
+        .zip(series.axis.timesteps().iter())
+        .map(|(w, step)| (*w, cell(step)))
+        .collect())
+}
+
+/// The clock hour (from `0` to `23`) in which a timestep starts
+fn hour_of_day(step: &Timestep) -> usize {
+    (step.start().hour_of_year.floor() as usize) % 24
+}
+
+/// Reduces the occupied, finite values of row `r` of a `series` into
+/// `n_cells` cells
+fn reduce_row(
+    series: &AnnualSeries,
+    r: usize,
+    cells: &[(Float, usize)],
+    n_cells: usize,
+    reducer: TemporalReducer,
+) -> Result<Vec<Float>, String> {
+    let mut values: Vec<Vec<(Float, Float)>> = vec![Vec::new(); n_cells];
+    for (t, (w, cell)) in cells.iter().enumerate() {
+        let v = series.values.get(r, t)?;
+        if *w > 0.0 && v.is_finite() {
+            values[*cell].push((v, *w));
+        }
+    }
+    let mut buffer = Vec::new();
+    Ok(values
+        .iter_mut()
+        .map(|v| reducer.reduce(v, &mut buffer))
+        .collect())
+}
+
+/// The value of each sensor at each hour of the day: a matrix with `24` rows
+/// (from 0:00 to 1:00 onwards) and one column per sensor, reducing the occupied
+/// timesteps of every day of the `series` at that hour. The median and the
+/// 95th percentile count every occupied timestep once, so the schedule can only
+/// have weights of `0` and `1` with them.
+pub fn temporal_map(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    reducer: TemporalReducer,
+) -> Result<Matrix, String> {
+    let cells = cells(series, schedule, reducer, hour_of_day)?;
+    let (n_sensors, _) = series.values.size();
+    let mut ret = Matrix::new(0.0, 24, n_sensors);
+    for r in 0..n_sensors {
+        for (hour, v) in reduce_row(series, r, &cells, 24, reducer)?
+            .iter()
+            .enumerate()
+        {
+            ret.set(hour, r, *v)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Like [`temporal_map`], for a single `sensor` and each month separately: a
+/// matrix with `12` rows (one per month, from January) and `24` columns (one per
+/// hour of the day), as in the classic temporal map plots. Timesteps belong to
+/// the month of their centre.
+pub fn monthly_temporal_map(
+    series: &AnnualSeries,
+    schedule: &OccupancySchedule,
+    sensor: usize,
+    reducer: TemporalReducer,
+) -> Result<Matrix, String> {
+    let (n_sensors, _) = series.values.size();
+    if sensor >= n_sensors {
+        return Err(format!(
+            "There are {} sensors, so there is no sensor {}",
+            n_sensors, sensor
+        ));
+    }
+    let cells = cells(series, schedule, reducer, |step| {
+        24 * (step.month() - 1) + hour_of_day(step)
+    })?;
+    let mut ret = Matrix::new(0.0, 12, 24);
+    for (i, v) in reduce_row(series, sensor, &cells, 12 * 24, reducer)?
+        .iter()
+        .enumerate()
+    {
+        ret.set(i / 24, i % 24, *v)?;
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::time::{TimeAxis, HOURS_PER_YEAR};
+    use validate::assert_close;
+
+    /// A year of three sensors, occupied from 8:00 to 18:00. The first one is
+    /// `10h + d % 5` at hour `h` of day `d` (so each residue comes up 73 times
+    /// at each hour), the second one is the same but missing where `d % 5 = 4`
+    /// at 9:00 and always at 12:00, and the third one is `100m + h` in month `m`.
+    fn diurnal() -> (AnnualSeries, OccupancySchedule) {
+        let axis = TimeAxis::annual(1).unwrap();
+        let mut values = Matrix::new(0.0, 3, HOURS_PER_YEAR);
+        for step in axis.timesteps() {
+            let (d, h) = (step.index / 24, step.index % 24);
+            let v = (10 * h + d % 5) as Float;
+            values.set(0, step.index, v).unwrap();
+            let missing = (h == 9 && d % 5 == 4) || h == 12;
+            let v1 = if missing { Float::NAN } else { v };
+            values.set(1, step.index, v1).unwrap();
+            let v2 = (100 * step.month() + h) as Float;
+            values.set(2, step.index, v2).unwrap();
+        }
+        let schedule = OccupancySchedule::office_hours(HOURS_PER_YEAR, 1, 8., 18., None).unwrap();
+        (AnnualSeries::new(values, axis).unwrap(), schedule)
+    }
+
+    #[test]
+    fn test_diurnal_profiles() {
+        let (series, schedule) = diurnal();
+        let expected = [
+            (TemporalReducer::Mean, 2., 1.5),
+            (TemporalReducer::Median, 2., 1.5),
+            (TemporalReducer::P95, 4., 3.),
+        ];
+        for (reducer, offset, offset_at_nine) in expected {
+            let map = temporal_map(&series, &schedule, reducer).unwrap();
+            assert_eq!(map.size(), (24, 3));
+            for h in 0..24 {
+                let v = map.get(h, 0).unwrap();
+                if (8..18).contains(&h) {
+                    assert_close!(v, 10. * h as Float + offset, 1e-4);
+                } else {
+                    assert!(v.is_nan(), "{:?} at {}: {}", reducer, h, v);
+                }
+            }
+            // Missing values are left out, and hours with none are empty
+            assert_close!(map.get(9, 1).unwrap(), 90. + offset_at_nine, 1e-4);
+            assert!(map.get(12, 1).unwrap().is_nan());
+            assert_close!(map.get(10, 1).unwrap(), 100. + offset, 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_monthly_map() {
+        let (series, schedule) = diurnal();
+        let map = monthly_temporal_map(&series, &schedule, 2, TemporalReducer::Median).unwrap();
+        assert_eq!(map.size(), (12, 24));
+        for m in 0..12 {
+            for h in 0..24 {
+                let v = map.get(m, h).unwrap();
+                if (8..18).contains(&h) {
+                    assert_close!(v, (100 * (m + 1) + h) as Float, 1e-4);
+                } else {
+                    assert!(v.is_nan());
+                }
+            }
+        }
+        assert!(monthly_temporal_map(&series, &schedule, 3, TemporalReducer::Mean).is_err());
+    }
+
+    #[test]
+    fn test_schedules() {
+        let (series, _) = diurnal();
+        // Every other day is half occupied, so it weighs half as much in the mean...
+        let weights: Vec<Float> = (0..HOURS_PER_YEAR)
+            .map(|i| if (i / 24) % 2 == 0 { 1. } else { 0.5 })
+            .collect();
+        let weighted = OccupancySchedule::from_weights(weights, 1).unwrap();
+        let map = temporal_map(&series, &weighted, TemporalReducer::Mean).unwrap();
+        let (mut sum, mut total) = (0.0, 0.0);
+        for d in 0..365 {
+            let w = if d % 2 == 0 { 1. } else { 0.5 };
+            sum += w * (d % 5) as Float;
+            total += w;
+        }
+        assert_close!(map.get(3, 0).unwrap(), 30. + sum / total, 1e-4);
+
+        // ...but cannot be counted by percentiles
+        assert!(temporal_map(&series, &weighted, TemporalReducer::Median).is_err());
+        let empty = OccupancySchedule::from_weights(vec![0.; HOURS_PER_YEAR], 1).unwrap();
+        assert!(temporal_map(&series, &empty, TemporalReducer::Mean).is_err());
+    }
+}
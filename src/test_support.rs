@@ -0,0 +1,407 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Canonical scenes whose results are known in closed form, to be used as
+//! oracles when validating the estimators. All the expected values assume a
+//! uniform sky of unit radiance: the sky component of a sensor (i.e., the sum
+//! of its direct coefficients) is then `PI` times its sky view factor.
+
+use crate::scene_builder::{Material, SceneBuilder};
+use crate::{Float, PI};
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use matrix::Matrix;
+use rendering::Scene;
+
+/// How much larger than the features of interest "infinite" surfaces are
+const INFINITE: Float = 1000.;
+
+/// The number of standard errors that an estimate is allowed to be away from
+/// the expected value in [`assert_estimate`]
+pub const ORACLE_Z: Float = 4.;
+
+/// A sensor of a [`ReferenceScene`], with its expected results
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceSensor {
+    /// The position and orientation of the sensor
+    pub ray: Ray3D,
+
+    /// The fraction of the irradiance of an unobstructed sky that
+    /// reaches the sensor straight from the sky (excluding the ground bin)
+    pub sky_view_factor: Float,
+
+    /// The sum of the coefficients of the paths that bounce exactly once, if
+    /// known in closed form
+    pub first_bounce: Option<Float>,
+}
+
+impl ReferenceSensor {
+    /// The expected sum of the direct coefficients
+    pub fn sky_component(&self) -> Float {
+        PI * self.sky_view_factor
+    }
+}
+
+/// A programmatically generated scene, together with sensors whose results
+/// are known in closed form
+pub struct ReferenceScene {
+    /// A short description of the scene
+    pub name: String,
+
+    /// The scene, with its accelerator already built
+    pub scene: Scene,
+
+    /// The sensors, and what they are expected to get
+    pub sensors: Vec<ReferenceSensor>,
+}
+
+/// The view factor from a differential element to a parallel rectangle of
+/// size `a` by `b` at distance `c`, with one of its corners right in front of
+/// the element (i.e., along its normal).
+pub fn parallel_rectangle_view_factor(a: Float, b: Float, c: Float) -> Float {
+    let (x, y) = (a / c, b / c);
+    let (sx, sy) = ((1. + x * x).sqrt(), (1. + y * y).sqrt());
+    (x / sx * (y / sx).atan() + y / sy * (x / sy).atan()) / (2. * PI)
+}
+
+/// The view factor from a differential element to an infinitely long strip
+/// perpendicular to it, at distance `d`, which starts at the plane of the
+/// element and has a height `height`
+pub fn perpendicular_strip_view_factor(d: Float, height: Float) -> Float {
+    0.5 * (1. - d / (d * d + height * height).sqrt())
+}
+
+/// Adds a polygon to a scene
+fn polygon(
+    builder: &mut SceneBuilder,
+    modifier: &str,
+    name: &str,
+    vertices: &[[Float; 3]],
+) -> Result<(), String> {
+    let vertices: Vec<Point3D> = vertices
+        .iter()
+        .map(|v| Point3D::new(v[0], v[1], v[2]))
+        .collect();
+    builder.add_polygon(modifier, name, &vertices)
+}
+
+/// A scene with a single grey Lambertian material
+fn with_material(name: &str, reflectance: Float) -> Result<SceneBuilder, String> {
+    let mut builder = SceneBuilder::new();
+    builder.add_material(name, Material::plastic(reflectance))?;
+    Ok(builder)
+}
+
+fn sensor(origin: Point3D, direction: Vector3D) -> Ray3D {
+    Ray3D { origin, direction }
+}
+
+/// A horizontal (and practically infinite) ground of a certain `reflectance`, with a sensor
+/// facing up and another facing down, both at `height` above it.
+///
+/// The one facing up sees the whole sky and nothing else. The one facing down sees
+/// the ground, whose radiance is `reflectance` because it is
+/// irradiated by the whole sky.
+pub fn unobstructed_plane(reflectance: Float, height: Float) -> Result<ReferenceScene, String> {
+    let l = INFINITE * height;
+    let mut builder = with_material("ground_mat", reflectance)?;
+    polygon(
+        &mut builder,
+        "ground_mat",
+        "ground",
+        &[[-l, -l, 0.], [l, -l, 0.], [l, l, 0.], [-l, l, 0.]],
+    )?;
+    let ground_factor = 4. * parallel_rectangle_view_factor(l, l, height);
+    Ok(ReferenceScene {
+        name: format!("unobstructed plane (reflectance {})", reflectance),
+        scene: builder.build()?.0,
+        sensors: vec![
+            ReferenceSensor {
+                ray: sensor(Point3D::new(0., 0., height), Vector3D::new(0., 0., 1.)),
+                sky_view_factor: 1.,
+                first_bounce: Some(0.),
+            },
+            ReferenceSensor {
+                ray: sensor(Point3D::new(0., 0., height), Vector3D::new(0., 0., -1.)),
+                sky_view_factor: 0.,
+                first_bounce: Some(PI * reflectance * ground_factor),
+            },
+        ],
+    })
+}
+
+/// A vertical (and practically infinitely long) wall of a certain `height`
+/// and `reflectance` at a distance `d` North of a sensor facing up, which
+/// is at the level of the bottom of the wall. There is no ground.
+///
+/// The wall sees half of its hemisphere covered by the sky, so its radiance
+/// is half its reflectance.
+pub fn infinite_wall(
+    d: Float,
+    height: Float,
+    reflectance: Float,
+) -> Result<ReferenceScene, String> {
+    let l = INFINITE * d.max(height);
+    let mut builder = with_material("wall_mat", reflectance)?;
+    polygon(
+        &mut builder,
+        "wall_mat",
+        "wall",
+        &[[-l, d, 0.], [l, d, 0.], [l, d, height], [-l, d, height]],
+    )?;
+    let wall_factor = perpendicular_strip_view_factor(d, height);
+    Ok(ReferenceScene {
+        name: format!("wall of height {} at distance {}", height, d),
+        scene: builder.build()?.0,
+        sensors: vec![ReferenceSensor {
+            ray: sensor(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.)),
+            sky_view_factor: 1. - wall_factor,
+            first_bounce: Some(PI * 0.5 * reflectance * wall_factor),
+        }],
+    })
+}
+
+/// A horizontal (and practically infinite) roof at a `height` above a sensor
+/// facing up, with a square aperture of side `a` centred above it. There is
+/// no ground, so the roof does not reflect anything towards the sensor.
+pub fn square_aperture(a: Float, height: Float) -> Result<ReferenceScene, String> {
+    let l = INFINITE * a.max(height);
+    let h = 0.5 * a;
+    let z = height;
+    let mut builder = with_material("roof_mat", 0.5)?;
+    for (name, x0, x1, y0, y1) in [
+        ("south", -l, l, -l, -h),
+        ("north", -l, l, h, l),
+        ("west", -l, -h, -h, h),
+        ("east", h, l, -h, h),
+    ] {
+        polygon(
+            &mut builder,
+            "roof_mat",
+            name,
+            &[[x0, y0, z], [x1, y0, z], [x1, y1, z], [x0, y1, z]],
+        )?;
+    }
+    Ok(ReferenceScene {
+        name: format!("square aperture of side {} at height {}", a, height),
+        scene: builder.build()?.0,
+        sensors: vec![ReferenceSensor {
+            ray: sensor(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.)),
+            sky_view_factor: 4. * parallel_rectangle_view_factor(h, h, height),
+            first_bounce: Some(0.),
+        }],
+    })
+}
+
+/// Two parallel (and practically infinitely long) vertical plates of a certain
+/// `height`, at a distance `width` from each other, like a street canyon. The
+/// sensor faces up from the middle of the canyon, at the level of the bottom of
+/// the plates.
+///
+/// The plates shade each other, so their radiance changes with height and their
+/// first bounce has no simple closed form.
+pub fn parallel_plates(width: Float, height: Float) -> Result<ReferenceScene, String> {
+    let l = INFINITE * width.max(height);
+    let d = 0.5 * width;
+    let mut builder = with_material("plate_mat", 0.5)?;
+    for (name, y) in [("south_plate", -d), ("north_plate", d)] {
+        polygon(
+            &mut builder,
+            "plate_mat",
+            name,
+            &[[-l, y, 0.], [l, y, 0.], [l, y, height], [-l, y, height]],
+        )?;
+    }
+    Ok(ReferenceScene {
+        name: format!("canyon of width {} and height {}", width, height),
+        scene: builder.build()?.0,
+        sensors: vec![ReferenceSensor {
+            ray: sensor(Point3D::new(0., 0., 0.), Vector3D::new(0., 0., 1.)),
+            sky_view_factor: 1. - 2. * perpendicular_strip_view_factor(d, height),
+            first_bounce: None,
+        }],
+    })
+}
+
+/// All the reference scenes, with some typical dimensions
+pub fn reference_scenes() -> Result<Vec<ReferenceScene>, String> {
+    Ok(vec![
+        unobstructed_plane(0.2, 1.)?,
+        infinite_wall(2., 3., 0.5)?,
+        square_aperture(1., 2.)?,
+        parallel_plates(4., 3.)?,
+    ])
+}
+
+/// A deterministic matrix of values in `[0, 1)`, for tests and benchmarks
+/// that need some data but do not care about its meaning. Different `seed`s
+/// give different matrices.
+pub fn pseudo_random(nrows: usize, ncols: usize, seed: usize) -> Matrix {
+    let mut m = Matrix::new(0.0, nrows, ncols);
+    for r in 0..nrows {
+        for c in 0..ncols {
+            let v = ((r * 7919 + c * 104729 + seed) % 1000) as Float / 1000.;
+            m.set(r, c, v).unwrap();
+        }
+    }
+    m
+}
+
+/// The peak resident memory of the process so far, in kB. Only available
+/// on Linux, `None` elsewhere.
+pub fn peak_memory_kb() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The environment variable that tells a test that [`peak_memory_of`] is
+/// running it in a process of its own, and which workload to run
+pub const MEMORY_PROBE: &str = "SOLAR_MODEL_MEMORY_PROBE";
+
+/// The environment variable with the file where [`report_peak_memory`]
+/// writes the peak memory of the probe
+const MEMORY_PROBE_FILE: &str = "SOLAR_MODEL_MEMORY_PROBE_FILE";
+
+/// Runs the `test` (i.e., its whole path, like `module::testing::name`) of
+/// the current test binary again in a process of its own, with
+/// [`MEMORY_PROBE`] set to `probe`, and returns the peak memory that such
+/// process reached, in kB. The peak memory of a process never goes down, so
+/// workloads need processes of their own for their peaks to be compared.
+///
+/// The test is expected to run the workload named by [`MEMORY_PROBE`] when
+/// that variable is set, and then call [`report_peak_memory`].
+pub fn peak_memory_of(test: &str, probe: &str) -> Result<usize, String> {
+    let file = std::env::temp_dir().join(format!(
+        "solar_model_{}_{}_{}",
+        std::process::id(),
+        test.replace("::", "_"),
+        probe
+    ));
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let status = std::process::Command::new(exe)
+        .args([test, "--exact", "--include-ignored", "--test-threads=1"])
+        .env(MEMORY_PROBE, probe)
+        .env(MEMORY_PROBE_FILE, &file)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .map_err(|e| e.to_string())?;
+    if !status.success() {
+        return Err(format!("Probe '{}' of test '{}' failed", probe, test));
+    }
+    let kb = std::fs::read_to_string(&file).map_err(|e| e.to_string())?;
+    std::fs::remove_file(&file).map_err(|e| e.to_string())?;
+    kb.trim().parse().map_err(|e| format!("{}", e))
+}
+
+/// Reports the peak memory of a probe run by [`peak_memory_of`]
+pub fn report_peak_memory() -> Result<(), String> {
+    let file = std::env::var(MEMORY_PROBE_FILE)
+        .map_err(|_| format!("{} is not set", MEMORY_PROBE_FILE))?;
+    let kb = peak_memory_kb().ok_or("Cannot read the peak memory of the process")?;
+    std::fs::write(file, kb.to_string()).map_err(|e| e.to_string())
+}
+
+/// Checks that a Monte Carlo `estimate` with a `standard_error` is within
+/// [`ORACLE_Z`] standard errors of the `expected` value. A small `bias` is
+/// allowed on top of that, for the approximations of the reference scenes
+/// (e.g., "infinite" surfaces that are just very large).
+///
+/// # Panics
+///
+/// If the estimate is too far from the expected value.
+pub fn assert_estimate(
+    name: &str,
+    estimate: Float,
+    standard_error: Float,
+    expected: Float,
+    bias: Float,
+) {
+    let tolerance = ORACLE_Z * standard_error + bias;
+    assert!(
+        (estimate - expected).abs() <= tolerance,
+        "{}: expecting {} but got {} (standard error {}, tolerance {})",
+        name,
+        expected,
+        estimate,
+        standard_error,
+        tolerance
+    );
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession};
+    use crate::sky::SkyBasis;
+    use crate::SensorSpec;
+    use validate::assert_close;
+
+    #[test]
+    fn test_view_factors() {
+        // A very large rectangle covers a quarter of the hemisphere
+        assert_close!(parallel_rectangle_view_factor(1e6, 1e6, 1.), 0.25, 1e-4);
+        // Analytic value for a unit square at unit distance
+        assert_close!(parallel_rectangle_view_factor(1., 1., 1.), 0.1385, 1e-4);
+        assert_close!(perpendicular_strip_view_factor(1., 1e9), 0.5, 1e-6);
+        assert_close!(perpendicular_strip_view_factor(1., 0.), 0., 1e-9);
+    }
+
+    #[test]
+    fn test_sky_components() {
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 4000,
+                ..DCOptions::default()
+            },
+        );
+        let basis = SkyBasis::new(1).unwrap();
+        for reference in reference_scenes().unwrap() {
+            let sensors: Vec<SensorSpec> = reference
+                .sensors
+                .iter()
+                .map(|s| SensorSpec::from(s.ray))
+                .collect();
+            let (dc, stats) = session
+                .calc_sensor_dc_with_stats(&sensors, &reference.scene, false)
+                .unwrap();
+            for (i, s) in reference.sensors.iter().enumerate() {
+                let sky: Float = (0..basis.n_bins())
+                    .filter(|bin| *bin != SkyBasis::GROUND_BIN)
+                    .map(|bin| dc.matrix.get(i, bin).unwrap())
+                    .sum();
+                assert_estimate(
+                    &reference.name,
+                    sky,
+                    stats.standard_errors[i],
+                    s.sky_component(),
+                    1e-3,
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_estimate() {
+        assert_estimate("too far", 1.1, 0.01, 1., 0.);
+    }
+}
@@ -0,0 +1,500 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The timesteps of annual series. Every annual quantity in this crate—sky
+//! matrices, occupancy schedules, annual results—has one column (or element)
+//! per timestep, counted in standard time from midnight of the 1st of January
+//! of a non-leap year. These types make that convention explicit, so that
+//! indices do not need to be converted by hand.
+//!
+//! Weather files follow a different convention: the data in the EPW row for
+//! hour `h` (from `1` to `24`) was measured during the hour that *ends* at `h`,
+//! so it belongs to the timestep that starts at `h - 1`. See
+//! [`Timestep::from_epw`].
+
+use crate::Float;
+use calendar::Date;
+use matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+/// The number of days in each month of a non-leap year
+pub(crate) const DAYS_PER_MONTH: [usize; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// The number of days in an annual series
+pub const DAYS_PER_YEAR: usize = 365;
+
+/// The number of hours in an annual series
+pub const HOURS_PER_YEAR: usize = 24 * DAYS_PER_YEAR;
+
+/// Whether a year of the Gregorian calendar has a 29th of February
+pub fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+/// The index of a `(month, day)` within the year, where `0` is the 1st of January
+pub(crate) fn day_of_year((month, day): (u8, u8)) -> Result<usize, String> {
+    let (month, day) = (month as usize, day as usize);
+    if !(1..=12).contains(&month) || day == 0 || day > DAYS_PER_MONTH[month - 1] {
+        return Err(format!(
+            "There is no day {} in month {} (of a non-leap year)",
+            day, month
+        ));
+    }
+    Ok(DAYS_PER_MONTH[..month - 1].iter().sum::<usize>() + day - 1)
+}
+
+/// The `(month, day)` of a day of the year, where `0` is the 1st of January.
+/// Days beyond the end of the year start over in January.
+pub(crate) fn month_day(day: usize) -> (u8, u8) {
+    let mut day = day % DAYS_PER_YEAR;
+    for (m, days) in DAYS_PER_MONTH.iter().enumerate() {
+        if day < *days {
+            return (m as u8 + 1, day as u8 + 1);
+        }
+        day -= days;
+    }
+    unreachable!()
+}
+
+/// An instant within the year, as the hours since midnight of the 1st of
+/// January (in standard time)
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// The hours since the start of the year
+    pub hour_of_year: Float,
+}
+
+impl Timestamp {
+    /// The instant of a `date`. Fails if the day does not exist in a non-leap
+    /// year (e.g., the 29th of February) or if the hour is not within `[0, 24]`.
+    pub fn from_date(date: Date) -> Result<Self, String> {
+        let day = day_of_year((date.month, date.day))?;
+        if !(0.0..=24.).contains(&date.hour) {
+            return Err(format!(
+                "The hour of a date must be between 0 and 24, but found {}",
+                date.hour
+            ));
+        }
+        Ok(Self {
+            hour_of_year: (24 * day) as Float + date.hour,
+        })
+    }
+
+    /// The index of the day (where `0` is the 1st of January). Instants beyond
+    /// the end of the year start over in January.
+    pub fn day_of_year(&self) -> usize {
+        (self.hour_of_year / 24.).floor() as usize % DAYS_PER_YEAR
+    }
+
+    /// The month, from `1` to `12`
+    pub fn month(&self) -> usize {
+        month_day(self.day_of_year()).0 as usize
+    }
+
+    /// The date of the instant, as used for calculating the position of the sun
+    pub fn date(&self) -> Date {
+        let (month, day) = month_day(self.day_of_year());
+        Date {
+            month,
+            day,
+            hour: self.hour_of_year - 24. * (self.hour_of_year / 24.).floor(),
+        }
+    }
+}
+
+/// A timestep of an annual series with a certain number of timesteps per hour
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestep {
+    /// The position of the timestep in the series (i.e., its column)
+    pub index: usize,
+    /// The number of timesteps in an hour
+    pub timesteps_per_hour: usize,
+}
+
+impl Timestep {
+    /// The `index`-th timestep of a series with `timesteps_per_hour` timesteps per hour
+    pub fn new(index: usize, timesteps_per_hour: usize) -> Result<Self, String> {
+        if timesteps_per_hour == 0 {
+            return Err("There must be at least one timestep per hour".to_string());
+        }
+        Ok(Self {
+            index,
+            timesteps_per_hour,
+        })
+    }
+
+    /// The timestep that contains an instant
+    pub fn containing(instant: Timestamp, timesteps_per_hour: usize) -> Result<Self, String> {
+        if instant.hour_of_year < 0.0 {
+            return Err(format!(
+                "Instants must not be before the start of the year, but found hour {}",
+                instant.hour_of_year
+            ));
+        }
+        let index = (instant.hour_of_year * timesteps_per_hour as Float).floor() as usize;
+        Self::new(index, timesteps_per_hour)
+    }
+
+    /// The timestep of the data in an hourly EPW row, which was measured during the
+    /// hour that ends at `hour` (from `1` to `24`) of a `year`. Annual series do not
+    /// have the 29th of February, so its rows are skipped (returning `None`), as long
+    /// as the `year` is a leap year.
+    pub fn from_epw(year: i32, month: u8, day: u8, hour: u8) -> Result<Option<Self>, String> {
+        if !(1..=24).contains(&hour) {
+            return Err(format!(
+                "Hours of EPW rows must be between 1 and 24, but found {}",
+                hour
+            ));
+        }
+        if (month, day) == (2, 29) {
+            if is_leap_year(year) {
+                return Ok(None);
+            }
+            return Err(format!("There is no 29th of February in {}", year));
+        }
+        let day = day_of_year((month, day))?;
+        Self::new(24 * day + hour as usize - 1, 1).map(Some)
+    }
+
+    /// The length of the timestep, in hours
+    pub fn hours(&self) -> Float {
+        1. / self.timesteps_per_hour as Float
+    }
+
+    /// The position of the timestep within a single year (series longer than
+    /// a year start over in January)
+    pub fn index_in_year(&self) -> usize {
+        self.index % (HOURS_PER_YEAR * self.timesteps_per_hour)
+    }
+
+    /// The instant at which the timestep starts
+    pub fn start(&self) -> Timestamp {
+        Timestamp {
+            hour_of_year: self.index_in_year() as Float * self.hours(),
+        }
+    }
+
+    /// The instant in the middle of the timestep, at which the weather and the
+    /// position of the sun are evaluated
+    pub fn centre(&self) -> Timestamp {
+        Timestamp {
+            hour_of_year: (self.index_in_year() as Float + 0.5) * self.hours(),
+        }
+    }
+
+    /// The instant at which the timestep ends
+    pub fn end(&self) -> Timestamp {
+        Timestamp {
+            hour_of_year: (self.index_in_year() + 1) as Float * self.hours(),
+        }
+    }
+
+    /// The date of the centre of the timestep
+    pub fn date(&self) -> Date {
+        self.centre().date()
+    }
+
+    /// The month (from `1` to `12`) of the centre of the timestep
+    pub fn month(&self) -> usize {
+        self.centre().month()
+    }
+}
+
+/// The timesteps of the columns of an annual series: `n_timesteps` consecutive
+/// timesteps, starting from the one at index `first`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeAxis {
+    /// The number of timesteps in an hour
+    pub timesteps_per_hour: usize,
+    /// The index of the first timestep (`0` starts at midnight of the 1st of January)
+    pub first: usize,
+    /// The number of timesteps
+    pub n_timesteps: usize,
+}
+
+impl TimeAxis {
+    /// An axis of `n_timesteps` starting from the timestep at index `first`
+    pub fn new(
+        timesteps_per_hour: usize,
+        first: usize,
+        n_timesteps: usize,
+    ) -> Result<Self, String> {
+        Timestep::new(first, timesteps_per_hour)?;
+        Ok(Self {
+            timesteps_per_hour,
+            first,
+            n_timesteps,
+        })
+    }
+
+    /// A whole year
+    pub fn annual(timesteps_per_hour: usize) -> Result<Self, String> {
+        Self::new(timesteps_per_hour, 0, HOURS_PER_YEAR * timesteps_per_hour)
+    }
+
+    /// The timestep of a column
+    pub fn timestep(&self, column: usize) -> Timestep {
+        Timestep {
+            index: self.first + column,
+            timesteps_per_hour: self.timesteps_per_hour,
+        }
+    }
+
+    /// All the timesteps, in order
+    pub fn timesteps(&self) -> Vec<Timestep> {
+        (0..self.n_timesteps).map(|c| self.timestep(c)).collect()
+    }
+
+    /// The total length of the axis, in hours
+    pub fn hours(&self) -> Float {
+        self.n_timesteps as Float / self.timesteps_per_hour as Float
+    }
+
+    /// Checks that a matrix has one column per timestep
+    pub fn check_columns(&self, matrix: &Matrix) -> Result<(), String> {
+        let (_, ncols) = matrix.size();
+        if ncols != self.n_timesteps {
+            return Err(format!(
+                "The time axis has {} timesteps, but the matrix has {} columns",
+                self.n_timesteps, ncols
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A period of the year made of whole days, from the `start` to the `end`
+/// (both included), given as `(month, day)`. Periods whose end comes before
+/// their start go through the New Year (e.g., a winter from December to
+/// February). Years are not leap years.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DateRange {
+    /// The first day of the period, as `(month, day)`
+    pub start: (u8, u8),
+    /// The last day of the period, as `(month, day)`
+    pub end: (u8, u8),
+}
+
+impl DateRange {
+    /// Creates a period, checking that both dates exist
+    pub fn new(start: (u8, u8), end: (u8, u8)) -> Result<Self, String> {
+        day_of_year(start)?;
+        day_of_year(end)?;
+        Ok(Self { start, end })
+    }
+
+    /// The whole year
+    pub fn year() -> Self {
+        Self {
+            start: (1, 1),
+            end: (12, 31),
+        }
+    }
+
+    /// The days of the period, in order, as their index within the year
+    /// (`0` is the 1st of January) and their date at midnight
+    pub fn days(&self) -> Result<Vec<(usize, Date)>, String> {
+        let first = day_of_year(self.start)?;
+        let last = day_of_year(self.end)?;
+        let n_days = if last >= first {
+            last - first + 1
+        } else {
+            DAYS_PER_YEAR - first + last + 1
+        };
+        Ok((0..n_days)
+            .map(|i| {
+                let day = (first + i) % DAYS_PER_YEAR;
+                let (month, d) = month_day(day);
+                let date = Date {
+                    month,
+                    day: d,
+                    hour: 0.0,
+                };
+                (day, date)
+            })
+            .collect())
+    }
+
+    /// The time axis of the period, with `timesteps_per_hour` timesteps per hour.
+    /// Periods that go through the New Year have timesteps beyond the end of the
+    /// year (see [`Timestep::index_in_year`]).
+    pub fn axis(&self, timesteps_per_hour: usize) -> Result<TimeAxis, String> {
+        let days = self.days()?;
+        let steps_per_day = 24 * timesteps_per_hour;
+        TimeAxis::new(
+            timesteps_per_hour,
+            days[0].0 * steps_per_day,
+            days.len() * steps_per_day,
+        )
+    }
+
+    /// The timesteps of the period, with `timesteps_per_hour` timesteps per hour
+    pub fn timesteps(&self, timesteps_per_hour: usize) -> Result<Vec<Timestep>, String> {
+        Ok(self.axis(timesteps_per_hour)?.timesteps())
+    }
+}
+
+/// An annual result—with one row per sensor and one column per timestep—and
+/// the timesteps of its columns
+#[derive(Debug, Clone)]
+pub struct AnnualSeries {
+    /// The values
+    pub values: Matrix,
+    /// The timesteps of the columns
+    pub axis: TimeAxis,
+}
+
+impl AnnualSeries {
+    /// Attaches a time axis to a matrix, checking that it has one column per timestep
+    pub fn new(values: Matrix, axis: TimeAxis) -> Result<Self, String> {
+        axis.check_columns(&values)?;
+        Ok(Self { values, axis })
+    }
+
+    /// Writes the series as CSV, with one line per timestep. The first columns
+    /// are the month, day and hour (of the centre) of the timestep, followed by
+    /// one column per sensor.
+    pub fn to_csv(&self) -> Result<String, String> {
+        let (nrows, _) = self.values.size();
+        let mut ret = "month,day,hour".to_string();
+        for r in 0..nrows {
+            ret.push_str(&format!(",sensor_{}", r));
+        }
+        ret.push('\n');
+        for (c, step) in self.axis.timesteps().iter().enumerate() {
+            let date = step.date();
+            ret.push_str(&format!("{},{},{}", date.month, date.day, date.hour));
+            for r in 0..nrows {
+                ret.push_str(&format!(",{}", self.values.get(r, c)?));
+            }
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    fn date(month: u8, day: u8, hour: Float) -> Date {
+        Date { month, day, hour }
+    }
+
+    #[test]
+    fn test_solstices() {
+        // Noon of the 21st of June is in the middle of the 4117th hour
+        let june = Timestamp::from_date(date(6, 21, 12.)).unwrap();
+        assert_close!(june.hour_of_year, 4116., 1e-3);
+        assert_eq!(june.day_of_year(), 171);
+        assert_eq!(june.month(), 6);
+        let step = Timestep::containing(june, 1).unwrap();
+        assert_eq!(step.index, 4116);
+        assert_close!(step.centre().hour_of_year, 4116.5, 1e-3);
+        assert_eq!(step.date(), date(6, 21, 12.5));
+
+        // With 4 timesteps per hour, 11:45 to 12:00 is the one before noon
+        let step = Timestep::containing(june, 4).unwrap();
+        assert_eq!(step.index, 4116 * 4);
+        assert_eq!(Timestep::new(4116 * 4 - 1, 4).unwrap().date().hour, 11.875);
+
+        let december = Timestamp::from_date(date(12, 21, 12.)).unwrap();
+        assert_close!(december.hour_of_year, 8508., 1e-3);
+        assert_eq!(december.date(), date(12, 21, 12.));
+
+        // The last timestep ends at the end of the year, and then it starts over
+        let last = Timestep::new(HOURS_PER_YEAR - 1, 1).unwrap();
+        assert_eq!(last.date(), date(12, 31, 23.5));
+        assert_close!(last.end().hour_of_year, 8760., 1e-3);
+        assert_eq!(
+            Timestep::new(HOURS_PER_YEAR, 1).unwrap().date(),
+            date(1, 1, 0.5)
+        );
+        assert_eq!(Timestep::new(31 * 24 - 1, 1).unwrap().month(), 1);
+        assert_eq!(Timestep::new(31 * 24, 1).unwrap().month(), 2);
+
+        assert!(Timestamp::from_date(date(2, 29, 0.)).is_err());
+        assert!(Timestamp::from_date(date(1, 1, 25.)).is_err());
+        assert!(Timestep::new(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_epw_rows() {
+        // The first row covers from midnight to 1am
+        let first = Timestep::from_epw(2019, 1, 1, 1).unwrap().unwrap();
+        assert_eq!(first.index, 0);
+        assert_close!(first.start().hour_of_year, 0., 1e-6);
+        assert_close!(first.end().hour_of_year, 1., 1e-6);
+        let last = Timestep::from_epw(2019, 12, 31, 24).unwrap().unwrap();
+        assert_eq!(last.index, HOURS_PER_YEAR - 1);
+        assert!(Timestep::from_epw(2019, 1, 1, 0).is_err());
+
+        // Leap years
+        assert!(is_leap_year(2020) && is_leap_year(2000));
+        assert!(!is_leap_year(2019) && !is_leap_year(1900));
+        assert_eq!(Timestep::from_epw(2020, 2, 29, 12).unwrap(), None);
+        assert!(Timestep::from_epw(2019, 2, 29, 12).is_err());
+        // ... the days after it keep their place in a non-leap series
+        let march = Timestep::from_epw(2020, 3, 1, 1).unwrap().unwrap();
+        assert_eq!(march.index, 59 * 24);
+        assert_eq!(march.date(), date(3, 1, 0.5));
+    }
+
+    #[test]
+    fn test_axis() {
+        let axis = TimeAxis::annual(2).unwrap();
+        assert_eq!(axis.n_timesteps, 2 * 8760);
+        assert_close!(axis.hours(), 8760., 1e-3);
+
+        let winter = DateRange::new((12, 1), (2, 28)).unwrap();
+        let days = winter.days().unwrap();
+        assert_eq!(days.len(), 31 + 31 + 28);
+        assert_eq!(days[0].0, 334);
+        assert_eq!((days[31].1.month, days[31].1.day), (1, 1));
+        assert_eq!(days.last().unwrap().0, 58);
+        let axis = winter.axis(1).unwrap();
+        assert_eq!(axis.first, 334 * 24);
+        assert_eq!(axis.n_timesteps, 90 * 24);
+        let new_year = axis.timestep(31 * 24);
+        assert_eq!(new_year.index_in_year(), 0);
+        assert_eq!(new_year.date(), date(1, 1, 0.5));
+
+        let steps = DateRange::new((3, 1), (3, 1))
+            .unwrap()
+            .timesteps(4)
+            .unwrap();
+        assert_eq!(steps.len(), 96);
+        assert_eq!(steps[0].index, 59 * 96);
+        assert_close!(steps[1].date().hour, 0.375, 1e-6);
+        assert!(DateRange::year().timesteps(0).is_err());
+        assert!(DateRange::new((2, 29), (3, 1)).is_err());
+        assert!(DateRange::new((13, 1), (3, 1)).is_err());
+        assert!(DateRange::new((1, 0), (3, 1)).is_err());
+
+        let axis = TimeAxis::new(1, 12, 2).unwrap();
+        let series = AnnualSeries::new(Matrix::new(1.5, 1, 2), axis).unwrap();
+        assert_eq!(
+            series.to_csv().unwrap(),
+            "month,day,hour,sensor_0\n1,1,12.5,1.5\n1,1,13.5,1.5\n"
+        );
+        assert!(AnnualSeries::new(Matrix::new(1.5, 1, 3), axis).is_err());
+    }
+}
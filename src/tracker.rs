@@ -0,0 +1,228 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::sensor::SensorSpec;
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use serde::{Deserialize, Serialize};
+
+/// How a single-axis tracker decides its rotation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TrackingAlgorithm {
+    /// The modules face the sun as closely as the axis allows
+    TrueTracking,
+
+    /// The modules back away from the sun when facing it would make
+    /// rows shade each other, given a Ground Coverage Ratio (i.e., module
+    /// width over row spacing).
+    Backtracking {
+        /// The Ground Coverage Ratio, between `0` and `1`
+        gcr: Float,
+    },
+}
+
+/// A horizontal single-axis tracker whose Daylight Coefficients are precalculated
+/// for a set of rotations. Each point of the tracker becomes one sensor per
+/// rotation, and the annual calculation picks the rotation that is closest
+/// to what the tracking algorithm asks for at each timestep.
+///
+/// Rotations are in degrees. A rotation of `0` means that the modules face up,
+/// and positive rotations tilt them towards the right-hand side of the axis
+/// (e.g., East for an axis pointing North).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackerSpec {
+    /// The azimuth of the axis in degrees, measured from North (`+Y`)
+    /// towards East (`+X`)
+    pub axis_azimuth: Float,
+
+    /// The maximum rotation of the tracker in either direction
+    pub max_rotation: Float,
+
+    /// The rotations for which coefficients are calculated
+    pub rotations: Vec<Float>,
+
+    /// The tracking algorithm
+    pub algorithm: TrackingAlgorithm,
+}
+
+impl TrackerSpec {
+    /// Checks that the tracker makes sense
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rotations.is_empty() {
+            return Err("A TrackerSpec needs at least one rotation".to_string());
+        }
+        if !(0.0..=90.).contains(&self.max_rotation) {
+            return Err(format!(
+                "The max_rotation of a TrackerSpec must be between 0 and 90... found {}",
+                self.max_rotation
+            ));
+        }
+        if let TrackingAlgorithm::Backtracking { gcr } = self.algorithm {
+            if gcr <= 0.0 || gcr > 1.0 {
+                return Err(format!(
+                    "The Ground Coverage Ratio of a backtracking tracker must be within (0, 1]... found {}",
+                    gcr
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of rotations (i.e., sensors per point)
+    pub fn n_rotations(&self) -> usize {
+        self.rotations.len()
+    }
+
+    /// The direction of the axis
+    fn axis(&self) -> Vector3D {
+        let azimuth = self.axis_azimuth.to_radians();
+        Vector3D::new(azimuth.sin(), azimuth.cos(), 0.)
+    }
+
+    /// The horizontal direction towards which positive rotations tilt
+    fn side(&self) -> Vector3D {
+        self.axis().cross(Vector3D::new(0., 0., 1.))
+    }
+
+    /// The normal of the modules when rotated by `rotation` degrees
+    pub fn normal(&self, rotation: Float) -> Vector3D {
+        let r = rotation.to_radians();
+        Vector3D::new(0., 0., 1.) * r.cos() + self.side() * r.sin()
+    }
+
+    /// Builds one [`SensorSpec`] per rotation at a certain point. When calculating
+    /// the coefficients of several points, the sensors of each point must
+    /// be placed one after the other.
+    pub fn sensors(&self, origin: Point3D) -> Vec<SensorSpec> {
+        self.rotations
+            .iter()
+            .map(|r| {
+                SensorSpec::from(Ray3D {
+                    origin,
+                    direction: self.normal(*r),
+                })
+            })
+            .collect()
+    }
+
+    /// The rotation (in degrees) requested by the tracking algorithm for a
+    /// certain sun position. The tracker stays flat when the sun is
+    /// below the horizon (i.e., `None`).
+    pub fn rotation_for_sun(&self, sun_direction: Option<Vector3D>) -> Float {
+        let sun = match sun_direction {
+            Some(s) if s.z > 0.0 => s,
+            _ => return 0.0,
+        };
+        // Projection of the sun onto the plane perpendicular to the axis
+        let ideal = (sun * self.side()).atan2(sun.z);
+        let rotation = match self.algorithm {
+            TrackingAlgorithm::TrueTracking => ideal,
+            TrackingAlgorithm::Backtracking { gcr } => {
+                let temp = ideal.cos() / gcr;
+                if temp < 1.0 {
+                    ideal - ideal.signum() * temp.acos()
+                } else {
+                    ideal
+                }
+            }
+        };
+        rotation
+            .to_degrees()
+            .clamp(-self.max_rotation, self.max_rotation)
+    }
+
+    /// The index of the rotation closest to the one requested by the
+    /// tracking algorithm
+    pub fn select_rotation(&self, sun_direction: Option<Vector3D>) -> usize {
+        let target = self.rotation_for_sun(sun_direction);
+        let mut best = 0;
+        for (i, r) in self.rotations.iter().enumerate() {
+            if (r - target).abs() < (self.rotations[best] - target).abs() {
+                best = i;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    fn tracker(algorithm: TrackingAlgorithm) -> TrackerSpec {
+        TrackerSpec {
+            axis_azimuth: 0.,
+            max_rotation: 60.,
+            rotations: vec![-45., 45.],
+            algorithm,
+        }
+    }
+
+    #[test]
+    fn test_normals() {
+        let t = tracker(TrackingAlgorithm::TrueTracking);
+        let n = t.normal(90.);
+        assert_close!(n.x, 1., 1e-6);
+        assert_close!(n.z, 0., 1e-6);
+        let sensors = t.sensors(Point3D::new(0., 0., 0.));
+        assert_eq!(sensors.len(), 2);
+        assert!(sensors[0].ray.direction.x < 0.0);
+        assert!(sensors[1].ray.direction.x > 0.0);
+    }
+
+    #[test]
+    fn test_true_tracking() {
+        let t = tracker(TrackingAlgorithm::TrueTracking);
+        assert!(t.validate().is_ok());
+
+        // Morning, sun in the East
+        let morning = Vector3D::new(1., 0., 1.).get_normalized();
+        assert_close!(t.rotation_for_sun(Some(morning)), 45., 1e-4);
+        assert_eq!(t.select_rotation(Some(morning)), 1);
+
+        // Low sun gets clamped
+        let low = Vector3D::new(-1., 0., 0.1).get_normalized();
+        assert_close!(t.rotation_for_sun(Some(low)), -60., 1e-4);
+        assert_eq!(t.select_rotation(Some(low)), 0);
+
+        // Night
+        assert_close!(t.rotation_for_sun(None), 0., 1e-6);
+    }
+
+    #[test]
+    fn test_backtracking() {
+        let t = tracker(TrackingAlgorithm::Backtracking { gcr: 0.5 });
+        // High sun: no shading, same as true tracking
+        let high = Vector3D::new(1., 0., 3.).get_normalized();
+        let ideal = (1. as Float).atan2(3.).to_degrees();
+        assert_close!(t.rotation_for_sun(Some(high)), ideal, 1e-4);
+
+        // Low sun: backs away towards flat
+        let low = Vector3D::new(1., 0., 0.2).get_normalized();
+        let rotation = t.rotation_for_sun(Some(low));
+        let ideal = (1. as Float).atan2(0.2).to_degrees();
+        assert!(rotation < ideal);
+        assert!(rotation.abs() < 60.);
+
+        let bad = tracker(TrackingAlgorithm::Backtracking { gcr: 0.0 });
+        assert!(bad.validate().is_err());
+    }
+}
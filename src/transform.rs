@@ -0,0 +1,390 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::sensor::{AngularMask, SensorSpec, TributaryArea};
+use crate::Float;
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// A change of coordinates made of a uniform scale, followed by a
+/// rotation and then a translation (i.e., `p' = R * (s * p) + t`).
+///
+/// Points are affected by all three, while directions are only
+/// rotated, so normals remain normalized.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    scale: Float,
+    rotation: [[Float; 3]; 3],
+    translation: [Float; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    const IDENTITY: [[Float; 3]; 3] = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+
+    /// A `Transform` that does nothing
+    pub fn identity() -> Self {
+        Self {
+            scale: 1.,
+            rotation: Self::IDENTITY,
+            translation: [0., 0., 0.],
+        }
+    }
+
+    /// Builds a `Transform` from its parts, checking that `scale` is positive
+    /// and that `rotation` (given by rows) is a proper rotation—i.e., orthonormal
+    /// and without mirroring.
+    pub fn new(
+        scale: Float,
+        rotation: [[Float; 3]; 3],
+        translation: Vector3D,
+    ) -> Result<Self, String> {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(format!(
+                "The scale of a Transform must be positive... found {}",
+                scale
+            ));
+        }
+        let tol = 1e-4;
+        for i in 0..3 {
+            for j in 0..3 {
+                let dot: Float = (0..3).map(|k| rotation[i][k] * rotation[j][k]).sum();
+                let expected = if i == j { 1. } else { 0. };
+                if (dot - expected).abs() > tol {
+                    return Err("The rotation of a Transform must be orthonormal".to_string());
+                }
+            }
+        }
+        if determinant(&rotation) < 0.0 {
+            return Err("The rotation of a Transform cannot mirror the geometry".to_string());
+        }
+        Ok(Self {
+            scale,
+            rotation,
+            translation: [translation.x, translation.y, translation.z],
+        })
+    }
+
+    /// Scales everything by `s`
+    pub fn scale(s: Float) -> Result<Self, String> {
+        Self::new(s, Self::IDENTITY, Vector3D::new(0., 0., 0.))
+    }
+
+    /// Moves everything by `v`
+    pub fn translation(v: Vector3D) -> Self {
+        Self {
+            translation: [v.x, v.y, v.z],
+            ..Self::identity()
+        }
+    }
+
+    /// Rotates `degrees` around the X axis (right-hand rule)
+    pub fn rotation_x(degrees: Float) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Self {
+            rotation: [[1., 0., 0.], [0., cos, -sin], [0., sin, cos]],
+            ..Self::identity()
+        }
+    }
+
+    /// Rotates `degrees` around the Y axis (right-hand rule)
+    pub fn rotation_y(degrees: Float) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Self {
+            rotation: [[cos, 0., sin], [0., 1., 0.], [-sin, 0., cos]],
+            ..Self::identity()
+        }
+    }
+
+    /// Rotates `degrees` around the Z axis (right-hand rule)
+    pub fn rotation_z(degrees: Float) -> Self {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Self {
+            rotation: [[cos, -sin, 0.], [sin, cos, 0.], [0., 0., 1.]],
+            ..Self::identity()
+        }
+    }
+
+    /// Converts from millimetres to metres
+    pub fn mm_to_m() -> Self {
+        Self {
+            scale: 0.001,
+            ..Self::identity()
+        }
+    }
+
+    /// Converts from a Y-up system (e.g., IFC exports, glTF) to a Z-up one,
+    /// like the one used by Radiance and this crate. The Y axis becomes
+    /// Z, and Z becomes -Y.
+    pub fn y_up_to_z_up() -> Self {
+        Self::rotation_x(90.)
+    }
+
+    /// Converts data exported in millimetres with Y-up into metres with Z-up,
+    /// as expected by Radiance scenes
+    pub fn ifc_mm_yup_to_radiance_m_zup() -> Self {
+        Self::mm_to_m().then(&Self::y_up_to_z_up())
+    }
+
+    /// Returns a `Transform` that applies `self` and then `other`
+    pub fn then(&self, other: &Self) -> Self {
+        // other(self(p)) = R2 (s2 (R1 s1 p + t1)) + t2
+        //                = (R2 R1) (s1 s2 p) + (s2 R2 t1 + t2)
+        let mut rotation = [[0.0; 3]; 3];
+        for (i, row) in rotation.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..3)
+                    .map(|k| other.rotation[i][k] * self.rotation[k][j])
+                    .sum();
+            }
+        }
+        let rotated_t = rotate(&other.rotation, self.translation);
+        Self {
+            scale: self.scale * other.scale,
+            rotation,
+            translation: [
+                other.scale * rotated_t[0] + other.translation[0],
+                other.scale * rotated_t[1] + other.translation[1],
+                other.scale * rotated_t[2] + other.translation[2],
+            ],
+        }
+    }
+
+    /// The `Transform` that undoes this one
+    pub fn inverse(&self) -> Self {
+        // p = R^T (p' - t) / s
+        let mut rotation = [[0.0; 3]; 3];
+        for (i, row) in rotation.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = self.rotation[j][i];
+            }
+        }
+        let t = rotate(&rotation, self.translation);
+        let scale = 1. / self.scale;
+        Self {
+            scale,
+            rotation,
+            translation: [-t[0] * scale, -t[1] * scale, -t[2] * scale],
+        }
+    }
+
+    /// The uniform scale of the transformation
+    pub fn get_scale(&self) -> Float {
+        self.scale
+    }
+
+    /// Transforms a point
+    pub fn transform_point(&self, p: Point3D) -> Point3D {
+        let [x, y, z] = self.transform_array(p.x, p.y, p.z);
+        Point3D::new(x, y, z)
+    }
+
+    /// Transforms `[x, y, z]` as if it were a point
+    pub(crate) fn transform_array(&self, x: Float, y: Float, z: Float) -> [Float; 3] {
+        let r = rotate(
+            &self.rotation,
+            [x * self.scale, y * self.scale, z * self.scale],
+        );
+        [
+            r[0] + self.translation[0],
+            r[1] + self.translation[1],
+            r[2] + self.translation[2],
+        ]
+    }
+
+    /// Transforms a direction, which is only rotated
+    pub fn transform_direction(&self, v: Vector3D) -> Vector3D {
+        let [x, y, z] = rotate(&self.rotation, [v.x, v.y, v.z]);
+        Vector3D::new(x, y, z)
+    }
+
+    /// Transforms the origin and direction of a ray
+    pub fn transform_ray(&self, ray: Ray3D) -> Ray3D {
+        Ray3D {
+            origin: self.transform_point(ray.origin),
+            direction: self.transform_direction(ray.direction),
+        }
+    }
+
+    /// Transforms a sensor. `Range` masks are relative to the sensor, so they
+    /// are kept as they are; `Predicate` masks are wrapped so they keep seeing
+    /// the same directions. Tributary areas are rotated and scaled.
+    pub fn transform_sensor(&self, sensor: &SensorSpec) -> SensorSpec {
+        let mask = match &sensor.mask {
+            Some(AngularMask::Predicate(p)) => {
+                let p = Arc::clone(p);
+                let inverse = self.inverse();
+                Some(AngularMask::Predicate(Arc::new(move |dir| {
+                    p(inverse.transform_direction(dir))
+                })))
+            }
+            other => other.clone(),
+        };
+        SensorSpec {
+            ray: self.transform_ray(sensor.ray),
+            mask,
+            zone: sensor.zone.clone(),
+            area: sensor.area.map(|a| TributaryArea {
+                u: self.transform_direction(a.u) * self.get_scale(),
+                v: self.transform_direction(a.v) * self.get_scale(),
+            }),
+            exclude_host_surface: sensor.exclude_host_surface.clone(),
+            id: sensor.id.clone(),
+            angular_response: sensor.angular_response.clone(),
+        }
+    }
+
+    /// Transforms a list of sensors
+    pub fn transform_sensors(&self, sensors: &[SensorSpec]) -> Vec<SensorSpec> {
+        sensors.iter().map(|s| self.transform_sensor(s)).collect()
+    }
+
+    /// Transforms a list of rays
+    pub fn transform_rays(&self, rays: &[Ray3D]) -> Vec<Ray3D> {
+        rays.iter().map(|r| self.transform_ray(*r)).collect()
+    }
+}
+
+fn rotate(m: &[[Float; 3]; 3], v: [Float; 3]) -> [Float; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+fn determinant(m: &[[Float; 3]; 3]) -> Float {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::session::{DCOptions, DCSession};
+    use rendering::Scene;
+    use validate::assert_close;
+
+    fn assert_points_close(a: Point3D, b: Point3D) {
+        assert_close!(a.x, b.x, 1e-4);
+        assert_close!(a.y, b.y, 1e-4);
+        assert_close!(a.z, b.z, 1e-4);
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(Transform::scale(0.).is_err());
+        assert!(Transform::scale(-1.).is_err());
+        let mirror = [[-1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+        assert!(Transform::new(1., mirror, Vector3D::new(0., 0., 0.)).is_err());
+        let skewed = [[1., 0.5, 0.], [0., 1., 0.], [0., 0., 1.]];
+        assert!(Transform::new(1., skewed, Vector3D::new(0., 0., 0.)).is_err());
+    }
+
+    #[test]
+    fn test_ifc_preset() {
+        let t = Transform::ifc_mm_yup_to_radiance_m_zup();
+
+        // 3 metres up, in millimetres and Y-up
+        let p = t.transform_point(Point3D::new(1000., 3000., 2000.));
+        assert_points_close(p, Point3D::new(1., -2., 3.));
+
+        // Directions are rotated but not scaled
+        let d = t.transform_direction(Vector3D::new(0., 1., 0.));
+        assert_close!(d.length(), 1., 1e-6);
+        assert_close!(d.z, 1., 1e-6);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let t = Transform::rotation_z(33.)
+            .then(&Transform::scale(2.5).unwrap())
+            .then(&Transform::translation(Vector3D::new(1., -4., 2.)))
+            .then(&Transform::ifc_mm_yup_to_radiance_m_zup());
+        let inv = t.inverse();
+        for p in [
+            Point3D::new(0., 0., 0.),
+            Point3D::new(1., 2., 3.),
+            Point3D::new(-7., 0.5, 100.),
+        ] {
+            assert_points_close(inv.transform_point(t.transform_point(p)), p);
+            assert_points_close(t.then(&inv).transform_point(p), p);
+        }
+        let d = Vector3D::new(0.3, -0.2, 0.9).get_normalized();
+        let back = inv.transform_direction(t.transform_direction(d));
+        assert_close!(back.x, d.x, 1e-6);
+        assert_close!(back.y, d.y, 1e-6);
+        assert_close!(back.z, d.z, 1e-6);
+        assert_close!(t.transform_direction(d).length(), 1., 1e-6);
+    }
+
+    #[test]
+    fn test_dc_on_transformed_sensors() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+
+        // Sensors in the scene's coordinates
+        let sensors: Vec<SensorSpec> = vec![
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(1., 2., 0.8),
+                direction: Vector3D::new(0., 0., 1.),
+            }),
+            SensorSpec::from(Ray3D {
+                origin: Point3D::new(3., 1., 1.2),
+                direction: Vector3D::new(0., -1., 0.),
+            })
+            .with_mask(AngularMask::predicate(|dir| dir.x > 0.0)),
+        ];
+
+        // The same sensors, as exported by an IFC tool
+        let t = Transform::ifc_mm_yup_to_radiance_m_zup();
+        let exported = t.inverse().transform_sensors(&sensors);
+        assert_close!(exported[0].ray.origin.y, 800., 1e-3);
+        let imported = t.transform_sensors(&exported);
+
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 500,
+                ..DCOptions::default()
+            },
+        );
+        let baseline = session.calc_sensor_dc(&sensors, &scene).unwrap();
+        let transformed = session.calc_sensor_dc(&imported, &scene).unwrap();
+        let (nrows, ncols) = baseline.matrix.size();
+        for r in 0..nrows {
+            for c in 0..ncols {
+                assert_close!(
+                    baseline.matrix.get(r, c).unwrap(),
+                    transformed.matrix.get(r, c).unwrap(),
+                    1e-6
+                );
+            }
+        }
+    }
+}
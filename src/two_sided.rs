@@ -0,0 +1,235 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::annual::apply_annual;
+use crate::labeled_matrix::LabeledMatrix;
+use crate::sky::SkyBasis;
+use crate::{Float, PI};
+use matrix::Matrix;
+
+/// The radiance of the ground, used by [`TwoSidedDC::apply_sky`]
+#[derive(Debug, Clone)]
+pub enum GroundModel {
+    /// A Lambertian ground of a certain albedo irradiated by the whole sky, whose
+    /// radiance is therefore the albedo times the cosine-weighted mean radiance
+    /// of the sky (i.e., `albedo * E_h / PI`, where `E_h` is the horizontal irradiance).
+    /// It is the same in every direction.
+    Uniform {
+        /// The fraction of the horizontal irradiance that the ground reflects
+        albedo: Float,
+    },
+
+    /// The radiance of each ground bin (one row per bin, as in
+    /// [`SkyBasis::ground_centroids`]) and timestep (one column per timestep,
+    /// like the sky matrix)
+    Vector(Matrix),
+}
+
+impl GroundModel {
+    /// The radiance of each ground bin at each timestep, for a sky matrix `skies`
+    /// that follows `basis`
+    pub fn ground_matrix(&self, basis: &SkyBasis, skies: &Matrix) -> Result<Matrix, String> {
+        basis.check_sky(skies)?;
+        let (_, n_steps) = skies.size();
+        let n_ground = basis.n_ground_bins();
+        match self {
+            Self::Vector(ground) => {
+                if ground.size() != (n_ground, n_steps) {
+                    return Err(format!(
+                        "Ground matrix has {} bins and {} timesteps, but {} bins and {} timesteps were expected",
+                        ground.size().0,
+                        ground.size().1,
+                        n_ground,
+                        n_steps
+                    ));
+                }
+                Ok(ground.clone())
+            }
+            Self::Uniform { albedo } => {
+                let weights: Vec<Float> = basis
+                    .centroids()
+                    .iter()
+                    .zip(basis.solid_angles())
+                    .map(|(dir, omega)| dir.z * omega)
+                    .collect();
+                let mut ret = Matrix::new(0.0, n_ground, n_steps);
+                for step in 0..n_steps {
+                    let mut horizontal = 0.0;
+                    for (bin, w) in weights.iter().enumerate().skip(1) {
+                        horizontal += w * skies.get(bin, step)?;
+                    }
+                    let radiance = albedo * horizontal / PI;
+                    for bin in 0..n_ground {
+                        ret.set(bin, step, radiance)?;
+                    }
+                }
+                Ok(ret)
+            }
+        }
+    }
+}
+
+/// Daylight Coefficients that keep the sky and the ground apart, so that the
+/// ground can be given a radiance in post-processing (e.g., to compare albedo
+/// assumptions in façade or PV studies). See
+/// [`DCSession::calc_two_sided_dc`](crate::DCSession::calc_two_sided_dc).
+#[derive(Debug, Clone)]
+pub struct TwoSidedDC {
+    /// The discretisation of the sky
+    pub basis: SkyBasis,
+
+    /// The coefficients of the sky, following `basis`. The ground bin is
+    /// empty, so this can be multiplied by ordinary sky matrices.
+    pub sky: LabeledMatrix,
+
+    /// The coefficients of the ground, with one column per ground bin: the sky
+    /// patches mirrored below the horizon (see [`SkyBasis::ground_bin`])
+    pub ground: Matrix,
+}
+
+impl TwoSidedDC {
+    /// Applies a sky matrix (see [`ApplySky`](crate::ApplySky)) to the sky
+    /// coefficients, and the ground radiance given by `ground` to the
+    /// ground coefficients, returning one row per sensor and one column per timestep.
+    ///
+    /// The ground bin of `skies` is ignored.
+    pub fn apply_sky(&self, skies: &Matrix, ground: &GroundModel) -> Result<Matrix, String> {
+        let ground_skies = ground.ground_matrix(&self.basis, skies)?;
+        let sky = apply_annual(&self.sky.matrix, skies)?;
+        let ground = apply_annual(&self.ground, &ground_skies)?;
+        Ok(&sky + &ground)
+    }
+
+    /// Folds the ground coefficients back into the ground bin of the sky,
+    /// which gives the ordinary (one-sided) Daylight Coefficient matrix.
+    pub fn folded(&self) -> Result<Matrix, String> {
+        let mut ret = self.sky.matrix.clone();
+        let (nrows, ncols) = self.ground.size();
+        for r in 0..nrows {
+            let mut sum = ret.get(r, SkyBasis::GROUND_BIN)?;
+            for c in 0..ncols {
+                sum += self.ground.get(r, c)?;
+            }
+            ret.set(r, SkyBasis::GROUND_BIN, sum)?;
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::sensor::SensorSpec;
+    use crate::session::{DCOptions, DCSession};
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use rendering::Scene;
+    use validate::assert_close;
+
+    fn row_sum(m: &Matrix, r: usize) -> Float {
+        let (_, ncols) = m.size();
+        (0..ncols).map(|c| m.get(r, c).unwrap()).sum()
+    }
+
+    fn vertical_sensor() -> Vec<SensorSpec> {
+        vec![SensorSpec::from(Ray3D {
+            origin: Point3D::new(0., 0., 1.),
+            direction: Vector3D::new(1., 0., 0.),
+        })]
+    }
+
+    #[test]
+    fn test_vertical_sensor() {
+        let mut scene = Scene::new();
+        scene.build_accelerator();
+        let session = DCSession::new(
+            1,
+            DCOptions {
+                max_depth: 0,
+                n_ambient_samples: 40_000,
+                ..DCOptions::default()
+            },
+        );
+        let dc = session
+            .calc_two_sided_dc(&vertical_sensor(), &scene)
+            .unwrap();
+        assert_eq!(dc.sky.matrix.size(), (1, dc.basis.n_bins()));
+        assert_eq!(dc.ground.size(), (1, dc.basis.n_ground_bins()));
+        assert_eq!(dc.sky.matrix.get(0, SkyBasis::GROUND_BIN).unwrap(), 0.0);
+        assert!(dc.sky.events.is_empty());
+
+        // Each half sees half of the view
+        assert_close!(row_sum(&dc.sky.matrix, 0), PI / 2., 0.03);
+        assert_close!(row_sum(&dc.ground, 0), PI / 2., 0.03);
+
+        // Folding the ground gives the ordinary matrix
+        let one_sided = session.calc_sensor_dc(&vertical_sensor(), &scene).unwrap();
+        let folded = dc.folded().unwrap();
+        for c in 0..dc.basis.n_bins() {
+            assert_close!(
+                folded.get(0, c).unwrap(),
+                one_sided.matrix.get(0, c).unwrap(),
+                1e-3
+            );
+        }
+
+        // A uniform sky over a ground of albedo 0.5
+        let n_steps = 3;
+        let skies = Matrix::new(1.0, dc.basis.n_bins(), n_steps);
+        let uniform = dc
+            .apply_sky(&skies, &GroundModel::Uniform { albedo: 0.5 })
+            .unwrap();
+        let vector = dc
+            .apply_sky(
+                &skies,
+                &GroundModel::Vector(Matrix::new(0.5, dc.basis.n_ground_bins(), n_steps)),
+            )
+            .unwrap();
+        assert_eq!(uniform.size(), (1, n_steps));
+        for step in 0..n_steps {
+            let expected = row_sum(&dc.sky.matrix, 0) + 0.5 * row_sum(&dc.ground, 0);
+            assert_close!(uniform.get(0, step).unwrap(), expected, 0.02);
+            assert_close!(vector.get(0, step).unwrap(), expected, 1e-4);
+        }
+
+        // Shapes are checked
+        assert!(dc
+            .apply_sky(
+                &skies,
+                &GroundModel::Vector(Matrix::new(0.5, dc.basis.n_ground_bins(), 1))
+            )
+            .is_err());
+        assert!(dc
+            .apply_sky(
+                &Matrix::new(1.0, 10, n_steps),
+                &GroundModel::Uniform { albedo: 0.2 }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_direct_only() {
+        let scene = Scene::new();
+        let session = DCSession::new(1, DCOptions::default());
+        assert!(!session.options().is_direct());
+        assert!(session
+            .calc_two_sided_dc(&vertical_sensor(), &scene)
+            .is_err());
+    }
+}
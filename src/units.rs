@@ -0,0 +1,152 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::transform::Transform;
+use crate::Float;
+use serde::{Deserialize, Serialize};
+
+/// The unit of length in which a scene (and its sensors) is modelled.
+///
+/// This crate works in metres: scenes in other units are scaled when
+/// they are loaded (see [`crate::load_scenes_with_units`]), so that the
+/// offsets and tolerances used when tracing keep their meaning.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LengthUnit {
+    /// Metres
+    #[default]
+    Metre,
+    /// Centimetres
+    Centimetre,
+    /// Millimetres
+    Millimetre,
+    /// Feet
+    Foot,
+    /// Inches
+    Inch,
+}
+
+impl LengthUnit {
+    /// The length of one unit, in metres
+    pub fn metres(&self) -> Float {
+        match self {
+            Self::Metre => 1.,
+            Self::Centimetre => 0.01,
+            Self::Millimetre => 0.001,
+            Self::Foot => 0.3048,
+            Self::Inch => 0.0254,
+        }
+    }
+
+    /// Reads a unit from its name or abbreviation (e.g., `mm`, `millimetres`, `feet`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        let name = name.trim_end_matches(['.', ',', ';', ')']);
+        match name {
+            "m" | "meter" | "meters" | "metre" | "metres" => Some(Self::Metre),
+            "cm" | "centimeter" | "centimeters" | "centimetre" | "centimetres" => {
+                Some(Self::Centimetre)
+            }
+            "mm" | "millimeter" | "millimeters" | "millimetre" | "millimetres" => {
+                Some(Self::Millimetre)
+            }
+            "ft" | "foot" | "feet" => Some(Self::Foot),
+            "in" | "inch" | "inches" => Some(Self::Inch),
+            _ => None,
+        }
+    }
+
+    /// Converts a length in this unit into metres
+    pub fn to_metres(&self, length: Float) -> Float {
+        length * self.metres()
+    }
+
+    /// Converts a length in metres into this unit
+    pub fn from_metres(&self, length: Float) -> Float {
+        length / self.metres()
+    }
+
+    /// The [`Transform`] that converts points (and sensors) in this unit into metres
+    pub fn transform_to_metres(&self) -> Transform {
+        Transform::scale(self.metres()).expect("the length of every unit is positive")
+    }
+}
+
+/// Looks for a declaration of units in the comments at the top of a Radiance
+/// file, such as `# units: mm` or `# Unit = feet`, as written by some CAD
+/// exporters. Only the comments before the first primitive are considered.
+pub fn detect_unit(content: &str) -> Option<LengthUnit> {
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let comment = line.strip_prefix('#')?.to_lowercase();
+        let mut words = comment
+            .split(|c: char| c.is_whitespace() || c == ':' || c == '=')
+            .filter(|w| !w.is_empty());
+        while let Some(word) = words.next() {
+            if word == "unit" || word == "units" {
+                if let Some(unit) = words.next().and_then(LengthUnit::from_name) {
+                    return Some(unit);
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use validate::assert_close;
+
+    #[test]
+    fn test_length_units() {
+        assert_close!(LengthUnit::Foot.to_metres(10.), 3.048, 1e-9);
+        assert_close!(LengthUnit::Millimetre.from_metres(0.8), 800., 1e-6);
+        assert_eq!(
+            LengthUnit::from_name("Millimetres"),
+            Some(LengthUnit::Millimetre)
+        );
+        assert_eq!(LengthUnit::from_name("ft."), Some(LengthUnit::Foot));
+        assert_eq!(LengthUnit::from_name("parsecs"), None);
+        let t = LengthUnit::Inch.transform_to_metres();
+        assert_close!(t.get_scale(), 0.0254, 1e-9);
+    }
+
+    #[test]
+    fn test_detect_unit() {
+        assert_eq!(
+            detect_unit("# Exported by SomeCAD\n# Units: mm\nvoid plastic a\n"),
+            Some(LengthUnit::Millimetre)
+        );
+        assert_eq!(detect_unit("\n#unit=feet\n"), Some(LengthUnit::Foot));
+        // Mentions of lengths are not declarations
+        assert_eq!(
+            detect_unit("# A 10 x 10 m courtyard\nvoid plastic a\n"),
+            None
+        );
+        // Comments after the first primitive are not headers
+        assert_eq!(
+            detect_unit("void plastic a\n0\n0\n5 0.5 0.5 0.5 0 0\n# units: mm\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,553 @@
+/*
+MIT License
+Copyright (c) 2021 Germán Molina
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use crate::labeled_matrix::{LabeledMatrix, RowMetadata};
+use crate::sensor::SensorSpec;
+use crate::Float;
+use matrix::Matrix;
+
+/// How the rows of the sensors in a zone are combined
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneAggregation {
+    /// The average of the sensors
+    #[default]
+    Mean,
+
+    /// The median of the sensors
+    Median,
+}
+
+/// The sensors of each zone, in order of first appearance. Sensors
+/// without a zone do not belong to any group.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ZoneGroups {
+    /// The name of each zone
+    pub names: Vec<String>,
+
+    /// The indices of the rows belonging to each zone
+    pub members: Vec<Vec<usize>>,
+}
+
+impl ZoneGroups {
+    /// Groups rows by their `zone`
+    pub fn new(rows: &[RowMetadata]) -> Self {
+        Self::from_zones(rows.iter().map(|r| r.zone.as_ref()))
+    }
+
+    /// Groups sensors by their `zone`, before calculating anything
+    pub fn from_sensors(sensors: &[SensorSpec]) -> Self {
+        Self::from_zones(sensors.iter().map(|s| s.zone.as_ref()))
+    }
+
+    fn from_zones<'a, I: Iterator<Item = Option<&'a String>>>(zones: I) -> Self {
+        let mut ret = Self::default();
+        for (i, zone) in zones.enumerate() {
+            let zone = match zone {
+                Some(z) => z,
+                None => continue,
+            };
+            match ret.names.iter().position(|n| n == zone) {
+                Some(j) => ret.members[j].push(i),
+                None => {
+                    ret.names.push(zone.clone());
+                    ret.members.push(vec![i]);
+                }
+            }
+        }
+        ret
+    }
+
+    /// The number of zones
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Whether there are no zones at all
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// One aggregated row per zone
+#[derive(Debug, Clone)]
+pub struct ZoneMatrix {
+    /// The name of each zone, one per row of `matrix`
+    pub zones: Vec<String>,
+
+    /// The number of sensors in each zone
+    pub n_sensors: Vec<usize>,
+
+    /// The number of sensors in each zone whose values are all finite
+    pub n_valid: Vec<usize>,
+
+    /// The aggregated values. Zones without valid sensors are filled with `NaN`.
+    pub matrix: Matrix,
+
+    /// Issues found while aggregating (e.g., zones without valid sensors)
+    pub warnings: Vec<String>,
+}
+
+impl ZoneMatrix {
+    /// Writes the matrix in CSV format, with one line per zone
+    pub fn to_csv(&self) -> Result<String, String> {
+        let (nrows, ncols) = self.matrix.size();
+        let mut ret = "zone,n_sensors,n_valid".to_string();
+        for c in 0..ncols {
+            ret.push_str(&format!(",{}", c));
+        }
+        ret.push('\n');
+        for r in 0..nrows {
+            ret.push_str(&format!(
+                "{},{},{}",
+                csv_field(&self.zones[r]),
+                self.n_sensors[r],
+                self.n_valid[r]
+            ));
+            for c in 0..ncols {
+                ret.push_str(&format!(",{}", self.matrix.get(r, c)?));
+            }
+            ret.push('\n');
+        }
+        Ok(ret)
+    }
+}
+
+/// Quotes a CSV field if needed
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Whether all the values in a row are finite
+fn is_valid_row(m: &Matrix, row: usize) -> Result<bool, String> {
+    let (_, ncols) = m.size();
+    for c in 0..ncols {
+        if !m.get(row, c)?.is_finite() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn median(values: &mut [Float]) -> Float {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let n = values.len();
+    if n == 0 {
+        Float::NAN
+    } else if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        0.5 * (values[n / 2 - 1] + values[n / 2])
+    }
+}
+
+/// Aggregates the rows of a matrix (e.g., Daylight Coefficients or annual results)
+/// by the zone of the sensor they belong to. Rows containing non-finite values
+/// are left out; zones without any valid row produce `NaN`s and a warning.
+pub fn aggregate_by_zone(
+    matrix: &Matrix,
+    rows: &[RowMetadata],
+    aggregation: ZoneAggregation,
+) -> Result<ZoneMatrix, String> {
+    let (nrows, ncols) = matrix.size();
+    if nrows != rows.len() {
+        return Err(format!(
+            "Matrix has {} rows, but {} rows are described",
+            nrows,
+            rows.len()
+        ));
+    }
+    let groups = ZoneGroups::new(rows);
+    let mut ret = ZoneMatrix {
+        zones: groups.names.clone(),
+        n_sensors: groups.members.iter().map(|m| m.len()).collect(),
+        n_valid: Vec::with_capacity(groups.len()),
+        matrix: Matrix::new(Float::NAN, groups.len(), ncols),
+        warnings: Vec::new(),
+    };
+
+    for (z, members) in groups.members.iter().enumerate() {
+        let mut valid = Vec::with_capacity(members.len());
+        for &r in members {
+            if is_valid_row(matrix, r)? {
+                valid.push(r);
+            }
+        }
+        ret.n_valid.push(valid.len());
+        if valid.is_empty() {
+            ret.warnings.push(format!(
+                "Zone '{}' has no valid sensors (out of {})",
+                groups.names[z],
+                members.len()
+            ));
+            continue;
+        }
+
+        let mut column = Vec::with_capacity(valid.len());
+        for c in 0..ncols {
+            column.clear();
+            for &r in &valid {
+                column.push(matrix.get(r, c)?);
+            }
+            let v = match aggregation {
+                ZoneAggregation::Mean => column.iter().sum::<Float>() / column.len() as Float,
+                ZoneAggregation::Median => median(&mut column),
+            };
+            ret.matrix.set(z, c, v)?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Aggregates the Daylight Coefficients of a [`LabeledMatrix`] by zone. See
+/// [`aggregate_by_zone`].
+pub fn zone_dc_rows(
+    dc: &LabeledMatrix,
+    aggregation: ZoneAggregation,
+) -> Result<ZoneMatrix, String> {
+    aggregate_by_zone(&dc.matrix, &dc.rows, aggregation)
+}
+
+/// Calculates the Daylight Autonomy of each sensor: the fraction of the
+/// occupied timesteps in which the illuminance—with one row per sensor and
+/// one column per timestep, as produced by [`crate::annual_irradiance`]—is at
+/// least `threshold`. If `occupied` is `None`, all timesteps count.
+///
+/// Sensors with non-finite values get a `NaN` Daylight Autonomy.
+pub fn daylight_autonomy(
+    illuminance: &Matrix,
+    threshold: Float,
+    occupied: Option<&[bool]>,
+) -> Result<Vec<Float>, String> {
+    let (nrows, ncols) = illuminance.size();
+    if let Some(occ) = occupied {
+        if occ.len() != ncols {
+            return Err(format!(
+                "Illuminance has {} timesteps, but the occupancy has {}",
+                ncols,
+                occ.len()
+            ));
+        }
+    }
+    let is_occupied = |c: usize| !matches!(occupied, Some(o) if !o[c]);
+    let n_occupied = (0..ncols).filter(|c| is_occupied(*c)).count();
+    if n_occupied == 0 {
+        return Err("Cannot calculate Daylight Autonomy without occupied timesteps".to_string());
+    }
+
+    let mut ret = Vec::with_capacity(nrows);
+    for r in 0..nrows {
+        if !is_valid_row(illuminance, r)? {
+            ret.push(Float::NAN);
+            continue;
+        }
+        let mut n = 0;
+        for c in (0..ncols).filter(|c| is_occupied(*c)) {
+            if illuminance.get(r, c)? >= threshold {
+                n += 1;
+            }
+        }
+        ret.push(n as Float / n_occupied as Float);
+    }
+    Ok(ret)
+}
+
+/// Calculates the Spatial Daylight Autonomy: the fraction of the sensors whose
+/// Daylight Autonomy is at least `min_autonomy` (e.g., `0.5` for sDA 300/50%).
+/// Sensors with a `NaN` Daylight Autonomy are left out; if none is left, the
+/// result is `NaN`.
+pub fn spatial_daylight_autonomy(autonomy: &[Float], min_autonomy: Float) -> Float {
+    let valid: Vec<Float> = autonomy.iter().copied().filter(|v| v.is_finite()).collect();
+    if valid.is_empty() {
+        return Float::NAN;
+    }
+    let n = valid.iter().filter(|v| **v >= min_autonomy).count();
+    n as Float / valid.len() as Float
+}
+
+/// The annual metrics of a zone
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneSummary {
+    /// The name of the zone
+    pub zone: String,
+
+    /// The number of sensors in the zone
+    pub n_sensors: usize,
+
+    /// The number of sensors with valid results
+    pub n_valid: usize,
+
+    /// The average Daylight Autonomy of the valid sensors
+    pub mean_autonomy: Float,
+
+    /// The Spatial Daylight Autonomy of the zone
+    pub spatial_autonomy: Float,
+}
+
+/// The [`ZoneSummary`] of each zone
+#[derive(Debug, Clone, Default)]
+pub struct ZoneSummaries {
+    /// One element per zone, in order of first appearance
+    pub zones: Vec<ZoneSummary>,
+
+    /// Issues found while summarising (e.g., zones without valid sensors)
+    pub warnings: Vec<String>,
+}
+
+impl ZoneSummaries {
+    /// Writes the summaries in CSV format, with one line per zone
+    pub fn to_csv(&self) -> String {
+        let mut ret = "zone,n_sensors,n_valid,mean_da,sda\n".to_string();
+        for z in &self.zones {
+            ret.push_str(&format!(
+                "{},{},{},{},{}\n",
+                csv_field(&z.zone),
+                z.n_sensors,
+                z.n_valid,
+                z.mean_autonomy,
+                z.spatial_autonomy
+            ));
+        }
+        ret
+    }
+}
+
+/// Calculates the Daylight Autonomy and Spatial Daylight Autonomy of each
+/// zone separately. See [`daylight_autonomy`] and [`spatial_daylight_autonomy`].
+pub fn zone_annual_summary(
+    illuminance: &Matrix,
+    rows: &[RowMetadata],
+    threshold: Float,
+    min_autonomy: Float,
+    occupied: Option<&[bool]>,
+) -> Result<ZoneSummaries, String> {
+    let (nrows, ..) = illuminance.size();
+    if nrows != rows.len() {
+        return Err(format!(
+            "Illuminance has {} rows, but {} rows are described",
+            nrows,
+            rows.len()
+        ));
+    }
+    let autonomy = daylight_autonomy(illuminance, threshold, occupied)?;
+    let groups = ZoneGroups::new(rows);
+    let mut ret = ZoneSummaries::default();
+    for (name, members) in groups.names.iter().zip(groups.members.iter()) {
+        let da: Vec<Float> = members.iter().map(|r| autonomy[*r]).collect();
+        let valid: Vec<Float> = da.iter().copied().filter(|v| v.is_finite()).collect();
+        let mean_autonomy = if valid.is_empty() {
+            ret.warnings.push(format!(
+                "Zone '{}' has no valid sensors (out of {})",
+                name,
+                members.len()
+            ));
+            Float::NAN
+        } else {
+            valid.iter().sum::<Float>() / valid.len() as Float
+        };
+        ret.zones.push(ZoneSummary {
+            zone: name.clone(),
+            n_sensors: members.len(),
+            n_valid: valid.len(),
+            mean_autonomy,
+            spatial_autonomy: spatial_daylight_autonomy(&da, min_autonomy),
+        });
+    }
+    Ok(ret)
+}
+
+/// Writes a matrix in CSV format with its rows grouped by zone. Each line
+/// contains the zone, the index of the row in the original matrix, the id of
+/// its sensor and its values. Zones are written in order of first appearance, and rows without
+/// a zone go last (with an empty zone).
+pub fn grouped_csv(matrix: &Matrix, rows: &[RowMetadata]) -> Result<String, String> {
+    let (nrows, ncols) = matrix.size();
+    if nrows != rows.len() {
+        return Err(format!(
+            "Matrix has {} rows, but {} rows are described",
+            nrows,
+            rows.len()
+        ));
+    }
+    let groups = ZoneGroups::new(rows);
+    let ungrouped: Vec<usize> = (0..nrows).filter(|r| rows[*r].zone.is_none()).collect();
+
+    let mut ret = "zone,row,id".to_string();
+    for c in 0..ncols {
+        ret.push_str(&format!(",{}", c));
+    }
+    ret.push('\n');
+    let empty = String::new();
+    let sections = groups
+        .names
+        .iter()
+        .zip(groups.members.iter())
+        .chain(std::iter::once((&empty, &ungrouped)));
+    for (name, members) in sections {
+        for &r in members {
+            ret.push_str(&format!(
+                "{},{},{}",
+                csv_field(name),
+                r,
+                csv_field(rows[r].id.as_str())
+            ));
+            for c in 0..ncols {
+                ret.push_str(&format!(",{}", matrix.get(r, c)?));
+            }
+            ret.push('\n');
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use crate::sensor_id::SensorId;
+    use geometry3d::{Point3D, Ray3D, Vector3D};
+    use validate::assert_close;
+
+    fn row(zone: Option<&str>) -> RowMetadata {
+        RowMetadata {
+            ray: Ray3D {
+                origin: Point3D::new(0., 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            },
+            mask: None,
+            zone: zone.map(|z| z.to_string()),
+            n_samples: 1,
+            id: SensorId::new("sensor").unwrap(),
+            angular_response: None,
+        }
+    }
+
+    fn matrix(values: &[[Float; 2]]) -> Matrix {
+        let mut ret = Matrix::new(0.0, values.len(), 2);
+        for (r, v) in values.iter().enumerate() {
+            ret.set(r, 0, v[0]).unwrap();
+            ret.set(r, 1, v[1]).unwrap();
+        }
+        ret
+    }
+
+    #[test]
+    fn test_aggregate_by_zone() {
+        let rows = vec![
+            row(Some("kitchen")),
+            row(Some("bedroom")),
+            row(None),
+            row(Some("kitchen")),
+            row(Some("kitchen")),
+            row(Some("bathroom")),
+        ];
+        let m = matrix(&[
+            [1., 10.],
+            [5., 5.],
+            [100., 100.],
+            [2., 20.],
+            [6., Float::NAN],
+            [Float::INFINITY, 0.],
+        ]);
+
+        let mean = aggregate_by_zone(&m, &rows, ZoneAggregation::Mean).unwrap();
+        assert_eq!(mean.zones, vec!["kitchen", "bedroom", "bathroom"]);
+        assert_eq!(mean.n_sensors, vec![3, 1, 1]);
+        assert_eq!(mean.n_valid, vec![2, 1, 0]);
+        assert_close!(mean.matrix.get(0, 0).unwrap(), 1.5, 1e-9);
+        assert_close!(mean.matrix.get(0, 1).unwrap(), 15., 1e-9);
+        assert_close!(mean.matrix.get(1, 0).unwrap(), 5., 1e-9);
+        assert!(mean.matrix.get(2, 0).unwrap().is_nan());
+        assert_eq!(mean.warnings.len(), 1);
+        assert!(mean.warnings[0].contains("bathroom"));
+        assert!(mean
+            .to_csv()
+            .unwrap()
+            .starts_with("zone,n_sensors,n_valid,0,1\nkitchen,3,2,1.5,15\n"));
+
+        let rows = vec![row(Some("a")), row(Some("a")), row(Some("a"))];
+        let m = matrix(&[[1., 4.], [9., 2.], [3., 3.]]);
+        let median = aggregate_by_zone(&m, &rows, ZoneAggregation::Median).unwrap();
+        assert_close!(median.matrix.get(0, 0).unwrap(), 3., 1e-9);
+        assert_close!(median.matrix.get(0, 1).unwrap(), 3., 1e-9);
+
+        assert!(aggregate_by_zone(&m, &rows[..2], ZoneAggregation::Mean).is_err());
+    }
+
+    #[test]
+    fn test_zone_annual_summary() {
+        let rows = vec![
+            row(Some("a")),
+            row(Some("a")),
+            row(Some("b")),
+            row(Some("c")),
+        ];
+        let mut ill = Matrix::new(0.0, 4, 4);
+        for c in 0..4 {
+            ill.set(0, c, 500.).unwrap(); // DA = 1
+            ill.set(1, c, if c == 0 { 500. } else { 100. }).unwrap(); // DA = 0.25
+            ill.set(2, c, if c < 2 { 300. } else { 0. }).unwrap(); // DA = 0.5
+            ill.set(3, c, Float::NAN).unwrap();
+        }
+        let da = daylight_autonomy(&ill, 300., None).unwrap();
+        assert_close!(da[0], 1., 1e-9);
+        assert_close!(da[1], 0.25, 1e-9);
+        assert_close!(da[2], 0.5, 1e-9);
+        assert!(da[3].is_nan());
+
+        // Only the last two timesteps are occupied
+        let occ = [false, false, true, true];
+        let da = daylight_autonomy(&ill, 300., Some(&occ)).unwrap();
+        assert_close!(da[2], 0., 1e-9);
+        assert!(daylight_autonomy(&ill, 300., Some(&occ[..3])).is_err());
+
+        let summary = zone_annual_summary(&ill, &rows, 300., 0.5, None).unwrap();
+        assert_eq!(summary.zones.len(), 3);
+        let a = &summary.zones[0];
+        assert_close!(a.mean_autonomy, 0.625, 1e-9);
+        assert_close!(a.spatial_autonomy, 0.5, 1e-9);
+        let b = &summary.zones[1];
+        assert_close!(b.spatial_autonomy, 1., 1e-9);
+        let c = &summary.zones[2];
+        assert_eq!(c.n_valid, 0);
+        assert!(c.mean_autonomy.is_nan());
+        assert!(c.spatial_autonomy.is_nan());
+        assert_eq!(summary.warnings.len(), 1);
+        assert!(summary.to_csv().contains("\nc,1,0,NaN,NaN\n"));
+    }
+
+    #[test]
+    fn test_grouped_csv() {
+        let rows = vec![
+            row(Some("b")),
+            row(None),
+            row(Some("a, west")),
+            row(Some("b")),
+        ];
+        let m = matrix(&[[0., 0.], [1., 1.], [2., 2.], [3., 3.]]);
+        let csv = grouped_csv(&m, &rows).unwrap();
+        assert_eq!(
+            csv,
+            "zone,row,id,0,1\nb,0,sensor,0,0\nb,3,sensor,3,3\n\"a, west\",2,sensor,2,2\n,1,sensor,1,1\n"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+#![cfg(feature = "async")]
+
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use light::{load_scene, CancellationToken, DCOptions, DCSession, RunControl, SensorSpec};
+use matrix::Matrix;
+use rendering::Scene;
+use std::sync::Arc;
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn setup(n_sensors: usize, n_ambient_samples: usize) -> (DCSession, Arc<Scene>, Vec<SensorSpec>) {
+    let (mut scene, _) = load_scene("./tests/obstruction/courtyard.rad").unwrap();
+    scene.build_accelerator();
+    let session = DCSession::new(
+        1,
+        DCOptions {
+            n_ambient_samples,
+            max_depth: 0,
+            ..DCOptions::default()
+        },
+    );
+    let sensors = (0..n_sensors)
+        .map(|i| {
+            Ray3D {
+                origin: Point3D::new(
+                    1. + 8. * i as light::Float / n_sensors as light::Float,
+                    5.,
+                    0.5,
+                ),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        })
+        .collect();
+    (session, Arc::new(scene), sensors)
+}
+
+fn assert_same(a: &Matrix, b: &Matrix) {
+    assert_eq!(a.size(), b.size());
+    let (nrows, ncols) = a.size();
+    for r in 0..nrows {
+        for c in 0..ncols {
+            assert_eq!(a.get(r, c).unwrap(), b.get(r, c).unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_send_bounds() {
+    fn is_send_sync<T: Send + Sync + 'static>() {}
+    is_send_sync::<DCSession>();
+    is_send_sync::<Scene>();
+    is_send_sync::<RunControl>();
+    is_send_sync::<CancellationToken>();
+}
+
+#[test]
+fn test_matches_blocking() {
+    let (session, scene, sensors) = setup(20, 200);
+    let expected = session.calc_sensor_dc(&sensors, &scene).unwrap().matrix;
+
+    let control = RunControl::new().with_batch_size(3);
+    let mut progress = control.subscribe();
+    let rt = runtime();
+    let (found, snapshots) = rt.block_on(async {
+        // Follow the progress as an HTTP handler streaming it would
+        let follower = tokio::spawn(async move {
+            let mut snapshots = Vec::new();
+            while progress.changed().await.is_ok() {
+                snapshots.push(*progress.borrow());
+            }
+            snapshots
+        });
+        let found = session.run_async(sensors, scene, control).await;
+        (found, follower.await.unwrap())
+    });
+    assert_same(&found.unwrap(), &expected);
+
+    // Updates can be merged, but they always move forward and end when all is done
+    assert!(!snapshots.is_empty());
+    assert!(snapshots.windows(2).all(|w| w[0].n_done < w[1].n_done));
+    let last = snapshots.last().unwrap();
+    assert!(last.is_finished());
+    assert_eq!(last.n_sensors, 20);
+    assert_eq!(last.fraction(), 1.);
+
+    // So do the samples, which are counted over the whole calculation
+    assert!(snapshots
+        .windows(2)
+        .all(|w| w[0].samples.samples_done < w[1].samples.samples_done));
+    assert!(snapshots
+        .iter()
+        .all(|s| s.samples.samples_total == 20 * 200));
+    assert_eq!(last.samples.samples_done, 20 * 200);
+    assert_eq!(last.samples.eta, Some(std::time::Duration::ZERO));
+}
+
+#[test]
+fn test_cancel() {
+    let (session, scene, sensors) = setup(10, 100);
+    let rt = runtime();
+
+    // Before starting
+    let control = RunControl::new();
+    let progress = control.subscribe();
+    control.token().cancel();
+    let found = rt.block_on(session.run_async(sensors.clone(), scene.clone(), control));
+    assert!(found.unwrap_err().contains("cancelled"));
+    assert_eq!(progress.borrow().n_done, 0);
+
+    // While running, from whoever follows the progress
+    let (session, scene, sensors) = setup(400, 20000);
+    let token = CancellationToken::new();
+    let control = RunControl::new()
+        .with_batch_size(1)
+        .with_token(token.clone());
+    let mut progress = control.subscribe();
+    let found = rt.block_on(async {
+        let follower = tokio::spawn(async move {
+            if progress.changed().await.is_ok() {
+                token.cancel();
+            }
+        });
+        let found = session.run_async(sensors, scene, control).await;
+        follower.await.unwrap();
+        found
+    });
+    assert!(found.unwrap_err().contains("cancelled"));
+}
+
+#[test]
+fn test_cancel_on_drop() {
+    let (session, scene, sensors) = setup(10, 100);
+    let control = RunControl::new();
+    let token = control.token();
+    let future = session.run_async(sensors.clone(), scene.clone(), control);
+    drop(future);
+    assert!(token.is_cancelled());
+
+    // Finishing does not cancel
+    let control = RunControl::new();
+    let token = control.token();
+    runtime()
+        .block_on(session.run_async(sensors, scene, control))
+        .unwrap();
+    assert!(!token.is_cancelled());
+}
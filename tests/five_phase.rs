@@ -0,0 +1,141 @@
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use light::{
+    DCOptions, DCSession, FivePhaseDC, FivePhaseSkies, Float, GroundConvention, Material,
+    SceneBuilder, SensorSpec, SkyBasis, SkyMatrix, SunMapping, TimeAxis, PI,
+};
+use matrix::Matrix;
+
+/// A roof at 1 m with an open square opening of side 1 m
+fn skylight() -> SceneBuilder {
+    let mut builder = SceneBuilder::new();
+    builder
+        .add_material("roof_mat", Material::plastic(0.))
+        .unwrap();
+    let (h, l) = (0.5, 20.);
+    for (name, x0, x1, y0, y1) in [
+        ("south", -l, l, -l, -h),
+        ("north", -l, l, h, l),
+        ("west", -l, -h, -h, h),
+        ("east", h, l, -h, h),
+    ] {
+        let vertices =
+            [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|(x, y)| Point3D::new(x, y, 1.));
+        builder.add_polygon("roof_mat", name, &vertices).unwrap();
+    }
+    builder
+}
+
+/// A uniform sky of some radiance, without the sun, in a `basis`
+fn uniform(basis: &SkyBasis, radiances: &[Float]) -> Matrix {
+    let mut ret = Matrix::new(0.0, basis.n_bins(), radiances.len());
+    for (c, l) in radiances.iter().enumerate() {
+        for bin in 1..basis.n_bins() {
+            ret.set(bin, c, *l).unwrap();
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_open_aperture() {
+    let (mut scene, _) = skylight().build().unwrap();
+    scene.build_accelerator();
+    let sensors: Vec<SensorSpec> = [-0.3, 0., 0.3]
+        .iter()
+        .map(|x| {
+            Ray3D {
+                origin: Point3D::new(*x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        })
+        .collect();
+    let options = DCOptions {
+        n_ambient_samples: 400_000,
+        max_depth: 0,
+        ..DCOptions::default()
+    };
+    let session = |mf: usize, seed: u64| DCSession::new(mf, DCOptions { seed, ..options });
+    let (coarse, fine) = (SkyBasis::new(1).unwrap(), SkyBasis::new(6).unwrap());
+
+    // Every path (direct only, as the roof is black), and the direct paths
+    // in both bases, from separate runs
+    let total = session(1, 1).calc_sensor_dc(&sensors, &scene).unwrap();
+    let mut bases = session(1, 2)
+        .calc_sensor_dc_in_bases(&sensors, &scene, &[coarse, fine])
+        .unwrap();
+    let sun = bases.pop().unwrap();
+    let direct = bases.pop().unwrap();
+    let dc = FivePhaseDC::new(coarse, total.clone(), direct, fine, sun).unwrap();
+
+    // A day of a sun that goes from East to West, high enough in the middle
+    // of the day to be seen through the opening
+    let axis = TimeAxis::new(1, 171 * 24, 24).unwrap();
+    let mut suns = Vec::new();
+    let mut radiances = Vec::new();
+    for hour in 0..24 {
+        let t = (hour as Float + 0.5 - 6.) / 12.;
+        if !(0.0..1.).contains(&t) {
+            suns.push(None);
+            radiances.push(0.0);
+            continue;
+        }
+        let altitude = (80. * (PI * t).sin()).to_radians();
+        let azimuth = (90. + 180. * t).to_radians();
+        let direction = Vector3D::new(
+            altitude.cos() * azimuth.sin(),
+            altitude.cos() * azimuth.cos(),
+            altitude.sin(),
+        );
+        suns.push(Some((direction, 800. * altitude.sin().sqrt())));
+        radiances.push(40. * altitude.sin());
+    }
+    let sky = SkyMatrix::new(
+        coarse,
+        uniform(&coarse, &radiances),
+        GroundConvention::GroundExcluded,
+    )
+    .unwrap();
+    let mapping = SunMapping::Shared(4);
+    let skies = FivePhaseSkies::from_suns(&sky, fine, &suns, &axis, mapping).unwrap();
+    let five_phase = dc.apply(&skies).unwrap();
+    assert_eq!(five_phase.axis, axis);
+
+    // The plain Daylight Coefficients, with the sun in the fine basis, from
+    // two runs that tell how far apart noise alone takes them
+    let fine_sky = SkyMatrix::new(
+        fine,
+        &uniform(&fine, &radiances) + &skies.sun.values,
+        GroundConvention::GroundExcluded,
+    )
+    .unwrap();
+    let two_phase = |seed: u64| {
+        session(6, seed)
+            .calc_sensor_dc(&sensors, &scene)
+            .unwrap()
+            .apply_sky(&fine_sky)
+            .unwrap()
+    };
+    let (reference, other) = (two_phase(3), two_phase(4));
+
+    let rms = |a: &Matrix, b: &Matrix| -> Float {
+        let mut sum = 0.0;
+        for r in 0..sensors.len() {
+            for c in 0..24 {
+                let d = a.get(r, c).unwrap() - b.get(r, c).unwrap();
+                sum += d * d;
+            }
+        }
+        (sum / (24 * sensors.len()) as Float).sqrt()
+    };
+    let noise = rms(&reference, &other);
+    let error = rms(&five_phase.values, &reference);
+    assert!(error < 1.5 * noise, "{} vs {}", error, noise);
+
+    let daily = |m: &Matrix, r: usize| -> Float { (0..24).map(|c| m.get(r, c).unwrap()).sum() };
+    for r in 0..sensors.len() {
+        let (found, exp) = (daily(&five_phase.values, r), daily(&reference, r));
+        assert!(exp > 0.0);
+        assert!((found - exp).abs() < 0.02 * exp, "{} vs {}", found, exp);
+    }
+}
@@ -0,0 +1,48 @@
+use light::{Project, SensorSource};
+
+#[test]
+fn test_tiny_project() {
+    let mut project = Project::from_file("./tests/project/tiny.json").unwrap();
+    assert_eq!(project.mf, 1);
+    assert_eq!(project.options.n_ambient_samples, 2000);
+    assert_eq!(project.grids.len(), 2);
+    assert!(matches!(project.grids[0].sensors, SensorSource::File(_)));
+    project.validate().unwrap();
+
+    let dir = std::env::temp_dir().join(format!("light_project_{}", std::process::id()));
+    project.output.dir = dir.clone();
+    let summary = project.run().unwrap();
+    assert_eq!(summary.grids.len(), 2);
+    assert_eq!(summary.grids[0].name, "floor");
+    assert_eq!(summary.grids[0].manifest.n_sensors, 3);
+    assert_eq!(summary.grids[1].manifest.n_sensors, 1);
+    for name in [
+        "floor.mtx",
+        "floor_irradiance.mtx",
+        "bench.mtx",
+        "bench_irradiance.mtx",
+        "floor_sensors.csv",
+        "bench_sensors.csv",
+        "manifest.json",
+    ] {
+        assert!(dir.join(name).is_file(), "{} is missing", name);
+    }
+
+    // Under a uniform sky, the sensors see the sky that the wall does not hide
+    let (irradiance, manifest) =
+        light::matrix_io::load_mtx(dir.join("floor_irradiance.mtx")).unwrap();
+    assert_eq!(manifest.unwrap(), summary.grids[0].manifest);
+    assert_eq!(irradiance.size(), (3, 2));
+    for r in 0..3 {
+        let (a, b) = (irradiance.get(r, 0).unwrap(), irradiance.get(r, 1).unwrap());
+        assert!(a > 0.0 && a < 100. * light::PI);
+        assert!((a - 2. * b).abs() < 1e-6 * a);
+    }
+    // The sensor closest to the wall sees the least sky
+    assert!(irradiance.get(0, 0).unwrap() < irradiance.get(2, 0).unwrap());
+
+    // One line per row, after the header
+    let sensors = std::fs::read_to_string(dir.join("floor_sensors.csv")).unwrap();
+    assert_eq!(sensors.lines().count(), 4);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
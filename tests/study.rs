@@ -0,0 +1,122 @@
+use geometry3d::{Point3D, Ray3D, Vector3D};
+use light::{
+    DCOptions, DCSession, Float, Material, OccupancySchedule, ReportThresholds, SceneBuilder,
+    SensorSpec, StudyRunner, StudySky, VariantStatus,
+};
+use matrix::Matrix;
+
+/// A roof at 1 m with a square opening of side `a`
+fn skylight(a: Float) -> SceneBuilder {
+    let mut builder = SceneBuilder::new();
+    builder
+        .add_material("roof_mat", Material::plastic(0.5))
+        .unwrap();
+    let (h, l) = (0.5 * a, 20.);
+    for (name, x0, x1, y0, y1) in [
+        ("south", -l, l, -l, -h),
+        ("north", -l, l, h, l),
+        ("west", -l, -h, -h, h),
+        ("east", h, l, -h, h),
+    ] {
+        let vertices =
+            [(x0, y0), (x1, y0), (x1, y1), (x0, y1)].map(|(x, y)| Point3D::new(x, y, 1.));
+        builder.add_polygon("roof_mat", name, &vertices).unwrap();
+    }
+    builder
+}
+
+#[test]
+fn test_study() {
+    let session = DCSession::new(
+        1,
+        DCOptions {
+            n_ambient_samples: 2000,
+            max_depth: 0,
+            ..DCOptions::default()
+        },
+    );
+    let n_bins = session.basis().unwrap().n_bins();
+    let sensors: Vec<SensorSpec> = [-0.3, 0., 0.3]
+        .iter()
+        .map(|x| {
+            Ray3D {
+                origin: Point3D::new(*x, 0., 0.),
+                direction: Vector3D::new(0., 0., 1.),
+            }
+            .into()
+        })
+        .collect();
+    // A day of uniform skies that get brighter every hour
+    let n_timesteps = 24;
+    let mut luminance = Matrix::new(0.0, n_bins, n_timesteps);
+    let mut radiance = Matrix::new(0.0, n_bins, n_timesteps);
+    for t in 0..n_timesteps {
+        for bin in 0..n_bins {
+            luminance.set(bin, t, 200. * t as Float).unwrap();
+            radiance.set(bin, t, 2. * t as Float).unwrap();
+        }
+    }
+    let sky = StudySky {
+        luminance,
+        radiance: Some(radiance),
+        schedule: OccupancySchedule::from_weights(vec![1.; n_timesteps], 1).unwrap(),
+        thresholds: ReportThresholds::default(),
+    };
+    let dir = std::env::temp_dir().join(format!("light_study_{}", std::process::id()));
+    let mut runner = StudyRunner::new(session, sensors, sky).with_output_dir(&dir);
+    runner.add_builder("small", skylight(0.5)).unwrap();
+    runner
+        .add_file("missing", "./tests/study/does_not_exist.rad")
+        .unwrap();
+    runner.add_builder("medium", skylight(1.)).unwrap();
+    runner.add_builder("large", skylight(2.)).unwrap();
+    assert!(runner.add_builder("large", skylight(3.)).is_err());
+    assert!(runner.add_builder("a/b", skylight(3.)).is_err());
+    assert_eq!(runner.len(), 4);
+
+    let summary = runner.run().unwrap();
+    assert_eq!(summary.variants.len(), 4);
+    assert_eq!(summary.n_failed(), 1);
+    assert!(matches!(
+        summary.variants[1].status,
+        VariantStatus::Failed(_)
+    ));
+    assert!(summary.variants[1].spatial_daylight_autonomy.is_none());
+
+    // Larger openings rank higher, and the failed variant goes last
+    let ranked: Vec<&str> = summary.ranked().iter().map(|v| v.id.as_str()).collect();
+    assert_eq!(ranked, vec!["large", "medium", "small", "missing"]);
+    let radiation: Vec<Float> = [0, 2, 3]
+        .iter()
+        .map(|i| summary.variants[*i].annual_radiation.unwrap())
+        .collect();
+    assert!(radiation[0] < radiation[1] && radiation[1] < radiation[2]);
+    let da: Vec<Float> = [0, 2, 3]
+        .iter()
+        .map(|i| summary.variants[*i].mean_daylight_autonomy.unwrap())
+        .collect();
+    assert!(da[0] < da[1] && da[1] < da[2]);
+
+    // Every variant that worked wrote its results, and so did the summary
+    for id in ["small", "medium", "large"] {
+        assert!(dir.join(format!("{}.mtx", id)).exists());
+        let metrics = std::fs::read_to_string(dir.join(format!("{}_metrics.csv", id))).unwrap();
+        assert_eq!(metrics.lines().count(), 4);
+    }
+    assert!(!dir.join("missing.mtx").exists());
+    let csv = std::fs::read_to_string(dir.join("summary.csv")).unwrap();
+    assert_eq!(csv, summary.to_csv());
+    assert!(csv
+        .lines()
+        .nth(2)
+        .unwrap()
+        .starts_with("missing,failed,,,,"));
+
+    // The same, as a batch
+    let outcome = runner.run_batch().unwrap();
+    assert_eq!(outcome.succeeded.len(), 3);
+    assert_eq!(outcome.failed.len(), 1);
+    assert_eq!(outcome.failed[0].id, "missing");
+    assert_eq!(outcome.get("large"), Some(&summary.variants[3]));
+    std::fs::remove_dir_all(&dir).unwrap();
+}